@@ -543,6 +543,49 @@ impl Aml for IO {
     }
 }
 
+pub struct Register {
+    space: OpRegionSpace,
+    bit_width: u8,
+    bit_offset: u8,
+    address: u64,
+    access_size: u8,
+}
+
+impl Register {
+    pub fn new(
+        space: OpRegionSpace,
+        bit_width: u8,
+        bit_offset: u8,
+        address: u64,
+        access_size: u8,
+    ) -> Self {
+        Register {
+            space,
+            bit_width,
+            bit_offset,
+            address,
+            access_size,
+        }
+    }
+}
+
+impl Aml for Register {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        bytes.push(0x82); /* Generic Register Descriptor */
+        bytes.append(&mut 12u16.to_le_bytes().to_vec());
+
+        // 12 bytes of payload
+        bytes.push(self.space as u8);
+        bytes.push(self.bit_width);
+        bytes.push(self.bit_offset);
+        bytes.push(self.access_size);
+        bytes.append(&mut self.address.to_le_bytes().to_vec());
+        bytes
+    }
+}
+
 pub struct Interrupt {
     consumer: bool,
     edge_triggered: bool,
@@ -1501,6 +1544,40 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_register() {
+        /*
+        Name (_CRS, ResourceTemplate ()  // _CRS: Current Resource Settings
+        {
+            Register (SystemMemory,
+                0x20,               // Bit Width
+                0x00,               // Bit Offset
+                0x0000000012345678, // Address
+                0x03,               // Access Size
+                )
+        })
+        */
+        let crs_register = [
+            0x08, 0x5F, 0x43, 0x52, 0x53, 0x11, 0x14, 0x0A, 0x11, 0x82, 0x0C, 0x00, 0x00, 0x20,
+            0x00, 0x03, 0x78, 0x56, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x79, 0x00,
+        ];
+
+        assert_eq!(
+            Name::new(
+                "_CRS".into(),
+                &ResourceTemplate::new(vec![&Register::new(
+                    OpRegionSpace::SystemMemory,
+                    32,
+                    0,
+                    0x1234_5678,
+                    3
+                )])
+            )
+            .to_aml_bytes(),
+            crs_register
+        );
+    }
+
     #[test]
     fn test_package() {
         /*
@@ -66,5 +66,6 @@ pub mod x86_64;
 
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::{
-    arch_memory_regions, configure_system, layout, layout::CMDLINE_MAX_SIZE, layout::CMDLINE_START,
+    arch_memory_regions, boot_ram_end, configure_system, layout, layout::CMDLINE_MAX_SIZE,
+    layout::CMDLINE_START,
 };
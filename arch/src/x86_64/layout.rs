@@ -56,6 +56,9 @@ pub const EBDA_START: GuestAddress = GuestAddress(0xa0000);
 // ACPI RSDP table
 pub const RSDP_POINTER: GuestAddress = EBDA_START;
 
+// SMBIOS tables, in the BIOS area scanned by the guest
+pub const SMBIOS_START: GuestAddress = GuestAddress(0xf0000);
+
 // == End of "EBDA" range ==
 
 // ** High RAM (start: 1MiB, length: 3071MiB) **
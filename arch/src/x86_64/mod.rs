@@ -10,12 +10,14 @@ pub mod interrupts;
 pub mod layout;
 mod mptable;
 pub mod regs;
+mod smbios;
 
 use crate::RegionType;
 use linux_loader::loader::bootparam::{boot_params, setup_header};
 use std::mem;
 use vm_memory::{
-    Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestUsize,
+    Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion,
+    GuestUsize,
 };
 
 const E820_RAM: u32 = 1;
@@ -38,6 +40,8 @@ pub enum Error {
     E820Configuration,
     /// Error writing MP table to memory.
     MpTableSetup(mptable::Error),
+    /// Error writing SMBIOS table to memory.
+    SmbiosSetup(smbios::Error),
 }
 
 impl From<Error> for super::Error {
@@ -101,6 +105,7 @@ pub fn arch_memory_regions(size: GuestUsize) -> Vec<(GuestAddress, usize, Region
 /// * `cmdline_addr` - Address in `guest_mem` where the kernel command line was loaded.
 /// * `cmdline_size` - Size of the kernel command line in bytes including the null terminator.
 /// * `num_cpus` - Number of virtual CPUs the guest will have.
+/// * `uuid` - Machine UUID exposed through SMBIOS, in its RFC 4122 binary form.
 #[allow(clippy::too_many_arguments)]
 pub fn configure_system(
     guest_mem: &GuestMemoryMmap,
@@ -109,6 +114,7 @@ pub fn configure_system(
     num_cpus: u8,
     setup_hdr: Option<setup_header>,
     rsdp_addr: Option<GuestAddress>,
+    uuid: Option<&[u8; 16]>,
 ) -> super::Result<()> {
     const KERNEL_BOOT_FLAG_MAGIC: u16 = 0xaa55;
     const KERNEL_HDR_MAGIC: u32 = 0x53726448;
@@ -118,6 +124,8 @@ pub fn configure_system(
     // Note that this puts the mptable at the last 1k of Linux's 640k base RAM
     mptable::setup_mptable(guest_mem, num_cpus).map_err(Error::MpTableSetup)?;
 
+    smbios::setup_smbios(guest_mem, uuid).map_err(Error::SmbiosSetup)?;
+
     let mut params: BootParamsWrapper = BootParamsWrapper(boot_params::default());
 
     if let Some(hdr) = setup_hdr {
@@ -135,7 +143,7 @@ pub fn configure_system(
 
     add_e820_entry(&mut params.0, 0, layout::EBDA_START.raw_value(), E820_RAM)?;
 
-    let mem_end = guest_mem.last_addr();
+    let mem_end = boot_ram_end(guest_mem);
     if mem_end < layout::MEM_32BIT_RESERVED_START {
         add_e820_entry(
             &mut params.0,
@@ -182,6 +190,27 @@ pub fn configure_system(
     Ok(())
 }
 
+/// Last address of the boot RAM, which is contiguous apart from the 32-bit
+/// memory hole. The memory past it, e.g. the virtio-mem region, gets
+/// hotplugged by the guest, hence isn't reported to the firmware or in the
+/// e820 map.
+pub fn boot_ram_end(guest_mem: &GuestMemoryMmap) -> GuestAddress {
+    let mut mem_end: Option<GuestAddress> = None;
+    for region in guest_mem.iter() {
+        if let Some(end) = mem_end {
+            let next = end.unchecked_add(1);
+            let contiguous = region.start_addr() == next
+                || (next == layout::MEM_32BIT_RESERVED_START
+                    && region.start_addr() == layout::RAM_64BIT_START);
+            if !contiguous {
+                break;
+            }
+        }
+        mem_end = Some(region.last_addr());
+    }
+    mem_end.unwrap_or_else(|| guest_mem.last_addr())
+}
+
 /// Add an e820 region to the e820 map.
 /// Returns Ok(()) if successful, or an error if there is no space left in the map.
 fn add_e820_entry(
@@ -227,7 +256,7 @@ mod tests {
     fn test_system_configuration() {
         let no_vcpus = 4;
         let gm = GuestMemoryMmap::from_ranges(&vec![(GuestAddress(0), 0x10000)]).unwrap();
-        let config_err = configure_system(&gm, GuestAddress(0), 0, 1, None, None);
+        let config_err = configure_system(&gm, GuestAddress(0), 0, 1, None, None, None);
         assert!(config_err.is_err());
 
         // Now assigning some memory that falls before the 32bit memory hole.
@@ -239,7 +268,7 @@ mod tests {
             .map(|r| (r.0, r.1))
             .collect();
         let gm = GuestMemoryMmap::from_ranges(&ram_regions).unwrap();
-        configure_system(&gm, GuestAddress(0), 0, no_vcpus, None, None).unwrap();
+        configure_system(&gm, GuestAddress(0), 0, no_vcpus, None, None, None).unwrap();
 
         // Now assigning some memory that is equal to the start of the 32bit memory hole.
         let mem_size = 3328 << 20;
@@ -250,7 +279,7 @@ mod tests {
             .map(|r| (r.0, r.1))
             .collect();
         let gm = GuestMemoryMmap::from_ranges(&ram_regions).unwrap();
-        configure_system(&gm, GuestAddress(0), 0, no_vcpus, None, None).unwrap();
+        configure_system(&gm, GuestAddress(0), 0, no_vcpus, None, None, None).unwrap();
 
         // Now assigning some memory that falls after the 32bit memory hole.
         let mem_size = 3330 << 20;
@@ -261,7 +290,35 @@ mod tests {
             .map(|r| (r.0, r.1))
             .collect();
         let gm = GuestMemoryMmap::from_ranges(&ram_regions).unwrap();
-        configure_system(&gm, GuestAddress(0), 0, no_vcpus, None, None).unwrap();
+        configure_system(&gm, GuestAddress(0), 0, no_vcpus, None, None, None).unwrap();
+    }
+
+    #[test]
+    fn test_boot_ram_end() {
+        let gm = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 128 << 20)]).unwrap();
+        assert_eq!(boot_ram_end(&gm), GuestAddress((128 << 20) - 1));
+
+        // Across the 32-bit memory hole.
+        let gm = GuestMemoryMmap::from_ranges(&[
+            (
+                GuestAddress(0),
+                layout::MEM_32BIT_RESERVED_START.raw_value() as usize,
+            ),
+            (layout::RAM_64BIT_START, 1 << 30),
+        ])
+        .unwrap();
+        assert_eq!(
+            boot_ram_end(&gm),
+            layout::RAM_64BIT_START.unchecked_add((1 << 30) - 1)
+        );
+
+        // Not the memory past a hole.
+        let gm = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 128 << 20),
+            (layout::RAM_64BIT_START, 1 << 30),
+        ])
+        .unwrap();
+        assert_eq!(boot_ram_end(&gm), GuestAddress((128 << 20) - 1));
     }
 
     #[test]
@@ -94,17 +94,31 @@ pub fn setup_regs(vcpu: &VcpuFd, boot_ip: u64, boot_sp: u64, boot_si: u64) -> Re
     vcpu.set_regs(&regs).map_err(Error::SetBaseRegisters)
 }
 
-/// Configures the segment registers and system page tables for a given CPU.
+/// Writes the GDT, the IDT and the identity mapped page tables the boot
+/// CPUs start with into guest memory, once per system.
 ///
 /// # Arguments
 ///
 /// * `mem` - The memory that will be passed to the guest.
+/// * `encryption_mask` - Set in every paging entry, i.e. the C-bit of a SEV
+///                       guest, for the guest memory to be mapped encrypted.
+pub fn setup_boot_tables(mem: &GuestMemoryMmap, encryption_mask: u64) -> Result<()> {
+    write_gdt_table(&boot_gdt_table(), mem)?;
+    write_idt_value(0, mem)?;
+    write_page_tables(mem, encryption_mask)
+}
+
+/// Configures the segment registers and system page tables for a given CPU,
+/// the tables having been written with `setup_boot_tables()`.
+///
+/// # Arguments
+///
 /// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
-pub fn setup_sregs(mem: &GuestMemoryMmap, vcpu: &VcpuFd) -> Result<()> {
+pub fn setup_sregs(vcpu: &VcpuFd) -> Result<()> {
     let mut sregs: kvm_sregs = vcpu.get_sregs().map_err(Error::GetStatusRegisters)?;
 
-    configure_segments_and_sregs(mem, &mut sregs)?;
-    setup_page_tables(mem, &mut sregs)?; // TODO(dgreid) - Can this be done once per system instead?
+    configure_segments_and_sregs(&mut sregs);
+    setup_page_tables(&mut sregs);
 
     vcpu.set_sregs(&sregs).map_err(Error::SetStatusRegisters)
 }
@@ -136,24 +150,25 @@ fn write_idt_value(val: u64, guest_mem: &GuestMemoryMmap) -> Result<()> {
         .map_err(Error::WriteIDT)
 }
 
-fn configure_segments_and_sregs(mem: &GuestMemoryMmap, sregs: &mut kvm_sregs) -> Result<()> {
-    let gdt_table: [u64; BOOT_GDT_MAX as usize] = [
+fn boot_gdt_table() -> [u64; BOOT_GDT_MAX] {
+    [
         gdt_entry(0, 0, 0),            // NULL
         gdt_entry(0xa09b, 0, 0xfffff), // CODE
         gdt_entry(0xc093, 0, 0xfffff), // DATA
         gdt_entry(0x808b, 0, 0xfffff), // TSS
-    ];
+    ]
+}
+
+fn configure_segments_and_sregs(sregs: &mut kvm_sregs) {
+    let gdt_table = boot_gdt_table();
 
     let code_seg = kvm_segment_from_gdt(gdt_table[1], 1);
     let data_seg = kvm_segment_from_gdt(gdt_table[2], 2);
     let tss_seg = kvm_segment_from_gdt(gdt_table[3], 3);
 
-    // Write segments
-    write_gdt_table(&gdt_table[..], mem)?;
     sregs.gdt.base = BOOT_GDT_START.raw_value();
     sregs.gdt.limit = mem::size_of_val(&gdt_table) as u16 - 1;
 
-    write_idt_value(0, mem)?;
     sregs.idt.base = BOOT_IDT_START.raw_value();
     sregs.idt.limit = mem::size_of::<u64>() as u16 - 1;
 
@@ -168,31 +183,35 @@ fn configure_segments_and_sregs(mem: &GuestMemoryMmap, sregs: &mut kvm_sregs) ->
     /* 64-bit protected mode */
     sregs.cr0 |= X86_CR0_PE;
     sregs.efer |= EFER_LME | EFER_LMA;
-
-    Ok(())
 }
 
-fn setup_page_tables(mem: &GuestMemoryMmap, sregs: &mut kvm_sregs) -> Result<()> {
+fn write_page_tables(mem: &GuestMemoryMmap, encryption_mask: u64) -> Result<()> {
     // Puts PML4 right after zero page but aligned to 4k.
 
     // Entry covering VA [0..512GB)
-    mem.write_obj(PDPTE_START.raw_value() | 0x03, PML4_START)
+    mem.write_obj(PDPTE_START.raw_value() | encryption_mask | 0x03, PML4_START)
         .map_err(Error::WritePML4Address)?;
 
     // Entry covering VA [0..1GB)
-    mem.write_obj(PDE_START.raw_value() | 0x03, PDPTE_START)
+    mem.write_obj(PDE_START.raw_value() | encryption_mask | 0x03, PDPTE_START)
         .map_err(Error::WritePDPTEAddress)?;
     // 512 2MB entries together covering VA [0..1GB). Note we are assuming
     // CPU supports 2MB pages (/proc/cpuinfo has 'pse'). All modern CPUs do.
     for i in 0..512 {
-        mem.write_obj((i << 21) + 0x83u64, PDE_START.unchecked_add(i * 8))
-            .map_err(Error::WritePDEAddress)?;
+        mem.write_obj(
+            ((i << 21) + 0x83u64) | encryption_mask,
+            PDE_START.unchecked_add(i * 8),
+        )
+        .map_err(Error::WritePDEAddress)?;
     }
 
+    Ok(())
+}
+
+fn setup_page_tables(sregs: &mut kvm_sregs) {
     sregs.cr3 = PML4_START.raw_value();
     sregs.cr4 |= X86_CR4_PAE;
     sregs.cr0 |= X86_CR0_PG;
-    Ok(())
 }
 
 fn create_msr_entries() -> Msrs {
@@ -280,7 +299,8 @@ mod tests {
     fn segments_and_sregs() {
         let mut sregs: kvm_sregs = Default::default();
         let gm = create_guest_mem();
-        configure_segments_and_sregs(&gm, &mut sregs).unwrap();
+        setup_boot_tables(&gm, 0).unwrap();
+        configure_segments_and_sregs(&mut sregs);
 
         assert_eq!(0x0, read_u64(&gm, BOOT_GDT_START));
         assert_eq!(
@@ -314,7 +334,8 @@ mod tests {
     fn page_tables() {
         let mut sregs: kvm_sregs = Default::default();
         let gm = create_guest_mem();
-        setup_page_tables(&gm, &mut sregs).unwrap();
+        write_page_tables(&gm, 0).unwrap();
+        setup_page_tables(&mut sregs);
 
         assert_eq!(0xa003, read_u64(&gm, PML4_START));
         assert_eq!(0xb003, read_u64(&gm, PDPTE_START));
@@ -330,6 +351,22 @@ mod tests {
         assert_eq!(X86_CR0_PG, sregs.cr0);
     }
 
+    #[test]
+    fn encrypted_page_tables() {
+        let c_bit = 1u64 << 47;
+        let gm = create_guest_mem();
+        write_page_tables(&gm, c_bit).unwrap();
+
+        assert_eq!(0xa003 | c_bit, read_u64(&gm, PML4_START));
+        assert_eq!(0xb003 | c_bit, read_u64(&gm, PDPTE_START));
+        for i in 0..512 {
+            assert_eq!(
+                ((i << 21) + 0x83u64) | c_bit,
+                read_u64(&gm, PDE_START.unchecked_add(i * 8))
+            );
+        }
+    }
+
     #[test]
     fn test_setup_fpu() {
         let kvm = Kvm::new().unwrap();
@@ -0,0 +1,278 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! SMBIOS tables, as described by the System Management BIOS (SMBIOS)
+//! Reference Specification 3.2, letting the guest identify the machine
+//! it runs on through DMI.
+
+use std::mem;
+use std::result;
+use std::slice;
+
+use layout::SMBIOS_START;
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+#[derive(Debug)]
+pub enum Error {
+    /// There was too little guest memory to store the entire SMBIOS table.
+    NotEnoughMemory,
+    /// The SMBIOS table has too little address space to be stored.
+    AddressOverflow,
+    /// Failure while zeroing out the memory for the SMBIOS table.
+    Clear(GuestMemoryError),
+    /// Failure to write the SMBIOS entry point structure.
+    WriteSmbiosEp(GuestMemoryError),
+    /// Failure to write an SMBIOS structure.
+    WriteData(GuestMemoryError),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+// Room reserved for the tables, which fit in a page.
+const SMBIOS_MAX_SIZE: u64 = 0x1000;
+
+const SM3_MAGIC_IDENT: &[u8; 5] = b"_SM3_";
+const BIOS_INFORMATION: u8 = 0;
+const SYSTEM_INFORMATION: u8 = 1;
+const END_OF_TABLE: u8 = 127;
+const PCI_SUPPORTED: u64 = 1 << 7;
+const IS_VIRTUAL_MACHINE: u8 = 1 << 4;
+const WAKE_UP_POWER_SWITCH: u8 = 6;
+
+const MANUFACTURER: &str = "Cloud Hypervisor";
+const PRODUCT_NAME: &str = "cloud-hypervisor";
+const BIOS_VERSION: &str = "0";
+
+fn compute_checksum<T: Copy>(v: &T) -> u8 {
+    // Safe because we are only reading the bytes within the size of the `T` reference `v`.
+    let v_slice = unsafe { slice::from_raw_parts(v as *const T as *const u8, mem::size_of::<T>()) };
+    let mut checksum: u8 = 0;
+    for i in v_slice.iter() {
+        checksum = checksum.wrapping_add(*i);
+    }
+    (!checksum).wrapping_add(1)
+}
+
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+struct Smbios30Entrypoint {
+    signature: [u8; 5],
+    checksum: u8,
+    length: u8,
+    majorver: u8,
+    minorver: u8,
+    docrev: u8,
+    revision: u8,
+    reserved: u8,
+    max_size: u32,
+    physptr: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+struct SmbiosBiosInfo {
+    typ: u8,
+    length: u8,
+    handle: u16,
+    vendor: u8,
+    version: u8,
+    start_addr: u16,
+    release_date: u8,
+    rom_size: u8,
+    characteristics: u64,
+    characteristics_ext1: u8,
+    characteristics_ext2: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+struct SmbiosSysInfo {
+    typ: u8,
+    length: u8,
+    handle: u16,
+    manufacturer: u8,
+    product_name: u8,
+    version: u8,
+    serial_number: u8,
+    uuid: [u8; 16],
+    wake_up_type: u8,
+    sku: u8,
+    family: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+struct SmbiosEndOfTable {
+    typ: u8,
+    length: u8,
+    handle: u16,
+}
+
+// These structures are only data, reading them from data is a safe initialization.
+unsafe impl ByteValued for Smbios30Entrypoint {}
+unsafe impl ByteValued for SmbiosBiosInfo {}
+unsafe impl ByteValued for SmbiosSysInfo {}
+unsafe impl ByteValued for SmbiosEndOfTable {}
+
+// SMBIOS stores the first three fields of the UUID in little endian, while
+// the RFC 4122 representation is big endian all along.
+fn smbios_uuid(uuid: &[u8; 16]) -> [u8; 16] {
+    let mut bytes = *uuid;
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    bytes
+}
+
+fn write_and_incr<T: ByteValued>(
+    mem: &GuestMemoryMmap,
+    val: T,
+    curptr: GuestAddress,
+) -> Result<GuestAddress> {
+    mem.write_obj(val, curptr).map_err(Error::WriteData)?;
+    curptr
+        .checked_add(mem::size_of::<T>() as u64)
+        .ok_or(Error::NotEnoughMemory)
+}
+
+// Writes the strings following a structure, the set being terminated by an
+// additional null byte.
+fn write_strings(
+    mem: &GuestMemoryMmap,
+    strings: &[&str],
+    mut curptr: GuestAddress,
+) -> Result<GuestAddress> {
+    for s in strings {
+        for c in s.bytes() {
+            curptr = write_and_incr(mem, c, curptr)?;
+        }
+        curptr = write_and_incr(mem, 0u8, curptr)?;
+    }
+    if strings.is_empty() {
+        curptr = write_and_incr(mem, 0u8, curptr)?;
+    }
+    write_and_incr(mem, 0u8, curptr)
+}
+
+/// Writes the SMBIOS tables describing the BIOS and the system, with the
+/// given `uuid` in its RFC 4122 binary form, if any.
+pub fn setup_smbios(mem: &GuestMemoryMmap, uuid: Option<&[u8; 16]>) -> Result<u64> {
+    let physptr = SMBIOS_START
+        .checked_add(mem::size_of::<Smbios30Entrypoint>() as u64)
+        .ok_or(Error::AddressOverflow)?;
+    mem.checked_offset(SMBIOS_START, SMBIOS_MAX_SIZE as usize - 1)
+        .ok_or(Error::NotEnoughMemory)?;
+    mem.write_slice(&[0u8; SMBIOS_MAX_SIZE as usize], SMBIOS_START)
+        .map_err(Error::Clear)?;
+
+    let mut curptr = physptr;
+    let mut handle = 0;
+
+    let bios_info = SmbiosBiosInfo {
+        typ: BIOS_INFORMATION,
+        length: mem::size_of::<SmbiosBiosInfo>() as u8,
+        handle,
+        vendor: 1,  // First string written in this section
+        version: 2, // Second string written in this section
+        characteristics: PCI_SUPPORTED,
+        characteristics_ext2: IS_VIRTUAL_MACHINE,
+        ..Default::default()
+    };
+    curptr = write_and_incr(mem, bios_info, curptr)?;
+    curptr = write_strings(mem, &[MANUFACTURER, BIOS_VERSION], curptr)?;
+
+    handle += 1;
+    let sys_info = SmbiosSysInfo {
+        typ: SYSTEM_INFORMATION,
+        length: mem::size_of::<SmbiosSysInfo>() as u8,
+        handle,
+        manufacturer: 1, // First string written in this section
+        product_name: 2, // Second string written in this section
+        uuid: uuid.map(smbios_uuid).unwrap_or_default(),
+        wake_up_type: WAKE_UP_POWER_SWITCH,
+        ..Default::default()
+    };
+    curptr = write_and_incr(mem, sys_info, curptr)?;
+    curptr = write_strings(mem, &[MANUFACTURER, PRODUCT_NAME], curptr)?;
+
+    handle += 1;
+    let end_of_table = SmbiosEndOfTable {
+        typ: END_OF_TABLE,
+        length: mem::size_of::<SmbiosEndOfTable>() as u8,
+        handle,
+    };
+    curptr = write_and_incr(mem, end_of_table, curptr)?;
+    curptr = write_strings(mem, &[], curptr)?;
+
+    let max_size = curptr.unchecked_offset_from(physptr);
+    let mut smbios_ep = Smbios30Entrypoint {
+        signature: *SM3_MAGIC_IDENT,
+        length: mem::size_of::<Smbios30Entrypoint>() as u8,
+        // SMBIOS rev 3.2.0
+        majorver: 0x03,
+        minorver: 0x02,
+        docrev: 0x00,
+        // SMBIOS 3.0 entry point structure
+        revision: 0x01,
+        max_size: max_size as u32,
+        physptr: physptr.raw_value(),
+        ..Default::default()
+    };
+    smbios_ep.checksum = compute_checksum(&smbios_ep);
+    mem.write_obj(smbios_ep, SMBIOS_START)
+        .map_err(Error::WriteSmbiosEp)?;
+
+    Ok(curptr.unchecked_offset_from(SMBIOS_START))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn struct_size() {
+        assert_eq!(mem::size_of::<Smbios30Entrypoint>(), 0x18);
+        assert_eq!(mem::size_of::<SmbiosBiosInfo>(), 0x14);
+        assert_eq!(mem::size_of::<SmbiosSysInfo>(), 0x1b);
+    }
+
+    #[test]
+    fn entrypoint_checksum() {
+        let mem = GuestMemoryMmap::from_ranges(&[(SMBIOS_START, 4096)]).unwrap();
+
+        setup_smbios(&mem, None).unwrap();
+
+        let smbios_ep: Smbios30Entrypoint = mem.read_obj(SMBIOS_START).unwrap();
+
+        assert_eq!(compute_checksum(&smbios_ep), 0);
+    }
+
+    #[test]
+    fn system_uuid() {
+        let mem = GuestMemoryMmap::from_ranges(&[(SMBIOS_START, 4096)]).unwrap();
+        let uuid = [
+            0x4e, 0xb6, 0x54, 0x2d, 0x7a, 0x26, 0x4a, 0x9c, 0x8c, 0x1b, 0xab, 0xa5, 0x55, 0x0e,
+            0x8e, 0x4f,
+        ];
+
+        setup_smbios(&mem, Some(&uuid)).unwrap();
+
+        let smbios_ep: Smbios30Entrypoint = mem.read_obj(SMBIOS_START).unwrap();
+        let bios_info_size = mem::size_of::<SmbiosBiosInfo>() as u64
+            + (MANUFACTURER.len() + BIOS_VERSION.len() + 3) as u64;
+        let sys_info: SmbiosSysInfo = mem
+            .read_obj(GuestAddress(smbios_ep.physptr + bios_info_size))
+            .unwrap();
+
+        assert_eq!(sys_info.typ, SYSTEM_INFORMATION);
+        assert_eq!(
+            sys_info.uuid,
+            [
+                0x2d, 0x54, 0xb6, 0x4e, 0x26, 0x7a, 0x9c, 0x4a, 0x8c, 0x1b, 0xab, 0xa5, 0x55, 0x0e,
+                0x8e, 0x4f
+            ]
+        );
+    }
+}
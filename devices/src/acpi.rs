@@ -9,18 +9,24 @@ use vmm_sys_util::eventfd::EventFd;
 use BusDevice;
 use HotPlugNotificationFlags;
 
-/// A device for handling ACPI shutdown and reboot
+/// A device for handling ACPI shutdown, hibernation and reboot
 pub struct AcpiShutdownDevice {
     exit_evt: EventFd,
     reset_evt: EventFd,
+    hibernate_evt: EventFd,
 }
 
 impl AcpiShutdownDevice {
     /// Constructs a device that will signal the given event when the guest requests it.
-    pub fn new(exit_evt: EventFd, reset_evt: EventFd) -> AcpiShutdownDevice {
+    pub fn new(
+        exit_evt: EventFd,
+        reset_evt: EventFd,
+        hibernate_evt: EventFd,
+    ) -> AcpiShutdownDevice {
         AcpiShutdownDevice {
             exit_evt,
             reset_evt,
+            hibernate_evt,
         }
     }
 }
@@ -41,10 +47,18 @@ impl BusDevice for AcpiShutdownDevice {
                 error!("Error triggering ACPI reset event: {}", e);
             }
         }
-        // The ACPI DSDT table specifies the S5 sleep state (shutdown) as value 5
+        // The ACPI DSDT table specifies the S4 sleep state (hibernation) as
+        // value 4, and the S5 sleep state (shutdown) as value 5
+        const S4_SLEEP_VALUE: u8 = 4;
         const S5_SLEEP_VALUE: u8 = 5;
         const SLEEP_STATUS_EN_BIT: u8 = 5;
         const SLEEP_VALUE_BIT: u8 = 2;
+        if data[0] == (S4_SLEEP_VALUE << SLEEP_VALUE_BIT) | (1 << SLEEP_STATUS_EN_BIT) {
+            debug!("ACPI Hibernation signalled");
+            if let Err(e) = self.hibernate_evt.write(1) {
+                error!("Error triggering ACPI hibernation event: {}", e);
+            }
+        }
         if data[0] == (S5_SLEEP_VALUE << SLEEP_VALUE_BIT) | (1 << SLEEP_STATUS_EN_BIT) {
             debug!("ACPI Shutdown signalled");
             extern crate bitflags;
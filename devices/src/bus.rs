@@ -8,7 +8,6 @@
 //! Handles routing to devices in an address space.
 
 use std::cmp::{Ord, Ordering, PartialEq, PartialOrd};
-use std::collections::btree_map::BTreeMap;
 use std::sync::{Arc, Mutex, RwLock};
 use std::{convert, error, fmt, io, result};
 
@@ -93,37 +92,135 @@ impl PartialOrd for BusRange {
 ///
 /// This doesn't have any restrictions on what kind of device or address space this applies to. The
 /// only restriction is that no two devices can overlap in this address space.
+///
+/// The devices are kept in an array sorted by address, for the device an exit
+/// targets to be found with a binary search over contiguous memory, every
+/// MMIO and PIO exit going through it.
 #[derive(Default)]
 pub struct Bus {
-    devices: RwLock<BTreeMap<BusRange, Arc<Mutex<dyn BusDevice>>>>,
+    #[allow(clippy::type_complexity)]
+    devices: RwLock<Vec<(BusRange, Arc<Mutex<dyn BusDevice>>)>>,
+}
+
+// Returns the index of the range starting at `base`, or the index where such a
+// range would be inserted.
+fn search(
+    devices: &[(BusRange, Arc<Mutex<dyn BusDevice>>)],
+    base: u64,
+) -> result::Result<usize, usize> {
+    devices.binary_search_by(|(range, _dev)| range.base.cmp(&base))
+}
+
+// Returns the length of the first access an access of `len` bytes at `addr`
+// is split into: the largest of 8, 4, 2 and 1 bytes which `addr` is aligned to
+// and which doesn't go past `len`.
+fn split_len(addr: u64, len: u64) -> u64 {
+    let mut size = 8;
+    while size > len || addr % size != 0 {
+        size /= 2;
+    }
+    size
 }
 
 impl Bus {
     /// Constructs an a bus with an empty address space.
     pub fn new() -> Bus {
         Bus {
-            devices: RwLock::new(BTreeMap::new()),
+            devices: RwLock::new(Vec::new()),
+        }
+    }
+
+    fn lookup(&self, addr: u64) -> Option<(BusRange, Arc<Mutex<dyn BusDevice>>)> {
+        let devices = self.devices.read().unwrap();
+        // The last range starting at or before `addr` is the only one which
+        // can contain it.
+        let index = match search(&devices, addr) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let (range, dev) = &devices[index];
+        if addr - range.base < range.len {
+            return Some((*range, dev.clone()));
         }
+        None
     }
 
-    fn first_before(&self, addr: u64) -> Option<(BusRange, Arc<Mutex<dyn BusDevice>>)> {
+    // Returns the base of the first range starting past `addr`.
+    fn next_base(&self, addr: u64) -> Option<u64> {
         let devices = self.devices.read().unwrap();
-        let (range, dev) = devices
-            .range(..=BusRange { base: addr, len: 1 })
-            .rev()
-            .next()?;
-        Some((*range, dev.clone()))
+        let index = match search(&devices, addr) {
+            Ok(index) => index + 1,
+            Err(index) => index,
+        };
+        devices.get(index).map(|(range, _dev)| range.base)
     }
 
     #[allow(clippy::type_complexity)]
     pub fn resolve(&self, addr: u64) -> Option<(u64, u64, Arc<Mutex<dyn BusDevice>>)> {
-        if let Some((range, dev)) = self.first_before(addr) {
-            let offset = addr - range.base;
-            if offset < range.len {
-                return Some((range.base, offset, dev));
+        self.lookup(addr)
+            .map(|(range, dev)| (range.base, addr - range.base, dev))
+    }
+
+    // Returns the device owning the whole access of `len` bytes at `addr`,
+    // if the devices can handle it as is: the access is 1, 2, 4 or 8 bytes
+    // wide, and doesn't go past the end of the device range.
+    #[allow(clippy::type_complexity)]
+    fn resolve_access(
+        &self,
+        addr: u64,
+        len: usize,
+    ) -> Option<(u64, u64, Arc<Mutex<dyn BusDevice>>)> {
+        let (range, dev) = self.lookup(addr)?;
+        let offset = addr - range.base;
+        match len {
+            1 | 2 | 4 | 8 if offset + len as u64 <= range.len => Some((range.base, offset, dev)),
+            _ => None,
+        }
+    }
+
+    // Splits an access the devices can't handle as is into naturally aligned
+    // accesses of 1, 2, 4 or 8 bytes, each going to the device owning it, as
+    // the guests with buggy drivers issue odd sized accesses, or accesses
+    // straddling two devices. The parts no device owns are ignored.
+    //
+    // Returns true if a device handled some part of the access.
+    fn split_access<F>(&self, addr: u64, len: usize, mut access: F) -> bool
+    where
+        F: FnMut(&mut dyn BusDevice, u64, u64, std::ops::Range<usize>),
+    {
+        let mut handled = false;
+        let mut pos = 0;
+        while pos < len {
+            let part_addr = addr + pos as u64;
+            let mut part_len = (len - pos) as u64;
+            let device = self.lookup(part_addr);
+            match &device {
+                Some((range, _)) => {
+                    part_len = part_len.min(range.base + range.len - part_addr);
+                }
+                // A part no device owns stops where the next device starts.
+                None => {
+                    if let Some(next_base) = self.next_base(part_addr) {
+                        part_len = part_len.min(next_base - part_addr);
+                    }
+                }
+            }
+            let part_len = split_len(part_addr, part_len) as usize;
+
+            if let Some((range, dev)) = device {
+                // OK to unwrap as lock() failing is a serious error condition and should panic.
+                access(
+                    &mut *dev.lock().expect("Failed to acquire device lock"),
+                    range.base,
+                    part_addr - range.base,
+                    pos..pos + part_len,
+                );
+                handled = true;
             }
+            pos += part_len;
         }
-        None
+        handled
     }
 
     /// Puts the given device at the given address space.
@@ -132,27 +229,25 @@ impl Bus {
             return Err(Error::ZeroSizedRange);
         }
 
-        // Reject all cases where the new device's range overlaps with an existing device.
-        if self
-            .devices
-            .read()
-            .unwrap()
-            .iter()
-            .any(|(range, _dev)| range.overlaps(base, len))
-        {
-            return Err(Error::Overlap);
-        }
+        let mut devices = self.devices.write().unwrap();
+        let index = match search(&devices, base) {
+            Ok(_) => return Err(Error::Overlap),
+            Err(index) => index,
+        };
 
-        if self
-            .devices
-            .write()
-            .unwrap()
-            .insert(BusRange { base, len }, device)
-            .is_some()
-        {
+        // Reject all cases where the new device's range overlaps with an existing device,
+        // which can only be one of its neighbours, the ranges being sorted and disjoint.
+        let overlaps = |index: usize| {
+            devices
+                .get(index)
+                .map_or(false, |(range, _dev)| range.overlaps(base, len))
+        };
+        if (index > 0 && overlaps(index - 1)) || overlaps(index) {
             return Err(Error::Overlap);
         }
 
+        devices.insert(index, (BusRange { base, len }, device));
+
         Ok(())
     }
 
@@ -162,13 +257,14 @@ impl Bus {
             return Err(Error::ZeroSizedRange);
         }
 
-        let bus_range = BusRange { base, len };
-
-        if self.devices.write().unwrap().remove(&bus_range).is_none() {
-            return Err(Error::MissingAddressRange);
+        let mut devices = self.devices.write().unwrap();
+        match search(&devices, base) {
+            Ok(index) => {
+                devices.remove(index);
+                Ok(())
+            }
+            Err(_) => Err(Error::MissingAddressRange),
         }
-
-        Ok(())
     }
 
     /// Updates the address range for an existing device.
@@ -194,32 +290,42 @@ impl Bus {
     }
 
     /// Reads data from the device that owns the range containing `addr` and puts it into `data`.
+    /// An access straddling several devices, or partly out of any device, is split between the
+    /// devices owning its parts.
     ///
-    /// Returns true on success, otherwise `data` is untouched.
+    /// Returns true if some device handled part of the access. The parts of `data` no device owns
+    /// are left untouched.
     pub fn read(&self, addr: u64, data: &mut [u8]) -> bool {
-        if let Some((base, offset, dev)) = self.resolve(addr) {
+        if let Some((base, offset, dev)) = self.resolve_access(addr, data.len()) {
             // OK to unwrap as lock() failing is a serious error condition and should panic.
             dev.lock()
                 .expect("Failed to acquire device lock")
                 .read(base, offset, data);
             true
         } else {
-            false
+            self.split_access(addr, data.len(), |dev, base, offset, part| {
+                dev.read(base, offset, &mut data[part])
+            })
         }
     }
 
-    /// Writes `data` to the device that owns the range containing `addr`.
+    /// Writes `data` to the device that owns the range containing `addr`. An access straddling
+    /// several devices, or partly out of any device, is split between the devices owning its
+    /// parts.
     ///
-    /// Returns true on success, otherwise `data` is untouched.
+    /// Returns true if some device handled part of the access. The parts of `data` no device owns
+    /// are dropped.
     pub fn write(&self, addr: u64, data: &[u8]) -> bool {
-        if let Some((base, offset, dev)) = self.resolve(addr) {
+        if let Some((base, offset, dev)) = self.resolve_access(addr, data.len()) {
             // OK to unwrap as lock() failing is a serious error condition and should panic.
             dev.lock()
                 .expect("Failed to acquire device lock")
                 .write(base, offset, data);
             true
         } else {
-            false
+            self.split_access(addr, data.len(), |dev, base, offset, part| {
+                dev.write(base, offset, &data[part])
+            })
         }
     }
 }
@@ -299,6 +405,128 @@ mod tests {
         assert!(bus.write(0x15, &values));
     }
 
+    #[test]
+    fn bus_resolve() {
+        let bus = Bus::new();
+        let dummy = Arc::new(Mutex::new(DummyDevice));
+        // Inserted out of order, with gaps in between.
+        for base in [0x50u64, 0x10, 0x30, 0x20].iter() {
+            assert!(bus.insert(dummy.clone(), *base, 0x8).is_ok());
+        }
+
+        assert!(bus.resolve(0x0).is_none());
+        assert_eq!(
+            bus.resolve(0x10).map(|(base, offset, _)| (base, offset)),
+            Some((0x10, 0))
+        );
+        assert_eq!(
+            bus.resolve(0x27).map(|(base, offset, _)| (base, offset)),
+            Some((0x20, 7))
+        );
+        assert!(bus.resolve(0x28).is_none());
+        assert_eq!(
+            bus.resolve(0x33).map(|(base, offset, _)| (base, offset)),
+            Some((0x30, 3))
+        );
+        assert!(bus.resolve(0x40).is_none());
+        assert!(bus.resolve(0x1000).is_none());
+
+        assert!(bus.remove(0x30, 0x8).is_ok());
+        assert!(bus.resolve(0x33).is_none());
+        assert!(bus.remove(0x30, 0x8).is_err());
+        assert!(bus.update_range(0x50, 0x8, 0x30, 0x8).is_ok());
+        assert_eq!(
+            bus.resolve(0x33).map(|(base, offset, _)| (base, offset)),
+            Some((0x30, 3))
+        );
+        assert!(bus.resolve(0x50).is_none());
+    }
+
+    // Records the accesses, to check how they are split.
+    #[derive(Default)]
+    struct RecordingDevice {
+        accesses: Vec<(u64, usize)>,
+    }
+    impl BusDevice for RecordingDevice {
+        fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+            self.accesses.push((offset, data.len()));
+            for (i, v) in data.iter_mut().enumerate() {
+                *v = (offset as u8) + (i as u8);
+            }
+        }
+
+        fn write(&mut self, _base: u64, offset: u64, data: &[u8]) {
+            self.accesses.push((offset, data.len()));
+        }
+    }
+
+    #[test]
+    fn bus_split_access() {
+        let bus = Bus::new();
+        let first = Arc::new(Mutex::new(RecordingDevice::default()));
+        let second = Arc::new(Mutex::new(RecordingDevice::default()));
+        assert!(bus.insert(first.clone(), 0x10, 0x10).is_ok());
+        assert!(bus.insert(second.clone(), 0x20, 0x10).is_ok());
+
+        // Unaligned, but supported widths go through as is.
+        assert!(bus.write(0x11, &[0; 4]));
+        assert_eq!(
+            first.lock().unwrap().accesses.drain(..).as_slice(),
+            [(1, 4)]
+        );
+
+        // Odd sized.
+        let mut data = [0; 7];
+        assert!(bus.read(0x11, &mut data));
+        assert_eq!(data, [1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(
+            first.lock().unwrap().accesses.drain(..).as_slice(),
+            [(1, 1), (2, 2), (4, 4)]
+        );
+
+        // Straddling two devices.
+        assert!(bus.write(0x1c, &[0; 8]));
+        assert_eq!(
+            first.lock().unwrap().accesses.drain(..).as_slice(),
+            [(0xc, 4)]
+        );
+        assert_eq!(
+            second.lock().unwrap().accesses.drain(..).as_slice(),
+            [(0, 4)]
+        );
+
+        // Partly out of any device.
+        assert!(bus.write(0x2e, &[0; 4]));
+        assert_eq!(
+            second.lock().unwrap().accesses.drain(..).as_slice(),
+            [(0xe, 2)]
+        );
+        assert!(!bus.write(0x30, &[0; 3]));
+    }
+
+    #[test]
+    fn bus_split_access_unaligned_device() {
+        let bus = Bus::new();
+        let device = Arc::new(Mutex::new(RecordingDevice::default()));
+        assert!(bus.insert(device.clone(), 0x13, 0x10).is_ok());
+
+        // Starting in the gap before the device, the part past the gap
+        // reaches the device.
+        let mut data = [0xff; 8];
+        assert!(bus.read(0x10, &mut data));
+        assert_eq!(data, [0xff, 0xff, 0xff, 0, 1, 2, 3, 4]);
+        assert_eq!(
+            device.lock().unwrap().accesses.drain(..).as_slice(),
+            [(0, 1), (1, 4)]
+        );
+
+        assert!(bus.write(0x11, &[0; 4]));
+        assert_eq!(
+            device.lock().unwrap().accesses.drain(..).as_slice(),
+            [(0, 1), (1, 1)]
+        );
+    }
+
     #[test]
     fn busrange_cmp() {
         let range = BusRange { base: 0x10, len: 2 };
@@ -10,6 +10,7 @@
 // See https://pdos.csail.mit.edu/6.828/2016/readings/ia32/ioapic.pdf for a specification.
 
 use crate::BusDevice;
+use anyhow::anyhow;
 use byteorder::{ByteOrder, LittleEndian};
 use std::io;
 use std::result;
@@ -18,6 +19,7 @@ use vm_device::interrupt::{
     InterruptIndex, InterruptManager, InterruptSourceConfig, InterruptSourceGroup,
     MsiIrqGroupConfig, MsiIrqSourceConfig,
 };
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
 use vm_memory::GuestAddress;
 
 #[derive(Debug)]
@@ -165,6 +167,13 @@ fn decode_irq_from_selector(selector: u8) -> (usize, bool) {
     )
 }
 
+#[derive(Deserialize, Serialize)]
+struct IoapicState {
+    id: u32,
+    reg_sel: u32,
+    reg_entries: Vec<RedirectionTableEntry>,
+}
+
 pub struct Ioapic {
     id: u32,
     reg_sel: u32,
@@ -386,3 +395,59 @@ impl Ioapic {
         }
     }
 }
+
+impl Pausable for Ioapic {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+}
+
+impl Snapshotable for Ioapic {
+    fn id(&self) -> String {
+        "ioapic".to_string()
+    }
+
+    fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        snapshot.add_state(
+            "ioapic",
+            &IoapicState {
+                id: self.id,
+                reg_sel: self.reg_sel,
+                reg_entries: self.reg_entries.to_vec(),
+            },
+        )?;
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        let state: IoapicState = snapshot.state("ioapic")?;
+        if state.reg_entries.len() != NUM_IOAPIC_PINS {
+            return Err(MigratableError::Restore(anyhow!(
+                "Invalid number of IOAPIC pins: {}",
+                state.reg_entries.len()
+            )));
+        }
+
+        self.id = state.id;
+        self.reg_sel = state.reg_sel;
+        self.reg_entries.copy_from_slice(&state.reg_entries);
+        // Program the interrupt routes the guest had set up.
+        for irq in 0..NUM_IOAPIC_PINS {
+            if self.reg_entries[irq] == 0 {
+                continue;
+            }
+            self.update_entry(irq).map_err(|e| {
+                MigratableError::Restore(anyhow!("Failed restoring IOAPIC entry: {:?}", e))
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Migratable for Ioapic {}
@@ -2,9 +2,12 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use anyhow::anyhow;
 use libc::{gmtime_r, time, time_t, tm};
 use std::cmp::min;
 use std::mem;
+use std::result;
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
 
 use crate::BusDevice;
 
@@ -13,6 +16,12 @@ const INDEX_OFFSET: u64 = 0x0;
 const DATA_OFFSET: u64 = 0x1;
 const DATA_LEN: usize = 128;
 
+#[derive(Deserialize, Serialize)]
+struct CmosState {
+    index: u8,
+    data: Vec<u8>,
+}
+
 /// A CMOS/RTC device commonly seen on x86 I/O port 0x70/0x71.
 pub struct Cmos {
     index: u8,
@@ -113,3 +122,47 @@ impl BusDevice for Cmos {
         }
     }
 }
+
+impl Pausable for Cmos {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+}
+
+impl Snapshotable for Cmos {
+    fn id(&self) -> String {
+        "cmos".to_string()
+    }
+
+    fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        snapshot.add_state(
+            "cmos",
+            &CmosState {
+                index: self.index,
+                data: self.data.to_vec(),
+            },
+        )?;
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        let state: CmosState = snapshot.state("cmos")?;
+        if state.data.len() != DATA_LEN {
+            return Err(MigratableError::Restore(anyhow!(
+                "Invalid CMOS data length: {}",
+                state.data.len()
+            )));
+        }
+
+        self.index = state.index & INDEX_MASK;
+        self.data.copy_from_slice(&state.data);
+        Ok(())
+    }
+}
+
+impl Migratable for Cmos {}
@@ -0,0 +1,88 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::io;
+use BusDevice;
+
+// Value returned on reads, which firmware like OVMF checks for before using
+// the port, the same way QEMU's isa-debugcon does.
+const DEBUG_CONSOLE_MAGIC: u8 = 0xe9;
+
+// Lines longer than this are logged in several chunks.
+const MAX_LINE_LEN: usize = 512;
+
+const LOG_PREFIX: &str = "Firmware log";
+
+/// A write-only debug console, meant to capture the firmware debug output,
+/// commonly sent to I/O port 0x402. Output is logged line by line.
+pub struct DebugConsole {
+    line: Vec<u8>,
+    out: Option<Box<dyn io::Write + Send>>,
+}
+
+impl DebugConsole {
+    pub fn new() -> DebugConsole {
+        DebugConsole {
+            line: Vec::with_capacity(MAX_LINE_LEN),
+            out: None,
+        }
+    }
+
+    /// Creates a debug console which also writes the logged lines to `out`.
+    pub fn with_output(out: Box<dyn io::Write + Send>) -> DebugConsole {
+        DebugConsole {
+            line: Vec::with_capacity(MAX_LINE_LEN),
+            out: Some(out),
+        }
+    }
+
+    fn flush_line(&mut self) {
+        if !self.line.is_empty() {
+            info!("[{}] {}", LOG_PREFIX, String::from_utf8_lossy(&self.line));
+            if let Some(out) = &mut self.out {
+                self.line.push(b'\n');
+                if let Err(e) = out.write_all(&self.line) {
+                    warn!("Failed writing the firmware log line: {}", e);
+                }
+            }
+            self.line.clear();
+        }
+    }
+}
+
+impl Default for DebugConsole {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BusDevice for DebugConsole {
+    fn read(&mut self, _base: u64, _offset: u64, data: &mut [u8]) {
+        if data.len() == 1 {
+            data[0] = DEBUG_CONSOLE_MAGIC;
+        }
+    }
+
+    fn write(&mut self, _base: u64, _offset: u64, data: &[u8]) {
+        for b in data {
+            match b {
+                b'\n' => self.flush_line(),
+                b'\r' => (),
+                _ => {
+                    self.line.push(*b);
+                    if self.line.len() >= MAX_LINE_LEN {
+                        self.flush_line();
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for DebugConsole {
+    fn drop(&mut self) {
+        self.flush_line();
+    }
+}
@@ -7,10 +7,14 @@
 
 #[cfg(feature = "cmos")]
 mod cmos;
+mod debug_console;
 mod i8042;
+mod pvpanic;
 mod serial;
 
 #[cfg(feature = "cmos")]
 pub use self::cmos::Cmos;
+pub use self::debug_console::DebugConsole;
 pub use self::i8042::I8042Device;
+pub use self::pvpanic::Pvpanic;
 pub use self::serial::Serial;
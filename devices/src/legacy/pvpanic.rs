@@ -0,0 +1,51 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use vmm_sys_util::eventfd::EventFd;
+
+use BusDevice;
+
+// Events the guest can report, also advertised on reads.
+const PVPANIC_PANICKED: u8 = 1 << 0;
+const PVPANIC_CRASH_LOADED: u8 = 1 << 1;
+
+/// A paravirtualized panic device, compatible with the QEMU pvpanic ISA
+/// device. The guest kernel writes to its I/O port when it panics.
+pub struct Pvpanic {
+    panic_evt: EventFd,
+}
+
+impl Pvpanic {
+    /// Constructs a pvpanic device that will signal the given event when the
+    /// guest reports a panic.
+    pub fn new(panic_evt: EventFd) -> Pvpanic {
+        Pvpanic { panic_evt }
+    }
+}
+
+impl BusDevice for Pvpanic {
+    fn read(&mut self, _base: u64, _offset: u64, data: &mut [u8]) {
+        if data.len() == 1 {
+            data[0] = PVPANIC_PANICKED | PVPANIC_CRASH_LOADED;
+        }
+    }
+
+    fn write(&mut self, _base: u64, _offset: u64, data: &[u8]) {
+        if data.len() != 1 {
+            return;
+        }
+
+        if data[0] & PVPANIC_CRASH_LOADED != 0 {
+            info!("Guest kernel panicked, loading its crash kernel");
+        }
+
+        if data[0] & PVPANIC_PANICKED != 0 {
+            debug!("pvpanic panic signalled");
+            if let Err(e) = self.panic_evt.write(1) {
+                error!("Error triggering pvpanic panic event: {}", e);
+            }
+        }
+    }
+}
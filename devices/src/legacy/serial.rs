@@ -10,6 +10,7 @@ use std::collections::VecDeque;
 use std::sync::Arc;
 use std::{io, result};
 use vm_device::interrupt::InterruptSourceGroup;
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
 use vmm_sys_util::errno::Result;
 
 const LOOP_SIZE: usize = 0x40;
@@ -50,6 +51,19 @@ const DEFAULT_MODEM_CONTROL: u8 = 0x8; // Auxiliary output 2
 const DEFAULT_MODEM_STATUS: u8 = 0x20 | 0x10 | 0x80; // data ready, clear to send, carrier detect
 const DEFAULT_BAUD_DIVISOR: u16 = 12; // 9600 bps
 
+#[derive(Deserialize, Serialize)]
+struct SerialState {
+    interrupt_enable: u8,
+    interrupt_identification: u8,
+    line_control: u8,
+    line_status: u8,
+    modem_control: u8,
+    modem_status: u8,
+    scratch: u8,
+    baud_divisor: u16,
+    in_buffer: Vec<u8>,
+}
+
 /// Emulates serial COM ports commonly seen on x86 I/O ports 0x3f8/0x2f8/0x3e8/0x2e8.
 ///
 /// This can optionally write the guest's output to a Write trait object. To send input to the
@@ -236,6 +250,57 @@ impl BusDevice for Serial {
     }
 }
 
+impl Pausable for Serial {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+}
+
+impl Snapshotable for Serial {
+    fn id(&self) -> String {
+        "serial".to_string()
+    }
+
+    fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        snapshot.add_state(
+            "serial",
+            &SerialState {
+                interrupt_enable: self.interrupt_enable,
+                interrupt_identification: self.interrupt_identification,
+                line_control: self.line_control,
+                line_status: self.line_status,
+                modem_control: self.modem_control,
+                modem_status: self.modem_status,
+                scratch: self.scratch,
+                baud_divisor: self.baud_divisor,
+                in_buffer: self.in_buffer.iter().copied().collect(),
+            },
+        )?;
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        let state: SerialState = snapshot.state("serial")?;
+        self.interrupt_enable = state.interrupt_enable;
+        self.interrupt_identification = state.interrupt_identification;
+        self.line_control = state.line_control;
+        self.line_status = state.line_status;
+        self.modem_control = state.modem_control;
+        self.modem_status = state.modem_status;
+        self.scratch = state.scratch;
+        self.baud_divisor = state.baud_divisor;
+        self.in_buffer = state.in_buffer.into_iter().collect();
+        Ok(())
+    }
+}
+
+impl Migratable for Serial {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
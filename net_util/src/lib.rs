@@ -19,6 +19,7 @@ extern crate vmm_sys_util;
 
 mod mac;
 mod tap;
+mod xdp;
 
 use std::io::Error as IoError;
 use std::mem;
@@ -27,6 +28,7 @@ use std::os::unix::io::FromRawFd;
 
 pub use mac::{MacAddr, MAC_ADDR_LEN};
 pub use tap::{Error as TapError, Tap};
+pub use xdp::{Error as XskError, Xsk};
 
 #[derive(Debug)]
 pub enum Error {
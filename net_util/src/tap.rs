@@ -5,7 +5,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{Error as IoError, Read, Result as IoResult, Write};
 use std::net;
 use std::os::raw::*;
@@ -13,6 +13,7 @@ use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 
 use super::{create_sockaddr, create_socket, Error as NetUtilError};
 use libc;
+use mac::{MacAddr, MAC_ADDR_LEN};
 use net_gen;
 use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val};
 
@@ -31,6 +32,12 @@ pub enum Error {
     /// Failed to create a socket.
     NetUtil(NetUtilError),
     InvalidIfname,
+    /// Couldn't duplicate the tap fd.
+    DupFd(IoError),
+    /// The fd isn't a tap with a virtio-net header.
+    InvalidTapFd,
+    /// Couldn't open the macvtap character device.
+    OpenMacvtap(IoError),
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
@@ -152,6 +159,87 @@ impl Tap {
         Self::open_named("vmtap%d", num_queue_pairs)
     }
 
+    /// Open a queue of the existing macvtap interface `if_name`, through
+    /// its /dev/tapN character device. Each call opens a new queue.
+    pub fn open_macvtap(if_name: &str) -> Result<Tap> {
+        let terminated_if_name = build_terminated_if_name(if_name)?;
+        let ifindex = fs::read_to_string(format!("/sys/class/net/{}/ifindex", if_name))
+            .map_err(Error::OpenMacvtap)?;
+        let path = format!("/dev/tap{}\0", ifindex.trim());
+
+        let fd = unsafe {
+            // Open calls are safe because we give a null-terminated string
+            // and verify the result.
+            libc::open(
+                path.as_ptr() as *const c_char,
+                libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::OpenMacvtap(IoError::last_os_error()));
+        }
+
+        let mut if_name = terminated_if_name;
+        if_name.pop();
+        // We just checked that the fd is valid.
+        Ok(Tap {
+            tap_file: unsafe { File::from_raw_fd(fd) },
+            if_name,
+        })
+    }
+
+    /// Whether `if_name` is a macvtap interface.
+    pub fn is_macvtap(if_name: &str) -> bool {
+        fs::metadata(format!("/sys/class/net/{}/macvtap", if_name)).is_ok()
+    }
+
+    /// orchestrator not granting CAP_NET_ADMIN to the VMM. The fd is
+    /// orchestrator lacking the will to grant CAP_NET_ADMIN. The fd is
+    /// duplicated, `fd` staying open so that it can be used again, e.g.
+    /// after a reboot.
+    pub fn from_tap_fd(fd: RawFd) -> Result<Tap> {
+        // fcntl is safe since we check the return value.
+        let fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(Error::DupFd(IoError::last_os_error()));
+        }
+        // We just checked that the fd is valid.
+        let tap_file = unsafe { File::from_raw_fd(fd) };
+
+        // ioctl is safe since we call it with a valid fd and check the return
+        // value.
+        let mut ifreq: net_gen::ifreq = Default::default();
+        let ret = unsafe { ioctl_with_mut_ref(&tap_file, net_gen::TUNGETIFF(), &mut ifreq) };
+        if ret < 0 {
+            return Err(Error::InvalidTapFd);
+        }
+
+        // The virtio-net header can only be enabled when attaching the queue.
+        let flags = unsafe { *ifreq.ifr_ifru.ifru_flags.as_ref() } as c_ushort as c_uint;
+        let expected = net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR;
+        if flags & expected != expected {
+            return Err(Error::InvalidTapFd);
+        }
+
+        // fcntl is safe since we call it with a valid fd and check the
+        // return value.
+        let ret = unsafe {
+            let status = libc::fcntl(fd, libc::F_GETFL);
+            libc::fcntl(fd, libc::F_SETFL, status | libc::O_NONBLOCK)
+        };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        let if_name_temp = unsafe { *ifreq.ifr_ifrn.ifrn_name.as_ref() };
+        let if_name = if_name_temp
+            .iter()
+            .take_while(|c| **c != 0)
+            .cloned()
+            .collect();
+        Ok(Tap { tap_file, if_name })
+    }
+
     /// Set the host-side IP address for the tap interface.
     pub fn set_ip_addr(&self, ip_addr: net::Ipv4Addr) -> Result<()> {
         let sock = create_socket().map_err(Error::NetUtil)?;
@@ -213,12 +301,135 @@ impl Tap {
         Ok(())
     }
 
-    /// Enable the tap interface.
+    /// Get the MAC address of the tap interface.
+    pub fn get_mac_addr(&self) -> Result<MacAddr> {
+        let sock = create_socket().map_err(Error::NetUtil)?;
+
+        let mut ifreq = self.get_ifreq();
+
+        // ioctl is safe. Called with a valid sock fd, and we check the return.
+        #[allow(clippy::cast_lossless)]
+        let ret = unsafe {
+            ioctl_with_mut_ref(
+                &sock,
+                net_gen::sockios::SIOCGIFHWADDR as c_ulong,
+                &mut ifreq,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        // We only access one field of the ifru union, hence this is safe.
+        let addr = unsafe { *ifreq.ifr_ifru.ifru_hwaddr.as_ref() };
+        let bytes: Vec<u8> = addr.sa_data[..MAC_ADDR_LEN]
+            .iter()
+            .map(|b| *b as u8)
+            .collect();
+        Ok(MacAddr::from_bytes_unchecked(&bytes))
+    }
+
+    /// Set the MAC address of the tap interface, which requires
+    /// CAP_NET_ADMIN unless it's already the right one.
+    pub fn set_mac_addr(&self, mac: MacAddr) -> Result<()> {
+        if self.get_mac_addr()? == mac {
+            return Ok(());
+        }
+
+        let sock = create_socket().map_err(Error::NetUtil)?;
+
+        let mut ifreq = self.get_ifreq();
+
+        // We only access one field of the ifru union, hence this is safe.
+        unsafe {
+            let ifru_hwaddr = ifreq.ifr_ifru.ifru_hwaddr.as_mut();
+            ifru_hwaddr.sa_family = libc::ARPHRD_ETHER;
+            for (i, b) in mac.get_bytes().iter().enumerate() {
+                ifru_hwaddr.sa_data[i] = *b as c_char;
+            }
+        }
+
+        // ioctl is safe. Called with a valid sock fd, and we check the return.
+        #[allow(clippy::cast_lossless)]
+        let ret =
+            unsafe { ioctl_with_ref(&sock, net_gen::sockios::SIOCSIFHWADDR as c_ulong, &ifreq) };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    /// Get the MTU of the tap interface.
+    pub fn get_mtu(&self) -> Result<u16> {
+        let sock = create_socket().map_err(Error::NetUtil)?;
+
+        let mut ifreq = self.get_ifreq();
+
+        // ioctl is safe. Called with a valid sock fd, and we check the return.
+        #[allow(clippy::cast_lossless)]
+        let ret = unsafe {
+            ioctl_with_mut_ref(&sock, net_gen::sockios::SIOCGIFMTU as c_ulong, &mut ifreq)
+        };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        // We only access one field of the ifru union, hence this is safe.
+        let mtu = unsafe { *ifreq.ifr_ifru.ifru_mtu.as_ref() };
+        Ok(mtu as u16)
+    }
+
+    /// Set the MTU of the tap interface, which requires CAP_NET_ADMIN unless
+    /// it's already the right one.
+    pub fn set_mtu(&self, mtu: u16) -> Result<()> {
+        if self.get_mtu()? == mtu {
+            return Ok(());
+        }
+
+        let sock = create_socket().map_err(Error::NetUtil)?;
+
+        let mut ifreq = self.get_ifreq();
+
+        // We only access one field of the ifru union, hence this is safe.
+        unsafe {
+            let ifru_mtu = ifreq.ifr_ifru.ifru_mtu.as_mut();
+            *ifru_mtu = c_int::from(mtu);
+        }
+
+        // ioctl is safe. Called with a valid sock fd, and we check the return.
+        #[allow(clippy::cast_lossless)]
+        let ret = unsafe { ioctl_with_ref(&sock, net_gen::sockios::SIOCSIFMTU as c_ulong, &ifreq) };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    /// Enable the tap interface. An interface already up, e.g. brought up by
+    /// whoever created it, is left alone, not requiring CAP_NET_ADMIN.
     pub fn enable(&self) -> Result<()> {
         let sock = create_socket().map_err(Error::NetUtil)?;
 
         let mut ifreq = self.get_ifreq();
 
+        // ioctl is safe. Called with a valid sock fd, and we check the return.
+        #[allow(clippy::cast_lossless)]
+        let ret = unsafe {
+            ioctl_with_mut_ref(&sock, net_gen::sockios::SIOCGIFFLAGS as c_ulong, &mut ifreq)
+        };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+        // We only access one field of the ifru union, hence this is safe.
+        let flags = unsafe { *ifreq.ifr_ifru.ifru_flags.as_ref() } as c_ushort as c_uint;
+        if flags & net_gen::net_device_flags_IFF_UP != 0 {
+            return Ok(());
+        }
+
+        let mut ifreq = self.get_ifreq();
+
         // We only access one field of the ifru union, hence this is safe.
         unsafe {
             let ifru_flags = ifreq.ifr_ifru.ifru_flags.as_mut();
@@ -455,6 +666,35 @@ mod tests {
         println!("created tap: {:?}", t);
     }
 
+    #[test]
+    fn test_tap_from_fd() {
+        let t = Tap::new(1).unwrap();
+        let t2 = Tap::from_tap_fd(t.as_raw_fd()).unwrap();
+        assert_eq!(
+            tap_name_to_string(&t),
+            String::from_utf8(t2.if_name).unwrap()
+        );
+
+        // Only taps are accepted.
+        let file = File::open("/dev/null").unwrap();
+        assert!(Tap::from_tap_fd(file.as_raw_fd()).is_err());
+    }
+
+    #[test]
+    fn test_tap_mac_addr() {
+        let t = Tap::new(1).unwrap();
+        let mac = super::MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        t.set_mac_addr(mac).unwrap();
+        assert_eq!(t.get_mac_addr().unwrap(), mac);
+    }
+
+    #[test]
+    fn test_tap_mtu() {
+        let t = Tap::new(1).unwrap();
+        t.set_mtu(9000).unwrap();
+        assert_eq!(t.get_mtu().unwrap(), 9000);
+    }
+
     #[test]
     fn test_tap_configure() {
         // This should be the first thing to be called inside the function, so everything else
@@ -0,0 +1,856 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! AF_XDP sockets, receiving the frames an XDP program redirects from a NIC
+//! queue and transmitting frames straight to it, bypassing most of the host
+//! network stack.
+//!
+//! The XDP program, along with the XSKMAP it redirects the frames to, has to
+//! be loaded beforehand (e.g. with `xdp-loader`), the map being pinned on a
+//! BPF filesystem. Each socket inserts itself in the map at the index of the
+//! NIC queue it's bound to.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{Error as IoError, Read, Result as IoResult, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use libc;
+
+const AF_XDP: libc::c_int = 44;
+const SOL_XDP: libc::c_int = 283;
+
+// Socket options, from <linux/if_xdp.h>.
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+
+// mmap() offsets of the rings.
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x8000_0000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x1_0000_0000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x1_8000_0000;
+
+// BPF commands, from <linux/bpf.h>.
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_OBJ_GET: libc::c_long = 7;
+
+// Each frame of the UMEM holds one packet, the ones received from the NIC
+// using the first half of the frames, the ones transmitted the second half.
+const FRAME_SIZE: u32 = 4096;
+const NUM_FRAMES: u32 = 4096;
+const RING_SIZE: u32 = NUM_FRAMES / 2;
+
+// Size of the virtio-net header including the num_buffers field.
+const VNET_HDR_LEN_MRG: usize = 12;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid interface name.
+    InvalidIfname,
+    /// The interface doesn't exist.
+    UnknownInterface(IoError),
+    /// Cannot create the AF_XDP socket.
+    CreateSocket(IoError),
+    /// Cannot allocate the memory shared with the kernel.
+    AllocateUmem(IoError),
+    /// Cannot register the memory shared with the kernel.
+    RegisterUmem(IoError),
+    /// Cannot set the size of a ring.
+    SetRingSize(IoError),
+    /// Cannot get the layout of the rings.
+    GetRingOffsets(IoError),
+    /// Cannot map a ring.
+    MapRing(IoError),
+    /// Cannot bind the socket to the NIC queue.
+    Bind(IoError),
+    /// Invalid XSKMAP path.
+    InvalidMapPath,
+    /// Cannot open the pinned XSKMAP.
+    OpenMap(IoError),
+    /// Cannot insert the socket in the XSKMAP.
+    UpdateMap(IoError),
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpUmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpRingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpMmapOffsets {
+    rx: XdpRingOffset,
+    tx: XdpRingOffset,
+    fr: XdpRingOffset,
+    cr: XdpRingOffset,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct SockaddrXdp {
+    sxdp_family: u16,
+    sxdp_flags: u16,
+    sxdp_ifindex: u32,
+    sxdp_queue_id: u32,
+    sxdp_shared_umem_fd: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+#[repr(C)]
+struct BpfObjGetAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+#[repr(C)]
+struct BpfMapUpdateAttr {
+    map_fd: u32,
+    pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+fn setsockopt<T>(fd: RawFd, name: libc::c_int, value: &T) -> IoResult<()> {
+    // Safe because the kernel only reads the value, of the given size.
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            SOL_XDP,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(())
+}
+
+fn bpf<T>(cmd: libc::c_long, attr: &T) -> IoResult<libc::c_long> {
+    // Safe because the kernel only reads the attributes, of the given size.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *const T,
+            mem::size_of::<T>() as libc::c_uint,
+        )
+    };
+    if ret < 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(ret)
+}
+
+// A ring shared with the kernel, which either produces or consumes its
+// entries, the other side being ours.
+struct Ring {
+    mmap: *mut libc::c_void,
+    mmap_len: usize,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    entries: *mut u8,
+    entry_size: usize,
+}
+
+impl Ring {
+    fn map(
+        fd: RawFd,
+        offsets: &XdpRingOffset,
+        entry_size: usize,
+        pgoff: libc::off_t,
+    ) -> IoResult<Ring> {
+        let mmap_len = offsets.desc as usize + RING_SIZE as usize * entry_size;
+        // Safe because we check the return value, the mapping being released
+        // on drop only.
+        let mmap = unsafe {
+            libc::mmap(
+                null_mut(),
+                mmap_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                pgoff,
+            )
+        };
+        if mmap == libc::MAP_FAILED {
+            return Err(IoError::last_os_error());
+        }
+
+        // Safe because the kernel gave the offsets within the mapping.
+        Ok(unsafe { Ring::new(mmap, mmap_len, offsets, entry_size) })
+    }
+
+    // Unsafe because the offsets must be within the `mmap_len` bytes mapped
+    // at `mmap`, the ring owning the mapping from then on.
+    unsafe fn new(
+        mmap: *mut libc::c_void,
+        mmap_len: usize,
+        offsets: &XdpRingOffset,
+        entry_size: usize,
+    ) -> Ring {
+        let base = mmap as *mut u8;
+        Ring {
+            mmap,
+            mmap_len,
+            producer: base.add(offsets.producer as usize) as *const AtomicU32,
+            consumer: base.add(offsets.consumer as usize) as *const AtomicU32,
+            entries: base.add(offsets.desc as usize),
+            entry_size,
+        }
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        // Safe because the pointer is within the mapping, which lives as long
+        // as the ring.
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        // Safe because the pointer is within the mapping, which lives as long
+        // as the ring.
+        unsafe { &*self.consumer }
+    }
+
+    fn entry<T>(&self, index: u32) -> *mut T {
+        // Safe because the index is masked to the number of entries.
+        unsafe {
+            self.entries
+                .add((index & (RING_SIZE - 1)) as usize * self.entry_size) as *mut T
+        }
+    }
+
+    // Number of entries we can produce.
+    fn free(&self) -> u32 {
+        let producer = self.producer().load(Ordering::Relaxed);
+        let consumer = self.consumer().load(Ordering::Acquire);
+        RING_SIZE - producer.wrapping_sub(consumer)
+    }
+
+    fn produce<T>(&self, value: T) {
+        let producer = self.producer().load(Ordering::Relaxed);
+        // Safe because the entry isn't the kernel's until published.
+        unsafe { self.entry::<T>(producer).write_volatile(value) };
+        self.producer()
+            .store(producer.wrapping_add(1), Ordering::Release);
+    }
+
+    fn consume<T: Copy>(&self) -> Option<T> {
+        let consumer = self.consumer().load(Ordering::Relaxed);
+        if self.producer().load(Ordering::Acquire) == consumer {
+            return None;
+        }
+        // Safe because the kernel published the entry.
+        let value = unsafe { self.entry::<T>(consumer).read_volatile() };
+        self.consumer()
+            .store(consumer.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        // Safe because the mapping is ours, and nothing points to it anymore.
+        unsafe { libc::munmap(self.mmap, self.mmap_len) };
+    }
+}
+
+struct XskInner {
+    rx: Ring,
+    tx: Ring,
+    fill: Ring,
+    completion: Ring,
+    umem: *mut u8,
+    // The frames available for transmitting.
+    tx_frames: Vec<u64>,
+    vnet_hdr_size: usize,
+    // Dropped last, once the rings and the UMEM are unmapped.
+    socket: File,
+}
+
+// Safe because the raw pointers point to the mappings owned by XskInner,
+// which is always accessed behind a Mutex.
+unsafe impl Send for XskInner {}
+
+impl XskInner {
+    fn frame(&mut self, addr: u64, len: usize) -> &mut [u8] {
+        // Safe because the kernel hands addresses within the UMEM, and the
+        // lengths are bounded to the frame size.
+        unsafe {
+            std::slice::from_raw_parts_mut(
+                self.umem.add(addr as usize),
+                std::cmp::min(len, (FRAME_SIZE - (addr as u32 % FRAME_SIZE)) as usize),
+            )
+        }
+    }
+
+    fn reclaim_tx_frames(&mut self) {
+        while let Some(addr) = self.completion.consume::<u64>() {
+            self.tx_frames.push(addr);
+        }
+    }
+
+    fn kick_tx(&self) -> IoResult<()> {
+        // Safe because no buffer is passed.
+        let ret = unsafe {
+            libc::sendto(
+                self.socket.as_raw_fd(),
+                null_mut(),
+                0,
+                libc::MSG_DONTWAIT,
+                null_mut(),
+                0,
+            )
+        };
+        if ret < 0 {
+            let e = IoError::last_os_error();
+            // The kernel is busy transmitting, or will get to it anyway.
+            match e.raw_os_error() {
+                Some(libc::EAGAIN) | Some(libc::EBUSY) | Some(libc::ENOBUFS) => {}
+                _ => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for XskInner {
+    fn drop(&mut self) {
+        // Safe because the UMEM is ours, the kernel holding its own reference
+        // to the pages until the socket is closed.
+        unsafe {
+            libc::munmap(
+                self.umem as *mut libc::c_void,
+                (NUM_FRAMES * FRAME_SIZE) as usize,
+            )
+        };
+    }
+}
+
+/// Handle for an AF_XDP socket bound to a NIC queue.
+///
+/// The frames read from or written to it start with the virtio-net header,
+/// as with a tap, though no offload is supported: the header of a received
+/// frame is always empty, and the header of a transmitted one is ignored.
+/// Clones share the same socket.
+#[derive(Clone)]
+pub struct Xsk {
+    fd: RawFd,
+    inner: Arc<Mutex<XskInner>>,
+}
+
+impl Xsk {
+    /// Creates an AF_XDP socket bound to the queue `queue_id` of the
+    /// interface `if_name`, inserting it in the XSKMAP pinned at `xsk_map`.
+    pub fn new(if_name: &str, queue_id: u32, xsk_map: &Path) -> Result<Xsk> {
+        let if_name = CString::new(if_name).map_err(|_| Error::InvalidIfname)?;
+        // Safe because the name is a valid C string.
+        let ifindex = unsafe { libc::if_nametoindex(if_name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(Error::UnknownInterface(IoError::last_os_error()));
+        }
+
+        // Safe because we check the return value, the fd being owned by the
+        // File from then on.
+        let fd = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(Error::CreateSocket(IoError::last_os_error()));
+        }
+        let socket = unsafe { File::from_raw_fd(fd) };
+
+        let umem_len = (NUM_FRAMES * FRAME_SIZE) as usize;
+        // Safe because we check the return value, the mapping being released
+        // when XskInner is dropped.
+        let umem = unsafe {
+            libc::mmap(
+                null_mut(),
+                umem_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE,
+                -1,
+                0,
+            )
+        };
+        if umem == libc::MAP_FAILED {
+            return Err(Error::AllocateUmem(IoError::last_os_error()));
+        }
+
+        let reg = XdpUmemReg {
+            addr: umem as u64,
+            len: umem_len as u64,
+            chunk_size: FRAME_SIZE,
+            ..Default::default()
+        };
+        let rings = setsockopt(fd, XDP_UMEM_REG, &reg)
+            .map_err(Error::RegisterUmem)
+            .and_then(|_| {
+                for ring in &[
+                    XDP_UMEM_FILL_RING,
+                    XDP_UMEM_COMPLETION_RING,
+                    XDP_RX_RING,
+                    XDP_TX_RING,
+                ] {
+                    setsockopt(fd, *ring, &RING_SIZE).map_err(Error::SetRingSize)?;
+                }
+
+                let mut offsets = XdpMmapOffsets::default();
+                let mut len = mem::size_of::<XdpMmapOffsets>() as libc::socklen_t;
+                // Safe because the kernel writes at most len bytes, and we
+                // check the return value.
+                let ret = unsafe {
+                    libc::getsockopt(
+                        fd,
+                        SOL_XDP,
+                        XDP_MMAP_OFFSETS,
+                        &mut offsets as *mut XdpMmapOffsets as *mut libc::c_void,
+                        &mut len,
+                    )
+                };
+                if ret < 0 {
+                    return Err(Error::GetRingOffsets(IoError::last_os_error()));
+                }
+                if len as usize != mem::size_of::<XdpMmapOffsets>() {
+                    // Kernels older than 5.4 lack the ring flags.
+                    return Err(Error::GetRingOffsets(IoError::from_raw_os_error(
+                        libc::EOPNOTSUPP,
+                    )));
+                }
+
+                let desc_size = mem::size_of::<XdpDesc>();
+                let addr_size = mem::size_of::<u64>();
+                Ok((
+                    Ring::map(fd, &offsets.rx, desc_size, XDP_PGOFF_RX_RING)
+                        .map_err(Error::MapRing)?,
+                    Ring::map(fd, &offsets.tx, desc_size, XDP_PGOFF_TX_RING)
+                        .map_err(Error::MapRing)?,
+                    Ring::map(fd, &offsets.fr, addr_size, XDP_UMEM_PGOFF_FILL_RING)
+                        .map_err(Error::MapRing)?,
+                    Ring::map(fd, &offsets.cr, addr_size, XDP_UMEM_PGOFF_COMPLETION_RING)
+                        .map_err(Error::MapRing)?,
+                ))
+            });
+        let (rx, tx, fill, completion) = match rings {
+            Ok(rings) => rings,
+            Err(e) => {
+                // Safe because nothing points to the UMEM.
+                unsafe { libc::munmap(umem, umem_len) };
+                return Err(e);
+            }
+        };
+
+        let inner = XskInner {
+            rx,
+            tx,
+            fill,
+            completion,
+            umem: umem as *mut u8,
+            tx_frames: (RING_SIZE..NUM_FRAMES)
+                .map(|frame| u64::from(frame * FRAME_SIZE))
+                .collect(),
+            vnet_hdr_size: VNET_HDR_LEN_MRG,
+            socket,
+        };
+        // The kernel receives in the first half of the frames.
+        for frame in 0..RING_SIZE {
+            inner.fill.produce(u64::from(frame * FRAME_SIZE));
+        }
+
+        // The kernel picks zero copy if the NIC driver supports it, and
+        // copies the frames otherwise.
+        let addr = SockaddrXdp {
+            sxdp_family: AF_XDP as u16,
+            sxdp_ifindex: ifindex,
+            sxdp_queue_id: queue_id,
+            ..Default::default()
+        };
+        // Safe because we check the return value.
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const SockaddrXdp as *const libc::sockaddr,
+                mem::size_of::<SockaddrXdp>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::Bind(IoError::last_os_error()));
+        }
+
+        let path =
+            CString::new(xsk_map.as_os_str().as_bytes()).map_err(|_| Error::InvalidMapPath)?;
+        let map_fd = bpf(
+            BPF_OBJ_GET,
+            &BpfObjGetAttr {
+                pathname: path.as_ptr() as u64,
+                bpf_fd: 0,
+                file_flags: 0,
+            },
+        )
+        .map_err(Error::OpenMap)?;
+        // Safe because the fd was just returned, the File owning it from then
+        // on.
+        let map = unsafe { File::from_raw_fd(map_fd as RawFd) };
+        // The socket is removed from the map when closed.
+        let value = fd as u32;
+        bpf(
+            BPF_MAP_UPDATE_ELEM,
+            &BpfMapUpdateAttr {
+                map_fd: map.as_raw_fd() as u32,
+                pad: 0,
+                key: &queue_id as *const u32 as u64,
+                value: &value as *const u32 as u64,
+                flags: 0,
+            },
+        )
+        .map_err(Error::UpdateMap)?;
+
+        Ok(Xsk {
+            fd,
+            inner: Arc::new(Mutex::new(inner)),
+        })
+    }
+
+    /// Sets the size of the virtio-net header the frames start with.
+    pub fn set_vnet_hdr_size(&self, size: usize) {
+        self.inner.lock().unwrap().vnet_hdr_size = size;
+    }
+}
+
+impl Read for Xsk {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        let hdr_size = inner.vnet_hdr_size;
+        let desc = match inner.rx.consume::<XdpDesc>() {
+            Some(desc) => desc,
+            None => return Err(IoError::from_raw_os_error(libc::EAGAIN)),
+        };
+
+        let frame_len = desc.len as usize;
+        let count = std::cmp::min(hdr_size + frame_len, buf.len());
+        for byte in buf[..hdr_size].iter_mut() {
+            *byte = 0;
+        }
+        if hdr_size >= VNET_HDR_LEN_MRG {
+            // num_buffers
+            buf[10] = 1;
+        }
+        let frame = inner.frame(desc.addr, frame_len);
+        buf[hdr_size..count].copy_from_slice(&frame[..count - hdr_size]);
+
+        // The frame goes back to the kernel, which can't hold more than the
+        // ring size, so there is always room for it.
+        inner
+            .fill
+            .produce(desc.addr - desc.addr % u64::from(FRAME_SIZE));
+
+        Ok(count)
+    }
+}
+
+impl Write for Xsk {
+    // The frame is only queued, the kernel sending it on flush(), or once
+    // there is no frame left.
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        let hdr_size = inner.vnet_hdr_size;
+        if buf.len() < hdr_size || buf.len() - hdr_size > FRAME_SIZE as usize {
+            return Err(IoError::from_raw_os_error(libc::EMSGSIZE));
+        }
+
+        inner.reclaim_tx_frames();
+        if inner.tx_frames.is_empty() || inner.tx.free() == 0 {
+            inner.kick_tx()?;
+            inner.reclaim_tx_frames();
+        }
+        let addr = match inner.tx_frames.pop() {
+            Some(addr) if inner.tx.free() > 0 => addr,
+            Some(addr) => {
+                inner.tx_frames.push(addr);
+                return Err(IoError::from_raw_os_error(libc::EAGAIN));
+            }
+            None => return Err(IoError::from_raw_os_error(libc::EAGAIN)),
+        };
+
+        let len = buf.len() - hdr_size;
+        inner.frame(addr, len).copy_from_slice(&buf[hdr_size..]);
+        inner.tx.produce(XdpDesc {
+            addr,
+            len: len as u32,
+            options: 0,
+        });
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        let inner = self.inner.lock().unwrap();
+        if inner.tx.free() < RING_SIZE {
+            inner.kick_tx()?;
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for Xsk {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl std::fmt::Debug for Xsk {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Xsk").field("fd", &self.fd).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A ring in anonymous memory, its indexes starting at `start`, for the
+    // test to play the kernel side.
+    fn ring(entry_size: usize, start: u32) -> Ring {
+        let offsets = XdpRingOffset {
+            producer: 0,
+            consumer: 64,
+            desc: 128,
+            flags: 0,
+        };
+        let mmap_len = offsets.desc as usize + RING_SIZE as usize * entry_size;
+        // Safe because we check the return value.
+        let mmap = unsafe {
+            libc::mmap(
+                null_mut(),
+                mmap_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(mmap, libc::MAP_FAILED);
+        // Safe because the offsets are within the mapping.
+        let ring = unsafe { Ring::new(mmap, mmap_len, &offsets, entry_size) };
+        ring.producer().store(start, Ordering::Relaxed);
+        ring.consumer().store(start, Ordering::Relaxed);
+        ring
+    }
+
+    // A socket whose rings start at `start`, as the real one is set up
+    // except for the kernel side.
+    fn xsk(start: u32) -> Xsk {
+        let umem_len = (NUM_FRAMES * FRAME_SIZE) as usize;
+        // Safe because we check the return value.
+        let umem = unsafe {
+            libc::mmap(
+                null_mut(),
+                umem_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(umem, libc::MAP_FAILED);
+
+        let desc_size = mem::size_of::<XdpDesc>();
+        let addr_size = mem::size_of::<u64>();
+        let socket = File::open("/dev/null").unwrap();
+        Xsk {
+            fd: socket.as_raw_fd(),
+            inner: Arc::new(Mutex::new(XskInner {
+                rx: ring(desc_size, start),
+                tx: ring(desc_size, start),
+                fill: ring(addr_size, start),
+                completion: ring(addr_size, start),
+                umem: umem as *mut u8,
+                tx_frames: (RING_SIZE..NUM_FRAMES)
+                    .map(|frame| u64::from(frame * FRAME_SIZE))
+                    .collect(),
+                vnet_hdr_size: VNET_HDR_LEN_MRG,
+                socket,
+            })),
+        }
+    }
+
+    #[test]
+    fn test_ring_wraparound() {
+        let start = u32::MAX - 2;
+        let ring = ring(mem::size_of::<u64>(), start);
+        assert_eq!(ring.free(), RING_SIZE);
+
+        // The indexes wrap around past u32::MAX, the entries past the last
+        // slot of the ring.
+        for value in 0..5u64 {
+            ring.produce(value);
+        }
+        assert_eq!(ring.producer().load(Ordering::Relaxed), 2);
+        assert_eq!(ring.free(), RING_SIZE - 5);
+        assert_eq!(ring.entry::<u64>(start), ring.entry::<u64>(RING_SIZE - 3));
+        assert_eq!(ring.entry::<u64>(0), ring.entry::<u64>(RING_SIZE));
+        for value in 0..5u64 {
+            assert_eq!(ring.consume::<u64>(), Some(value));
+        }
+        assert_eq!(ring.consume::<u64>(), None);
+        assert_eq!(ring.free(), RING_SIZE);
+
+        // A full ring across the wraparound.
+        for value in 0..u64::from(RING_SIZE) {
+            ring.produce(value);
+        }
+        assert_eq!(ring.free(), 0);
+        assert_eq!(ring.consume::<u64>(), Some(0));
+        assert_eq!(ring.free(), 1);
+    }
+
+    #[test]
+    fn test_read_frames() {
+        let start = u32::MAX - 1;
+        let mut xsk = xsk(start);
+        let payload: Vec<u8> = (0..100).collect();
+
+        // The kernel received the frames at some headroom into frames 3
+        // to 5, the indexes of the rx ring wrapping around in between.
+        for frame in 3..6u64 {
+            let inner = xsk.inner.lock().unwrap();
+            let addr = frame * u64::from(FRAME_SIZE) + 256;
+            // Safe because the address is within the UMEM.
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    payload.as_ptr(),
+                    inner.umem.add(addr as usize),
+                    payload.len(),
+                )
+            };
+            inner.rx.produce(XdpDesc {
+                addr,
+                len: payload.len() as u32,
+                options: 0,
+            });
+        }
+
+        let mut buf = [0xffu8; 256];
+        assert_eq!(xsk.read(&mut buf).unwrap(), VNET_HDR_LEN_MRG + 100);
+        // An empty header, but for num_buffers.
+        assert_eq!(
+            &buf[..VNET_HDR_LEN_MRG],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0]
+        );
+        assert_eq!(&buf[VNET_HDR_LEN_MRG..VNET_HDR_LEN_MRG + 100], &payload[..]);
+
+        // Without num_buffers in the header.
+        xsk.set_vnet_hdr_size(10);
+        let mut buf = [0xffu8; 256];
+        assert_eq!(xsk.read(&mut buf).unwrap(), 110);
+        assert_eq!(&buf[..10], &[0; 10]);
+        assert_eq!(&buf[10..110], &payload[..]);
+
+        // Truncated to the buffer.
+        let mut buf = [0xffu8; 50];
+        assert_eq!(xsk.read(&mut buf).unwrap(), 50);
+        assert_eq!(&buf[10..], &payload[..40]);
+
+        assert_eq!(
+            xsk.read(&mut buf).unwrap_err().raw_os_error(),
+            Some(libc::EAGAIN)
+        );
+
+        // The frames went back to the kernel, without the headroom.
+        let inner = xsk.inner.lock().unwrap();
+        assert_eq!(inner.rx.consumer().load(Ordering::Relaxed), 1);
+        for frame in 3..6u64 {
+            assert_eq!(
+                inner.fill.consume::<u64>(),
+                Some(frame * u64::from(FRAME_SIZE))
+            );
+        }
+        assert_eq!(inner.fill.consume::<u64>(), None);
+    }
+
+    #[test]
+    fn test_write_frames() {
+        let start = u32::MAX;
+        let mut xsk = xsk(start);
+        let mut buf = vec![0xffu8; VNET_HDR_LEN_MRG];
+        buf.extend(0..100u8);
+
+        // The header is left out of the frame.
+        assert_eq!(xsk.write(&buf).unwrap(), buf.len());
+        assert_eq!(xsk.write(&buf).unwrap(), buf.len());
+        let last_frame = u64::from((NUM_FRAMES - 1) * FRAME_SIZE);
+        {
+            let mut inner = xsk.inner.lock().unwrap();
+            // The tx ring wrapped around.
+            assert_eq!(inner.tx.producer().load(Ordering::Relaxed), 1);
+            let desc = inner.tx.consume::<XdpDesc>().unwrap();
+            assert_eq!(desc.addr, last_frame);
+            assert_eq!(desc.len, 100);
+            assert_eq!(inner.frame(desc.addr, 100), &buf[VNET_HDR_LEN_MRG..]);
+            let desc = inner.tx.consume::<XdpDesc>().unwrap();
+            assert_eq!(desc.addr, last_frame - u64::from(FRAME_SIZE));
+            assert!(inner.tx.consume::<XdpDesc>().is_none());
+
+            // The kernel sent the first frame.
+            inner.completion.produce(last_frame);
+        }
+
+        // The frame the kernel completed is used again.
+        assert_eq!(xsk.write(&buf).unwrap(), buf.len());
+        {
+            let inner = xsk.inner.lock().unwrap();
+            assert_eq!(inner.completion.consumer().load(Ordering::Relaxed), 0);
+            assert_eq!(inner.tx.consume::<XdpDesc>().unwrap().addr, last_frame);
+        }
+
+        // Shorter than the header, or longer than a frame.
+        assert_eq!(
+            xsk.write(&buf[..VNET_HDR_LEN_MRG - 1])
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EMSGSIZE)
+        );
+        let buf = vec![0u8; VNET_HDR_LEN_MRG + FRAME_SIZE as usize + 1];
+        assert_eq!(
+            xsk.write(&buf).unwrap_err().raw_os_error(),
+            Some(libc::EMSGSIZE)
+        );
+    }
+}
@@ -0,0 +1,1520 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::{Error, Result};
+use devices::BusDevice;
+use pci::{
+    BarReprogrammingParams, MsixCap, MsixConfig, PciBarConfiguration, PciBarRegionType,
+    PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciHeaderType,
+    PciMassStorageSubclass, PciProgrammingInterface,
+};
+use std::any::Any;
+use std::cmp;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::result;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use vm_allocator::SystemAllocator;
+use vm_device::interrupt::{
+    InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqGroupConfig,
+};
+use vm_memory::{
+    Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
+    GuestMemoryMmap, GuestUsize,
+};
+use vm_virtio::DiskFile;
+use vmm_sys_util::eventfd::EventFd;
+
+// Intel vendor ID, along with the device ID QEMU uses for its emulated NVMe
+// controller, which guest drivers already know about.
+const NVME_VENDOR_ID: u16 = 0x8086;
+const NVME_DEVICE_ID: u16 = 0x5845;
+
+// The controller registers, the doorbells, the MSI-X table and the MSI-X PBA
+// each get their own page of BAR 0.
+const NVME_BAR_SIZE: u64 = 0x4000;
+const REGISTERS_SIZE: u64 = 0x1000;
+const DOORBELLS_BAR_OFFSET: u64 = 0x1000;
+const DOORBELLS_SIZE: u64 = 0x1000;
+const MSIX_TABLE_BAR_OFFSET: u64 = 0x2000;
+const MSIX_TABLE_SIZE: u64 = 0x1000;
+const MSIX_PBA_BAR_OFFSET: u64 = 0x3000;
+const MSIX_PBA_SIZE: u64 = 0x1000;
+
+// The MSI-X table page holds up to 256 vectors, one being used by the admin
+// queue.
+const MAX_IO_QUEUES: u16 = 255;
+
+// Controller registers, see the NVMe 1.2 specification.
+const REG_CAP: u64 = 0x00;
+const REG_VS: u64 = 0x08;
+const REG_CC: u64 = 0x14;
+const REG_CSTS: u64 = 0x1c;
+const REG_AQA: u64 = 0x24;
+const REG_ASQ: u64 = 0x28;
+const REG_ACQ: u64 = 0x30;
+
+const NVME_VERSION: u32 = 0x0001_0200;
+
+// Physically contiguous queues, an 8 seconds enable timeout, and the NVM
+// command set. Only 4 KiB memory pages are supported.
+const CAP_CQR: u64 = 1 << 16;
+const CAP_TO: u64 = 0x10 << 24;
+const CAP_CSS_NVM: u64 = 1 << 37;
+
+const CC_EN: u32 = 1;
+const CC_SHN_SHIFT: u32 = 14;
+const CC_SHN_MASK: u32 = 0x3;
+const CSTS_RDY: u32 = 1;
+const CSTS_CFS: u32 = 1 << 1;
+const CSTS_SHST_MASK: u32 = 0x3 << 2;
+const CSTS_SHST_COMPLETE: u32 = 0x2 << 2;
+
+const PAGE_SIZE: u64 = 0x1000;
+// Transfers are limited to 2^MDTS memory pages.
+const MDTS: u8 = 8;
+const MAX_TRANSFER_SIZE: u64 = PAGE_SIZE << MDTS;
+
+const SECTOR_SHIFT: u64 = 9;
+
+// The one and only namespace.
+const NSID: u32 = 1;
+const NSID_ALL: u32 = 0xffff_ffff;
+
+const SQ_ENTRY_SIZE: u64 = 64;
+const CQ_ENTRY_SIZE: u64 = 16;
+const IDENTIFY_DATA_SIZE: usize = 4096;
+
+// Admin command set opcodes.
+const ADMIN_DELETE_SQ: u8 = 0x00;
+const ADMIN_CREATE_SQ: u8 = 0x01;
+const ADMIN_GET_LOG_PAGE: u8 = 0x02;
+const ADMIN_DELETE_CQ: u8 = 0x04;
+const ADMIN_CREATE_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_ABORT: u8 = 0x08;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+const ADMIN_GET_FEATURES: u8 = 0x0a;
+const ADMIN_ASYNC_EVENT_REQUEST: u8 = 0x0c;
+
+// NVM command set opcodes.
+const NVM_FLUSH: u8 = 0x00;
+const NVM_WRITE: u8 = 0x01;
+const NVM_READ: u8 = 0x02;
+
+const IDENTIFY_CNS_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CNS_CONTROLLER: u32 = 0x01;
+const IDENTIFY_CNS_ACTIVE_NAMESPACES: u32 = 0x02;
+
+const FEATURE_VOLATILE_WRITE_CACHE: u32 = 0x06;
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+const FEATURE_ASYNC_EVENT_CONFIG: u32 = 0x0b;
+
+// Status codes, along with their status code type in the upper byte.
+const SC_INVALID_OPCODE: u16 = 0x001;
+const SC_INVALID_FIELD: u16 = 0x002;
+const SC_DATA_TRANSFER_ERROR: u16 = 0x004;
+const SC_INTERNAL_ERROR: u16 = 0x006;
+const SC_INVALID_NAMESPACE: u16 = 0x00b;
+const SC_LBA_OUT_OF_RANGE: u16 = 0x080;
+const SC_CQ_INVALID: u16 = 0x100;
+const SC_INVALID_QID: u16 = 0x101;
+const SC_INVALID_QSIZE: u16 = 0x102;
+const SC_INVALID_INT_VECTOR: u16 = 0x108;
+const SC_INVALID_QUEUE_DELETION: u16 = 0x10c;
+const SC_WRITE_TO_READ_ONLY: u16 = 0x182;
+
+// Do Not Retry bit of the completion status field.
+const STATUS_DNR: u16 = 1 << 15;
+
+// Commands complete with either their command specific result, or a status
+// code.
+type CommandResult = result::Result<u32, u16>;
+
+#[derive(Copy, Clone)]
+enum PciNvmProgrammingInterface {
+    Nvme = 0x02,
+}
+
+impl PciProgrammingInterface for PciNvmProgrammingInterface {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+// Submission queue entry.
+#[allow(dead_code)]
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct Command {
+    cdw0: u32,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    mptr: u64,
+    prp1: u64,
+    prp2: u64,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+}
+
+unsafe impl ByteValued for Command {}
+
+impl Command {
+    fn opcode(&self) -> u8 {
+        self.cdw0 as u8
+    }
+
+    fn cid(&self) -> u16 {
+        (self.cdw0 >> 16) as u16
+    }
+}
+
+// Completion queue entry.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct Completion {
+    dw0: u32,
+    dw1: u32,
+    sq_head: u16,
+    sq_id: u16,
+    cid: u16,
+    status: u16,
+}
+
+unsafe impl ByteValued for Completion {}
+
+#[derive(Copy, Clone)]
+struct SubmissionQueue {
+    addr: GuestAddress,
+    size: u16,
+    head: u16,
+    tail: u16,
+    cqid: u16,
+}
+
+#[derive(Copy, Clone)]
+struct CompletionQueue {
+    addr: GuestAddress,
+    size: u16,
+    head: u16,
+    tail: u16,
+    phase: bool,
+    vector: u16,
+    irq_enabled: bool,
+}
+
+impl CompletionQueue {
+    fn is_full(&self) -> bool {
+        (u32::from(self.tail) + 1) % u32::from(self.size) == u32::from(self.head)
+    }
+}
+
+// Update the bytes of `register` covered by `data`, `offset` being relative
+// to the start of the register.
+fn write_register_bytes(register: &mut u64, offset: u64, data: &[u8]) {
+    let mut bytes = register.to_le_bytes();
+    let offset = offset as usize;
+    if offset + data.len() > bytes.len() {
+        return;
+    }
+    bytes[offset..offset + data.len()].copy_from_slice(data);
+    *register = u64::from_le_bytes(bytes);
+}
+
+// Identify strings are ASCII, padded with spaces.
+fn copy_ascii(dst: &mut [u8], src: &[u8]) {
+    for (i, b) in dst.iter_mut().enumerate() {
+        *b = match src.get(i) {
+            Some(c) if *c != 0 => *c,
+            _ => b' ',
+        };
+    }
+}
+
+// Split a transfer of `len` bytes into the guest memory segments described
+// by the PRP entries of a command. When the transfer spans more than two
+// memory pages, PRP2 points to a list of entries, the last entry of each
+// list page chaining to the next one.
+fn prp_segments(
+    mem: &GuestMemoryMmap,
+    prp1: u64,
+    prp2: u64,
+    len: u64,
+) -> result::Result<Vec<(GuestAddress, usize)>, u16> {
+    let mut segments = Vec::new();
+
+    // Only the first entry can point in the middle of a page.
+    let first_len = cmp::min(len, PAGE_SIZE - (prp1 & (PAGE_SIZE - 1)));
+    segments.push((GuestAddress(prp1), first_len as usize));
+    let mut remaining = len - first_len;
+
+    if remaining == 0 {
+        return Ok(segments);
+    }
+    if remaining <= PAGE_SIZE {
+        segments.push((GuestAddress(prp2), remaining as usize));
+        return Ok(segments);
+    }
+
+    let mut list_addr = prp2;
+    while remaining > 0 {
+        let entries = (PAGE_SIZE - (list_addr & (PAGE_SIZE - 1))) / 8;
+        // A list page must describe at least one memory page besides the
+        // pointer to the next list page.
+        if list_addr & 0x7 != 0 || (entries < 2 && remaining > PAGE_SIZE) {
+            return Err(SC_DATA_TRANSFER_ERROR);
+        }
+
+        for i in 0..entries {
+            let entry: u64 = mem
+                .read_obj(GuestAddress(list_addr + i * 8))
+                .map_err(|_| SC_DATA_TRANSFER_ERROR)?;
+
+            if i == entries - 1 && remaining > PAGE_SIZE {
+                list_addr = entry;
+                break;
+            }
+
+            let segment_len = cmp::min(remaining, PAGE_SIZE);
+            segments.push((GuestAddress(entry), segment_len as usize));
+            remaining -= segment_len;
+            if remaining == 0 {
+                break;
+            }
+        }
+    }
+
+    Ok(segments)
+}
+
+// Copy `data` to the guest memory the PRP entries of a command point to.
+fn write_prp_data(mem: &GuestMemoryMmap, cmd: &Command, data: &[u8]) -> result::Result<(), u16> {
+    let mut offset = 0;
+    for (addr, len) in prp_segments(mem, cmd.prp1, cmd.prp2, data.len() as u64)? {
+        mem.write_slice(&data[offset..offset + len], addr)
+            .map_err(|_| SC_DATA_TRANSFER_ERROR)?;
+        offset += len;
+    }
+
+    Ok(())
+}
+
+// Epoll events of the worker thread.
+const DOORBELL_EVENT: u64 = 1;
+const KILL_EVENT: u64 = 2;
+
+// The controller state, shared by the vCPUs accessing the registers and the
+// worker thread processing the queues.
+struct Controller<T: DiskFile> {
+    msix_config: Arc<Mutex<MsixConfig>>,
+    msix_num: u16,
+    interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+    memory: GuestMemoryAtomic<GuestMemoryMmap>,
+    disk: T,
+    disk_nsectors: u64,
+    readonly: bool,
+    serial: Vec<u8>,
+    num_io_queues: u16,
+    max_queue_size: u16,
+    cc: u32,
+    csts: u32,
+    aqa: u32,
+    asq: u64,
+    acq: u64,
+    // Indexed by queue ID, the admin queues being the first ones.
+    sqs: Vec<Option<SubmissionQueue>>,
+    cqs: Vec<Option<CompletionQueue>>,
+}
+
+impl<T: DiskFile> Controller<T> {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        msix_config: Arc<Mutex<MsixConfig>>,
+        interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
+        memory: GuestMemoryAtomic<GuestMemoryMmap>,
+        mut disk: T,
+        serial: Vec<u8>,
+        readonly: bool,
+        num_io_queues: u16,
+        max_queue_size: u16,
+    ) -> Result<Self> {
+        let disk_size = disk.seek(SeekFrom::End(0)).map_err(Error::DiskSize)?;
+        if disk_size % (1 << SECTOR_SHIFT) != 0 {
+            warn!(
+                "Disk size {} is not a multiple of sector size {}; \
+                 the remainder will not be visible to the guest.",
+                disk_size,
+                1 << SECTOR_SHIFT
+            );
+        }
+
+        Ok(Controller {
+            msix_config,
+            // One vector for the admin queue, and one for each I/O queue.
+            msix_num: num_io_queues + 1,
+            interrupt_source_group,
+            memory,
+            disk,
+            disk_nsectors: disk_size >> SECTOR_SHIFT,
+            readonly,
+            serial,
+            num_io_queues,
+            max_queue_size,
+            cc: 0,
+            csts: 0,
+            aqa: 0,
+            asq: 0,
+            acq: 0,
+            sqs: vec![None; num_io_queues as usize + 1],
+            cqs: vec![None; num_io_queues as usize + 1],
+        })
+    }
+
+    fn cap(&self) -> u64 {
+        u64::from(self.max_queue_size - 1) | CAP_CQR | CAP_TO | CAP_CSS_NVM
+    }
+
+    fn read_register(&self, offset: u64, data: &mut [u8]) {
+        let (reg, value) = match offset {
+            o if REG_CAP <= o && o < REG_CAP + 8 => (REG_CAP, self.cap()),
+            o if REG_VS <= o && o < REG_VS + 4 => (REG_VS, u64::from(NVME_VERSION)),
+            o if REG_CC <= o && o < REG_CC + 4 => (REG_CC, u64::from(self.cc)),
+            o if REG_CSTS <= o && o < REG_CSTS + 4 => (REG_CSTS, u64::from(self.csts)),
+            o if REG_AQA <= o && o < REG_AQA + 4 => (REG_AQA, u64::from(self.aqa)),
+            o if REG_ASQ <= o && o < REG_ASQ + 8 => (REG_ASQ, self.asq),
+            o if REG_ACQ <= o && o < REG_ACQ + 8 => (REG_ACQ, self.acq),
+            _ => (offset, 0),
+        };
+
+        let bytes = value.to_le_bytes();
+        let start = (offset - reg) as usize;
+        let end = cmp::min(bytes.len(), start + data.len());
+        data[..end - start].copy_from_slice(&bytes[start..end]);
+    }
+
+    fn write_register(&mut self, offset: u64, data: &[u8]) {
+        match offset {
+            o if REG_CC <= o && o < REG_CC + 4 => {
+                let mut cc = u64::from(self.cc);
+                write_register_bytes(&mut cc, o - REG_CC, data);
+                self.write_cc(cc as u32);
+            }
+            o if REG_AQA <= o && o < REG_AQA + 4 => {
+                let mut aqa = u64::from(self.aqa);
+                write_register_bytes(&mut aqa, o - REG_AQA, data);
+                self.aqa = aqa as u32;
+            }
+            o if REG_ASQ <= o && o < REG_ASQ + 8 => {
+                write_register_bytes(&mut self.asq, o - REG_ASQ, data)
+            }
+            o if REG_ACQ <= o && o < REG_ACQ + 8 => {
+                write_register_bytes(&mut self.acq, o - REG_ACQ, data)
+            }
+            _ => debug!("Ignoring NVMe register write at 0x{:x}", offset),
+        }
+    }
+
+    fn write_cc(&mut self, cc: u32) {
+        let old_cc = self.cc;
+        self.cc = cc;
+
+        if cc & CC_EN != 0 && old_cc & CC_EN == 0 {
+            self.enable();
+        } else if cc & CC_EN == 0 && old_cc & CC_EN != 0 {
+            self.reset();
+        }
+
+        let shn = (cc >> CC_SHN_SHIFT) & CC_SHN_MASK;
+        let old_shn = (old_cc >> CC_SHN_SHIFT) & CC_SHN_MASK;
+        if shn != 0 && old_shn == 0 {
+            // Whatever the disk image may be caching is written back before
+            // the shutdown is reported as complete.
+            if let Err(e) = self.disk.flush() {
+                error!("Failed to flush NVMe disk on shutdown: {:?}", e);
+            }
+            self.csts = (self.csts & !CSTS_SHST_MASK) | CSTS_SHST_COMPLETE;
+        } else if shn == 0 {
+            self.csts &= !CSTS_SHST_MASK;
+        }
+    }
+
+    fn enable(&mut self) {
+        // Queue sizes are 0's based.
+        let asqs = (self.aqa & 0xfff) as u16 + 1;
+        let acqs = ((self.aqa >> 16) & 0xfff) as u16 + 1;
+        let asq = self.asq & !(PAGE_SIZE - 1);
+        let acq = self.acq & !(PAGE_SIZE - 1);
+
+        if asqs < 2 || acqs < 2 || asq == 0 || acq == 0 {
+            error!("Invalid NVMe admin queues configuration");
+            self.csts |= CSTS_CFS;
+            return;
+        }
+
+        self.sqs[0] = Some(SubmissionQueue {
+            addr: GuestAddress(asq),
+            size: asqs,
+            head: 0,
+            tail: 0,
+            cqid: 0,
+        });
+        self.cqs[0] = Some(CompletionQueue {
+            addr: GuestAddress(acq),
+            size: acqs,
+            head: 0,
+            tail: 0,
+            phase: true,
+            vector: 0,
+            irq_enabled: true,
+        });
+
+        self.csts |= CSTS_RDY;
+    }
+
+    fn reset(&mut self) {
+        for sq in self.sqs.iter_mut() {
+            *sq = None;
+        }
+        for cq in self.cqs.iter_mut() {
+            *cq = None;
+        }
+
+        self.csts = 0;
+    }
+
+    fn write_doorbell(&mut self, offset: u64, value: u32) {
+        if self.csts & CSTS_RDY == 0 {
+            return;
+        }
+
+        // Each queue pair has a submission queue tail doorbell, followed by
+        // a completion queue head doorbell.
+        let index = (offset / 4) as usize;
+        let qid = index / 2;
+        if qid >= self.sqs.len() {
+            return;
+        }
+
+        if index % 2 == 0 {
+            match self.sqs[qid].as_mut() {
+                Some(sq) if value < u32::from(sq.size) => sq.tail = value as u16,
+                _ => {
+                    warn!("Invalid NVMe submission queue {} doorbell", qid);
+                    return;
+                }
+            }
+
+            self.process_submission_queue(qid);
+        } else {
+            match self.cqs[qid].as_mut() {
+                Some(cq) if value < u32::from(cq.size) => cq.head = value as u16,
+                _ => {
+                    warn!("Invalid NVMe completion queue {} doorbell", qid);
+                    return;
+                }
+            }
+
+            // The submission queues waiting for room in this completion
+            // queue can move forward.
+            for sqid in 0..self.sqs.len() {
+                if self.sqs[sqid].map_or(false, |sq| sq.cqid as usize == qid) {
+                    self.process_submission_queue(sqid);
+                }
+            }
+        }
+    }
+
+    fn process_submission_queue(&mut self, sqid: usize) {
+        let mem = self.memory.memory();
+        let mut used_cqid = None;
+
+        loop {
+            let sq = match self.sqs[sqid] {
+                Some(sq) if sq.head != sq.tail => sq,
+                _ => break,
+            };
+
+            // Commands are left in the submission queue until the guest
+            // makes room in the completion queue.
+            let cqid = sq.cqid as usize;
+            match self.cqs[cqid] {
+                Some(cq) if !cq.is_full() => (),
+                _ => break,
+            }
+
+            let cmd: Command =
+                match mem.read_obj(sq.addr.unchecked_add(u64::from(sq.head) * SQ_ENTRY_SIZE)) {
+                    Ok(cmd) => cmd,
+                    Err(e) => {
+                        error!("Failed to read NVMe command: {:?}", e);
+                        self.csts |= CSTS_CFS;
+                        break;
+                    }
+                };
+
+            let sq_head = ((u32::from(sq.head) + 1) % u32::from(sq.size)) as u16;
+            if let Some(sq) = self.sqs[sqid].as_mut() {
+                sq.head = sq_head;
+            }
+
+            let result = if sqid == 0 {
+                self.execute_admin_command(&mem, &cmd)
+            } else {
+                Some(self.execute_nvm_command(&mem, &cmd))
+            };
+
+            if let Some(result) = result {
+                self.post_completion(&mem, cqid, sqid as u16, sq_head, cmd.cid(), result);
+                used_cqid = Some(cqid);
+            }
+        }
+
+        if let Some(cqid) = used_cqid {
+            self.signal_completion_queue(cqid);
+        }
+    }
+
+    fn post_completion(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        cqid: usize,
+        sqid: u16,
+        sq_head: u16,
+        cid: u16,
+        result: CommandResult,
+    ) {
+        let cq = match self.cqs[cqid].as_mut() {
+            Some(cq) => cq,
+            None => return,
+        };
+
+        let (dw0, status) = match result {
+            Ok(dw0) => (dw0, 0),
+            Err(status) => (0, (status << 1) | STATUS_DNR),
+        };
+        let completion = Completion {
+            dw0,
+            dw1: 0,
+            sq_head,
+            sq_id: sqid,
+            cid,
+            status: status | cq.phase as u16,
+        };
+
+        if let Err(e) = mem.write_obj(
+            completion,
+            cq.addr.unchecked_add(u64::from(cq.tail) * CQ_ENTRY_SIZE),
+        ) {
+            error!("Failed to write NVMe completion: {:?}", e);
+            self.csts |= CSTS_CFS;
+            return;
+        }
+
+        // The phase tag flips every time the queue wraps around, which is
+        // how the guest tells new entries from old ones.
+        cq.tail += 1;
+        if cq.tail == cq.size {
+            cq.tail = 0;
+            cq.phase = !cq.phase;
+        }
+    }
+
+    fn signal_completion_queue(&self, cqid: usize) {
+        let cq = match self.cqs[cqid] {
+            Some(cq) if cq.irq_enabled => cq,
+            _ => return,
+        };
+
+        let config = &mut self.msix_config.lock().unwrap();
+        let entry = &config.table_entries[cq.vector as usize];
+        // A masked vector only gets its pending bit set.
+        if config.masked() || entry.masked() {
+            config.set_pba_bit(cq.vector, false);
+            return;
+        }
+
+        if let Err(e) = self
+            .interrupt_source_group
+            .trigger(cq.vector as InterruptIndex)
+        {
+            error!("Failed to signal NVMe completion queue {}: {:?}", cqid, e);
+        }
+    }
+
+    // Returns no result for the commands completing asynchronously.
+    fn execute_admin_command(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        cmd: &Command,
+    ) -> Option<CommandResult> {
+        let result = match cmd.opcode() {
+            ADMIN_DELETE_SQ => self.delete_submission_queue(cmd),
+            ADMIN_CREATE_SQ => self.create_submission_queue(cmd),
+            ADMIN_GET_LOG_PAGE => self.get_log_page(mem, cmd),
+            ADMIN_DELETE_CQ => self.delete_completion_queue(cmd),
+            ADMIN_CREATE_CQ => self.create_completion_queue(cmd),
+            ADMIN_IDENTIFY => self.identify(mem, cmd),
+            // Commands complete as soon as they are submitted, leaving
+            // nothing to abort.
+            ADMIN_ABORT => Ok(1),
+            ADMIN_SET_FEATURES => self.set_features(cmd),
+            ADMIN_GET_FEATURES => self.get_features(cmd),
+            // No asynchronous event is ever reported, the request remains
+            // outstanding until the controller is reset.
+            ADMIN_ASYNC_EVENT_REQUEST => return None,
+            opcode => {
+                debug!("Unsupported NVMe admin command 0x{:x}", opcode);
+                Err(SC_INVALID_OPCODE)
+            }
+        };
+
+        Some(result)
+    }
+
+    fn create_completion_queue(&mut self, cmd: &Command) -> CommandResult {
+        let qid = (cmd.cdw10 & 0xffff) as usize;
+        let size = (cmd.cdw10 >> 16) + 1;
+        let contiguous = cmd.cdw11 & 0x1 != 0;
+        let irq_enabled = cmd.cdw11 & 0x2 != 0;
+        let vector = (cmd.cdw11 >> 16) as u16;
+
+        if qid == 0 || qid >= self.cqs.len() || self.cqs[qid].is_some() {
+            return Err(SC_INVALID_QID);
+        }
+        if size < 2 || size > u32::from(self.max_queue_size) {
+            return Err(SC_INVALID_QSIZE);
+        }
+        if !contiguous {
+            return Err(SC_INVALID_FIELD);
+        }
+        if vector >= self.msix_num {
+            return Err(SC_INVALID_INT_VECTOR);
+        }
+
+        self.cqs[qid] = Some(CompletionQueue {
+            addr: GuestAddress(cmd.prp1 & !(PAGE_SIZE - 1)),
+            size: size as u16,
+            head: 0,
+            tail: 0,
+            phase: true,
+            vector,
+            irq_enabled,
+        });
+
+        Ok(0)
+    }
+
+    fn create_submission_queue(&mut self, cmd: &Command) -> CommandResult {
+        let qid = (cmd.cdw10 & 0xffff) as usize;
+        let size = (cmd.cdw10 >> 16) + 1;
+        let contiguous = cmd.cdw11 & 0x1 != 0;
+        let cqid = (cmd.cdw11 >> 16) as usize;
+
+        if qid == 0 || qid >= self.sqs.len() || self.sqs[qid].is_some() {
+            return Err(SC_INVALID_QID);
+        }
+        if size < 2 || size > u32::from(self.max_queue_size) {
+            return Err(SC_INVALID_QSIZE);
+        }
+        if !contiguous {
+            return Err(SC_INVALID_FIELD);
+        }
+        if cqid == 0 || cqid >= self.cqs.len() || self.cqs[cqid].is_none() {
+            return Err(SC_CQ_INVALID);
+        }
+
+        self.sqs[qid] = Some(SubmissionQueue {
+            addr: GuestAddress(cmd.prp1 & !(PAGE_SIZE - 1)),
+            size: size as u16,
+            head: 0,
+            tail: 0,
+            cqid: cqid as u16,
+        });
+
+        Ok(0)
+    }
+
+    fn delete_submission_queue(&mut self, cmd: &Command) -> CommandResult {
+        let qid = (cmd.cdw10 & 0xffff) as usize;
+        if qid == 0 || qid >= self.sqs.len() || self.sqs[qid].is_none() {
+            return Err(SC_INVALID_QID);
+        }
+
+        self.sqs[qid] = None;
+
+        Ok(0)
+    }
+
+    fn delete_completion_queue(&mut self, cmd: &Command) -> CommandResult {
+        let qid = (cmd.cdw10 & 0xffff) as usize;
+        if qid == 0 || qid >= self.cqs.len() || self.cqs[qid].is_none() {
+            return Err(SC_INVALID_QID);
+        }
+        // Submission queues must be deleted before their completion queue.
+        if self
+            .sqs
+            .iter()
+            .any(|sq| sq.map_or(false, |sq| sq.cqid as usize == qid))
+        {
+            return Err(SC_INVALID_QUEUE_DELETION);
+        }
+
+        self.cqs[qid] = None;
+
+        Ok(0)
+    }
+
+    fn get_log_page(&self, mem: &GuestMemoryMmap, cmd: &Command) -> CommandResult {
+        // The number of dwords to return is 0's based.
+        let len = (u64::from(cmd.cdw10 >> 16) + 1) * 4;
+        if len > MAX_TRANSFER_SIZE {
+            return Err(SC_INVALID_FIELD);
+        }
+
+        // Neither errors, health information nor firmware slots are
+        // tracked, every log page reads as zeros.
+        write_prp_data(mem, cmd, &vec![0u8; len as usize])?;
+
+        Ok(0)
+    }
+
+    fn identify(&self, mem: &GuestMemoryMmap, cmd: &Command) -> CommandResult {
+        let mut data = vec![0u8; IDENTIFY_DATA_SIZE];
+
+        match cmd.cdw10 & 0xff {
+            IDENTIFY_CNS_NAMESPACE => {
+                if cmd.nsid != NSID {
+                    return Err(SC_INVALID_NAMESPACE);
+                }
+                self.identify_namespace(&mut data);
+            }
+            IDENTIFY_CNS_CONTROLLER => self.identify_controller(&mut data),
+            IDENTIFY_CNS_ACTIVE_NAMESPACES => {
+                // Only the namespaces with an ID greater than the one
+                // provided are listed.
+                if cmd.nsid < NSID {
+                    data[0..4].copy_from_slice(&NSID.to_le_bytes());
+                }
+            }
+            _ => return Err(SC_INVALID_FIELD),
+        }
+
+        write_prp_data(mem, cmd, &data)?;
+
+        Ok(0)
+    }
+
+    fn identify_controller(&self, data: &mut [u8]) {
+        data[0..2].copy_from_slice(&NVME_VENDOR_ID.to_le_bytes());
+        data[2..4].copy_from_slice(&NVME_VENDOR_ID.to_le_bytes());
+        copy_ascii(&mut data[4..24], &self.serial);
+        copy_ascii(&mut data[24..64], b"Cloud Hypervisor NVMe Controller");
+        copy_ascii(&mut data[64..72], env!("CARGO_PKG_VERSION").as_bytes());
+        data[77] = MDTS;
+        data[80..84].copy_from_slice(&NVME_VERSION.to_le_bytes());
+        // Up to 4 outstanding asynchronous event requests, 0's based.
+        data[259] = 3;
+        // Submission and completion queue entries are 64 and 16 bytes.
+        data[512] = 0x66;
+        data[513] = 0x44;
+        data[516..520].copy_from_slice(&NSID.to_le_bytes());
+        // A volatile write cache is present, meaning flushes are needed.
+        data[525] = 1;
+    }
+
+    fn identify_namespace(&self, data: &mut [u8]) {
+        let nsectors = self.disk_nsectors.to_le_bytes();
+        data[0..8].copy_from_slice(&nsectors);
+        data[8..16].copy_from_slice(&nsectors);
+        data[16..24].copy_from_slice(&nsectors);
+        // Write protected namespace.
+        if self.readonly {
+            data[99] = 1;
+        }
+        // A single LBA format, with 512 bytes blocks and no metadata.
+        data[128..132].copy_from_slice(&((SECTOR_SHIFT as u32) << 16).to_le_bytes());
+    }
+
+    // Number of I/O submission and completion queues, 0's based.
+    fn number_of_queues(&self) -> u32 {
+        let num_queues = u32::from(self.num_io_queues - 1);
+        (num_queues << 16) | num_queues
+    }
+
+    fn set_features(&mut self, cmd: &Command) -> CommandResult {
+        match cmd.cdw10 & 0xff {
+            // The number of queues can't be changed, the actual one is
+            // returned whatever was requested.
+            FEATURE_NUMBER_OF_QUEUES => Ok(self.number_of_queues()),
+            fid if fid >= 0x01 && fid <= FEATURE_ASYNC_EVENT_CONFIG => Ok(0),
+            _ => Err(SC_INVALID_FIELD),
+        }
+    }
+
+    fn get_features(&self, cmd: &Command) -> CommandResult {
+        match cmd.cdw10 & 0xff {
+            FEATURE_VOLATILE_WRITE_CACHE => Ok(1),
+            FEATURE_NUMBER_OF_QUEUES => Ok(self.number_of_queues()),
+            fid if fid >= 0x01 && fid <= FEATURE_ASYNC_EVENT_CONFIG => Ok(0),
+            _ => Err(SC_INVALID_FIELD),
+        }
+    }
+
+    fn execute_nvm_command(&mut self, mem: &GuestMemoryMmap, cmd: &Command) -> CommandResult {
+        match cmd.opcode() {
+            NVM_FLUSH => {
+                if cmd.nsid != NSID && cmd.nsid != NSID_ALL {
+                    return Err(SC_INVALID_NAMESPACE);
+                }
+                self.disk.flush().map_err(|e| {
+                    error!("Failed to flush NVMe disk: {:?}", e);
+                    SC_INTERNAL_ERROR
+                })?;
+                Ok(0)
+            }
+            NVM_WRITE => self.read_write(mem, cmd, true),
+            NVM_READ => self.read_write(mem, cmd, false),
+            opcode => {
+                debug!("Unsupported NVMe I/O command 0x{:x}", opcode);
+                Err(SC_INVALID_OPCODE)
+            }
+        }
+    }
+
+    fn read_write(&mut self, mem: &GuestMemoryMmap, cmd: &Command, write: bool) -> CommandResult {
+        if cmd.nsid != NSID {
+            return Err(SC_INVALID_NAMESPACE);
+        }
+
+        let slba = u64::from(cmd.cdw10) | (u64::from(cmd.cdw11) << 32);
+        // The number of logical blocks is 0's based.
+        let nlb = u64::from(cmd.cdw12 & 0xffff) + 1;
+        if slba
+            .checked_add(nlb)
+            .map_or(true, |end| end > self.disk_nsectors)
+        {
+            return Err(SC_LBA_OUT_OF_RANGE);
+        }
+
+        let len = nlb << SECTOR_SHIFT;
+        if len > MAX_TRANSFER_SIZE {
+            return Err(SC_INVALID_FIELD);
+        }
+        if write && self.readonly {
+            return Err(SC_WRITE_TO_READ_ONLY);
+        }
+
+        let segments = prp_segments(mem, cmd.prp1, cmd.prp2, len)?;
+
+        self.disk
+            .seek(SeekFrom::Start(slba << SECTOR_SHIFT))
+            .map_err(|e| {
+                error!("Failed to seek NVMe disk: {:?}", e);
+                SC_INTERNAL_ERROR
+            })?;
+        for (addr, len) in segments {
+            let res = if write {
+                mem.write_all_to(addr, &mut self.disk, len)
+            } else {
+                mem.read_exact_from(addr, &mut self.disk, len)
+            };
+            res.map_err(|e| {
+                error!("Failed to access NVMe disk: {:?}", e);
+                SC_DATA_TRANSFER_ERROR
+            })?;
+        }
+
+        Ok(0)
+    }
+}
+
+// Processes the doorbells the vCPUs rang so far.
+fn process_doorbells<T: DiskFile>(
+    controller: &Mutex<Controller<T>>,
+    doorbells: &Mutex<Vec<(u64, u32)>>,
+) {
+    // The doorbells are taken along with the controller, not to process the
+    // ones rung before a reset after it.
+    let mut controller = controller.lock().unwrap();
+    let rung: Vec<(u64, u32)> = doorbells.lock().unwrap().drain(..).collect();
+    for (offset, value) in rung {
+        controller.write_doorbell(offset, value);
+    }
+}
+
+// Processes the doorbells the vCPUs rang, until the controller goes away.
+fn run_worker<T: DiskFile>(
+    controller: Arc<Mutex<Controller<T>>>,
+    doorbells: Arc<Mutex<Vec<(u64, u32)>>>,
+    doorbell_evt: EventFd,
+    kill_evt: EventFd,
+) -> io::Result<()> {
+    let epoll_fd = epoll::create(true)?;
+    epoll::ctl(
+        epoll_fd,
+        epoll::ControlOptions::EPOLL_CTL_ADD,
+        doorbell_evt.as_raw_fd(),
+        epoll::Event::new(epoll::Events::EPOLLIN, DOORBELL_EVENT),
+    )?;
+    epoll::ctl(
+        epoll_fd,
+        epoll::ControlOptions::EPOLL_CTL_ADD,
+        kill_evt.as_raw_fd(),
+        epoll::Event::new(epoll::Events::EPOLLIN, KILL_EVENT),
+    )?;
+
+    let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 2];
+    loop {
+        let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+            Ok(num_events) => num_events,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        for event in events.iter().take(num_events) {
+            match event.data {
+                DOORBELL_EVENT => {
+                    doorbell_evt.read()?;
+                    process_doorbells(&controller, &doorbells);
+                }
+                // The commands already submitted complete before the worker
+                // goes away.
+                KILL_EVENT => {
+                    process_doorbells(&controller, &doorbells);
+                    return Ok(());
+                }
+                _ => error!("Unknown NVMe worker event {}", event.data),
+            }
+        }
+    }
+}
+
+/// An NVMe controller exposing a disk image as its single namespace.
+///
+/// Commands are processed from a worker thread, the vCPU ringing a doorbell
+/// only queuing it and waking the worker up. KVM ioeventfds aren't used, as
+/// they drop the value written to the doorbell, which is the new queue tail
+/// or head. Each I/O completion queue gets its own MSI-X vector, the admin
+/// completion queue using vector 0.
+pub struct NvmeController<T: DiskFile> {
+    configuration: PciConfiguration,
+    msix_config: Arc<Mutex<MsixConfig>>,
+    msix_num: u16,
+    controller: Arc<Mutex<Controller<T>>>,
+    // The doorbell offsets and values, in the order the vCPUs rang them.
+    doorbells: Arc<Mutex<Vec<(u64, u32)>>>,
+    doorbell_evt: EventFd,
+    kill_evt: EventFd,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl<T: 'static + DiskFile + Send> NvmeController<T> {
+    /// Create an NVMe controller for `disk`, with up to `num_io_queues` I/O
+    /// queue pairs of up to `queue_size` entries each.
+    pub fn new(
+        memory: GuestMemoryAtomic<GuestMemoryMmap>,
+        disk: T,
+        disk_path: &PathBuf,
+        readonly: bool,
+        num_io_queues: u16,
+        queue_size: u16,
+        interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+    ) -> Result<Self> {
+        let num_io_queues = cmp::max(cmp::min(num_io_queues, MAX_IO_QUEUES), 1);
+        let max_queue_size = cmp::max(queue_size, 2);
+
+        // One vector for the admin queue, and one for each I/O queue.
+        let msix_num = num_io_queues + 1;
+        let interrupt_source_group = interrupt_manager
+            .create_group(MsiIrqGroupConfig {
+                base: 0,
+                count: msix_num as InterruptIndex,
+            })
+            .map_err(Error::CreateInterruptGroup)?;
+
+        let msix_config = Arc::new(Mutex::new(MsixConfig::new(
+            msix_num,
+            interrupt_source_group.clone(),
+        )));
+
+        let configuration = PciConfiguration::new(
+            NVME_VENDOR_ID,
+            NVME_DEVICE_ID,
+            PciClassCode::MassStorage,
+            &PciMassStorageSubclass::NVMController,
+            Some(&PciNvmProgrammingInterface::Nvme),
+            PciHeaderType::Device,
+            NVME_VENDOR_ID,
+            NVME_DEVICE_ID,
+            Some(msix_config.clone()),
+        );
+
+        let controller = Arc::new(Mutex::new(Controller::new(
+            msix_config.clone(),
+            interrupt_source_group,
+            memory,
+            disk,
+            vm_virtio::build_disk_image_id(disk_path),
+            readonly,
+            num_io_queues,
+            max_queue_size,
+        )?));
+
+        let doorbells = Arc::new(Mutex::new(Vec::new()));
+        let doorbell_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::CreateEventFd)?;
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::CreateEventFd)?;
+
+        let worker_controller = controller.clone();
+        let worker_doorbells = doorbells.clone();
+        let worker_doorbell_evt = doorbell_evt.try_clone().map_err(Error::CreateEventFd)?;
+        let worker_kill_evt = kill_evt.try_clone().map_err(Error::CreateEventFd)?;
+        let worker = thread::Builder::new()
+            .name("nvme".to_string())
+            .spawn(move || {
+                if let Err(e) = run_worker(
+                    worker_controller,
+                    worker_doorbells,
+                    worker_doorbell_evt,
+                    worker_kill_evt,
+                ) {
+                    error!("NVMe worker failed: {:?}", e);
+                }
+            })
+            .map_err(Error::WorkerSpawn)?;
+
+        Ok(NvmeController {
+            configuration,
+            msix_config,
+            msix_num,
+            controller,
+            doorbells,
+            doorbell_evt,
+            kill_evt,
+            worker: Some(worker),
+        })
+    }
+
+    fn ring_doorbell(&self, offset: u64, data: &[u8]) {
+        if data.len() != 4 {
+            return;
+        }
+
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(data);
+        self.doorbells
+            .lock()
+            .unwrap()
+            .push((offset, u32::from_le_bytes(bytes)));
+        if let Err(e) = self.doorbell_evt.write(1) {
+            error!("Failed to wake the NVMe worker up: {:?}", e);
+        }
+    }
+
+    fn write_register(&self, offset: u64, data: &[u8]) {
+        let mut controller = self.controller.lock().unwrap();
+        controller.write_register(offset, data);
+        // The doorbells rung until now are moot once disabled.
+        if controller.csts & CSTS_RDY == 0 {
+            self.doorbells.lock().unwrap().clear();
+        }
+    }
+}
+
+impl<T: DiskFile> NvmeController<T> {
+    /// Stop the worker thread, once it processed the commands submitted so
+    /// far, and write back whatever the disk image caches, the guest may not
+    /// have shut the controller down before going away.
+    pub fn shutdown(&mut self) {
+        let _ = self.kill_evt.write(1);
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                error!("NVMe worker panicked");
+            }
+        }
+
+        if let Err(e) = self.controller.lock().unwrap().disk.flush() {
+            error!("Failed to flush NVMe disk on shutdown: {:?}", e);
+        }
+    }
+}
+
+impl<T: DiskFile> Drop for NvmeController<T> {
+    fn drop(&mut self) {
+        let _ = self.kill_evt.write(1);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl<T: 'static + DiskFile + Send> PciDevice for NvmeController<T> {
+    fn allocate_bars(
+        &mut self,
+        allocator: &mut SystemAllocator,
+    ) -> result::Result<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>, PciDeviceError> {
+        let region_type = PciBarRegionType::Memory64BitRegion;
+        let addr = allocator
+            .allocate_mmio_addresses(None, NVME_BAR_SIZE, Some(NVME_BAR_SIZE))
+            .ok_or(PciDeviceError::IoAllocationFailed(NVME_BAR_SIZE))?;
+
+        let config = PciBarConfiguration::default()
+            .set_register_index(0)
+            .set_address(addr.raw_value())
+            .set_size(NVME_BAR_SIZE)
+            .set_region_type(region_type);
+        let bar = self
+            .configuration
+            .add_pci_bar(&config)
+            .map_err(|e| PciDeviceError::IoRegistrationFailed(addr.raw_value(), e))?
+            as u8;
+
+        let msix_cap = MsixCap::new(
+            bar,
+            self.msix_num,
+            MSIX_TABLE_BAR_OFFSET as u32,
+            bar,
+            MSIX_PBA_BAR_OFFSET as u32,
+        );
+        self.configuration
+            .add_capability(&msix_cap)
+            .map_err(PciDeviceError::CapabilitiesSetup)?;
+
+        Ok(vec![(addr, NVME_BAR_SIZE, region_type)])
+    }
+
+    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        self.configuration
+            .write_config_register(reg_idx, offset, data);
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        self.configuration.read_reg(reg_idx)
+    }
+
+    fn detect_bar_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        self.configuration.detect_bar_reprogramming(reg_idx, data)
+    }
+
+    fn read_bar(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        match offset {
+            o if o < REGISTERS_SIZE => self.controller.lock().unwrap().read_register(o, data),
+            o if MSIX_TABLE_BAR_OFFSET <= o && o < MSIX_TABLE_BAR_OFFSET + MSIX_TABLE_SIZE => self
+                .msix_config
+                .lock()
+                .unwrap()
+                .read_table(o - MSIX_TABLE_BAR_OFFSET, data),
+            o if MSIX_PBA_BAR_OFFSET <= o && o < MSIX_PBA_BAR_OFFSET + MSIX_PBA_SIZE => self
+                .msix_config
+                .lock()
+                .unwrap()
+                .read_pba(o - MSIX_PBA_BAR_OFFSET, data),
+            // The doorbells are write only.
+            _ => (),
+        }
+    }
+
+    fn write_bar(&mut self, _base: u64, offset: u64, data: &[u8]) {
+        match offset {
+            o if o < REGISTERS_SIZE => self.write_register(o, data),
+            o if DOORBELLS_BAR_OFFSET <= o && o < DOORBELLS_BAR_OFFSET + DOORBELLS_SIZE => {
+                self.ring_doorbell(o - DOORBELLS_BAR_OFFSET, data)
+            }
+            o if MSIX_TABLE_BAR_OFFSET <= o && o < MSIX_TABLE_BAR_OFFSET + MSIX_TABLE_SIZE => self
+                .msix_config
+                .lock()
+                .unwrap()
+                .write_table(o - MSIX_TABLE_BAR_OFFSET, data),
+            o if MSIX_PBA_BAR_OFFSET <= o && o < MSIX_PBA_BAR_OFFSET + MSIX_PBA_SIZE => self
+                .msix_config
+                .lock()
+                .unwrap()
+                .write_pba(o - MSIX_PBA_BAR_OFFSET, data),
+            _ => (),
+        }
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl<T: 'static + DiskFile + Send> BusDevice for NvmeController<T> {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.read_bar(base, offset, data)
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) {
+        self.write_bar(base, offset, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use vm_device::interrupt::InterruptSourceConfig;
+    use vm_virtio::RawFile;
+    use vmm_sys_util::write_zeroes::PunchHole;
+
+    const ASQ: u64 = 0x10000;
+    const ACQ: u64 = 0x11000;
+
+    struct TestInterrupt {}
+
+    impl InterruptSourceGroup for TestInterrupt {
+        fn trigger(&self, _index: InterruptIndex) -> result::Result<(), io::Error> {
+            Ok(())
+        }
+        fn update(
+            &self,
+            _index: InterruptIndex,
+            _config: InterruptSourceConfig,
+        ) -> result::Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    fn memory() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap()
+    }
+
+    // An enabled controller, with admin queues of `queue_size` entries.
+    fn controller(mem: &GuestMemoryMmap, queue_size: u16) -> Controller<RawFile> {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(1 << 20).unwrap();
+        let interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>> =
+            Arc::new(Box::new(TestInterrupt {}));
+        let msix_config = Arc::new(Mutex::new(MsixConfig::new(
+            2,
+            interrupt_source_group.clone(),
+        )));
+
+        let mut controller = Controller::new(
+            msix_config,
+            interrupt_source_group,
+            GuestMemoryAtomic::new(mem.clone()),
+            RawFile::new(file, false),
+            b"serial".to_vec(),
+            false,
+            1,
+            queue_size,
+        )
+        .unwrap();
+
+        let aqa = u32::from(queue_size - 1) << 16 | u32::from(queue_size - 1);
+        controller.write_register(REG_AQA, &aqa.to_le_bytes());
+        controller.write_register(REG_ASQ, &ASQ.to_le_bytes());
+        controller.write_register(REG_ACQ, &ACQ.to_le_bytes());
+        controller.write_register(REG_CC, &CC_EN.to_le_bytes());
+        assert_eq!(controller.csts & CSTS_RDY, CSTS_RDY);
+
+        controller
+    }
+
+    fn completion(mem: &GuestMemoryMmap, index: u64) -> Completion {
+        mem.read_obj(GuestAddress(ACQ + index * CQ_ENTRY_SIZE))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_prp_segments() {
+        let mem = memory();
+
+        // Within a single page.
+        assert_eq!(
+            prp_segments(&mem, 0x1200, 0, 0x800).unwrap(),
+            vec![(GuestAddress(0x1200), 0x800)]
+        );
+
+        // Across two pages, PRP2 pointing to the second one.
+        assert_eq!(
+            prp_segments(&mem, 0x1800, 0x5000, 0x1000).unwrap(),
+            vec![(GuestAddress(0x1800), 0x800), (GuestAddress(0x5000), 0x800)]
+        );
+
+        // A list starting 2 entries before the end of its page, the last one
+        // chaining to the next list page.
+        mem.write_obj(0x20000u64, GuestAddress(0x8ff0)).unwrap();
+        mem.write_obj(0x9000u64, GuestAddress(0x8ff8)).unwrap();
+        mem.write_obj(0x21000u64, GuestAddress(0x9000)).unwrap();
+        mem.write_obj(0x22000u64, GuestAddress(0x9008)).unwrap();
+        assert_eq!(
+            prp_segments(&mem, 0x1000, 0x8ff0, 4 * PAGE_SIZE).unwrap(),
+            vec![
+                (GuestAddress(0x1000), 0x1000),
+                (GuestAddress(0x20000), 0x1000),
+                (GuestAddress(0x21000), 0x1000),
+                (GuestAddress(0x22000), 0x1000),
+            ]
+        );
+
+        // The list must be 8 bytes aligned.
+        assert_eq!(
+            prp_segments(&mem, 0x1000, 0x8004, 3 * PAGE_SIZE),
+            Err(SC_DATA_TRANSFER_ERROR)
+        );
+        // A list page can't hold the pointer to the next one only.
+        assert_eq!(
+            prp_segments(&mem, 0x1000, 0x8ff8, 3 * PAGE_SIZE),
+            Err(SC_DATA_TRANSFER_ERROR)
+        );
+    }
+
+    #[test]
+    fn test_completion_phase() {
+        let mem = memory();
+        let mut controller = controller(&mem, 2);
+
+        controller.post_completion(&mem, 0, 0, 1, 0x10, Ok(0));
+        controller.post_completion(&mem, 0, 0, 0, 0x11, Ok(0));
+        assert_eq!(completion(&mem, 0).status, 1);
+        assert_eq!(completion(&mem, 1).status, 1);
+
+        // The phase tag flips once the queue wrapped around.
+        controller.post_completion(&mem, 0, 0, 1, 0x12, Ok(0));
+        let entry = completion(&mem, 0);
+        assert_eq!(entry.cid, 0x12);
+        assert_eq!(entry.status, 0);
+        assert_eq!(controller.cqs[0].unwrap().tail, 1);
+        assert!(!controller.cqs[0].unwrap().phase);
+    }
+
+    #[test]
+    fn test_completion_status() {
+        let mem = memory();
+        let mut controller = controller(&mem, 4);
+
+        let unsupported = Command {
+            cdw0: 0x7 << 16 | 0xff,
+            ..Default::default()
+        };
+        let get_features = Command {
+            cdw0: 0x8 << 16 | u32::from(ADMIN_GET_FEATURES),
+            cdw10: FEATURE_VOLATILE_WRITE_CACHE,
+            ..Default::default()
+        };
+        mem.write_obj(unsupported, GuestAddress(ASQ)).unwrap();
+        mem.write_obj(get_features, GuestAddress(ASQ + SQ_ENTRY_SIZE))
+            .unwrap();
+        controller.write_doorbell(0, 2);
+
+        let entry = completion(&mem, 0);
+        assert_eq!(entry.cid, 0x7);
+        assert_eq!(entry.sq_head, 1);
+        assert_eq!(entry.status, (SC_INVALID_OPCODE << 1) | STATUS_DNR | 1);
+
+        let entry = completion(&mem, 1);
+        assert_eq!(entry.cid, 0x8);
+        assert_eq!(entry.sq_head, 2);
+        assert_eq!(entry.status, 1);
+        assert_eq!(entry.dw0, 1);
+
+        // Nothing is processed once the controller is disabled.
+        controller.write_register(REG_CC, &0u32.to_le_bytes());
+        controller.write_doorbell(0, 3);
+        assert_eq!(completion(&mem, 2).status, 0);
+    }
+
+    // A disk counting how many times it got flushed.
+    #[derive(Clone)]
+    struct FlushCountingDisk {
+        disk: RawFile,
+        flushes: Arc<AtomicUsize>,
+    }
+
+    impl Read for FlushCountingDisk {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.disk.read(buf)
+        }
+    }
+
+    impl Write for FlushCountingDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.disk.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+            self.disk.flush()
+        }
+    }
+
+    impl Seek for FlushCountingDisk {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.disk.seek(pos)
+        }
+    }
+
+    impl PunchHole for FlushCountingDisk {
+        fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+            self.disk.punch_hole(offset, length)
+        }
+    }
+
+    struct TestInterruptManager {}
+
+    impl InterruptManager for TestInterruptManager {
+        type GroupConfig = MsiIrqGroupConfig;
+
+        fn create_group(
+            &self,
+            _config: MsiIrqGroupConfig,
+        ) -> result::Result<Arc<Box<dyn InterruptSourceGroup>>, io::Error> {
+            Ok(Arc::new(Box::new(TestInterrupt {})))
+        }
+
+        fn destroy_group(
+            &self,
+            _group: Arc<Box<dyn InterruptSourceGroup>>,
+        ) -> result::Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let image = tempfile::NamedTempFile::new().unwrap();
+        image.as_file().set_len(1 << 20).unwrap();
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let disk = FlushCountingDisk {
+            disk: RawFile::new(image.reopen().unwrap(), false),
+            flushes: flushes.clone(),
+        };
+        let interrupt_manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>> =
+            Arc::new(TestInterruptManager {});
+
+        let mut nvme = NvmeController::new(
+            GuestMemoryAtomic::new(memory()),
+            disk,
+            &image.path().to_path_buf(),
+            false,
+            1,
+            64,
+            &interrupt_manager,
+        )
+        .unwrap();
+        assert!(nvme.worker.is_some());
+
+        nvme.shutdown();
+        assert!(nvme.worker.is_none());
+        assert_eq!(flushes.load(Ordering::SeqCst), 1);
+
+        // Dropping the controller afterwards is fine.
+        drop(nvme);
+        assert_eq!(flushes.load(Ordering::SeqCst), 1);
+    }
+}
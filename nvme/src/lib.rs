@@ -0,0 +1,33 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Emulated NVMe controller, exposing a single namespace backed by the same
+//! disk images as virtio-blk.
+
+#[macro_use]
+extern crate log;
+
+mod controller;
+
+pub use controller::NvmeController;
+
+use std::io;
+
+/// Errors associated with the NVMe controller creation.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot create the MSI-X interrupt group.
+    CreateInterruptGroup(io::Error),
+
+    /// Cannot find out the size of the disk image.
+    DiskSize(io::Error),
+
+    /// Cannot create the worker thread events.
+    CreateEventFd(io::Error),
+
+    /// Cannot spawn the worker thread.
+    WorkerSpawn(io::Error),
+}
+pub type Result<T> = std::result::Result<T, Error>;
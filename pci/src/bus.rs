@@ -10,6 +10,7 @@ use byteorder::{ByteOrder, LittleEndian};
 use devices::BusDevice;
 use std;
 use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ops::DerefMut;
 use std::sync::{Arc, Mutex, Weak};
 use vm_memory::{Address, GuestAddress, GuestUsize};
@@ -17,6 +18,9 @@ use vm_memory::{Address, GuestAddress, GuestUsize};
 const VENDOR_ID_INTEL: u16 = 0x8086;
 const DEVICE_ID_INTEL_VIRT_PCIE_HOST: u16 = 0x0d57;
 
+// Number of devices on a bus, the device ID being 5 bits.
+const NUM_DEVICE_IDS: u32 = 32;
+
 /// Errors for device manager.
 #[derive(Debug)]
 pub enum PciRootError {
@@ -28,6 +32,12 @@ pub enum PciRootError {
     PioInsert(devices::BusError),
     /// Could not add a device to the mmio bus.
     MmioInsert(devices::BusError),
+    /// The device ID is not one a device can be given.
+    InvalidPciDeviceId(u32),
+    /// The device ID is already in use.
+    AlreadyInUsePciDeviceId(u32),
+    /// All the device IDs of the bus are in use.
+    NoPciDeviceIdAvailable,
 }
 pub type Result<T> = std::result::Result<T, PciRootError>;
 
@@ -79,18 +89,22 @@ impl PciDevice for PciRoot {
 pub struct PciBus {
     /// Devices attached to this bus.
     /// Device 0 is host bridge.
-    devices: Vec<Arc<Mutex<dyn PciDevice>>>,
+    devices: BTreeMap<usize, Arc<Mutex<dyn PciDevice>>>,
+    /// Device IDs requested explicitly, which are not handed out by
+    /// next_device_id().
+    reserved_device_ids: BTreeSet<u32>,
     device_reloc: Weak<dyn DeviceRelocation>,
 }
 
 impl PciBus {
     pub fn new(pci_root: PciRoot, device_reloc: Weak<dyn DeviceRelocation>) -> Self {
-        let mut devices: Vec<Arc<Mutex<dyn PciDevice>>> = Vec::new();
+        let mut devices: BTreeMap<usize, Arc<Mutex<dyn PciDevice>>> = BTreeMap::new();
 
-        devices.push(Arc::new(Mutex::new(pci_root)));
+        devices.insert(0, Arc::new(Mutex::new(pci_root)));
 
         PciBus {
             devices,
+            reserved_device_ids: BTreeSet::new(),
             device_reloc,
         }
     }
@@ -119,13 +133,33 @@ impl PciBus {
         Ok(())
     }
 
-    pub fn add_device(&mut self, device: Arc<Mutex<dyn PciDevice>>) -> Result<()> {
-        self.devices.push(device);
+    /// Keeps `device_id` for a device to be added later on, so that no other
+    /// device gets it.
+    pub fn reserve_device_id(&mut self, device_id: u32) -> Result<()> {
+        if device_id == 0 || device_id >= NUM_DEVICE_IDS {
+            return Err(PciRootError::InvalidPciDeviceId(device_id));
+        }
+        if !self.reserved_device_ids.insert(device_id) {
+            return Err(PciRootError::AlreadyInUsePciDeviceId(device_id));
+        }
+        Ok(())
+    }
+
+    pub fn add_device(&mut self, device_id: u32, device: Arc<Mutex<dyn PciDevice>>) -> Result<()> {
+        if self.devices.contains_key(&(device_id as usize)) {
+            return Err(PciRootError::AlreadyInUsePciDeviceId(device_id));
+        }
+        self.devices.insert(device_id as usize, device);
         Ok(())
     }
 
-    pub fn next_device_id(&self) -> u32 {
-        self.devices.len() as u32
+    pub fn next_device_id(&self) -> Result<u32> {
+        (0..NUM_DEVICE_IDS)
+            .find(|id| {
+                !self.devices.contains_key(&(*id as usize))
+                    && !self.reserved_device_ids.contains(id)
+            })
+            .ok_or(PciRootError::NoPciDeviceIdAvailable)
     }
 }
 
@@ -166,7 +200,7 @@ impl PciConfigIo {
             .lock()
             .unwrap()
             .devices
-            .get(device)
+            .get(&device)
             .map_or(0xffff_ffff, |d| {
                 d.lock().unwrap().read_config_register(register)
             })
@@ -191,7 +225,7 @@ impl PciConfigIo {
         }
 
         let pci_bus = self.pci_bus.lock().unwrap();
-        if let Some(d) = pci_bus.devices.get(device) {
+        if let Some(d) = pci_bus.devices.get(&device) {
             let mut device = d.lock().unwrap();
 
             // Find out if one of the device's BAR is being reprogrammed, and
@@ -288,7 +322,7 @@ impl PciConfigMmio {
             .lock()
             .unwrap()
             .devices
-            .get(device)
+            .get(&device)
             .map_or(0xffff_ffff, |d| {
                 d.lock().unwrap().read_config_register(register)
             })
@@ -307,7 +341,7 @@ impl PciConfigMmio {
         }
 
         let pci_bus = self.pci_bus.lock().unwrap();
-        if let Some(d) = pci_bus.devices.get(device) {
+        if let Some(d) = pci_bus.devices.get(&device) {
             let mut device = d.lock().unwrap();
 
             // Find out if one of the device's BAR is being reprogrammed, and
@@ -376,3 +410,83 @@ fn parse_config_address(config_address: u32) -> (usize, usize, usize, usize) {
 
     (bus_number, device_number, function_number, register_number)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    struct NoopRelocation {}
+
+    impl DeviceRelocation for NoopRelocation {
+        fn move_bar(
+            &self,
+            _old_base: u64,
+            _new_base: u64,
+            _len: u64,
+            _pci_dev: &mut dyn PciDevice,
+            _region_type: PciBarRegionType,
+        ) -> std::result::Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    fn pci_bus() -> PciBus {
+        PciBus::new(PciRoot::new(None), Weak::<NoopRelocation>::new())
+    }
+
+    fn device() -> Arc<Mutex<dyn PciDevice>> {
+        Arc::new(Mutex::new(PciRoot::new(None)))
+    }
+
+    #[test]
+    fn test_reserve_device_id() {
+        let mut bus = pci_bus();
+
+        // The host bridge has device 0, and the device ID is 5 bits.
+        match bus.reserve_device_id(0) {
+            Err(PciRootError::InvalidPciDeviceId(0)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        match bus.reserve_device_id(32) {
+            Err(PciRootError::InvalidPciDeviceId(32)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        bus.reserve_device_id(5).unwrap();
+        match bus.reserve_device_id(5) {
+            Err(PciRootError::AlreadyInUsePciDeviceId(5)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_next_device_id() {
+        let mut bus = pci_bus();
+        assert_eq!(bus.next_device_id().unwrap(), 1);
+
+        // The reserved IDs aren't handed out.
+        bus.reserve_device_id(1).unwrap();
+        bus.reserve_device_id(2).unwrap();
+        assert_eq!(bus.next_device_id().unwrap(), 3);
+
+        bus.add_device(3, device()).unwrap();
+        assert_eq!(bus.next_device_id().unwrap(), 4);
+        match bus.add_device(3, device()) {
+            Err(PciRootError::AlreadyInUsePciDeviceId(3)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // A reserved ID is given to the device it was reserved for.
+        bus.add_device(1, device()).unwrap();
+        assert_eq!(bus.next_device_id().unwrap(), 4);
+
+        for device_id in 4..NUM_DEVICE_IDS {
+            bus.add_device(device_id, device()).unwrap();
+        }
+        match bus.next_device_id() {
+            Err(PciRootError::NoPciDeviceIdAvailable) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
@@ -6,8 +6,10 @@ use std::sync::{Arc, Mutex};
 
 use crate::device::BarReprogrammingParams;
 use crate::{MsixConfig, PciInterruptPin};
+use anyhow::anyhow;
 use byteorder::{ByteOrder, LittleEndian};
 use std::fmt::{self, Display};
+use vm_device::MigratableError;
 
 // The number of 32bit registers in the config space, 256 bytes.
 const NUM_CONFIGURATION_REGISTERS: usize = 64;
@@ -244,6 +246,15 @@ pub trait PciCapability {
     fn id(&self) -> PciCapabilityID;
 }
 
+/// What the guest can change of a configuration space, to bring it back on
+/// restore.
+#[derive(Deserialize, Serialize)]
+pub struct PciConfigurationState {
+    registers: Vec<u32>,
+    bar_addr: Vec<u32>,
+    rom_bar_addr: u32,
+}
+
 /// Contains the configuration space of a PCI node.
 /// See the [specification](https://en.wikipedia.org/wiki/PCI_configuration_space).
 /// The configuration space is accessed with DWORD reads and writes from the guest.
@@ -390,6 +401,71 @@ impl PciConfiguration {
         }
     }
 
+    pub fn state(&self) -> PciConfigurationState {
+        PciConfigurationState {
+            registers: self.registers.to_vec(),
+            bar_addr: self.bar_addr.to_vec(),
+            rom_bar_addr: self.rom_bar_addr,
+        }
+    }
+
+    /// Brings the configuration space back to `state`, returning the BARs
+    /// the guest had moved away from where they are allocated, for the
+    /// caller to move them the same way.
+    pub fn set_state(
+        &mut self,
+        state: &PciConfigurationState,
+    ) -> std::result::Result<Vec<BarReprogrammingParams>, MigratableError> {
+        if state.registers.len() != NUM_CONFIGURATION_REGISTERS
+            || state.bar_addr.len() != NUM_BAR_REGS
+        {
+            return Err(MigratableError::Restore(anyhow!(
+                "Invalid PCI configuration space state"
+            )));
+        }
+
+        let old_bars: Vec<u64> = (0..NUM_BAR_REGS).map(|i| self.get_bar_addr(i)).collect();
+        let old_rom_bar = u64::from(self.rom_bar_addr & ROM_BAR_ADDR_MASK);
+
+        self.registers.copy_from_slice(&state.registers);
+        self.bar_addr.copy_from_slice(&state.bar_addr);
+        self.rom_bar_addr = state.rom_bar_addr;
+
+        let mut moves = Vec::new();
+        for (i, old_base) in old_bars.into_iter().enumerate() {
+            let region_type = match self.bar_type[i] {
+                Some(region_type) => region_type,
+                // Not a BAR of its own, or the upper half of a 64 bits one.
+                None => continue,
+            };
+            let new_base = self.get_bar_addr(i);
+            if new_base == old_base {
+                continue;
+            }
+            let mut len = u64::from(self.bar_size[i]);
+            if region_type == PciBarRegionType::Memory64BitRegion {
+                len |= u64::from(self.bar_size[i + 1]) << 32;
+            }
+            moves.push(BarReprogrammingParams {
+                old_base,
+                new_base,
+                len,
+                region_type,
+            });
+        }
+        let new_rom_bar = u64::from(self.rom_bar_addr & ROM_BAR_ADDR_MASK);
+        if self.rom_bar_used && new_rom_bar != old_rom_bar {
+            moves.push(BarReprogrammingParams {
+                old_base: old_rom_bar,
+                new_base: new_rom_bar,
+                len: u64::from(self.rom_bar_size),
+                region_type: PciBarRegionType::Memory32BitRegion,
+            });
+        }
+
+        Ok(moves)
+    }
+
     /// Reads a 32bit register from `reg_idx` in the register map.
     pub fn read_reg(&self, reg_idx: usize) -> u32 {
         *(self.registers.get(reg_idx).unwrap_or(&0xffff_ffff))
@@ -934,4 +1010,55 @@ mod tests {
         assert_eq!(subclass, 0x01);
         assert_eq!(prog_if, 0x5a);
     }
+
+    #[test]
+    fn restore_moved_bar() {
+        let new_cfg = || {
+            let mut cfg = PciConfiguration::new(
+                0x1234,
+                0x5678,
+                PciClassCode::MultimediaController,
+                &PciMultimediaSubclass::AudioController,
+                None,
+                PciHeaderType::Device,
+                0xABCD,
+                0x2468,
+                None,
+            );
+            cfg.add_pci_bar(
+                &PciBarConfiguration::default()
+                    .set_register_index(0)
+                    .set_address(0x1_0000_0000)
+                    .set_size(0x1000)
+                    .set_region_type(PciBarRegionType::Memory64BitRegion),
+            )
+            .unwrap();
+            cfg
+        };
+
+        // The guest moves the BAR, and sets the interrupt line.
+        let mut cfg = new_cfg();
+        assert!(cfg
+            .detect_bar_reprogramming(BAR0_REG, &0x2000_0004u32.to_le_bytes())
+            .is_none());
+        cfg.write_config_register(BAR0_REG, 0, &0x2000_0004u32.to_le_bytes());
+        let params = cfg
+            .detect_bar_reprogramming(BAR0_REG + 1, &2u32.to_le_bytes())
+            .unwrap();
+        cfg.write_config_register(BAR0_REG + 1, 0, &2u32.to_le_bytes());
+        assert_eq!(params.new_base, 0x2_2000_0000);
+        cfg.write_config_register(INTERRUPT_LINE_PIN_REG, 0, &[5]);
+
+        let mut restored = new_cfg();
+        let moves = restored.set_state(&cfg.state()).unwrap();
+        assert_eq!(moves.len(), 1);
+        assert_eq!(moves[0].old_base, 0x1_0000_0000);
+        assert_eq!(moves[0].new_base, 0x2_2000_0000);
+        assert_eq!(moves[0].len, 0x1000);
+        assert_eq!(restored.get_bar_addr(0), 0x2_2000_0000);
+        assert_eq!(restored.read_reg(INTERRUPT_LINE_PIN_REG) & 0xff, 5);
+
+        // Nothing to move when restoring where the BAR already is.
+        assert!(restored.set_state(&cfg.state()).unwrap().is_empty());
+    }
 }
@@ -5,7 +5,11 @@
 //! Implements pci devices and busses.
 #[macro_use]
 extern crate log;
+extern crate anyhow;
 extern crate devices;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate vm_memory;
 
 mod bus;
@@ -17,14 +21,16 @@ mod msix;
 pub use self::bus::{PciBus, PciConfigIo, PciConfigMmio, PciRoot, PciRootError};
 pub use self::configuration::{
     PciBarConfiguration, PciBarPrefetchable, PciBarRegionType, PciCapability, PciCapabilityID,
-    PciClassCode, PciConfiguration, PciHeaderType, PciMassStorageSubclass,
+    PciClassCode, PciConfiguration, PciConfigurationState, PciHeaderType, PciMassStorageSubclass,
     PciNetworkControllerSubclass, PciProgrammingInterface, PciSerialBusSubClass, PciSubclass,
 };
 pub use self::device::{
     BarReprogrammingParams, DeviceRelocation, Error as PciDeviceError, PciDevice,
 };
 pub use self::msi::{msi_num_enabled_vectors, MsiCap, MsiConfig};
-pub use self::msix::{MsixCap, MsixConfig, MsixTableEntry, MSIX_TABLE_ENTRY_SIZE};
+pub use self::msix::{
+    MsixCap, MsixConfig, MsixConfigState, MsixTableEntry, MSIX_TABLE_ENTRY_SIZE,
+};
 
 /// PCI has four interrupt pins A->D.
 #[derive(Copy, Clone)]
@@ -9,10 +9,12 @@ extern crate vm_memory;
 use std::sync::Arc;
 
 use crate::{PciCapability, PciCapabilityID};
+use anyhow::anyhow;
 use byteorder::{ByteOrder, LittleEndian};
 use vm_device::interrupt::{
     InterruptIndex, InterruptSourceConfig, InterruptSourceGroup, MsiIrqSourceConfig,
 };
+use vm_device::MigratableError;
 use vm_memory::ByteValued;
 
 const MAX_MSIX_VECTORS_PER_DEVICE: u16 = 2048;
@@ -25,7 +27,7 @@ const FUNCTION_MASK_MASK: u16 = (1 << FUNCTION_MASK_BIT) as u16;
 const MSIX_ENABLE_MASK: u16 = (1 << MSIX_ENABLE_BIT) as u16;
 pub const MSIX_TABLE_ENTRY_SIZE: usize = 16;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct MsixTableEntry {
     pub msg_addr_lo: u32,
     pub msg_addr_hi: u32,
@@ -50,6 +52,16 @@ impl Default for MsixTableEntry {
     }
 }
 
+/// The MSI-X table and state the guest programmed, saved with the
+/// snapshots.
+#[derive(Deserialize, Serialize)]
+pub struct MsixConfigState {
+    table_entries: Vec<MsixTableEntry>,
+    pba_entries: Vec<u64>,
+    masked: bool,
+    enabled: bool,
+}
+
 pub struct MsixConfig {
     pub table_entries: Vec<MsixTableEntry>,
     pub pba_entries: Vec<u64>,
@@ -88,6 +100,41 @@ impl MsixConfig {
         self.enabled
     }
 
+    pub fn state(&self) -> MsixConfigState {
+        MsixConfigState {
+            table_entries: self.table_entries.clone(),
+            pba_entries: self.pba_entries.clone(),
+            masked: self.masked,
+            enabled: self.enabled,
+        }
+    }
+
+    /// Brings the MSI-X table back to `state`, reprogramming the interrupt
+    /// routes if the guest had enabled MSI-X.
+    pub fn set_state(&mut self, state: &MsixConfigState) -> Result<(), MigratableError> {
+        if state.table_entries.len() != self.table_entries.len()
+            || state.pba_entries.len() != self.pba_entries.len()
+        {
+            return Err(MigratableError::Restore(anyhow!(
+                "Invalid number of MSI-X vectors: {}",
+                state.table_entries.len()
+            )));
+        }
+
+        self.table_entries = state.table_entries.clone();
+        self.pba_entries = state.pba_entries.clone();
+        let mut reg = 0u16;
+        if state.masked {
+            reg |= FUNCTION_MASK_MASK;
+        }
+        if state.enabled {
+            reg |= MSIX_ENABLE_MASK;
+        }
+        self.set_msg_ctl(reg);
+
+        Ok(())
+    }
+
     pub fn set_msg_ctl(&mut self, reg: u16) {
         let old_masked = self.masked;
         let old_enabled = self.enabled;
@@ -8,9 +8,11 @@ extern crate log;
 mod qcow_raw_file;
 mod refcount;
 mod vec_cache;
+pub mod vhd;
+pub mod vhdx;
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use libc::{EINVAL, ENOSPC, ENOTSUP};
+use libc::{EINVAL, ENOSPC, ENOTSUP, EOPNOTSUPP};
 use remain::sorted;
 use vm_virtio::RawFile;
 use vmm_sys_util::{
@@ -22,14 +24,21 @@ use std::cmp::{max, min};
 use std::fmt::{self, Display};
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
+use std::sync::{Arc, Mutex};
 
 use crate::qcow_raw_file::QcowRawFile;
 use crate::refcount::RefCount;
 use crate::vec_cache::{CacheMap, Cacheable, VecCache};
+pub use crate::vhd::VhdFile;
+pub use crate::vhdx::VhdxFile;
 
 #[sorted]
 #[derive(Debug)]
 pub enum Error {
+    BackingFileQcow(Box<Error>),
+    BackingFileSizeMismatch(u64, u64),
+    BackingFileVhd(vhd::Error),
+    BackingFileVhdx(vhdx::Error),
     BackingFilesNotSupported,
     CompressedBlocksNotSupported,
     EvictingCache(io::Error),
@@ -63,6 +72,7 @@ pub enum Error {
     SizeTooSmallForNumberOfClusters,
     TooManyL1Entries(u64),
     TooManyRefcounts(u64),
+    UnsupportedImageType,
     UnsupportedRefcountOrder,
     UnsupportedVersion(u32),
     WritingData(io::Error),
@@ -78,6 +88,14 @@ impl Display for Error {
 
         #[sorted]
         match self {
+            BackingFileQcow(e) => write!(f, "invalid QCOW2 backing file: {}", e),
+            BackingFileSizeMismatch(size, backing_size) => write!(
+                f,
+                "overlay size {} doesn't match the backing file size {}",
+                size, backing_size
+            ),
+            BackingFileVhd(e) => write!(f, "invalid VHD backing file: {}", e),
+            BackingFileVhdx(e) => write!(f, "invalid VHDX backing file: {}", e),
             BackingFilesNotSupported => write!(f, "backing files not supported"),
             CompressedBlocksNotSupported => write!(f, "compressed blocks not supported"),
             EvictingCache(e) => write!(f, "failed to evict cache: {}", e),
@@ -115,6 +133,7 @@ impl Display for Error {
             SizeTooSmallForNumberOfClusters => write!(f, "size too small for number of clusters"),
             TooManyL1Entries(count) => write!(f, "l1 entry table too large: {}", count),
             TooManyRefcounts(count) => write!(f, "ref count table too large: {}", count),
+            UnsupportedImageType => write!(f, "unsupported image type"),
             UnsupportedRefcountOrder => write!(f, "unsupported refcount order"),
             UnsupportedVersion(v) => write!(f, "unsupported version: {}", v),
             WritingData(e) => write!(f, "failed to write data: {}", e),
@@ -126,6 +145,8 @@ impl Display for Error {
 pub enum ImageType {
     Raw,
     Qcow2,
+    Vhd,
+    Vhdx,
 }
 
 // Maximum data size supported.
@@ -378,6 +399,41 @@ fn max_refcount_clusters(refcount_order: u32, cluster_size: u32, num_clusters: u
 /// #   Ok(())
 /// # }
 /// ```
+/// Read-only image providing the content of the clusters an overlay doesn't
+/// allocate.
+pub trait BackingImage: Read + Seek + Send {}
+impl<T: Read + Seek + Send> BackingImage for T {}
+
+// The backing image is shared between the clones of the overlay.
+#[derive(Clone)]
+struct BackingFile {
+    image: Arc<Mutex<Box<dyn BackingImage>>>,
+    size: u64,
+}
+
+impl BackingFile {
+    // Reads `buf.len()` bytes at `address`, the bytes past the end of the
+    // backing image reading as zeros.
+    fn read_at(&self, address: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        let count = min(buf.len() as u64, self.size.saturating_sub(address)) as usize;
+        if count > 0 {
+            let mut image = self.image.lock().unwrap();
+            image.seek(SeekFrom::Start(address))?;
+            image.read_exact(&mut buf[..count])?;
+        }
+        for b in &mut buf[count..] {
+            *b = 0;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for BackingFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BackingFile {{ size: {} }}", self.size)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct QcowFile {
     raw_file: QcowRawFile,
@@ -391,7 +447,8 @@ pub struct QcowFile {
     // List of unreferenced clusters available to be used. unref clusters become available once the
     // removal of references to them have been synced to disk.
     avail_clusters: Vec<u64>,
-    //TODO(dgreid) Add support for backing files. - backing_file: Option<Box<QcowFile<T>>>,
+    // Image the unallocated clusters are read from, when used as an overlay.
+    backing_file: Option<BackingFile>,
 }
 
 impl QcowFile {
@@ -527,6 +584,7 @@ impl QcowFile {
             current_offset: 0,
             unref_clusters: Vec::new(),
             avail_clusters: Vec::new(),
+            backing_file: None,
         };
 
         // Check that the L1 and refcount tables fit in a 64bit address space.
@@ -570,6 +628,51 @@ impl QcowFile {
         Ok(qcow)
     }
 
+    /// Opens `overlay` as the QCOW2 image holding the writes made on top of
+    /// the read-only `backing` image, any of the supported image types. An
+    /// empty overlay is initialized with the size of the backing image.
+    ///
+    /// The backing image isn't referenced from the overlay header, it has
+    /// to be given again every time the overlay is opened.
+    pub fn new_overlay(mut backing: RawFile, overlay: RawFile) -> Result<QcowFile> {
+        let mut backing_image: Box<dyn BackingImage> = match detect_image_type(&mut backing)? {
+            ImageType::Raw => Box::new(backing),
+            ImageType::Qcow2 => {
+                Box::new(QcowFile::from(backing).map_err(|e| Error::BackingFileQcow(Box::new(e)))?)
+            }
+            ImageType::Vhd => Box::new(VhdFile::from(backing).map_err(Error::BackingFileVhd)?),
+            ImageType::Vhdx => Box::new(VhdxFile::from(backing).map_err(Error::BackingFileVhdx)?),
+        };
+
+        let overlay_size = overlay.metadata().map_err(Error::GettingFileSize)?.len();
+        let mut qcow = if overlay_size == 0 {
+            let size = backing_image
+                .seek(SeekFrom::End(0))
+                .map_err(Error::SeekingFile)?;
+            QcowFile::new(overlay, 3, size)?
+        } else {
+            QcowFile::from(overlay)?
+        };
+
+        qcow.set_backing_image(backing_image)?;
+        Ok(qcow)
+    }
+
+    // Makes the unallocated clusters read from the backing image, which
+    // must have the same size as this image.
+    fn set_backing_image(&mut self, mut image: Box<dyn BackingImage>) -> Result<()> {
+        let size = image.seek(SeekFrom::End(0)).map_err(Error::SeekingFile)?;
+        if size != self.virtual_size() {
+            return Err(Error::BackingFileSizeMismatch(self.virtual_size(), size));
+        }
+
+        self.backing_file = Some(BackingFile {
+            image: Arc::new(Mutex::new(image)),
+            size,
+        });
+        Ok(())
+    }
+
     /// Returns the `QcowHeader` for this file.
     pub fn header(&self) -> &QcowHeader {
         &self.header
@@ -1032,6 +1135,7 @@ impl QcowFile {
                 // Need to allocate a data cluster
                 let cluster_addr = self.append_data_cluster()?;
                 self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                self.copy_backing_cluster(address, cluster_addr)?;
                 cluster_addr
             }
             a => a,
@@ -1078,6 +1182,24 @@ impl QcowFile {
         Ok(())
     }
 
+    // Copies the content of the cluster containing `address` from the backing
+    // image to the newly allocated `cluster_addr`, before it gets partially
+    // overwritten.
+    fn copy_backing_cluster(&mut self, address: u64, cluster_addr: u64) -> std::io::Result<()> {
+        let backing_file = match &self.backing_file {
+            Some(backing_file) => backing_file,
+            None => return Ok(()),
+        };
+
+        let cluster_size = self.raw_file.cluster_size();
+        let mut data = vec![0u8; cluster_size as usize];
+        backing_file.read_at(address - self.raw_file.cluster_offset(address), &mut data)?;
+        self.raw_file
+            .file_mut()
+            .seek(SeekFrom::Start(cluster_addr))?;
+        self.raw_file.file_mut().write_all(&data)
+    }
+
     // Allocate a new cluster and return its offset within the raw file.
     fn get_new_cluster(&mut self) -> std::io::Result<u64> {
         // First use a pre allocated cluster if one is available.
@@ -1416,6 +1538,8 @@ impl Read for QcowFile {
                 self.raw_file
                     .file_mut()
                     .read_exact(&mut buf[nread..(nread + count)])?;
+            } else if let Some(backing_file) = &self.backing_file {
+                backing_file.read_at(curr_addr, &mut buf[nread..(nread + count)])?;
             } else {
                 // Previously unwritten region, return zeros
                 for b in &mut buf[nread..(nread + count)] {
@@ -1514,6 +1638,12 @@ impl FileSetLen for QcowFile {
 
 impl PunchHole for QcowFile {
     fn punch_hole(&mut self, offset: u64, length: u64) -> std::io::Result<()> {
+        // Deallocated clusters would expose the backing image content
+        // instead of reading as zeros.
+        if self.backing_file.is_some() {
+            return Err(std::io::Error::from_raw_os_error(EOPNOTSUPP));
+        }
+
         let mut remaining = length;
         let mut offset = offset;
         while remaining > 0 {
@@ -1660,6 +1790,7 @@ where
                 .map_err(Error::SettingFileSize)?;
             convert_reader_writer(reader, &mut dst_writer, src_size)
         }
+        ImageType::Vhd | ImageType::Vhdx => Err(Error::UnsupportedImageType),
     }
 }
 
@@ -1678,18 +1809,42 @@ pub fn convert(mut src_file: RawFile, dst_file: RawFile, dst_type: ImageType) ->
             let mut src_reader = src_file;
             convert_reader(&mut src_reader, dst_file, dst_type)
         }
+        ImageType::Vhd | ImageType::Vhdx => Err(Error::UnsupportedImageType),
     }
 }
 
-/// Detect the type of an image file by checking for a valid qcow2 header.
+// Returns whether the file holds `signature` at `offset`.
+fn has_signature(file: &mut RawFile, offset: u64, signature: &[u8]) -> Result<bool> {
+    let mut buf = vec![0u8; signature.len()];
+    file.seek(SeekFrom::Start(offset))
+        .map_err(Error::SeekingFile)?;
+    file.read_exact(&mut buf).map_err(Error::ReadingHeader)?;
+    Ok(buf == signature)
+}
+
+/// Detect the type of an image file by checking for a valid qcow2 header,
+/// VHDX file identifier or VHD footer.
+
 pub fn detect_image_type(file: &mut RawFile) -> Result<ImageType> {
     let orig_seek = file
         .seek(SeekFrom::Current(0))
         .map_err(Error::SeekingFile)?;
+    let file_size = file.seek(SeekFrom::End(0)).map_err(Error::SeekingFile)?;
     file.seek(SeekFrom::Start(0)).map_err(Error::SeekingFile)?;
     let magic = file.read_u32::<BigEndian>().map_err(Error::ReadingHeader)?;
+    // Dynamic VHD images start with a copy of the footer, while fixed ones
+    // only have it at the end.
     let image_type = if magic == QCOW_MAGIC {
         ImageType::Qcow2
+    } else if file_size >= vhdx::FILE_SIGNATURE.len() as u64
+        && has_signature(file, 0, vhdx::FILE_SIGNATURE)?
+    {
+        ImageType::Vhdx
+    } else if file_size >= vhd::FOOTER_SIZE
+        && (has_signature(file, 0, vhd::FOOTER_COOKIE)?
+            || has_signature(file, file_size - vhd::FOOTER_SIZE, vhd::FOOTER_COOKIE)?)
+    {
+        ImageType::Vhd
     } else {
         ImageType::Raw
     };
@@ -2463,6 +2618,45 @@ mod tests {
         });
     }
 
+    #[test]
+    fn overlay_read_write() {
+        let mut backing = RawFile::new(tempfile().unwrap(), false);
+        backing.write_all(&[0xaau8; 0x30000]).unwrap();
+        let overlay = RawFile::new(tempfile().unwrap(), false);
+
+        let mut file = QcowFile::new_overlay(backing.clone(), overlay.clone()).unwrap();
+        assert_eq!(file.seek(SeekFrom::End(0)).unwrap(), 0x30000);
+
+        // The cluster is copied from the backing file before being written.
+        file.seek(SeekFrom::Start(0x10080)).unwrap();
+        file.write_all(&[0x55u8; 0x100]).unwrap();
+        assert!(file.punch_hole(0, 0x10000).is_err());
+        drop(file);
+
+        // The writes are found again once the overlay is reopened, and the
+        // backing file is left untouched.
+        let mut file = QcowFile::new_overlay(backing.clone(), overlay).unwrap();
+        let mut buf = vec![0u8; 0x20000];
+        file.seek(SeekFrom::Start(0x10000)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert!(buf[..0x80].iter().all(|b| *b == 0xaa));
+        assert!(buf[0x80..0x180].iter().all(|b| *b == 0x55));
+        assert!(buf[0x180..].iter().all(|b| *b == 0xaa));
+
+        backing.seek(SeekFrom::Start(0x10000)).unwrap();
+        backing.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 0xaa));
+
+        // The overlay must match the size of the backing file.
+        backing.set_len(0x40000).unwrap();
+        let overlay = RawFile::new(tempfile().unwrap(), false);
+        QcowFile::new(overlay.clone(), 3, 0x30000).unwrap();
+        match QcowFile::new_overlay(backing, overlay) {
+            Err(Error::BackingFileSizeMismatch(0x30000, 0x40000)) => (),
+            _ => panic!("overlay of a different size accepted"),
+        }
+    }
+
     fn seek_cur(file: &mut QcowFile) -> u64 {
         file.seek(SeekFrom::Current(0)).unwrap()
     }
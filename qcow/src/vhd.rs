@@ -0,0 +1,531 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Fixed and dynamic VHD images, as described by the Virtual Hard Disk Image
+//! Format Specification.
+//!
+//! A fixed image is the raw disk content followed by a 512 bytes footer. A
+//! dynamic image starts with a copy of the footer and a header pointing to
+//! the Block Allocation Table (BAT), which maps each block of the disk to
+//! its location in the file. Blocks are allocated at the end of the file on
+//! their first write, and read as zeros until then.
+
+use byteorder::{BigEndian, ByteOrder};
+use libc::{EINVAL, ENOSPC};
+use std::cmp::min;
+use std::fmt::{self, Display};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use vm_virtio::RawFile;
+use vmm_sys_util::write_zeroes::PunchHole;
+
+pub(crate) const FOOTER_SIZE: u64 = 512;
+pub(crate) const FOOTER_COOKIE: &[u8] = b"conectix";
+const FOOTER_CHECKSUM_OFFSET: usize = 64;
+
+const DYNAMIC_HEADER_SIZE: usize = 1024;
+const DYNAMIC_HEADER_COOKIE: &[u8] = b"cxsparse";
+const DYNAMIC_HEADER_CHECKSUM_OFFSET: usize = 36;
+
+const DISK_TYPE_FIXED: u32 = 2;
+const DISK_TYPE_DYNAMIC: u32 = 3;
+const DISK_TYPE_DIFFERENCING: u32 = 4;
+
+const SECTOR_SIZE: u64 = 512;
+const BAT_ENTRY_UNUSED: u32 = 0xffff_ffff;
+// Keeps the BAT, which lives in RAM, under 64MB.
+const MAX_BAT_ENTRIES: u32 = 1 << 24;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The block size isn't a power of two multiple of the sector size.
+    InvalidBlockSize(u32),
+    /// The dynamic disk header is invalid.
+    InvalidDynamicHeader,
+    /// The dynamic disk header checksum doesn't match its content.
+    InvalidDynamicHeaderChecksum,
+    /// The footer is invalid.
+    InvalidFooter,
+    /// The footer checksum doesn't match its content.
+    InvalidFooterChecksum,
+    /// Cannot read the BAT.
+    ReadingBat(io::Error),
+    /// Cannot read the dynamic disk header.
+    ReadingDynamicHeader(io::Error),
+    /// Cannot read the footer.
+    ReadingFooter(io::Error),
+    /// The BAT is too large to be kept in RAM.
+    TooManyBatEntries(u32),
+    /// Differencing images aren't supported.
+    UnsupportedDifferencingDisk,
+    /// The disk type is unknown.
+    UnsupportedDiskType(u32),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidBlockSize(size) => write!(f, "invalid block size {}", size),
+            InvalidDynamicHeader => write!(f, "invalid dynamic disk header"),
+            InvalidDynamicHeaderChecksum => write!(f, "invalid dynamic disk header checksum"),
+            InvalidFooter => write!(f, "invalid footer"),
+            InvalidFooterChecksum => write!(f, "invalid footer checksum"),
+            ReadingBat(e) => write!(f, "failed to read the BAT: {}", e),
+            ReadingDynamicHeader(e) => write!(f, "failed to read dynamic disk header: {}", e),
+            ReadingFooter(e) => write!(f, "failed to read footer: {}", e),
+            TooManyBatEntries(count) => write!(f, "BAT too large: {}", count),
+            UnsupportedDifferencingDisk => write!(f, "differencing disks not supported"),
+            UnsupportedDiskType(t) => write!(f, "unsupported disk type {}", t),
+        }
+    }
+}
+
+// One's complement of the sum of all the bytes, skipping the checksum field.
+fn checksum(buf: &[u8], checksum_offset: usize) -> u32 {
+    let sum = buf
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i < checksum_offset || *i >= checksum_offset + 4)
+        .fold(0u32, |sum, (_, b)| sum.wrapping_add(u32::from(*b)));
+    !sum
+}
+
+fn read_at(file: &mut RawFile, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+fn write_at(file: &mut RawFile, offset: u64, buf: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buf)
+}
+
+#[derive(Clone, Debug)]
+struct DynamicDisk {
+    bat_offset: u64,
+    bat: Vec<u32>,
+    block_size: u64,
+    // Each block starts with a bitmap of its sectors, padded to a sector.
+    bitmap_size: u64,
+    // The footer always ends the file, and moves whenever a block is added.
+    footer_offset: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct VhdFile {
+    file: RawFile,
+    footer: Vec<u8>,
+    virtual_size: u64,
+    dynamic: Option<DynamicDisk>,
+    current_offset: u64,
+}
+
+impl VhdFile {
+    /// Creates a VhdFile from `file`, which must be a fixed or dynamic VHD
+    /// image.
+    pub fn from(mut file: RawFile) -> Result<VhdFile> {
+        let file_size = file.seek(SeekFrom::End(0)).map_err(Error::ReadingFooter)?;
+        if file_size < FOOTER_SIZE {
+            return Err(Error::InvalidFooter);
+        }
+
+        let footer_offset = file_size - FOOTER_SIZE;
+        let mut footer = vec![0u8; FOOTER_SIZE as usize];
+        read_at(&mut file, footer_offset, &mut footer).map_err(Error::ReadingFooter)?;
+        if &footer[0..8] != FOOTER_COOKIE {
+            return Err(Error::InvalidFooter);
+        }
+        if BigEndian::read_u32(&footer[FOOTER_CHECKSUM_OFFSET..])
+            != checksum(&footer, FOOTER_CHECKSUM_OFFSET)
+        {
+            return Err(Error::InvalidFooterChecksum);
+        }
+
+        let virtual_size = BigEndian::read_u64(&footer[48..56]);
+        let dynamic = match BigEndian::read_u32(&footer[60..64]) {
+            DISK_TYPE_FIXED => {
+                if virtual_size > footer_offset {
+                    return Err(Error::InvalidFooter);
+                }
+                None
+            }
+            DISK_TYPE_DYNAMIC => Some(Self::read_dynamic_disk(
+                &mut file,
+                BigEndian::read_u64(&footer[16..24]),
+                virtual_size,
+                footer_offset,
+            )?),
+            DISK_TYPE_DIFFERENCING => return Err(Error::UnsupportedDifferencingDisk),
+            disk_type => return Err(Error::UnsupportedDiskType(disk_type)),
+        };
+
+        Ok(VhdFile {
+            file,
+            footer,
+            virtual_size,
+            dynamic,
+            current_offset: 0,
+        })
+    }
+
+    fn read_dynamic_disk(
+        file: &mut RawFile,
+        header_offset: u64,
+        virtual_size: u64,
+        footer_offset: u64,
+    ) -> Result<DynamicDisk> {
+        let mut header = vec![0u8; DYNAMIC_HEADER_SIZE];
+        read_at(file, header_offset, &mut header).map_err(Error::ReadingDynamicHeader)?;
+        if &header[0..8] != DYNAMIC_HEADER_COOKIE {
+            return Err(Error::InvalidDynamicHeader);
+        }
+        if BigEndian::read_u32(&header[DYNAMIC_HEADER_CHECKSUM_OFFSET..])
+            != checksum(&header, DYNAMIC_HEADER_CHECKSUM_OFFSET)
+        {
+            return Err(Error::InvalidDynamicHeaderChecksum);
+        }
+
+        let block_size = BigEndian::read_u32(&header[32..36]);
+        if !block_size.is_power_of_two() || u64::from(block_size) < SECTOR_SIZE {
+            return Err(Error::InvalidBlockSize(block_size));
+        }
+        let block_size = u64::from(block_size);
+
+        let entries = BigEndian::read_u32(&header[28..32]);
+        if entries > MAX_BAT_ENTRIES {
+            return Err(Error::TooManyBatEntries(entries));
+        }
+        if u64::from(entries) * block_size < virtual_size {
+            return Err(Error::InvalidDynamicHeader);
+        }
+
+        let bat_offset = BigEndian::read_u64(&header[16..24]);
+        let mut raw_bat = vec![0u8; entries as usize * 4];
+        read_at(file, bat_offset, &mut raw_bat).map_err(Error::ReadingBat)?;
+        let bat = raw_bat.chunks(4).map(BigEndian::read_u32).collect();
+
+        // One bit per sector, rounded up to a whole sector.
+        let bitmap_bytes = (block_size / SECTOR_SIZE + 7) / 8;
+        let bitmap_size = (bitmap_bytes + SECTOR_SIZE - 1) / SECTOR_SIZE * SECTOR_SIZE;
+
+        Ok(DynamicDisk {
+            bat_offset,
+            bat,
+            block_size,
+            bitmap_size,
+            footer_offset,
+        })
+    }
+
+    /// Returns the size of the disk, as seen by the guest.
+    pub fn virtual_size(&self) -> u64 {
+        self.virtual_size
+    }
+
+    // Returns the file offset `address` is stored at, if allocated, and how
+    // many of the next `count` bytes are contiguous in the file.
+    fn map(&self, address: u64, count: usize) -> (Option<u64>, usize) {
+        match &self.dynamic {
+            None => (Some(address), count),
+            Some(disk) => {
+                let in_block = address % disk.block_size;
+                let count = min(count as u64, disk.block_size - in_block) as usize;
+                match disk.bat[(address / disk.block_size) as usize] {
+                    BAT_ENTRY_UNUSED => (None, count),
+                    sector => (
+                        Some(u64::from(sector) * SECTOR_SIZE + disk.bitmap_size + in_block),
+                        count,
+                    ),
+                }
+            }
+        }
+    }
+
+    // Adds the block holding `address` at the end of the file, with all its
+    // sectors marked as present, and returns its file offset.
+    fn allocate_block(&mut self, address: u64) -> io::Result<u64> {
+        let disk = self.dynamic.as_mut().unwrap();
+        let block = address / disk.block_size;
+        let block_offset = disk.footer_offset;
+        let sector = block_offset / SECTOR_SIZE;
+        if sector >= u64::from(BAT_ENTRY_UNUSED) {
+            return Err(io::Error::from_raw_os_error(ENOSPC));
+        }
+
+        // The footer is written at its new location first, so that the file
+        // always ends with one. The block data is zeroed by extending the
+        // file, and the old footer is overwritten by the bitmap.
+        let footer_offset = block_offset + disk.bitmap_size + disk.block_size;
+        self.file.set_len(footer_offset + FOOTER_SIZE)?;
+        write_at(&mut self.file, footer_offset, &self.footer)?;
+        write_at(
+            &mut self.file,
+            block_offset,
+            &vec![0xffu8; disk.bitmap_size as usize],
+        )?;
+
+        let mut entry = [0u8; 4];
+        BigEndian::write_u32(&mut entry, sector as u32);
+        write_at(&mut self.file, disk.bat_offset + block * 4, &entry)?;
+
+        disk.bat[block as usize] = sector as u32;
+        disk.footer_offset = footer_offset;
+
+        Ok(block_offset + disk.bitmap_size + address % disk.block_size)
+    }
+
+    // Limits the number of bytes to transfer to the end of the disk.
+    fn limit_range(&self, count: usize) -> usize {
+        min(
+            count as u64,
+            self.virtual_size.saturating_sub(self.current_offset),
+        ) as usize
+    }
+}
+
+impl Read for VhdFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_count = self.limit_range(buf.len());
+
+        let mut nread = 0;
+        while nread < read_count {
+            let address = self.current_offset + nread as u64;
+            let (file_offset, count) = self.map(address, read_count - nread);
+            let buf = &mut buf[nread..(nread + count)];
+
+            if let Some(offset) = file_offset {
+                read_at(&mut self.file, offset, buf)?;
+            } else {
+                // Unallocated blocks read as zeros.
+                for b in buf.iter_mut() {
+                    *b = 0;
+                }
+            }
+
+            nread += count;
+        }
+        self.current_offset += read_count as u64;
+        Ok(read_count)
+    }
+}
+
+impl Write for VhdFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let write_count = self.limit_range(buf.len());
+
+        let mut nwritten = 0;
+        while nwritten < write_count {
+            let address = self.current_offset + nwritten as u64;
+            let (file_offset, count) = self.map(address, write_count - nwritten);
+            let offset = match file_offset {
+                Some(offset) => offset,
+                None => self.allocate_block(address)?,
+            };
+
+            write_at(&mut self.file, offset, &buf[nwritten..(nwritten + count)])?;
+
+            nwritten += count;
+        }
+        self.current_offset += write_count as u64;
+        Ok(write_count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl PunchHole for VhdFile {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        let end = min(offset.saturating_add(length), self.virtual_size);
+        let mut address = offset;
+        while address < end {
+            let (file_offset, count) = self.map(address, (end - address) as usize);
+            // Unallocated blocks already read as zeros.
+            if let Some(file_offset) = file_offset {
+                self.file.punch_hole(file_offset, count as u64)?;
+            }
+            address += count as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Seek for VhdFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(off) => {
+                if off < 0 {
+                    self.virtual_size.checked_sub(off.wrapping_neg() as u64)
+                } else {
+                    self.virtual_size.checked_add(off as u64)
+                }
+            }
+            SeekFrom::Current(off) => {
+                if off < 0 {
+                    self.current_offset.checked_sub(off.wrapping_neg() as u64)
+                } else {
+                    self.current_offset.checked_add(off as u64)
+                }
+            }
+        };
+
+        match new_offset {
+            Some(offset) if offset <= self.virtual_size => {
+                self.current_offset = offset;
+                Ok(offset)
+            }
+            _ => Err(io::Error::from_raw_os_error(EINVAL)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempfile;
+
+    const BLOCK_SIZE: u32 = 0x20_0000;
+    const DISK_SIZE: u64 = 4 * BLOCK_SIZE as u64;
+
+    fn footer(disk_type: u32, data_offset: u64) -> Vec<u8> {
+        let mut footer = vec![0u8; FOOTER_SIZE as usize];
+        footer[0..8].copy_from_slice(FOOTER_COOKIE);
+        BigEndian::write_u32(&mut footer[8..12], 2);
+        BigEndian::write_u32(&mut footer[12..16], 0x0001_0000);
+        BigEndian::write_u64(&mut footer[16..24], data_offset);
+        BigEndian::write_u64(&mut footer[40..48], DISK_SIZE);
+        BigEndian::write_u64(&mut footer[48..56], DISK_SIZE);
+        BigEndian::write_u32(&mut footer[60..64], disk_type);
+        let sum = checksum(&footer, FOOTER_CHECKSUM_OFFSET);
+        BigEndian::write_u32(&mut footer[64..68], sum);
+        footer
+    }
+
+    fn fixed_image() -> RawFile {
+        let mut file = RawFile::new(tempfile().unwrap(), false);
+        file.set_len(DISK_SIZE).unwrap();
+        write_at(&mut file, DISK_SIZE, &footer(DISK_TYPE_FIXED, u64::MAX)).unwrap();
+        file
+    }
+
+    // Footer copy, dynamic disk header, then a one sector BAT.
+    fn dynamic_image() -> RawFile {
+        let mut file = RawFile::new(tempfile().unwrap(), false);
+        let footer = footer(DISK_TYPE_DYNAMIC, FOOTER_SIZE);
+        write_at(&mut file, 0, &footer).unwrap();
+
+        let mut header = vec![0u8; DYNAMIC_HEADER_SIZE];
+        header[0..8].copy_from_slice(DYNAMIC_HEADER_COOKIE);
+        BigEndian::write_u64(&mut header[8..16], u64::MAX);
+        BigEndian::write_u64(&mut header[16..24], 1536);
+        BigEndian::write_u32(&mut header[24..28], 0x0001_0000);
+        BigEndian::write_u32(&mut header[28..32], 4);
+        BigEndian::write_u32(&mut header[32..36], BLOCK_SIZE);
+        let sum = checksum(&header, DYNAMIC_HEADER_CHECKSUM_OFFSET);
+        BigEndian::write_u32(&mut header[36..40], sum);
+        write_at(&mut file, FOOTER_SIZE, &header).unwrap();
+
+        write_at(&mut file, 1536, &[0xffu8; 512]).unwrap();
+        write_at(&mut file, 2048, &footer).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_fixed_read_write() {
+        let mut disk = VhdFile::from(fixed_image()).unwrap();
+        assert_eq!(disk.seek(SeekFrom::End(0)).unwrap(), DISK_SIZE);
+
+        disk.seek(SeekFrom::Start(0x1000)).unwrap();
+        disk.write_all(&[0x55; 0x1000]).unwrap();
+        let mut buf = [0u8; 0x2000];
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert!(buf[..0x1000].iter().all(|b| *b == 0));
+        assert!(buf[0x1000..].iter().all(|b| *b == 0x55));
+
+        // Nothing is written past the disk, keeping the footer intact.
+        disk.seek(SeekFrom::End(-2)).unwrap();
+        assert_eq!(disk.write(&[0xaa; 4]).unwrap(), 2);
+        assert!(VhdFile::from(disk.file.clone()).is_ok());
+    }
+
+    #[test]
+    fn test_dynamic_read_write() {
+        let mut disk = VhdFile::from(dynamic_image()).unwrap();
+        assert_eq!(disk.virtual_size(), DISK_SIZE);
+
+        // Unallocated blocks read as zeros.
+        let mut buf = vec![0xffu8; 0x1000];
+        disk.seek(SeekFrom::Start(0x10_0000)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+
+        // Cross the boundary between the first and second blocks.
+        let address = u64::from(BLOCK_SIZE) - 0x800;
+        disk.seek(SeekFrom::Start(address)).unwrap();
+        disk.write_all(&[0x55; 0x1000]).unwrap();
+
+        // The allocated blocks and the BAT are found again once reopened.
+        let mut disk = VhdFile::from(disk.file.clone()).unwrap();
+        let bat = &disk.dynamic.as_ref().unwrap().bat;
+        assert_ne!(bat[0], BAT_ENTRY_UNUSED);
+        assert_ne!(bat[1], BAT_ENTRY_UNUSED);
+        assert_eq!(bat[2], BAT_ENTRY_UNUSED);
+
+        let mut buf = vec![0u8; 0x2000];
+        disk.seek(SeekFrom::Start(address - 0x800)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert!(buf[..0x800].iter().all(|b| *b == 0));
+        assert!(buf[0x800..0x1800].iter().all(|b| *b == 0x55));
+        assert!(buf[0x1800..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_dynamic_punch_hole() {
+        let mut disk = VhdFile::from(dynamic_image()).unwrap();
+        disk.write_all(&[0x55; 0x2000]).unwrap();
+
+        // Punching holes zeroes allocated and unallocated ranges alike.
+        disk.punch_hole(0x800, 0x1000).unwrap();
+        disk.punch_hole(u64::from(BLOCK_SIZE), 0x1000).unwrap();
+        assert_eq!(disk.dynamic.as_ref().unwrap().bat[1], BAT_ENTRY_UNUSED);
+
+        let mut buf = vec![0u8; 0x2000];
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert!(buf[..0x800].iter().all(|b| *b == 0x55));
+        assert!(buf[0x800..0x1800].iter().all(|b| *b == 0));
+        assert!(buf[0x1800..].iter().all(|b| *b == 0x55));
+    }
+
+    #[test]
+    fn test_invalid_footer_checksum() {
+        let mut file = fixed_image();
+        write_at(&mut file, DISK_SIZE + 48, &[0x01]).unwrap();
+        match VhdFile::from(file) {
+            Err(Error::InvalidFooterChecksum) => (),
+            _ => panic!("corrupted footer accepted"),
+        }
+    }
+
+    #[test]
+    fn test_differencing_rejected() {
+        let mut file = fixed_image();
+        write_at(
+            &mut file,
+            DISK_SIZE,
+            &footer(DISK_TYPE_DIFFERENCING, FOOTER_SIZE),
+        )
+        .unwrap();
+        match VhdFile::from(file) {
+            Err(Error::UnsupportedDifferencingDisk) => (),
+            _ => panic!("differencing disk accepted"),
+        }
+    }
+}
@@ -0,0 +1,724 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! VHDX images, as described by the VHDX Format Specification.
+//!
+//! The file starts with an identifier, followed by two copies of the header
+//! and of the region table. The region table locates the metadata, giving
+//! the disk geometry, and the Block Allocation Table (BAT), which maps each
+//! payload block to its location in the file. Blocks are allocated at the
+//! end of the file on their first write.
+//!
+//! Images whose log needs to be replayed, as well as differencing images,
+//! aren't supported. The BAT is updated in place rather than through the
+//! log.
+
+use byteorder::{ByteOrder, LittleEndian};
+use libc::EINVAL;
+use std::cmp::min;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use vm_virtio::RawFile;
+use vmm_sys_util::write_zeroes::PunchHole;
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+
+pub(crate) const FILE_SIGNATURE: &[u8] = b"vhdxfile";
+
+const HEADER_OFFSETS: [u64; 2] = [64 * KIB, 128 * KIB];
+const HEADER_SIZE: usize = 4096;
+const HEADER_SIGNATURE: &[u8] = b"head";
+const HEADER_VERSION: u16 = 1;
+
+const REGION_TABLE_OFFSETS: [u64; 2] = [192 * KIB, 256 * KIB];
+const REGION_TABLE_SIZE: usize = 64 * 1024;
+const REGION_TABLE_SIGNATURE: &[u8] = b"regi";
+const REGION_ENTRY_SIZE: usize = 32;
+const MAX_REGION_ENTRIES: usize = 2047;
+const REGION_REQUIRED: u32 = 1;
+
+const METADATA_SIGNATURE: &[u8] = b"metadata";
+const METADATA_ENTRY_SIZE: usize = 32;
+const MAX_METADATA_ENTRIES: usize = 2047;
+const MAX_METADATA_SIZE: u64 = 16 * MIB;
+const METADATA_REQUIRED: u32 = 1 << 2;
+const FILE_PARAMETERS_HAS_PARENT: u32 = 1 << 1;
+
+// GUIDs, in their on-disk form where the first three fields are little
+// endian.
+const BAT_GUID: [u8; 16] = [
+    0x66, 0x77, 0xc2, 0x2d, 0x23, 0xf6, 0x00, 0x42, 0x9d, 0x64, 0x11, 0x5e, 0x9b, 0xfd, 0x4a, 0x08,
+];
+const METADATA_GUID: [u8; 16] = [
+    0x06, 0xa2, 0x7c, 0x8b, 0x90, 0x47, 0x9a, 0x4b, 0xb8, 0xfe, 0x57, 0x5f, 0x05, 0x0f, 0x88, 0x6e,
+];
+const FILE_PARAMETERS_GUID: [u8; 16] = [
+    0x37, 0x67, 0xa1, 0xca, 0x36, 0xfa, 0x43, 0x4d, 0xb3, 0xb6, 0x33, 0xf0, 0xaa, 0x44, 0xe7, 0x6b,
+];
+const VIRTUAL_DISK_SIZE_GUID: [u8; 16] = [
+    0x24, 0x42, 0xa5, 0x2f, 0x1b, 0xcd, 0x76, 0x48, 0xb2, 0x11, 0x5d, 0xbe, 0xd8, 0x3b, 0xf4, 0xb8,
+];
+const LOGICAL_SECTOR_SIZE_GUID: [u8; 16] = [
+    0x1d, 0xbf, 0x41, 0x81, 0x6f, 0xa9, 0x09, 0x47, 0xba, 0x47, 0xf2, 0x33, 0xa8, 0xfa, 0xab, 0x5f,
+];
+const PHYSICAL_SECTOR_SIZE_GUID: [u8; 16] = [
+    0xc7, 0x48, 0xa3, 0xcd, 0x5d, 0x44, 0x71, 0x44, 0x9c, 0xc9, 0xe9, 0x88, 0x52, 0x51, 0xc5, 0x56,
+];
+const PAGE_83_DATA_GUID: [u8; 16] = [
+    0xab, 0x12, 0xca, 0xbe, 0xe6, 0xb2, 0x23, 0x45, 0x93, 0xef, 0xc3, 0x09, 0xe0, 0x00, 0xc7, 0x46,
+];
+
+const MIN_BLOCK_SIZE: u32 = 1 << 20;
+const MAX_BLOCK_SIZE: u32 = 256 << 20;
+
+// Number of sectors described by a sector bitmap block.
+const SECTORS_PER_BITMAP_BLOCK: u64 = 1 << 23;
+
+const BAT_STATE_MASK: u64 = 0x7;
+const BAT_OFFSET_MASK: u64 = !(MIB - 1);
+const PAYLOAD_BLOCK_FULLY_PRESENT: u64 = 6;
+// Keeps the BAT, which lives in RAM, under 64MB.
+const MAX_BAT_ENTRIES: u64 = 1 << 23;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The BAT doesn't fit in its region, or is too large to be kept in RAM.
+    InvalidBatSize(u64),
+    /// The block size isn't a power of two between 1MB and 256MB.
+    InvalidBlockSize(u32),
+    /// The file doesn't start with the VHDX identifier.
+    InvalidFileIdentifier,
+    /// The logical sector size is neither 512 nor 4096 bytes.
+    InvalidLogicalSectorSize(u32),
+    /// The metadata region is invalid.
+    InvalidMetadata,
+    /// The log must be replayed before the image can be used.
+    LogReplayNotSupported,
+    /// A required metadata item is missing.
+    MissingMetadata,
+    /// The BAT or metadata region is missing.
+    MissingRegion,
+    /// Neither copy of the header is valid.
+    NoValidHeader,
+    /// Neither copy of the region table is valid.
+    NoValidRegionTable,
+    /// Cannot read the BAT.
+    ReadingBat(io::Error),
+    /// Cannot read a header.
+    ReadingHeader(io::Error),
+    /// Cannot read the metadata.
+    ReadingMetadata(io::Error),
+    /// Cannot read a region table.
+    ReadingRegionTable(io::Error),
+    /// Differencing images aren't supported.
+    UnsupportedDifferencingDisk,
+    /// A required metadata item is unknown.
+    UnsupportedMetadata,
+    /// A required region is unknown.
+    UnsupportedRegion,
+    /// The header version is unknown.
+    UnsupportedVersion(u16),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidBatSize(count) => write!(f, "invalid BAT size: {} entries", count),
+            InvalidBlockSize(size) => write!(f, "invalid block size {}", size),
+            InvalidFileIdentifier => write!(f, "invalid file identifier"),
+            InvalidLogicalSectorSize(size) => write!(f, "invalid logical sector size {}", size),
+            InvalidMetadata => write!(f, "invalid metadata"),
+            LogReplayNotSupported => write!(f, "log replay not supported"),
+            MissingMetadata => write!(f, "missing required metadata"),
+            MissingRegion => write!(f, "missing BAT or metadata region"),
+            NoValidHeader => write!(f, "no valid header"),
+            NoValidRegionTable => write!(f, "no valid region table"),
+            ReadingBat(e) => write!(f, "failed to read the BAT: {}", e),
+            ReadingHeader(e) => write!(f, "failed to read header: {}", e),
+            ReadingMetadata(e) => write!(f, "failed to read metadata: {}", e),
+            ReadingRegionTable(e) => write!(f, "failed to read region table: {}", e),
+            UnsupportedDifferencingDisk => write!(f, "differencing disks not supported"),
+            UnsupportedMetadata => write!(f, "unsupported required metadata"),
+            UnsupportedRegion => write!(f, "unsupported required region"),
+            UnsupportedVersion(v) => write!(f, "unsupported version: {}", v),
+        }
+    }
+}
+
+// CRC-32C (Castagnoli), as used by the header and region table checksums.
+fn crc32c(buf: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in buf {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// Checks the CRC-32C stored at offset 4, computed with that field zeroed.
+fn valid_checksum(buf: &[u8]) -> bool {
+    let mut zeroed = buf.to_vec();
+    LittleEndian::write_u32(&mut zeroed[4..8], 0);
+    LittleEndian::read_u32(&buf[4..8]) == crc32c(&zeroed)
+}
+
+fn set_checksum(buf: &mut [u8]) {
+    LittleEndian::write_u32(&mut buf[4..8], 0);
+    let crc = crc32c(buf);
+    LittleEndian::write_u32(&mut buf[4..8], crc);
+}
+
+fn read_at(file: &mut RawFile, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+fn write_at(file: &mut RawFile, offset: u64, buf: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buf)
+}
+
+// Random (version 4) GUID.
+fn random_guid() -> io::Result<[u8; 16]> {
+    let mut guid = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut guid)?;
+    guid[7] = (guid[7] & 0x0f) | 0x40;
+    guid[8] = (guid[8] & 0x3f) | 0x80;
+    Ok(guid)
+}
+
+#[derive(Clone, Debug)]
+pub struct VhdxFile {
+    file: RawFile,
+    // Current header, and which of the two copies it is.
+    header: Vec<u8>,
+    header_index: usize,
+    // Whether the header has been updated since the image was opened.
+    header_updated: bool,
+    bat_offset: u64,
+    bat: Vec<u64>,
+    block_size: u64,
+    chunk_ratio: u64,
+    virtual_size: u64,
+    file_size: u64,
+    current_offset: u64,
+}
+
+impl VhdxFile {
+    /// Creates a VhdxFile from `file`, which must be a VHDX image.
+    pub fn from(mut file: RawFile) -> Result<VhdxFile> {
+        let mut signature = [0u8; 8];
+        read_at(&mut file, 0, &mut signature).map_err(Error::ReadingHeader)?;
+        if signature != FILE_SIGNATURE {
+            return Err(Error::InvalidFileIdentifier);
+        }
+
+        let (header, header_index) = Self::read_header(&mut file)?;
+        let version = LittleEndian::read_u16(&header[66..68]);
+        if version != HEADER_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        if header[48..64].iter().any(|b| *b != 0) {
+            return Err(Error::LogReplayNotSupported);
+        }
+
+        let ((bat_offset, bat_length), (metadata_offset, metadata_length)) =
+            Self::read_region_table(&mut file)?;
+
+        if metadata_length > MAX_METADATA_SIZE {
+            return Err(Error::InvalidMetadata);
+        }
+        let mut metadata = vec![0u8; metadata_length as usize];
+        read_at(&mut file, metadata_offset, &mut metadata).map_err(Error::ReadingMetadata)?;
+        let (block_size, virtual_size, logical_sector_size) = Self::parse_metadata(&metadata)?;
+
+        let chunk_ratio = SECTORS_PER_BITMAP_BLOCK * logical_sector_size / block_size;
+        let payload_blocks = (virtual_size + block_size - 1) / block_size;
+        // A sector bitmap entry follows every chunk_ratio payload entries.
+        let entries = payload_blocks + payload_blocks.saturating_sub(1) / chunk_ratio;
+        if entries > MAX_BAT_ENTRIES || entries * 8 > bat_length {
+            return Err(Error::InvalidBatSize(entries));
+        }
+
+        let mut raw_bat = vec![0u8; entries as usize * 8];
+        read_at(&mut file, bat_offset, &mut raw_bat).map_err(Error::ReadingBat)?;
+        let bat = raw_bat.chunks(8).map(LittleEndian::read_u64).collect();
+
+        let file_size = file.seek(SeekFrom::End(0)).map_err(Error::ReadingBat)?;
+
+        Ok(VhdxFile {
+            file,
+            header,
+            header_index,
+            header_updated: false,
+            bat_offset,
+            bat,
+            block_size,
+            chunk_ratio,
+            virtual_size,
+            file_size,
+            current_offset: 0,
+        })
+    }
+
+    // Returns the valid header with the highest sequence number.
+    fn read_header(file: &mut RawFile) -> Result<(Vec<u8>, usize)> {
+        let mut current: Option<(Vec<u8>, usize)> = None;
+        for (index, offset) in HEADER_OFFSETS.iter().enumerate() {
+            let mut header = vec![0u8; HEADER_SIZE];
+            read_at(file, *offset, &mut header).map_err(Error::ReadingHeader)?;
+            if &header[0..4] != HEADER_SIGNATURE || !valid_checksum(&header) {
+                continue;
+            }
+
+            let sequence = LittleEndian::read_u64(&header[8..16]);
+            if let Some((current_header, _)) = &current {
+                if LittleEndian::read_u64(&current_header[8..16]) >= sequence {
+                    continue;
+                }
+            }
+            current = Some((header, index));
+        }
+
+        current.ok_or(Error::NoValidHeader)
+    }
+
+    // Returns the offset and length of the BAT and metadata regions.
+    #[allow(clippy::type_complexity)]
+    fn read_region_table(file: &mut RawFile) -> Result<((u64, u64), (u64, u64))> {
+        for offset in REGION_TABLE_OFFSETS.iter() {
+            let mut table = vec![0u8; REGION_TABLE_SIZE];
+            read_at(file, *offset, &mut table).map_err(Error::ReadingRegionTable)?;
+            if &table[0..4] != REGION_TABLE_SIGNATURE || !valid_checksum(&table) {
+                continue;
+            }
+
+            let count = LittleEndian::read_u32(&table[8..12]) as usize;
+            if count > MAX_REGION_ENTRIES {
+                continue;
+            }
+
+            let mut bat = None;
+            let mut metadata = None;
+            for entry in table[16..].chunks(REGION_ENTRY_SIZE).take(count) {
+                let region = (
+                    LittleEndian::read_u64(&entry[16..24]),
+                    u64::from(LittleEndian::read_u32(&entry[24..28])),
+                );
+                if entry[0..16] == BAT_GUID {
+                    bat = Some(region);
+                } else if entry[0..16] == METADATA_GUID {
+                    metadata = Some(region);
+                } else if LittleEndian::read_u32(&entry[28..32]) & REGION_REQUIRED != 0 {
+                    return Err(Error::UnsupportedRegion);
+                }
+            }
+
+            return match (bat, metadata) {
+                (Some(bat), Some(metadata)) => Ok((bat, metadata)),
+                _ => Err(Error::MissingRegion),
+            };
+        }
+
+        Err(Error::NoValidRegionTable)
+    }
+
+    // Returns the block size, the virtual disk size and the logical sector
+    // size.
+    fn parse_metadata(metadata: &[u8]) -> Result<(u64, u64, u64)> {
+        if metadata.len() < METADATA_ENTRY_SIZE || &metadata[0..8] != METADATA_SIGNATURE {
+            return Err(Error::InvalidMetadata);
+        }
+
+        let count = LittleEndian::read_u16(&metadata[10..12]) as usize;
+        if count > MAX_METADATA_ENTRIES {
+            return Err(Error::InvalidMetadata);
+        }
+
+        let mut file_parameters = None;
+        let mut virtual_size = None;
+        let mut logical_sector_size = None;
+        for entry in metadata[METADATA_ENTRY_SIZE..]
+            .chunks(METADATA_ENTRY_SIZE)
+            .take(count)
+        {
+            if entry.len() < METADATA_ENTRY_SIZE {
+                return Err(Error::InvalidMetadata);
+            }
+
+            let offset = LittleEndian::read_u32(&entry[16..20]) as usize;
+            let length = LittleEndian::read_u32(&entry[20..24]) as usize;
+            let item = metadata
+                .get(offset..offset + length)
+                .ok_or(Error::InvalidMetadata)?;
+            let guid = &entry[0..16];
+
+            if guid == FILE_PARAMETERS_GUID && length >= 8 {
+                file_parameters = Some((
+                    LittleEndian::read_u32(&item[0..4]),
+                    LittleEndian::read_u32(&item[4..8]),
+                ));
+            } else if guid == VIRTUAL_DISK_SIZE_GUID && length >= 8 {
+                virtual_size = Some(LittleEndian::read_u64(&item[0..8]));
+            } else if guid == LOGICAL_SECTOR_SIZE_GUID && length >= 4 {
+                logical_sector_size = Some(LittleEndian::read_u32(&item[0..4]));
+            } else if guid != PHYSICAL_SECTOR_SIZE_GUID
+                && guid != PAGE_83_DATA_GUID
+                && LittleEndian::read_u32(&entry[24..28]) & METADATA_REQUIRED != 0
+            {
+                return Err(Error::UnsupportedMetadata);
+            }
+        }
+
+        let (block_size, flags) = file_parameters.ok_or(Error::MissingMetadata)?;
+        let virtual_size = virtual_size.ok_or(Error::MissingMetadata)?;
+        let logical_sector_size = logical_sector_size.ok_or(Error::MissingMetadata)?;
+
+        if flags & FILE_PARAMETERS_HAS_PARENT != 0 {
+            return Err(Error::UnsupportedDifferencingDisk);
+        }
+        if !block_size.is_power_of_two()
+            || block_size < MIN_BLOCK_SIZE
+            || block_size > MAX_BLOCK_SIZE
+        {
+            return Err(Error::InvalidBlockSize(block_size));
+        }
+        if logical_sector_size != 512 && logical_sector_size != 4096 {
+            return Err(Error::InvalidLogicalSectorSize(logical_sector_size));
+        }
+
+        Ok((
+            u64::from(block_size),
+            virtual_size,
+            u64::from(logical_sector_size),
+        ))
+    }
+
+    /// Returns the size of the disk, as seen by the guest.
+    pub fn virtual_size(&self) -> u64 {
+        self.virtual_size
+    }
+
+    fn bat_index(&self, address: u64) -> usize {
+        let block = address / self.block_size;
+        (block + block / self.chunk_ratio) as usize
+    }
+
+    // Returns the file offset `address` is stored at, if allocated, and how
+    // many of the next `count` bytes are contiguous in the file.
+    fn map(&self, address: u64, count: usize) -> (Option<u64>, usize) {
+        let in_block = address % self.block_size;
+        let count = min(count as u64, self.block_size - in_block) as usize;
+        let entry = self.bat[self.bat_index(address)];
+        if entry & BAT_STATE_MASK == PAYLOAD_BLOCK_FULLY_PRESENT {
+            (Some((entry & BAT_OFFSET_MASK) + in_block), count)
+        } else {
+            // Blocks which are not present, zeroed or unmapped read as zeros.
+            (None, count)
+        }
+    }
+
+    // Writes a new copy of the header, with new write GUIDs, over the old
+    // copy. This is required before modifying the image for the first time.
+    fn update_header(&mut self) -> io::Result<()> {
+        let mut header = self.header.clone();
+        let sequence = LittleEndian::read_u64(&header[8..16]) + 1;
+        LittleEndian::write_u64(&mut header[8..16], sequence);
+        header[16..32].copy_from_slice(&random_guid()?);
+        header[32..48].copy_from_slice(&random_guid()?);
+        set_checksum(&mut header);
+
+        let index = 1 - self.header_index;
+        write_at(&mut self.file, HEADER_OFFSETS[index], &header)?;
+        self.file.sync_data()?;
+
+        self.header = header;
+        self.header_index = index;
+        self.header_updated = true;
+        Ok(())
+    }
+
+    // Adds the block holding `address` at the end of the file, and returns
+    // its file offset.
+    fn allocate_block(&mut self, address: u64) -> io::Result<u64> {
+        // Payload blocks are 1MB aligned, and zeroed by extending the file.
+        let block_offset = (self.file_size + MIB - 1) / MIB * MIB;
+        self.file_size = block_offset + self.block_size;
+        self.file.set_len(self.file_size)?;
+
+        let index = self.bat_index(address);
+        let entry = block_offset | PAYLOAD_BLOCK_FULLY_PRESENT;
+        let mut raw_entry = [0u8; 8];
+        LittleEndian::write_u64(&mut raw_entry, entry);
+        write_at(
+            &mut self.file,
+            self.bat_offset + index as u64 * 8,
+            &raw_entry,
+        )?;
+        self.bat[index] = entry;
+
+        Ok(block_offset + address % self.block_size)
+    }
+
+    // Limits the number of bytes to transfer to the end of the disk.
+    fn limit_range(&self, count: usize) -> usize {
+        min(
+            count as u64,
+            self.virtual_size.saturating_sub(self.current_offset),
+        ) as usize
+    }
+}
+
+impl Read for VhdxFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_count = self.limit_range(buf.len());
+
+        let mut nread = 0;
+        while nread < read_count {
+            let address = self.current_offset + nread as u64;
+            let (file_offset, count) = self.map(address, read_count - nread);
+            let buf = &mut buf[nread..(nread + count)];
+
+            if let Some(offset) = file_offset {
+                read_at(&mut self.file, offset, buf)?;
+            } else {
+                for b in buf.iter_mut() {
+                    *b = 0;
+                }
+            }
+
+            nread += count;
+        }
+        self.current_offset += read_count as u64;
+        Ok(read_count)
+    }
+}
+
+impl Write for VhdxFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let write_count = self.limit_range(buf.len());
+        if write_count > 0 && !self.header_updated {
+            self.update_header()?;
+        }
+
+        let mut nwritten = 0;
+        while nwritten < write_count {
+            let address = self.current_offset + nwritten as u64;
+            let (file_offset, count) = self.map(address, write_count - nwritten);
+            let offset = match file_offset {
+                Some(offset) => offset,
+                None => self.allocate_block(address)?,
+            };
+
+            write_at(&mut self.file, offset, &buf[nwritten..(nwritten + count)])?;
+
+            nwritten += count;
+        }
+        self.current_offset += write_count as u64;
+        Ok(write_count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl PunchHole for VhdxFile {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        let end = min(offset.saturating_add(length), self.virtual_size);
+        if offset < end && !self.header_updated {
+            self.update_header()?;
+        }
+
+        let mut address = offset;
+        while address < end {
+            let (file_offset, count) = self.map(address, (end - address) as usize);
+            // Unallocated blocks already read as zeros.
+            if let Some(file_offset) = file_offset {
+                self.file.punch_hole(file_offset, count as u64)?;
+            }
+            address += count as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Seek for VhdxFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(off) => {
+                if off < 0 {
+                    self.virtual_size.checked_sub(off.wrapping_neg() as u64)
+                } else {
+                    self.virtual_size.checked_add(off as u64)
+                }
+            }
+            SeekFrom::Current(off) => {
+                if off < 0 {
+                    self.current_offset.checked_sub(off.wrapping_neg() as u64)
+                } else {
+                    self.current_offset.checked_add(off as u64)
+                }
+            }
+        };
+
+        match new_offset {
+            Some(offset) if offset <= self.virtual_size => {
+                self.current_offset = offset;
+                Ok(offset)
+            }
+            _ => Err(io::Error::from_raw_os_error(EINVAL)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempfile;
+
+    const BLOCK_SIZE: u64 = MIB;
+    const DISK_SIZE: u64 = 4 * MIB;
+    const METADATA_OFFSET: u64 = 2 * MIB;
+    const BAT_OFFSET: u64 = 3 * MIB;
+
+    fn header(log_guid: [u8; 16]) -> Vec<u8> {
+        let mut header = vec![0u8; HEADER_SIZE];
+        header[0..4].copy_from_slice(HEADER_SIGNATURE);
+        LittleEndian::write_u64(&mut header[8..16], 1);
+        header[48..64].copy_from_slice(&log_guid);
+        LittleEndian::write_u16(&mut header[66..68], HEADER_VERSION);
+        LittleEndian::write_u32(&mut header[68..72], MIB as u32);
+        LittleEndian::write_u64(&mut header[72..80], MIB);
+        set_checksum(&mut header);
+        header
+    }
+
+    fn region_table() -> Vec<u8> {
+        let mut table = vec![0u8; REGION_TABLE_SIZE];
+        table[0..4].copy_from_slice(REGION_TABLE_SIGNATURE);
+        LittleEndian::write_u32(&mut table[8..12], 2);
+        for (entry, (guid, offset)) in table[16..]
+            .chunks_mut(REGION_ENTRY_SIZE)
+            .zip(&[(BAT_GUID, BAT_OFFSET), (METADATA_GUID, METADATA_OFFSET)])
+        {
+            entry[0..16].copy_from_slice(guid);
+            LittleEndian::write_u64(&mut entry[16..24], *offset);
+            LittleEndian::write_u32(&mut entry[24..28], MIB as u32);
+            LittleEndian::write_u32(&mut entry[28..32], REGION_REQUIRED);
+        }
+        set_checksum(&mut table);
+        table
+    }
+
+    fn metadata() -> Vec<u8> {
+        let mut metadata = vec![0u8; 64 * KIB as usize + 64];
+        metadata[0..8].copy_from_slice(METADATA_SIGNATURE);
+        LittleEndian::write_u16(&mut metadata[10..12], 3);
+
+        let items = [
+            (FILE_PARAMETERS_GUID, 0, 8),
+            (VIRTUAL_DISK_SIZE_GUID, 8, 8),
+            (LOGICAL_SECTOR_SIZE_GUID, 16, 4),
+        ];
+        for (entry, (guid, offset, length)) in metadata[METADATA_ENTRY_SIZE..]
+            .chunks_mut(METADATA_ENTRY_SIZE)
+            .zip(items.iter())
+        {
+            entry[0..16].copy_from_slice(guid);
+            LittleEndian::write_u32(&mut entry[16..20], 64 * KIB as u32 + offset);
+            LittleEndian::write_u32(&mut entry[20..24], *length);
+            LittleEndian::write_u32(&mut entry[24..28], METADATA_REQUIRED);
+        }
+
+        let items = &mut metadata[64 * KIB as usize..];
+        LittleEndian::write_u32(&mut items[0..4], BLOCK_SIZE as u32);
+        LittleEndian::write_u64(&mut items[8..16], DISK_SIZE);
+        LittleEndian::write_u32(&mut items[16..20], 512);
+        metadata
+    }
+
+    // Dynamic image with no block allocated, and only the first header.
+    fn image(log_guid: [u8; 16]) -> RawFile {
+        let mut file = RawFile::new(tempfile().unwrap(), false);
+        file.set_len(BAT_OFFSET + MIB).unwrap();
+        write_at(&mut file, 0, FILE_SIGNATURE).unwrap();
+        write_at(&mut file, HEADER_OFFSETS[0], &header(log_guid)).unwrap();
+        for offset in REGION_TABLE_OFFSETS.iter() {
+            write_at(&mut file, *offset, &region_table()).unwrap();
+        }
+        write_at(&mut file, METADATA_OFFSET, &metadata()).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn test_read_write() {
+        let mut disk = VhdxFile::from(image([0u8; 16])).unwrap();
+        assert_eq!(disk.seek(SeekFrom::End(0)).unwrap(), DISK_SIZE);
+
+        let mut buf = vec![0xffu8; 0x1000];
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+
+        // Cross the boundary between the first and second blocks.
+        let address = BLOCK_SIZE - 0x800;
+        disk.seek(SeekFrom::Start(address)).unwrap();
+        disk.write_all(&[0x55; 0x1000]).unwrap();
+
+        // The second header copy is now the current one, and the blocks
+        // were allocated after the existing content.
+        let mut disk = VhdxFile::from(disk.file.clone()).unwrap();
+        assert_eq!(disk.header_index, 1);
+        assert_eq!(LittleEndian::read_u64(&disk.header[8..16]), 2);
+        assert_eq!(
+            disk.bat[0],
+            (BAT_OFFSET + MIB) | PAYLOAD_BLOCK_FULLY_PRESENT
+        );
+        assert_eq!(disk.bat[2], 0);
+
+        let mut buf = vec![0u8; 0x2000];
+        disk.seek(SeekFrom::Start(address - 0x800)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert!(buf[..0x800].iter().all(|b| *b == 0));
+        assert!(buf[0x800..0x1800].iter().all(|b| *b == 0x55));
+        assert!(buf[0x1800..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_log_replay_rejected() {
+        match VhdxFile::from(image([0x01; 16])) {
+            Err(Error::LogReplayNotSupported) => (),
+            _ => panic!("image with a log accepted"),
+        }
+    }
+
+    #[test]
+    fn test_corrupted_header_rejected() {
+        let mut file = image([0u8; 16]);
+        write_at(&mut file, HEADER_OFFSETS[0] + 8, &[0x02]).unwrap();
+        match VhdxFile::from(file) {
+            Err(Error::NoValidHeader) => (),
+            _ => panic!("corrupted header accepted"),
+        }
+    }
+}
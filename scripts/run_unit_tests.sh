@@ -11,4 +11,5 @@ sudo adduser $USER kvm
 newgrp kvm << EOF || exit 1
   export RUST_BACKTRACE=1
   cargo test --workspace "$@" || exit 1;
+  cargo test -p vm-virtio --features "io_uring" "$@" || exit 1;
 EOF
@@ -0,0 +1,524 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+#[macro_use(crate_version, crate_authors)]
+extern crate clap;
+extern crate serde_json;
+extern crate vmm;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use vmm::api::{VmCounters, VmInfo};
+use vmm::config::ConsoleOutputMode;
+use vmm::state_dir;
+use vmm::vm::VmState;
+
+// Events shown below the counters, the older ones scrolling out.
+const MAX_EVENTS: usize = 10;
+
+// Ctrl-], detaching from the console.
+const DETACH_KEY: u8 = 0x1d;
+
+#[derive(Debug)]
+enum Error {
+    /// Cannot connect to the API socket.
+    Connect(io::Error),
+
+    /// Cannot send the request or read the response.
+    Socket(io::Error),
+
+    /// The response isn't a valid HTTP response.
+    InvalidResponse,
+
+    /// The request failed, with the status code and the body of the
+    /// response.
+    ServerResponse(u16, String),
+
+    /// Cannot parse the body of the response.
+    Json(serde_json::Error),
+
+    /// The serial port or the console isn't served on a socket.
+    NoConsoleSocket,
+
+    /// Cannot connect to the console socket.
+    ConsoleConnect(io::Error),
+
+    /// Cannot relay the console input or output.
+    Console(io::Error),
+
+    /// Cannot set the terminal mode.
+    Terminal(io::Error),
+}
+type Result<T> = std::result::Result<T, Error>;
+
+// Returns the status code and the body of the HTTP response, once it has
+// been fully read.
+fn parse_response(response: &[u8]) -> Result<Option<(u16, String)>> {
+    let header_end = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(position) => position + 4,
+        None => return Ok(None),
+    };
+    let header = String::from_utf8_lossy(&response[..header_end]);
+    let mut lines = header.lines();
+
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or(Error::InvalidResponse)?;
+    let mut content_length = 0;
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| Error::InvalidResponse)?;
+            }
+        }
+    }
+
+    if response.len() < header_end + content_length {
+        return Ok(None);
+    }
+    let body = &response[header_end..header_end + content_length];
+    Ok(Some((status, String::from_utf8_lossy(body).into_owned())))
+}
+
+fn api_request(socket: &str, method: &str, endpoint: &str) -> Result<String> {
+    let mut stream = UnixStream::connect(socket).map_err(Error::Connect)?;
+    stream
+        .write_all(
+            format!(
+                "{} /api/v1/{} HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n",
+                method, endpoint
+            )
+            .as_bytes(),
+        )
+        .map_err(Error::Socket)?;
+
+    // The server keeps the connection open, hence reading up to the length
+    // of the body.
+    let mut response = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let count = stream.read(&mut buf).map_err(Error::Socket)?;
+        if count == 0 {
+            return Err(Error::InvalidResponse);
+        }
+        response.extend_from_slice(&buf[..count]);
+        if let Some((status, body)) = parse_response(&response)? {
+            if status >= 300 {
+                return Err(Error::ServerResponse(status, body));
+            }
+            return Ok(body);
+        }
+    }
+}
+
+fn vm_info(socket: &str) -> Result<VmInfo> {
+    let body = api_request(socket, "GET", "vm.info")?;
+    serde_json::from_str(&body).map_err(Error::Json)
+}
+
+fn vm_counters(socket: &str) -> Result<VmCounters> {
+    let body = api_request(socket, "GET", "vm.counters")?;
+    serde_json::from_str(&body).map_err(Error::Json)
+}
+
+// What the previous refresh saw, to report the changes as events and the
+// vCPUs usage over the interval.
+#[derive(Default)]
+struct Watch {
+    start: Option<Instant>,
+    state: Option<VmState>,
+    guest_os: bool,
+    vcpus: usize,
+    lost_disks: Vec<PathBuf>,
+    counters: Option<(Instant, VmCounters)>,
+    events: VecDeque<String>,
+}
+
+impl Watch {
+    fn event(&mut self, event: String) {
+        let elapsed = self.start.get_or_insert_with(Instant::now).elapsed();
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events
+            .push_back(format!("[{:>8.1}s] {}", elapsed.as_secs_f64(), event));
+    }
+
+    fn update_info(&mut self, info: Option<&VmInfo>) {
+        let state = info.map(|info| info.state);
+        if state != self.state {
+            match (self.state, state) {
+                (None, Some(state)) => self.event(format!("VM created, {:?}", state)),
+                (Some(_), None) => self.event("VM deleted".to_string()),
+                (Some(old), Some(new)) => {
+                    let mut event = format!("VM {:?} -> {:?}", old, new);
+                    // The VMM may have paused the VM on its own.
+                    if let Some(reason) = info.and_then(|info| info.paused_reason) {
+                        event.push_str(&format!(" ({:?})", reason));
+                    }
+                    self.event(event)
+                }
+                (None, None) => (),
+            }
+            self.state = state;
+        }
+
+        let info = match info {
+            Some(info) => info,
+            None => {
+                self.guest_os = false;
+                self.vcpus = 0;
+                self.lost_disks.clear();
+                return;
+            }
+        };
+
+        match &info.guest_os {
+            Some(guest_os) if !self.guest_os => {
+                self.event(format!(
+                    "Guest OS detected: {:?} {}",
+                    guest_os.family,
+                    guest_os.version.as_deref().unwrap_or("")
+                ));
+                self.guest_os = true;
+            }
+            None => self.guest_os = false,
+            _ => (),
+        }
+
+        let lost = info
+            .lost_disks
+            .iter()
+            .filter(|disk| !self.lost_disks.contains(disk))
+            .map(|disk| format!("Disk {:?} lost", disk));
+        let back = self
+            .lost_disks
+            .iter()
+            .filter(|disk| !info.lost_disks.contains(disk))
+            .map(|disk| format!("Disk {:?} back", disk));
+        let events: Vec<String> = lost.chain(back).collect();
+        for event in events {
+            self.event(event);
+        }
+        self.lost_disks = info.lost_disks.clone();
+
+        let vcpus = usize::from(info.config.lock().unwrap().cpus.boot_vcpus);
+        if self.vcpus != 0 && vcpus != self.vcpus {
+            self.event(format!("vCPUs {} -> {}", self.vcpus, vcpus));
+        }
+        self.vcpus = vcpus;
+    }
+
+    fn update_counters(&mut self, counters: Option<&VmCounters>) {
+        let previous = self
+            .counters
+            .as_ref()
+            .and_then(|(_, previous)| previous.overcommit.as_ref());
+        let event = match (previous, counters.and_then(|c| c.overcommit.as_ref())) {
+            (Some(previous), Some(overcommit))
+                if overcommit.interventions > previous.interventions =>
+            {
+                Some(format!(
+                    "Overcommit adjusted the guest memory, {} MiB unplugged",
+                    overcommit.unplugged >> 20
+                ))
+            }
+            _ => None,
+        };
+        if let Some(event) = event {
+            self.event(event);
+        }
+    }
+
+    fn render(&self, socket: &str, counters: Option<&VmCounters>) -> String {
+        let mut out = String::new();
+        out.push_str(&format!(
+            "{} -- VM {}\n\n",
+            socket,
+            self.state
+                .map(|state| format!("{:?}", state))
+                .unwrap_or_else(|| "not created".to_string())
+        ));
+
+        if let Some(counters) = counters {
+            out.push_str(&format!(
+                "{:>6} {:>14} {:>8}\n",
+                "vCPU", "CPU time (s)", "usage"
+            ));
+            for vcpu in counters.vcpus.iter() {
+                // The usage over the interval, when the vCPU was there on
+                // the previous refresh.
+                let usage = self.counters.as_ref().and_then(|(at, previous)| {
+                    let previous = previous.vcpus.iter().find(|v| v.id == vcpu.id)?;
+                    let interval = at.elapsed().as_nanos() as f64;
+                    let cpu_time = vcpu.cpu_time.saturating_sub(previous.cpu_time) as f64;
+                    Some(cpu_time * 100.0 / interval)
+                });
+                out.push_str(&format!(
+                    "{:>6} {:>14.2} {:>8}\n",
+                    vcpu.id,
+                    vcpu.cpu_time as f64 / 1e9,
+                    usage
+                        .map(|usage| format!("{:.1}%", usage))
+                        .unwrap_or_else(|| "-".to_string())
+                ));
+            }
+            out.push('\n');
+            if let Some(working_set) = counters.working_set {
+                out.push_str(&format!("Working set: {} MiB\n", working_set >> 20));
+            }
+            if let Some(balloon) = counters.balloon {
+                out.push_str(&format!("Balloon: {} MiB\n", balloon >> 20));
+            }
+            if let Some(reported) = counters.free_pages_reported {
+                out.push_str(&format!("Free pages reported: {} MiB\n", reported >> 20));
+            }
+            if let Some(plugged) = counters.memory_plugged {
+                out.push_str(&format!("Memory plugged: {} MiB\n", plugged >> 20));
+            }
+            if let Some(overcommit) = &counters.overcommit {
+                out.push_str(&format!(
+                    "Overcommit: {} interventions, {} MiB unplugged\n",
+                    overcommit.interventions,
+                    overcommit.unplugged >> 20
+                ));
+            }
+            out.push('\n');
+        }
+
+        out.push_str("Events:\n");
+        for event in self.events.iter() {
+            out.push_str(event);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+fn watch(socket: &str, interval: Duration) -> Result<()> {
+    let mut watch = Watch::default();
+    loop {
+        // The VM not being created isn't an error, the VMM answering
+        // anyway.
+        let info = match vm_info(socket) {
+            Ok(info) => Some(info),
+            Err(Error::ServerResponse(_, _)) => None,
+            Err(e) => return Err(e),
+        };
+        watch.update_info(info.as_ref());
+
+        let counters = match watch.state {
+            Some(VmState::Running) | Some(VmState::Paused) => match vm_counters(socket) {
+                Ok(counters) => Some(counters),
+                Err(Error::ServerResponse(_, _)) => None,
+                Err(e) => return Err(e),
+            },
+            _ => None,
+        };
+        watch.update_counters(counters.as_ref());
+
+        // Clearing the terminal before redrawing the whole view.
+        print!("\x1b[2J\x1b[H{}", watch.render(socket, counters.as_ref()));
+        io::stdout().flush().map_err(Error::Socket)?;
+
+        watch.counters = counters.map(|counters| (Instant::now(), counters));
+        thread::sleep(interval);
+    }
+}
+
+// Puts the terminal in raw mode, for the keys to reach the guest as they
+// are typed, until dropped.
+struct RawTerminal {
+    termios: Option<libc::termios>,
+}
+
+impl RawTerminal {
+    fn new() -> Result<Self> {
+        let stdin = io::stdin();
+        // Safe because termios is plain data, the fd is valid, and we check
+        // the return values.
+        unsafe {
+            if libc::isatty(stdin.as_raw_fd()) == 0 {
+                return Ok(RawTerminal { termios: None });
+            }
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(stdin.as_raw_fd(), &mut termios) < 0 {
+                return Err(Error::Terminal(io::Error::last_os_error()));
+            }
+            let mut raw = termios;
+            libc::cfmakeraw(&mut raw);
+            if libc::tcsetattr(stdin.as_raw_fd(), libc::TCSANOW, &raw) < 0 {
+                return Err(Error::Terminal(io::Error::last_os_error()));
+            }
+            Ok(RawTerminal {
+                termios: Some(termios),
+            })
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if let Some(termios) = self.termios.as_ref() {
+            // Safe because the fd is valid and termios was read from it.
+            unsafe { libc::tcsetattr(io::stdin().as_raw_fd(), libc::TCSANOW, termios) };
+        }
+    }
+}
+
+// The socket the serial port or the console is served on.
+fn console_socket(socket: &str, serial: bool) -> Result<PathBuf> {
+    let info = vm_info(socket)?;
+    let config = info.config.lock().unwrap();
+    let console = if serial {
+        &config.serial
+    } else {
+        &config.console
+    };
+    match (&console.mode, &console.socket) {
+        (ConsoleOutputMode::Socket, Some(path)) => {
+            Ok(state_dir::resolve(config.state_dir.as_deref(), path))
+        }
+        _ => Err(Error::NoConsoleSocket),
+    }
+}
+
+// Relays the terminal input to the console and the console output to the
+// terminal, until the detach key is typed or the VMM closes the console.
+fn console(socket: &str, serial: bool) -> Result<()> {
+    let path = console_socket(socket, serial)?;
+    let mut stream = UnixStream::connect(&path).map_err(Error::ConsoleConnect)?;
+    eprint!("Attached to {}, detach with Ctrl-]\r\n", path.display());
+
+    let _terminal = RawTerminal::new()?;
+    let mut stdin = io::stdin();
+    let mut stdout = io::stdout();
+    let mut fds = [
+        libc::pollfd {
+            fd: stdin.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: stream.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+    let mut buf = [0u8; 4096];
+    loop {
+        // Safe because the fds are valid, and we check the return value.
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(Error::Console(e));
+        }
+
+        if fds[0].revents != 0 {
+            let count = stdin.read(&mut buf).map_err(Error::Console)?;
+            let input = &buf[..count];
+            let detach = input.iter().position(|b| *b == DETACH_KEY);
+            stream
+                .write_all(&input[..detach.unwrap_or(count)])
+                .map_err(Error::Console)?;
+            if detach.is_some() || count == 0 {
+                break;
+            }
+        }
+        if fds[1].revents != 0 {
+            let count = stream.read(&mut buf).map_err(Error::Console)?;
+            if count == 0 {
+                break;
+            }
+            stdout.write_all(&buf[..count]).map_err(Error::Console)?;
+            stdout.flush().map_err(Error::Console)?;
+        }
+    }
+
+    eprint!("\r\nDetached from {}\r\n", path.display());
+    Ok(())
+}
+
+fn do_command(matches: &ArgMatches) -> Result<()> {
+    let socket = matches.value_of("api-socket").unwrap();
+    match matches.subcommand() {
+        ("watch", Some(watch_matches)) => {
+            let interval = watch_matches
+                .value_of("interval")
+                .unwrap()
+                .parse::<u64>()
+                .unwrap_or(1)
+                .max(1);
+            watch(socket, Duration::from_secs(interval))
+        }
+        ("console", Some(console_matches)) => console(socket, console_matches.is_present("serial")),
+        _ => unreachable!(),
+    }
+}
+
+fn main() {
+    let cmd_arguments = App::new("ch-remote")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Remotely control a cloud-hypervisor VMM.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("api-socket")
+                .long("api-socket")
+                .help("HTTP API socket path (UNIX Domain Socket).")
+                .takes_value(true)
+                .number_of_values(1)
+                .required(true),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about(
+                    "Follow the VM live, showing its counters along with its state changes \
+                     as they happen",
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .help("Refresh interval, in seconds")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .default_value("1"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("console")
+                .about(
+                    "Attach to the guest console, served on a socket with --console socket=, \
+                     detaching with Ctrl-]",
+                )
+                .arg(
+                    Arg::with_name("serial")
+                        .long("serial")
+                        .help("Attach to the serial port, served with --serial socket="),
+                ),
+        )
+        .get_matches();
+
+    if let Err(e) = do_command(&cmd_arguments) {
+        eprintln!("Error running command: {:?}", e);
+        process::exit(1)
+    };
+}
@@ -12,12 +12,14 @@ extern crate clap;
 use clap::{App, Arg, ArgGroup, ArgMatches};
 use libc::EFD_NONBLOCK;
 use log::LevelFilter;
+use std::path::Path;
 use std::sync::mpsc::channel;
 use std::sync::{Arc, Mutex};
 use std::{env, process};
 use vhost_user_block::start_block_backend;
 use vhost_user_net::start_net_backend;
 use vmm::config;
+use vmm::firecracker::FirecrackerConfig;
 use vmm_sys_util::eventfd::EventFd;
 
 struct Logger {
@@ -89,7 +91,12 @@ fn create_app<'a, 'b>(
         .arg(
             Arg::with_name("cpus")
                 .long("cpus")
-                .help("Number of virtual CPUs")
+                .help(
+                    "Virtual CPUs parameters \"boot=<boot_vcpus>,max=<max_vcpus>,\
+                     features=<+|-><cpuid_feature>:<+|-><cpuid_feature>:...,\
+                     tsc_khz=<tsc_frequency_in_khz>,nested=on|off,\
+                     hyperv=on|off,model=<cpu_model>,freq_hints=on|off\"",
+                )
                 .default_value(&default_vcpus)
                 .group("vm-config"),
         )
@@ -99,11 +106,36 @@ fn create_app<'a, 'b>(
                 .help(
                     "Memory parameters \"size=<guest_memory_size>,\
                      file=<backing_file_path>,mergeable=on|off,\
-                     hotplug_size=<hotpluggable_memory_size>\"",
+                     hotplug_size=<hotpluggable_memory_size>,\
+                     dirty_rate_limit=<dirty_bytes_per_second_per_vcpu>,\
+                     mlock=on|off,prefault=on|off,working_set=on|off,\
+                     overcommit=off|auto,target_free_host=<percent>,\
+                     hotplug_method=acpi|virtio-mem,\
+                     hotplugged_size=<hotplugged_memory_size>\"",
                 )
                 .default_value(&default_memory)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("memory-zone")
+                .long("memory-zone")
+                .help(
+                    "User defined memory zone parameters \"size=<guest_memory_zone_size>,\
+                     file=<backing_file_path>,hugepages=on|off,\
+                     host_numa_node=<node_id>\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("sgx-epc")
+                .long("sgx-epc")
+                .help("SGX EPC section parameters \"size=<epc_section_size>,prefault=on|off\"")
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::with_name("kernel")
                 .long("kernel")
@@ -123,11 +155,30 @@ fn create_app<'a, 'b>(
                 .long("disk")
                 .help(
                     "Disk parameters \"path=<disk_image_path>,\
-                     readonly=on|off,iommu=on|off,\
+                     readonly=on|off,direct=on|off,iommu=on|off,\
                      num_queues=<number_of_queues>,\
                      queue_size=<size_of_each_queue>,
                      vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,
-                     wce=<true|false, default true>\"",
+                     wce=<true|false, default true>,nvme=on|off,\
+                     bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
+                     ops_size=<io_ops>,ops_one_time_burst=<io_ops>,ops_refill_time=<ms>,\
+                     watch=on|off,overlay=<overlay_image_path>,cdrom=on|off,boot=on|off,\
+                     serial=<serial_number>,\
+                     pci_address=<[[segment:]bus:]device[.function]>,\
+                     on_error=report|stop,track_changes=on|off,id=<device_id>\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("scsi")
+                .long("scsi")
+                .help(
+                    "SCSI controller parameters \"luns=<lun0_path>:<lun1_path>:...,\
+                     readonly=on|off,iommu=on|off,\
+                     num_queues=<number_of_request_queues>,\
+                     queue_size=<size_of_each_queue>\"",
                 )
                 .takes_value(true)
                 .min_values(1)
@@ -141,7 +192,16 @@ fn create_app<'a, 'b>(
                      ip=<ip_addr>,mask=<net_mask>,mac=<mac_addr>,\
                      iommu=on|off,num_queues=<number_of_queues>,\
                      queue_size=<size_of_each_queue>,\
-                     vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>\"",
+                     vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,\
+                     transitional=on|off,standby=on|off,\
+                     xdp=<nic_if_name>,xdp_map=<pinned_xskmap_path>,\
+                     fds=<tap_fd1:tap_fd2...>,host_mac=<host_mac_addr>,mtu=<mtu>,\
+                     offload_csum=on|off,offload_tso=on|off,offload_ufo=on|off,\
+                     rx_bw_size=<bytes>,rx_bw_one_time_burst=<bytes>,rx_bw_refill_time=<ms>,\
+                     rx_ops_size=<frames>,rx_ops_one_time_burst=<frames>,rx_ops_refill_time=<ms>,\
+                     tx_bw_size=<bytes>,tx_bw_one_time_burst=<bytes>,tx_bw_refill_time=<ms>,\
+                     tx_ops_size=<frames>,tx_ops_one_time_burst=<frames>,tx_ops_refill_time=<ms>,\
+                     pci_address=<[[segment:]bus:]device[.function]>,id=<device_id>\"",
                 )
                 .takes_value(true)
                 .min_values(1)
@@ -157,14 +217,62 @@ fn create_app<'a, 'b>(
                 .default_value(&default_rng)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("balloon")
+                .long("balloon")
+                .help(
+                    "Memory balloon parameters \
+                     \"size=<balloon_size>,deflate_on_oom=on|off,\
+                     free_page_reporting=on|off\"",
+                )
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("on-panic")
+                .long("on-panic")
+                .help("Action taken when the guest panics \"log|pause|shutdown|reboot\"")
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("unknown-access")
+                .long("unknown-access")
+                .help(
+                    "Action taken on guest accesses to unknown PIO or MMIO addresses \
+                     \"ignore|log|fault,log_limit=<max_accesses_logged>\"",
+                )
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("uuid")
+                .long("uuid")
+                .help("VM UUID, exposed to the guest through SMBIOS, generated if not given")
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("state-dir")
+                .long("state-dir")
+                .help(
+                    "Directory holding the VM state: its configuration, snapshots and \
+                     console output files given as relative paths",
+                )
+                .takes_value(true)
+                .group("vm-config"),
+        )
         .arg(
             Arg::with_name("fs")
                 .long("fs")
                 .help(
                     "virtio-fs parameters \"tag=<tag_name>,\
-                     sock=<socket_path>,num_queues=<number_of_queues>,\
+                     sock=<socket_path>,path=<shared_directory_path>,\
+                     num_queues=<number_of_queues>,\
                      queue_size=<size_of_each_queue>,dax=on|off,\
-                     cache_size=<DAX cache size: default 8Gib>\"",
+                     cache_size=<DAX cache size: default 8Gib>,spawn=on|off,\
+                     virtiofsd=<virtiofsd_path>,sandbox=namespace|chroot,\
+                     pci_address=<[[segment:]bus:]device[.function]>\"",
                 )
                 .takes_value(true)
                 .min_values(1)
@@ -175,7 +283,8 @@ fn create_app<'a, 'b>(
                 .long("pmem")
                 .help(
                     "Persistent memory parameters \"file=<backing_file_path>,\
-                     size=<persistent_memory_size>,iommu=on|off,mergeable=on|off\"",
+                     size=<persistent_memory_size>,iommu=on|off,mergeable=on|off,\
+                     discard_writes=on|off\"",
                 )
                 .takes_value(true)
                 .min_values(1)
@@ -184,18 +293,45 @@ fn create_app<'a, 'b>(
         .arg(
             Arg::with_name("serial")
                 .long("serial")
-                .help("Control serial port: off|null|tty|file=/path/to/a/file")
-                .default_value("null")
+                .help(
+                    "Control serial port: \"off|null|pty|tty|file=/path/to/a/file|\
+                     socket=/path/to/a/socket,mux=on|off\", \
+                     defaults to null with the virtio console profile, tty with the legacy one",
+                )
+                .takes_value(true)
                 .group("vm-config"),
         )
         .arg(
             Arg::with_name("console")
                 .long("console")
                 .help(
-                    "Control (virtio) console: \"off|null|tty|file=/path/to/a/file,\
-                     iommu=on|off\"",
+                    "Control (virtio) console: \"off|null|pty|tty|file=/path/to/a/file|\
+                     socket=/path/to/a/socket,\
+                     iommu=on|off,queue_size=<size_of_each_queue>,mux=on|off\", \
+                     defaults to tty with the virtio console profile, off with the legacy one",
+                )
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("console-port")
+                .long("console-port")
+                .help(
+                    "Named virtio-console port parameters \"name=<port_name>,\
+                     file=<output_file_path>|socket=<socket_path>\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("console-profile")
+                .long("console-profile")
+                .help(
+                    "Default serial port and console setup \"virtio|legacy\", \
+                     virtio unless the guest lacks a virtio-console driver",
                 )
-                .default_value("tty")
+                .takes_value(true)
                 .group("vm-config"),
         )
         .arg(
@@ -204,7 +340,22 @@ fn create_app<'a, 'b>(
                 .help("Direct device assignment parameter")
                 .help(
                     "Direct device assignment parameters \
-                     \"path=<device_path>,iommu=on|off\"",
+                     \"path=<device_path>,iommu=on|off,reset=flr|bus|none,\
+                     reset_on_attach=on|off,reset_on_release=on|off,\
+                     p2p_dma=on|off,\
+                     pci_address=<[[segment:]bus:]device[.function]>\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("sriov-vf")
+                .long("sriov-vf")
+                .help(
+                    "SR-IOV VF assignment parameters \"pf=<pf_interface_name>,\
+                     index=<vf_index>,mac=<vf_mac>,vlan=<vf_vlan_id>,iommu=on|off,\
+                     failover=on|off\"",
                 )
                 .takes_value(true)
                 .min_values(1)
@@ -227,12 +378,43 @@ fn create_app<'a, 'b>(
                 .long("vsock")
                 .help(
                     "Virtio VSOCK parameters \"cid=<context_id>,\
-                     sock=<socket_path>,iommu=on|off\"",
+                     sock=<socket_path>,iommu=on|off\" \
+                     (socket=<socket_path> being accepted for sock)",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("vdpa")
+                .long("vdpa")
+                .help(
+                    "vDPA device parameters \"path=<vhost_vdpa_device_path>,\
+                     num_queues=<number_of_queues>\"",
                 )
                 .takes_value(true)
                 .min_values(1)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("cgroup")
+                .long("cgroup")
+                .help(
+                    "Cgroup v2 confinement parameters \"path=<cgroup_path>,\
+                     vcpus_cpu_max=<quota_us>,vcpus_cpuset=<cpu_list>,\
+                     vmm_cpu_max=<quota_us>,vmm_cpuset=<cpu_list>,\
+                     cpu_period=<period_us>\"",
+                )
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("platform")
+                .long("platform")
+                .help("Platform parameters \"sev=on|off,sev_policy=<sev_guest_policy>\"")
+                .takes_value(true)
+                .group("vm-config"),
+        )
         .arg(
             Arg::with_name("vhost-user-blk")
                 .long("vhost-user-blk")
@@ -246,6 +428,16 @@ fn create_app<'a, 'b>(
                 .min_values(1)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("firecracker-config")
+                .long("firecracker-config")
+                .help(
+                    "Firecracker configuration file (as given to \
+                     firecracker --config-file) the VM configuration is imported from",
+                )
+                .takes_value(true)
+                .conflicts_with("vm-config"),
+        )
         .arg(
             Arg::with_name("v")
                 .short("v")
@@ -270,6 +462,26 @@ fn create_app<'a, 'b>(
                 .default_value(&api_server_path)
                 .group("vmm-config"),
         )
+        .arg(
+            Arg::with_name("api-socket-ro")
+                .long("api-socket-ro")
+                .help(
+                    "Read-only HTTP API socket path (UNIX domain socket), only \
+                     serving the GET requests, e.g. for monitoring agents",
+                )
+                .takes_value(true)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::with_name("qmp-socket")
+                .long("qmp-socket")
+                .help(
+                    "QMP socket path (UNIX domain socket), accepting the QEMU \
+                     query-status, stop, cont, system_powerdown and quit commands",
+                )
+                .takes_value(true)
+                .group("vmm-config"),
+        )
         .arg(
             Arg::with_name("net-backend")
                 .long("net-backend")
@@ -298,12 +510,24 @@ fn create_app<'a, 'b>(
 }
 
 fn start_vmm(cmd_arguments: ArgMatches) {
-    let vm_params = config::VmParams::from_arg_matches(&cmd_arguments);
-    let vm_config = match config::VmConfig::parse(vm_params) {
-        Ok(config) => config,
-        Err(e) => {
-            println!("Failed parsing parameters {:?}", e);
-            process::exit(1);
+    let vm_config = if let Some(path) = cmd_arguments.value_of("firecracker-config") {
+        match FirecrackerConfig::from_file(Path::new(path))
+            .and_then(|fc_config| fc_config.to_vm_config())
+        {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Failed importing the Firecracker configuration {:?}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        let vm_params = config::VmParams::from_arg_matches(&cmd_arguments);
+        match config::VmConfig::parse(vm_params) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Failed parsing parameters {:?}", e);
+                process::exit(1);
+            }
         }
     };
 
@@ -316,7 +540,7 @@ fn start_vmm(cmd_arguments: ArgMatches) {
          \n\tKernel: {:?}\n\tKernel cmdline: {}\n\tDisk(s): {:?}",
         api_socket_path,
         vm_config.cpus.boot_vcpus,
-        vm_config.memory.size >> 20,
+        vm_config.memory.total_size() >> 20,
         vm_config.kernel,
         vm_config.cmdline.args.as_str(),
         vm_config.disks,
@@ -329,6 +553,9 @@ fn start_vmm(cmd_arguments: ArgMatches) {
     let vmm_thread = match vmm::start_vmm_thread(
         env!("CARGO_PKG_VERSION").to_string(),
         api_socket_path,
+        cmd_arguments.value_of("api-socket-ro"),
+        cmd_arguments.value_of("qmp-socket"),
+        None,
         api_evt.try_clone().unwrap(),
         http_sender,
         api_request_receiver,
@@ -340,7 +567,9 @@ fn start_vmm(cmd_arguments: ArgMatches) {
         }
     };
 
-    if cmd_arguments.is_present("vm-config") && vm_config.valid() {
+    if (cmd_arguments.is_present("vm-config") || cmd_arguments.is_present("firecracker-config"))
+        && vm_config.valid()
+    {
         // Create and boot the VM based off the VM config we just built.
         let sender = api_request_sender.clone();
         vmm::api::vm_create(
@@ -436,7 +665,8 @@ mod unit_tests {
     use crate::{create_app, prepare_default_values};
     use std::path::PathBuf;
     use vmm::config::{
-        CmdlineConfig, ConsoleConfig, ConsoleOutputMode, CpusConfig, MemoryConfig, RngConfig,
+        CmdlineConfig, ConsoleConfig, ConsoleOutputMode, CpusConfig, HotplugMethod, MemoryConfig,
+        MemoryOvercommit, PanicAction, RngConfig, UnknownAccessAction, UnknownAccessConfig,
         VmConfig, VmParams,
     };
 
@@ -492,18 +722,34 @@ mod unit_tests {
                 cpus: CpusConfig {
                     boot_vcpus: 1,
                     max_vcpus: 1,
+                    features: None,
+                    tsc_khz: None,
+                    nested: None,
+                    hyperv: false,
+                    model: None,
+                    freq_hints: false,
                 },
                 memory: MemoryConfig {
                     size: 536_870_912,
                     file: None,
                     mergeable: false,
                     hotplug_size: None,
+                    zones: None,
+                    dirty_rate_limit: None,
+                    mlock: false,
+                    prefault: false,
+                    working_set: false,
+                    overcommit: MemoryOvercommit::Off,
+                    target_free_host: 10,
+                    hotplug_method: HotplugMethod::Acpi,
+                    hotplugged_size: None,
                 },
                 kernel: None,
                 cmdline: CmdlineConfig {
                     args: String::from(""),
                 },
                 disks: None,
+                scsi: None,
                 net: None,
                 rng: RngConfig {
                     src: PathBuf::from("/dev/urandom"),
@@ -514,18 +760,38 @@ mod unit_tests {
                 serial: ConsoleConfig {
                     file: None,
                     mode: ConsoleOutputMode::Null,
+                    socket: None,
                     iommu: false,
+                    queue_size: 256,
+                    mux: false,
                 },
                 console: ConsoleConfig {
                     file: None,
                     mode: ConsoleOutputMode::Tty,
+                    socket: None,
                     iommu: false,
+                    queue_size: 256,
+                    mux: false,
                 },
+                console_ports: None,
                 devices: None,
+                sriov_vfs: None,
                 vhost_user_net: None,
                 vhost_user_blk: None,
                 vsock: None,
+                vdpa: None,
                 iommu: false,
+                cgroup: None,
+                sgx_epc: None,
+                platform: None,
+                balloon: None,
+                on_panic: PanicAction::Log,
+                unknown_access: UnknownAccessConfig {
+                    action: UnknownAccessAction::Log,
+                    log_limit: 10,
+                },
+                uuid: None,
+                state_dir: None,
             };
 
             aver_eq!(tb, expected_vm_config, result_vm_config);
@@ -557,6 +823,107 @@ mod unit_tests {
                 }"#,
                 false,
             ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--cpus",
+                    "boot=1,features=-avx512f:+invtsc",
+                ],
+                r#"{
+                    "cpus": {"boot_vcpus": 1, "max_vcpus": 1, "features": [
+                        {"name": "avx512f", "enabled": false},
+                        {"name": "invtsc", "enabled": true}
+                    ]}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--cpus", "boot=1,features=-avx512f"],
+                r#"{
+                    "cpus": {"boot_vcpus": 1, "max_vcpus": 1, "features": [
+                        {"name": "avx512f", "enabled": true}
+                    ]}
+                }"#,
+                false,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--cpus",
+                    "boot=2,features=-kvm_steal_time:-kvm_async_pf:+kvm_pv_unhalt",
+                ],
+                r#"{
+                    "cpus": {"boot_vcpus": 2, "max_vcpus": 2, "features": [
+                        {"name": "kvm_steal_time", "enabled": false},
+                        {"name": "kvm_async_pf", "enabled": false},
+                        {"name": "kvm_pv_unhalt", "enabled": true}
+                    ]}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--cpus", "boot=2,tsc_khz=2500000"],
+                r#"{
+                    "cpus": {"boot_vcpus": 2, "max_vcpus": 2, "tsc_khz": 2500000}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--cpus", "boot=2,nested=on"],
+                r#"{
+                    "cpus": {"boot_vcpus": 2, "max_vcpus": 2, "nested": true}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--cpus", "boot=2,nested=off"],
+                r#"{
+                    "cpus": {"boot_vcpus": 2, "max_vcpus": 2, "nested": false}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--cpus", "boot=2,nested=off"],
+                r#"{
+                    "cpus": {"boot_vcpus": 2, "max_vcpus": 2}
+                }"#,
+                false,
+            ),
+            (
+                vec!["cloud-hypervisor", "--cpus", "boot=2,hyperv=on"],
+                r#"{
+                    "cpus": {"boot_vcpus": 2, "max_vcpus": 2, "hyperv": true}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--cpus", "boot=2,hyperv=on"],
+                r#"{
+                    "cpus": {"boot_vcpus": 2, "max_vcpus": 2}
+                }"#,
+                false,
+            ),
+            (
+                vec!["cloud-hypervisor", "--cpus", "boot=2,model=skylake-server"],
+                r#"{
+                    "cpus": {"boot_vcpus": 2, "max_vcpus": 2, "model": "skylake-server"}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--cpus", "boot=2,model=skylake-server"],
+                r#"{
+                    "cpus": {"boot_vcpus": 2, "max_vcpus": 2, "model": "haswell"}
+                }"#,
+                false,
+            ),
+            (
+                vec!["cloud-hypervisor", "--cpus", "boot=2,freq_hints=on"],
+                r#"{
+                    "cpus": {"boot_vcpus": 2, "max_vcpus": 2, "freq_hints": true}
+                }"#,
+                true,
+            ),
         ]
         .iter()
         .for_each(|(cli, openapi, equal)| {
@@ -613,87 +980,447 @@ mod unit_tests {
                 }"#,
                 false,
             ),
-        ]
-        .iter()
-        .for_each(|(cli, openapi, equal)| {
-            compare_vm_config_cli_vs_json(cli, openapi, *equal);
-        });
-    }
-
-    #[test]
-    fn test_valid_vm_config_kernel() {
-        vec![(
-            vec!["cloud-hypervisor", "--kernel", "/path/to/kernel"],
-            r#"{
-                "kernel": {"path": "/path/to/kernel"}
-            }"#,
-            true,
-        )]
-        .iter()
-        .for_each(|(cli, openapi, equal)| {
-            compare_vm_config_cli_vs_json(cli, openapi, *equal);
-        });
-    }
-
-    #[test]
-    fn test_valid_vm_config_cmdline() {
-        vec![(
-            vec!["cloud-hypervisor", "--cmdline", "arg1=foo arg2=bar"],
-            r#"{
-                "cmdline": {"args": "arg1=foo arg2=bar"}
-            }"#,
-            true,
-        )]
-        .iter()
-        .for_each(|(cli, openapi, equal)| {
-            compare_vm_config_cli_vs_json(cli, openapi, *equal);
-        });
-    }
-
-    #[test]
-    fn test_valid_vm_config_disks() {
-        vec![
             (
                 vec![
                     "cloud-hypervisor",
-                    "--disk",
-                    "path=/path/to/disk/1",
-                    "path=/path/to/disk/2",
+                    "--memory",
+                    "size=1G,dirty_rate_limit=32M",
                 ],
                 r#"{
-                    "disks": [
-                        {"path": "/path/to/disk/1"},
-                        {"path": "/path/to/disk/2"}
-                    ]
+                    "memory": {"size": 1073741824, "dirty_rate_limit": 33554432}
                 }"#,
                 true,
             ),
             (
                 vec![
                     "cloud-hypervisor",
-                    "--disk",
-                    "path=/path/to/disk/1",
-                    "path=/path/to/disk/2",
+                    "--memory",
+                    "size=1G,mlock=on,prefault=on",
                 ],
                 r#"{
-                    "disks": [
-                        {"path": "/path/to/disk/1"}
-                    ]
+                    "memory": {"size": 1073741824, "mlock": true, "prefault": true}
                 }"#,
-                false,
+                true,
             ),
             (
-                vec![
-                    "cloud-hypervisor",
-                    "--disk",
-                    "path=/path/to/disk/1,vhost_user=true,socket=/tmp/socket1",
-                    "path=/path/to/disk/2",
-                ],
+                vec!["cloud-hypervisor", "--memory", "size=1G,prefault=on"],
                 r#"{
-                    "disks": [
-                        {"path": "/path/to/disk/1", "vhost_user":true, "vhost_socket":"/tmp/socket1"},
-                        {"path": "/path/to/disk/2"}
-                    ]
+                    "memory": {"size": 1073741824}
+                }"#,
+                false,
+            ),
+            (
+                vec!["cloud-hypervisor", "--memory", "size=1G,working_set=on"],
+                r#"{
+                    "memory": {"size": 1073741824, "working_set": true}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--memory", "size=1G,working_set=on"],
+                r#"{
+                    "memory": {"size": 1073741824}
+                }"#,
+                false,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--memory",
+                    "size=1G,overcommit=auto,target_free_host=20%",
+                    "--balloon",
+                    "size=0",
+                ],
+                r#"{
+                    "memory": {"size": 1073741824, "overcommit": "Auto", "target_free_host": 20},
+                    "balloon": {"size": 0}
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--memory",
+                    "size=1G,overcommit=auto",
+                    "--balloon",
+                    "size=0",
+                ],
+                r#"{
+                    "memory": {"size": 1073741824, "target_free_host": 10},
+                    "balloon": {"size": 0}
+                }"#,
+                false,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--memory",
+                    "size=1G,hotplug_size=1G,hotplug_method=virtio-mem,hotplugged_size=512M",
+                ],
+                r#"{
+                    "memory": {"size": 1073741824, "hotplug_size": 1073741824, "hotplug_method": "VirtioMem", "hotplugged_size": 536870912}
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--memory",
+                    "size=1G,hotplug_size=1G,overcommit=auto,hotplug_method=virtio-mem",
+                ],
+                r#"{
+                    "memory": {"size": 1073741824, "hotplug_size": 1073741824, "overcommit": "Auto", "hotplug_method": "VirtioMem"}
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--memory",
+                    "size=0",
+                    "--memory-zone",
+                    "size=1G,file=/path/to/zone/file",
+                    "size=2G,hugepages=on,host_numa_node=1",
+                ],
+                r#"{
+                    "memory": {"size": 3221225472, "zones": [
+                        {"size": 1073741824, "file": "/path/to/zone/file"},
+                        {"size": 2147483648, "hugepages": true, "host_numa_node": 1}
+                    ]}
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--memory",
+                    "size=0",
+                    "--memory-zone",
+                    "size=1G",
+                    "size=2G,hugepages=on",
+                ],
+                r#"{
+                    "memory": {"size": 3221225472, "zones": [
+                        {"size": 1073741824},
+                        {"size": 2147483648, "hugepages": false}
+                    ]}
+                }"#,
+                false,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--memory-zone",
+                    "size=1G",
+                    "size=1G,host_numa_node=0",
+                ],
+                r#"{
+                    "memory": {"size": 2147483648, "zones": [
+                        {"size": 1073741824},
+                        {"size": 1073741824, "host_numa_node": 0}
+                    ]}
+                }"#,
+                true,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_invalid_vm_config_memory_zones() {
+        let (default_vcpus, default_memory, default_rng) = prepare_default_values();
+
+        vec![
+            // The memory size conflicts with the zones.
+            vec![
+                "cloud-hypervisor",
+                "--memory",
+                "size=1G",
+                "--memory-zone",
+                "size=1G",
+                "size=1G",
+            ],
+            // Hugepages come from the backing file.
+            vec![
+                "cloud-hypervisor",
+                "--memory-zone",
+                "size=1G,file=/path/to/zone/file,hugepages=on",
+            ],
+        ]
+        .iter()
+        .for_each(|cli| {
+            let cmd_arguments =
+                create_app(&default_vcpus, &default_memory, &default_rng, "").get_matches_from(cli);
+            let vm_params = VmParams::from_arg_matches(&cmd_arguments);
+            assert!(VmConfig::parse(vm_params).is_err());
+        });
+
+        let memory: MemoryConfig = serde_json::from_str(
+            r#"{"size": 1073741824, "zones": [
+                {"size": 1073741824},
+                {"size": 1073741824}
+            ]}"#,
+        )
+        .unwrap();
+        assert!(memory.validate().is_err());
+
+        // The virtio-mem region holds the hotplugged memory, by whole
+        // blocks.
+        vec![
+            r#"{"size": 1073741824, "hotplug_method": "VirtioMem"}"#,
+            r#"{"size": 1073741824, "hotplug_size": 1048576, "hotplug_method": "VirtioMem"}"#,
+            r#"{"size": 1073741824, "hotplug_size": 1073741824, "hotplugged_size": 536870912}"#,
+            r#"{"size": 1073741824, "hotplug_size": 536870912, "hotplug_method": "VirtioMem",
+                "hotplugged_size": 1073741824}"#,
+        ]
+        .iter()
+        .for_each(|json| {
+            let memory: MemoryConfig = serde_json::from_str(json).unwrap();
+            assert!(memory.validate().is_err());
+        });
+
+        let memory: MemoryConfig = serde_json::from_str(
+            r#"{"size": 1073741824, "hotplug_size": 1073741824, "hotplug_method": "VirtioMem",
+                "hotplugged_size": 536870912}"#,
+        )
+        .unwrap();
+        assert!(memory.validate().is_ok());
+    }
+
+    #[test]
+    fn test_valid_vm_config_sgx_epc() {
+        vec![
+            (
+                vec!["cloud-hypervisor", "--sgx-epc", "size=64M"],
+                r#"{
+                    "sgx_epc": [
+                        {"size": 67108864}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--sgx-epc",
+                    "size=64M,prefault=on",
+                    "size=32M",
+                ],
+                r#"{
+                    "sgx_epc": [
+                        {"size": 67108864, "prefault": true},
+                        {"size": 33554432}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--sgx-epc", "size=64M,prefault=on"],
+                r#"{
+                    "sgx_epc": [
+                        {"size": 67108864}
+                    ]
+                }"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_kernel() {
+        vec![(
+            vec!["cloud-hypervisor", "--kernel", "/path/to/kernel"],
+            r#"{
+                "kernel": {"path": "/path/to/kernel"}
+            }"#,
+            true,
+        )]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_cmdline() {
+        vec![(
+            vec!["cloud-hypervisor", "--cmdline", "arg1=foo arg2=bar"],
+            r#"{
+                "cmdline": {"args": "arg1=foo arg2=bar"}
+            }"#,
+            true,
+        )]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_disks() {
+        vec![
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--disk",
+                    "path=/path/to/disk/1",
+                    "path=/path/to/disk/2",
+                ],
+                r#"{
+                    "disks": [
+                        {"path": "/path/to/disk/1"},
+                        {"path": "/path/to/disk/2"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--disk",
+                    "path=/path/to/disk/1",
+                    "path=/path/to/disk/2",
+                ],
+                r#"{
+                    "disks": [
+                        {"path": "/path/to/disk/1"}
+                    ]
+                }"#,
+                false,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--disk",
+                    "path=/path/to/disk/1,vhost_user=true,socket=/tmp/socket1",
+                    "path=/path/to/disk/2",
+                ],
+                r#"{
+                    "disks": [
+                        {"path": "/path/to/disk/1", "vhost_user":true, "vhost_socket":"/tmp/socket1"},
+                        {"path": "/path/to/disk/2"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--disk",
+                    "path=/path/to/disk/1,vhost_user=true,socket=/tmp/socket1,wce=true",
+                    "path=/path/to/disk/2",
+                ],
+                r#"{
+                    "disks": [
+                        {"path": "/path/to/disk/1", "vhost_user":true, "vhost_socket":"/tmp/socket1", "wce":true},
+                        {"path": "/path/to/disk/2"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--disk",
+                    "path=/path/to/disk/1,nvme=on",
+                    "path=/path/to/disk/2",
+                ],
+                r#"{
+                    "disks": [
+                        {"path": "/path/to/disk/1", "nvme": true},
+                        {"path": "/path/to/disk/2"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--disk",
+                    "path=/path/to/disk/1,nvme=on",
+                    "path=/path/to/disk/2",
+                ],
+                r#"{
+                    "disks": [
+                        {"path": "/path/to/disk/1"},
+                        {"path": "/path/to/disk/2"}
+                    ]
+                }"#,
+                false,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--disk",
+                    "path=/path/to/disk/1,readonly=on",
+                    "path=/path/to/disk/2,direct=on",
+                ],
+                r#"{
+                    "disks": [
+                        {"path": "/path/to/disk/1", "readonly": true},
+                        {"path": "/path/to/disk/2", "direct": true}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--disk",
+                    "path=/path/to/disk/1,readonly=on,direct=on",
+                ],
+                r#"{
+                    "disks": [
+                        {"path": "/path/to/disk/1", "readonly": true}
+                    ]
+                }"#,
+                false,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--disk",
+                    "path=/path/to/disk/1,watch=on",
+                ],
+                r#"{
+                    "disks": [
+                        {"path": "/path/to/disk/1", "watch": true}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--disk",
+                    "path=/path/to/base,overlay=/path/to/overlay",
+                ],
+                r#"{
+                    "disks": [
+                        {"path": "/path/to/base", "overlay": "/path/to/overlay"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--disk",
+                    "path=/path/to/disk/1",
+                    "path=/path/to/installer.iso,cdrom=on,boot=on",
+                ],
+                r#"{
+                    "disks": [
+                        {"path": "/path/to/disk/1"},
+                        {"path": "/path/to/installer.iso", "readonly": true, "cdrom": true, "boot": true}
+                    ]
                 }"#,
                 true,
             ),
@@ -701,17 +1428,163 @@ mod unit_tests {
                 vec![
                     "cloud-hypervisor",
                     "--disk",
-                    "path=/path/to/disk/1,vhost_user=true,socket=/tmp/socket1,wce=true",
+                    "path=/path/to/disk/1,serial=os-disk",
+                ],
+                r#"{
+                    "disks": [
+                        {"path": "/path/to/disk/1", "serial": "os-disk"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--disk",
+                    "path=/path/to/disk/1,on_error=stop",
+                    "path=/path/to/disk/2,on_error=report",
+                ],
+                r#"{
+                    "disks": [
+                        {"path": "/path/to/disk/1", "on_error": "Stop"},
+                        {"path": "/path/to/disk/2"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--disk",
+                    "path=/path/to/disk/1,track_changes=on",
+                    "path=/path/to/disk/2,track_changes=off",
+                ],
+                r#"{
+                    "disks": [
+                        {"path": "/path/to/disk/1", "track_changes": true},
+                        {"path": "/path/to/disk/2"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--disk",
+                    "path=/path/to/disk/1,id=os",
                     "path=/path/to/disk/2",
                 ],
                 r#"{
                     "disks": [
-                        {"path": "/path/to/disk/1", "vhost_user":true, "vhost_socket":"/tmp/socket1", "wce":true},
+                        {"path": "/path/to/disk/1", "id": "os"},
                         {"path": "/path/to/disk/2"}
                     ]
                 }"#,
                 true,
             ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--disk",
+                    "path=/path/to/disk/1,pci_address=0000:00:05.0",
+                    "path=/path/to/disk/2,pci_address=1f",
+                ],
+                r#"{
+                    "disks": [
+                        {"path": "/path/to/disk/1", "pci_address": {"device": 5}},
+                        {"path": "/path/to/disk/2", "pci_address": {"bus": 0, "device": 31, "function": 0}}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--disk",
+                    "path=/path/to/disk/1,bw_size=10M,ops_size=1000,ops_one_time_burst=5000,ops_refill_time=100",
+                ],
+                r#"{
+                    "disks": [
+                        {
+                            "path": "/path/to/disk/1",
+                            "rate_limiter_config": {
+                                "bandwidth": {"size": 10485760},
+                                "ops": {"size": 1000, "one_time_burst": 5000, "refill_time": 100}
+                            }
+                        }
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--disk",
+                    "path=/path/to/disk/1,bw_size=10M",
+                ],
+                r#"{
+                    "disks": [
+                        {
+                            "path": "/path/to/disk/1",
+                            "rate_limiter_config": {
+                                "bandwidth": {"size": 10485760, "refill_time": 100}
+                            }
+                        }
+                    ]
+                }"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_scsi() {
+        vec![
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--scsi",
+                    "luns=/path/to/disk/1:/dev/sg2",
+                ],
+                r#"{
+                    "scsi": [
+                        {"luns": ["/path/to/disk/1", "/dev/sg2"]}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--scsi",
+                    "luns=/path/to/disk/1,num_queues=4,queue_size=256",
+                    "luns=/path/to/disk/2,readonly=on",
+                ],
+                r#"{
+                    "scsi": [
+                        {"luns": ["/path/to/disk/1"], "num_queues": 4, "queue_size": 256},
+                        {"luns": ["/path/to/disk/2"], "readonly": true}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--scsi",
+                    "luns=/path/to/disk/1:/path/to/disk/2",
+                ],
+                r#"{
+                    "scsi": [
+                        {"luns": ["/path/to/disk/1"]}
+                    ]
+                }"#,
+                false,
+            ),
         ]
         .iter()
         .for_each(|(cli, openapi, equal)| {
@@ -730,52 +1603,189 @@ mod unit_tests {
                 r#"{
                     "net": []
                 }"#,
-                false,
+                false,
+            ),
+            (
+                vec!["cloud-hypervisor", "--net", "mac=12:34:56:78:90:ab"],
+                r#"{
+                    "net": [
+                        {"mac": "12:34:56:78:90:ab"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--net", "mac=12:34:56:78:90:ab,id=eth0"],
+                r#"{
+                    "net": [
+                        {"mac": "12:34:56:78:90:ab", "id": "eth0"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--net",
+                    "mac=12:34:56:78:90:ab,tap=tap0",
+                ],
+                r#"{
+                    "net": [
+                        {"mac": "12:34:56:78:90:ab", "tap": "tap0"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--net",
+                    "mac=12:34:56:78:90:ab,tap=tap0,ip=1.2.3.4",
+                ],
+                r#"{
+                    "net": [
+                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "ip": "1.2.3.4"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--net",
+                    "mac=12:34:56:78:90:ab,tap=tap0,ip=1.2.3.4,mask=5.6.7.8",
+                ],
+                r#"{
+                    "net": [
+                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "ip": "1.2.3.4", "mask": "5.6.7.8"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--net",
+                    "mac=12:34:56:78:90:ab,tap=tap0,ip=1.2.3.4,mask=5.6.7.8,num_queues=4",
+                ],
+                r#"{
+                    "net": [
+                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "ip": "1.2.3.4", "mask": "5.6.7.8", "num_queues": 4}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--net",
+                    "mac=12:34:56:78:90:ab,tap=tap0,ip=1.2.3.4,mask=5.6.7.8,num_queues=4,queue_size=128",
+                ],
+                r#"{
+                    "net": [
+                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "ip": "1.2.3.4", "mask": "5.6.7.8", "num_queues": 4, "queue_size": 128}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--net",
+                    "mac=12:34:56:78:90:ab,tap=tap0,ip=1.2.3.4,mask=5.6.7.8,num_queues=2,queue_size=256",
+                ],
+                r#"{
+                    "net": [
+                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "ip": "1.2.3.4", "mask": "5.6.7.8"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--net",
+                    "mac=12:34:56:78:90:ab,tap=tap0,ip=1.2.3.4,mask=5.6.7.8",
+                ],
+                r#"{
+                    "net": [
+                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "ip": "1.2.3.4", "mask": "5.6.7.8", "num_queues": 2, "queue_size": 256}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--net",
+                    "mac=12:34:56:78:90:ab,tap=tap0,ip=1.2.3.4,mask=5.6.7.8,num_queues=2,queue_size=256,iommu=on",
+                ],
+                r#"{
+                    "net": [
+                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "ip": "1.2.3.4", "mask": "5.6.7.8", "num_queues": 2, "queue_size": 256, "iommu": true}
+                    ]
+                }"#,
+                false,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--net",
+                    "mac=12:34:56:78:90:ab,tap=tap0,ip=1.2.3.4,mask=5.6.7.8,num_queues=2,queue_size=256,iommu=on",
+                ],
+                r#"{
+                    "net": [
+                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "ip": "1.2.3.4", "mask": "5.6.7.8", "num_queues": 2, "queue_size": 256, "iommu": true}
+                    ],
+                    "iommu": true
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--net",
+                    "mac=12:34:56:78:90:ab,tap=tap0,ip=1.2.3.4,mask=5.6.7.8,num_queues=2,queue_size=256,iommu=off",
+                ],
+                r#"{
+                    "net": [
+                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "ip": "1.2.3.4", "mask": "5.6.7.8", "num_queues": 2, "queue_size": 256, "iommu": false}
+                    ]
+                }"#,
+                true,
             ),
             (
-                vec!["cloud-hypervisor", "--net", "mac=12:34:56:78:90:ab"],
+                vec!["cloud-hypervisor", "--net", "mac=12:34:56:78:90:ab,vhost_user=true,socket=/tmp/socket"],
                 r#"{
                     "net": [
-                        {"mac": "12:34:56:78:90:ab"}
+                        {"mac": "12:34:56:78:90:ab", "vhost_user": true, "vhost_socket": "/tmp/socket"}
                     ]
                 }"#,
                 true,
             ),
             (
-                vec![
-                    "cloud-hypervisor",
-                    "--net",
-                    "mac=12:34:56:78:90:ab,tap=tap0",
-                ],
+                vec!["cloud-hypervisor", "--net", "mac=12:34:56:78:90:ab,tap=tap0,transitional=on"],
                 r#"{
                     "net": [
-                        {"mac": "12:34:56:78:90:ab", "tap": "tap0"}
+                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "transitional": true}
                     ]
                 }"#,
                 true,
             ),
             (
-                vec![
-                    "cloud-hypervisor",
-                    "--net",
-                    "mac=12:34:56:78:90:ab,tap=tap0,ip=1.2.3.4",
-                ],
+                vec!["cloud-hypervisor", "--net", "mac=12:34:56:78:90:ab,tap=tap0,transitional=on"],
                 r#"{
                     "net": [
-                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "ip": "1.2.3.4"}
+                        {"mac": "12:34:56:78:90:ab", "tap": "tap0"}
                     ]
                 }"#,
-                true,
+                false,
             ),
             (
-                vec![
-                    "cloud-hypervisor",
-                    "--net",
-                    "mac=12:34:56:78:90:ab,tap=tap0,ip=1.2.3.4,mask=5.6.7.8",
-                ],
+                vec!["cloud-hypervisor", "--net", "mac=12:34:56:78:90:ab,tap=tap0,standby=on"],
                 r#"{
                     "net": [
-                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "ip": "1.2.3.4", "mask": "5.6.7.8"}
+                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "standby": true}
                     ]
                 }"#,
                 true,
@@ -784,11 +1794,11 @@ mod unit_tests {
                 vec![
                     "cloud-hypervisor",
                     "--net",
-                    "mac=12:34:56:78:90:ab,tap=tap0,ip=1.2.3.4,mask=5.6.7.8,num_queues=4",
+                    "mac=12:34:56:78:90:ab,xdp=eth1,xdp_map=/sys/fs/bpf/xsks_map,num_queues=4",
                 ],
                 r#"{
                     "net": [
-                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "ip": "1.2.3.4", "mask": "5.6.7.8", "num_queues": 4}
+                        {"mac": "12:34:56:78:90:ab", "xdp": "eth1", "xdp_map": "/sys/fs/bpf/xsks_map", "num_queues": 4}
                     ]
                 }"#,
                 true,
@@ -797,11 +1807,13 @@ mod unit_tests {
                 vec![
                     "cloud-hypervisor",
                     "--net",
-                    "mac=12:34:56:78:90:ab,tap=tap0,ip=1.2.3.4,mask=5.6.7.8,num_queues=4,queue_size=128",
+                    "mac=12:34:56:78:90:ab,fd=3",
+                    "mac=12:34:56:78:90:ac,host_mac=12:34:56:78:90:ad,tap=macvtap0",
                 ],
                 r#"{
                     "net": [
-                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "ip": "1.2.3.4", "mask": "5.6.7.8", "num_queues": 4, "queue_size": 128}
+                        {"mac": "12:34:56:78:90:ab", "fds": [3]},
+                        {"mac": "12:34:56:78:90:ac", "host_mac": "12:34:56:78:90:ad", "tap": "macvtap0"}
                     ]
                 }"#,
                 true,
@@ -810,11 +1822,13 @@ mod unit_tests {
                 vec![
                     "cloud-hypervisor",
                     "--net",
-                    "mac=12:34:56:78:90:ab,tap=tap0,ip=1.2.3.4,mask=5.6.7.8,num_queues=2,queue_size=256",
+                    "mac=12:34:56:78:90:ab,fds=3:4:5:6",
+                    "mac=12:34:56:78:90:ac,fds=7:8,num_queues=4",
                 ],
                 r#"{
                     "net": [
-                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "ip": "1.2.3.4", "mask": "5.6.7.8"}
+                        {"mac": "12:34:56:78:90:ab", "fds": [3, 4, 5, 6], "num_queues": 8},
+                        {"mac": "12:34:56:78:90:ac", "fds": [7, 8], "num_queues": 4}
                     ]
                 }"#,
                 true,
@@ -823,11 +1837,21 @@ mod unit_tests {
                 vec![
                     "cloud-hypervisor",
                     "--net",
-                    "mac=12:34:56:78:90:ab,tap=tap0,ip=1.2.3.4,mask=5.6.7.8",
+                    "mac=12:34:56:78:90:ab,tap=tap0,rx_bw_size=100M,tx_bw_size=10M,tx_ops_size=10000,tx_ops_refill_time=100",
                 ],
                 r#"{
                     "net": [
-                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "ip": "1.2.3.4", "mask": "5.6.7.8", "num_queues": 2, "queue_size": 256}
+                        {
+                            "mac": "12:34:56:78:90:ab",
+                            "tap": "tap0",
+                            "rx_rate_limiter_config": {
+                                "bandwidth": {"size": 104857600}
+                            },
+                            "tx_rate_limiter_config": {
+                                "bandwidth": {"size": 10485760},
+                                "ops": {"size": 10000, "refill_time": 100}
+                            }
+                        }
                     ]
                 }"#,
                 true,
@@ -836,26 +1860,32 @@ mod unit_tests {
                 vec![
                     "cloud-hypervisor",
                     "--net",
-                    "mac=12:34:56:78:90:ab,tap=tap0,ip=1.2.3.4,mask=5.6.7.8,num_queues=2,queue_size=256,iommu=on",
+                    "mac=12:34:56:78:90:ab,tap=tap0,rx_bw_size=1M,rx_bw_one_time_burst=10M,rx_bw_refill_time=500,rx_ops_size=100",
                 ],
                 r#"{
                     "net": [
-                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "ip": "1.2.3.4", "mask": "5.6.7.8", "num_queues": 2, "queue_size": 256, "iommu": true}
+                        {
+                            "mac": "12:34:56:78:90:ab",
+                            "tap": "tap0",
+                            "rx_rate_limiter_config": {
+                                "bandwidth": {"size": 1048576, "one_time_burst": 10485760, "refill_time": 500},
+                                "ops": {"size": 100}
+                            }
+                        }
                     ]
                 }"#,
-                false,
+                true,
             ),
             (
                 vec![
                     "cloud-hypervisor",
                     "--net",
-                    "mac=12:34:56:78:90:ab,tap=tap0,ip=1.2.3.4,mask=5.6.7.8,num_queues=2,queue_size=256,iommu=on",
+                    "mac=12:34:56:78:90:ab,tap=tap0,mtu=9000",
                 ],
                 r#"{
                     "net": [
-                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "ip": "1.2.3.4", "mask": "5.6.7.8", "num_queues": 2, "queue_size": 256, "iommu": true}
-                    ],
-                    "iommu": true
+                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "mtu": 9000}
+                    ]
                 }"#,
                 true,
             ),
@@ -863,23 +1893,27 @@ mod unit_tests {
                 vec![
                     "cloud-hypervisor",
                     "--net",
-                    "mac=12:34:56:78:90:ab,tap=tap0,ip=1.2.3.4,mask=5.6.7.8,num_queues=2,queue_size=256,iommu=off",
+                    "mac=12:34:56:78:90:ab,tap=tap0,offload_tso=off,offload_ufo=off",
                 ],
                 r#"{
                     "net": [
-                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "ip": "1.2.3.4", "mask": "5.6.7.8", "num_queues": 2, "queue_size": 256, "iommu": false}
+                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "offload_tso": false, "offload_ufo": false}
                     ]
                 }"#,
                 true,
             ),
             (
-                vec!["cloud-hypervisor", "--net", "mac=12:34:56:78:90:ab,vhost_user=true,socket=/tmp/socket"],
+                vec![
+                    "cloud-hypervisor",
+                    "--net",
+                    "mac=12:34:56:78:90:ab,tap=tap0,offload_csum=off",
+                ],
                 r#"{
                     "net": [
-                        {"mac": "12:34:56:78:90:ab", "vhost_user": true, "vhost_socket": "/tmp/socket"}
+                        {"mac": "12:34:56:78:90:ab", "tap": "tap0"}
                     ]
                 }"#,
-                true,
+                false,
             ),
         ]
         .iter()
@@ -888,6 +1922,44 @@ mod unit_tests {
         });
     }
 
+    #[test]
+    fn test_invalid_vm_config_net() {
+        let (default_vcpus, default_memory, default_rng) = prepare_default_values();
+
+        vec![
+            // A token bucket needs a size.
+            vec![
+                "cloud-hypervisor",
+                "--net",
+                "mac=12:34:56:78:90:ab,tap=tap0,rx_bw_refill_time=100",
+            ],
+            vec![
+                "cloud-hypervisor",
+                "--net",
+                "mac=12:34:56:78:90:ab,tap=tap0,tx_ops_one_time_burst=10",
+            ],
+            vec![
+                "cloud-hypervisor",
+                "--net",
+                "mac=12:34:56:78:90:ab,tap=tap0,tx_ops_size=many",
+            ],
+            // The frames of the vhost-user devices aren't processed by the
+            // VMM.
+            vec![
+                "cloud-hypervisor",
+                "--net",
+                "mac=12:34:56:78:90:ab,vhost_user=true,socket=/tmp/socket,tx_bw_size=10M",
+            ],
+        ]
+        .iter()
+        .for_each(|cli| {
+            let cmd_arguments =
+                create_app(&default_vcpus, &default_memory, &default_rng, "").get_matches_from(cli);
+            let vm_params = VmParams::from_arg_matches(&cmd_arguments);
+            assert!(VmConfig::parse(vm_params).is_err());
+        });
+    }
+
     #[test]
     fn test_valid_vm_config_rng() {
         vec![(
@@ -1052,6 +2124,32 @@ mod unit_tests {
                 }"#,
                 false,
             ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--fs",
+                    "tag=virtiofs1,path=/path/to/shared",
+                ],
+                r#"{
+                    "fs": [
+                        {"tag": "virtiofs1", "path": "/path/to/shared"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--fs",
+                    "tag=virtiofs1,sock=/path/to/sock1,path=/path/to/shared,spawn=on,sandbox=chroot",
+                ],
+                r#"{
+                    "fs": [
+                        {"tag": "virtiofs1", "sock": "/path/to/sock1", "path": "/path/to/shared", "spawn": true, "sandbox": "Chroot"}
+                    ]
+                }"#,
+                true,
+            ),
         ]
         .iter()
         .for_each(|(cli, openapi, equal)| {
@@ -1130,6 +2228,19 @@ mod unit_tests {
                 }"#,
                 true,
             ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--pmem",
+                    "file=/path/to/img/1,discard_writes=on",
+                ],
+                r#"{
+                    "pmem": [
+                        {"file": "/path/to/img/1", "discard_writes": true}
+                    ]
+                }"#,
+                true,
+            ),
         ]
         .iter()
         .for_each(|(cli, openapi, equal)| {
@@ -1161,6 +2272,72 @@ mod unit_tests {
                 }"#,
                 true,
             ),
+            (
+                vec!["cloud-hypervisor", "--serial", "tty"],
+                r#"{
+                    "serial": {"mode": "Tty"},
+                    "console": {"mode": "Off"}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--console-profile", "legacy"],
+                r#"{
+                    "serial": {"mode": "Tty"},
+                    "console": {"mode": "Off"}
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--console-profile",
+                    "legacy",
+                    "--console",
+                    "file=/tmp/console",
+                ],
+                r#"{
+                    "serial": {"mode": "Tty"},
+                    "console": {"mode": "File", "file": "/tmp/console"}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--serial", "pty", "--console", "pty"],
+                r#"{
+                    "serial": {"mode": "Pty"},
+                    "console": {"mode": "Pty"}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--console", "socket=/tmp/console.sock"],
+                r#"{
+                    "console": {"mode": "Socket", "socket": "/tmp/console.sock"}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--console", "tty,queue_size=1024"],
+                r#"{
+                    "console": {"mode": "Tty", "queue_size": 1024}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--console", "tty,mux=on"],
+                r#"{
+                    "console": {"mode": "Tty", "mux": true}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--console", "tty,mux=on"],
+                r#"{
+                    "console": {"mode": "Tty"}
+                }"#,
+                false,
+            ),
         ]
         .iter()
         .for_each(|(cli, openapi, equal)| {
@@ -1238,7 +2415,190 @@ mod unit_tests {
                         {"path": "/path/to/device", "iommu": false}
                     ]
                 }"#,
-                true,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--device",
+                    "path=/path/to/device,reset=bus,reset_on_attach=off",
+                ],
+                r#"{
+                    "devices": [
+                        {"path": "/path/to/device", "reset": "Bus", "reset_on_attach": false}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--device",
+                    "path=/path/to/device,reset=none",
+                ],
+                r#"{
+                    "devices": [
+                        {"path": "/path/to/device", "reset": "Flr"}
+                    ]
+                }"#,
+                false,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--device",
+                    "path=/path/to/device/1,p2p_dma=on",
+                    "path=/path/to/device/2,p2p_dma=on",
+                ],
+                r#"{
+                    "devices": [
+                        {"path": "/path/to/device/1", "p2p_dma": true},
+                        {"path": "/path/to/device/2", "p2p_dma": true}
+                    ]
+                }"#,
+                true,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_invalid_vm_config_pci_address() {
+        let (default_vcpus, default_memory, default_rng) = prepare_default_values();
+
+        vec![
+            // Device 0 is the host bridge, and the device ID is 5 bits.
+            vec![
+                "cloud-hypervisor",
+                "--disk",
+                "path=/path/to/disk,pci_address=0",
+            ],
+            vec![
+                "cloud-hypervisor",
+                "--disk",
+                "path=/path/to/disk,pci_address=20",
+            ],
+            // Only the functions 0 of the devices of the bus 0 of the
+            // segment 0 are emulated.
+            vec![
+                "cloud-hypervisor",
+                "--disk",
+                "path=/path/to/disk,pci_address=5.1",
+            ],
+            vec![
+                "cloud-hypervisor",
+                "--disk",
+                "path=/path/to/disk,pci_address=01:05.0",
+            ],
+            vec![
+                "cloud-hypervisor",
+                "--disk",
+                "path=/path/to/disk,pci_address=0001:00:05.0",
+            ],
+            vec![
+                "cloud-hypervisor",
+                "--disk",
+                "path=/path/to/disk,pci_address=zz",
+            ],
+            // Two devices can't share an address.
+            vec![
+                "cloud-hypervisor",
+                "--disk",
+                "path=/path/to/disk,pci_address=5",
+                "--net",
+                "mac=12:34:56:78:90:ab,tap=tap0,pci_address=00:05.0",
+            ],
+            vec![
+                "cloud-hypervisor",
+                "--device",
+                "path=/path/to/device/1,pci_address=6",
+                "path=/path/to/device/2,pci_address=6",
+            ],
+        ]
+        .iter()
+        .for_each(|cli| {
+            let cmd_arguments =
+                create_app(&default_vcpus, &default_memory, &default_rng, "").get_matches_from(cli);
+            let vm_params = VmParams::from_arg_matches(&cmd_arguments);
+            assert!(VmConfig::parse(vm_params).is_err());
+        });
+
+        // The addresses given through the API are checked too.
+        vec![
+            r#"{"disks": [{"path": "/path/to/disk", "pci_address": {"device": 32}}]}"#,
+            r#"{"fs": [
+                {"tag": "myfs", "sock": "/tmp/sock", "pci_address": {"device": 7}},
+                {"tag": "myfs2", "sock": "/tmp/sock2", "pci_address": {"device": 7}}
+            ]}"#,
+        ]
+        .iter()
+        .for_each(|json| {
+            let vm_config: VmConfig = serde_json::from_str(json).unwrap();
+            assert!(vm_config.validate_pci_addresses().is_err());
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_sriov_vfs() {
+        vec![
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--sriov-vf",
+                    "pf=enp3s0f0,index=0",
+                    "pf=enp3s0f0,index=1,mac=12:34:56:78:90:ab,vlan=100",
+                ],
+                r#"{
+                    "sriov_vfs": [
+                        {"pf": "enp3s0f0", "index": 0},
+                        {"pf": "enp3s0f0", "index": 1, "mac": "12:34:56:78:90:ab", "vlan": 100}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--sriov-vf",
+                    "pf=enp3s0f0,index=2,iommu=on",
+                ],
+                r#"{
+                    "sriov_vfs": [
+                        {"pf": "enp3s0f0", "index": 2, "iommu": true}
+                    ],
+                    "iommu": true
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--net",
+                    "mac=12:34:56:78:90:ab,tap=tap0,standby=on",
+                    "--sriov-vf",
+                    "pf=enp3s0f0,index=0,mac=12:34:56:78:90:ab,failover=on",
+                ],
+                r#"{
+                    "net": [
+                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "standby": true}
+                    ],
+                    "sriov_vfs": [
+                        {"pf": "enp3s0f0", "index": 0, "mac": "12:34:56:78:90:ab", "failover": true}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--sriov-vf", "pf=enp3s0f0,index=0"],
+                r#"{
+                    "sriov_vfs": [
+                        {"pf": "enp3s0f0", "index": 1}
+                    ]
+                }"#,
+                false,
             ),
         ]
         .iter()
@@ -1478,6 +2838,15 @@ mod unit_tests {
                 }"#,
                 false,
             ),
+            (
+                vec!["cloud-hypervisor", "--vsock", "cid=3,socket=/run/vm.sock"],
+                r#"{
+                    "vsock": [
+                        {"cid": 3, "socket": "/run/vm.sock"}
+                    ]
+                }"#,
+                true,
+            ),
             (
                 vec![
                     "cloud-hypervisor",
@@ -1537,6 +2906,338 @@ mod unit_tests {
             compare_vm_config_cli_vs_json(cli, openapi, *equal);
         });
     }
+
+    #[test]
+    fn test_valid_vm_config_vdpa() {
+        vec![
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--vdpa",
+                    "path=/dev/vhost-vdpa-0",
+                    "path=/dev/vhost-vdpa-1,num_queues=4",
+                ],
+                r#"{
+                    "vdpa": [
+                        {"path": "/dev/vhost-vdpa-0", "num_queues": 2},
+                        {"path": "/dev/vhost-vdpa-1", "num_queues": 4}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--vdpa", "path=/dev/vhost-vdpa-0"],
+                r#"{
+                    "vdpa": [
+                        {"path": "/dev/vhost-vdpa-0", "num_queues": 4}
+                    ]
+                }"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_console_ports() {
+        vec![
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--console-port",
+                    "name=org.qemu.guest_agent.0,socket=/run/vm-qga.sock",
+                    "name=log,file=/var/log/vm.log",
+                ],
+                r#"{
+                    "console_ports": [
+                        {"name": "org.qemu.guest_agent.0", "socket": "/run/vm-qga.sock"},
+                        {"name": "log", "file": "/var/log/vm.log"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--console-port",
+                    "name=log,file=/var/log/vm.log",
+                ],
+                r#"{
+                    "console_ports": [
+                        {"name": "log", "socket": "/var/log/vm.log"}
+                    ]
+                }"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_cgroup() {
+        vec![
+            (
+                vec!["cloud-hypervisor", "--cgroup", "path=/sys/fs/cgroup/vm0"],
+                r#"{
+                    "cgroup": {"path": "/sys/fs/cgroup/vm0"}
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--cgroup",
+                    "path=/sys/fs/cgroup/vm0,vcpus_cpu_max=200000,vcpus_cpuset=2-5:8,vmm_cpuset=0",
+                ],
+                r#"{
+                    "cgroup": {
+                        "path": "/sys/fs/cgroup/vm0",
+                        "vcpus_cpu_max": 200000,
+                        "vcpus_cpuset": "2-5,8",
+                        "vmm_cpuset": "0",
+                        "cpu_period": 100000
+                    }
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--cgroup",
+                    "path=/sys/fs/cgroup/vm0,vmm_cpu_max=50000,cpu_period=200000",
+                ],
+                r#"{
+                    "cgroup": {"path": "/sys/fs/cgroup/vm0", "vmm_cpu_max": 50000}
+                }"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_platform() {
+        vec![
+            (
+                vec!["cloud-hypervisor", "--platform", "sev=on"],
+                r#"{
+                    "platform": {"sev": true}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--platform", "sev=on,sev_policy=0x5"],
+                r#"{
+                    "platform": {"sev": true, "sev_policy": 5}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--platform", "sev=on,sev_policy=0"],
+                r#"{
+                    "platform": {"sev": true}
+                }"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_balloon() {
+        vec![
+            (
+                vec!["cloud-hypervisor", "--balloon", "size=1G"],
+                r#"{
+                    "balloon": {"size": 1073741824}
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--balloon",
+                    "size=512M,deflate_on_oom=on",
+                ],
+                r#"{
+                    "balloon": {"size": 536870912, "deflate_on_oom": true}
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--balloon",
+                    "size=512M,deflate_on_oom=on",
+                ],
+                r#"{
+                    "balloon": {"size": 536870912}
+                }"#,
+                false,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--balloon",
+                    "size=0,free_page_reporting=on",
+                ],
+                r#"{
+                    "balloon": {"size": 0, "free_page_reporting": true}
+                }"#,
+                true,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_on_panic() {
+        vec![
+            (
+                vec!["cloud-hypervisor", "--on-panic", "pause"],
+                r#"{
+                    "on_panic": "Pause"
+                }"#,
+                true,
+            ),
+            (vec!["cloud-hypervisor", "--on-panic", "log"], r#"{}"#, true),
+            (
+                vec!["cloud-hypervisor", "--on-panic", "reboot"],
+                r#"{
+                    "on_panic": "Shutdown"
+                }"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_unknown_access() {
+        vec![
+            (
+                vec!["cloud-hypervisor", "--unknown-access", "fault"],
+                r#"{
+                    "unknown_access": {"action": "Fault"}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--unknown-access", "log,log_limit=100"],
+                r#"{
+                    "unknown_access": {"action": "Log", "log_limit": 100}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--unknown-access", "log"],
+                r#"{}"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--unknown-access", "ignore"],
+                r#"{
+                    "unknown_access": {"action": "Log"}
+                }"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_state_dir() {
+        vec![
+            (
+                vec!["cloud-hypervisor", "--state-dir", "/var/lib/vm0"],
+                r#"{
+                    "state_dir": "/var/lib/vm0"
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--state-dir",
+                    "/var/lib/vm0",
+                    "--serial",
+                    "file=serial.log",
+                ],
+                r#"{
+                    "state_dir": "/var/lib/vm0",
+                    "serial": {"mode": "File", "file": "serial.log"}
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor", "--state-dir", "/var/lib/vm0"],
+                r#"{}"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_uuid() {
+        vec![
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--uuid",
+                    "4eb6542d-7a26-4a9c-8c1b-aba5550e8e4f",
+                ],
+                r#"{
+                    "uuid": "4eb6542d-7a26-4a9c-8c1b-aba5550e8e4f"
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--uuid",
+                    "4EB6542D-7A26-4A9C-8C1B-ABA5550E8E4F",
+                ],
+                r#"{
+                    "uuid": "4eb6542d-7a26-4a9c-8c1b-aba5550e8e4f"
+                }"#,
+                true,
+            ),
+            (
+                vec!["cloud-hypervisor"],
+                r#"{"uuid": "4eb6542d-7a26-4a9c-8c1b-aba5550e8e4f"}"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
 }
 
 #[cfg(test)]
@@ -26,8 +26,8 @@ mod vfio_pci;
 
 use std::mem::size_of;
 
-pub use vfio_device::{VfioContainer, VfioDevice, VfioDmaMapping, VfioError};
-pub use vfio_pci::{VfioPciDevice, VfioPciError};
+pub use vfio_device::{VfioContainer, VfioDevice, VfioDmaMapping, VfioError, VfioResetMethod};
+pub use vfio_pci::{VfioPciDevice, VfioPciError, VfioResetPolicy};
 
 // Returns a `Vec<T>` with a size in bytes at least as large as `size_in_bytes`.
 fn vec_with_size_in_bytes<T: Default>(size_in_bytes: usize) -> Vec<T> {
@@ -55,6 +55,8 @@ pub enum VfioError {
     VfioDeviceSetIrq,
     ReadLink(io::Error),
     ParseInt(num::ParseIntError),
+    VfioDeviceReset(io::Error),
+    VfioDevicePciHotReset(io::Error),
 }
 pub type Result<T> = std::result::Result<T, VfioError>;
 
@@ -105,6 +107,10 @@ impl fmt::Display for VfioError {
             VfioError::VfioDeviceSetIrq => write!(f, "failed to set vfio deviece irq"),
             VfioError::ReadLink(e) => write!(f, "failed to read link from path: {}", e),
             VfioError::ParseInt(e) => write!(f, "failed to parse integer: {}", e),
+            VfioError::VfioDeviceReset(e) => write!(f, "failed to reset vfio device: {}", e),
+            VfioError::VfioDevicePciHotReset(e) => {
+                write!(f, "failed to reset vfio device's bus: {}", e)
+            }
         }
     }
 }
@@ -580,6 +586,19 @@ impl ExternalDmaMapping for VfioDmaMapping {
     }
 }
 
+/// How a VFIO device gets reset.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VfioResetMethod {
+    /// Function level reset, carried out by the host kernel with FLR when
+    /// the device supports it, or another method only resetting the
+    /// function otherwise.
+    Flr,
+    /// Secondary bus reset of the bus the device sits on.
+    Bus,
+    /// The device is never reset.
+    None,
+}
+
 /// Vfio device for exposing regions which could be read/write to kernel vfio device.
 pub struct VfioDevice {
     device: File,
@@ -631,6 +650,54 @@ impl VfioDevice {
         }
     }
 
+    /// Resets the device with the given method.
+    ///
+    /// # Arguments
+    ///
+    /// * `method` - Reset the function alone, or the whole bus it sits on.
+    pub fn reset_with(&self, method: VfioResetMethod) -> Result<()> {
+        match method {
+            VfioResetMethod::Flr => {
+                if self.flags & VFIO_DEVICE_FLAGS_RESET == 0 {
+                    return Err(VfioError::VfioDeviceReset(io::Error::from_raw_os_error(
+                        libc::ENOTTY,
+                    )));
+                }
+
+                // Safe as we are the owner of self.
+                let ret = unsafe { ioctl(self, VFIO_DEVICE_RESET()) };
+                if ret < 0 {
+                    return Err(VfioError::VfioDeviceReset(io::Error::last_os_error()));
+                }
+            }
+            VfioResetMethod::Bus => {
+                // Every device on the bus must belong to a group the caller
+                // owns, which only holds if the bus is not shared with
+                // devices assigned elsewhere.
+                let mut hot_reset = vec_with_array_field::<vfio_pci_hot_reset, i32>(1);
+                hot_reset[0].argsz =
+                    (mem::size_of::<vfio_pci_hot_reset>() + mem::size_of::<i32>()) as u32;
+                hot_reset[0].flags = 0;
+                hot_reset[0].count = 1;
+                // Safe as the vector was allocated with room for one group.
+                unsafe {
+                    hot_reset[0].group_fds.as_mut_slice(1)[0] = self.group.as_raw_fd();
+                }
+
+                // Safe as we are the owner of self and hot_reset which are
+                // valid value.
+                let ret =
+                    unsafe { ioctl_with_ref(self, VFIO_DEVICE_PCI_HOT_RESET(), &hot_reset[0]) };
+                if ret < 0 {
+                    return Err(VfioError::VfioDevicePciHotReset(io::Error::last_os_error()));
+                }
+            }
+            VfioResetMethod::None => {}
+        }
+
+        Ok(())
+    }
+
     /// Enables a VFIO device IRQs.
     /// This maps a vector of EventFds to all VFIO managed interrupts. In other words, this
     /// tells VFIO which EventFd to write into whenever one of the device interrupt vector
@@ -7,7 +7,7 @@ extern crate devices;
 extern crate pci;
 extern crate vm_allocator;
 
-use crate::vfio_device::VfioDevice;
+use crate::vfio_device::{VfioContainer, VfioDevice, VfioError, VfioResetMethod};
 use byteorder::{ByteOrder, LittleEndian};
 use devices::BusDevice;
 use kvm_bindings::kvm_userspace_memory_region;
@@ -20,7 +20,7 @@ use pci::{
 use std::any::Any;
 use std::os::unix::io::AsRawFd;
 use std::ptr::null_mut;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 use std::{fmt, io, result};
 use vfio_bindings::bindings::vfio::*;
 use vm_allocator::SystemAllocator;
@@ -43,6 +43,14 @@ pub enum VfioPciError {
     MsixNotConfigured,
     UpdateMsiEventFd,
     UpdateMsixEventFd,
+    DisableMsi(VfioError),
+    DisableMsix(VfioError),
+    UnmapRegionGuest(kvm_ioctls::Error),
+    UnmapRegionHost(io::Error),
+    DmaUnmap(VfioError),
+    Reset(VfioError),
+    P2pDmaMap(VfioError),
+    P2pDmaUnmap(VfioError),
 }
 pub type Result<T> = std::result::Result<T, VfioPciError>;
 
@@ -64,6 +72,28 @@ impl fmt::Display for VfioPciError {
             VfioPciError::MsixNotConfigured => write!(f, "MSI-X interrupt not yet configured"),
             VfioPciError::UpdateMsiEventFd => write!(f, "failed to update MSI eventfd"),
             VfioPciError::UpdateMsixEventFd => write!(f, "failed to update MSI-X eventfd"),
+            VfioPciError::DisableMsi(e) => write!(f, "failed to disable MSI: {}", e),
+            VfioPciError::DisableMsix(e) => write!(f, "failed to disable MSI-X: {}", e),
+            VfioPciError::UnmapRegionGuest(e) => {
+                write!(f, "failed to unmap VFIO PCI region from guest: {}", e)
+            }
+            VfioPciError::UnmapRegionHost(e) => {
+                write!(f, "failed to unmap VFIO PCI region from VMM: {}", e)
+            }
+            VfioPciError::DmaUnmap(e) => {
+                write!(f, "failed to remove guest memory from IOMMU table: {}", e)
+            }
+            VfioPciError::Reset(e) => write!(f, "failed to reset VFIO PCI device: {}", e),
+            VfioPciError::P2pDmaMap(e) => {
+                write!(f, "failed to map BAR into peer device IOMMU table: {}", e)
+            }
+            VfioPciError::P2pDmaUnmap(e) => {
+                write!(
+                    f,
+                    "failed to remove BAR from peer device IOMMU table: {}",
+                    e
+                )
+            }
         }
     }
 }
@@ -261,6 +291,12 @@ impl VfioPciConfig {
         u32::from_le_bytes(data)
     }
 
+    fn write_config_word(&self, buf: u16, offset: u32) {
+        let data: [u8; 2] = buf.to_le_bytes();
+        self.device
+            .region_write(VFIO_PCI_CONFIG_REGION_INDEX, &data, offset.into())
+    }
+
     fn write_config_dword(&self, buf: u32, offset: u32) {
         let data: [u8; 4] = buf.to_le_bytes();
         self.device
@@ -271,6 +307,27 @@ impl VfioPciConfig {
 /// VfioPciDevice represents a VFIO PCI device.
 /// This structure implements the BusDevice and PciDevice traits.
 ///
+/// When, and how, a VFIO PCI device gets reset. Some devices don't recover
+/// from some reset methods, or from being reset at all.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VfioResetPolicy {
+    pub method: VfioResetMethod,
+    /// Reset the device before the guest gets it.
+    pub on_attach: bool,
+    /// Reset the device once the guest is done with it.
+    pub on_release: bool,
+}
+
+impl Default for VfioResetPolicy {
+    fn default() -> Self {
+        VfioResetPolicy {
+            method: VfioResetMethod::Flr,
+            on_attach: true,
+            on_release: true,
+        }
+    }
+}
+
 /// A VfioPciDevice is bound to a VfioDevice and is also a PCI device.
 /// The VMM creates a VfioDevice, then assigns it to a VfioPciDevice,
 /// which then gets added to the PCI bus.
@@ -281,6 +338,9 @@ pub struct VfioPciDevice {
     configuration: PciConfiguration,
     mmio_regions: Vec<MmioRegion>,
     interrupt: Interrupt,
+    reset_policy: VfioResetPolicy,
+    p2p_peers: Vec<Weak<VfioContainer>>,
+    released: bool,
 }
 
 impl VfioPciDevice {
@@ -289,9 +349,14 @@ impl VfioPciDevice {
         vm_fd: &Arc<VmFd>,
         device: VfioDevice,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+        reset_policy: VfioResetPolicy,
     ) -> Result<Self> {
         let device = Arc::new(device);
-        device.reset();
+        if reset_policy.on_attach {
+            device
+                .reset_with(reset_policy.method)
+                .map_err(VfioPciError::Reset)?;
+        }
 
         let configuration = PciConfiguration::new(
             0,
@@ -317,6 +382,9 @@ impl VfioPciDevice {
                 msi: None,
                 msix: None,
             },
+            reset_policy,
+            p2p_peers: Vec::new(),
+            released: false,
         };
 
         vfio_pci_device.parse_capabilities(interrupt_manager);
@@ -574,41 +642,227 @@ impl VfioPciDevice {
         Ok(new_mem_slot)
     }
 
-    pub fn unmap_mmio_regions(&mut self) {
-        for region in self.mmio_regions.iter() {
-            if let (Some(addr), Some(size)) = (region.host_addr, region.mmap_size) {
+    pub fn container(&self) -> Arc<VfioContainer> {
+        self.device.get_container()
+    }
+
+    // Guest address, size and host address of each mapped BAR.
+    fn bar_mappings(&self) -> Vec<(u64, u64, u64)> {
+        self.mmio_regions
+            .iter()
+            .filter_map(|region| {
+                if let (Some(host_addr), Some(size)) = (region.host_addr, region.mmap_size) {
+                    let (mmap_offset, _) = self.device.get_region_mmap(region.index);
+                    Some((
+                        region.start.raw_value() + mmap_offset,
+                        size as u64,
+                        host_addr,
+                    ))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Let the device behind `container` DMA to this device mapped BARs,
+    /// by mapping them in its IOMMU table at their guest address. The
+    /// mappings follow the BARs when the guest moves them.
+    pub fn add_p2p_peer(&mut self, container: Arc<VfioContainer>) -> Result<()> {
+        for (iova, size, host_addr) in self.bar_mappings() {
+            container
+                .vfio_dma_map(iova, size, host_addr)
+                .map_err(VfioPciError::P2pDmaMap)?;
+        }
+        self.p2p_peers.push(Arc::downgrade(&container));
+
+        Ok(())
+    }
+
+    fn remove_p2p_peers(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        let bar_mappings = self.bar_mappings();
+
+        // A peer which is already gone doesn't need any cleanup.
+        for peer in self.p2p_peers.drain(..).filter_map(|peer| peer.upgrade()) {
+            for (iova, size, _) in bar_mappings.iter() {
+                if let Err(e) = peer.vfio_dma_unmap(*iova, *size) {
+                    if result.is_ok() {
+                        result = Err(VfioPciError::P2pDmaUnmap(e));
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Remove the mappings of the device regions, from the guest first so
+    /// that it can't access them anymore, then from the VMM.
+    pub fn unmap_mmio_regions(&mut self) -> Result<()> {
+        let mut result = Ok(());
+
+        for region in self.mmio_regions.iter_mut() {
+            if let (Some(slot), Some(addr), Some(size)) =
+                (region.mem_slot, region.host_addr, region.mmap_size)
+            {
+                let mem_region = kvm_userspace_memory_region {
+                    slot,
+                    guest_phys_addr: 0,
+                    memory_size: 0,
+                    userspace_addr: 0,
+                    flags: 0,
+                };
+
+                // Safe because a zero sized region deletes the slot.
+                if let Err(e) = unsafe { self.vm_fd.set_user_memory_region(mem_region) } {
+                    // The guest may still access the mapping, which must
+                    // then be left in place.
+                    if result.is_ok() {
+                        result = Err(VfioPciError::UnmapRegionGuest(e));
+                    }
+                    continue;
+                }
+
                 let ret = unsafe { libc::munmap(addr as *mut libc::c_void, size) };
-                if ret != 0 {
-                    error!(
-                        "Could not unmap regions, error:{}",
-                        io::Error::last_os_error()
-                    );
+                if ret != 0 && result.is_ok() {
+                    result = Err(VfioPciError::UnmapRegionHost(io::Error::last_os_error()));
                 }
+
+                region.mem_slot = None;
+                region.host_addr = None;
+                region.mmap_size = None;
             }
         }
+
+        result
+    }
+
+    /// Quiesce the device and tear down everything that lets it access the
+    /// guest, or the guest access it, following `RELEASE_ORDER`. All steps
+    /// are attempted, and the first failure is returned.
+    ///
+    /// There is no eject handshake with the guest driver, since devices
+    /// can't be hot-unplugged: the device must only be released once the
+    /// guest can't reach it anymore, that is once the vCPUs are stopped.
+    /// It is released by the device manager on shutdown, or when dropped.
+    pub fn release(&mut self) -> Result<()> {
+        if self.released {
+            return Ok(());
+        }
+        self.released = true;
+
+        release_device(self)
     }
 }
 
-impl Drop for VfioPciDevice {
-    fn drop(&mut self) {
-        self.unmap_mmio_regions();
+// The steps releasing a device, which release_device() runs in order.
+trait ReleaseSteps {
+    fn stop_dma(&mut self) -> Result<()>;
+    fn disable_interrupts(&mut self) -> Result<()>;
+    fn remove_peers(&mut self) -> Result<()>;
+    fn unmap_regions(&mut self) -> Result<()>;
+    fn unmap_dma(&mut self) -> Result<()>;
+}
+
+impl ReleaseSteps for VfioPciDevice {
+    fn stop_dma(&mut self) -> Result<()> {
+        // Stop any DMA by clearing Bus Master Enable, and drop any ongoing
+        // transaction by resetting the device, unless the reset policy says
+        // otherwise.
+        let command = self.vfio_pci_configuration.read_config_word(PCI_COMMAND);
+        self.vfio_pci_configuration
+            .write_config_word(command & !PCI_COMMAND_BUS_MASTER, PCI_COMMAND);
+        if self.reset_policy.on_release {
+            self.device
+                .reset_with(self.reset_policy.method)
+                .map_err(VfioPciError::Reset)
+        } else {
+            Ok(())
+        }
+    }
 
+    fn disable_interrupts(&mut self) -> Result<()> {
+        let mut msix_result = Ok(());
         if let Some(msix) = &self.interrupt.msix {
-            if msix.bar.enabled() && self.device.disable_msix().is_err() {
-                error!("Could not disable MSI-X");
+            if msix.bar.enabled() {
+                msix_result = self
+                    .device
+                    .disable_msix()
+                    .map_err(VfioPciError::DisableMsix);
             }
         }
 
+        let mut msi_result = Ok(());
         if let Some(msi) = &self.interrupt.msi {
-            if msi.cfg.enabled() && self.device.disable_msi().is_err() {
-                error!("Could not disable MSI");
+            if msi.cfg.enabled() {
+                msi_result = self.device.disable_msi().map_err(VfioPciError::DisableMsi);
             }
         }
 
-        if self.device.unset_dma_map().is_err() {
-            error!("failed to remove all guest memory regions from iommu table");
+        msix_result.and(msi_result)
+    }
+
+    fn remove_peers(&mut self) -> Result<()> {
+        self.remove_p2p_peers()
+    }
+
+    fn unmap_regions(&mut self) -> Result<()> {
+        self.unmap_mmio_regions()
+    }
+
+    fn unmap_dma(&mut self) -> Result<()> {
+        self.device.unset_dma_map().map_err(VfioPciError::DmaUnmap)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ReleaseStep {
+    StopDma,
+    DisableInterrupts,
+    RemoveP2pPeers,
+    UnmapRegions,
+    UnmapDma,
+}
+
+// DMA is stopped first, and the guest memory is removed from the IOMMU table
+// last, so that the device never writes to memory which could be reused.
+const RELEASE_ORDER: [ReleaseStep; 5] = [
+    ReleaseStep::StopDma,
+    ReleaseStep::DisableInterrupts,
+    ReleaseStep::RemoveP2pPeers,
+    ReleaseStep::UnmapRegions,
+    ReleaseStep::UnmapDma,
+];
+
+// Run every release step in order, even after a failure, and return the first
+// error.
+fn release_device<D: ReleaseSteps>(device: &mut D) -> Result<()> {
+    let mut result = Ok(());
+    for s in RELEASE_ORDER.iter() {
+        let step_result = match s {
+            ReleaseStep::StopDma => device.stop_dma(),
+            ReleaseStep::DisableInterrupts => device.disable_interrupts(),
+            ReleaseStep::RemoveP2pPeers => device.remove_peers(),
+            ReleaseStep::UnmapRegions => device.unmap_regions(),
+            ReleaseStep::UnmapDma => device.unmap_dma(),
+        };
+        if let Err(e) = step_result {
+            error!("Failed releasing VFIO device ({:?}): {}", s, e);
+            if result.is_ok() {
+                result = Err(e);
+            }
         }
     }
+
+    result
+}
+
+impl Drop for VfioPciDevice {
+    fn drop(&mut self) {
+        // Errors have already been logged.
+        let _ = self.release();
+    }
 }
 
 impl BusDevice for VfioPciDevice {
@@ -631,6 +885,10 @@ const PCI_CONFIG_IO_BAR: u32 = 0x1;
 const PCI_CONFIG_MEMORY_BAR_FLAG_MASK: u32 = 0xf;
 // 64-bit memory bar flag.
 const PCI_CONFIG_MEMORY_BAR_64BIT: u32 = 0x4;
+
+// PCI command register offset, and its Bus Master Enable bit.
+const PCI_COMMAND: u32 = 0x4;
+const PCI_COMMAND_BUS_MASTER: u16 = 0x4;
 // PCI config register size (4 bytes).
 const PCI_CONFIG_REGISTER_SIZE: usize = 4;
 // Number of BARs for a PCI device
@@ -945,6 +1203,15 @@ impl PciDevice for VfioPciDevice {
                     if let Some(host_addr) = region.host_addr {
                         let (mmap_offset, mmap_size) = self.device.get_region_mmap(region.index);
 
+                        // Move the BAR in the peer devices IOMMU tables.
+                        for peer in self.p2p_peers.iter().filter_map(|peer| peer.upgrade()) {
+                            peer.vfio_dma_unmap(old_base + mmap_offset, mmap_size)
+                                .and_then(|_| {
+                                    peer.vfio_dma_map(new_base + mmap_offset, mmap_size, host_addr)
+                                })
+                                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                        }
+
                         // Remove old region from KVM
                         let old_mem_region = kvm_userspace_memory_region {
                             slot: mem_slot,
@@ -986,3 +1253,94 @@ impl PciDevice for VfioPciDevice {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A device recording the release steps run on it, two of them failing
+    // if asked to.
+    #[derive(Default)]
+    struct TestDevice {
+        steps: Vec<&'static str>,
+        failing: bool,
+    }
+
+    impl TestDevice {
+        fn step(&mut self, name: &'static str) -> Result<()> {
+            self.steps.push(name);
+            match name {
+                "disable_interrupts" if self.failing => Err(VfioPciError::MsixNotConfigured),
+                "unmap_regions" if self.failing => Err(VfioPciError::MsiNotConfigured),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl ReleaseSteps for TestDevice {
+        fn stop_dma(&mut self) -> Result<()> {
+            self.step("stop_dma")
+        }
+
+        fn disable_interrupts(&mut self) -> Result<()> {
+            self.step("disable_interrupts")
+        }
+
+        fn remove_peers(&mut self) -> Result<()> {
+            self.step("remove_peers")
+        }
+
+        fn unmap_regions(&mut self) -> Result<()> {
+            self.step("unmap_regions")
+        }
+
+        fn unmap_dma(&mut self) -> Result<()> {
+            self.step("unmap_dma")
+        }
+    }
+
+    #[test]
+    fn test_release_order() {
+        let mut device = TestDevice::default();
+        assert!(release_device(&mut device).is_ok());
+
+        // DMA is stopped before anything else, and the guest memory only
+        // leaves the IOMMU table once nothing can reach it anymore.
+        assert_eq!(
+            device.steps,
+            vec![
+                "stop_dma",
+                "disable_interrupts",
+                "remove_peers",
+                "unmap_regions",
+                "unmap_dma",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_release_keeps_first_error() {
+        let mut device = TestDevice {
+            failing: true,
+            ..Default::default()
+        };
+        let result = release_device(&mut device);
+
+        // A failing step doesn't prevent the following ones from running,
+        // in particular removing the guest memory from the IOMMU table.
+        assert_eq!(
+            device.steps,
+            vec![
+                "stop_dma",
+                "disable_interrupts",
+                "remove_peers",
+                "unmap_regions",
+                "unmap_dma",
+            ]
+        );
+        match result {
+            Err(VfioPciError::MsixNotConfigured) => {}
+            _ => panic!("expected the first error to be returned"),
+        }
+    }
+}
@@ -16,7 +16,7 @@ extern crate vm_virtio;
 use epoll;
 use libc::EFD_NONBLOCK;
 use log::*;
-use qcow::{self, ImageType, QcowFile};
+use qcow::{self, ImageType, QcowFile, VhdFile, VhdxFile};
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::Read;
@@ -38,6 +38,7 @@ use virtio_bindings::bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::{Bytes, GuestMemoryError, GuestMemoryMmap};
 use vm_virtio::block::{build_disk_image_id, Request};
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::write_zeroes::PunchHole;
 
 const QUEUE_SIZE: usize = 1024;
 const SECTOR_SHIFT: u8 = 9;
@@ -48,8 +49,8 @@ const BLK_SIZE: u32 = 512;
 // and the overhead of the emulation layer.
 const POLL_QUEUE_US: u128 = 50;
 
-trait DiskFile: Read + Seek + Write + Send + Sync {}
-impl<D: Read + Seek + Write + Send + Sync> DiskFile for D {}
+trait DiskFile: Read + Seek + Write + PunchHole + Send + Sync {}
+impl<D: Read + Seek + Write + PunchHole + Send + Sync> DiskFile for D {}
 
 pub type Result<T> = std::result::Result<T, Error>;
 pub type VhostUserBackendResult<T> = std::result::Result<T, std::io::Error>;
@@ -131,6 +132,8 @@ impl VhostUserBlkBackend {
         let mut image = match image_type {
             ImageType::Raw => Box::new(raw_img) as Box<dyn DiskFile>,
             ImageType::Qcow2 => Box::new(QcowFile::from(raw_img).unwrap()) as Box<dyn DiskFile>,
+            ImageType::Vhd => Box::new(VhdFile::from(raw_img).unwrap()) as Box<dyn DiskFile>,
+            ImageType::Vhdx => Box::new(VhdxFile::from(raw_img).unwrap()) as Box<dyn DiskFile>,
         };
 
         let nsectors = (image.seek(SeekFrom::End(0)).unwrap() as u64) / SECTOR_SIZE;
@@ -173,7 +176,7 @@ impl VhostUserBlkBackend {
                 Ok(request) => {
                     debug!("element is a valid request");
                     let status = match request.execute(
-                        &mut self.disk_image,
+                        self.disk_image.as_mut(),
                         self.disk_nsectors,
                         mem,
                         &self.disk_image_id,
@@ -0,0 +1,614 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A virtio-fs device serving a host directory from the VMM process itself,
+//! through `PassthroughFs`, for setups where running a separate virtiofsd
+//! isn't worth it. The DAX window, if any, is mapped directly by the device.
+//!
+//! The file system isn't sandboxed as virtiofsd is, hence it is confined to
+//! the shared directory by resolving every path beneath its parent directory
+//! without following any symlink, and the guest can't create device nodes.
+
+use crate::descriptor_utils::{Reader, Writer};
+use crate::fs_cache_req_handler::FsCacheReqHandler;
+use crate::fuse::{self, RemovemappingOne};
+use crate::passthrough::{self, PassthroughFs};
+use crate::server::Server;
+use libc::EFD_NONBLOCK;
+use std::cmp;
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_memory::{ByteValued, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
+use vm_virtio::{
+    wait_for_resume, ActivateError, ActivateResult, Error as DeviceError, Queue, VirtioDevice,
+    VirtioDeviceType, VirtioInterrupt, VirtioInterruptType, VirtioSharedMemoryList,
+    VIRTIO_F_VERSION_1,
+};
+use vmm_sys_util::eventfd::EventFd;
+
+// The high priority queue comes first, followed by the request queues.
+const NUM_QUEUE_OFFSET: usize = 1;
+
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+struct VirtioFsConfig {
+    tag: [u8; 36],
+    num_request_queues: u32,
+}
+
+impl Default for VirtioFsConfig {
+    fn default() -> Self {
+        VirtioFsConfig {
+            tag: [0; 36],
+            num_request_queues: 0,
+        }
+    }
+}
+
+unsafe impl ByteValued for VirtioFsConfig {}
+
+// Maps the files content into the DAX window on behalf of the file system.
+struct CacheHandler {
+    cache_size: u64,
+    mmap_cache_addr: u64,
+}
+
+impl CacheHandler {
+    // Returns the host address of a range of the DAX window, making sure the
+    // guest can't reach beyond it, as the mappings are done with MAP_FIXED.
+    fn cache_addr(&self, offset: u64, len: u64) -> io::Result<u64> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.cache_size => Ok(self.mmap_cache_addr + offset),
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+}
+
+impl FsCacheReqHandler for CacheHandler {
+    fn map(
+        &mut self,
+        foffset: u64,
+        moffset: u64,
+        len: u64,
+        flags: u64,
+        fd: RawFd,
+    ) -> io::Result<()> {
+        let addr = self.cache_addr(moffset, len)?;
+        let prot = if (flags & fuse::SetupmappingFlags::WRITE.bits()) != 0 {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+
+        // Safe because the range is within the DAX window, which is only
+        // accessed by the guest.
+        let ret = unsafe {
+            libc::mmap(
+                addr as *mut libc::c_void,
+                len as usize,
+                prot,
+                libc::MAP_SHARED | libc::MAP_FIXED,
+                fd,
+                foffset as libc::off_t,
+            )
+        };
+        if ret == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    fn unmap(&mut self, requests: Vec<RemovemappingOne>) -> io::Result<()> {
+        for req in requests {
+            // The whole mapping is removed with a length of all ones.
+            let len = if req.len == 0xffff_ffff_ffff_ffff {
+                self.cache_size.saturating_sub(req.moffset)
+            } else {
+                req.len
+            };
+
+            let addr = self.cache_addr(req.moffset, len)?;
+            // Safe because the range is within the DAX window, which is only
+            // accessed by the guest.
+            let ret = unsafe {
+                libc::mmap(
+                    addr as *mut libc::c_void,
+                    len as usize,
+                    libc::PROT_NONE,
+                    libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_FIXED,
+                    -1,
+                    0 as libc::off_t,
+                )
+            };
+            if ret == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct FsEpollHandler {
+    queues: Vec<Queue>,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    server: Arc<Server<PassthroughFs>>,
+    cache_handler: Option<CacheHandler>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    queue_evts: Vec<EventFd>,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+}
+
+impl FsEpollHandler {
+    fn process_queue(&mut self, queue_index: usize) -> bool {
+        let queue = &mut self.queues[queue_index];
+        let server = &self.server;
+        let cache_handler = &mut self.cache_handler;
+
+        let mut used_desc_heads = Vec::new();
+        let mem = self.mem.memory();
+        for avail_desc in queue.iter(&mem) {
+            let head_index = avail_desc.index;
+            let chain = Reader::new(&mem, avail_desc.clone())
+                .and_then(|reader| Ok((reader, Writer::new(&mem, avail_desc.clone())?)));
+            let len = match chain {
+                Ok((reader, writer)) => {
+                    match server.handle_message(reader, writer, cache_handler.as_mut()) {
+                        Ok(len) => len as u32,
+                        Err(e) => {
+                            error!("Failed to process virtio-fs request: {}", e);
+                            0
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Invalid virtio-fs descriptor chain: {}", e);
+                    0
+                }
+            };
+
+            used_desc_heads.push((head_index, len));
+        }
+
+        for &(desc_index, len) in &used_desc_heads {
+            queue.add_used(&mem, desc_index, len);
+        }
+        !used_desc_heads.is_empty()
+    }
+
+    fn signal_used_queue(&self, queue_index: usize) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), DeviceError> {
+        // The queue events use their queue index as epoll token, followed by
+        // the kill and pause events.
+        let kill_event = self.queue_evts.len() as u64;
+        let pause_event = kill_event + 1;
+
+        let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
+        let mut fds: Vec<(RawFd, u64)> = self
+            .queue_evts
+            .iter()
+            .enumerate()
+            .map(|(i, evt)| (evt.as_raw_fd(), i as u64))
+            .collect();
+        fds.push((self.kill_evt.as_raw_fd(), kill_event));
+        fds.push((self.pause_evt.as_raw_fd(), pause_event));
+        for (fd, token) in fds {
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                fd,
+                epoll::Event::new(epoll::Events::EPOLLIN, token),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+
+        const EPOLL_EVENTS_LEN: usize = 100;
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+
+        'epoll: loop {
+            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+                Ok(res) => res,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        // The epoll wait can be interrupted before any of the
+                        // requested events occurred, which isn't an error.
+                        continue;
+                    }
+                    return Err(DeviceError::EpollWait(e));
+                }
+            };
+
+            for event in events.iter().take(num_events) {
+                let ev_type = event.data;
+
+                if ev_type == kill_event {
+                    debug!("KILL_EVENT received, stopping epoll loop");
+                    break 'epoll;
+                } else if ev_type == pause_event {
+                    debug!("PAUSE_EVENT received, pausing virtio-fs epoll loop");
+                    wait_for_resume(&paused, &paused_sync);
+                } else {
+                    let queue_index = ev_type as usize;
+                    if let Err(e) = self.queue_evts[queue_index].read() {
+                        error!("Failed to get queue event: {:?}", e);
+                        break 'epoll;
+                    } else if self.process_queue(queue_index) {
+                        if let Err(e) = self.signal_used_queue(queue_index) {
+                            error!("Failed to signal used queue: {:?}", e);
+                            break 'epoll;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Virtio device exposing a host directory to the guest through virtio-fs,
+/// without any vhost-user backend.
+pub struct Fs {
+    server: Arc<Server<PassthroughFs>>,
+    queue_sizes: Vec<u16>,
+    avail_features: u64,
+    acked_features: u64,
+    config: VirtioFsConfig,
+    cache: Option<(VirtioSharedMemoryList, u64)>,
+    kill_evt: Option<EventFd>,
+    pause_evt: Option<EventFd>,
+    queue_evts: Option<Vec<EventFd>>,
+    interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
+    epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
+    paused: Arc<AtomicBool>,
+    paused_sync: Option<Arc<Barrier>>,
+}
+
+impl Fs {
+    /// Create a new virtio-fs device sharing the `shared_dir` host directory.
+    pub fn new(
+        shared_dir: &str,
+        tag: &str,
+        req_num_queues: usize,
+        queue_size: u16,
+        cache: Option<(VirtioSharedMemoryList, u64)>,
+    ) -> io::Result<Fs> {
+        if tag.len() > 36 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        let fs_cfg = passthrough::Config {
+            root_dir: shared_dir.to_string(),
+            // The umask is shared with the whole VMM.
+            clear_umask: false,
+            sandboxed: false,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::new(fs_cfg)?;
+
+        let mut config = VirtioFsConfig::default();
+        config.tag[..tag.len()].copy_from_slice(tag.as_bytes());
+        config.num_request_queues = req_num_queues as u32;
+
+        Ok(Fs {
+            server: Arc::new(Server::new(fs)),
+            queue_sizes: vec![queue_size; NUM_QUEUE_OFFSET + req_num_queues],
+            avail_features: 1u64 << VIRTIO_F_VERSION_1,
+            acked_features: 0,
+            config,
+            cache,
+            kill_evt: None,
+            pause_evt: None,
+            queue_evts: None,
+            interrupt_cb: None,
+            epoll_threads: None,
+            paused: Arc::new(AtomicBool::new(false)),
+            paused_sync: None,
+        })
+    }
+}
+
+impl Drop for Fs {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for Fs {
+    fn device_type(&self) -> u32 {
+        VirtioDeviceType::TYPE_FS as u32
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        self.queue_sizes.as_slice()
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("fs: virtio-fs got unknown feature ack: {:x}", v);
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_slice = self.config.as_slice();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {
+        warn!("fs: virtio-fs configuration is read-only");
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != self.queue_sizes.len() || queue_evts.len() != self.queue_sizes.len() {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                self.queue_sizes.len(),
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        let (self_kill_evt, kill_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating kill EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.kill_evt = Some(self_kill_evt);
+
+        let (self_pause_evt, pause_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating pause EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.pause_evt = Some(self_pause_evt);
+
+        // Save the interrupt EventFD as we need to return it on reset
+        // but clone it to pass into the thread.
+        self.interrupt_cb = Some(interrupt_cb.clone());
+
+        let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
+        for queue_evt in queue_evts.iter() {
+            // Save the queue EventFD as we need to return it on reset
+            // but clone it to pass into the thread.
+            tmp_queue_evts.push(queue_evt.try_clone().map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?);
+        }
+        self.queue_evts = Some(tmp_queue_evts);
+
+        let mut handler = FsEpollHandler {
+            queues,
+            mem,
+            server: self.server.clone(),
+            cache_handler: self.cache.as_ref().map(|cache| CacheHandler {
+                cache_size: cache.0.len,
+                mmap_cache_addr: cache.1,
+            }),
+            interrupt_cb,
+            queue_evts,
+            kill_evt,
+            pause_evt,
+        };
+
+        let paused = self.paused.clone();
+        let paused_sync = Arc::new(Barrier::new(2));
+        let paused_sync_clone = paused_sync.clone();
+        let mut epoll_threads = Vec::new();
+        thread::Builder::new()
+            .name("virtio_fs".to_string())
+            .spawn(move || handler.run(paused, paused_sync_clone))
+            .map(|thread| epoll_threads.push(thread))
+            .map_err(|e| {
+                error!("failed to clone the virtio-fs epoll thread: {}", e);
+                ActivateError::BadActivate
+            })?;
+
+        self.epoll_threads = Some(epoll_threads);
+        self.paused_sync = Some(paused_sync);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+        // We first must resume the virtio thread if it was paused.
+        if self.pause_evt.take().is_some() {
+            self.resume().ok()?;
+        }
+
+        // Then kill it.
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        // Return the interrupt and queue EventFDs
+        Some((
+            self.interrupt_cb.take().unwrap(),
+            self.queue_evts.take().unwrap(),
+        ))
+    }
+
+    fn get_shm_regions(&self) -> Option<VirtioSharedMemoryList> {
+        self.cache.as_ref().map(|cache| cache.0.clone())
+    }
+}
+
+impl Pausable for Fs {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        debug!("Pausing virtio-fs");
+        self.paused.store(true, Ordering::SeqCst);
+        if let Some(pause_evt) = &self.pause_evt {
+            pause_evt
+                .write(1)
+                .map_err(|e| MigratableError::Pause(e.into()))?;
+
+            // Wait for the epoll thread to acknowledge the pause.
+            if let Some(paused_sync) = &self.paused_sync {
+                paused_sync.wait();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        debug!("Resuming virtio-fs");
+        self.paused.store(false, Ordering::SeqCst);
+        // Drain the pause event before waking the epoll thread up, for it
+        // not to see it again.
+        if let Some(pause_evt) = &self.pause_evt {
+            let _ = pause_evt.read();
+        }
+        if let Some(epoll_threads) = &self.epoll_threads {
+            for thread in epoll_threads.iter() {
+                thread.thread().unpark();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Snapshotable for Fs {
+    fn id(&self) -> String {
+        "vhost-user-fs".to_string()
+    }
+}
+impl Migratable for Fs {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shared_dir() -> tempfile::TempDir {
+        tempfile::tempdir().unwrap()
+    }
+
+    #[test]
+    fn test_fs_config() {
+        let dir = shared_dir();
+        let fs = Fs::new(dir.path().to_str().unwrap(), "myfs", 2, 1024, None).unwrap();
+
+        assert_eq!(fs.device_type(), VirtioDeviceType::TYPE_FS as u32);
+        assert_eq!(fs.queue_max_sizes(), &[1024, 1024, 1024]);
+        assert!(fs.get_shm_regions().is_none());
+
+        let mut tag = [0xffu8; 36];
+        fs.read_config(0, &mut tag);
+        assert_eq!(&tag[..4], b"myfs");
+        assert!(tag[4..].iter().all(|b| *b == 0));
+
+        let mut num_request_queues = [0u8; 4];
+        fs.read_config(36, &mut num_request_queues);
+        assert_eq!(u32::from_le_bytes(num_request_queues), 2);
+
+        // Reads beyond the configuration space are ignored.
+        let mut data = [0xffu8; 4];
+        fs.read_config(40, &mut data);
+        assert_eq!(data, [0xff; 4]);
+    }
+
+    #[test]
+    fn test_fs_tag_too_long() {
+        let dir = shared_dir();
+        let tag = "t".repeat(37);
+        assert!(Fs::new(dir.path().to_str().unwrap(), &tag, 1, 1024, None).is_err());
+        let tag = "t".repeat(36);
+        assert!(Fs::new(dir.path().to_str().unwrap(), &tag, 1, 1024, None).is_ok());
+    }
+
+    #[test]
+    fn test_fs_ack_features() {
+        let dir = shared_dir();
+        let mut fs = Fs::new(dir.path().to_str().unwrap(), "myfs", 1, 1024, None).unwrap();
+
+        assert_eq!(fs.features(), 1u64 << VIRTIO_F_VERSION_1);
+        fs.ack_features((1u64 << VIRTIO_F_VERSION_1) | (1u64 << 5));
+        assert_eq!(fs.acked_features, 1u64 << VIRTIO_F_VERSION_1);
+    }
+
+    #[test]
+    fn test_cache_addr() {
+        let handler = CacheHandler {
+            cache_size: 0x10_0000,
+            mmap_cache_addr: 0x7f00_0000_0000,
+        };
+
+        assert_eq!(handler.cache_addr(0, 0x10_0000).unwrap(), 0x7f00_0000_0000);
+        assert_eq!(
+            handler.cache_addr(0x8_0000, 0x1000).unwrap(),
+            0x7f00_0008_0000
+        );
+        // The guest can't map anything beyond the DAX window.
+        assert!(handler.cache_addr(0x8_0000, 0x8_0001).is_err());
+        assert!(handler.cache_addr(0x10_0000, 1).is_err());
+        assert!(handler.cache_addr(u64::max_value(), 2).is_err());
+    }
+
+    #[test]
+    fn test_cache_unmap_out_of_window() {
+        let mut handler = CacheHandler {
+            cache_size: 0x10_0000,
+            mmap_cache_addr: 0x7f00_0000_0000,
+        };
+
+        // Nothing is unmapped when a range reaches beyond the window.
+        let requests = vec![RemovemappingOne {
+            moffset: 0x10_0000,
+            len: 0x1000,
+        }];
+        assert!(handler.unmap(requests).is_err());
+    }
+}
@@ -6,6 +6,7 @@
 extern crate log;
 
 pub mod descriptor_utils;
+pub mod device;
 pub mod file_traits;
 pub mod filesystem;
 pub mod fs_cache_req_handler;
@@ -30,6 +30,13 @@ const PARENT_DIR_CSTR: &[u8] = b"..\0";
 const EMPTY_CSTR: &[u8] = b"\0";
 const PROC_CSTR: &[u8] = b"/proc\0";
 
+// The openat2() system call isn't exposed by libc yet, it has the same number
+// on all architectures.
+const SYS_OPENAT2: libc::c_long = 437;
+const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+const RESOLVE_NO_SYMLINKS: u64 = 0x04;
+const RESOLVE_BENEATH: u64 = 0x08;
+
 type Inode = u64;
 type Handle = u64;
 
@@ -61,6 +68,14 @@ struct LinuxDirent64 {
 }
 unsafe impl ByteValued for LinuxDirent64 {}
 
+#[repr(C)]
+#[derive(Default)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
 macro_rules! scoped_cred {
     ($name:ident, $ty:ty, $syscall_nr:expr) => {
         #[derive(Debug)]
@@ -127,6 +142,60 @@ fn ebadf() -> io::Error {
     io::Error::from_raw_os_error(libc::EBADF)
 }
 
+// Names are relative to their parent directory, one holding a '/' could reach
+// outside of the shared directory.
+fn validate_name(name: &CStr) -> io::Result<()> {
+    if name.to_bytes().contains(&b'/') {
+        Err(io::Error::from_raw_os_error(libc::EINVAL))
+    } else {
+        Ok(())
+    }
+}
+
+// Opens `name` relative to `dir`. When `confined`, the resolution can't go through any symlink nor
+// leave `dir`, which would let a client reach outside of the shared directory whenever a directory
+// gets moved out of it behind our back.
+fn openat(dir: &File, name: &CStr, flags: i32, mode: u32, confined: bool) -> io::Result<File> {
+    let fd = if confined {
+        let how = OpenHow {
+            flags: (flags | libc::O_CLOEXEC) as u64,
+            // The mode is only valid along with O_CREAT or O_TMPFILE.
+            mode: if flags & libc::O_CREAT != 0 {
+                u64::from(mode)
+            } else {
+                0
+            },
+            resolve: RESOLVE_BENEATH | RESOLVE_NO_SYMLINKS | RESOLVE_NO_MAGICLINKS,
+        };
+        // Safe because this doesn't modify any memory and we check the return value.
+        unsafe {
+            libc::syscall(
+                SYS_OPENAT2,
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                &how as *const OpenHow,
+                size_of::<OpenHow>(),
+            ) as libc::c_int
+        }
+    } else {
+        // Safe because this doesn't modify any memory and we check the return value.
+        unsafe {
+            libc::openat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                flags | libc::O_CLOEXEC,
+                mode,
+            )
+        }
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because we just opened this fd.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
 fn stat(f: &File) -> io::Result<libc::stat64> {
     let mut st = MaybeUninit::<libc::stat64>::zeroed();
 
@@ -231,6 +300,21 @@ pub struct Config {
     ///
     /// The default is `/`.
     pub root_dir: String,
+
+    /// Whether the process umask should be cleared, so that the client can set all the bits of
+    /// the mode of the files it creates. This affects the whole process, hence it should only be
+    /// set when the file system runs in a process of its own.
+    ///
+    /// The default value for this option is `true`.
+    pub clear_umask: bool,
+
+    /// Whether the file system runs within a sandbox, confining it to the shared directory. When
+    /// it doesn't, e.g. when it is served from the VMM process itself, the client isn't allowed to
+    /// create device nodes, and the lookups can neither follow symlinks nor leave the directory
+    /// they start from.
+    ///
+    /// The default value for this option is `true`.
+    pub sandboxed: bool,
 }
 
 impl Default for Config {
@@ -241,6 +325,8 @@ impl Default for Config {
             cache_policy: Default::default(),
             writeback: false,
             root_dir: String::from("/"),
+            clear_umask: true,
+            sandboxed: true,
         }
     }
 }
@@ -365,6 +451,17 @@ impl PassthroughFs {
     }
 
     fn do_lookup(&self, parent: Inode, name: &CStr) -> io::Result<Entry> {
+        validate_name(name)?;
+
+        // The root directory is its own parent, as with a chroot, so that the
+        // client can't reach outside of it.
+        let name = if parent == fuse::ROOT_ID && name.to_bytes_with_nul() == PARENT_DIR_CSTR {
+            // Safe because this is a constant value and a valid C string.
+            unsafe { CStr::from_bytes_with_nul_unchecked(CURRENT_DIR_CSTR) }
+        } else {
+            name
+        };
+
         let p = self
             .inodes
             .read()
@@ -373,20 +470,13 @@ impl PassthroughFs {
             .map(Arc::clone)
             .ok_or_else(ebadf)?;
 
-        // Safe because this doesn't modify any memory and we check the return value.
-        let fd = unsafe {
-            libc::openat(
-                p.file.as_raw_fd(),
-                name.as_ptr(),
-                libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC,
-            )
-        };
-        if fd < 0 {
-            return Err(io::Error::last_os_error());
-        }
-
-        // Safe because we just opened this fd.
-        let f = unsafe { File::from_raw_fd(fd) };
+        let f = openat(
+            &p.file,
+            name,
+            libc::O_PATH | libc::O_NOFOLLOW,
+            0,
+            !self.cfg.sandboxed,
+        )?;
 
         let st = stat(&f)?;
 
@@ -586,6 +676,7 @@ impl PassthroughFs {
     }
 
     fn do_unlink(&self, parent: Inode, name: &CStr, flags: libc::c_int) -> io::Result<()> {
+        validate_name(name)?;
         let data = self
             .inodes
             .read()
@@ -670,7 +761,9 @@ impl FileSystem for PassthroughFs {
         // Safe because this doesn't modify any memory and there is no need to check the return
         // value because this system call always succeeds. We need to clear the umask here because
         // we want the client to be able to set all the bits in the mode.
-        unsafe { libc::umask(0o000) };
+        if self.cfg.clear_umask {
+            unsafe { libc::umask(0o000) };
+        }
 
         let mut inodes = self.inodes.write().unwrap();
 
@@ -767,6 +860,7 @@ impl FileSystem for PassthroughFs {
         mode: u32,
         umask: u32,
     ) -> io::Result<Entry> {
+        validate_name(name)?;
         let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
         let data = self
             .inodes
@@ -861,6 +955,7 @@ impl FileSystem for PassthroughFs {
         flags: u32,
         umask: u32,
     ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
+        validate_name(name)?;
         let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
         let data = self
             .inodes
@@ -870,23 +965,15 @@ impl FileSystem for PassthroughFs {
             .map(Arc::clone)
             .ok_or_else(ebadf)?;
 
-        // Safe because this doesn't modify any memory and we check the return value. We don't
-        // really check `flags` because if the kernel can't handle poorly specified flags then we
-        // have much bigger problems.
-        let fd = unsafe {
-            libc::openat(
-                data.file.as_raw_fd(),
-                name.as_ptr(),
-                flags as i32 | libc::O_CREAT | libc::O_CLOEXEC | libc::O_NOFOLLOW,
-                mode & !(umask & 0o777),
-            )
-        };
-        if fd < 0 {
-            return Err(io::Error::last_os_error());
-        }
-
-        // Safe because we just opened this fd.
-        let file = Mutex::new(unsafe { File::from_raw_fd(fd) });
+        // We don't really check `flags` because if the kernel can't handle poorly specified flags
+        // then we have much bigger problems.
+        let file = Mutex::new(openat(
+            &data.file,
+            name,
+            flags as i32 | libc::O_CREAT | libc::O_NOFOLLOW,
+            mode & !(umask & 0o777),
+            !self.cfg.sandboxed,
+        )?);
 
         let entry = self.do_lookup(parent, name)?;
 
@@ -1160,6 +1247,8 @@ impl FileSystem for PassthroughFs {
         newname: &CStr,
         flags: u32,
     ) -> io::Result<()> {
+        validate_name(oldname)?;
+        validate_name(newname)?;
         let old_inode = self
             .inodes
             .read()
@@ -1204,6 +1293,12 @@ impl FileSystem for PassthroughFs {
         rdev: u32,
         umask: u32,
     ) -> io::Result<Entry> {
+        validate_name(name)?;
+        // Without a sandbox, a device node would give access to any device of the host.
+        let file_type = mode & libc::S_IFMT;
+        if !self.cfg.sandboxed && (file_type == libc::S_IFCHR || file_type == libc::S_IFBLK) {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
         let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
         let data = self
             .inodes
@@ -1237,6 +1332,7 @@ impl FileSystem for PassthroughFs {
         newparent: Inode,
         newname: &CStr,
     ) -> io::Result<Entry> {
+        validate_name(newname)?;
         let data = self
             .inodes
             .read()
@@ -1279,6 +1375,7 @@ impl FileSystem for PassthroughFs {
         parent: Inode,
         name: &CStr,
     ) -> io::Result<Entry> {
+        validate_name(name)?;
         let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
         let data = self
             .inodes
@@ -1589,3 +1686,135 @@ impl FileSystem for PassthroughFs {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx() -> Context {
+        Context {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        }
+    }
+
+    fn passthrough_fs(root_dir: &std::path::Path, sandboxed: bool) -> PassthroughFs {
+        let fs = PassthroughFs::new(Config {
+            root_dir: root_dir.to_str().unwrap().to_string(),
+            clear_umask: false,
+            sandboxed,
+            ..Default::default()
+        })
+        .unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        fs
+    }
+
+    fn cstr(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    #[test]
+    fn test_mknod_device_unsandboxed() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = passthrough_fs(dir.path(), false);
+
+        for file_type in &[libc::S_IFCHR, libc::S_IFBLK] {
+            let err = fs
+                .mknod(
+                    ctx(),
+                    fuse::ROOT_ID,
+                    &cstr("dev"),
+                    file_type | 0o600,
+                    0x0103,
+                    0,
+                )
+                .unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        }
+        assert!(!dir.path().join("dev").exists());
+
+        // Other kinds of nodes can still be created.
+        fs.mknod(
+            ctx(),
+            fuse::ROOT_ID,
+            &cstr("fifo"),
+            libc::S_IFIFO | 0o600,
+            0,
+            0,
+        )
+        .unwrap();
+        assert!(dir.path().join("fifo").exists());
+    }
+
+    #[test]
+    fn test_lookup_unsandboxed() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::os::unix::fs::symlink("/", dir.path().join("link")).unwrap();
+        let fs = passthrough_fs(dir.path(), false);
+
+        // The root directory is its own parent.
+        let root = fs.lookup(ctx(), fuse::ROOT_ID, &cstr("..")).unwrap();
+        assert_eq!(root.inode, fuse::ROOT_ID);
+
+        // A symlink can be looked up, but isn't followed.
+        let link = fs.lookup(ctx(), fuse::ROOT_ID, &cstr("link")).unwrap();
+        assert_eq!(link.attr.st_mode & libc::S_IFMT, libc::S_IFLNK);
+
+        // The lookups can't leave the directory they start from, as it
+        // could have been moved out of the shared directory.
+        let sub = fs.lookup(ctx(), fuse::ROOT_ID, &cstr("sub")).unwrap();
+        let err = fs.lookup(ctx(), sub.inode, &cstr("..")).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+
+        let err = fs
+            .lookup(ctx(), fuse::ROOT_ID, &cstr("sub/.."))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[test]
+    fn test_lookup_sandboxed() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        let fs = passthrough_fs(dir.path(), true);
+
+        let sub = fs.lookup(ctx(), fuse::ROOT_ID, &cstr("sub")).unwrap();
+        let parent = fs.lookup(ctx(), sub.inode, &cstr("..")).unwrap();
+        assert_eq!(parent.inode, fuse::ROOT_ID);
+    }
+
+    #[test]
+    fn test_create_unsandboxed() {
+        let dir = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink("/tmp/target", dir.path().join("link")).unwrap();
+        let fs = passthrough_fs(dir.path(), false);
+
+        let (entry, handle, _) = fs
+            .create(
+                ctx(),
+                fuse::ROOT_ID,
+                &cstr("file"),
+                0o600,
+                libc::O_RDWR as u32,
+                0,
+            )
+            .unwrap();
+        assert!(handle.is_some());
+        assert_eq!(entry.attr.st_mode & libc::S_IFMT, libc::S_IFREG);
+
+        // Creating through a symlink is refused.
+        assert!(fs
+            .create(
+                ctx(),
+                fuse::ROOT_ID,
+                &cstr("link"),
+                0o600,
+                libc::O_RDWR as u32,
+                0,
+            )
+            .is_err());
+    }
+}
@@ -30,7 +30,7 @@ use vhost_rs::vhost_user::Error as VhostUserError;
 use vhost_user_backend::{VhostUserBackend, VhostUserDaemon, Vring, VringWorker};
 use virtio_bindings::bindings::virtio_net::*;
 use vm_memory::GuestMemoryMmap;
-use vm_virtio::net_util::{open_tap, RxVirtio, TxVirtio};
+use vm_virtio::net_util::{open_tap, NetOffloads, RxVirtio, TxVirtio};
 use vm_virtio::Queue;
 use vmm_sys_util::eventfd::EventFd;
 
@@ -145,8 +145,16 @@ impl VhostUserNetBackend {
         num_queues: usize,
         queue_size: u16,
     ) -> Result<Self> {
-        let taps =
-            open_tap(None, Some(ip_addr), Some(netmask), num_queues / 2).map_err(Error::OpenTap)?;
+        let taps = open_tap(
+            None,
+            Some(ip_addr),
+            Some(netmask),
+            None,
+            None,
+            NetOffloads::default(),
+            num_queues / 2,
+        )
+        .map_err(Error::OpenTap)?;
 
         Self::new_with_tap(taps, num_queues, queue_size)
     }
@@ -237,7 +245,7 @@ impl VhostUserNetBackend {
     fn process_tx(&mut self, mut queue: &mut Queue, index: usize) -> Result<()> {
         let mem = self.mem.as_ref().ok_or(Error::NoMemoryConfigured)?;
 
-        self.txs[index].process_desc_chain(&mem, &mut self.taps[index].0, &mut queue);
+        self.txs[index].process_desc_chain(&mem, &mut self.taps[index].0, &mut queue, None);
 
         Ok(())
     }
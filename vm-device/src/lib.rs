@@ -1,4 +1,7 @@
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate thiserror;
 extern crate vm_memory;
 
@@ -9,6 +12,10 @@ use vm_memory::{
     MemoryRegionAddress,
 };
 
+use anyhow::anyhow;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
 use thiserror::Error;
 
 /// Trait meant for triggering the DMA mapping update related to an external
@@ -30,6 +37,12 @@ pub enum MigratableError {
 
     #[error("Failed to resume migratable component: {0}")]
     Resume(#[source] anyhow::Error),
+
+    #[error("Failed to snapshot migratable component: {0}")]
+    Snapshot(#[source] anyhow::Error),
+
+    #[error("Failed to restore migratable component: {0}")]
+    Restore(#[source] anyhow::Error),
 }
 
 /// A Pausable component can be paused and resumed.
@@ -41,8 +54,82 @@ pub trait Pausable {
     fn resume(&mut self) -> std::result::Result<(), MigratableError>;
 }
 
-/// A snapshotable component can be snapshoted.
-pub trait Snapshotable {}
+/// The state of a component, made of its own state sections and of the
+/// snapshots of its sub-components, each of them identified by a name.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub id: String,
+    pub snapshots: BTreeMap<String, Snapshot>,
+    pub snapshot_data: BTreeMap<String, serde_json::Value>,
+}
+
+impl Snapshot {
+    pub fn new(id: &str) -> Self {
+        Snapshot {
+            id: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Add the snapshot of a sub-component, under its id.
+    pub fn add_snapshot(&mut self, snapshot: Snapshot) {
+        self.snapshots.insert(snapshot.id.clone(), snapshot);
+    }
+
+    /// Take the snapshot of the sub-component `id` out.
+    pub fn take_snapshot(&mut self, id: &str) -> Result<Snapshot, MigratableError> {
+        self.snapshots.remove(id).ok_or_else(|| {
+            MigratableError::Restore(anyhow!("No snapshot of {} in {}", id, self.id))
+        })
+    }
+
+    /// Save `state` as the state section `id`.
+    pub fn add_state<T: Serialize>(&mut self, id: &str, state: &T) -> Result<(), MigratableError> {
+        let value = serde_json::to_value(state).map_err(|e| {
+            MigratableError::Snapshot(anyhow!("Failed saving {} of {}: {}", id, self.id, e))
+        })?;
+        self.snapshot_data.insert(id.to_string(), value);
+        Ok(())
+    }
+
+    /// Get the state section `id` back.
+    pub fn state<T: DeserializeOwned>(&self, id: &str) -> Result<T, MigratableError> {
+        let value = self
+            .snapshot_data
+            .get(id)
+            .ok_or_else(|| MigratableError::Restore(anyhow!("No {} state in {}", id, self.id)))?;
+        serde_json::from_value(value.clone()).map_err(|e| {
+            MigratableError::Restore(anyhow!("Invalid {} state in {}: {}", id, self.id, e))
+        })
+    }
+}
+
+/// A snapshotable component can be snapshoted, once paused, and restored
+/// before being resumed. Components with some state the VMM can't save,
+/// e.g. held by an external process or by a host device, keep the default
+/// implementations, which refuse both.
+pub trait Snapshotable {
+    /// The component name, identifying its snapshot.
+    fn id(&self) -> String;
+
+    /// Save the component state.
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        Err(MigratableError::Snapshot(anyhow!(
+            "{} doesn't support snapshots",
+            self.id()
+        )))
+    }
+
+    /// Bring the component back to the state it had when `snapshot` was
+    /// taken.
+    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        let _ = snapshot;
+        Err(MigratableError::Restore(anyhow!(
+            "{} doesn't support snapshots",
+            self.id()
+        )))
+    }
+}
 
 /// Trait to be implemented by any component (device, CPU, RAM, etc) that
 /// can be migrated.
@@ -84,6 +171,32 @@ mod tests {
     use super::*;
     use vm_memory::{GuestAddress, GuestMemoryMmap};
 
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct TestState {
+        registers: Vec<u32>,
+        enabled: bool,
+    }
+
+    #[test]
+    fn test_snapshot_state() {
+        let state = TestState {
+            registers: vec![1, 2, 3],
+            enabled: true,
+        };
+        let mut device = Snapshot::new("device");
+        device.add_state("test", &state).unwrap();
+        let mut parent = Snapshot::new("parent");
+        parent.add_snapshot(device);
+
+        let data = serde_json::to_vec(&parent).unwrap();
+        let mut parent: Snapshot = serde_json::from_slice(&data).unwrap();
+        let device = parent.take_snapshot("device").unwrap();
+        assert_eq!(device.state::<TestState>("test").unwrap(), state);
+        assert!(device.state::<TestState>("other").is_err());
+        assert!(device.state::<u32>("test").is_err());
+        assert!(parent.take_snapshot("device").is_err());
+    }
+
     #[test]
     fn test_get_host_address_range() {
         let start_addr1 = GuestAddress(0x0);
@@ -0,0 +1,869 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, DeviceEventT, Queue, VirtioDevice, VirtioDeviceType,
+    VIRTIO_F_VERSION_1,
+};
+use crate::{wait_for_resume, VirtioDeviceState, VirtioInterrupt, VirtioInterruptType};
+use epoll;
+use libc::EFD_NONBLOCK;
+use std::cmp;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use vm_device::{
+    get_host_address_range, Migratable, MigratableError, Pausable, Snapshot, Snapshotable,
+};
+use vm_memory::{
+    Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap,
+};
+use vmm_sys_util::eventfd::EventFd;
+
+const QUEUE_SIZE: u16 = 128;
+// The inflate and deflate queues, followed by the free page reporting queue
+// when the feature is offered.
+const NUM_QUEUES: usize = 2;
+const REPORTING_QUEUE_INDEX: usize = 2;
+
+// The guest describes the balloon pages through their 4 KiB page frame
+// number, whatever the host or guest page size is.
+const VIRTIO_BALLOON_PFN_SHIFT: u64 = 12;
+const VIRTIO_BALLOON_PAGE_SIZE: usize = 1 << VIRTIO_BALLOON_PFN_SHIFT;
+
+// The guest deflates the balloon by itself when running out of memory.
+const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u64 = 2;
+// The guest reports the free pages it holds, for the host to reclaim them.
+const VIRTIO_BALLOON_F_REPORTING: u64 = 5;
+
+// Pages are being added to the balloon.
+const INFLATE_QUEUE_EVENT: DeviceEventT = 0;
+// Pages are being removed from the balloon.
+const DEFLATE_QUEUE_EVENT: DeviceEventT = 1;
+// The device has been dropped.
+const KILL_EVENT: DeviceEventT = 2;
+// The device should be paused.
+const PAUSE_EVENT: DeviceEventT = 3;
+// Free pages are being reported.
+const REPORTING_QUEUE_EVENT: DeviceEventT = 4;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioBalloonConfig {
+    // Number of pages the host wants the balloon to hold.
+    num_pages: u32,
+    // Number of pages the balloon actually holds, updated by the guest.
+    actual: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioBalloonConfig {}
+
+// The pages held by the balloon, one bit per page frame number.
+#[derive(Default)]
+struct BalloonPages {
+    bitmap: Vec<u64>,
+}
+
+impl BalloonPages {
+    fn insert(&mut self, pfn: u32) {
+        let word = pfn as usize / 64;
+        if word >= self.bitmap.len() {
+            self.bitmap.resize(word + 1, 0);
+        }
+        self.bitmap[word] |= 1 << (pfn % 64);
+    }
+
+    fn remove(&mut self, pfn: u32) {
+        if let Some(bits) = self.bitmap.get_mut(pfn as usize / 64) {
+            *bits &= !(1 << (pfn % 64));
+        }
+    }
+
+    fn clear(&mut self) {
+        self.bitmap.clear();
+    }
+
+    // The guest memory ranges the pages cover, as address and length.
+    fn ranges(&self) -> Vec<(u64, u64)> {
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for (word, bits) in self.bitmap.iter().enumerate() {
+            if *bits == 0 {
+                continue;
+            }
+            for bit in 0..64 {
+                if bits & (1 << bit) == 0 {
+                    continue;
+                }
+                let addr = ((word * 64 + bit) as u64) << VIRTIO_BALLOON_PFN_SHIFT;
+                match ranges.last_mut() {
+                    Some((start, length)) if *start + *length == addr => {
+                        *length += VIRTIO_BALLOON_PAGE_SIZE as u64
+                    }
+                    _ => ranges.push((addr, VIRTIO_BALLOON_PAGE_SIZE as u64)),
+                }
+            }
+        }
+        ranges
+    }
+}
+
+struct BalloonEpollHandler {
+    queues: Vec<Queue>,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    pages: Arc<Mutex<BalloonPages>>,
+    inflate_queue_evt: EventFd,
+    deflate_queue_evt: EventFd,
+    reporting_queue_evt: Option<EventFd>,
+    reported: Arc<AtomicU64>,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+}
+
+impl BalloonEpollHandler {
+    // Give the memory backing the range back to the host. It gets faulted
+    // in again, zeroed, as soon as the guest accesses it.
+    fn release_range(mem: &GuestMemoryMmap, addr: GuestAddress, len: usize) -> io::Result<()> {
+        let host_addr = get_host_address_range(mem, addr, len)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EFAULT))?;
+
+        // Safe because the range is part of the guest memory mapping.
+        let ret =
+            unsafe { libc::madvise(host_addr as *mut libc::c_void, len, libc::MADV_DONTNEED) };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    fn release_page(mem: &GuestMemoryMmap, pfn: u32) {
+        let addr = GuestAddress(u64::from(pfn) << VIRTIO_BALLOON_PFN_SHIFT);
+        if let Err(e) = Self::release_range(mem, addr, VIRTIO_BALLOON_PAGE_SIZE) {
+            error!("Failed to release balloon page 0x{:x}: {}", pfn, e);
+        }
+    }
+
+    // The guest reports its free pages through chains of write-only
+    // descriptors, each covering a range of guest memory. The pages stay
+    // the guest's, the host only dropping their content.
+    fn process_reporting_queue(&mut self) -> bool {
+        let queue = &mut self.queues[REPORTING_QUEUE_INDEX];
+
+        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        let mem = self.mem.memory();
+        for avail_desc in queue.iter(&mem) {
+            let head_index = avail_desc.index;
+            let mut desc = Some(avail_desc);
+            while let Some(d) = desc {
+                if d.is_write_only() {
+                    match Self::release_range(&mem, d.addr, d.len as usize) {
+                        Ok(()) => {
+                            self.reported.fetch_add(u64::from(d.len), Ordering::SeqCst);
+                        }
+                        Err(e) => error!(
+                            "Failed to release reported range 0x{:x}-0x{:x}: {}",
+                            d.addr.raw_value(),
+                            d.addr.raw_value() + u64::from(d.len),
+                            e
+                        ),
+                    }
+                }
+                desc = d.next_descriptor();
+            }
+
+            used_desc_heads[used_count] = (head_index, 0);
+            used_count += 1;
+        }
+
+        for &(desc_index, len) in &used_desc_heads[..used_count] {
+            queue.add_used(&mem, desc_index, len);
+        }
+        used_count > 0
+    }
+
+    fn process_queue(&mut self, queue_index: usize) -> bool {
+        if queue_index == REPORTING_QUEUE_INDEX {
+            return self.process_reporting_queue();
+        }
+
+        let queue = &mut self.queues[queue_index];
+
+        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        let mem = self.mem.memory();
+        for avail_desc in queue.iter(&mem) {
+            // Each descriptor holds an array of page frame numbers. Deflated
+            // pages are populated again on access, they only need to be
+            // forgotten.
+            if !avail_desc.is_write_only() {
+                let mut pages = self.pages.lock().unwrap();
+                let num_pfns = avail_desc.len as usize / std::mem::size_of::<u32>();
+                for i in 0..num_pfns {
+                    let pfn_addr = avail_desc.addr.unchecked_add((i * 4) as u64);
+                    match mem.read_obj::<u32>(pfn_addr) {
+                        Ok(pfn) if queue_index == 0 => {
+                            Self::release_page(&mem, pfn);
+                            pages.insert(pfn);
+                        }
+                        Ok(pfn) => pages.remove(pfn),
+                        Err(e) => {
+                            error!("Failed to read balloon page frame number: {:?}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+
+            used_desc_heads[used_count] = (avail_desc.index, 0);
+            used_count += 1;
+        }
+
+        for &(desc_index, len) in &used_desc_heads[..used_count] {
+            queue.add_used(&mem, desc_index, len);
+        }
+        used_count > 0
+    }
+
+    fn signal_used_queue(&self, queue_index: usize) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), DeviceError> {
+        // Create the epoll file descriptor
+        let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
+
+        // Add events
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.inflate_queue_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(INFLATE_QUEUE_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.deflate_queue_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(DEFLATE_QUEUE_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+        if let Some(reporting_queue_evt) = &self.reporting_queue_evt {
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                reporting_queue_evt.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(REPORTING_QUEUE_EVENT)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.kill_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(KILL_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.pause_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PAUSE_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+
+        const EPOLL_EVENTS_LEN: usize = 100;
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+
+        'epoll: loop {
+            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+                Ok(res) => res,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        // It's well defined from the epoll_wait() syscall
+                        // documentation that the epoll loop can be interrupted
+                        // before any of the requested events occurred or the
+                        // timeout expired. In both those cases, epoll_wait()
+                        // returns an error of type EINTR, but this should not
+                        // be considered as a regular error. Instead it is more
+                        // appropriate to retry, by calling into epoll_wait().
+                        continue;
+                    }
+                    return Err(DeviceError::EpollWait(e));
+                }
+            };
+
+            for event in events.iter().take(num_events) {
+                let ev_type = event.data as u16;
+
+                match ev_type {
+                    INFLATE_QUEUE_EVENT | DEFLATE_QUEUE_EVENT | REPORTING_QUEUE_EVENT => {
+                        let (queue_index, queue_evt) = match ev_type {
+                            INFLATE_QUEUE_EVENT => (0, &self.inflate_queue_evt),
+                            DEFLATE_QUEUE_EVENT => (1, &self.deflate_queue_evt),
+                            // Only registered along with its queue event.
+                            _ => (
+                                REPORTING_QUEUE_INDEX,
+                                self.reporting_queue_evt.as_ref().unwrap(),
+                            ),
+                        };
+                        if let Err(e) = queue_evt.read() {
+                            error!("Failed to get queue event: {:?}", e);
+                            break 'epoll;
+                        } else if self.process_queue(queue_index) {
+                            if let Err(e) = self.signal_used_queue(queue_index) {
+                                error!("Failed to signal used queue: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                    }
+                    KILL_EVENT => {
+                        debug!("KILL_EVENT received, stopping epoll loop");
+                        break 'epoll;
+                    }
+                    PAUSE_EVENT => {
+                        debug!("PAUSE_EVENT received, pausing virtio-balloon epoll loop");
+                        wait_for_resume(&paused, &paused_sync);
+                    }
+                    _ => {
+                        error!("Unknown event for virtio-balloon");
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Virtio device letting the host reclaim memory from the guest, by having
+/// the guest driver hand pages over to the balloon.
+pub struct Balloon {
+    kill_evt: Option<EventFd>,
+    pause_evt: Option<EventFd>,
+    config: VirtioBalloonConfig,
+    queue_sizes: Vec<u16>,
+    avail_features: u64,
+    acked_features: u64,
+    queue_evts: Option<Vec<EventFd>>,
+    interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
+    epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
+    paused: Arc<AtomicBool>,
+    paused_sync: Option<Arc<Barrier>>,
+    pages: Arc<Mutex<BalloonPages>>,
+    reported: Arc<AtomicU64>,
+}
+
+impl Balloon {
+    /// Create a new virtio balloon device, asking the guest for `size` bytes.
+    /// With `deflate_on_oom`, the guest takes pages back from the balloon
+    /// instead of running out of memory. With `free_page_reporting`, the
+    /// guest reports its free pages, whose memory is given back to the
+    /// host without them leaving the guest.
+    pub fn new(size: u64, deflate_on_oom: bool, free_page_reporting: bool) -> io::Result<Balloon> {
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+        let mut queue_sizes = vec![QUEUE_SIZE; NUM_QUEUES];
+
+        if deflate_on_oom {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM;
+        }
+        if free_page_reporting {
+            avail_features |= 1u64 << VIRTIO_BALLOON_F_REPORTING;
+            queue_sizes.push(QUEUE_SIZE);
+        }
+
+        Ok(Balloon {
+            kill_evt: None,
+            pause_evt: None,
+            config: VirtioBalloonConfig {
+                num_pages: size_to_pages(size)?,
+                actual: 0,
+            },
+            queue_sizes,
+            avail_features,
+            acked_features: 0u64,
+            queue_evts: None,
+            interrupt_cb: None,
+            epoll_threads: None,
+            paused: Arc::new(AtomicBool::new(false)),
+            paused_sync: None,
+            pages: Arc::new(Mutex::new(BalloonPages::default())),
+            reported: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Change the amount of memory the guest is asked to give back. The
+    /// guest driver is notified if it's already running.
+    pub fn resize(&mut self, size: u64) -> io::Result<()> {
+        self.config.num_pages = size_to_pages(size)?;
+
+        if let Some(interrupt_cb) = &self.interrupt_cb {
+            interrupt_cb.trigger(&VirtioInterruptType::Config, None)?;
+        }
+
+        Ok(())
+    }
+
+    /// Amount of memory the guest is asked to give back.
+    pub fn size(&self) -> u64 {
+        u64::from(self.config.num_pages) << VIRTIO_BALLOON_PFN_SHIFT
+    }
+
+    /// Amount of memory the guest actually gave back.
+    pub fn actual_size(&self) -> u64 {
+        u64::from(self.config.actual) << VIRTIO_BALLOON_PFN_SHIFT
+    }
+
+    /// Guest memory ranges, as address and length, the guest gave to the
+    /// balloon, hence doesn't use. Their content is meaningless.
+    pub fn free_ranges(&self) -> Vec<(u64, u64)> {
+        self.pages.lock().unwrap().ranges()
+    }
+
+    /// Whether the guest is offered to report its free pages.
+    pub fn free_page_reporting(&self) -> bool {
+        self.avail_features & (1u64 << VIRTIO_BALLOON_F_REPORTING) != 0
+    }
+
+    /// Amount of memory the guest reported as free, and which got given
+    /// back to the host, since the device was created.
+    pub fn reported_size(&self) -> u64 {
+        self.reported.load(Ordering::SeqCst)
+    }
+}
+
+fn size_to_pages(size: u64) -> io::Result<u32> {
+    let num_pages = size >> VIRTIO_BALLOON_PFN_SHIFT;
+    if num_pages > u64::from(u32::MAX) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "balloon size is too large",
+        ));
+    }
+
+    Ok(num_pages as u32)
+}
+
+impl Drop for Balloon {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for Balloon {
+    fn device_type(&self) -> u32 {
+        VirtioDeviceType::TYPE_BALLOON as u32
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        self.queue_sizes.as_slice()
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_slice = self.config.as_slice();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            return;
+        }
+
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // Only the actual number of pages can be written by the driver.
+        let actual_offset = std::mem::size_of::<u32>() as u64;
+        if offset != actual_offset || data.len() != std::mem::size_of::<u32>() {
+            warn!(
+                "virtio-balloon: invalid config write (offset={:x}, len={:x})",
+                offset,
+                data.len()
+            );
+            return;
+        }
+
+        self.config.as_mut_slice()[offset as usize..].copy_from_slice(data);
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        mut queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != self.queue_sizes.len() || queue_evts.len() != self.queue_sizes.len() {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                self.queue_sizes.len(),
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        let (self_kill_evt, kill_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating kill EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.kill_evt = Some(self_kill_evt);
+
+        let (self_pause_evt, pause_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating pause EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.pause_evt = Some(self_pause_evt);
+
+        // Save the interrupt EventFD as we need to return it on reset
+        // but clone it to pass into the thread.
+        self.interrupt_cb = Some(interrupt_cb.clone());
+
+        let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
+        for queue_evt in queue_evts.iter() {
+            // Save the queue EventFD as we need to return it on reset
+            // but clone it to pass into the thread.
+            tmp_queue_evts.push(queue_evt.try_clone().map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?);
+        }
+        self.queue_evts = Some(tmp_queue_evts);
+
+        // The reporting queue is left disabled by a driver not negotiating
+        // the feature.
+        let reporting_queue_evt = if self.acked_features & (1u64 << VIRTIO_BALLOON_F_REPORTING) != 0
+        {
+            Some(queue_evts.remove(REPORTING_QUEUE_INDEX))
+        } else {
+            None
+        };
+
+        let mut handler = BalloonEpollHandler {
+            queues,
+            mem,
+            interrupt_cb,
+            pages: self.pages.clone(),
+            inflate_queue_evt: queue_evts.remove(0),
+            deflate_queue_evt: queue_evts.remove(0),
+            reporting_queue_evt,
+            reported: self.reported.clone(),
+            kill_evt,
+            pause_evt,
+        };
+
+        let paused = self.paused.clone();
+        let paused_sync = Arc::new(Barrier::new(2));
+        let paused_sync_clone = paused_sync.clone();
+        let mut epoll_threads = Vec::new();
+        thread::Builder::new()
+            .name("virtio_balloon".to_string())
+            .spawn(move || handler.run(paused, paused_sync_clone))
+            .map(|thread| epoll_threads.push(thread))
+            .map_err(|e| {
+                error!("failed to clone the virtio-balloon epoll thread: {}", e);
+                ActivateError::BadActivate
+            })?;
+
+        self.epoll_threads = Some(epoll_threads);
+        self.paused_sync = Some(paused_sync);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+        // We first must resume the virtio thread if it was paused.
+        if self.pause_evt.take().is_some() {
+            self.resume().ok()?;
+        }
+
+        // Then kill it.
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        // Pages are all given back to the guest on reset.
+        self.config.actual = 0;
+        self.pages.lock().unwrap().clear();
+
+        // Return the interrupt and queue EventFDs
+        Some((
+            self.interrupt_cb.take().unwrap(),
+            self.queue_evts.take().unwrap(),
+        ))
+    }
+}
+
+virtio_pausable!(Balloon);
+
+impl Snapshotable for Balloon {
+    fn id(&self) -> String {
+        "virtio-balloon".to_string()
+    }
+
+    fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        VirtioDeviceState::new(
+            self.avail_features,
+            self.acked_features,
+            self.config.as_slice(),
+        )
+        .add_to(&mut snapshot)?;
+        snapshot.add_state("balloon", &self.pages.lock().unwrap().bitmap)?;
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        let virtio_state = VirtioDeviceState::from(&snapshot)?;
+        self.acked_features = virtio_state.acked_features(self.avail_features)?;
+        virtio_state.restore_config(&mut self.config)?;
+        // Still the guest's, for them to be deflated and left out of
+        // the next snapshots.
+        self.pages.lock().unwrap().bitmap = snapshot.state("balloon")?;
+        Ok(())
+    }
+}
+impl Migratable for Balloon {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::tests::VirtQueue as GuestQ;
+    use crate::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    struct NoopVirtioInterrupt {}
+
+    impl VirtioInterrupt for NoopVirtioInterrupt {
+        fn trigger(
+            &self,
+            _int_type: &VirtioInterruptType,
+            _queue: Option<&Queue>,
+        ) -> result::Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    // The guest hands `pfns` over through a single descriptor of the queue.
+    fn push_pfns(queue: &GuestQ, mem: &GuestMemoryMmap, pfns: &[u32], array_addr: u64) {
+        for (i, pfn) in pfns.iter().enumerate() {
+            mem.write_obj(*pfn, GuestAddress(array_addr + i as u64 * 4))
+                .unwrap();
+        }
+        let avail_idx = queue.avail.idx.get();
+        let desc_index = avail_idx % QUEUE_SIZE;
+        queue.dtable[desc_index as usize].set(array_addr, pfns.len() as u32 * 4, 0, 0);
+        queue.avail.ring[desc_index as usize].set(desc_index);
+        queue.avail.idx.set(avail_idx + 1);
+    }
+
+    #[test]
+    fn test_balloon_inflate_deflate() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let inflate_queue = GuestQ::new(GuestAddress(0), &mem, QUEUE_SIZE);
+        let deflate_queue = GuestQ::new(GuestAddress(0x4000), &mem, QUEUE_SIZE);
+        let pages = Arc::new(Mutex::new(BalloonPages::default()));
+
+        let mut handler = BalloonEpollHandler {
+            queues: vec![inflate_queue.create_queue(), deflate_queue.create_queue()],
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            pages: pages.clone(),
+            inflate_queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            deflate_queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            reporting_queue_evt: None,
+            reported: Arc::new(AtomicU64::new(0)),
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+        };
+
+        // Nothing to process yet.
+        assert!(!handler.process_queue(0));
+
+        // The inflated pages are given back to the host, reading as zeros
+        // afterwards.
+        mem.write_obj(0xffu8, GuestAddress(0x10 << 12)).unwrap();
+        push_pfns(&inflate_queue, &mem, &[0x10, 0x11, 0x20], 0x8000);
+        assert!(handler.process_queue(0));
+        assert_eq!(inflate_queue.used.idx.get(), 1);
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x10 << 12)).unwrap(), 0);
+        assert_eq!(
+            pages.lock().unwrap().ranges(),
+            vec![(0x10 << 12, 0x2000), (0x20 << 12, 0x1000)]
+        );
+
+        // The deflated pages are only forgotten about.
+        push_pfns(&deflate_queue, &mem, &[0x11], 0x9000);
+        assert!(handler.process_queue(1));
+        assert_eq!(deflate_queue.used.idx.get(), 1);
+        assert_eq!(
+            pages.lock().unwrap().ranges(),
+            vec![(0x10 << 12, 0x1000), (0x20 << 12, 0x1000)]
+        );
+    }
+
+    #[test]
+    fn test_balloon_free_page_reporting() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let inflate_queue = GuestQ::new(GuestAddress(0), &mem, QUEUE_SIZE);
+        let deflate_queue = GuestQ::new(GuestAddress(0x4000), &mem, QUEUE_SIZE);
+        let reporting_queue = GuestQ::new(GuestAddress(0x8000), &mem, QUEUE_SIZE);
+        let pages = Arc::new(Mutex::new(BalloonPages::default()));
+        let reported = Arc::new(AtomicU64::new(0));
+
+        let mut handler = BalloonEpollHandler {
+            queues: vec![
+                inflate_queue.create_queue(),
+                deflate_queue.create_queue(),
+                reporting_queue.create_queue(),
+            ],
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            pages: pages.clone(),
+            inflate_queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            deflate_queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            reporting_queue_evt: Some(EventFd::new(EFD_NONBLOCK).unwrap()),
+            reported: reported.clone(),
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+        };
+
+        // Two free ranges reported through a single chain.
+        mem.write_obj(0xffu8, GuestAddress(0x20000)).unwrap();
+        mem.write_obj(0xffu8, GuestAddress(0x40000)).unwrap();
+        mem.write_obj(0xffu8, GuestAddress(0x60000)).unwrap();
+        reporting_queue.dtable[0].set(0x20000, 0x2000, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 1);
+        reporting_queue.dtable[1].set(0x40000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        reporting_queue.avail.ring[0].set(0);
+        reporting_queue.avail.idx.set(1);
+
+        assert!(handler.process_queue(REPORTING_QUEUE_INDEX));
+        assert_eq!(reporting_queue.used.idx.get(), 1);
+        assert_eq!(reported.load(Ordering::SeqCst), 0x3000);
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x20000)).unwrap(), 0);
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x40000)).unwrap(), 0);
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(0x60000)).unwrap(), 0xff);
+        // The reported pages are still the guest's.
+        assert!(pages.lock().unwrap().ranges().is_empty());
+    }
+
+    #[test]
+    fn test_balloon_free_page_reporting_feature() {
+        let balloon = Balloon::new(0, false, false).unwrap();
+        assert_eq!(balloon.features() & (1 << VIRTIO_BALLOON_F_REPORTING), 0);
+        assert_eq!(balloon.queue_max_sizes().len(), NUM_QUEUES);
+
+        let balloon = Balloon::new(0, false, true).unwrap();
+        assert_ne!(balloon.features() & (1 << VIRTIO_BALLOON_F_REPORTING), 0);
+        assert_eq!(balloon.queue_max_sizes().len(), NUM_QUEUES + 1);
+    }
+
+    #[test]
+    fn test_balloon_deflate_on_oom() {
+        let mut balloon = Balloon::new(0, false, false).unwrap();
+        assert_eq!(
+            balloon.features() & (1 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM),
+            0
+        );
+        // The feature can't be acked when not offered.
+        balloon.ack_features(1 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM);
+        assert_eq!(balloon.acked_features, 0);
+
+        let mut balloon = Balloon::new(0, true, false).unwrap();
+        assert_ne!(
+            balloon.features() & (1 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM),
+            0
+        );
+        balloon.ack_features(balloon.features());
+        assert_ne!(
+            balloon.acked_features & (1 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM),
+            0
+        );
+    }
+
+    #[test]
+    fn test_balloon_config() {
+        let mut balloon = Balloon::new(16 << 20, false, false).unwrap();
+        let mut num_pages = [0u8; 4];
+        balloon.read_config(0, &mut num_pages);
+        assert_eq!(u32::from_le_bytes(num_pages), 4096);
+
+        balloon.resize(8 << 20).unwrap();
+        assert_eq!(balloon.size(), 8 << 20);
+
+        // The guest reports the pages it holds, and only those.
+        balloon.write_config(4, &2048u32.to_le_bytes());
+        balloon.write_config(0, &0u32.to_le_bytes());
+        assert_eq!(balloon.actual_size(), 8 << 20);
+        assert_eq!(balloon.size(), 8 << 20);
+
+        assert!(Balloon::new(1 << 50, false, false).is_err());
+    }
+
+    #[test]
+    fn test_balloon_pages() {
+        let mut pages = BalloonPages::default();
+        assert!(pages.ranges().is_empty());
+
+        for pfn in [1, 2, 3, 63, 64, 200].iter() {
+            pages.insert(*pfn);
+        }
+        pages.remove(2);
+        // Not in the balloon.
+        pages.remove(1000);
+        assert_eq!(
+            pages.ranges(),
+            vec![
+                (0x1000, 0x1000),
+                (0x3000, 0x1000),
+                (63 << 12, 0x2000),
+                (200 << 12, 0x1000)
+            ]
+        );
+
+        pages.clear();
+        assert!(pages.ranges().is_empty());
+    }
+}
@@ -13,7 +13,8 @@ use super::{
     ActivateError, ActivateResult, DescriptorChain, DeviceEventT, Queue, VirtioDevice,
     VirtioDeviceType, VirtioInterruptType,
 };
-use crate::VirtioInterrupt;
+use crate::device::{join_epoll_threads, wait_for_resume};
+use crate::{ChangedBlocks, RateLimiter, VirtioDeviceState, VirtioInterrupt};
 use epoll;
 use libc::{c_void, EFD_NONBLOCK};
 use std::alloc::{alloc_zeroed, dealloc, Layout};
@@ -27,19 +28,27 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::PathBuf;
 use std::result;
 use std::slice;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
 use virtio_bindings::bindings::virtio_blk::*;
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
 use vm_memory::{
     ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
     GuestMemoryError, GuestMemoryMmap,
 };
-use vmm_sys_util::{eventfd::EventFd, seek_hole::SeekHole, write_zeroes::PunchHole};
+use vmm_sys_util::{
+    eventfd::EventFd, seek_hole::SeekHole, timerfd::TimerFd, write_zeroes::PunchHole,
+};
 
 const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
+// Largest discard or write zeroes request the guest is allowed to send, in
+// sectors.
+const MAX_DISCARD_WRITE_ZEROES_SECTORS: u32 = u32::MAX >> SECTOR_SHIFT;
+// Size of the buffer used to write zeroes when the disk image can't punch
+// holes.
+const WRITE_ZEROES_BUFFER_SIZE: u64 = 1 << 20;
 
 // New descriptors are pending on the virtio queue.
 const QUEUE_AVAIL_EVENT: DeviceEventT = 0;
@@ -49,6 +58,8 @@ pub const KILL_EVENT: DeviceEventT = 1;
 pub const BLOCK_EVENTS_COUNT: usize = 2;
 // The device should be paused.
 const PAUSE_EVENT: DeviceEventT = 3;
+// The rate limiter budget allows processing the queue again.
+const RATE_LIMITER_EVENT: DeviceEventT = 4;
 
 #[derive(Debug)]
 pub enum Error {
@@ -78,6 +89,9 @@ pub enum ExecuteError {
     Seek(io::Error),
     Write(GuestMemoryError),
     Unsupported(u32),
+    Submit(io::Error),
+    Discard(io::Error),
+    WriteZeroes(io::Error),
 }
 
 impl ExecuteError {
@@ -89,12 +103,32 @@ impl ExecuteError {
             ExecuteError::Seek(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Write(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
+            ExecuteError::Submit(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Discard(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::WriteZeroes(_) => VIRTIO_BLK_S_IOERR,
+        }
+    }
+
+    /// Whether the backing store failed the request, rather than the
+    /// request being invalid.
+    pub fn is_backing_store_error(&self) -> bool {
+        match self {
+            ExecuteError::BadRequest(_) | ExecuteError::Unsupported(_) => false,
+            // The guest memory is read from or written to the disk image.
+            ExecuteError::Read(GuestMemoryError::IOError(_))
+            | ExecuteError::Write(GuestMemoryError::IOError(_)) => true,
+            ExecuteError::Read(_) | ExecuteError::Write(_) => false,
+            ExecuteError::Flush(_)
+            | ExecuteError::Seek(_)
+            | ExecuteError::Submit(_)
+            | ExecuteError::Discard(_)
+            | ExecuteError::WriteZeroes(_) => true,
         }
     }
 }
 
-pub trait DiskFile: Read + Seek + Write + Clone {}
-impl<D: Read + Seek + Write + Clone> DiskFile for D {}
+pub trait DiskFile: Read + Seek + Write + PunchHole + Clone {}
+impl<D: Read + Seek + Write + PunchHole + Clone> DiskFile for D {}
 
 #[derive(Debug)]
 pub struct RawFile {
@@ -189,6 +223,12 @@ impl RawFile {
     }
 }
 
+impl AsRawFd for RawFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
 impl Read for RawFile {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         if self.is_aligned(buf) {
@@ -408,6 +448,8 @@ pub enum RequestType {
     Out,
     Flush,
     GetDeviceID,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
@@ -421,6 +463,8 @@ pub fn request_type(
         VIRTIO_BLK_T_OUT => Ok(RequestType::Out),
         VIRTIO_BLK_T_FLUSH => Ok(RequestType::Flush),
         VIRTIO_BLK_T_GET_ID => Ok(RequestType::GetDeviceID),
+        VIRTIO_BLK_T_DISCARD => Ok(RequestType::Discard),
+        VIRTIO_BLK_T_WRITE_ZEROES => Ok(RequestType::WriteZeroes),
         t => Ok(RequestType::Unsupported(t)),
     }
 }
@@ -450,28 +494,46 @@ fn build_device_id(disk_path: &PathBuf) -> result::Result<String, Error> {
     Ok(device_id)
 }
 
+/// Maximum length of a disk serial number, as returned to the guest.
+pub const DISK_SERIAL_MAX_LEN: usize = VIRTIO_BLK_ID_BYTES as usize;
+
+/// Builds the answer to the device ID request from the disk serial number.
+pub fn build_serial_id(serial: &str) -> Vec<u8> {
+    let mut disk_image_id = vec![0; DISK_SERIAL_MAX_LEN];
+    // The kernel only knows to read a maximum of VIRTIO_BLK_ID_BYTES.
+    // This will also zero out any leftover bytes.
+    let serial = serial.as_bytes();
+    let bytes_to_copy = cmp::min(serial.len(), DISK_SERIAL_MAX_LEN);
+    disk_image_id[..bytes_to_copy].clone_from_slice(&serial[..bytes_to_copy]);
+    disk_image_id
+}
+
 pub fn build_disk_image_id(disk_path: &PathBuf) -> Vec<u8> {
-    let mut default_disk_image_id = vec![0; VIRTIO_BLK_ID_BYTES as usize];
     match build_device_id(disk_path) {
         Err(_) => {
             warn!("Could not generate device id. We'll use a default.");
+            vec![0; DISK_SERIAL_MAX_LEN]
         }
-        Ok(m) => {
-            // The kernel only knows to read a maximum of VIRTIO_BLK_ID_BYTES.
-            // This will also zero out any leftover bytes.
-            let disk_id = m.as_bytes();
-            let bytes_to_copy = cmp::min(disk_id.len(), VIRTIO_BLK_ID_BYTES as usize);
-            default_disk_image_id[..bytes_to_copy].clone_from_slice(&disk_id[..bytes_to_copy])
-        }
+        Ok(m) => build_serial_id(&m),
     }
-    default_disk_image_id
 }
 
-pub struct Request {
-    request_type: RequestType,
+// Range of sectors a discard or write zeroes request applies to.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct DiscardWriteZeroesSegment {
     sector: u64,
-    data_addr: GuestAddress,
-    data_len: u32,
+    num_sectors: u32,
+    flags: u32,
+}
+
+unsafe impl ByteValued for DiscardWriteZeroesSegment {}
+
+pub struct Request {
+    pub(crate) request_type: RequestType,
+    pub(crate) sector: u64,
+    pub(crate) data_addr: GuestAddress,
+    pub(crate) data_len: u32,
     pub status_addr: GuestAddress,
 }
 
@@ -520,6 +582,12 @@ impl Request {
             if !data_desc.is_write_only() && req.request_type == RequestType::GetDeviceID {
                 return Err(Error::UnexpectedReadOnlyDescriptor);
             }
+            if data_desc.is_write_only()
+                && (req.request_type == RequestType::Discard
+                    || req.request_type == RequestType::WriteZeroes)
+            {
+                return Err(Error::UnexpectedWriteOnlyDescriptor);
+            }
 
             req.data_addr = data_desc.addr;
             req.data_len = data_desc.len;
@@ -539,14 +607,38 @@ impl Request {
         Ok(req)
     }
 
-    #[allow(clippy::ptr_arg)]
-    pub fn execute<T: Seek + Read + Write>(
-        &self,
-        disk: &mut T,
-        disk_nsectors: u64,
-        mem: &GuestMemoryMmap,
-        disk_id: &Vec<u8>,
-    ) -> result::Result<u32, ExecuteError> {
+    // Number of bytes accounted for by the rate limiter.
+    pub(crate) fn rate_limited_bytes(&self) -> u64 {
+        match self.request_type {
+            RequestType::In | RequestType::Out => u64::from(self.data_len),
+            _ => 0,
+        }
+    }
+
+    // The ranges of bytes, as offset and length, the request writes to. The
+    // discarded ranges are included, whether they get punched or not.
+    pub(crate) fn written_ranges(&self, mem: &GuestMemoryMmap) -> Vec<(u64, u64)> {
+        match self.request_type {
+            RequestType::Out => vec![(self.sector << SECTOR_SHIFT, u64::from(self.data_len))],
+            RequestType::Discard | RequestType::WriteZeroes => {
+                let segment_size = std::mem::size_of::<DiscardWriteZeroesSegment>();
+                (0..self.data_len as usize / segment_size)
+                    .filter_map(|i| {
+                        let addr = self.data_addr.checked_add((i * segment_size) as u64)?;
+                        let segment: DiscardWriteZeroesSegment = mem.read_obj(addr).ok()?;
+                        Some((
+                            segment.sector << SECTOR_SHIFT,
+                            u64::from(segment.num_sectors) << SECTOR_SHIFT,
+                        ))
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // Check the request doesn't access anything beyond the end of the disk.
+    pub(crate) fn check_range(&self, disk_nsectors: u64) -> result::Result<(), ExecuteError> {
         let mut top: u64 = u64::from(self.data_len) / SECTOR_SIZE;
         if u64::from(self.data_len) % SECTOR_SIZE != 0 {
             top += 1;
@@ -558,6 +650,101 @@ impl Request {
             return Err(ExecuteError::BadRequest(Error::InvalidOffset));
         }
 
+        Ok(())
+    }
+
+    // Discard or write zeroes the ranges of sectors the request describes.
+    // Discarding is only a hint, which is ignored when the disk image can't
+    // punch holes.
+    pub(crate) fn execute_discard_write_zeroes<T: Seek + Write + PunchHole + ?Sized>(
+        &self,
+        disk: &mut T,
+        disk_nsectors: u64,
+        mem: &GuestMemoryMmap,
+    ) -> result::Result<u32, ExecuteError> {
+        let segment_size = std::mem::size_of::<DiscardWriteZeroesSegment>();
+        let data_len = self.data_len as usize;
+        if data_len == 0 || data_len % segment_size != 0 {
+            return Err(ExecuteError::BadRequest(Error::DescriptorLengthTooSmall));
+        }
+
+        for i in 0..data_len / segment_size {
+            let addr = self
+                .data_addr
+                .checked_add((i * segment_size) as u64)
+                .ok_or(ExecuteError::BadRequest(Error::CheckedOffset(
+                    self.data_addr,
+                    i * segment_size,
+                )))?;
+            let segment: DiscardWriteZeroesSegment =
+                mem.read_obj(addr).map_err(ExecuteError::Read)?;
+
+            let unmap = match (self.request_type, segment.flags) {
+                (RequestType::Discard, 0) => true,
+                (RequestType::WriteZeroes, 0) => false,
+                (RequestType::WriteZeroes, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP) => true,
+                (RequestType::Discard, _) => {
+                    return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_DISCARD))
+                }
+                _ => return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_WRITE_ZEROES)),
+            };
+
+            let top = segment
+                .sector
+                .checked_add(u64::from(segment.num_sectors))
+                .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
+            if top > disk_nsectors {
+                return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+            }
+
+            let offset = segment.sector << SECTOR_SHIFT;
+            let length = u64::from(segment.num_sectors) << SECTOR_SHIFT;
+            let punched = if unmap {
+                match disk.punch_hole(offset, length) {
+                    Ok(()) => true,
+                    Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => false,
+                    Err(e) if self.request_type == RequestType::Discard => {
+                        return Err(ExecuteError::Discard(e))
+                    }
+                    Err(e) => return Err(ExecuteError::WriteZeroes(e)),
+                }
+            } else {
+                false
+            };
+
+            if !punched && self.request_type == RequestType::WriteZeroes {
+                disk.seek(SeekFrom::Start(offset))
+                    .map_err(ExecuteError::Seek)?;
+                let zeroes = vec![0u8; cmp::min(length, WRITE_ZEROES_BUFFER_SIZE) as usize];
+                let mut remaining = length;
+                while remaining > 0 {
+                    let count = cmp::min(remaining, zeroes.len() as u64) as usize;
+                    disk.write_all(&zeroes[..count])
+                        .map_err(ExecuteError::WriteZeroes)?;
+                    remaining -= count as u64;
+                }
+            }
+        }
+
+        Ok(0)
+    }
+
+    #[allow(clippy::ptr_arg)]
+    pub fn execute<T: Seek + Read + Write + PunchHole + ?Sized>(
+        &self,
+        disk: &mut T,
+        disk_nsectors: u64,
+        mem: &GuestMemoryMmap,
+        disk_id: &Vec<u8>,
+    ) -> result::Result<u32, ExecuteError> {
+        if self.request_type == RequestType::Discard
+            || self.request_type == RequestType::WriteZeroes
+        {
+            return self.execute_discard_write_zeroes(disk, disk_nsectors, mem);
+        }
+
+        self.check_range(disk_nsectors)?;
+
         disk.seek(SeekFrom::Start(self.sector << SECTOR_SHIFT))
             .map_err(ExecuteError::Seek)?;
 
@@ -584,55 +771,102 @@ impl Request {
                 mem.write_slice(&disk_id.as_slice(), self.data_addr)
                     .map_err(ExecuteError::Write)?;
             }
+            RequestType::Discard | RequestType::WriteZeroes => unreachable!(),
             RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
         };
         Ok(0)
     }
+
+    // Writes the status of the executed request for the guest, returning
+    // the length to report in the used ring.
+    pub(crate) fn complete(
+        &self,
+        mem: &GuestMemoryMmap,
+        result: result::Result<u32, ExecuteError>,
+    ) -> u32 {
+        let (status, len) = match result {
+            Ok(len) => (VIRTIO_BLK_S_OK, len),
+            Err(e) => {
+                error!("Failed to execute request: {:?}", e);
+                // We need at least 1 byte for the status.
+                (e.status(), 1)
+            }
+        };
+        // We use unwrap because the request parsing process already checked that the
+        // status_addr was valid.
+        mem.write_obj(status, self.status_addr).unwrap();
+        len
+    }
 }
 
 struct BlockEpollHandler<T: DiskFile> {
     queue: Queue,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     disk_image: Arc<Mutex<T>>,
-    disk_nsectors: u64,
+    disk_nsectors: Arc<AtomicU64>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     disk_image_id: Vec<u8>,
     kill_evt: EventFd,
     pause_evt: EventFd,
+    rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    rate_limiter_timer: Option<TimerFd>,
+    error_evt: Option<EventFd>,
+    // A request failed, and is to be retried once the device is resumed.
+    failed: bool,
+    changed_blocks: Option<Arc<ChangedBlocks>>,
 }
 
 impl<T: DiskFile> BlockEpollHandler<T> {
     fn process_queue(&mut self) -> bool {
+        if self.failed {
+            return false;
+        }
+
         let queue = &mut self.queue;
 
         let mut used_desc_heads = Vec::new();
         let mut used_count = 0;
+        let mut throttled = None;
         let mem = self.mem.memory();
         for avail_desc in queue.iter(&mem) {
             let len;
             match Request::parse(&avail_desc, &mem) {
                 Ok(request) => {
+                    if let Some(rate_limiter) = &self.rate_limiter {
+                        if let Err(wait) = rate_limiter
+                            .lock()
+                            .unwrap()
+                            .consume(request.rate_limited_bytes())
+                        {
+                            throttled = Some(wait);
+                            break;
+                        }
+                    }
+
                     let mut disk_image_locked = self.disk_image.lock().unwrap();
-                    let mut disk_image = disk_image_locked.deref_mut();
-                    let status = match request.execute(
-                        &mut disk_image,
-                        self.disk_nsectors,
+                    let disk_image = disk_image_locked.deref_mut();
+                    let result = request.execute(
+                        disk_image,
+                        self.disk_nsectors.load(Ordering::Acquire),
                         &mem,
                         &self.disk_image_id,
-                    ) {
-                        Ok(l) => {
-                            len = l;
-                            VIRTIO_BLK_S_OK
+                    );
+                    match &result {
+                        Ok(_) => {
+                            if let Some(changed_blocks) = &self.changed_blocks {
+                                for (offset, length) in request.written_ranges(&mem) {
+                                    changed_blocks.mark(offset, length);
+                                }
+                            }
                         }
-                        Err(e) => {
-                            error!("Failed to execute request: {:?}", e);
-                            len = 1; // We need at least 1 byte for the status.
-                            e.status()
+                        Err(e) if self.error_evt.is_some() && e.is_backing_store_error() => {
+                            error!("Failed to execute request, stopping the disk: {:?}", e);
+                            self.failed = true;
+                            break;
                         }
-                    };
-                    // We use unwrap because the request parsing process already checked that the
-                    // status_addr was valid.
-                    mem.write_obj(status, request.status_addr).unwrap();
+                        Err(_) => {}
+                    }
+                    len = request.complete(&mem, result);
                 }
                 Err(e) => {
                     error!("Failed to parse available descriptor chain: {:?}", e);
@@ -643,12 +877,45 @@ impl<T: DiskFile> BlockEpollHandler<T> {
             used_count += 1;
         }
 
+        // The failed request stays available, and the VMM is told to
+        // pause the VM until the backing store is fixed.
+        if self.failed {
+            queue.go_to_previous_position();
+            if let Some(error_evt) = &self.error_evt {
+                if let Err(e) = error_evt.write(1) {
+                    error!("Failed signaling the disk error: {:?}", e);
+                }
+            }
+        }
+
+        // The throttled request will be processed once the rate limiter
+        // allows it.
+        if let Some(wait) = throttled {
+            queue.go_to_previous_position();
+            if let Some(timer) = &mut self.rate_limiter_timer {
+                if let Err(e) = timer.reset(wait, None) {
+                    error!("Failed to arm the rate limiter timer: {:?}", e);
+                }
+            }
+        }
+
         for &(desc_index, len) in used_desc_heads.iter() {
             queue.add_used(&mem, desc_index, len);
         }
         used_count > 0
     }
 
+    // Retries the request which failed, the backing store being hopefully
+    // fixed by now. Returns whether requests were completed.
+    fn retry_failed_request(&mut self) -> bool {
+        if !self.failed {
+            return false;
+        }
+
+        self.failed = false;
+        self.process_queue()
+    }
+
     fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
         self.interrupt_cb
             .trigger(&VirtioInterruptType::Queue, Some(&self.queue))
@@ -664,10 +931,13 @@ impl<T: DiskFile> BlockEpollHandler<T> {
         mut disk_image: T,
         disk_path: &PathBuf,
     ) -> result::Result<(), DeviceError> {
-        self.disk_nsectors = disk_image
-            .seek(SeekFrom::End(0))
-            .map_err(DeviceError::IoError)?
-            / SECTOR_SIZE;
+        self.disk_nsectors.store(
+            disk_image
+                .seek(SeekFrom::End(0))
+                .map_err(DeviceError::IoError)?
+                / SECTOR_SIZE,
+            Ordering::Release,
+        );
         self.disk_image_id = build_disk_image_id(disk_path);
         self.disk_image = Arc::new(Mutex::new(disk_image));
         Ok(())
@@ -677,6 +947,7 @@ impl<T: DiskFile> BlockEpollHandler<T> {
         &mut self,
         queue_evt: EventFd,
         paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
     ) -> result::Result<(), DeviceError> {
         // Create the epoll file descriptor
         let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
@@ -703,6 +974,15 @@ impl<T: DiskFile> BlockEpollHandler<T> {
             epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PAUSE_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
+        if let Some(timer) = &self.rate_limiter_timer {
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                timer.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(RATE_LIMITER_EVENT)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
 
         const EPOLL_EVENTS_LEN: usize = 100;
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
@@ -740,17 +1020,33 @@ impl<T: DiskFile> BlockEpollHandler<T> {
                             }
                         }
                     }
+                    RATE_LIMITER_EVENT => {
+                        if let Some(timer) = &mut self.rate_limiter_timer {
+                            if let Err(e) = timer.wait() {
+                                error!("Failed to get rate limiter timer event: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                        if self.process_queue() {
+                            if let Err(e) = self.signal_used_queue() {
+                                error!("Failed to signal used queue: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                    }
                     KILL_EVENT => {
                         debug!("KILL_EVENT received, stopping epoll loop");
                         break 'epoll;
                     }
                     PAUSE_EVENT => {
                         debug!("PAUSE_EVENT received, pausing virtio-block epoll loop");
-                        // We loop here to handle spurious park() returns.
-                        // Until we have not resumed, the paused boolean will
-                        // be true.
-                        while paused.load(Ordering::SeqCst) {
-                            thread::park();
+                        wait_for_resume(&paused, &paused_sync);
+
+                        if self.retry_failed_request() {
+                            if let Err(e) = self.signal_used_queue() {
+                                error!("Failed to signal used queue: {:?}", e);
+                                break 'epoll;
+                            }
                         }
                     }
                     _ => {
@@ -764,6 +1060,21 @@ impl<T: DiskFile> BlockEpollHandler<T> {
     }
 }
 
+// Each queue handler sharing the rate limiter of a disk gets its own timer,
+// firing when the budget allows the queue to be processed again.
+pub(crate) fn rate_limiter_timer(
+    rate_limiter: &Option<Arc<Mutex<RateLimiter>>>,
+) -> result::Result<Option<TimerFd>, ActivateError> {
+    if rate_limiter.is_none() {
+        return Ok(None);
+    }
+
+    TimerFd::new().map(Some).map_err(|e| {
+        error!("failed creating rate limiter TimerFd: {}", e);
+        ActivateError::BadActivate
+    })
+}
+
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C, packed)]
 pub struct VirtioBlockGeometry {
@@ -804,8 +1115,8 @@ unsafe impl ByteValued for VirtioBlockConfig {}
 pub struct Block<T: DiskFile> {
     kill_evt: Option<EventFd>,
     disk_image: Arc<Mutex<T>>,
-    disk_path: PathBuf,
-    disk_nsectors: u64,
+    disk_image_id: Vec<u8>,
+    disk_nsectors: Arc<AtomicU64>,
     avail_features: u64,
     acked_features: u64,
     config: VirtioBlockConfig,
@@ -814,56 +1125,48 @@ pub struct Block<T: DiskFile> {
     epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
     pause_evt: Option<EventFd>,
     paused: Arc<AtomicBool>,
+    paused_sync: Option<Arc<Barrier>>,
     queue_size: Vec<u16>,
+    rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    error_evt: Option<EventFd>,
+    changed_blocks: Option<Arc<ChangedBlocks>>,
 }
 
 impl<T: DiskFile> Block<T> {
     /// Create a new virtio block device that operates on the given file.
     ///
-    /// The given file must be seekable and sizable.
+    /// The given file must be seekable and sizable. Without a `serial`, the
+    /// guest gets an ID built from the disk image inode.
+    ///
+    /// With an `error_evt`, a request failed by the backing store isn't
+    /// completed: the device stops processing its queue and writes to the
+    /// `error_evt`, the request being retried once the device is resumed.
+    /// Otherwise the error is reported to the guest.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         mut disk_image: T,
         disk_path: PathBuf,
+        serial: Option<String>,
         is_disk_read_only: bool,
         iommu: bool,
         num_queues: usize,
         queue_size: u16,
+        rate_limiter: Option<RateLimiter>,
+        error_evt: Option<EventFd>,
     ) -> io::Result<Block<T>> {
         let disk_size = disk_image.seek(SeekFrom::End(0))? as u64;
-        if disk_size % SECTOR_SIZE != 0 {
-            warn!(
-                "Disk size {} is not a multiple of sector size {}; \
-                 the remainder will not be visible to the guest.",
-                disk_size, SECTOR_SIZE
-            );
-        }
-
-        let mut avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_BLK_F_FLUSH);
-
-        if iommu {
-            avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
-        }
-
-        if is_disk_read_only {
-            avail_features |= 1u64 << VIRTIO_BLK_F_RO;
-        }
-
-        let disk_nsectors = disk_size / SECTOR_SIZE;
-        let mut config = VirtioBlockConfig {
-            capacity: disk_nsectors,
-            ..Default::default()
-        };
-
-        if num_queues > 1 {
-            avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
-            config.num_queues = num_queues as u16;
-        }
+        let (avail_features, config) =
+            build_features_and_config(disk_size, is_disk_read_only, iommu, num_queues);
+        let disk_nsectors = config.capacity;
 
         Ok(Block {
             kill_evt: None,
             disk_image: Arc::new(Mutex::new(disk_image)),
-            disk_path,
-            disk_nsectors,
+            disk_image_id: match serial {
+                Some(serial) => build_serial_id(&serial),
+                None => build_disk_image_id(&disk_path),
+            },
+            disk_nsectors: Arc::new(AtomicU64::new(disk_nsectors)),
             avail_features,
             acked_features: 0u64,
             config,
@@ -872,9 +1175,131 @@ impl<T: DiskFile> Block<T> {
             epoll_threads: None,
             pause_evt: None,
             paused: Arc::new(AtomicBool::new(false)),
+            paused_sync: None,
             queue_size: vec![queue_size; num_queues],
+            rate_limiter: rate_limiter.map(|r| Arc::new(Mutex::new(r))),
+            error_evt,
+            changed_blocks: None,
         })
     }
+
+    /// Tracks the blocks the guest writes to, from now on. Must be called
+    /// before the device is activated.
+    pub fn track_changes(&mut self) -> Arc<ChangedBlocks> {
+        let disk_size = self.disk_nsectors.load(Ordering::Acquire) * SECTOR_SIZE;
+        let changed_blocks = Arc::new(ChangedBlocks::new(disk_size));
+        self.changed_blocks = Some(changed_blocks.clone());
+        changed_blocks
+    }
+}
+
+// The features and configuration space of a block device, whichever backend
+// executes its requests.
+pub(crate) fn build_features_and_config(
+    disk_size: u64,
+    is_disk_read_only: bool,
+    iommu: bool,
+    num_queues: usize,
+) -> (u64, VirtioBlockConfig) {
+    if disk_size % SECTOR_SIZE != 0 {
+        warn!(
+            "Disk size {} is not a multiple of sector size {}; \
+             the remainder will not be visible to the guest.",
+            disk_size, SECTOR_SIZE
+        );
+    }
+
+    let mut avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_BLK_F_FLUSH);
+
+    if iommu {
+        avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+    }
+
+    if is_disk_read_only {
+        avail_features |= 1u64 << VIRTIO_BLK_F_RO;
+    }
+
+    let mut config = VirtioBlockConfig {
+        capacity: disk_size / SECTOR_SIZE,
+        ..Default::default()
+    };
+
+    if !is_disk_read_only {
+        enable_discard_write_zeroes(&mut avail_features, &mut config);
+    }
+
+    if num_queues > 1 {
+        avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
+        config.num_queues = num_queues as u16;
+    }
+
+    (avail_features, config)
+}
+
+// Lets the guest discard and write zeroes one range of sectors at a time.
+fn enable_discard_write_zeroes(avail_features: &mut u64, config: &mut VirtioBlockConfig) {
+    *avail_features |= (1u64 << VIRTIO_BLK_F_DISCARD) | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
+    config.max_discard_sectors = MAX_DISCARD_WRITE_ZEROES_SECTORS;
+    config.max_discard_seg = 1;
+    config.discard_sector_alignment = 1;
+    config.max_write_zeroes_sectors = MAX_DISCARD_WRITE_ZEROES_SECTORS;
+    config.max_write_zeroes_seg = 1;
+    config.write_zeroes_may_unmap = 1;
+}
+
+/// Block device whose capacity can follow the size of its disk image while
+/// the guest is running.
+pub trait DiskResize: Send {
+    /// Reads the disk image size again and updates the device capacity,
+    /// notifying the guest driver if it's already running and the capacity
+    /// changed. Returns the new size, in bytes.
+    fn resize(&mut self) -> io::Result<u64>;
+}
+
+// Updates the capacity advertised through the configuration space, the
+// guest driver only being told if it changed.
+pub(crate) fn update_capacity(
+    disk_size: u64,
+    disk_nsectors: &AtomicU64,
+    config: &mut VirtioBlockConfig,
+    interrupt_cb: &Option<Arc<dyn VirtioInterrupt>>,
+) -> io::Result<()> {
+    let capacity = disk_size / SECTOR_SIZE;
+    let current_capacity = config.capacity;
+    if current_capacity == capacity {
+        return Ok(());
+    }
+
+    if disk_size % SECTOR_SIZE != 0 {
+        warn!(
+            "Disk size {} is not a multiple of sector size {}; \
+             the remainder will not be visible to the guest.",
+            disk_size, SECTOR_SIZE
+        );
+    }
+
+    disk_nsectors.store(capacity, Ordering::Release);
+    config.capacity = capacity;
+
+    if let Some(interrupt_cb) = interrupt_cb {
+        interrupt_cb.trigger(&VirtioInterruptType::Config, None)?;
+    }
+
+    Ok(())
+}
+
+impl<T: 'static + DiskFile + Send> DiskResize for Block<T> {
+    fn resize(&mut self) -> io::Result<u64> {
+        let disk_size = self.disk_image.lock().unwrap().seek(SeekFrom::End(0))?;
+        update_capacity(
+            disk_size,
+            &self.disk_nsectors,
+            &mut self.config,
+            &self.interrupt_cb,
+        )?;
+
+        Ok(disk_size)
+    }
 }
 
 impl<T: DiskFile> Drop for Block<T> {
@@ -971,8 +1396,6 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
             })?;
         self.pause_evt = Some(self_pause_evt);
 
-        let disk_image_id = build_disk_image_id(&self.disk_path);
-
         let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
         for queue_evt in queue_evts.iter() {
             // Save the queue EventFD as we need to return it on reset
@@ -995,24 +1418,38 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
         }
         self.queue_evts = Some(tmp_queue_evts);
 
+        // One epoll thread per queue, plus the device pausing them.
+        let paused_sync = Arc::new(Barrier::new(self.queue_size.len() + 1));
         let mut epoll_threads = Vec::new();
         for _ in 0..self.queue_size.len() {
             let mut handler = BlockEpollHandler {
                 queue: queues.remove(0),
                 mem: mem.clone(),
                 disk_image: self.disk_image.clone(),
-                disk_nsectors: self.disk_nsectors,
+                disk_nsectors: self.disk_nsectors.clone(),
                 interrupt_cb: interrupt_cb.clone(),
-                disk_image_id: disk_image_id.clone(),
+                disk_image_id: self.disk_image_id.clone(),
                 kill_evt: kill_evt.try_clone().unwrap(),
                 pause_evt: pause_evt.try_clone().unwrap(),
+                rate_limiter: self.rate_limiter.clone(),
+                rate_limiter_timer: rate_limiter_timer(&self.rate_limiter)?,
+                error_evt: match &self.error_evt {
+                    Some(error_evt) => Some(error_evt.try_clone().map_err(|e| {
+                        error!("failed to clone error EventFd: {}", e);
+                        ActivateError::BadActivate
+                    })?),
+                    None => None,
+                },
+                failed: false,
+                changed_blocks: self.changed_blocks.clone(),
             };
 
             let queue_evt = queue_evts.remove(0);
             let paused = self.paused.clone();
+            let paused_sync = paused_sync.clone();
             thread::Builder::new()
                 .name("virtio_blk".to_string())
-                .spawn(move || handler.run(queue_evt, paused))
+                .spawn(move || handler.run(queue_evt, paused, paused_sync))
                 .map(|thread| epoll_threads.push(thread))
                 .map_err(|e| {
                     error!("failed to clone the virtio-blk epoll thread: {}", e);
@@ -1025,6 +1462,7 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
         self.interrupt_cb = Some(interrupt_cb);
 
         self.epoll_threads = Some(epoll_threads);
+        self.paused_sync = Some(paused_sync);
 
         Ok(())
     }
@@ -1046,8 +1484,214 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
             self.queue_evts.take().unwrap(),
         ))
     }
+
+    fn shutdown(&mut self) {
+        // A paused thread wouldn't see the kill event.
+        if self.paused.load(Ordering::SeqCst) {
+            let _ = self.resume();
+        }
+        if let Some(kill_evt) = self.kill_evt.take() {
+            let _ = kill_evt.write(1);
+        }
+        if let Some(epoll_threads) = self.epoll_threads.take() {
+            join_epoll_threads("virtio-blk", epoll_threads);
+        }
+
+        // The image caches, e.g. the QCOW2 tables, hold writes the guest
+        // considers done.
+        if let Err(e) = self.disk_image.lock().unwrap().flush() {
+            error!("Failed to flush the disk image: {:?}", e);
+        }
+    }
 }
 
 virtio_pausable!(Block, T: 'static + DiskFile + Send);
-impl<T: 'static + DiskFile + Send> Snapshotable for Block<T> {}
+impl<T: 'static + DiskFile + Send> Snapshotable for Block<T> {
+    fn id(&self) -> String {
+        "virtio-block".to_string()
+    }
+
+    fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        VirtioDeviceState::new(
+            self.avail_features,
+            self.acked_features,
+            self.config.as_slice(),
+        )
+        .add_to(&mut snapshot)?;
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        let virtio_state = VirtioDeviceState::from(&snapshot)?;
+        self.acked_features = virtio_state.acked_features(self.avail_features)?;
+        virtio_state.restore_config(&mut self.config)?;
+        // The disk image may have been resized since.
+        self.config.capacity = self.disk_nsectors.load(Ordering::Acquire);
+        Ok(())
+    }
+}
 impl<T: 'static + DiskFile + Send> Migratable for Block<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::tests::VirtQueue as GuestQ;
+    use crate::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    const QUEUE_SIZE: u16 = 16;
+    const REQUEST_ADDR: u64 = 0x1_0000;
+
+    struct NoopVirtioInterrupt {}
+
+    impl VirtioInterrupt for NoopVirtioInterrupt {
+        fn trigger(
+            &self,
+            _int_type: &VirtioInterruptType,
+            _queue: Option<&Queue>,
+        ) -> result::Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    // A disk whose writes fail while its backing store is broken.
+    #[derive(Clone)]
+    struct FailingDisk {
+        broken: Arc<AtomicBool>,
+    }
+
+    impl Read for FailingDisk {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+    }
+
+    impl Write for FailingDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.broken.load(Ordering::SeqCst) {
+                return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for FailingDisk {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            match pos {
+                SeekFrom::Start(offset) => Ok(offset),
+                _ => Ok(0),
+            }
+        }
+    }
+
+    impl PunchHole for FailingDisk {
+        fn punch_hole(&mut self, _offset: u64, _length: u64) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn epoll_handler(
+        mem: &GuestMemoryMmap,
+        queue: Queue,
+        broken: &Arc<AtomicBool>,
+        error_evt: Option<EventFd>,
+    ) -> BlockEpollHandler<FailingDisk> {
+        BlockEpollHandler {
+            queue,
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            disk_image: Arc::new(Mutex::new(FailingDisk {
+                broken: broken.clone(),
+            })),
+            disk_nsectors: Arc::new(AtomicU64::new(8)),
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            disk_image_id: Vec::new(),
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            rate_limiter: None,
+            rate_limiter_timer: None,
+            error_evt,
+            failed: false,
+            changed_blocks: None,
+        }
+    }
+
+    // The guest writes a sector to sector 1, from the descriptors 0 to 2.
+    fn push_write_request(queue: &GuestQ, mem: &GuestMemoryMmap) {
+        mem.write_obj(VIRTIO_BLK_T_OUT, GuestAddress(REQUEST_ADDR))
+            .unwrap();
+        mem.write_obj(1u64, GuestAddress(REQUEST_ADDR + 8)).unwrap();
+        mem.write_obj(0xffu8, GuestAddress(REQUEST_ADDR + 0x2000))
+            .unwrap();
+        queue.dtable[0].set(REQUEST_ADDR, 16, VIRTQ_DESC_F_NEXT, 1);
+        queue.dtable[1].set(
+            REQUEST_ADDR + 0x1000,
+            SECTOR_SIZE as u32,
+            VIRTQ_DESC_F_NEXT,
+            2,
+        );
+        queue.dtable[2].set(REQUEST_ADDR + 0x2000, 1, VIRTQ_DESC_F_WRITE, 0);
+
+        queue.avail.ring[0].set(0);
+        queue.avail.idx.set(1);
+    }
+
+    fn status(mem: &GuestMemoryMmap) -> u32 {
+        u32::from(
+            mem.read_obj::<u8>(GuestAddress(REQUEST_ADDR + 0x2000))
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_error_policy_report() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, QUEUE_SIZE);
+        let broken = Arc::new(AtomicBool::new(true));
+        let mut handler = epoll_handler(&mem, guest_queue.create_queue(), &broken, None);
+
+        // The request is completed with an error.
+        push_write_request(&guest_queue, &mem);
+        assert!(handler.process_queue());
+        assert!(!handler.failed);
+        assert_eq!(guest_queue.used.idx.get(), 1);
+        assert_eq!(status(&mem), VIRTIO_BLK_S_IOERR);
+    }
+
+    #[test]
+    fn test_error_policy_stop() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0), &mem, QUEUE_SIZE);
+        let broken = Arc::new(AtomicBool::new(true));
+        let error_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let mut handler = epoll_handler(
+            &mem,
+            guest_queue.create_queue(),
+            &broken,
+            Some(error_evt.try_clone().unwrap()),
+        );
+
+        // The request is left in the queue, and the VMM is told to pause
+        // the VM.
+        push_write_request(&guest_queue, &mem);
+        assert!(!handler.process_queue());
+        assert!(handler.failed);
+        assert_eq!(error_evt.read().unwrap(), 1);
+        assert_eq!(guest_queue.used.idx.get(), 0);
+        assert_eq!(status(&mem), 0xff);
+
+        // Nothing is processed until the device is resumed.
+        assert!(!handler.process_queue());
+
+        // The request succeeds once retried with the backing store fixed.
+        broken.store(false, Ordering::SeqCst);
+        assert!(handler.retry_failed_request());
+        assert!(!handler.failed);
+        assert!(error_evt.read().is_err());
+        assert_eq!(guest_queue.used.idx.get(), 1);
+        assert_eq!(status(&mem), VIRTIO_BLK_S_OK);
+    }
+}
@@ -0,0 +1,945 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, DeviceEventT, Queue, VirtioDevice, VirtioDeviceType,
+    VirtioInterruptType,
+};
+use crate::block::{
+    build_disk_image_id, build_features_and_config, build_serial_id, rate_limiter_timer,
+    update_capacity, DiskResize, Error, ExecuteError, RawFile, Request, RequestType,
+    VirtioBlockConfig, SECTOR_SIZE,
+};
+use crate::device::{join_epoll_threads, wait_for_resume};
+use crate::{RateLimiter, VirtioDeviceState, VirtioInterrupt};
+use epoll;
+use io_uring::{opcode, types, IoUring, Probe};
+use libc::EFD_NONBLOCK;
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::result;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use vm_device::{
+    get_host_address_range, Migratable, MigratableError, Pausable, Snapshot, Snapshotable,
+};
+use vm_memory::{
+    ByteValued, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryError, GuestMemoryMmap,
+};
+use vmm_sys_util::{eventfd::EventFd, timerfd::TimerFd};
+
+// New descriptors are pending on the virtio queue.
+const QUEUE_AVAIL_EVENT: DeviceEventT = 0;
+// The device has been dropped.
+const KILL_EVENT: DeviceEventT = 1;
+// The device should be paused.
+const PAUSE_EVENT: DeviceEventT = 2;
+// Some submitted requests have completed.
+const COMPLETION_EVENT: DeviceEventT = 3;
+// The rate limiter budget allows processing the queue again.
+const RATE_LIMITER_EVENT: DeviceEventT = 4;
+
+/// Check whether the host kernel provides everything the io_uring based
+/// virtio-blk device relies on.
+pub fn block_io_uring_is_supported() -> bool {
+    let io_uring = match IoUring::new(1) {
+        Ok(io_uring) => io_uring,
+        Err(e) => {
+            info!("io_uring is not supported: {}", e);
+            return false;
+        }
+    };
+
+    let submitter = io_uring.submitter();
+
+    let event_fd = match EventFd::new(EFD_NONBLOCK) {
+        Ok(event_fd) => event_fd,
+        Err(_) => return false,
+    };
+    if let Err(e) = submitter.register_eventfd(event_fd.as_raw_fd()) {
+        info!("io_uring eventfd registration is not supported: {}", e);
+        return false;
+    }
+
+    let mut probe = Probe::new();
+    if let Err(e) = submitter.register_probe(&mut probe) {
+        info!("io_uring probing is not supported: {}", e);
+        return false;
+    }
+
+    probe.is_supported(opcode::Readv::CODE)
+        && probe.is_supported(opcode::Writev::CODE)
+        && probe.is_supported(opcode::Fsync::CODE)
+}
+
+// A request submitted to the ring, waiting for its completion.
+struct InflightRequest {
+    request: Request,
+    // The ring only holds a pointer to the I/O vector, which must remain
+    // valid until the request completes.
+    _iovec: Box<libc::iovec>,
+}
+
+struct BlockIoUringEpollHandler {
+    queue: Queue,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    disk_fd: RawFd,
+    // The requests other than reads, writes and flushes aren't submitted to
+    // the ring, but executed synchronously on this copy of the disk image.
+    disk_image: RawFile,
+    disk_nsectors: Arc<AtomicU64>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    disk_image_id: Vec<u8>,
+    io_uring: IoUring,
+    completion_evt: EventFd,
+    inflight: HashMap<u16, InflightRequest>,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+    rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    rate_limiter_timer: Option<TimerFd>,
+}
+
+impl BlockIoUringEpollHandler {
+    // Submit the read, write and flush requests to the ring. The other
+    // requests are completed straight away, in which case true is returned
+    // so that the guest gets notified.
+    fn process_queue_submit(&mut self) -> result::Result<bool, DeviceError> {
+        let queue = &mut self.queue;
+        let mem = self.mem.memory();
+
+        let mut used_desc_heads = Vec::new();
+        let mut submitted = false;
+        let mut throttled = None;
+        for avail_desc in queue.iter(&mem) {
+            let request = match Request::parse(&avail_desc, &mem) {
+                Ok(request) => request,
+                Err(e) => {
+                    error!("Failed to parse available descriptor chain: {:?}", e);
+                    used_desc_heads.push((avail_desc.index, 0));
+                    continue;
+                }
+            };
+
+            if let Some(rate_limiter) = &self.rate_limiter {
+                if let Err(wait) = rate_limiter
+                    .lock()
+                    .unwrap()
+                    .consume(request.rate_limited_bytes())
+                {
+                    throttled = Some(wait);
+                    break;
+                }
+            }
+
+            let disk_nsectors = self.disk_nsectors.load(Ordering::Acquire);
+            let result = match request.request_type {
+                RequestType::In | RequestType::Out | RequestType::Flush => {
+                    match Self::submit(
+                        &mut self.io_uring,
+                        self.disk_fd,
+                        disk_nsectors,
+                        &mem,
+                        avail_desc.index,
+                        &request,
+                    ) {
+                        Ok(iovec) => {
+                            self.inflight.insert(
+                                avail_desc.index,
+                                InflightRequest {
+                                    request,
+                                    _iovec: iovec,
+                                },
+                            );
+                            submitted = true;
+                            continue;
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+                _ => request.execute(
+                    &mut self.disk_image,
+                    disk_nsectors,
+                    &mem,
+                    &self.disk_image_id,
+                ),
+            };
+
+            let len = request.complete(&mem, result);
+            used_desc_heads.push((avail_desc.index, len));
+        }
+
+        // The throttled request will be submitted once the rate limiter
+        // allows it.
+        if let Some(wait) = throttled {
+            queue.go_to_previous_position();
+            if let Some(timer) = &mut self.rate_limiter_timer {
+                if let Err(e) = timer.reset(wait, None) {
+                    error!("Failed to arm the rate limiter timer: {:?}", e);
+                }
+            }
+        }
+
+        if submitted {
+            self.io_uring
+                .submitter()
+                .submit()
+                .map_err(DeviceError::IoError)?;
+        }
+
+        for &(desc_index, len) in used_desc_heads.iter() {
+            queue.add_used(&mem, desc_index, len);
+        }
+
+        Ok(!used_desc_heads.is_empty())
+    }
+
+    fn submit(
+        io_uring: &mut IoUring,
+        disk_fd: RawFd,
+        disk_nsectors: u64,
+        mem: &GuestMemoryMmap,
+        index: u16,
+        request: &Request,
+    ) -> result::Result<Box<libc::iovec>, ExecuteError> {
+        request.check_range(disk_nsectors)?;
+
+        let offset = (request.sector * SECTOR_SIZE) as i64;
+        let len = request.data_len as usize;
+        let iovec = Box::new(libc::iovec {
+            iov_base: match request.request_type {
+                RequestType::Flush => std::ptr::null_mut(),
+                _ => get_host_address_range(mem, request.data_addr, len).ok_or(
+                    ExecuteError::BadRequest(Error::CheckedOffset(request.data_addr, len)),
+                )? as *mut libc::c_void,
+            },
+            iov_len: len,
+        });
+
+        let fd = types::Fd(disk_fd);
+        let entry = match request.request_type {
+            RequestType::In => opcode::Readv::new(fd, iovec.as_ref(), 1)
+                .offset(offset)
+                .build(),
+            RequestType::Out => opcode::Writev::new(fd, iovec.as_ref(), 1)
+                .offset(offset)
+                .build(),
+            RequestType::Flush => opcode::Fsync::new(fd).build(),
+            _ => unreachable!(),
+        };
+
+        let (_, sq, _) = io_uring.split();
+        let mut avail_sq = sq.available();
+        // The I/O vector and the guest memory it points to remain valid
+        // until the request completes.
+        unsafe { avail_sq.push(entry.user_data(u64::from(index))) }.map_err(|_| {
+            ExecuteError::Submit(io::Error::new(
+                io::ErrorKind::Other,
+                "io_uring submission queue is full",
+            ))
+        })?;
+
+        Ok(iovec)
+    }
+
+    // Complete the requests the ring reported as done, returning whether
+    // the guest should be notified.
+    fn process_queue_complete(&mut self) -> bool {
+        let queue = &mut self.queue;
+        let mem = self.mem.memory();
+
+        let mut used_desc_heads = Vec::new();
+        let (_, _, cq) = self.io_uring.split();
+        for entry in cq.available() {
+            let index = entry.user_data() as u16;
+            let inflight = match self.inflight.remove(&index) {
+                Some(inflight) => inflight,
+                None => {
+                    error!("Unknown io_uring completion {}", index);
+                    continue;
+                }
+            };
+            let request = inflight.request;
+
+            // The request fails the same way as when executed synchronously.
+            let result = if entry.result() < 0 {
+                let e = io::Error::from_raw_os_error(-entry.result());
+                Err(match request.request_type {
+                    RequestType::In => ExecuteError::Read(GuestMemoryError::IOError(e)),
+                    RequestType::Out => ExecuteError::Write(GuestMemoryError::IOError(e)),
+                    _ => ExecuteError::Flush(e),
+                })
+            } else if request.request_type == RequestType::In {
+                Ok(request.data_len)
+            } else {
+                Ok(0)
+            };
+
+            let len = request.complete(&mem, result);
+            used_desc_heads.push((index, len));
+        }
+
+        for &(desc_index, len) in used_desc_heads.iter() {
+            queue.add_used(&mem, desc_index, len);
+        }
+
+        !used_desc_heads.is_empty()
+    }
+
+    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queue))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(
+        &mut self,
+        queue_evt: EventFd,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), DeviceError> {
+        // Create the epoll file descriptor
+        let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
+
+        // Add events
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            queue_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(QUEUE_AVAIL_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.kill_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(KILL_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.pause_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PAUSE_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.completion_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(COMPLETION_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+        if let Some(timer) = &self.rate_limiter_timer {
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                timer.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(RATE_LIMITER_EVENT)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+
+        const EPOLL_EVENTS_LEN: usize = 100;
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+
+        'epoll: loop {
+            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+                Ok(res) => res,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        // It's well defined from the epoll_wait() syscall
+                        // documentation that the epoll loop can be interrupted
+                        // before any of the requested events occurred or the
+                        // timeout expired. In both those cases, epoll_wait()
+                        // returns an error of type EINTR, but this should not
+                        // be considered as a regular error. Instead it is more
+                        // appropriate to retry, by calling into epoll_wait().
+                        continue;
+                    }
+                    return Err(DeviceError::EpollWait(e));
+                }
+            };
+
+            for event in events.iter().take(num_events) {
+                let ev_type = event.data as u16;
+
+                match ev_type {
+                    QUEUE_AVAIL_EVENT | RATE_LIMITER_EVENT => {
+                        if ev_type == QUEUE_AVAIL_EVENT {
+                            if let Err(e) = queue_evt.read() {
+                                error!("Failed to get queue event: {:?}", e);
+                                break 'epoll;
+                            }
+                        } else if let Some(timer) = &mut self.rate_limiter_timer {
+                            if let Err(e) = timer.wait() {
+                                error!("Failed to get rate limiter timer event: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                        match self.process_queue_submit() {
+                            Ok(true) => {
+                                if let Err(e) = self.signal_used_queue() {
+                                    error!("Failed to signal used queue: {:?}", e);
+                                    break 'epoll;
+                                }
+                            }
+                            Ok(false) => {}
+                            Err(e) => {
+                                error!("Failed to submit requests: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                    }
+                    COMPLETION_EVENT => {
+                        if let Err(e) = self.completion_evt.read() {
+                            error!("Failed to get completion event: {:?}", e);
+                            break 'epoll;
+                        } else if self.process_queue_complete() {
+                            if let Err(e) = self.signal_used_queue() {
+                                error!("Failed to signal used queue: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                    }
+                    KILL_EVENT => {
+                        debug!("KILL_EVENT received, stopping epoll loop");
+                        break 'epoll;
+                    }
+                    PAUSE_EVENT => {
+                        debug!("PAUSE_EVENT received, pausing virtio-block epoll loop");
+                        wait_for_resume(&paused, &paused_sync);
+                    }
+                    _ => {
+                        error!("Unknown event for virtio-block");
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Virtio block device submitting the guest requests asynchronously to the
+/// host kernel through io_uring, instead of reading and writing the disk
+/// image from the virtio-blk thread.
+pub struct BlockIoUring {
+    kill_evt: Option<EventFd>,
+    disk_image: RawFile,
+    disk_image_id: Vec<u8>,
+    disk_nsectors: Arc<AtomicU64>,
+    avail_features: u64,
+    acked_features: u64,
+    config: VirtioBlockConfig,
+    queue_evts: Option<Vec<EventFd>>,
+    interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
+    epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
+    pause_evt: Option<EventFd>,
+    paused: Arc<AtomicBool>,
+    paused_sync: Option<Arc<Barrier>>,
+    queue_size: Vec<u16>,
+    rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+}
+
+impl BlockIoUring {
+    /// Create a new io_uring based virtio block device operating on the
+    /// given raw image.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        mut disk_image: RawFile,
+        disk_path: PathBuf,
+        serial: Option<String>,
+        is_disk_read_only: bool,
+        iommu: bool,
+        num_queues: usize,
+        queue_size: u16,
+        rate_limiter: Option<RateLimiter>,
+    ) -> io::Result<BlockIoUring> {
+        let disk_size = disk_image.seek(SeekFrom::End(0))? as u64;
+        let (avail_features, config) =
+            build_features_and_config(disk_size, is_disk_read_only, iommu, num_queues);
+        let disk_nsectors = config.capacity;
+
+        Ok(BlockIoUring {
+            kill_evt: None,
+            disk_image,
+            disk_image_id: match serial {
+                Some(serial) => build_serial_id(&serial),
+                None => build_disk_image_id(&disk_path),
+            },
+            disk_nsectors: Arc::new(AtomicU64::new(disk_nsectors)),
+            avail_features,
+            acked_features: 0u64,
+            config,
+            queue_evts: None,
+            interrupt_cb: None,
+            epoll_threads: None,
+            pause_evt: None,
+            paused: Arc::new(AtomicBool::new(false)),
+            paused_sync: None,
+            queue_size: vec![queue_size; num_queues],
+            rate_limiter: rate_limiter.map(|r| Arc::new(Mutex::new(r))),
+        })
+    }
+}
+
+impl DiskResize for BlockIoUring {
+    fn resize(&mut self) -> io::Result<u64> {
+        let disk_size = self.disk_image.seek(SeekFrom::End(0))?;
+        update_capacity(
+            disk_size,
+            &self.disk_nsectors,
+            &mut self.config,
+            &self.interrupt_cb,
+        )?;
+
+        Ok(disk_size)
+    }
+}
+
+impl Drop for BlockIoUring {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for BlockIoUring {
+    fn device_type(&self) -> u32 {
+        VirtioDeviceType::TYPE_BLOCK as u32
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        self.queue_size.as_slice()
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_slice = self.config.as_slice();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        let config_slice = self.config.as_mut_slice();
+        let data_len = data.len() as u64;
+        let config_len = config_slice.len() as u64;
+        if offset + data_len > config_len {
+            error!("Failed to write config space");
+            return;
+        }
+        let (_, right) = config_slice.split_at_mut(offset as usize);
+        right.copy_from_slice(&data[..]);
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        mut queues: Vec<Queue>,
+        mut queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != self.queue_size.len() || queue_evts.len() != self.queue_size.len() {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                self.queue_size.len(),
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        let (self_kill_evt, kill_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating kill EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+
+        self.kill_evt = Some(self_kill_evt);
+
+        let (self_pause_evt, pause_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating pause EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.pause_evt = Some(self_pause_evt);
+
+        let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
+        for queue_evt in queue_evts.iter() {
+            // Save the queue EventFD as we need to return it on reset
+            // but clone it to pass into the thread.
+            tmp_queue_evts.push(queue_evt.try_clone().map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?);
+        }
+        self.queue_evts = Some(tmp_queue_evts);
+
+        // One epoll thread per queue, plus the device pausing them.
+        let paused_sync = Arc::new(Barrier::new(self.queue_size.len() + 1));
+        let mut epoll_threads = Vec::new();
+        for i in 0..self.queue_size.len() {
+            // Each queue gets its own ring, large enough to hold every
+            // descriptor of the queue.
+            let io_uring = IoUring::new(u32::from(self.queue_size[i])).map_err(|e| {
+                error!("failed to create io_uring instance: {}", e);
+                ActivateError::BadActivate
+            })?;
+            let completion_evt = EventFd::new(EFD_NONBLOCK).map_err(|e| {
+                error!("failed creating io_uring completion EventFd: {}", e);
+                ActivateError::BadActivate
+            })?;
+            io_uring
+                .submitter()
+                .register_eventfd(completion_evt.as_raw_fd())
+                .map_err(|e| {
+                    error!("failed to register io_uring completion EventFd: {}", e);
+                    ActivateError::BadActivate
+                })?;
+
+            let mut handler = BlockIoUringEpollHandler {
+                queue: queues.remove(0),
+                mem: mem.clone(),
+                disk_fd: self.disk_image.as_raw_fd(),
+                disk_image: self.disk_image.clone(),
+                disk_nsectors: self.disk_nsectors.clone(),
+                interrupt_cb: interrupt_cb.clone(),
+                disk_image_id: self.disk_image_id.clone(),
+                io_uring,
+                completion_evt,
+                inflight: HashMap::new(),
+                kill_evt: kill_evt.try_clone().unwrap(),
+                pause_evt: pause_evt.try_clone().unwrap(),
+                rate_limiter: self.rate_limiter.clone(),
+                rate_limiter_timer: rate_limiter_timer(&self.rate_limiter)?,
+            };
+
+            let queue_evt = queue_evts.remove(0);
+            let paused = self.paused.clone();
+            let paused_sync = paused_sync.clone();
+            thread::Builder::new()
+                .name("virtio_blk_io_uring".to_string())
+                .spawn(move || handler.run(queue_evt, paused, paused_sync))
+                .map(|thread| epoll_threads.push(thread))
+                .map_err(|e| {
+                    error!("failed to clone the virtio-blk epoll thread: {}", e);
+                    ActivateError::BadActivate
+                })?;
+        }
+
+        // Save the interrupt EventFD as we need to return it on reset
+        // but clone it to pass into the thread.
+        self.interrupt_cb = Some(interrupt_cb);
+
+        self.epoll_threads = Some(epoll_threads);
+        self.paused_sync = Some(paused_sync);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+        // We first must resume the virtio thread if it was paused.
+        if self.pause_evt.take().is_some() {
+            self.resume().ok()?;
+        }
+
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        // Return the interrupt and queue EventFDs
+        Some((
+            self.interrupt_cb.take().unwrap(),
+            self.queue_evts.take().unwrap(),
+        ))
+    }
+
+    fn shutdown(&mut self) {
+        // A paused thread wouldn't see the kill event.
+        if self.paused.load(Ordering::SeqCst) {
+            let _ = self.resume();
+        }
+        if let Some(kill_evt) = self.kill_evt.take() {
+            let _ = kill_evt.write(1);
+        }
+        if let Some(epoll_threads) = self.epoll_threads.take() {
+            join_epoll_threads("virtio-blk", epoll_threads);
+        }
+
+        if let Err(e) = self.disk_image.sync_data() {
+            error!("Failed to sync the disk image: {:?}", e);
+        }
+    }
+}
+
+virtio_pausable!(BlockIoUring);
+impl Snapshotable for BlockIoUring {
+    fn id(&self) -> String {
+        "virtio-block".to_string()
+    }
+
+    fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        VirtioDeviceState::new(
+            self.avail_features,
+            self.acked_features,
+            self.config.as_slice(),
+        )
+        .add_to(&mut snapshot)?;
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        let virtio_state = VirtioDeviceState::from(&snapshot)?;
+        self.acked_features = virtio_state.acked_features(self.avail_features)?;
+        virtio_state.restore_config(&mut self.config)?;
+        // The disk image may have been resized since.
+        self.config.capacity = self.disk_nsectors.load(Ordering::Acquire);
+        Ok(())
+    }
+}
+impl Migratable for BlockIoUring {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::tests::VirtQueue as GuestQ;
+    use crate::{Block, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+    use std::io::Read;
+    use virtio_bindings::bindings::virtio_blk::*;
+    use vm_memory::{Bytes, GuestAddress};
+
+    const QUEUE_SIZE: u16 = 16;
+
+    struct NoopVirtioInterrupt {}
+
+    impl VirtioInterrupt for NoopVirtioInterrupt {
+        fn trigger(
+            &self,
+            _int_type: &VirtioInterruptType,
+            _queue: Option<&Queue>,
+        ) -> result::Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    fn disk_image(size: u64) -> RawFile {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(size).unwrap();
+        RawFile::new(file, false)
+    }
+
+    // The guest places a request on sector 1 in the descriptors from
+    // `index`: the header at `addr`, followed by the data and the status.
+    fn push_request(
+        queue: &GuestQ,
+        mem: &GuestMemoryMmap,
+        index: u16,
+        request_type: u32,
+        addr: u64,
+        data_len: u32,
+        data_flags: u16,
+    ) {
+        mem.write_obj(request_type, GuestAddress(addr)).unwrap();
+        mem.write_obj(1u64, GuestAddress(addr + 8)).unwrap();
+        queue.dtable[index as usize].set(addr, 16, VIRTQ_DESC_F_NEXT, index + 1);
+        queue.dtable[index as usize + 1].set(
+            addr + 0x1000,
+            data_len,
+            data_flags | VIRTQ_DESC_F_NEXT,
+            index + 2,
+        );
+        queue.dtable[index as usize + 2].set(addr + 0x2000, 1, VIRTQ_DESC_F_WRITE, 0);
+
+        let avail_idx = queue.avail.idx.get();
+        queue.avail.ring[(avail_idx % QUEUE_SIZE) as usize].set(index);
+        queue.avail.idx.set(avail_idx + 1);
+    }
+
+    fn status(mem: &GuestMemoryMmap, addr: u64) -> u32 {
+        u32::from(mem.read_obj::<u8>(GuestAddress(addr + 0x2000)).unwrap())
+    }
+
+    #[test]
+    fn test_block_io_uring_features() {
+        // The remainder of the last sector isn't exposed.
+        let disk = disk_image(0x10_0000 + 100);
+        for &(read_only, iommu, num_queues) in
+            [(false, false, 1), (true, false, 1), (false, true, 4)].iter()
+        {
+            let block = Block::new(
+                disk.clone(),
+                PathBuf::from("disk.img"),
+                None,
+                read_only,
+                iommu,
+                num_queues,
+                QUEUE_SIZE,
+                None,
+                None,
+            )
+            .unwrap();
+            let block_io_uring = BlockIoUring::new(
+                disk.clone(),
+                PathBuf::from("disk.img"),
+                None,
+                read_only,
+                iommu,
+                num_queues,
+                QUEUE_SIZE,
+                None,
+            )
+            .unwrap();
+
+            assert_eq!(block_io_uring.features(), block.features());
+            assert_eq!(block_io_uring.queue_max_sizes(), block.queue_max_sizes());
+            let mut config = [0u8; 64];
+            let mut expected = [0u8; 64];
+            block_io_uring.read_config(0, &mut config);
+            block.read_config(0, &mut expected);
+            assert_eq!(config, expected);
+            assert_eq!(block_io_uring.config.capacity, 0x800);
+        }
+    }
+
+    #[test]
+    fn test_block_io_uring_requests() {
+        if !block_io_uring_is_supported() {
+            return;
+        }
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let queue = GuestQ::new(GuestAddress(0), &mem, QUEUE_SIZE);
+        let mut disk = disk_image(0x10_0000);
+        let io_uring = IoUring::new(u32::from(QUEUE_SIZE)).unwrap();
+        let completion_evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        io_uring
+            .submitter()
+            .register_eventfd(completion_evt.as_raw_fd())
+            .unwrap();
+
+        let mut handler = BlockIoUringEpollHandler {
+            queue: queue.create_queue(),
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            disk_fd: disk.as_raw_fd(),
+            disk_image: disk.clone(),
+            disk_nsectors: Arc::new(AtomicU64::new(0x800)),
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            disk_image_id: build_serial_id("serial"),
+            io_uring,
+            completion_evt,
+            inflight: HashMap::new(),
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            rate_limiter: None,
+            rate_limiter_timer: None,
+        };
+
+        // The writes and reads complete through the ring.
+        mem.write_slice(&[0xab; 512], GuestAddress(0x11000))
+            .unwrap();
+        push_request(&queue, &mem, 0, VIRTIO_BLK_T_OUT, 0x10000, 512, 0);
+        assert!(!handler.process_queue_submit().unwrap());
+        handler.io_uring.submit_and_wait(1).unwrap();
+        assert!(handler.process_queue_complete());
+        assert_eq!(queue.used.idx.get(), 1);
+        assert_eq!(queue.used.ring[0].get().len, 0);
+        assert_eq!(status(&mem, 0x10000), VIRTIO_BLK_S_OK);
+        let mut data = [0u8; 512];
+        disk.seek(SeekFrom::Start(512)).unwrap();
+        disk.read_exact(&mut data).unwrap();
+        assert_eq!(data[..], [0xab; 512][..]);
+
+        push_request(
+            &queue,
+            &mem,
+            3,
+            VIRTIO_BLK_T_IN,
+            0x20000,
+            512,
+            VIRTQ_DESC_F_WRITE,
+        );
+        assert!(!handler.process_queue_submit().unwrap());
+        handler.io_uring.submit_and_wait(1).unwrap();
+        assert!(handler.process_queue_complete());
+        assert_eq!(queue.used.idx.get(), 2);
+        assert_eq!(queue.used.ring[1].get().len, 512);
+        assert_eq!(status(&mem, 0x20000), VIRTIO_BLK_S_OK);
+        mem.read_slice(&mut data, GuestAddress(0x21000)).unwrap();
+        assert_eq!(data[..], [0xab; 512][..]);
+
+        // The other requests complete straight away, as with the
+        // synchronous backend.
+        push_request(
+            &queue,
+            &mem,
+            6,
+            VIRTIO_BLK_T_GET_ID,
+            0x30000,
+            VIRTIO_BLK_ID_BYTES,
+            VIRTQ_DESC_F_WRITE,
+        );
+        push_request(&queue, &mem, 9, 0xff, 0x40000, 512, 0);
+        assert!(handler.process_queue_submit().unwrap());
+        assert_eq!(queue.used.idx.get(), 4);
+        assert_eq!(status(&mem, 0x30000), VIRTIO_BLK_S_OK);
+        let mut id = [0u8; VIRTIO_BLK_ID_BYTES as usize];
+        mem.read_slice(&mut id, GuestAddress(0x31000)).unwrap();
+        assert_eq!(id[..], build_serial_id("serial")[..]);
+        assert_eq!(status(&mem, 0x40000), VIRTIO_BLK_S_UNSUPP);
+        assert_eq!(queue.used.ring[3].get().len, 1);
+
+        // A request past the end of the disk is rejected before reaching
+        // the ring.
+        push_request(
+            &queue,
+            &mem,
+            12,
+            VIRTIO_BLK_T_IN,
+            0x50000,
+            512,
+            VIRTQ_DESC_F_WRITE,
+        );
+        mem.write_obj(0x800u64, GuestAddress(0x50008)).unwrap();
+        assert!(handler.process_queue_submit().unwrap());
+        assert!(handler.inflight.is_empty());
+        assert_eq!(status(&mem, 0x50000), VIRTIO_BLK_S_IOERR);
+    }
+}
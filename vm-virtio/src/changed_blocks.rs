@@ -0,0 +1,144 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Tracks the blocks of a disk written by the guest between checkpoints, so
+//! that a backup only needs to read the blocks changed since the previous
+//! one.
+//!
+//! Each block records the generation it was last written in, the generation
+//! being bumped by every checkpoint. The blocks changed since a checkpoint
+//! are the ones written in a later generation.
+
+use std::result;
+use std::sync::Mutex;
+
+/// Granularity of the tracking, in bytes.
+pub const CHANGED_BLOCK_SIZE: u64 = 64 << 10;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The checkpoint hasn't been taken, e.g. it was taken before the VMM
+    /// got restarted.
+    UnknownCheckpoint(u64),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+struct Generations {
+    // The generation the blocks are being written in, the previous ones
+    // being the checkpoints taken so far.
+    current: u32,
+    // Per block, the generation it was last written in, 0 if it has never
+    // been written.
+    blocks: Vec<u32>,
+}
+
+pub struct ChangedBlocks {
+    generations: Mutex<Generations>,
+}
+
+impl ChangedBlocks {
+    pub fn new(disk_size: u64) -> Self {
+        let nblocks = (disk_size + CHANGED_BLOCK_SIZE - 1) / CHANGED_BLOCK_SIZE;
+        ChangedBlocks {
+            generations: Mutex::new(Generations {
+                current: 1,
+                blocks: vec![0; nblocks as usize],
+            }),
+        }
+    }
+
+    /// Records the `length` bytes at `offset` as written.
+    pub fn mark(&self, offset: u64, length: u64) {
+        if length == 0 {
+            return;
+        }
+        let first = (offset / CHANGED_BLOCK_SIZE) as usize;
+        let last = ((offset + length - 1) / CHANGED_BLOCK_SIZE) as usize;
+
+        let mut generations = self.generations.lock().unwrap();
+        let current = generations.current;
+        // The disk may have grown since the tracking started.
+        if last >= generations.blocks.len() {
+            generations.blocks.resize(last + 1, 0);
+        }
+        for block in generations.blocks[first..=last].iter_mut() {
+            *block = current;
+        }
+    }
+
+    /// Takes a checkpoint, returning its identifier.
+    pub fn checkpoint(&self) -> u64 {
+        let mut generations = self.generations.lock().unwrap();
+        generations.current += 1;
+        u64::from(generations.current - 1)
+    }
+
+    /// Returns the ranges of bytes, as offset and length, written since the
+    /// checkpoint `since`. The ranges are aligned on CHANGED_BLOCK_SIZE.
+    pub fn changed_since(&self, since: u64) -> Result<Vec<(u64, u64)>> {
+        let generations = self.generations.lock().unwrap();
+        if since == 0 || since >= u64::from(generations.current) {
+            return Err(Error::UnknownCheckpoint(since));
+        }
+
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for (index, generation) in generations.blocks.iter().enumerate() {
+            if u64::from(*generation) <= since {
+                continue;
+            }
+            let offset = index as u64 * CHANGED_BLOCK_SIZE;
+            match ranges.last_mut() {
+                Some((start, length)) if *start + *length == offset => {
+                    *length += CHANGED_BLOCK_SIZE
+                }
+                _ => ranges.push((offset, CHANGED_BLOCK_SIZE)),
+            }
+        }
+
+        Ok(ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changed_since() {
+        let blocks = ChangedBlocks::new(16 * CHANGED_BLOCK_SIZE);
+        assert!(blocks.changed_since(1).is_err());
+
+        blocks.mark(0, 512);
+        let first = blocks.checkpoint();
+        assert_eq!(blocks.changed_since(first).unwrap(), vec![]);
+
+        // Spanning two blocks.
+        blocks.mark(2 * CHANGED_BLOCK_SIZE + 512, CHANGED_BLOCK_SIZE);
+        blocks.mark(8 * CHANGED_BLOCK_SIZE, 512);
+        assert_eq!(
+            blocks.changed_since(first).unwrap(),
+            vec![
+                (2 * CHANGED_BLOCK_SIZE, 2 * CHANGED_BLOCK_SIZE),
+                (8 * CHANGED_BLOCK_SIZE, CHANGED_BLOCK_SIZE)
+            ]
+        );
+
+        let second = blocks.checkpoint();
+        blocks.mark(0, 512);
+        // Past the end of the disk, once it has grown.
+        blocks.mark(20 * CHANGED_BLOCK_SIZE, 512);
+        assert_eq!(
+            blocks.changed_since(second).unwrap(),
+            vec![
+                (0, CHANGED_BLOCK_SIZE),
+                (20 * CHANGED_BLOCK_SIZE, CHANGED_BLOCK_SIZE)
+            ]
+        );
+        assert_eq!(blocks.changed_since(first).unwrap().len(), 4);
+
+        assert!(blocks.changed_since(0).is_err());
+        assert!(blocks.changed_since(3).is_err());
+    }
+}
@@ -6,27 +6,35 @@ use super::{
     ActivateError, ActivateResult, DeviceEventT, Queue, VirtioDevice, VirtioDeviceType,
     VirtioInterruptType, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
 };
-use crate::VirtioInterrupt;
+use crate::{wait_for_resume, VirtioDeviceState, VirtioInterrupt};
+use anyhow::anyhow;
 use epoll;
 use libc::EFD_NONBLOCK;
 use std;
 use std::cmp;
 use std::collections::VecDeque;
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::ops::DerefMut;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
 use vm_memory::{ByteValued, Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vmm_sys_util::eventfd::EventFd;
 
-const QUEUE_SIZE: u16 = 256;
 const NUM_QUEUES: usize = 2;
-const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+
+// With named ports, the control queues follow the queues of the console
+// port, and the queues of the named ports follow the control queues.
+const CONTROL_RX_QUEUE: usize = 2;
+const CONTROL_TX_QUEUE: usize = 3;
+
+// The named ports are identified by the low byte of their events.
+const MAX_NAMED_PORTS: usize = 0xff;
 
 // New descriptors are pending on the virtio queue.
 const INPUT_QUEUE_EVENT: DeviceEventT = 0;
@@ -39,9 +47,29 @@ const KILL_EVENT: DeviceEventT = 3;
 const CONFIG_EVENT: DeviceEventT = 4;
 // The device should be paused.
 const PAUSE_EVENT: DeviceEventT = 5;
+// New descriptors are pending on the control queues or on the queues of a
+// named port, the low byte being the queue index.
+const MULTIPORT_QUEUE_EVENT: DeviceEventT = 0x100;
+// A client connects to the socket of a named port, the low byte being the
+// port id.
+const PORT_LISTENER_EVENT: DeviceEventT = 0x200;
+// Some input from the client of a named port, the low byte being the port
+// id.
+const PORT_STREAM_EVENT: DeviceEventT = 0x300;
 
 //Console size feature bit
 const VIRTIO_CONSOLE_F_SIZE: u64 = 0;
+// Several ports feature bit
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1;
+
+// Control messages, from the virtio specification.
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_RESIZE: u16 = 5;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
 
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
@@ -55,6 +83,129 @@ pub struct VirtioConsoleConfig {
 // Safe because it only has data and has no implicit padding.
 unsafe impl ByteValued for VirtioConsoleConfig {}
 
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioConsoleControl {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioConsoleControl {}
+
+impl VirtioConsoleControl {
+    fn message(id: u32, event: u16, value: u16, data: &[u8]) -> Vec<u8> {
+        let mut message = VirtioConsoleControl { id, event, value }
+            .as_slice()
+            .to_vec();
+        message.extend_from_slice(data);
+        message
+    }
+}
+
+fn port_rx_queue(id: usize) -> usize {
+    if id == 0 {
+        0
+    } else {
+        CONTROL_RX_QUEUE + 2 * id
+    }
+}
+
+/// Where the data of a named port goes to and comes from.
+pub enum ConsolePortBackend {
+    /// The guest output is written to the file, the port having no input.
+    File(Box<dyn io::Write + Send>),
+    /// The port is connected to the client of the socket, one at a time.
+    Socket(UnixListener),
+}
+
+/// A port of the virtio console, in addition to the console port, which the
+/// guest finds by its name, e.g. under /dev/virtio-ports/ for Linux.
+pub struct ConsolePort {
+    pub name: String,
+    pub backend: ConsolePortBackend,
+}
+
+struct PortState {
+    port: ConsolePort,
+    stream: Option<UnixStream>,
+    in_buffer: VecDeque<u8>,
+    guest_open: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ConsolePortState {
+    guest_open: bool,
+    in_buffer: Vec<u8>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ConsoleState {
+    in_buffer: Vec<u8>,
+    ports: Vec<ConsolePortState>,
+}
+
+impl PortState {
+    // A port backed by a file is always connected on the host side.
+    fn host_connected(&self) -> bool {
+        match self.port.backend {
+            ConsolePortBackend::File(_) => true,
+            ConsolePortBackend::Socket(_) => self.stream.is_some(),
+        }
+    }
+}
+
+// Writes the buffered input into the empty buffers of the receive queue,
+// returning whether some were used.
+fn fill_queue(mem: &GuestMemoryMmap, queue: &mut Queue, in_buffer: &mut VecDeque<u8>) -> bool {
+    let mut used_desc_heads = Vec::new();
+
+    if in_buffer.is_empty() {
+        return false;
+    }
+
+    for avail_desc in queue.iter(mem) {
+        let len = cmp::min(avail_desc.len as u32, in_buffer.len() as u32);
+        let source_slice = in_buffer.drain(..len as usize).collect::<Vec<u8>>();
+        if let Err(e) = mem.write_slice(&source_slice[..], avail_desc.addr) {
+            error!("Failed to write slice: {:?}", e);
+            queue.go_to_previous_position();
+            break;
+        }
+
+        used_desc_heads.push((avail_desc.index, len));
+
+        if in_buffer.is_empty() {
+            break;
+        }
+    }
+
+    for &(desc_index, len) in used_desc_heads.iter() {
+        queue.add_used(mem, desc_index, len);
+    }
+
+    !used_desc_heads.is_empty()
+}
+
+// Writes the buffers of the transmit queue to `out`, returning whether some
+// were used.
+fn drain_queue(mem: &GuestMemoryMmap, queue: &mut Queue, mut out: &mut dyn io::Write) -> bool {
+    let mut used_desc_heads = Vec::new();
+
+    for avail_desc in queue.iter(mem) {
+        let _ = mem.write_to(avail_desc.addr, &mut out, avail_desc.len as usize);
+        let _ = out.flush();
+
+        used_desc_heads.push((avail_desc.index, avail_desc.len));
+    }
+
+    for &(desc_index, len) in used_desc_heads.iter() {
+        queue.add_used(mem, desc_index, len);
+    }
+    !used_desc_heads.is_empty()
+}
+
 struct ConsoleEpollHandler {
     queues: Vec<Queue>,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
@@ -63,6 +214,12 @@ struct ConsoleEpollHandler {
     out: Arc<Mutex<Box<dyn io::Write + Send + Sync + 'static>>>,
     input_queue_evt: EventFd,
     output_queue_evt: EventFd,
+    // The events of the control queues and of the named ports queues.
+    multiport_queue_evts: Vec<EventFd>,
+    ports: Arc<Mutex<Vec<PortState>>>,
+    // The control messages waiting for buffers on the control receive queue.
+    control_out: VecDeque<Vec<u8>>,
+    config: Arc<Mutex<VirtioConsoleConfig>>,
     input_evt: EventFd,
     config_evt: EventFd,
     kill_evt: EventFd,
@@ -78,83 +235,270 @@ impl ConsoleEpollHandler {
      */
     fn process_input_queue(&mut self) -> bool {
         let mut in_buffer = self.in_buffer.lock().unwrap();
-        let recv_queue = &mut self.queues[0]; //receiveq
-        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
-        let mut used_count = 0;
+        let mem = self.mem.memory();
+        fill_queue(&mem, &mut self.queues[0], &mut in_buffer) //receiveq
+    }
 
-        if in_buffer.is_empty() {
-            return false;
+    /*
+     * Each port of virtio console device has one transmit
+     * queue. For outgoing data, characters are placed in
+     * the transmit queue by the driver. Therefore, here
+     * we read data from the transmit queue and flush them
+     * to the referenced address.
+     */
+    fn process_output_queue(&mut self) -> bool {
+        let mut out = self.out.lock().unwrap();
+        let mem = self.mem.memory();
+        drain_queue(&mem, &mut self.queues[1], out.deref_mut()) //transmitq
+    }
+
+    fn send_control(&mut self, id: usize, event: u16, value: u16, data: &[u8]) {
+        self.control_out
+            .push_back(VirtioConsoleControl::message(id as u32, event, value, data));
+    }
+
+    // With named ports, the guest takes the size of the console port from a
+    // control message rather than from the configuration space.
+    fn send_console_size(&mut self) {
+        if self.queues.len() > CONTROL_RX_QUEUE {
+            let config = *self.config.lock().unwrap();
+            let mut size = config.rows.to_le_bytes().to_vec();
+            size.extend_from_slice(&config.cols.to_le_bytes());
+            self.send_control(0, VIRTIO_CONSOLE_RESIZE, 0, &size);
         }
+    }
+
+    // Each control message goes in a buffer of its own.
+    fn process_control_rx_queue(&mut self) -> bool {
+        let recv_queue = &mut self.queues[CONTROL_RX_QUEUE];
+        let mut used_desc_heads = Vec::new();
 
         let mem = self.mem.memory();
-        for avail_desc in recv_queue.iter(&mem) {
-            let len = cmp::min(avail_desc.len as u32, in_buffer.len() as u32);
-            let source_slice = in_buffer.drain(..len as usize).collect::<Vec<u8>>();
-            if let Err(e) = mem.write_slice(&source_slice[..], avail_desc.addr) {
-                error!("Failed to write slice: {:?}", e);
-                recv_queue.go_to_previous_position();
-                break;
+        while !self.control_out.is_empty() {
+            let avail_desc = match recv_queue.iter(&mem).next() {
+                Some(avail_desc) => avail_desc,
+                None => break,
+            };
+            let message = self.control_out.pop_front().unwrap();
+            let len = cmp::min(avail_desc.len as usize, message.len());
+            if let Err(e) = mem.write_slice(&message[..len], avail_desc.addr) {
+                error!("Failed to write control message: {:?}", e);
             }
+            used_desc_heads.push((avail_desc.index, len as u32));
+        }
 
-            used_desc_heads[used_count] = (avail_desc.index, len);
-            used_count += 1;
+        for &(desc_index, len) in used_desc_heads.iter() {
+            recv_queue.add_used(&mem, desc_index, len);
+        }
+        !used_desc_heads.is_empty()
+    }
+
+    fn process_control_tx_queue(&mut self) -> bool {
+        let trans_queue = &mut self.queues[CONTROL_TX_QUEUE];
+        let mut used_desc_heads = Vec::new();
+        let mut messages = Vec::new();
 
-            if in_buffer.is_empty() {
-                break;
+        let mem = self.mem.memory();
+        for avail_desc in trans_queue.iter(&mem) {
+            if avail_desc.len as usize >= std::mem::size_of::<VirtioConsoleControl>() {
+                match mem.read_obj::<VirtioConsoleControl>(avail_desc.addr) {
+                    Ok(message) => messages.push(message),
+                    Err(e) => error!("Failed to read control message: {:?}", e),
+                }
             }
+            used_desc_heads.push((avail_desc.index, avail_desc.len));
         }
 
-        for &(desc_index, len) in &used_desc_heads[..used_count] {
-            recv_queue.add_used(&mem, desc_index, len);
+        for &(desc_index, len) in used_desc_heads.iter() {
+            trans_queue.add_used(&mem, desc_index, len);
         }
+        drop(mem);
 
-        used_count > 0
+        for message in messages {
+            self.handle_control(message);
+        }
+        !used_desc_heads.is_empty()
     }
 
-    /*
-     * Each port of virtio console device has one transmit
-     * queue. For outgoing data, characters are placed in
-     * the transmit queue by the driver. Therefore, here
-     * we read data from the transmit queue and flush them
-     * to the referenced address.
-     */
-    fn process_output_queue(&mut self) -> bool {
-        let trans_queue = &mut self.queues[1]; //transmitq
-        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
-        let mut used_count = 0;
+    fn handle_control(&mut self, message: VirtioConsoleControl) {
+        let id = message.id as usize;
+        let nports = self.ports.lock().unwrap().len();
+        match message.event {
+            VIRTIO_CONSOLE_DEVICE_READY if message.value == 1 => {
+                for id in 0..=nports {
+                    self.send_control(id, VIRTIO_CONSOLE_DEVICE_ADD, 1, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_DEVICE_READY => error!("The guest failed to set the console up"),
+            VIRTIO_CONSOLE_PORT_READY if message.value == 1 => {
+                if id == 0 {
+                    self.send_control(0, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                    self.send_console_size();
+                    self.send_control(0, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+                } else if id <= nports {
+                    let (name, connected) = {
+                        let ports = self.ports.lock().unwrap();
+                        let port = &ports[id - 1];
+                        (port.port.name.clone(), port.host_connected())
+                    };
+                    self.send_control(id, VIRTIO_CONSOLE_PORT_NAME, 1, name.as_bytes());
+                    if connected {
+                        self.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+                    }
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY => error!("The guest failed to set the port {} up", id),
+            VIRTIO_CONSOLE_PORT_OPEN if id > 0 && id <= nports => {
+                self.ports.lock().unwrap()[id - 1].guest_open = message.value == 1;
+                // Some input may have been waiting for the guest.
+                if self.process_port_rx_queue(id) {
+                    if let Err(e) = self.signal_used_queue(port_rx_queue(id)) {
+                        error!("Failed to signal used queue: {:?}", e);
+                    }
+                }
+            }
+            event => debug!("Ignoring console control event {} for port {}", event, id),
+        }
+    }
 
+    // The input is only given to the guest once it opened the port, as the
+    // guest discards it otherwise.
+    fn process_port_rx_queue(&mut self, id: usize) -> bool {
+        let mut ports = self.ports.lock().unwrap();
+        let port = &mut ports[id - 1];
+        if !port.guest_open {
+            return false;
+        }
         let mem = self.mem.memory();
-        for avail_desc in trans_queue.iter(&mem) {
-            let len;
-            let mut out = self.out.lock().unwrap();
-            let _ = mem.write_to(
-                avail_desc.addr,
-                &mut out.deref_mut(),
-                avail_desc.len as usize,
-            );
-            let _ = out.flush();
+        fill_queue(
+            &mem,
+            &mut self.queues[port_rx_queue(id)],
+            &mut port.in_buffer,
+        )
+    }
 
-            len = avail_desc.len;
-            used_desc_heads[used_count] = (avail_desc.index, len);
-            used_count += 1;
+    // The output of a port backed by a socket without any client is
+    // discarded.
+    fn process_port_tx_queue(&mut self, id: usize) -> bool {
+        let mut ports = self.ports.lock().unwrap();
+        let port = &mut ports[id - 1];
+        let mem = self.mem.memory();
+        let queue = &mut self.queues[port_rx_queue(id) + 1];
+        match (&mut port.port.backend, &mut port.stream) {
+            (ConsolePortBackend::File(out), _) => drain_queue(&mem, queue, out.deref_mut()),
+            (ConsolePortBackend::Socket(_), Some(stream)) => drain_queue(&mem, queue, stream),
+            (ConsolePortBackend::Socket(_), None) => drain_queue(&mem, queue, &mut io::sink()),
         }
+    }
 
-        for &(desc_index, len) in &used_desc_heads[..used_count] {
-            trans_queue.add_used(&mem, desc_index, len);
+    fn accept_port_client(
+        &mut self,
+        epoll_fd: RawFd,
+        id: usize,
+    ) -> result::Result<(), DeviceError> {
+        let mut ports = self.ports.lock().unwrap();
+        let port = &mut ports[id - 1];
+        let stream = match &port.port.backend {
+            ConsolePortBackend::Socket(listener) => match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Failed to accept console port client: {:?}", e);
+                    return Ok(());
+                }
+            },
+            ConsolePortBackend::File(_) => return Ok(()),
+        };
+        // One client at a time, the next ones being disconnected.
+        if port.stream.is_some() {
+            warn!("Console port {} already has a client", port.port.name);
+            return Ok(());
         }
-        used_count > 0
+        stream.set_nonblocking(true).map_err(DeviceError::IoError)?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            stream.as_raw_fd(),
+            epoll::Event::new(
+                epoll::Events::EPOLLIN,
+                u64::from(PORT_STREAM_EVENT | id as u16),
+            ),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+        port.stream = Some(stream);
+        drop(ports);
+
+        self.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+        Ok(())
     }
 
-    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+    fn read_port_client(&mut self, epoll_fd: RawFd, id: usize) -> result::Result<(), DeviceError> {
+        let mut ports = self.ports.lock().unwrap();
+        let port = &mut ports[id - 1];
+        let stream = match port.stream.as_mut() {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        let mut buf = [0u8; 4096];
+        let closed = loop {
+            match stream.read(&mut buf) {
+                Ok(0) => break true,
+                Ok(count) => port.in_buffer.extend(&buf[..count]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break false,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    error!("Failed to read from console port client: {:?}", e);
+                    break true;
+                }
+            }
+        };
+        if closed {
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_DEL,
+                stream.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, 0),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+            port.stream = None;
+            port.in_buffer.clear();
+        }
+        drop(ports);
+
+        if closed {
+            self.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 0, &[]);
+        } else if self.process_port_rx_queue(id) {
+            self.signal_used_queue(port_rx_queue(id))?;
+        }
+        Ok(())
+    }
+
+    fn process_multiport_queue(&mut self, index: usize) -> result::Result<(), DeviceError> {
+        let used = match index {
+            CONTROL_RX_QUEUE => false,
+            CONTROL_TX_QUEUE => self.process_control_tx_queue(),
+            index if index % 2 == 0 => self.process_port_rx_queue((index - CONTROL_RX_QUEUE) / 2),
+            index => self.process_port_tx_queue((index - CONTROL_TX_QUEUE) / 2),
+        };
+        if used {
+            self.signal_used_queue(index)?;
+        }
+        Ok(())
+    }
+
+    fn signal_used_queue(&self, index: usize) -> result::Result<(), DeviceError> {
         self.interrupt_cb
-            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[0]))
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[index]))
             .map_err(|e| {
                 error!("Failed to signal used queue: {:?}", e);
                 DeviceError::FailedSignalingUsedQueue(e)
             })
     }
 
-    fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), DeviceError> {
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), DeviceError> {
         // Create the epoll file descriptor
         let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
 
@@ -203,6 +547,41 @@ impl ConsoleEpollHandler {
         )
         .map_err(DeviceError::EpollCtl)?;
 
+        for (index, queue_evt) in self.multiport_queue_evts.iter().enumerate() {
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                queue_evt.as_raw_fd(),
+                epoll::Event::new(
+                    epoll::Events::EPOLLIN,
+                    u64::from(MULTIPORT_QUEUE_EVENT | (CONTROL_RX_QUEUE + index) as u16),
+                ),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+        for (index, port) in self.ports.lock().unwrap().iter().enumerate() {
+            let id = index as u16 + 1;
+            if let ConsolePortBackend::Socket(listener) = &port.port.backend {
+                epoll::ctl(
+                    epoll_fd,
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    listener.as_raw_fd(),
+                    epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PORT_LISTENER_EVENT | id)),
+                )
+                .map_err(DeviceError::EpollCtl)?;
+            }
+            // The client may have connected before the device got reset.
+            if let Some(stream) = &port.stream {
+                epoll::ctl(
+                    epoll_fd,
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    stream.as_raw_fd(),
+                    epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PORT_STREAM_EVENT | id)),
+                )
+                .map_err(DeviceError::EpollCtl)?;
+            }
+        }
+
         const EPOLL_EVENTS_LEN: usize = 100;
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
 
@@ -233,7 +612,7 @@ impl ConsoleEpollHandler {
                             error!("Failed to get queue event: {:?}", e);
                             break 'epoll;
                         } else if self.process_input_queue() {
-                            if let Err(e) = self.signal_used_queue() {
+                            if let Err(e) = self.signal_used_queue(0) {
                                 error!("Failed to signal used queue: {:?}", e);
                                 break 'epoll;
                             }
@@ -252,7 +631,7 @@ impl ConsoleEpollHandler {
                             error!("Failed to get input event: {:?}", e);
                             break 'epoll;
                         } else if self.process_input_queue() {
-                            if let Err(e) = self.signal_used_queue() {
+                            if let Err(e) = self.signal_used_queue(0) {
                                 error!("Failed to signal used queue: {:?}", e);
                                 break 'epoll;
                             }
@@ -262,7 +641,9 @@ impl ConsoleEpollHandler {
                         if let Err(e) = self.config_evt.read() {
                             error!("Failed to get config event: {:?}", e);
                             break 'epoll;
-                        } else if let Err(e) = self
+                        }
+                        self.send_console_size();
+                        if let Err(e) = self
                             .interrupt_cb
                             .trigger(&VirtioInterruptType::Config, None)
                         {
@@ -276,11 +657,29 @@ impl ConsoleEpollHandler {
                     }
                     PAUSE_EVENT => {
                         debug!("PAUSE_EVENT received, pausing virtio-console epoll loop");
-                        // We loop here to handle spurious park() returns.
-                        // Until we have not resumed, the paused boolean will
-                        // be true.
-                        while paused.load(Ordering::SeqCst) {
-                            thread::park();
+                        wait_for_resume(&paused, &paused_sync);
+                    }
+                    ev_type if ev_type & 0xff00 == MULTIPORT_QUEUE_EVENT => {
+                        let index = (ev_type & 0xff) as usize;
+                        if let Err(e) = self.multiport_queue_evts[index - CONTROL_RX_QUEUE].read() {
+                            error!("Failed to get queue event: {:?}", e);
+                            break 'epoll;
+                        } else if let Err(e) = self.process_multiport_queue(index) {
+                            error!("Failed to process queue {}: {:?}", index, e);
+                            break 'epoll;
+                        }
+                    }
+                    ev_type if ev_type & 0xff00 == PORT_LISTENER_EVENT => {
+                        if let Err(e) = self.accept_port_client(epoll_fd, (ev_type & 0xff) as usize)
+                        {
+                            error!("Failed to connect console port client: {:?}", e);
+                            break 'epoll;
+                        }
+                    }
+                    ev_type if ev_type & 0xff00 == PORT_STREAM_EVENT => {
+                        if let Err(e) = self.read_port_client(epoll_fd, (ev_type & 0xff) as usize) {
+                            error!("Failed to read console port client: {:?}", e);
+                            break 'epoll;
                         }
                     }
                     _ => {
@@ -288,6 +687,14 @@ impl ConsoleEpollHandler {
                     }
                 }
             }
+
+            // The control messages queued while handling the events.
+            if !self.control_out.is_empty() && self.process_control_rx_queue() {
+                if let Err(e) = self.signal_used_queue(CONTROL_RX_QUEUE) {
+                    error!("Failed to signal used queue: {:?}", e);
+                    break 'epoll;
+                }
+            }
         }
 
         Ok(())
@@ -311,12 +718,9 @@ impl ConsoleInput {
     }
 
     pub fn update_console_size(&self, cols: u16, rows: u16) {
-        if self
-            .acked_features
-            .fetch_and(1u64 << VIRTIO_CONSOLE_F_SIZE, Ordering::SeqCst)
-            != 0
-        {
-            self.config.lock().unwrap().update_console_size(cols, rows);
+        // Kept for the driver to find, even if it isn't told yet.
+        self.config.lock().unwrap().update_console_size(cols, rows);
+        if self.acked_features.load(Ordering::SeqCst) & (1u64 << VIRTIO_CONSOLE_F_SIZE) != 0 {
             //Send the interrupt to the driver
             let _ = self.config_evt.write(1);
         }
@@ -324,11 +728,11 @@ impl ConsoleInput {
 }
 
 impl VirtioConsoleConfig {
-    pub fn new(cols: u16, rows: u16) -> Self {
+    pub fn new(cols: u16, rows: u16, max_nr_ports: u32) -> Self {
         VirtioConsoleConfig {
             cols,
             rows,
-            max_nr_ports: 1u32,
+            max_nr_ports,
             emerg_wr: 0u32,
         }
     }
@@ -348,19 +752,27 @@ pub struct Console {
     config: Arc<Mutex<VirtioConsoleConfig>>,
     input: Arc<ConsoleInput>,
     out: Arc<Mutex<Box<dyn io::Write + Send + Sync + 'static>>>,
+    ports: Arc<Mutex<Vec<PortState>>>,
+    queue_size: Vec<u16>,
     queue_evts: Option<Vec<EventFd>>,
     interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
     epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
     paused: Arc<AtomicBool>,
+    paused_sync: Option<Arc<Barrier>>,
+    // Whether the guest had opened the ports, when restoring a snapshot.
+    restored_guest_open: Option<Vec<bool>>,
 }
 
 impl Console {
-    /// Create a new virtio console device that gets random data from /dev/urandom.
+    /// Create a new virtio console device writing the console port output to
+    /// `out`, with the named `ports` in addition to the console port.
     pub fn new(
         out: Box<dyn io::Write + Send + Sync + 'static>,
+        ports: Vec<ConsolePort>,
         cols: u16,
         rows: u16,
         iommu: bool,
+        queue_size: u16,
     ) -> io::Result<(Console, Arc<ConsoleInput>)> {
         let mut avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_CONSOLE_F_SIZE;
 
@@ -368,9 +780,41 @@ impl Console {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
         }
 
+        if ports.len() > MAX_NAMED_PORTS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("more than {} console ports", MAX_NAMED_PORTS),
+            ));
+        }
+
+        // The guest only sets the queues of the named ports and the control
+        // queues up when it supports several ports.
+        let mut num_queues = NUM_QUEUES;
+        if !ports.is_empty() {
+            avail_features |= 1u64 << VIRTIO_CONSOLE_F_MULTIPORT;
+            num_queues += 2 + 2 * ports.len();
+        }
+
+        let mut port_states = Vec::new();
+        for port in ports {
+            if let ConsolePortBackend::Socket(listener) = &port.backend {
+                listener.set_nonblocking(true)?;
+            }
+            port_states.push(PortState {
+                port,
+                stream: None,
+                in_buffer: VecDeque::new(),
+                guest_open: false,
+            });
+        }
+
         let input_evt = EventFd::new(EFD_NONBLOCK).unwrap();
         let config_evt = EventFd::new(EFD_NONBLOCK).unwrap();
-        let console_config = Arc::new(Mutex::new(VirtioConsoleConfig::new(cols, rows)));
+        let console_config = Arc::new(Mutex::new(VirtioConsoleConfig::new(
+            cols,
+            rows,
+            1 + port_states.len() as u32,
+        )));
         let console_input = Arc::new(ConsoleInput {
             input_evt,
             config_evt,
@@ -388,10 +832,14 @@ impl Console {
                 config: console_config,
                 input: console_input.clone(),
                 out: Arc::new(Mutex::new(out)),
+                ports: Arc::new(Mutex::new(port_states)),
+                queue_size: vec![queue_size; num_queues],
                 queue_evts: None,
                 interrupt_cb: None,
                 epoll_threads: None,
                 paused: Arc::new(AtomicBool::new(false)),
+                paused_sync: None,
+                restored_guest_open: None,
             },
             console_input,
         ))
@@ -413,7 +861,7 @@ impl VirtioDevice for Console {
     }
 
     fn queue_max_sizes(&self) -> &[u16] {
-        QUEUE_SIZES
+        self.queue_size.as_slice()
     }
 
     fn features(&self) -> u64 {
@@ -460,10 +908,11 @@ impl VirtioDevice for Console {
         queues: Vec<Queue>,
         mut queue_evts: Vec<EventFd>,
     ) -> ActivateResult {
-        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+        let num_queues = self.queue_size.len();
+        if queues.len() != num_queues || queue_evts.len() != num_queues {
             error!(
                 "Cannot perform activate. Expected {} queue(s), got {}",
-                NUM_QUEUES,
+                num_queues,
                 queues.len()
             );
             return Err(ActivateError::BadActivate);
@@ -511,6 +960,13 @@ impl VirtioDevice for Console {
             }
         }
 
+        // The guest opens the ports again once it has set them up, unless
+        // they are restored as it had left them.
+        let guest_open = self.restored_guest_open.take().unwrap_or_default();
+        for (i, port) in self.ports.lock().unwrap().iter_mut().enumerate() {
+            port.guest_open = guest_open.get(i).copied().unwrap_or(false);
+        }
+
         let mut handler = ConsoleEpollHandler {
             queues,
             mem,
@@ -519,6 +975,10 @@ impl VirtioDevice for Console {
             out: self.out.clone(),
             input_queue_evt: queue_evts.remove(0),
             output_queue_evt: queue_evts.remove(0),
+            multiport_queue_evts: queue_evts,
+            ports: self.ports.clone(),
+            control_out: VecDeque::new(),
+            config: self.config.clone(),
             input_evt: self.input.input_evt.try_clone().unwrap(),
             config_evt: self.input.config_evt.try_clone().unwrap(),
             kill_evt,
@@ -526,10 +986,12 @@ impl VirtioDevice for Console {
         };
 
         let paused = self.paused.clone();
+        let paused_sync = Arc::new(Barrier::new(2));
+        let paused_sync_clone = paused_sync.clone();
         let mut epoll_threads = Vec::new();
         thread::Builder::new()
             .name("virtio_console".to_string())
-            .spawn(move || handler.run(paused))
+            .spawn(move || handler.run(paused, paused_sync_clone))
             .map(|thread| epoll_threads.push(thread))
             .map_err(|e| {
                 error!("failed to clone the virtio-console epoll thread: {}", e);
@@ -537,6 +999,7 @@ impl VirtioDevice for Console {
             })?;
 
         self.epoll_threads = Some(epoll_threads);
+        self.paused_sync = Some(paused_sync);
 
         Ok(())
     }
@@ -561,5 +1024,118 @@ impl VirtioDevice for Console {
 }
 
 virtio_pausable!(Console);
-impl Snapshotable for Console {}
+impl Snapshotable for Console {
+    fn id(&self) -> String {
+        "virtio-console".to_string()
+    }
+
+    fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        VirtioDeviceState::new(
+            self.avail_features,
+            self.acked_features,
+            self.config.lock().unwrap().as_slice(),
+        )
+        .add_to(&mut snapshot)?;
+        let ports = self
+            .ports
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|port| ConsolePortState {
+                guest_open: port.guest_open,
+                in_buffer: port.in_buffer.iter().copied().collect(),
+            })
+            .collect();
+        snapshot.add_state(
+            "console",
+            &ConsoleState {
+                in_buffer: self
+                    .input
+                    .in_buffer
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .copied()
+                    .collect(),
+                ports,
+            },
+        )?;
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        let virtio_state = VirtioDeviceState::from(&snapshot)?;
+        let state: ConsoleState = snapshot.state("console")?;
+        let mut ports = self.ports.lock().unwrap();
+        if state.ports.len() != ports.len() {
+            return Err(MigratableError::Restore(anyhow!(
+                "Invalid number of console ports: {}",
+                state.ports.len()
+            )));
+        }
+
+        self.acked_features = virtio_state.acked_features(self.avail_features)?;
+        virtio_state.restore_config(self.config.lock().unwrap().deref_mut())?;
+        *self.input.in_buffer.lock().unwrap() = state.in_buffer.into_iter().collect();
+        for (port, port_state) in ports.iter_mut().zip(state.ports.iter()) {
+            port.in_buffer = port_state.in_buffer.iter().copied().collect();
+        }
+        self.restored_guest_open = Some(state.ports.iter().map(|p| p.guest_open).collect());
+        Ok(())
+    }
+}
 impl Migratable for Console {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_message() {
+        assert_eq!(
+            VirtioConsoleControl::message(2, VIRTIO_CONSOLE_PORT_NAME, 1, b"agent"),
+            vec![2, 0, 0, 0, 7, 0, 1, 0, b'a', b'g', b'e', b'n', b't']
+        );
+    }
+
+    #[test]
+    fn test_multiport_queues() {
+        let (console, _) = Console::new(
+            Box::new(io::sink()),
+            vec![ConsolePort {
+                name: "log".to_string(),
+                backend: ConsolePortBackend::File(Box::new(io::sink())),
+            }],
+            80,
+            25,
+            false,
+            64,
+        )
+        .unwrap();
+        assert_eq!(console.queue_max_sizes().len(), 6);
+        assert_ne!(console.features() & (1u64 << VIRTIO_CONSOLE_F_MULTIPORT), 0);
+        assert_eq!(console.config.lock().unwrap().max_nr_ports, 2);
+        assert_eq!(port_rx_queue(0), 0);
+        assert_eq!(port_rx_queue(1), 4);
+    }
+
+    #[test]
+    fn test_update_console_size() {
+        let (console, input) =
+            Console::new(Box::new(io::sink()), Vec::new(), 80, 25, false, 64).unwrap();
+
+        // Not told to the driver before it acks the size feature.
+        input.update_console_size(100, 30);
+        assert!(input.config_evt.read().is_err());
+
+        let acked_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_CONSOLE_F_SIZE;
+        input.acked_features.store(acked_features, Ordering::SeqCst);
+        input.update_console_size(120, 40);
+        assert_eq!(input.config_evt.read().unwrap(), 1);
+        assert_eq!(input.acked_features.load(Ordering::SeqCst), acked_features);
+
+        let config = *console.config.lock().unwrap();
+        assert_eq!((config.cols, config.rows), (120, 40));
+    }
+}
@@ -7,8 +7,12 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use super::*;
-use std::sync::Arc;
-use vm_memory::{GuestAddress, GuestMemoryAtomic, GuestMemoryMmap, GuestUsize};
+use anyhow::anyhow;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use vm_device::{MigratableError, Snapshot};
+use vm_memory::{ByteValued, GuestAddress, GuestMemoryAtomic, GuestMemoryMmap, GuestUsize};
 use vmm_sys_util::eventfd::EventFd;
 
 pub enum VirtioInterruptType {
@@ -97,6 +101,12 @@ pub trait VirtioDevice: Send {
         None
     }
 
+    /// Whether the transport should also expose the legacy interface, for
+    /// drivers predating virtio 1.0.
+    fn transitional(&self) -> bool {
+        false
+    }
+
     fn iommu_translate(&self, addr: u64) -> u64 {
         addr
     }
@@ -108,6 +118,89 @@ pub trait VirtioDevice: Send {
     fn shutdown(&mut self) {}
 }
 
+/// The state all the virtio devices have, saved in their snapshots as the
+/// "virtio" section next to the state of their own.
+#[derive(Deserialize, Serialize)]
+pub struct VirtioDeviceState {
+    pub avail_features: u64,
+    pub acked_features: u64,
+    pub config: Vec<u8>,
+}
+
+impl VirtioDeviceState {
+    pub fn new(avail_features: u64, acked_features: u64, config: &[u8]) -> Self {
+        VirtioDeviceState {
+            avail_features,
+            acked_features,
+            config: config.to_vec(),
+        }
+    }
+
+    pub fn add_to(&self, snapshot: &mut Snapshot) -> std::result::Result<(), MigratableError> {
+        snapshot.add_state("virtio", self)
+    }
+
+    pub fn from(snapshot: &Snapshot) -> std::result::Result<Self, MigratableError> {
+        snapshot.state("virtio")
+    }
+
+    /// The features the driver had acked, checking the device still offers
+    /// them, e.g. it isn't backed by a tap device without offloads now.
+    pub fn acked_features(&self, avail_features: u64) -> std::result::Result<u64, MigratableError> {
+        if self.acked_features & !avail_features != 0 {
+            return Err(MigratableError::Restore(anyhow!(
+                "Acked features 0x{:x} not offered anymore, only 0x{:x} are",
+                self.acked_features,
+                avail_features
+            )));
+        }
+        Ok(self.acked_features)
+    }
+
+    /// Copies the saved configuration space into `config`.
+    pub fn restore_config<T: ByteValued>(
+        &self,
+        config: &mut T,
+    ) -> std::result::Result<(), MigratableError> {
+        let slice = config.as_mut_slice();
+        if self.config.len() != slice.len() {
+            return Err(MigratableError::Restore(anyhow!(
+                "Invalid configuration space length: {}",
+                self.config.len()
+            )));
+        }
+        slice.copy_from_slice(&self.config);
+        Ok(())
+    }
+}
+
+/// Waits for the epoll threads of a device, once told to stop, so that the
+/// requests they were processing complete before the backend goes away.
+pub(crate) fn join_epoll_threads(
+    device: &str,
+    threads: Vec<std::thread::JoinHandle<std::result::Result<(), Error>>>,
+) {
+    for thread in threads {
+        match thread.join() {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => error!("{} epoll thread failed: {:?}", device, e),
+            Err(e) => error!("{} epoll thread panicked: {:?}", device, e),
+        }
+    }
+}
+
+/// Acknowledges a pause from an epoll thread of a device, the device waiting
+/// for all its epoll threads to have stopped processing the queues, then
+/// parks the thread until the device is resumed.
+pub fn wait_for_resume(paused: &AtomicBool, paused_sync: &Barrier) {
+    paused_sync.wait();
+    // We loop here to handle spurious park() returns. Until we have not
+    // resumed, the paused boolean will be true.
+    while paused.load(Ordering::SeqCst) {
+        thread::park();
+    }
+}
+
 /// Trait providing address translation the same way a physical DMA remapping
 /// table would provide translation between an IOVA and a physical address.
 /// The goal of this trait is to be used by virtio devices to perform the
@@ -141,6 +234,12 @@ macro_rules! virtio_pausable_trait_inner {
                 pause_evt
                     .write(1)
                     .map_err(|e| MigratableError::Pause(e.into()))?;
+
+                // Wait for the epoll threads to acknowledge the pause, for
+                // the queues not to be processed anymore once we return.
+                if let Some(paused_sync) = &self.paused_sync {
+                    paused_sync.wait();
+                }
             }
 
             Ok(())
@@ -152,6 +251,11 @@ macro_rules! virtio_pausable_trait_inner {
                 VirtioDeviceType::from(self.device_type())
             );
             self.paused.store(false, Ordering::SeqCst);
+            // Drain the pause event before waking the epoll threads up, for
+            // them not to see it again.
+            if let Some(pause_evt) = &self.pause_evt {
+                let _ = pause_evt.read();
+            }
             if let Some(epoll_threads) = &self.epoll_threads {
                 for i in 0..epoll_threads.len() {
                     epoll_threads[i].thread().unpark();
@@ -7,7 +7,7 @@ use super::{
     ActivateError, ActivateResult, DescriptorChain, DeviceEventT, Queue, VirtioDevice,
     VirtioDeviceType, VIRTIO_F_VERSION_1,
 };
-use crate::{DmaRemapping, VirtioInterrupt, VirtioInterruptType};
+use crate::{wait_for_resume, DmaRemapping, VirtioInterrupt, VirtioInterruptType};
 use epoll;
 use libc::EFD_NONBLOCK;
 use std::cmp;
@@ -19,7 +19,7 @@ use std::ops::Bound::Included;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Barrier, RwLock};
 use std::thread;
 use vm_device::{ExternalDmaMapping, Migratable, MigratableError, Pausable, Snapshotable};
 use vm_memory::{
@@ -57,6 +57,15 @@ const PROBE_PROP_SIZE: u32 =
 const MSI_IOVA_START: u64 = 0xfee0_0000;
 const MSI_IOVA_END: u64 = 0xfeef_ffff;
 
+// The MSI doorbells are reported as reserved, and the endpoints write to them
+// untranslated. The VMM, and the host interrupt remapping for the assigned
+// devices, only deliver the MSIs programmed for the endpoint, keeping them
+// isolated. Mapping the doorbells to memory is refused, as it would break
+// the MSIs of the endpoint.
+fn overlaps_msi_doorbells(virt_start: u64, virt_end: u64) -> bool {
+    virt_start <= MSI_IOVA_END && virt_end >= MSI_IOVA_START
+}
+
 /// Virtio IOMMU features
 #[allow(unused)]
 const VIRTIO_IOMMU_F_INPUT_RANGE: u32 = 0;
@@ -169,7 +178,6 @@ const VIRTIO_IOMMU_S_UNSUPP: u8 = 2;
 const VIRTIO_IOMMU_S_DEVERR: u8 = 3;
 #[allow(unused)]
 const VIRTIO_IOMMU_S_INVAL: u8 = 4;
-#[allow(unused)]
 const VIRTIO_IOMMU_S_RANGE: u8 = 5;
 #[allow(unused)]
 const VIRTIO_IOMMU_S_NOENT: u8 = 6;
@@ -408,6 +416,7 @@ impl Request {
 
         // Create the reply
         let mut reply: Vec<u8> = Vec::new();
+        let mut status = VIRTIO_IOMMU_S_OK;
 
         let hdr_len = match req_head.type_ {
             VIRTIO_IOMMU_T_ATTACH => {
@@ -481,25 +490,30 @@ impl Request {
                 // Copy the value to use it as a proper reference.
                 let domain = req.domain;
 
-                // Trigger external mapping if necessary.
-                if let Some(ext_map) = ext_domain_mapping.get(&domain) {
-                    let size = req.virt_end - req.virt_start + 1;
-                    ext_map
-                        .map(req.virt_start, req.phys_start, size)
-                        .map_err(Error::ExternalMapping)?;
-                }
-
-                // Add new mapping associated with the domain
-                if let Some(entry) = mapping.mappings.write().unwrap().get_mut(&domain) {
-                    entry.insert(
-                        req.virt_start,
-                        Mapping {
-                            gpa: req.phys_start,
-                            size: req.virt_end - req.virt_start + 1,
-                        },
-                    );
+                if overlaps_msi_doorbells(req.virt_start, req.virt_end) {
+                    warn!("Refusing to map the MSI doorbells for domain {}", domain);
+                    status = VIRTIO_IOMMU_S_RANGE;
                 } else {
-                    return Err(Error::InvalidMapRequest);
+                    // Trigger external mapping if necessary.
+                    if let Some(ext_map) = ext_domain_mapping.get(&domain) {
+                        let size = req.virt_end - req.virt_start + 1;
+                        ext_map
+                            .map(req.virt_start, req.phys_start, size)
+                            .map_err(Error::ExternalMapping)?;
+                    }
+
+                    // Add new mapping associated with the domain
+                    if let Some(entry) = mapping.mappings.write().unwrap().get_mut(&domain) {
+                        entry.insert(
+                            req.virt_start,
+                            Mapping {
+                                gpa: req.phys_start,
+                                size: req.virt_end - req.virt_start + 1,
+                            },
+                        );
+                    } else {
+                        return Err(Error::InvalidMapRequest);
+                    }
                 }
 
                 0
@@ -576,7 +590,7 @@ impl Request {
         }
 
         let tail = VirtioIommuReqTail {
-            status: VIRTIO_IOMMU_S_OK,
+            status,
             ..Default::default()
         };
         reply.extend_from_slice(tail.as_slice());
@@ -643,7 +657,11 @@ impl IommuEpollHandler {
             })
     }
 
-    fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), DeviceError> {
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), DeviceError> {
         // Create the epoll file descriptor
         let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
 
@@ -730,12 +748,7 @@ impl IommuEpollHandler {
                     }
                     PAUSE_EVENT => {
                         debug!("PAUSE_EVENT received, pausing virtio-iommu epoll loop");
-                        // We loop here to handle spurious park() returns.
-                        // Until we have not resumed, the paused boolean will
-                        // be true.
-                        while paused.load(Ordering::SeqCst) {
-                            thread::park();
-                        }
+                        wait_for_resume(&paused, &paused_sync);
                     }
                     _ => {
                         error!("Unknown event for virtio-iommu");
@@ -802,6 +815,7 @@ pub struct Iommu {
     interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
     epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
     paused: Arc<AtomicBool>,
+    paused_sync: Option<Arc<Barrier>>,
 }
 
 impl Iommu {
@@ -833,6 +847,7 @@ impl Iommu {
                 interrupt_cb: None,
                 epoll_threads: None,
                 paused: Arc::new(AtomicBool::new(false)),
+                paused_sync: None,
             },
             mapping,
         ))
@@ -1001,10 +1016,12 @@ impl VirtioDevice for Iommu {
         };
 
         let paused = self.paused.clone();
+        let paused_sync = Arc::new(Barrier::new(2));
+        let paused_sync_clone = paused_sync.clone();
         let mut epoll_threads = Vec::new();
         thread::Builder::new()
             .name("virtio_iommu".to_string())
-            .spawn(move || handler.run(paused))
+            .spawn(move || handler.run(paused, paused_sync_clone))
             .map(|thread| epoll_threads.push(thread))
             .map_err(|e| {
                 error!("failed to clone the virtio-iommu epoll thread: {}", e);
@@ -1012,6 +1029,7 @@ impl VirtioDevice for Iommu {
             })?;
 
         self.epoll_threads = Some(epoll_threads);
+        self.paused_sync = Some(paused_sync);
 
         Ok(())
     }
@@ -1036,5 +1054,9 @@ impl VirtioDevice for Iommu {
 }
 
 virtio_pausable!(Iommu);
-impl Snapshotable for Iommu {}
+impl Snapshotable for Iommu {
+    fn id(&self) -> String {
+        "virtio-iommu".to_string()
+    }
+}
 impl Migratable for Iommu {}
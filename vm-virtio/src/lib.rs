@@ -10,12 +10,18 @@
 
 //! Implements virtio devices, queues, and transport mechanisms.
 
+extern crate anyhow;
 extern crate arc_swap;
 extern crate epoll;
+#[cfg(feature = "io_uring")]
+extern crate io_uring;
 #[macro_use]
 extern crate log;
 #[cfg(feature = "pci_support")]
 extern crate pci;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate vhost_rs;
 extern crate virtio_bindings;
 extern crate vm_device;
@@ -26,28 +32,46 @@ use std::io;
 
 #[macro_use]
 mod device;
+mod balloon;
 pub mod block;
+#[cfg(feature = "io_uring")]
+mod block_io_uring;
+mod changed_blocks;
 mod console;
 mod iommu;
+mod mem;
+mod nbd;
 pub mod net;
 pub mod net_util;
 mod pmem;
 mod queue;
+pub mod rate_limiter;
 mod rng;
+mod scsi;
+pub mod vdpa;
 pub mod vsock;
 
 pub mod transport;
 pub mod vhost_user;
 
+pub use self::balloon::*;
 pub use self::block::*;
+#[cfg(feature = "io_uring")]
+pub use self::block_io_uring::*;
+pub use self::changed_blocks::{ChangedBlocks, Error as ChangedBlocksError, CHANGED_BLOCK_SIZE};
 pub use self::console::*;
 pub use self::device::*;
 pub use self::iommu::*;
+pub use self::mem::*;
+pub use self::nbd::*;
 pub use self::net::*;
 pub use self::net_util::*;
 pub use self::pmem::*;
 pub use self::queue::*;
+pub use self::rate_limiter::*;
 pub use self::rng::*;
+pub use self::scsi::*;
+pub use self::vdpa::Vdpa;
 pub use self::vsock::*;
 
 const DEVICE_INIT: u32 = 0x00;
@@ -57,8 +81,8 @@ const DEVICE_DRIVER_OK: u32 = 0x04;
 const DEVICE_FEATURES_OK: u32 = 0x08;
 const DEVICE_FAILED: u32 = 0x80;
 
-const VIRTIO_F_VERSION_1: u32 = 32;
-const VIRTIO_F_IOMMU_PLATFORM: u32 = 33;
+pub const VIRTIO_F_VERSION_1: u32 = 32;
+pub const VIRTIO_F_IOMMU_PLATFORM: u32 = 33;
 const VIRTIO_F_IN_ORDER: u32 = 35;
 
 // Types taken from linux/virtio_ids.h
@@ -66,17 +90,19 @@ const VIRTIO_F_IN_ORDER: u32 = 35;
 #[allow(dead_code)]
 #[allow(non_camel_case_types)]
 #[repr(C)]
-enum VirtioDeviceType {
+pub enum VirtioDeviceType {
     TYPE_NET = 1,
     TYPE_BLOCK = 2,
     TYPE_CONSOLE = 3,
     TYPE_RNG = 4,
     TYPE_BALLOON = 5,
+    TYPE_SCSI = 8,
     TYPE_9P = 9,
     TYPE_GPU = 16,
     TYPE_INPUT = 18,
     TYPE_VSOCK = 19,
     TYPE_IOMMU = 23,
+    TYPE_MEM = 24,
     TYPE_FS = 26,
     TYPE_PMEM = 27,
     TYPE_UNKNOWN = 0xFF,
@@ -90,11 +116,13 @@ impl From<u32> for VirtioDeviceType {
             3 => VirtioDeviceType::TYPE_CONSOLE,
             4 => VirtioDeviceType::TYPE_RNG,
             5 => VirtioDeviceType::TYPE_BALLOON,
+            8 => VirtioDeviceType::TYPE_SCSI,
             9 => VirtioDeviceType::TYPE_9P,
             16 => VirtioDeviceType::TYPE_GPU,
             18 => VirtioDeviceType::TYPE_INPUT,
             19 => VirtioDeviceType::TYPE_VSOCK,
             23 => VirtioDeviceType::TYPE_IOMMU,
+            24 => VirtioDeviceType::TYPE_MEM,
             26 => VirtioDeviceType::TYPE_FS,
             27 => VirtioDeviceType::TYPE_PMEM,
             _ => VirtioDeviceType::TYPE_UNKNOWN,
@@ -113,11 +141,13 @@ impl fmt::Display for VirtioDeviceType {
             VirtioDeviceType::TYPE_CONSOLE => "console",
             VirtioDeviceType::TYPE_RNG => "rng",
             VirtioDeviceType::TYPE_BALLOON => "balloon",
+            VirtioDeviceType::TYPE_SCSI => "scsi",
             VirtioDeviceType::TYPE_GPU => "gpu",
             VirtioDeviceType::TYPE_9P => "9p",
             VirtioDeviceType::TYPE_INPUT => "input",
             VirtioDeviceType::TYPE_VSOCK => "vsock",
             VirtioDeviceType::TYPE_IOMMU => "iommu",
+            VirtioDeviceType::TYPE_MEM => "mem",
             VirtioDeviceType::TYPE_FS => "fs",
             VirtioDeviceType::TYPE_PMEM => "pmem",
             VirtioDeviceType::TYPE_UNKNOWN => "UNKNOWN",
@@ -151,6 +181,8 @@ pub enum ActivateError {
     VhostUserBlkSetup(vhost_user::Error),
     /// Failed to reset vhost-user daemon.
     VhostUserReset(vhost_user::Error),
+    /// Failed to set the vDPA device up.
+    VdpaSetup(vdpa::Error),
 }
 
 pub type ActivateResult = std::result::Result<(), ActivateError>;
@@ -0,0 +1,847 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, DeviceEventT, Queue, VirtioDevice, VirtioDeviceType,
+    VIRTIO_F_VERSION_1,
+};
+use crate::{wait_for_resume, VirtioDeviceState, VirtioInterrupt, VirtioInterruptType};
+use anyhow::anyhow;
+use epoll;
+use libc::EFD_NONBLOCK;
+use std::cmp;
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use vm_device::{
+    get_host_address_range, Migratable, MigratableError, Pausable, Snapshot, Snapshotable,
+};
+use vm_memory::{
+    ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap,
+};
+use vmm_sys_util::eventfd::EventFd;
+
+const QUEUE_SIZE: u16 = 128;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+
+/// Granularity at which the guest memory is plugged and unplugged.
+pub const VIRTIO_MEM_BLOCK_SIZE: u64 = 2 << 20;
+
+// Requests from the guest driver.
+const VIRTIO_MEM_REQ_PLUG: u16 = 0;
+const VIRTIO_MEM_REQ_UNPLUG: u16 = 1;
+const VIRTIO_MEM_REQ_UNPLUG_ALL: u16 = 2;
+const VIRTIO_MEM_REQ_STATE: u16 = 3;
+
+// Responses to the requests.
+const VIRTIO_MEM_RESP_ACK: u16 = 0;
+const VIRTIO_MEM_RESP_NACK: u16 = 1;
+const VIRTIO_MEM_RESP_ERROR: u16 = 3;
+
+// State of a range of blocks.
+const VIRTIO_MEM_STATE_PLUGGED: u16 = 0;
+const VIRTIO_MEM_STATE_UNPLUGGED: u16 = 1;
+const VIRTIO_MEM_STATE_MIXED: u16 = 2;
+
+// New requests are available.
+const QUEUE_AVAIL_EVENT: DeviceEventT = 0;
+// The device has been dropped.
+const KILL_EVENT: DeviceEventT = 1;
+// The device should be paused.
+const PAUSE_EVENT: DeviceEventT = 2;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioMemConfig {
+    // Size of the blocks the region is plugged and unplugged by.
+    block_size: u64,
+    node_id: u16,
+    padding: [u8; 6],
+    // Guest physical address of the region.
+    addr: u64,
+    region_size: u64,
+    // Part of the region the guest may plug blocks into.
+    usable_region_size: u64,
+    // Amount of memory plugged, updated as the guest plugs and unplugs.
+    plugged_size: u64,
+    // Amount of memory the host wants the guest to have plugged.
+    requested_size: u64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioMemConfig {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioMemReq {
+    req_type: u16,
+    padding: [u16; 3],
+    addr: u64,
+    nb_blocks: u16,
+    padding_1: [u16; 3],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioMemReq {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioMemResp {
+    resp_type: u16,
+    padding: [u16; 3],
+    state: u16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioMemResp {}
+
+// The device configuration along with which blocks of the region are
+// plugged, shared between the device and its epoll thread.
+struct MemState {
+    config: VirtioMemConfig,
+    plugged: Vec<bool>,
+}
+
+impl MemState {
+    // The blocks a request applies to, if they are within the usable part
+    // of the region.
+    fn blocks(&self, addr: u64, nb_blocks: u16) -> Option<Range<usize>> {
+        let offset = addr.checked_sub(self.config.addr)?;
+        if nb_blocks == 0 || offset % self.config.block_size != 0 {
+            return None;
+        }
+
+        let first = (offset / self.config.block_size) as usize;
+        let last = first + nb_blocks as usize;
+        if last as u64 * self.config.block_size > self.config.usable_region_size {
+            return None;
+        }
+
+        Some(first..last)
+    }
+
+    fn state(&self, blocks: Range<usize>) -> u16 {
+        let plugged = self.plugged[blocks.clone()].iter().filter(|p| **p).count();
+        if plugged == blocks.len() {
+            VIRTIO_MEM_STATE_PLUGGED
+        } else if plugged == 0 {
+            VIRTIO_MEM_STATE_UNPLUGGED
+        } else {
+            VIRTIO_MEM_STATE_MIXED
+        }
+    }
+
+    // The guest memory ranges, as address and length, of the blocks being
+    // plugged, or not.
+    fn ranges(&self, plugged: bool) -> Vec<(u64, u64)> {
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for (block, _) in self
+            .plugged
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| **p == plugged)
+        {
+            let addr = self.config.addr + block as u64 * self.config.block_size;
+            match ranges.last_mut() {
+                Some((start, length)) if *start + *length == addr => {
+                    *length += self.config.block_size
+                }
+                _ => ranges.push((addr, self.config.block_size)),
+            }
+        }
+        ranges
+    }
+
+    // Give the memory of the blocks back to the host. It gets faulted in
+    // again, zeroed, once the guest plugs them back and accesses them.
+    fn discard(&self, mem: &GuestMemoryMmap, blocks: Range<usize>) {
+        let addr = self.config.addr + blocks.start as u64 * self.config.block_size;
+        let len = blocks.len() * self.config.block_size as usize;
+        let host_addr = match get_host_address_range(mem, GuestAddress(addr), len) {
+            Some(host_addr) => host_addr,
+            None => {
+                error!(
+                    "Invalid virtio-mem range 0x{:x}-0x{:x}",
+                    addr,
+                    addr + len as u64
+                );
+                return;
+            }
+        };
+
+        // Safe because the range is part of the guest memory mapping.
+        let ret =
+            unsafe { libc::madvise(host_addr as *mut libc::c_void, len, libc::MADV_DONTNEED) };
+        if ret != 0 {
+            error!(
+                "Failed to discard virtio-mem range 0x{:x}-0x{:x}: {}",
+                addr,
+                addr + len as u64,
+                io::Error::last_os_error()
+            );
+        }
+    }
+
+    fn unplug_all(&mut self, mem: &GuestMemoryMmap) {
+        for (addr, length) in self.ranges(true) {
+            let first = ((addr - self.config.addr) / self.config.block_size) as usize;
+            let last = first + (length / self.config.block_size) as usize;
+            self.discard(mem, first..last);
+        }
+        for plugged in self.plugged.iter_mut() {
+            *plugged = false;
+        }
+        self.config.plugged_size = 0;
+    }
+
+    fn handle_request(&mut self, mem: &GuestMemoryMmap, req: &VirtioMemReq) -> VirtioMemResp {
+        let mut resp = VirtioMemResp {
+            resp_type: VIRTIO_MEM_RESP_ERROR,
+            ..Default::default()
+        };
+
+        if req.req_type == VIRTIO_MEM_REQ_UNPLUG_ALL {
+            self.unplug_all(mem);
+            resp.resp_type = VIRTIO_MEM_RESP_ACK;
+            return resp;
+        }
+
+        let blocks = match self.blocks(req.addr, req.nb_blocks) {
+            Some(blocks) => blocks,
+            None => return resp,
+        };
+        let size = blocks.len() as u64 * self.config.block_size;
+        let state = self.state(blocks.clone());
+
+        match req.req_type {
+            VIRTIO_MEM_REQ_PLUG if state == VIRTIO_MEM_STATE_UNPLUGGED => {
+                // The guest can't go beyond what the host asks for.
+                if self.config.plugged_size + size > self.config.requested_size {
+                    resp.resp_type = VIRTIO_MEM_RESP_NACK;
+                } else {
+                    for plugged in self.plugged[blocks].iter_mut() {
+                        *plugged = true;
+                    }
+                    self.config.plugged_size += size;
+                    resp.resp_type = VIRTIO_MEM_RESP_ACK;
+                }
+            }
+            VIRTIO_MEM_REQ_UNPLUG if state == VIRTIO_MEM_STATE_PLUGGED => {
+                self.discard(mem, blocks.clone());
+                for plugged in self.plugged[blocks].iter_mut() {
+                    *plugged = false;
+                }
+                self.config.plugged_size -= size;
+                resp.resp_type = VIRTIO_MEM_RESP_ACK;
+            }
+            VIRTIO_MEM_REQ_STATE => {
+                resp.resp_type = VIRTIO_MEM_RESP_ACK;
+                resp.state = state;
+            }
+            _ => {}
+        }
+
+        resp
+    }
+}
+
+struct MemEpollHandler {
+    queue: Queue,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    state: Arc<Mutex<MemState>>,
+    queue_evt: EventFd,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+}
+
+impl MemEpollHandler {
+    fn process_queue(&mut self) -> bool {
+        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        let mem = self.mem.memory();
+        for avail_desc in self.queue.iter(&mem) {
+            // The request is followed by the descriptor the response is
+            // written to.
+            let mut len = 0;
+            let resp_desc = avail_desc.next_descriptor();
+            match (
+                mem.read_obj::<VirtioMemReq>(avail_desc.addr),
+                resp_desc
+                    .filter(|d| d.is_write_only() && d.len as usize >= size_of::<VirtioMemResp>()),
+            ) {
+                (Ok(req), Some(resp_desc))
+                    if !avail_desc.is_write_only()
+                        && avail_desc.len as usize >= size_of::<VirtioMemReq>() =>
+                {
+                    let resp = self.state.lock().unwrap().handle_request(&mem, &req);
+                    match mem.write_obj(resp, resp_desc.addr) {
+                        Ok(()) => len = size_of::<VirtioMemResp>() as u32,
+                        Err(e) => error!("Failed to write virtio-mem response: {:?}", e),
+                    }
+                }
+                _ => error!("Invalid virtio-mem request"),
+            }
+
+            used_desc_heads[used_count] = (avail_desc.index, len);
+            used_count += 1;
+        }
+
+        for &(desc_index, len) in &used_desc_heads[..used_count] {
+            self.queue.add_used(&mem, desc_index, len);
+        }
+        used_count > 0
+    }
+
+    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queue))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), DeviceError> {
+        // Create the epoll file descriptor
+        let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
+
+        // Add events
+        for (fd, event) in [
+            (self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT),
+            (self.kill_evt.as_raw_fd(), KILL_EVENT),
+            (self.pause_evt.as_raw_fd(), PAUSE_EVENT),
+        ]
+        .iter()
+        {
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                *fd,
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(*event)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+
+        const EPOLL_EVENTS_LEN: usize = 100;
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+
+        'epoll: loop {
+            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+                Ok(res) => res,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        // The epoll wait can be interrupted before any of the
+                        // requested events occurred, which isn't an error.
+                        continue;
+                    }
+                    return Err(DeviceError::EpollWait(e));
+                }
+            };
+
+            for event in events.iter().take(num_events) {
+                let ev_type = event.data as u16;
+
+                match ev_type {
+                    QUEUE_AVAIL_EVENT => {
+                        if let Err(e) = self.queue_evt.read() {
+                            error!("Failed to get queue event: {:?}", e);
+                            break 'epoll;
+                        } else if self.process_queue() {
+                            if let Err(e) = self.signal_used_queue() {
+                                error!("Failed to signal used queue: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                    }
+                    KILL_EVENT => {
+                        debug!("KILL_EVENT received, stopping epoll loop");
+                        break 'epoll;
+                    }
+                    PAUSE_EVENT => {
+                        debug!("PAUSE_EVENT received, pausing virtio-mem epoll loop");
+                        wait_for_resume(&paused, &paused_sync);
+                    }
+                    _ => {
+                        error!("Unknown event for virtio-mem");
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Virtio device letting the guest plug and unplug memory, by blocks of
+/// `VIRTIO_MEM_BLOCK_SIZE`, within a region of its address space, up to
+/// the amount the host requests.
+pub struct Mem {
+    kill_evt: Option<EventFd>,
+    pause_evt: Option<EventFd>,
+    avail_features: u64,
+    acked_features: u64,
+    queue_evts: Option<Vec<EventFd>>,
+    interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
+    epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
+    paused: Arc<AtomicBool>,
+    paused_sync: Option<Arc<Barrier>>,
+    mem: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
+    state: Arc<Mutex<MemState>>,
+}
+
+impl Mem {
+    /// Create a new virtio-mem device for the `region_size` bytes of guest
+    /// memory at `addr`, the guest being asked to plug `requested_size`
+    /// bytes of it.
+    pub fn new(addr: GuestAddress, region_size: u64, requested_size: u64) -> io::Result<Mem> {
+        if addr.0 % VIRTIO_MEM_BLOCK_SIZE != 0
+            || region_size == 0
+            || region_size % VIRTIO_MEM_BLOCK_SIZE != 0
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "virtio-mem region must be aligned on the block size",
+            ));
+        }
+
+        let mut state = MemState {
+            config: VirtioMemConfig {
+                block_size: VIRTIO_MEM_BLOCK_SIZE,
+                addr: addr.0,
+                region_size,
+                usable_region_size: region_size,
+                ..Default::default()
+            },
+            plugged: vec![false; (region_size / VIRTIO_MEM_BLOCK_SIZE) as usize],
+        };
+        state.config.requested_size = Mem::check_size(&state.config, requested_size)?;
+
+        Ok(Mem {
+            kill_evt: None,
+            pause_evt: None,
+            avail_features: 1u64 << VIRTIO_F_VERSION_1,
+            acked_features: 0u64,
+            queue_evts: None,
+            interrupt_cb: None,
+            epoll_threads: None,
+            paused: Arc::new(AtomicBool::new(false)),
+            paused_sync: None,
+            mem: None,
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    fn check_size(config: &VirtioMemConfig, size: u64) -> io::Result<u64> {
+        if size > config.region_size || size % config.block_size != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid virtio-mem size",
+            ));
+        }
+
+        Ok(size)
+    }
+
+    /// Change the amount of memory the guest is asked to plug. The guest
+    /// driver is notified if it's already running.
+    pub fn resize(&mut self, size: u64) -> io::Result<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.config.requested_size = Mem::check_size(&state.config, size)?;
+        }
+
+        if let Some(interrupt_cb) = &self.interrupt_cb {
+            interrupt_cb.trigger(&VirtioInterruptType::Config, None)?;
+        }
+
+        Ok(())
+    }
+
+    /// Amount of memory the guest is asked to plug.
+    pub fn requested_size(&self) -> u64 {
+        self.state.lock().unwrap().config.requested_size
+    }
+
+    /// Amount of memory the guest actually plugged.
+    pub fn plugged_size(&self) -> u64 {
+        self.state.lock().unwrap().config.plugged_size
+    }
+
+    /// Size of the region the memory is plugged into.
+    pub fn region_size(&self) -> u64 {
+        self.state.lock().unwrap().config.region_size
+    }
+
+    /// Guest memory ranges, as address and length, the guest didn't plug,
+    /// hence can't use. Their content is meaningless.
+    pub fn unplugged_ranges(&self) -> Vec<(u64, u64)> {
+        self.state.lock().unwrap().ranges(false)
+    }
+}
+
+impl Drop for Mem {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for Mem {
+    fn device_type(&self) -> u32 {
+        VirtioDeviceType::TYPE_MEM as u32
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let state = self.state.lock().unwrap();
+        let config_slice = state.config.as_slice();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            return;
+        }
+
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {
+        warn!("virtio-mem: configuration is read-only");
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        mut queues: Vec<Queue>,
+        mut queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != QUEUE_SIZES.len() || queue_evts.len() != QUEUE_SIZES.len() {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                QUEUE_SIZES.len(),
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        let (self_kill_evt, kill_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating kill EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.kill_evt = Some(self_kill_evt);
+
+        let (self_pause_evt, pause_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating pause EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.pause_evt = Some(self_pause_evt);
+
+        // Save the interrupt EventFD as we need to return it on reset
+        // but clone it to pass into the thread.
+        self.interrupt_cb = Some(interrupt_cb.clone());
+
+        let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
+        for queue_evt in queue_evts.iter() {
+            // Save the queue EventFD as we need to return it on reset
+            // but clone it to pass into the thread.
+            tmp_queue_evts.push(queue_evt.try_clone().map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?);
+        }
+        self.queue_evts = Some(tmp_queue_evts);
+
+        // Kept to unplug the whole region on reset.
+        self.mem = Some(mem.clone());
+
+        let mut handler = MemEpollHandler {
+            queue: queues.remove(0),
+            mem,
+            interrupt_cb,
+            state: self.state.clone(),
+            queue_evt: queue_evts.remove(0),
+            kill_evt,
+            pause_evt,
+        };
+
+        let paused = self.paused.clone();
+        let paused_sync = Arc::new(Barrier::new(2));
+        let paused_sync_clone = paused_sync.clone();
+        let mut epoll_threads = Vec::new();
+        thread::Builder::new()
+            .name("virtio_mem".to_string())
+            .spawn(move || handler.run(paused, paused_sync_clone))
+            .map(|thread| epoll_threads.push(thread))
+            .map_err(|e| {
+                error!("failed to clone the virtio-mem epoll thread: {}", e);
+                ActivateError::BadActivate
+            })?;
+
+        self.epoll_threads = Some(epoll_threads);
+        self.paused_sync = Some(paused_sync);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+        // We first must resume the virtio thread if it was paused.
+        if self.pause_evt.take().is_some() {
+            self.resume().ok()?;
+        }
+
+        // Then kill it.
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        // The whole region is unplugged on reset.
+        if let Some(mem) = self.mem.take() {
+            self.state.lock().unwrap().unplug_all(&mem.memory());
+        }
+
+        // Return the interrupt and queue EventFDs
+        Some((
+            self.interrupt_cb.take().unwrap(),
+            self.queue_evts.take().unwrap(),
+        ))
+    }
+}
+
+virtio_pausable!(Mem);
+
+impl Snapshotable for Mem {
+    fn id(&self) -> String {
+        "virtio-mem".to_string()
+    }
+
+    fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
+        let state = self.state.lock().unwrap();
+        let mut snapshot = Snapshot::new(&self.id());
+        VirtioDeviceState::new(
+            self.avail_features,
+            self.acked_features,
+            state.config.as_slice(),
+        )
+        .add_to(&mut snapshot)?;
+        snapshot.add_state("plugged", &state.plugged)?;
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        let virtio_state = VirtioDeviceState::from(&snapshot)?;
+        self.acked_features = virtio_state.acked_features(self.avail_features)?;
+        let mut state = self.state.lock().unwrap();
+        virtio_state.restore_config(&mut state.config)?;
+        let plugged: Vec<bool> = snapshot.state("plugged")?;
+        if plugged.len() != state.plugged.len() {
+            return Err(MigratableError::Restore(anyhow!(
+                "virtio-mem region of {} blocks instead of {}",
+                plugged.len(),
+                state.plugged.len()
+            )));
+        }
+        state.plugged = plugged;
+        Ok(())
+    }
+}
+impl Migratable for Mem {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::tests::VirtQueue as GuestQ;
+    use crate::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    struct NoopVirtioInterrupt {}
+
+    impl VirtioInterrupt for NoopVirtioInterrupt {
+        fn trigger(
+            &self,
+            _int_type: &VirtioInterruptType,
+            _queue: Option<&Queue>,
+        ) -> result::Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    const REGION_ADDR: u64 = 0x100_0000;
+    const REGION_SIZE: u64 = 4 * VIRTIO_MEM_BLOCK_SIZE;
+
+    fn block(index: u64) -> u64 {
+        REGION_ADDR + index * VIRTIO_MEM_BLOCK_SIZE
+    }
+
+    // Send a request through the queue, returning the response.
+    fn request(
+        handler: &mut MemEpollHandler,
+        queue: &GuestQ,
+        mem: &GuestMemoryMmap,
+        req_type: u16,
+        addr: u64,
+        nb_blocks: u16,
+    ) -> VirtioMemResp {
+        let req = VirtioMemReq {
+            req_type,
+            addr,
+            nb_blocks,
+            ..Default::default()
+        };
+        mem.write_obj(req, GuestAddress(0x8000)).unwrap();
+        mem.write_obj(VirtioMemResp::default(), GuestAddress(0x9000))
+            .unwrap();
+
+        let avail_idx = queue.avail.idx.get();
+        queue.dtable[0].set(
+            0x8000,
+            size_of::<VirtioMemReq>() as u32,
+            VIRTQ_DESC_F_NEXT,
+            1,
+        );
+        queue.dtable[1].set(
+            0x9000,
+            size_of::<VirtioMemResp>() as u32,
+            VIRTQ_DESC_F_WRITE,
+            0,
+        );
+        queue.avail.ring[(avail_idx % QUEUE_SIZE) as usize].set(0);
+        queue.avail.idx.set(avail_idx + 1);
+
+        assert!(handler.process_queue());
+        mem.read_obj(GuestAddress(0x9000)).unwrap()
+    }
+
+    #[test]
+    fn test_mem_plug_unplug() {
+        let mem = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x10_0000),
+            (GuestAddress(REGION_ADDR), REGION_SIZE as usize),
+        ])
+        .unwrap();
+        let queue = GuestQ::new(GuestAddress(0), &mem, QUEUE_SIZE);
+        let device = Mem::new(
+            GuestAddress(REGION_ADDR),
+            REGION_SIZE,
+            2 * VIRTIO_MEM_BLOCK_SIZE,
+        )
+        .unwrap();
+        let state = device.state.clone();
+        let mut handler = MemEpollHandler {
+            queue: queue.create_queue(),
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            interrupt_cb: Arc::new(NoopVirtioInterrupt {}),
+            state: state.clone(),
+            queue_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+        };
+        let mut request = |req_type, addr, nb_blocks| {
+            request(&mut handler, &queue, &mem, req_type, addr, nb_blocks)
+        };
+
+        let resp = request(VIRTIO_MEM_REQ_PLUG, block(1), 2);
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ACK);
+        assert_eq!(device.plugged_size(), 2 * VIRTIO_MEM_BLOCK_SIZE);
+
+        // Beyond the requested size.
+        let resp = request(VIRTIO_MEM_REQ_PLUG, block(0), 1);
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_NACK);
+        // Already plugged.
+        let resp = request(VIRTIO_MEM_REQ_PLUG, block(2), 1);
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ERROR);
+        // Outside of the region, or not aligned.
+        let resp = request(VIRTIO_MEM_REQ_STATE, block(3), 2);
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ERROR);
+        let resp = request(VIRTIO_MEM_REQ_STATE, block(0) + 0x1000, 1);
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ERROR);
+
+        let resp = request(VIRTIO_MEM_REQ_STATE, block(0), 2);
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ACK);
+        assert_eq!(resp.state, VIRTIO_MEM_STATE_MIXED);
+        let resp = request(VIRTIO_MEM_REQ_STATE, block(1), 2);
+        assert_eq!(resp.state, VIRTIO_MEM_STATE_PLUGGED);
+
+        // The unplugged blocks lose their content.
+        mem.write_obj(0xffu8, GuestAddress(block(2))).unwrap();
+        let resp = request(VIRTIO_MEM_REQ_UNPLUG, block(2), 1);
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ACK);
+        assert_eq!(mem.read_obj::<u8>(GuestAddress(block(2))).unwrap(), 0);
+        assert_eq!(
+            device.unplugged_ranges(),
+            vec![
+                (block(0), VIRTIO_MEM_BLOCK_SIZE),
+                (block(2), 2 * VIRTIO_MEM_BLOCK_SIZE)
+            ]
+        );
+        // Not plugged anymore.
+        let resp = request(VIRTIO_MEM_REQ_UNPLUG, block(1), 2);
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ERROR);
+
+        let resp = request(VIRTIO_MEM_REQ_UNPLUG_ALL, 0, 0);
+        assert_eq!(resp.resp_type, VIRTIO_MEM_RESP_ACK);
+        assert_eq!(device.plugged_size(), 0);
+        let resp = request(VIRTIO_MEM_REQ_STATE, block(0), 4);
+        assert_eq!(resp.state, VIRTIO_MEM_STATE_UNPLUGGED);
+    }
+
+    #[test]
+    fn test_mem_resize() {
+        assert!(Mem::new(GuestAddress(REGION_ADDR + 0x1000), REGION_SIZE, 0).is_err());
+        assert!(Mem::new(GuestAddress(REGION_ADDR), 0, 0).is_err());
+        assert!(Mem::new(GuestAddress(REGION_ADDR), REGION_SIZE, REGION_SIZE + 1).is_err());
+
+        let mut device = Mem::new(GuestAddress(REGION_ADDR), REGION_SIZE, 0).unwrap();
+        device.resize(VIRTIO_MEM_BLOCK_SIZE).unwrap();
+        assert_eq!(device.requested_size(), VIRTIO_MEM_BLOCK_SIZE);
+        assert!(device.resize(REGION_SIZE + VIRTIO_MEM_BLOCK_SIZE).is_err());
+        assert!(device.resize(0x1000).is_err());
+        assert_eq!(device.requested_size(), VIRTIO_MEM_BLOCK_SIZE);
+
+        let mut requested_size = [0u8; 8];
+        device.read_config(48, &mut requested_size);
+        assert_eq!(u64::from_le_bytes(requested_size), VIRTIO_MEM_BLOCK_SIZE);
+        assert_eq!(device.unplugged_ranges(), vec![(REGION_ADDR, REGION_SIZE)]);
+    }
+}
@@ -0,0 +1,485 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Network Block Device (NBD) client, exposing a remote export as a disk
+//! image the virtio-blk device can operate on.
+//!
+//! Exports are designated by URIs, either `nbd://host[:port][/export]` for a
+//! TCP connection, or `nbd+unix:///export?socket=/path/to/socket` for a UNIX
+//! socket. The client negotiates the export through the newstyle handshake
+//! and then only relies on simple replies, as described in the protocol
+//! specification https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md
+
+use std::cmp;
+use std::convert::TryFrom;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::write_zeroes::PunchHole;
+
+const NBD_DEFAULT_PORT: u16 = 10809;
+
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943; // "NBDMAGIC"
+const NBD_OPTS_MAGIC: u64 = 0x4948_4156_454f_5054; // "IHAVEOPT"
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+// Handshake flags
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+// Transmission flags
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_WRITE_ZEROES: u16 = 6;
+
+// Servers may refuse requests bigger than this.
+const NBD_MAX_REQUEST_SIZE: usize = 32 << 20;
+
+// Padding following the export information, unless the server doesn't send
+// it anymore.
+const NBD_EXPORT_PADDING: usize = 124;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u16(stream: &mut dyn Read) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(stream: &mut dyn Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(stream: &mut dyn Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    stream.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// Returns whether a disk path designates an NBD export.
+pub fn is_nbd_uri(path: &str) -> bool {
+    path.starts_with("nbd://") || path.starts_with("nbd+unix://")
+}
+
+/// Connection to the server, shared by all the clones of an `NbdDisk`.
+pub trait NbdStream: Read + Write + Send {}
+impl<S: Read + Write + Send> NbdStream for S {}
+
+struct NbdConnection {
+    stream: Box<dyn NbdStream>,
+    handle: u64,
+}
+
+impl NbdConnection {
+    fn request(&mut self, command: u16, offset: u64, length: u32, data: &[u8]) -> io::Result<()> {
+        self.handle = self.handle.wrapping_add(1);
+
+        let mut request = Vec::with_capacity(28 + data.len());
+        request.extend_from_slice(&NBD_REQUEST_MAGIC.to_be_bytes());
+        request.extend_from_slice(&0u16.to_be_bytes());
+        request.extend_from_slice(&command.to_be_bytes());
+        request.extend_from_slice(&self.handle.to_be_bytes());
+        request.extend_from_slice(&offset.to_be_bytes());
+        request.extend_from_slice(&length.to_be_bytes());
+        request.extend_from_slice(data);
+        self.stream.write_all(&request)
+    }
+
+    // Waits for the reply to the last request. The server errors are errno
+    // values.
+    fn reply(&mut self) -> io::Result<()> {
+        let magic = read_u32(&mut self.stream)?;
+        if magic != NBD_SIMPLE_REPLY_MAGIC {
+            return Err(invalid_data(format!(
+                "Invalid NBD reply magic {:#x}",
+                magic
+            )));
+        }
+        let error = read_u32(&mut self.stream)?;
+        let handle = read_u64(&mut self.stream)?;
+        if handle != self.handle {
+            return Err(invalid_data(format!(
+                "Unexpected NBD reply handle {}, expecting {}",
+                handle, self.handle
+            )));
+        }
+        if error != 0 {
+            return Err(io::Error::from_raw_os_error(error as i32));
+        }
+
+        Ok(())
+    }
+}
+
+/// NBD export, which can be read, written and seeked like a file.
+#[derive(Clone)]
+pub struct NbdDisk {
+    connection: Arc<Mutex<NbdConnection>>,
+    size: u64,
+    flags: u16,
+    position: u64,
+}
+
+impl NbdDisk {
+    /// Connects to the export designated by the given URI.
+    pub fn connect(uri: &str) -> io::Result<Self> {
+        let invalid_uri = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid NBD URI {}", uri),
+            )
+        };
+
+        if uri.starts_with("nbd+unix://") {
+            let mut elements = uri["nbd+unix://".len()..].splitn(2, '?');
+            let export = elements.next().unwrap_or("").trim_start_matches('/');
+            let socket = elements
+                .next()
+                .and_then(|query| {
+                    query
+                        .split('&')
+                        .find(|param| param.starts_with("socket="))
+                        .map(|param| &param["socket=".len()..])
+                })
+                .ok_or_else(invalid_uri)?;
+
+            let stream = UnixStream::connect(socket)?;
+            NbdDisk::from_stream(Box::new(stream), export)
+        } else if uri.starts_with("nbd://") {
+            let mut elements = uri["nbd://".len()..].splitn(2, '/');
+            let address = elements
+                .next()
+                .filter(|a| !a.is_empty())
+                .ok_or_else(invalid_uri)?;
+            let export = elements.next().unwrap_or("");
+            let address = if address.contains(':') {
+                address.to_string()
+            } else {
+                format!("{}:{}", address, NBD_DEFAULT_PORT)
+            };
+
+            let stream = TcpStream::connect(address)?;
+            stream.set_nodelay(true)?;
+            NbdDisk::from_stream(Box::new(stream), export)
+        } else {
+            Err(invalid_uri())
+        }
+    }
+
+    /// Negotiates the given export over an established connection.
+    pub fn from_stream(mut stream: Box<dyn NbdStream>, export: &str) -> io::Result<Self> {
+        let magic = read_u64(&mut stream)?;
+        if magic != NBD_MAGIC {
+            return Err(invalid_data(format!("Invalid NBD magic {:#x}", magic)));
+        }
+        let magic = read_u64(&mut stream)?;
+        if magic != NBD_OPTS_MAGIC {
+            return Err(invalid_data(String::from(
+                "NBD server doesn't support the newstyle negotiation",
+            )));
+        }
+
+        let handshake_flags = read_u16(&mut stream)?;
+        let mut client_flags = 0;
+        if handshake_flags & NBD_FLAG_FIXED_NEWSTYLE != 0 {
+            client_flags |= NBD_FLAG_C_FIXED_NEWSTYLE;
+        }
+        if handshake_flags & NBD_FLAG_NO_ZEROES != 0 {
+            client_flags |= NBD_FLAG_C_NO_ZEROES;
+        }
+        stream.write_all(&client_flags.to_be_bytes())?;
+
+        // The server closes the connection if the export doesn't exist.
+        let mut option = Vec::with_capacity(16 + export.len());
+        option.extend_from_slice(&NBD_OPTS_MAGIC.to_be_bytes());
+        option.extend_from_slice(&NBD_OPT_EXPORT_NAME.to_be_bytes());
+        option.extend_from_slice(&(export.len() as u32).to_be_bytes());
+        option.extend_from_slice(export.as_bytes());
+        stream.write_all(&option)?;
+
+        let size = read_u64(&mut stream).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => {
+                invalid_data(format!("NBD server refused the export \"{}\"", export))
+            }
+            _ => e,
+        })?;
+        let flags = read_u16(&mut stream)?;
+        if client_flags & NBD_FLAG_C_NO_ZEROES == 0 {
+            let mut padding = [0u8; NBD_EXPORT_PADDING];
+            stream.read_exact(&mut padding)?;
+        }
+
+        Ok(NbdDisk {
+            connection: Arc::new(Mutex::new(NbdConnection { stream, handle: 0 })),
+            size,
+            flags,
+            position: 0,
+        })
+    }
+
+    /// Returns whether the server only allows the export to be read.
+    pub fn is_read_only(&self) -> bool {
+        self.flags & NBD_FLAG_READ_ONLY != 0
+    }
+
+    // Length of the next request, which mustn't go past the end of the
+    // export.
+    fn request_len(&self, len: usize) -> usize {
+        let remaining = self.size.saturating_sub(self.position);
+        cmp::min(
+            cmp::min(len as u64, remaining) as usize,
+            NBD_MAX_REQUEST_SIZE,
+        )
+    }
+}
+
+impl Read for NbdDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.request_len(buf.len());
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let mut connection = self.connection.lock().unwrap();
+        connection.request(NBD_CMD_READ, self.position, len as u32, &[])?;
+        connection.reply()?;
+        connection.stream.read_exact(&mut buf[..len])?;
+
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for NbdDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.is_read_only() {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        let len = self.request_len(buf.len());
+        if len == 0 {
+            return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+        }
+
+        let mut connection = self.connection.lock().unwrap();
+        connection.request(NBD_CMD_WRITE, self.position, len as u32, &buf[..len])?;
+        connection.reply()?;
+
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.flags & NBD_FLAG_SEND_FLUSH == 0 {
+            return Ok(());
+        }
+
+        let mut connection = self.connection.lock().unwrap();
+        connection.request(NBD_CMD_FLUSH, 0, 0, &[])?;
+        connection.reply()
+    }
+}
+
+impl Seek for NbdDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => i64::try_from(offset).ok(),
+            SeekFrom::End(offset) => (self.size as i64).checked_add(offset),
+            SeekFrom::Current(offset) => (self.position as i64).checked_add(offset),
+        };
+
+        match position {
+            Some(position) if position >= 0 => {
+                self.position = position as u64;
+                Ok(self.position)
+            }
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+}
+
+// Zeroes the range through write zeroes commands, letting the server punch
+// holes in its storage. Exports without write zeroes support can't punch
+// holes.
+impl PunchHole for NbdDisk {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        if self.is_read_only() {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        if self.flags & NBD_FLAG_SEND_WRITE_ZEROES == 0 {
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+
+        let end = cmp::min(offset.saturating_add(length), self.size);
+        let mut offset = offset;
+        let mut connection = self.connection.lock().unwrap();
+        while offset < end {
+            let len = cmp::min(end - offset, NBD_MAX_REQUEST_SIZE as u64);
+            connection.request(NBD_CMD_WRITE_ZEROES, offset, len as u32, &[])?;
+            connection.reply()?;
+            offset += len;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for NbdConnection {
+    fn drop(&mut self) {
+        // Let the server know the client is going away. There's no reply.
+        if let Err(e) = self.request(NBD_CMD_DISC, 0, 0, &[]) {
+            debug!("Failed to disconnect from the NBD server: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const EXPORT_SIZE: usize = 1 << 20;
+
+    // Minimal NBD server, serving an in-memory export.
+    fn serve(mut stream: UnixStream, read_only: bool) {
+        let mut export = vec![0u8; EXPORT_SIZE];
+
+        stream.write_all(&NBD_MAGIC.to_be_bytes()).unwrap();
+        stream.write_all(&NBD_OPTS_MAGIC.to_be_bytes()).unwrap();
+        stream
+            .write_all(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes())
+            .unwrap();
+        assert_eq!(
+            read_u32(&mut stream).unwrap(),
+            NBD_FLAG_C_FIXED_NEWSTYLE | NBD_FLAG_C_NO_ZEROES
+        );
+        assert_eq!(read_u64(&mut stream).unwrap(), NBD_OPTS_MAGIC);
+        assert_eq!(read_u32(&mut stream).unwrap(), NBD_OPT_EXPORT_NAME);
+        let mut name = vec![0u8; read_u32(&mut stream).unwrap() as usize];
+        stream.read_exact(&mut name).unwrap();
+        assert_eq!(name, b"disk");
+
+        let mut flags = NBD_FLAG_SEND_FLUSH | NBD_FLAG_SEND_WRITE_ZEROES;
+        if read_only {
+            flags |= NBD_FLAG_READ_ONLY;
+        }
+        stream
+            .write_all(&(EXPORT_SIZE as u64).to_be_bytes())
+            .unwrap();
+        stream.write_all(&flags.to_be_bytes()).unwrap();
+
+        loop {
+            assert_eq!(read_u32(&mut stream).unwrap(), NBD_REQUEST_MAGIC);
+            read_u16(&mut stream).unwrap();
+            let command = read_u16(&mut stream).unwrap();
+            let handle = read_u64(&mut stream).unwrap();
+            let offset = read_u64(&mut stream).unwrap() as usize;
+            let length = read_u32(&mut stream).unwrap() as usize;
+
+            let mut reply = Vec::new();
+            reply.extend_from_slice(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes());
+            reply.extend_from_slice(&0u32.to_be_bytes());
+            reply.extend_from_slice(&handle.to_be_bytes());
+            match command {
+                NBD_CMD_READ => reply.extend_from_slice(&export[offset..offset + length]),
+                NBD_CMD_WRITE => stream
+                    .read_exact(&mut export[offset..offset + length])
+                    .unwrap(),
+                NBD_CMD_FLUSH => {}
+                NBD_CMD_WRITE_ZEROES => {
+                    for b in export[offset..offset + length].iter_mut() {
+                        *b = 0;
+                    }
+                }
+                NBD_CMD_DISC => return,
+                _ => panic!("Unexpected NBD command {}", command),
+            }
+            stream.write_all(&reply).unwrap();
+        }
+    }
+
+    fn nbd_disk(read_only: bool) -> (NbdDisk, thread::JoinHandle<()>) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || serve(server, read_only));
+        let disk = NbdDisk::from_stream(Box::new(client), "disk").unwrap();
+
+        (disk, server)
+    }
+
+    #[test]
+    fn test_nbd_uri() {
+        assert!(is_nbd_uri("nbd://localhost:10809/disk"));
+        assert!(is_nbd_uri("nbd+unix:///disk?socket=/tmp/nbd.sock"));
+        assert!(!is_nbd_uri("/path/to/disk"));
+
+        assert!(NbdDisk::connect("nbd://").is_err());
+        assert!(NbdDisk::connect("nbd+unix:///disk").is_err());
+    }
+
+    #[test]
+    fn test_nbd_read_write() {
+        let (mut disk, server) = nbd_disk(false);
+
+        assert!(!disk.is_read_only());
+        assert_eq!(disk.seek(SeekFrom::End(0)).unwrap(), EXPORT_SIZE as u64);
+
+        let data = [0xa5u8; 4096];
+        disk.seek(SeekFrom::Start(8192)).unwrap();
+        disk.write_all(&data).unwrap();
+        disk.flush().unwrap();
+
+        // Clones share the connection, but not the position.
+        let mut clone = disk.clone();
+        let mut buf = [0u8; 4096];
+        clone.seek(SeekFrom::Start(8192)).unwrap();
+        clone.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[..], data[..]);
+
+        // Write zeroes requests stop at the end of the export as well.
+        disk.punch_hole(8192 + 1024, 1024).unwrap();
+        disk.punch_hole(EXPORT_SIZE as u64 - 512, 4096).unwrap();
+        clone.seek(SeekFrom::Start(8192)).unwrap();
+        clone.read_exact(&mut buf).unwrap();
+        assert!(buf[..1024].iter().all(|b| *b == 0xa5));
+        assert!(buf[1024..2048].iter().all(|b| *b == 0));
+        assert!(buf[2048..].iter().all(|b| *b == 0xa5));
+
+        // Reads stop at the end of the export.
+        disk.seek(SeekFrom::End(-512)).unwrap();
+        assert_eq!(disk.read(&mut buf).unwrap(), 512);
+        assert_eq!(disk.read(&mut buf).unwrap(), 0);
+
+        drop(disk);
+        drop(clone);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_nbd_read_only() {
+        let (mut disk, server) = nbd_disk(true);
+
+        assert!(disk.is_read_only());
+        assert!(disk.write(&[0u8; 512]).is_err());
+        assert!(disk.punch_hole(0, 512).is_err());
+
+        drop(disk);
+        server.join().unwrap();
+    }
+}
@@ -6,15 +6,19 @@
 // found in the THIRD-PARTY file.
 
 use super::net_util::{
-    build_net_config_space, build_net_config_space_with_mq, open_tap, register_listener,
-    unregister_listener, CtrlVirtio, NetCtrlEpollHandler, RxVirtio, TxVirtio, VirtioNetConfig,
-    KILL_EVENT, NET_EVENTS_COUNT, PAUSE_EVENT, RX_QUEUE_EVENT, RX_TAP_EVENT, TX_QUEUE_EVENT,
+    build_net_config_space, build_net_config_space_with_mq, open_tap, open_tap_fds, open_xsk,
+    register_listener, unregister_listener, vnet_hdr_len, CtrlVirtio, NetBackend,
+    NetCtrlEpollHandler, NetOffloads, RxVirtio, TxVirtio, VirtioNetConfig, KILL_EVENT,
+    NET_EVENTS_COUNT, PAUSE_EVENT, RX_QUEUE_EVENT, RX_RATE_LIMITER_EVENT, RX_TAP_EVENT,
+    TX_QUEUE_EVENT, TX_RATE_LIMITER_EVENT,
 };
 use super::Error as DeviceError;
 use super::{
     ActivateError, ActivateResult, Queue, VirtioDevice, VirtioDeviceType, VirtioInterruptType,
 };
-use crate::VirtioInterrupt;
+use crate::block::rate_limiter_timer;
+use crate::device::{join_epoll_threads, wait_for_resume};
+use crate::{RateLimiter, VirtioDeviceState, VirtioInterrupt};
 use epoll;
 use libc::EAGAIN;
 use libc::EFD_NONBLOCK;
@@ -22,29 +26,42 @@ use net_util::{MacAddr, Tap};
 use std::cmp;
 use std::io::Read;
 use std::io::{self, Write};
+use std::mem;
 use std::net::Ipv4Addr;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
 use std::vec::Vec;
 use virtio_bindings::bindings::virtio_net::*;
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
 use vm_memory::{ByteValued, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
-use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::{eventfd::EventFd, timerfd::TimerFd};
+
+// The device is a standby for a primary device with the same MAC address,
+// which the guest uses whenever it's present.
+const VIRTIO_NET_F_STANDBY: u64 = 62;
+// The device advertises the MTU the guest should use.
+const VIRTIO_NET_F_MTU: u64 = 3;
 
 #[derive(Debug)]
 pub enum Error {
     /// Failed to open taps.
     OpenTap(super::net_util::Error),
+    /// Failed to open AF_XDP sockets.
+    OpenXsk(super::net_util::Error),
+    /// The number of queues isn't a non zero even number, one Rx and one Tx
+    /// queue per pair.
+    InvalidNumQueues(usize),
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
 struct NetEpollHandler {
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
-    tap: Tap,
+    tap: Box<dyn NetBackend>,
     rx: RxVirtio,
     tx: TxVirtio,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
@@ -52,6 +69,13 @@ struct NetEpollHandler {
     pause_evt: EventFd,
     epoll_fd: RawFd,
     rx_tap_listening: bool,
+    rx_rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    rx_rate_limiter_timer: Option<TimerFd>,
+    // The frame read from the tap waits for the rate limiter to allow
+    // receiving it.
+    rx_throttled: bool,
+    tx_rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    tx_rate_limiter_timer: Option<TimerFd>,
 }
 
 impl NetEpollHandler {
@@ -89,13 +113,52 @@ impl NetEpollHandler {
         self.rx.process_desc_chain(&mem, next_desc, &mut queue)
     }
 
+    // Consumes the rate limiter budget for the frame read from the tap.
+    // When it's insufficient, the tap is no longer listened to until the
+    // timer fires, and false is returned.
+    fn rx_rate_limit(&mut self) -> bool {
+        let result = match &self.rx_rate_limiter {
+            Some(rate_limiter) => rate_limiter
+                .lock()
+                .unwrap()
+                .consume(self.rx.bytes_read as u64),
+            None => Ok(()),
+        };
+
+        match result {
+            Ok(()) => {
+                self.rx_throttled = false;
+                true
+            }
+            Err(wait) => {
+                self.rx_throttled = true;
+                if self.rx_tap_listening {
+                    unregister_listener(
+                        self.epoll_fd,
+                        self.tap.as_raw_fd(),
+                        epoll::Events::EPOLLIN,
+                        u64::from(RX_TAP_EVENT),
+                    )
+                    .unwrap();
+                    self.rx_tap_listening = false;
+                }
+                if let Some(timer) = &mut self.rx_rate_limiter_timer {
+                    if let Err(e) = timer.reset(wait, None) {
+                        error!("Failed to arm the rx rate limiter timer: {:?}", e);
+                    }
+                }
+                false
+            }
+        }
+    }
+
     fn process_rx(&mut self, queue: &mut Queue) -> result::Result<(), DeviceError> {
         // Read as many frames as possible.
         loop {
             match self.read_tap() {
                 Ok(count) => {
                     self.rx.bytes_read = count;
-                    if !self.rx_single_frame(queue) {
+                    if !self.rx_rate_limit() || !self.rx_single_frame(queue) {
                         self.rx.deferred_frame = true;
                         break;
                     }
@@ -123,7 +186,7 @@ impl NetEpollHandler {
     }
 
     fn resume_rx(&mut self, queue: &mut Queue) -> result::Result<(), DeviceError> {
-        if self.rx.deferred_frame {
+        if self.rx.deferred_frame && !self.rx_throttled {
             if self.rx_single_frame(queue) {
                 self.rx.deferred_frame = false;
                 // process_rx() was interrupted possibly before consuming all
@@ -143,7 +206,18 @@ impl NetEpollHandler {
     fn process_tx(&mut self, mut queue: &mut Queue) -> result::Result<(), DeviceError> {
         let mem = self.mem.memory();
 
-        self.tx.process_desc_chain(&mem, &mut self.tap, &mut queue);
+        if let Some(wait) = self.tx.process_desc_chain(
+            &mem,
+            &mut self.tap,
+            &mut queue,
+            self.tx_rate_limiter.as_deref(),
+        ) {
+            if let Some(timer) = &mut self.tx_rate_limiter_timer {
+                if let Err(e) = timer.reset(wait, None) {
+                    error!("Failed to arm the tx rate limiter timer: {:?}", e);
+                }
+            }
+        }
 
         Ok(())
     }
@@ -158,7 +232,7 @@ impl NetEpollHandler {
         }
 
         self.resume_rx(&mut queue).unwrap();
-        if !self.rx_tap_listening {
+        if !self.rx_tap_listening && !self.rx_throttled {
             register_listener(
                 self.epoll_fd,
                 self.tap.as_raw_fd(),
@@ -179,6 +253,12 @@ impl NetEpollHandler {
     }
 
     fn handle_rx_tap_event(&mut self, mut queue: &mut Queue) {
+        // The tap may have been found readable before it was no longer
+        // listened to.
+        if self.rx_throttled {
+            return;
+        }
+
         if self.rx.deferred_frame
         // Process a deferred frame first if available. Don't read from tap again
         // until we manage to receive this deferred frame.
@@ -195,9 +275,43 @@ impl NetEpollHandler {
         }
     }
 
+    fn handle_rx_rate_limiter_event(&mut self, mut queue: &mut Queue) {
+        if let Some(timer) = &mut self.rx_rate_limiter_timer {
+            if let Err(e) = timer.wait() {
+                error!("Failed to get rx rate limiter timer event: {:?}", e);
+            }
+        }
+
+        if !self.rx_throttled || !self.rx_rate_limit() {
+            return;
+        }
+        self.resume_rx(&mut queue).unwrap();
+        if !self.rx_tap_listening && !self.rx_throttled {
+            register_listener(
+                self.epoll_fd,
+                self.tap.as_raw_fd(),
+                epoll::Events::EPOLLIN,
+                u64::from(RX_TAP_EVENT),
+            )
+            .unwrap();
+            self.rx_tap_listening = true;
+        }
+    }
+
+    fn handle_tx_rate_limiter_event(&mut self, mut queue: &mut Queue) {
+        if let Some(timer) = &mut self.tx_rate_limiter_timer {
+            if let Err(e) = timer.wait() {
+                error!("Failed to get tx rate limiter timer event: {:?}", e);
+            }
+        }
+
+        self.process_tx(&mut queue).unwrap();
+    }
+
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
         mut queues: Vec<Queue>,
         queue_evts: Vec<EventFd>,
     ) -> result::Result<(), DeviceError> {
@@ -233,6 +347,24 @@ impl NetEpollHandler {
             epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PAUSE_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
+        if let Some(timer) = &self.rx_rate_limiter_timer {
+            epoll::ctl(
+                self.epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                timer.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(RX_RATE_LIMITER_EVENT)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+        if let Some(timer) = &self.tx_rate_limiter_timer {
+            epoll::ctl(
+                self.epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                timer.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(TX_RATE_LIMITER_EVENT)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
 
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); NET_EVENTS_COUNT];
 
@@ -267,18 +399,19 @@ impl NetEpollHandler {
                     RX_TAP_EVENT => {
                         self.handle_rx_tap_event(&mut queues[0]);
                     }
+                    RX_RATE_LIMITER_EVENT => {
+                        self.handle_rx_rate_limiter_event(&mut queues[0]);
+                    }
+                    TX_RATE_LIMITER_EVENT => {
+                        self.handle_tx_rate_limiter_event(&mut queues[1]);
+                    }
                     KILL_EVENT => {
                         debug!("KILL_EVENT received, stopping epoll loop");
                         break 'epoll;
                     }
                     PAUSE_EVENT => {
                         debug!("PAUSE_EVENT received, pausing virtio-net epoll loop");
-                        // We loop here to handle spurious park() returns.
-                        // Until we have not resumed, the paused boolean will
-                        // be true.
-                        while paused.load(Ordering::SeqCst) {
-                            thread::park();
-                        }
+                        wait_for_resume(&paused, &paused_sync);
                     }
                     _ => {
                         error!("Unknown event for virtio-net");
@@ -293,7 +426,7 @@ impl NetEpollHandler {
 pub struct Net {
     kill_evt: Option<EventFd>,
     pause_evt: Option<EventFd>,
-    taps: Option<Vec<Tap>>,
+    taps: Option<Vec<Box<dyn NetBackend>>>,
     avail_features: u64,
     acked_features: u64,
     config: VirtioNetConfig,
@@ -302,34 +435,52 @@ pub struct Net {
     epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
     ctrl_queue_epoll_thread: Option<thread::JoinHandle<result::Result<(), DeviceError>>>,
     paused: Arc<AtomicBool>,
+    paused_sync: Option<Arc<Barrier>>,
     queue_size: Vec<u16>,
+    transitional: bool,
+    // Always there, unlimited by default, for the budgets to be changed
+    // while the device is running.
+    rx_rate_limiter: Arc<Mutex<RateLimiter>>,
+    tx_rate_limiter: Arc<Mutex<RateLimiter>>,
 }
 
 impl Net {
-    /// Create a new virtio network device with the given TAP interface.
-    pub fn new_with_tap(
-        taps: Vec<Tap>,
+    // The backends have to support the checksum and segmentation offloads
+    // for the device to offer them.
+    #[allow(clippy::too_many_arguments)]
+    fn new_with_backends(
+        backends: Vec<Box<dyn NetBackend>>,
+        offloads: NetOffloads,
         guest_mac: Option<MacAddr>,
+        mtu: Option<u16>,
         iommu: bool,
         num_queues: usize,
         queue_size: u16,
+        transitional: bool,
+        standby: bool,
     ) -> Result<Self> {
-        let mut avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
-            | 1 << VIRTIO_NET_F_CSUM
-            | 1 << VIRTIO_NET_F_GUEST_TSO4
-            | 1 << VIRTIO_NET_F_GUEST_UFO
-            | 1 << VIRTIO_NET_F_HOST_TSO4
-            | 1 << VIRTIO_NET_F_HOST_UFO
-            | 1 << VIRTIO_F_VERSION_1;
+        if num_queues == 0 || num_queues % 2 != 0 || backends.len() != num_queues / 2 {
+            return Err(Error::InvalidNumQueues(num_queues));
+        }
+
+        let mut avail_features = 1 << VIRTIO_F_VERSION_1 | offloads.features();
 
         if iommu {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
         }
 
+        if standby {
+            avail_features |= 1u64 << VIRTIO_NET_F_STANDBY;
+        }
+
         avail_features |= 1 << VIRTIO_NET_F_CTRL_VQ;
         let queue_num = num_queues + 1;
 
         let mut config = VirtioNetConfig::default();
+        if let Some(mtu) = mtu {
+            config.mtu = mtu;
+            avail_features |= 1 << VIRTIO_NET_F_MTU;
+        }
         if let Some(mac) = guest_mac {
             build_net_config_space(&mut config, mac, num_queues, &mut avail_features);
         } else {
@@ -339,7 +490,7 @@ impl Net {
         Ok(Net {
             kill_evt: None,
             pause_evt: None,
-            taps: Some(taps),
+            taps: Some(backends),
             avail_features,
             acked_features: 0u64,
             config,
@@ -348,24 +499,169 @@ impl Net {
             epoll_threads: None,
             ctrl_queue_epoll_thread: None,
             paused: Arc::new(AtomicBool::new(false)),
+            paused_sync: None,
             queue_size: vec![queue_size; queue_num],
+            transitional,
+            rx_rate_limiter: Arc::new(Mutex::new(RateLimiter::default())),
+            tx_rate_limiter: Arc::new(Mutex::new(RateLimiter::default())),
         })
     }
 
+    /// Limits the frames received and sent by the guest, the budgets being
+    /// shared by all the queue pairs. A missing limiter lifts the limit.
+    /// This can be done at any time, the new budgets applying to the next
+    /// frames.
+    pub fn set_rate_limiters(&self, rx: Option<RateLimiter>, tx: Option<RateLimiter>) {
+        *self.rx_rate_limiter.lock().unwrap() = rx.unwrap_or_default();
+        *self.tx_rate_limiter.lock().unwrap() = tx.unwrap_or_default();
+    }
+
+    /// Create a new virtio network device with the given TAP interface,
+    /// advertising `mtu` and `offloads` to the guest. The taps must have
+    /// been opened with the same offloads.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_with_tap(
+        taps: Vec<Tap>,
+        guest_mac: Option<MacAddr>,
+        mtu: Option<u16>,
+        offloads: NetOffloads,
+        iommu: bool,
+        num_queues: usize,
+        queue_size: u16,
+        transitional: bool,
+        standby: bool,
+    ) -> Result<Self> {
+        Self::new_with_backends(
+            taps.into_iter()
+                .map(|tap| Box::new(tap) as Box<dyn NetBackend>)
+                .collect(),
+            offloads,
+            guest_mac,
+            mtu,
+            iommu,
+            num_queues,
+            queue_size,
+            transitional,
+            standby,
+        )
+    }
+
+    /// Create a new virtio network device on top of AF_XDP sockets, bound
+    /// to the queues of the NIC `if_name`, one per queue pair. The XDP
+    /// program redirecting the frames to the sockets must have been loaded,
+    /// its XSKMAP being pinned at `xsk_map`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_with_xdp(
+        if_name: &str,
+        xsk_map: &Path,
+        guest_mac: Option<MacAddr>,
+        iommu: bool,
+        num_queues: usize,
+        queue_size: u16,
+        transitional: bool,
+        standby: bool,
+    ) -> Result<Self> {
+        if num_queues == 0 || num_queues % 2 != 0 {
+            return Err(Error::InvalidNumQueues(num_queues));
+        }
+        let xsks = open_xsk(if_name, xsk_map, num_queues / 2).map_err(Error::OpenXsk)?;
+
+        // The frames go to the NIC as they are, segmentation and checksums
+        // included.
+        Self::new_with_backends(
+            xsks.into_iter()
+                .map(|xsk| Box::new(xsk) as Box<dyn NetBackend>)
+                .collect(),
+            NetOffloads::none(),
+            guest_mac,
+            None,
+            iommu,
+            num_queues,
+            queue_size,
+            transitional,
+            standby,
+        )
+    }
+
+    /// Create a new virtio network device on top of the tap or macvtap
+    /// queues opened by the caller as `fds`, one per queue pair, `host_mac`
+    /// being the MAC address of the interface on the host. `mtu` and
+    /// `offloads` are set on the interface and advertised to the guest.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_with_tap_fds(
+        fds: &[RawFd],
+        host_mac: Option<MacAddr>,
+        guest_mac: Option<MacAddr>,
+        mtu: Option<u16>,
+        offloads: NetOffloads,
+        iommu: bool,
+        num_queues: usize,
+        queue_size: u16,
+        transitional: bool,
+        standby: bool,
+    ) -> Result<Self> {
+        if num_queues == 0 || num_queues % 2 != 0 || fds.len() != num_queues / 2 {
+            return Err(Error::InvalidNumQueues(num_queues));
+        }
+        let taps = open_tap_fds(fds, host_mac, mtu, offloads).map_err(Error::OpenTap)?;
+
+        Self::new_with_tap(
+            taps,
+            guest_mac,
+            mtu,
+            offloads,
+            iommu,
+            num_queues,
+            queue_size,
+            transitional,
+            standby,
+        )
+    }
+
     /// Create a new virtio network device with the given IP address and
-    /// netmask.
+    /// netmask, `host_mac` being the MAC address of the interface on the host.
+    /// `mtu` and `offloads` are set on the interface and advertised to the
+    /// guest.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         if_name: Option<&str>,
         ip_addr: Option<Ipv4Addr>,
         netmask: Option<Ipv4Addr>,
+        host_mac: Option<MacAddr>,
         guest_mac: Option<MacAddr>,
+        mtu: Option<u16>,
+        offloads: NetOffloads,
         iommu: bool,
         num_queues: usize,
         queue_size: u16,
+        transitional: bool,
+        standby: bool,
     ) -> Result<Self> {
-        let taps = open_tap(if_name, ip_addr, netmask, num_queues / 2).map_err(Error::OpenTap)?;
-
-        Self::new_with_tap(taps, guest_mac, iommu, num_queues, queue_size)
+        if num_queues == 0 || num_queues % 2 != 0 {
+            return Err(Error::InvalidNumQueues(num_queues));
+        }
+        let taps = open_tap(
+            if_name,
+            ip_addr,
+            netmask,
+            host_mac,
+            mtu,
+            offloads,
+            num_queues / 2,
+        )
+        .map_err(Error::OpenTap)?;
+
+        Self::new_with_tap(
+            taps,
+            guest_mac,
+            mtu,
+            offloads,
+            iommu,
+            num_queues,
+            queue_size,
+            transitional,
+            standby,
+        )
     }
 }
 
@@ -391,6 +687,10 @@ impl VirtioDevice for Net {
         self.avail_features
     }
 
+    fn transitional(&self) -> bool {
+        self.transitional
+    }
+
     fn ack_features(&mut self, value: u64) {
         let mut v = value;
         // Check if the guest is ACK'ing a feature that we didn't claim to have.
@@ -461,7 +761,25 @@ impl VirtioDevice for Net {
             })?;
         self.pause_evt = Some(self_pause_evt);
 
-        if let Some(mut taps) = self.taps.clone() {
+        if let Some(mut taps) = self
+            .taps
+            .as_ref()
+            .map(|taps| taps.iter().map(|tap| tap.box_clone()).collect::<Vec<_>>())
+        {
+            // Drivers which didn't negotiate VIRTIO_F_VERSION_1 use the legacy
+            // header, which lacks the num_buffers field.
+            let vnet_hdr_size = if self.acked_features & (1 << VIRTIO_F_VERSION_1) != 0 {
+                vnet_hdr_len()
+            } else {
+                mem::size_of::<virtio_net_hdr>()
+            };
+            for tap in taps.iter() {
+                tap.set_vnet_hdr_size(vnet_hdr_size).map_err(|e| {
+                    error!("failed to set vnet header size: {:?}", e);
+                    ActivateError::BadActivate
+                })?;
+            }
+
             // Save the interrupt EventFD as we need to return it on reset
             // but clone it to pass into the thread.
             self.interrupt_cb = Some(interrupt_cb.clone());
@@ -478,7 +796,14 @@ impl VirtioDevice for Net {
             self.queue_evts = Some(tmp_queue_evts);
 
             let queue_num = queues.len();
-            if (self.acked_features & 1 << VIRTIO_NET_F_CTRL_VQ) != 0 && queue_num % 2 != 0 {
+            let ctrl_queue =
+                (self.acked_features & 1 << VIRTIO_NET_F_CTRL_VQ) != 0 && queue_num % 2 != 0;
+
+            // One epoll thread per queue pair and the control queue one,
+            // plus the device pausing them.
+            let paused_sync = Arc::new(Barrier::new(taps.len() + ctrl_queue as usize + 1));
+
+            if ctrl_queue {
                 let cvq_queue = queues.remove(queue_num - 1);
                 let cvq_queue_evt = queue_evts.remove(queue_num - 1);
 
@@ -491,9 +816,10 @@ impl VirtioDevice for Net {
                 };
 
                 let paused = self.paused.clone();
+                let paused_sync = paused_sync.clone();
                 thread::Builder::new()
-                    .name("virtio_net".to_string())
-                    .spawn(move || ctrl_handler.run_ctrl(paused))
+                    .name("virtio_net_ctrl".to_string())
+                    .spawn(move || ctrl_handler.run_ctrl(paused, paused_sync))
                     .map(|thread| self.ctrl_queue_epoll_thread = Some(thread))
                     .map_err(|e| {
                         error!("failed to clone queue EventFd: {}", e);
@@ -502,7 +828,9 @@ impl VirtioDevice for Net {
             }
 
             let mut epoll_threads = Vec::new();
-            for _ in 0..taps.len() {
+            // Each queue pair gets its own thread, along with its own tap
+            // queue, so that the throughput scales with the guest vCPUs.
+            for i in 0..taps.len() {
                 let rx = RxVirtio::new();
                 let tx = TxVirtio::new();
                 let rx_tap_listening = false;
@@ -515,6 +843,8 @@ impl VirtioDevice for Net {
                 queue_evt_pair.push(queue_evts.remove(0));
                 queue_evt_pair.push(queue_evts.remove(0));
 
+                let rx_rate_limiter = Some(self.rx_rate_limiter.clone());
+                let tx_rate_limiter = Some(self.tx_rate_limiter.clone());
                 let mut handler = NetEpollHandler {
                     mem: mem.clone(),
                     tap: taps.remove(0),
@@ -525,12 +855,18 @@ impl VirtioDevice for Net {
                     pause_evt: pause_evt.try_clone().unwrap(),
                     epoll_fd: 0,
                     rx_tap_listening,
+                    rx_rate_limiter_timer: rate_limiter_timer(&rx_rate_limiter)?,
+                    rx_rate_limiter,
+                    rx_throttled: false,
+                    tx_rate_limiter_timer: rate_limiter_timer(&tx_rate_limiter)?,
+                    tx_rate_limiter,
                 };
 
                 let paused = self.paused.clone();
+                let paused_sync = paused_sync.clone();
                 thread::Builder::new()
-                    .name("virtio_net".to_string())
-                    .spawn(move || handler.run(paused, queue_pair, queue_evt_pair))
+                    .name(format!("virtio_net_q{}", i))
+                    .spawn(move || handler.run(paused, paused_sync, queue_pair, queue_evt_pair))
                     .map(|thread| epoll_threads.push(thread))
                     .map_err(|e| {
                         error!("failed to clone queue EventFd: {}", e);
@@ -539,6 +875,7 @@ impl VirtioDevice for Net {
             }
 
             self.epoll_threads = Some(epoll_threads);
+            self.paused_sync = Some(paused_sync);
 
             return Ok(());
         }
@@ -562,8 +899,51 @@ impl VirtioDevice for Net {
             self.queue_evts.take().unwrap(),
         ))
     }
+
+    fn shutdown(&mut self) {
+        // A paused thread wouldn't see the kill event.
+        if self.paused.load(Ordering::SeqCst) {
+            let _ = self.resume();
+        }
+        if let Some(kill_evt) = self.kill_evt.take() {
+            let _ = kill_evt.write(1);
+        }
+
+        // The taps are owned by the epoll threads once activated, and closed
+        // as they exit.
+        if let Some(epoll_threads) = self.epoll_threads.take() {
+            join_epoll_threads("virtio-net", epoll_threads);
+        }
+        if let Some(ctrl_queue_epoll_thread) = self.ctrl_queue_epoll_thread.take() {
+            join_epoll_threads("virtio-net", vec![ctrl_queue_epoll_thread]);
+        }
+        self.taps = None;
+    }
 }
 
 virtio_ctrl_q_pausable!(Net);
-impl Snapshotable for Net {}
+impl Snapshotable for Net {
+    fn id(&self) -> String {
+        "virtio-net".to_string()
+    }
+
+    fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        VirtioDeviceState::new(
+            self.avail_features,
+            self.acked_features,
+            self.config.as_slice(),
+        )
+        .add_to(&mut snapshot)?;
+        Ok(snapshot)
+    }
+
+    // The frames in flight are lost, as they would on a real network.
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        let virtio_state = VirtioDeviceState::from(&snapshot)?;
+        self.acked_features = virtio_state.acked_features(self.avail_features)?;
+        virtio_state.restore_config(&mut self.config)?;
+        Ok(())
+    }
+}
 impl Migratable for Net {}
@@ -3,16 +3,19 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use super::Error as DeviceError;
-use super::{DescriptorChain, DeviceEventT, Queue};
-use net_util::{MacAddr, Tap, TapError};
+use super::{wait_for_resume, DescriptorChain, DeviceEventT, Queue, RateLimiter};
+use net_util::{MacAddr, Tap, TapError, Xsk, XskError};
 use std::cmp;
 use std::fs;
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
 use std::mem;
 use std::net::Ipv4Addr;
+use std::os::raw::c_uint;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Barrier, Mutex};
+use std::time::Duration;
 use virtio_bindings::bindings::virtio_net::*;
 use vm_memory::{
     ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryError,
@@ -38,8 +41,12 @@ pub const RX_TAP_EVENT: DeviceEventT = 2;
 pub const KILL_EVENT: DeviceEventT = 3;
 // The device should be paused.
 pub const PAUSE_EVENT: DeviceEventT = 4;
+// The rate limiter allows receiving frames again.
+pub const RX_RATE_LIMITER_EVENT: DeviceEventT = 5;
+// The rate limiter allows sending frames again.
+pub const TX_RATE_LIMITER_EVENT: DeviceEventT = 6;
 // Number of DeviceEventT events supported by this implementation.
-pub const NET_EVENTS_COUNT: usize = 5;
+pub const NET_EVENTS_COUNT: usize = 7;
 // The device has been dropped.
 const CTRL_QUEUE_EVENT: DeviceEventT = 0;
 // Number of DeviceEventT events supported by this implementation.
@@ -89,12 +96,53 @@ pub enum Error {
     TapSetIp(TapError),
     /// Setting tap netmask failed.
     TapSetNetmask(TapError),
+    /// Setting tap MAC address failed.
+    TapSetMac(TapError),
+    /// Setting tap MTU failed.
+    TapSetMtu(TapError),
+    /// The tap fds are queues of different interfaces.
+    MixedTapFds,
     /// Setting tap interface offload flags failed.
     TapSetOffload(TapError),
     /// Setting vnet header size failed.
     TapSetVnetHdrSize(TapError),
     /// Enabling tap interface failed.
     TapEnable(TapError),
+    /// Opening an AF_XDP socket failed.
+    XskOpen(XskError),
+}
+
+/// The host end of a virtio-net queue pair, the frames read from it and
+/// written to it starting with the virtio-net header. It's non-blocking, a
+/// read failing with EAGAIN when there is no frame, and its fd is polled for
+/// the frames to read.
+pub trait NetBackend: Read + Write + AsRawFd + Send {
+    /// Sets the size of the virtio-net header the frames start with.
+    fn set_vnet_hdr_size(&self, size: usize) -> Result<()>;
+
+    /// Returns another handle to the same backend.
+    fn box_clone(&self) -> Box<dyn NetBackend>;
+}
+
+impl NetBackend for Tap {
+    fn set_vnet_hdr_size(&self, size: usize) -> Result<()> {
+        Tap::set_vnet_hdr_size(self, size as i32).map_err(Error::TapSetVnetHdrSize)
+    }
+
+    fn box_clone(&self) -> Box<dyn NetBackend> {
+        Box::new(self.clone())
+    }
+}
+
+impl NetBackend for Xsk {
+    fn set_vnet_hdr_size(&self, size: usize) -> Result<()> {
+        Xsk::set_vnet_hdr_size(self, size);
+        Ok(())
+    }
+
+    fn box_clone(&self) -> Box<dyn NetBackend> {
+        Box::new(self.clone())
+    }
 }
 
 pub struct CtrlVirtio {
@@ -212,7 +260,11 @@ pub struct NetCtrlEpollHandler {
 }
 
 impl NetCtrlEpollHandler {
-    pub fn run_ctrl(&mut self, paused: Arc<AtomicBool>) -> std::result::Result<(), DeviceError> {
+    pub fn run_ctrl(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> std::result::Result<(), DeviceError> {
         // Create the epoll file descriptor
         self.epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
 
@@ -269,12 +321,7 @@ impl NetCtrlEpollHandler {
                     }
                     PAUSE_EVENT => {
                         debug!("PAUSE_EVENT received, pausing vhost-user epoll loop");
-                        // We loop here to handle spurious park() returns.
-                        // Until we have not resumed, the paused boolean will
-                        // be true.
-                        while paused.load(Ordering::SeqCst) {
-                            std::thread::park();
-                        }
+                        wait_for_resume(&paused, &paused_sync);
                     }
                     _ => {
                         error!("Unknown event for virtio-net");
@@ -307,7 +354,17 @@ impl TxVirtio {
         }
     }
 
-    pub fn process_desc_chain(&mut self, mem: &GuestMemoryMmap, tap: &mut Tap, queue: &mut Queue) {
+    /// Sends the frames available in `queue` to `tap`, as long as
+    /// `rate_limiter` allows it. Returns the time after which the remaining
+    /// frames can be sent when throttled.
+    pub fn process_desc_chain(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        tap: &mut dyn Write,
+        queue: &mut Queue,
+        rate_limiter: Option<&Mutex<RateLimiter>>,
+    ) -> Option<Duration> {
+        let mut throttled = None;
         while let Some(avail_desc) = queue.iter(&mem).next() {
             let head_index = avail_desc.index;
             let mut read_count = 0;
@@ -323,6 +380,15 @@ impl TxVirtio {
                 next_desc = desc.next_descriptor();
             }
 
+            if let Some(rate_limiter) = rate_limiter {
+                if let Err(wait) = rate_limiter.lock().unwrap().consume(read_count as u64) {
+                    // The frame will be sent once the rate limiter allows it.
+                    queue.go_to_previous_position();
+                    throttled = Some(wait);
+                    break;
+                }
+            }
+
             read_count = 0;
             // Copy buffer from across multiple descriptors.
             // TODO(performance - Issue #420): change this to use `writev()` instead of `write()`
@@ -353,6 +419,13 @@ impl TxVirtio {
             };
             queue.add_used(&mem, head_index, 0);
         }
+
+        // Some backends only queue the frames until flushed.
+        if let Err(e) = tap.flush() {
+            error!("net: tx: failed to flush frames: {}", e);
+        }
+
+        throttled
     }
 }
 
@@ -457,7 +530,7 @@ pub fn build_net_config_space_with_mq(
     }
 }
 
-fn vnet_hdr_len() -> usize {
+pub fn vnet_hdr_len() -> usize {
     mem::size_of::<virtio_net_hdr_v1>()
 }
 
@@ -481,30 +554,116 @@ fn check_mq_support(if_name: &Option<&str>, queue_pairs: usize) -> Result<()> {
     Ok(())
 }
 
+// Set up a queue of the tap, the offloads and the header size being
+// per queue.
+/// The checksum and segmentation offloads negotiated with the guest, and
+/// programmed on the tap.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NetOffloads {
+    pub csum: bool,
+    pub tso: bool,
+    pub ufo: bool,
+}
+
+impl Default for NetOffloads {
+    fn default() -> Self {
+        NetOffloads {
+            csum: true,
+            tso: true,
+            ufo: true,
+        }
+    }
+}
+
+impl NetOffloads {
+    pub fn none() -> Self {
+        NetOffloads {
+            csum: false,
+            tso: false,
+            ufo: false,
+        }
+    }
+
+    // The segmentation offloads rely on the checksum offload, both for the
+    // guest and for the tap.
+    fn tso(&self) -> bool {
+        self.csum && self.tso
+    }
+
+    fn ufo(&self) -> bool {
+        self.csum && self.ufo
+    }
+
+    /// The virtio-net features advertising the offloads to the guest.
+    pub fn features(&self) -> u64 {
+        let mut features = 0;
+        if self.csum {
+            features |= 1 << VIRTIO_NET_F_GUEST_CSUM | 1 << VIRTIO_NET_F_CSUM;
+        }
+        if self.tso() {
+            features |= 1 << VIRTIO_NET_F_GUEST_TSO4 | 1 << VIRTIO_NET_F_HOST_TSO4;
+        }
+        if self.ufo() {
+            features |= 1 << VIRTIO_NET_F_GUEST_UFO | 1 << VIRTIO_NET_F_HOST_UFO;
+        }
+        features
+    }
+
+    // The offloads the tap may apply to the frames it hands over, i.e. the
+    // frames the guest receives.
+    fn tap_flags(&self) -> c_uint {
+        let mut flags = 0;
+        if self.csum {
+            flags |= net_gen::TUN_F_CSUM;
+        }
+        if self.tso() {
+            flags |= net_gen::TUN_F_TSO4 | net_gen::TUN_F_TSO6;
+        }
+        if self.ufo() {
+            flags |= net_gen::TUN_F_UFO;
+        }
+        flags
+    }
+}
+
+fn configure_tap_queue(tap: &Tap, offloads: NetOffloads) -> Result<()> {
+    tap.set_offload(offloads.tap_flags())
+        .map_err(Error::TapSetOffload)?;
+    tap.set_vnet_hdr_size(vnet_hdr_len() as i32)
+        .map_err(Error::TapSetVnetHdrSize)
+}
+
 /// Create a new virtio network device with the given IP address and
-/// netmask.
+/// netmask. The interface `if_name` can be a tap, or a macvtap created
+/// beforehand. `host_mac` is the MAC address of the interface on the host.
 pub fn open_tap(
     if_name: Option<&str>,
     ip_addr: Option<Ipv4Addr>,
     netmask: Option<Ipv4Addr>,
+    host_mac: Option<MacAddr>,
+    mtu: Option<u16>,
+    offloads: NetOffloads,
     num_rx_q: usize,
 ) -> Result<Vec<Tap>> {
     let mut taps: Vec<Tap> = Vec::new();
     let mut ifname: String = String::new();
-    let vnet_hdr_size = vnet_hdr_len() as i32;
-    let flag = net_gen::TUN_F_CSUM | net_gen::TUN_F_UFO | net_gen::TUN_F_TSO4 | net_gen::TUN_F_TSO6;
+    let macvtap = if_name.map_or(false, Tap::is_macvtap);
 
     // In case the tap interface already exists, check if the number of
     // queues is appropriate. The tap might not support multiqueue while
     // the number of queues indicates the user expects multiple queues, or
     // on the contrary, the tap might support multiqueue while the number
     // of queues indicates the user doesn't expect multiple queues.
-    check_mq_support(&if_name, num_rx_q)?;
+    // A macvtap gets a queue every time it's opened.
+    if !macvtap {
+        check_mq_support(&if_name, num_rx_q)?;
+    }
 
     for i in 0..num_rx_q {
         let tap: Tap;
         if i == 0 {
             tap = match if_name {
+                Some(name) if macvtap => Tap::open_macvtap(name).map_err(Error::TapOpen)?,
                 Some(name) => Tap::open_named(name, num_rx_q).map_err(Error::TapOpen)?,
                 None => Tap::new(num_rx_q).map_err(Error::TapOpen)?,
             };
@@ -514,21 +673,118 @@ pub fn open_tap(
             if let Some(mask) = netmask {
                 tap.set_netmask(mask).map_err(Error::TapSetNetmask)?;
             }
+            if let Some(mac) = host_mac {
+                tap.set_mac_addr(mac).map_err(Error::TapSetMac)?;
+            }
+            if let Some(mtu) = mtu {
+                tap.set_mtu(mtu).map_err(Error::TapSetMtu)?;
+            }
             tap.enable().map_err(Error::TapEnable)?;
-            tap.set_offload(flag).map_err(Error::TapSetOffload)?;
-
-            tap.set_vnet_hdr_size(vnet_hdr_size)
-                .map_err(Error::TapSetVnetHdrSize)?;
+            configure_tap_queue(&tap, offloads)?;
 
             ifname = String::from_utf8(tap.get_if_name()).unwrap();
         } else {
-            tap = Tap::open_named(ifname.as_str(), num_rx_q).map_err(Error::TapOpen)?;
-            tap.set_offload(flag).map_err(Error::TapSetOffload)?;
+            tap = if macvtap {
+                Tap::open_macvtap(ifname.as_str()).map_err(Error::TapOpen)?
+            } else {
+                Tap::open_named(ifname.as_str(), num_rx_q).map_err(Error::TapOpen)?
+            };
+            configure_tap_queue(&tap, offloads)?;
+        }
+        taps.push(tap);
+    }
+    Ok(taps)
+}
 
-            tap.set_vnet_hdr_size(vnet_hdr_size)
-                .map_err(Error::TapSetVnetHdrSize)?;
+/// Use the queues of a tap or macvtap the caller opened as `fds`, one per
+/// queue pair. `host_mac` is the MAC address of the interface on the host.
+pub fn open_tap_fds(
+    fds: &[RawFd],
+    host_mac: Option<MacAddr>,
+    mtu: Option<u16>,
+    offloads: NetOffloads,
+) -> Result<Vec<Tap>> {
+    let mut taps: Vec<Tap> = Vec::new();
+    for fd in fds {
+        let tap = Tap::from_tap_fd(*fd).map_err(Error::TapOpen)?;
+        match taps.first() {
+            Some(first) if first.get_if_name() != tap.get_if_name() => {
+                return Err(Error::MixedTapFds);
+            }
+            Some(_) => {}
+            None => {
+                if let Some(mac) = host_mac {
+                    tap.set_mac_addr(mac).map_err(Error::TapSetMac)?;
+                }
+                if let Some(mtu) = mtu {
+                    tap.set_mtu(mtu).map_err(Error::TapSetMtu)?;
+                }
+                tap.enable().map_err(Error::TapEnable)?;
+            }
         }
+        configure_tap_queue(&tap, offloads)?;
         taps.push(tap);
     }
     Ok(taps)
 }
+
+/// Open one AF_XDP socket per queue pair, bound to the queues of the NIC
+/// `if_name` with the same indexes, and inserted in the XSKMAP pinned at
+/// `xsk_map`.
+pub fn open_xsk(if_name: &str, xsk_map: &Path, num_queue_pairs: usize) -> Result<Vec<Xsk>> {
+    (0..num_queue_pairs)
+        .map(|queue_id| Xsk::new(if_name, queue_id as u32, xsk_map).map_err(Error::XskOpen))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::tests::VirtQueue as GuestQ;
+    use crate::TokenBucket;
+    use std::thread;
+
+    const QUEUE_SIZE: u16 = 16;
+
+    // The guest sends a frame of `len` bytes from the descriptor `index`.
+    fn push_frame(queue: &GuestQ, mem: &GuestMemoryMmap, index: u16, len: u32) {
+        let addr = 0x1000 * (u64::from(index) + 1);
+        mem.write_slice(&vec![index as u8; len as usize], GuestAddress(addr))
+            .unwrap();
+        queue.dtable[index as usize].set(addr, len, 0, 0);
+
+        let avail_idx = queue.avail.idx.get();
+        queue.avail.ring[(avail_idx % QUEUE_SIZE) as usize].set(index);
+        queue.avail.idx.set(avail_idx + 1);
+    }
+
+    #[test]
+    fn test_tx_rate_limiter_refill() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+        let guest_queue = GuestQ::new(GuestAddress(0x8_0000), &mem, QUEUE_SIZE);
+        let mut queue = guest_queue.create_queue();
+        let rate_limiter = Mutex::new(RateLimiter::new(TokenBucket::new(1000, 0, 100), None));
+        let mut tx = TxVirtio::new();
+        let mut tap = Vec::new();
+
+        push_frame(&guest_queue, &mem, 0, 600);
+        push_frame(&guest_queue, &mem, 1, 600);
+
+        // Only the first frame fits in the bucket, the second one is left
+        // in the queue.
+        let wait = tx
+            .process_desc_chain(&mem, &mut tap, &mut queue, Some(&rate_limiter))
+            .unwrap();
+        assert!(wait > Duration::from_millis(0) && wait <= Duration::from_millis(20));
+        assert_eq!(tap, vec![0u8; 600]);
+        assert_eq!(guest_queue.used.idx.get(), 1);
+
+        // The second frame is sent once the bucket has been refilled.
+        thread::sleep(wait);
+        assert!(tx
+            .process_desc_chain(&mem, &mut tap, &mut queue, Some(&rate_limiter))
+            .is_none());
+        assert_eq!(&tap[600..], &[1u8; 600][..]);
+        assert_eq!(guest_queue.used.idx.get(), 2);
+    }
+}
@@ -11,7 +11,8 @@ use super::{
     ActivateError, ActivateResult, DescriptorChain, DeviceEventT, Queue, VirtioDevice,
     VirtioDeviceType, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
 };
-use crate::{VirtioInterrupt, VirtioInterruptType};
+use crate::device::{join_epoll_threads, wait_for_resume};
+use crate::{VirtioDeviceState, VirtioInterrupt, VirtioInterruptType};
 use epoll;
 use libc::EFD_NONBLOCK;
 use std::cmp;
@@ -22,9 +23,9 @@ use std::mem::size_of;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Barrier};
 use std::thread;
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
     GuestMemoryError, GuestMemoryMmap, GuestUsize,
@@ -221,7 +222,11 @@ impl PmemEpollHandler {
             })
     }
 
-    fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), DeviceError> {
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), DeviceError> {
         // Create the epoll file descriptor
         let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
 
@@ -291,12 +296,7 @@ impl PmemEpollHandler {
                     }
                     PAUSE_EVENT => {
                         debug!("PAUSE_EVENT received, pausing virtio-pmem epoll loop");
-                        // We loop here to handle spurious park() returns.
-                        // Until we have not resumed, the paused boolean will
-                        // be true.
-                        while paused.load(Ordering::SeqCst) {
-                            thread::park();
-                        }
+                        wait_for_resume(&paused, &paused_sync);
                     }
                     _ => {
                         error!("Unknown event for virtio-block");
@@ -320,6 +320,7 @@ pub struct Pmem {
     interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
     epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
     paused: Arc<AtomicBool>,
+    paused_sync: Option<Arc<Barrier>>,
 }
 
 impl Pmem {
@@ -346,6 +347,7 @@ impl Pmem {
             interrupt_cb: None,
             epoll_threads: None,
             paused: Arc::new(AtomicBool::new(false)),
+            paused_sync: None,
         })
     }
 }
@@ -467,10 +469,12 @@ impl VirtioDevice for Pmem {
             };
 
             let paused = self.paused.clone();
+            let paused_sync = Arc::new(Barrier::new(2));
+            let paused_sync_clone = paused_sync.clone();
             let mut epoll_threads = Vec::new();
             thread::Builder::new()
                 .name("virtio_pmem".to_string())
-                .spawn(move || handler.run(paused))
+                .spawn(move || handler.run(paused, paused_sync_clone))
                 .map(|thread| epoll_threads.push(thread))
                 .map_err(|e| {
                     error!("failed to clone virtio-pmem epoll thread: {}", e);
@@ -478,6 +482,7 @@ impl VirtioDevice for Pmem {
                 })?;
 
             self.epoll_threads = Some(epoll_threads);
+            self.paused_sync = Some(paused_sync);
 
             return Ok(());
         }
@@ -501,8 +506,52 @@ impl VirtioDevice for Pmem {
             self.queue_evts.take().unwrap(),
         ))
     }
+
+    fn shutdown(&mut self) {
+        // A paused thread wouldn't see the kill event.
+        if self.paused.load(Ordering::SeqCst) {
+            let _ = self.resume();
+        }
+        if let Some(kill_evt) = self.kill_evt.take() {
+            let _ = kill_evt.write(1);
+        }
+        if let Some(epoll_threads) = self.epoll_threads.take() {
+            join_epoll_threads("virtio-pmem", epoll_threads);
+        }
+
+        // The guest writes through the shared mapping of the file, which
+        // only reach the file once written back. The guest may not have
+        // asked for a flush before going away.
+        if let Some(disk) = self.disk.as_ref() {
+            if let Err(e) = disk.sync_all() {
+                error!("Failed to flush the pmem file: {:?}", e);
+            }
+        }
+    }
 }
 
 virtio_pausable!(Pmem);
-impl Snapshotable for Pmem {}
+impl Snapshotable for Pmem {
+    fn id(&self) -> String {
+        "virtio-pmem".to_string()
+    }
+
+    fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        VirtioDeviceState::new(
+            self.avail_features,
+            self.acked_features,
+            self.config.as_slice(),
+        )
+        .add_to(&mut snapshot)?;
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        let virtio_state = VirtioDeviceState::from(&snapshot)?;
+        self.acked_features = virtio_state.acked_features(self.avail_features)?;
+        virtio_state.restore_config(&mut self.config)?;
+        Ok(())
+    }
+}
 impl Migratable for Pmem {}
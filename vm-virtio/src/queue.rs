@@ -418,6 +418,11 @@ impl Queue {
 
     /// A consuming iterator over all available descriptor chain heads offered by the driver.
     pub fn iter<'a, 'b>(&'b mut self, mem: &'a GuestMemoryMmap) -> AvailIter<'a, 'b> {
+        // A queue the driver left disabled holds no request.
+        if !self.ready {
+            return AvailIter::new(mem, &mut self.next_avail);
+        }
+
         let queue_size = self.actual_size();
         let avail_ring = self.avail_ring;
 
@@ -528,6 +533,17 @@ impl Queue {
     pub fn go_to_previous_position(&mut self) {
         self.next_avail -= Wrapping(1);
     }
+
+    /// Picks the queue up where it was when restoring a snapshot, from the
+    /// used ring index found in the restored memory. Paused devices have
+    /// used all the descriptors they had taken off the available ring.
+    pub fn restore_position(&mut self, mem: &GuestMemoryMmap) {
+        let used_idx = mem
+            .read_obj::<u16>(self.used_ring.unchecked_add(2))
+            .unwrap_or_default();
+        self.next_avail = Wrapping(used_idx);
+        self.next_used = Wrapping(used_idx);
+    }
 }
 
 #[cfg(test)]
@@ -961,4 +977,22 @@ pub(crate) mod tests {
         assert_eq!(x.id, 1);
         assert_eq!(x.len, 0x1000);
     }
+
+    #[test]
+    fn test_restore_position() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        vq.used.idx.set(0xfffe);
+        let mut q = vq.create_queue();
+        q.restore_position(m);
+        assert_eq!(q.next_avail.0, 0xfffe);
+        assert_eq!(q.next_used.0, 0xfffe);
+
+        q.add_used(m, 1, 0x1000);
+        q.add_used(m, 2, 0x1000);
+        assert_eq!(vq.used.idx.get(), 0);
+        assert_eq!(vq.used.ring[14].get().id, 1);
+        assert_eq!(vq.used.ring[15].get().id, 2);
+    }
 }
@@ -0,0 +1,198 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Token bucket based rate limiting of the virtio queues.
+//!
+//! A `RateLimiter` is made of up to two token buckets, one accounting for the
+//! bytes transferred and the other one for the number of operations. Each
+//! bucket holds at most `size` tokens and is entirely refilled over
+//! `refill_time` milliseconds. An optional one time burst gives some extra
+//! tokens which are consumed first and never refilled, allowing the guest to
+//! boot faster for instance.
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+const NANOS_PER_MILLISECOND: u128 = 1_000_000;
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    // Maximum number of tokens the bucket can hold.
+    size: u64,
+    // Remaining tokens from the initial burst.
+    one_time_burst: u64,
+    // Time it takes to refill the bucket entirely, in nanoseconds.
+    refill_time: u128,
+    // Tokens currently available. It becomes negative when a request
+    // bigger than the bucket has been let through, so that the excess is
+    // paid for before any other request.
+    budget: i128,
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full token bucket, refilled with `size` tokens every
+    /// `refill_time` milliseconds. Returns `None` if either of them is zero,
+    /// which would disable the bucket.
+    pub fn new(size: u64, one_time_burst: u64, refill_time: u64) -> Option<Self> {
+        if size == 0 || refill_time == 0 {
+            return None;
+        }
+
+        Some(TokenBucket {
+            size,
+            one_time_burst,
+            refill_time: u128::from(refill_time) * NANOS_PER_MILLISECOND,
+            budget: i128::from(size),
+            last_update: Instant::now(),
+        })
+    }
+
+    fn refill(&mut self) {
+        let elapsed = self.last_update.elapsed().as_nanos();
+        let tokens = elapsed * u128::from(self.size) / self.refill_time;
+        if tokens == 0 {
+            return;
+        }
+
+        // Only account for the time the new tokens took to be generated,
+        // so that the remainder isn't lost.
+        let used = tokens * self.refill_time / u128::from(self.size);
+        self.last_update += Duration::from_nanos(used as u64);
+        self.budget = cmp::min(self.budget + tokens as i128, i128::from(self.size));
+    }
+
+    fn available(&self) -> i128 {
+        self.budget + i128::from(self.one_time_burst)
+    }
+
+    fn can_consume(&self, tokens: u64) -> bool {
+        // A request bigger than the bucket can only go through once the
+        // bucket is full, otherwise it would be blocked forever.
+        let tokens = cmp::min(tokens, self.size);
+        self.available() >= i128::from(tokens)
+    }
+
+    fn consume(&mut self, tokens: u64) {
+        let from_burst = cmp::min(tokens, self.one_time_burst);
+        self.one_time_burst -= from_burst;
+        self.budget -= i128::from(tokens - from_burst);
+    }
+
+    // Time after which the given number of tokens will be available.
+    fn wait_time(&self, tokens: u64) -> Duration {
+        let tokens = cmp::min(tokens, self.size);
+        let missing = i128::from(tokens) - self.available();
+        if missing <= 0 {
+            return Duration::from_nanos(0);
+        }
+
+        let nanos = (missing as u128 * self.refill_time + u128::from(self.size) - 1)
+            / u128::from(self.size);
+        Duration::from_nanos(nanos as u64)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    bandwidth: Option<TokenBucket>,
+    ops: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter from the bandwidth, in bytes, and operations
+    /// token buckets. A missing bucket doesn't limit anything.
+    pub fn new(bandwidth: Option<TokenBucket>, ops: Option<TokenBucket>) -> Self {
+        RateLimiter { bandwidth, ops }
+    }
+
+    /// Consumes the tokens for one operation transferring `bytes` bytes.
+    /// When the budget is insufficient, nothing is consumed and the time
+    /// after which the operation should be retried is returned instead.
+    pub fn consume(&mut self, bytes: u64) -> Result<(), Duration> {
+        let mut wait = Duration::from_nanos(0);
+        if let Some(bandwidth) = &mut self.bandwidth {
+            bandwidth.refill();
+            if !bandwidth.can_consume(bytes) {
+                wait = cmp::max(wait, bandwidth.wait_time(bytes));
+            }
+        }
+        if let Some(ops) = &mut self.ops {
+            ops.refill();
+            if !ops.can_consume(1) {
+                wait = cmp::max(wait, ops.wait_time(1));
+            }
+        }
+
+        if wait > Duration::from_nanos(0) {
+            return Err(wait);
+        }
+
+        if let Some(bandwidth) = &mut self.bandwidth {
+            bandwidth.consume(bytes);
+        }
+        if let Some(ops) = &mut self.ops {
+            ops.consume(1);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_token_bucket_disabled() {
+        assert!(TokenBucket::new(0, 0, 100).is_none());
+        assert!(TokenBucket::new(100, 0, 0).is_none());
+
+        let mut rate_limiter = RateLimiter::new(None, None);
+        for _ in 0..1000 {
+            assert!(rate_limiter.consume(1 << 20).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_rate_limiter_bandwidth() {
+        let mut rate_limiter = RateLimiter::new(TokenBucket::new(1000, 0, 100), None);
+
+        assert!(rate_limiter.consume(600).is_ok());
+        let wait = rate_limiter.consume(600).unwrap_err();
+        assert!(wait > Duration::from_millis(0) && wait <= Duration::from_millis(20));
+
+        thread::sleep(wait);
+        assert!(rate_limiter.consume(600).is_ok());
+    }
+
+    #[test]
+    fn test_rate_limiter_ops() {
+        let mut rate_limiter = RateLimiter::new(None, TokenBucket::new(2, 0, 100));
+
+        assert!(rate_limiter.consume(0).is_ok());
+        assert!(rate_limiter.consume(0).is_ok());
+        assert!(rate_limiter.consume(0).is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter_one_time_burst() {
+        let mut rate_limiter = RateLimiter::new(TokenBucket::new(1000, 500, 10_000), None);
+
+        assert!(rate_limiter.consume(1500).is_ok());
+        assert!(rate_limiter.consume(1).is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter_oversized_request() {
+        let mut rate_limiter = RateLimiter::new(TokenBucket::new(1000, 0, 100), None);
+
+        // A full bucket lets a bigger request through, which then has to
+        // be paid for.
+        assert!(rate_limiter.consume(3000).is_ok());
+        let wait = rate_limiter.consume(1).unwrap_err();
+        assert!(wait >= Duration::from_millis(190));
+    }
+}
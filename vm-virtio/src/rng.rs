@@ -7,7 +7,7 @@ use super::{
     ActivateError, ActivateResult, DeviceEventT, Queue, VirtioDevice, VirtioDeviceType,
     VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
 };
-use crate::{VirtioInterrupt, VirtioInterruptType};
+use crate::{wait_for_resume, VirtioDeviceState, VirtioInterrupt, VirtioInterruptType};
 use epoll;
 use libc::EFD_NONBLOCK;
 use std;
@@ -16,9 +16,9 @@ use std::io;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Barrier};
 use std::thread;
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
 use vm_memory::{Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vmm_sys_util::eventfd::EventFd;
 
@@ -87,7 +87,11 @@ impl RngEpollHandler {
             })
     }
 
-    fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), DeviceError> {
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), DeviceError> {
         // Create the epoll file descriptor
         let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
 
@@ -156,12 +160,7 @@ impl RngEpollHandler {
                     }
                     PAUSE_EVENT => {
                         debug!("PAUSE_EVENT received, pausing virtio-rng epoll loop");
-                        // We loop here to handle spurious park() returns.
-                        // Until we have not resumed, the paused boolean will
-                        // be true.
-                        while paused.load(Ordering::SeqCst) {
-                            thread::park();
-                        }
+                        wait_for_resume(&paused, &paused_sync);
                     }
                     _ => {
                         error!("Unknown event for virtio-block");
@@ -185,6 +184,7 @@ pub struct Rng {
     interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
     epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
     paused: Arc<AtomicBool>,
+    paused_sync: Option<Arc<Barrier>>,
 }
 
 impl Rng {
@@ -207,6 +207,7 @@ impl Rng {
             interrupt_cb: None,
             epoll_threads: None,
             paused: Arc::new(AtomicBool::new(false)),
+            paused_sync: None,
         })
     }
 }
@@ -317,10 +318,12 @@ impl VirtioDevice for Rng {
             };
 
             let paused = self.paused.clone();
+            let paused_sync = Arc::new(Barrier::new(2));
+            let paused_sync_clone = paused_sync.clone();
             let mut epoll_threads = Vec::new();
             thread::Builder::new()
                 .name("virtio_rng".to_string())
-                .spawn(move || handler.run(paused))
+                .spawn(move || handler.run(paused, paused_sync_clone))
                 .map(|thread| epoll_threads.push(thread))
                 .map_err(|e| {
                     error!("failed to clone the virtio-rng epoll thread: {}", e);
@@ -328,6 +331,7 @@ impl VirtioDevice for Rng {
                 })?;
 
             self.epoll_threads = Some(epoll_threads);
+            self.paused_sync = Some(paused_sync);
 
             return Ok(());
         }
@@ -355,5 +359,22 @@ impl VirtioDevice for Rng {
 }
 
 virtio_pausable!(Rng);
-impl Snapshotable for Rng {}
+impl Snapshotable for Rng {
+    fn id(&self) -> String {
+        "virtio-rng".to_string()
+    }
+
+    fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        VirtioDeviceState::new(self.avail_features, self.acked_features, &[])
+            .add_to(&mut snapshot)?;
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        self.acked_features =
+            VirtioDeviceState::from(&snapshot)?.acked_features(self.avail_features)?;
+        Ok(())
+    }
+}
 impl Migratable for Rng {}
@@ -0,0 +1,1711 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, DescriptorChain, DeviceEventT, Queue, VirtioDevice,
+    VirtioDeviceType, VirtioInterruptType, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
+};
+use crate::device::{join_epoll_threads, wait_for_resume};
+use crate::{build_disk_image_id, VirtioInterrupt};
+use epoll;
+use libc::{c_ulong, c_void, EFD_NONBLOCK};
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_memory::{
+    Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
+    GuestMemoryError, GuestMemoryMmap,
+};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+
+const SECTOR_SHIFT: u8 = 9;
+const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
+
+// The control queue and the event queue always come first, followed by
+// the request queues.
+const CONTROL_QUEUE_INDEX: usize = 0;
+const EVENT_QUEUE_INDEX: usize = 1;
+const REQUEST_QUEUES_OFFSET: usize = 2;
+
+// New descriptors are pending on the control queue.
+const CONTROL_QUEUE_EVENT: DeviceEventT = 0;
+// New descriptors are pending on the event queue.
+const EVENT_QUEUE_EVENT: DeviceEventT = 1;
+// The device has been dropped.
+const KILL_EVENT: DeviceEventT = 2;
+// The device should be paused.
+const PAUSE_EVENT: DeviceEventT = 3;
+// New descriptors are pending on one of the request queues. The actual
+// event is REQUEST_QUEUE_EVENT + the index of the request queue.
+const REQUEST_QUEUE_EVENT: DeviceEventT = 4;
+
+// Sizes advertised through the configuration space.
+const VIRTIO_SCSI_CDB_SIZE: usize = 32;
+const VIRTIO_SCSI_SENSE_SIZE: usize = 96;
+const VIRTIO_SCSI_SEG_MAX: u32 = 128;
+const VIRTIO_SCSI_MAX_SECTORS: u32 = 0xffff;
+const VIRTIO_SCSI_CMD_PER_LUN: u32 = 128;
+// Flat space addressing limits the LUN to 14 bits.
+const VIRTIO_SCSI_MAX_LUN: u32 = 16383;
+
+// Control queue request types.
+const VIRTIO_SCSI_T_TMF: u32 = 0;
+const VIRTIO_SCSI_T_AN_QUERY: u32 = 1;
+const VIRTIO_SCSI_T_AN_SUBSCRIBE: u32 = 2;
+
+// Response codes.
+const VIRTIO_SCSI_S_OK: u8 = 0;
+const VIRTIO_SCSI_S_BAD_TARGET: u8 = 3;
+const VIRTIO_SCSI_S_FAILURE: u8 = 9;
+const VIRTIO_SCSI_S_FUNCTION_COMPLETE: u8 = 0;
+
+// SCSI status codes.
+const SCSI_STATUS_GOOD: u8 = 0x00;
+const SCSI_STATUS_CHECK_CONDITION: u8 = 0x02;
+const SCSI_STATUS_RESERVATION_CONFLICT: u8 = 0x18;
+
+// SCSI sense keys and additional sense codes.
+const SENSE_KEY_NO_SENSE: u8 = 0x00;
+const SENSE_KEY_MEDIUM_ERROR: u8 = 0x03;
+const SENSE_KEY_ILLEGAL_REQUEST: u8 = 0x05;
+const SENSE_KEY_DATA_PROTECT: u8 = 0x07;
+const ASC_INVALID_COMMAND_OPCODE: u8 = 0x20;
+const ASC_LBA_OUT_OF_RANGE: u8 = 0x21;
+const ASC_INVALID_FIELD_IN_CDB: u8 = 0x24;
+const ASC_WRITE_PROTECTED: u8 = 0x27;
+const ASC_UNRECOVERED_READ_ERROR: u8 = 0x11;
+const ASC_WRITE_ERROR: u8 = 0x0c;
+const ASC_PARAMETER_LIST_LENGTH_ERROR: u8 = 0x1a;
+const ASC_INVALID_FIELD_IN_PARAMETER_LIST: u8 = 0x26;
+
+// SCSI operation codes handled by the emulated LUNs.
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const FORMAT_UNIT: u8 = 0x04;
+const READ_6: u8 = 0x08;
+const WRITE_6: u8 = 0x0a;
+const INQUIRY: u8 = 0x12;
+const MODE_SELECT_6: u8 = 0x15;
+const MODE_SENSE_6: u8 = 0x1a;
+const START_STOP_UNIT: u8 = 0x1b;
+const ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const WRITE_VERIFY_10: u8 = 0x2e;
+const VERIFY_10: u8 = 0x2f;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const WRITE_BUFFER: u8 = 0x3b;
+const WRITE_LONG_10: u8 = 0x3f;
+const WRITE_SAME_10: u8 = 0x41;
+const UNMAP: u8 = 0x42;
+const MODE_SELECT_10: u8 = 0x55;
+const MODE_SENSE_10: u8 = 0x5a;
+const PERSISTENT_RESERVE_IN: u8 = 0x5e;
+const PERSISTENT_RESERVE_OUT: u8 = 0x5f;
+const ATA_PASS_THROUGH_16: u8 = 0x85;
+const READ_16: u8 = 0x88;
+const COMPARE_AND_WRITE: u8 = 0x89;
+const WRITE_16: u8 = 0x8a;
+const WRITE_VERIFY_16: u8 = 0x8e;
+const SYNCHRONIZE_CACHE_16: u8 = 0x91;
+const WRITE_SAME_16: u8 = 0x93;
+const SERVICE_ACTION_IN_16: u8 = 0x9e;
+const REPORT_LUNS: u8 = 0xa0;
+const ATA_PASS_THROUGH_12: u8 = 0xa1;
+const WRITE_12: u8 = 0xaa;
+const WRITE_VERIFY_12: u8 = 0xae;
+const SAI_READ_CAPACITY_16: u8 = 0x10;
+
+// Persistent reservation service actions.
+const PR_IN_READ_KEYS: u8 = 0x00;
+const PR_IN_READ_RESERVATION: u8 = 0x01;
+const PR_IN_REPORT_CAPABILITIES: u8 = 0x02;
+const PR_OUT_REGISTER: u8 = 0x00;
+const PR_OUT_RESERVE: u8 = 0x01;
+const PR_OUT_RELEASE: u8 = 0x02;
+const PR_OUT_CLEAR: u8 = 0x03;
+const PR_OUT_PREEMPT: u8 = 0x04;
+const PR_OUT_PREEMPT_AND_ABORT: u8 = 0x05;
+const PR_OUT_REGISTER_AND_IGNORE_EXISTING_KEY: u8 = 0x06;
+// Size of the PERSISTENT RESERVE OUT parameter list.
+const PR_OUT_PARAMETER_LIST_SIZE: usize = 24;
+// Write Exclusive, Exclusive Access, and their registrants only and all
+// registrants variants.
+const PR_TYPE_MASK: [u8; 2] = [0xea, 0x01];
+
+// SG_IO definitions from include/scsi/sg.h.
+const SG_IO: c_ulong = 0x2285;
+const SG_DXFER_NONE: i32 = -1;
+const SG_DXFER_TO_DEV: i32 = -2;
+const SG_DXFER_FROM_DEV: i32 = -3;
+const SG_IO_TIMEOUT_MS: u32 = 30_000;
+
+#[derive(Debug)]
+enum Error {
+    /// Guest gave us bad memory addresses.
+    GuestMemory(GuestMemoryError),
+    /// Guest gave us a descriptor chain too short to hold the headers.
+    DescriptorChainTooShort,
+    /// The SG_IO ioctl failed.
+    SgIo(io::Error),
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+pub struct VirtioScsiConfig {
+    pub num_queues: u32,
+    pub seg_max: u32,
+    pub max_sectors: u32,
+    pub cmd_per_lun: u32,
+    pub event_info_size: u32,
+    pub sense_size: u32,
+    pub cdb_size: u32,
+    pub max_channel: u16,
+    pub max_target: u16,
+    pub max_lun: u32,
+}
+
+unsafe impl ByteValued for VirtioScsiConfig {}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C, packed)]
+struct VirtioScsiCmdReq {
+    lun: [u8; 8],
+    tag: u64,
+    task_attr: u8,
+    prio: u8,
+    crn: u8,
+    cdb: [u8; VIRTIO_SCSI_CDB_SIZE],
+}
+
+unsafe impl ByteValued for VirtioScsiCmdReq {}
+
+#[derive(Copy, Clone)]
+#[repr(C, packed)]
+struct VirtioScsiCmdResp {
+    sense_len: u32,
+    resid: u32,
+    status_qualifier: u16,
+    status: u8,
+    response: u8,
+    sense: [u8; VIRTIO_SCSI_SENSE_SIZE],
+}
+
+impl Default for VirtioScsiCmdResp {
+    fn default() -> Self {
+        VirtioScsiCmdResp {
+            sense_len: 0,
+            resid: 0,
+            status_qualifier: 0,
+            status: SCSI_STATUS_GOOD,
+            response: VIRTIO_SCSI_S_OK,
+            sense: [0; VIRTIO_SCSI_SENSE_SIZE],
+        }
+    }
+}
+
+unsafe impl ByteValued for VirtioScsiCmdResp {}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C, packed)]
+struct VirtioScsiCtrlTmfReq {
+    type_: u32,
+    subtype: u32,
+    lun: [u8; 8],
+    tag: u64,
+}
+
+unsafe impl ByteValued for VirtioScsiCtrlTmfReq {}
+
+#[derive(Copy, Clone, Default)]
+#[repr(C, packed)]
+struct VirtioScsiCtrlAnResp {
+    event_actual: u32,
+    response: u8,
+}
+
+unsafe impl ByteValued for VirtioScsiCtrlAnResp {}
+
+#[repr(C)]
+struct SgIoHdr {
+    interface_id: i32,
+    dxfer_direction: i32,
+    cmd_len: u8,
+    mx_sb_len: u8,
+    iovec_count: u16,
+    dxfer_len: u32,
+    dxferp: *mut c_void,
+    cmdp: *mut u8,
+    sbp: *mut u8,
+    timeout: u32,
+    flags: u32,
+    pack_id: i32,
+    usr_ptr: *mut c_void,
+    status: u8,
+    masked_status: u8,
+    msg_status: u8,
+    sb_len_wr: u8,
+    host_status: u16,
+    driver_status: u16,
+    resid: i32,
+    duration: u32,
+    info: u32,
+}
+
+/// Host backend for a single LUN of the controller.
+enum LunBackend {
+    /// Disk image or block device, exposed as an emulated direct access
+    /// device.
+    Emulated {
+        file: File,
+        nsectors: u64,
+        readonly: bool,
+        reservation: PersistentReservation,
+    },
+    /// Host SCSI generic device (/dev/sgN), commands are forwarded through
+    /// the SG_IO ioctl.
+    Passthrough { file: File, readonly: bool },
+}
+
+/// A logical unit attached to the virtio-scsi controller.
+pub struct ScsiLun {
+    path: PathBuf,
+    backend: LunBackend,
+}
+
+impl ScsiLun {
+    /// Open the LUN at `path`. Character devices are assumed to be SCSI
+    /// generic devices and get passed through to the guest, anything else
+    /// is emulated as a disk. A read-only LUN is opened read-only, and the
+    /// commands writing to the medium are rejected.
+    pub fn new(path: PathBuf, readonly: bool) -> io::Result<ScsiLun> {
+        let file_type = path.metadata()?.file_type();
+        let backend = if file_type.is_char_device() {
+            let file = OpenOptions::new().read(true).write(!readonly).open(&path)?;
+            LunBackend::Passthrough { file, readonly }
+        } else {
+            let mut file = OpenOptions::new().read(true).write(!readonly).open(&path)?;
+            let size = file.seek(SeekFrom::End(0))?;
+            if size % SECTOR_SIZE != 0 {
+                warn!(
+                    "SCSI LUN {:?} size {} is not a multiple of sector size {}; \
+                     the remainder will not be visible to the guest.",
+                    path, size, SECTOR_SIZE
+                );
+            }
+            LunBackend::Emulated {
+                file,
+                nsectors: size / SECTOR_SIZE,
+                readonly,
+                reservation: PersistentReservation::default(),
+            }
+        };
+
+        Ok(ScsiLun { path, backend })
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        match self.backend {
+            LunBackend::Passthrough { .. } => true,
+            _ => false,
+        }
+    }
+
+    // Write back what the host caches of an emulated LUN. The passed through
+    // ones are left to the guest, through SYNCHRONIZE CACHE.
+    fn flush(&self) -> io::Result<()> {
+        match &self.backend {
+            LunBackend::Emulated {
+                file,
+                readonly: false,
+                ..
+            } => file.sync_data(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Outcome of a SCSI command, from the guest point of view.
+struct CmdResult {
+    status: u8,
+    sense: Vec<u8>,
+    data_in: Vec<u8>,
+}
+
+impl CmdResult {
+    fn good(data_in: Vec<u8>) -> Self {
+        CmdResult {
+            status: SCSI_STATUS_GOOD,
+            sense: Vec::new(),
+            data_in,
+        }
+    }
+
+    fn check_condition(key: u8, asc: u8) -> Self {
+        CmdResult {
+            status: SCSI_STATUS_CHECK_CONDITION,
+            sense: fixed_sense(key, asc),
+            data_in: Vec::new(),
+        }
+    }
+}
+
+/// Persistent reservation state of an emulated LUN. The guest is the only
+/// initiator, hence there's at most one registered key. The state is lost
+/// when the VM stops, persisting it through power loss isn't supported.
+#[derive(Default)]
+struct PersistentReservation {
+    generation: u32,
+    key: Option<u64>,
+    reservation_type: Option<u8>,
+}
+
+impl PersistentReservation {
+    fn conflict() -> CmdResult {
+        CmdResult {
+            status: SCSI_STATUS_RESERVATION_CONFLICT,
+            sense: Vec::new(),
+            data_in: Vec::new(),
+        }
+    }
+
+    // PERSISTENT RESERVE IN
+    fn read(&self, cdb: &[u8]) -> CmdResult {
+        let alloc_len = be16(&cdb[7..9]) as usize;
+        let mut data = self.generation.to_be_bytes().to_vec();
+        match cdb[1] & 0x1f {
+            PR_IN_READ_KEYS => {
+                let keys: Vec<u8> = self
+                    .key
+                    .iter()
+                    .flat_map(|k| k.to_be_bytes().to_vec())
+                    .collect();
+                data.extend_from_slice(&(keys.len() as u32).to_be_bytes());
+                data.extend_from_slice(&keys);
+            }
+            PR_IN_READ_RESERVATION => match (self.key, self.reservation_type) {
+                (Some(key), Some(reservation_type)) => {
+                    data.extend_from_slice(&16u32.to_be_bytes());
+                    data.extend_from_slice(&key.to_be_bytes());
+                    data.extend_from_slice(&[0, 0, 0, 0, 0, reservation_type, 0, 0]);
+                }
+                _ => data.extend_from_slice(&0u32.to_be_bytes()),
+            },
+            PR_IN_REPORT_CAPABILITIES => {
+                // Type mask valid
+                data = vec![0, 8, 0, 0x80, PR_TYPE_MASK[0], PR_TYPE_MASK[1], 0, 0];
+            }
+            _ => {
+                return CmdResult::check_condition(
+                    SENSE_KEY_ILLEGAL_REQUEST,
+                    ASC_INVALID_FIELD_IN_CDB,
+                )
+            }
+        }
+        data.truncate(alloc_len);
+        CmdResult::good(data)
+    }
+
+    // PERSISTENT RESERVE OUT
+    fn update(&mut self, cdb: &[u8], data_out: &[u8]) -> CmdResult {
+        if data_out.len() < PR_OUT_PARAMETER_LIST_SIZE {
+            return CmdResult::check_condition(
+                SENSE_KEY_ILLEGAL_REQUEST,
+                ASC_PARAMETER_LIST_LENGTH_ERROR,
+            );
+        }
+        let key = be64(&data_out[0..8]);
+        let service_action_key = be64(&data_out[8..16]);
+        // Activate persist through power loss
+        if data_out[20] & 0x1 != 0 {
+            return CmdResult::check_condition(
+                SENSE_KEY_ILLEGAL_REQUEST,
+                ASC_INVALID_FIELD_IN_PARAMETER_LIST,
+            );
+        }
+        let reservation_type = cdb[2] & 0x0f;
+        let service_action = cdb[1] & 0x1f;
+
+        // Apart from the registration which ignores it, the key must be the
+        // one registered.
+        if service_action != PR_OUT_REGISTER_AND_IGNORE_EXISTING_KEY && key != self.key.unwrap_or(0)
+        {
+            return PersistentReservation::conflict();
+        }
+        let registered = self.key.is_some();
+
+        match service_action {
+            PR_OUT_REGISTER | PR_OUT_REGISTER_AND_IGNORE_EXISTING_KEY => {
+                if service_action_key == 0 {
+                    self.key = None;
+                    self.reservation_type = None;
+                } else {
+                    self.key = Some(service_action_key);
+                }
+                self.generation = self.generation.wrapping_add(1);
+            }
+            PR_OUT_RESERVE if registered => match self.reservation_type {
+                Some(current) if current != reservation_type => {
+                    return PersistentReservation::conflict()
+                }
+                _ => self.reservation_type = Some(reservation_type),
+            },
+            PR_OUT_RELEASE if registered => match self.reservation_type {
+                Some(current) if current != reservation_type => {
+                    return CmdResult::check_condition(
+                        SENSE_KEY_ILLEGAL_REQUEST,
+                        ASC_INVALID_FIELD_IN_CDB,
+                    )
+                }
+                _ => self.reservation_type = None,
+            },
+            PR_OUT_CLEAR if registered => {
+                self.key = None;
+                self.reservation_type = None;
+                self.generation = self.generation.wrapping_add(1);
+            }
+            PR_OUT_PREEMPT | PR_OUT_PREEMPT_AND_ABORT if registered => {
+                // The only registrant can only preempt itself.
+                if service_action_key != key {
+                    return PersistentReservation::conflict();
+                }
+                if self.reservation_type.is_some() {
+                    self.reservation_type = Some(reservation_type);
+                }
+                self.generation = self.generation.wrapping_add(1);
+            }
+            PR_OUT_RESERVE
+            | PR_OUT_RELEASE
+            | PR_OUT_CLEAR
+            | PR_OUT_PREEMPT
+            | PR_OUT_PREEMPT_AND_ABORT => return PersistentReservation::conflict(),
+            _ => {
+                return CmdResult::check_condition(
+                    SENSE_KEY_ILLEGAL_REQUEST,
+                    ASC_INVALID_FIELD_IN_CDB,
+                )
+            }
+        }
+
+        CmdResult::good(Vec::new())
+    }
+}
+
+fn fixed_sense(key: u8, asc: u8) -> Vec<u8> {
+    let mut sense = vec![0u8; 18];
+    sense[0] = 0x70;
+    sense[2] = key;
+    sense[7] = 10;
+    sense[12] = asc;
+    sense
+}
+
+fn be16(b: &[u8]) -> u64 {
+    u64::from(u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn be32(b: &[u8]) -> u64 {
+    u64::from(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn be64(b: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&b[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+// Length of the CDB based on the group code of the operation.
+fn cdb_length(cdb: &[u8]) -> usize {
+    match cdb[0] >> 5 {
+        0 => 6,
+        1 | 2 => 10,
+        3 => cmp::min(usize::from(cdb[7]) + 8, VIRTIO_SCSI_CDB_SIZE),
+        4 => 16,
+        5 => 12,
+        _ => VIRTIO_SCSI_CDB_SIZE,
+    }
+}
+
+// Returns the LUN targeted by the single level flat/peripheral addressing
+// used by the Linux driver, or None if the request isn't for target 0.
+fn decode_lun(lun: &[u8; 8]) -> Option<usize> {
+    if lun[0] != 1 || lun[1] != 0 {
+        return None;
+    }
+    Some((usize::from(lun[2] & 0x3f) << 8) | usize::from(lun[3]))
+}
+
+fn report_luns(num_luns: usize, alloc_len: usize) -> Vec<u8> {
+    let mut data = vec![0u8; 8 + num_luns * 8];
+    data[0..4].copy_from_slice(&((num_luns * 8) as u32).to_be_bytes());
+    for lun in 0..num_luns {
+        let entry = &mut data[8 + lun * 8..16 + lun * 8];
+        if lun < 256 {
+            entry[1] = lun as u8;
+        } else {
+            entry[0] = 0x40 | (lun >> 8) as u8;
+            entry[1] = (lun & 0xff) as u8;
+        }
+    }
+    data.truncate(alloc_len);
+    data
+}
+
+fn inquiry(cdb: &[u8], path: &PathBuf) -> CmdResult {
+    let alloc_len = be16(&cdb[3..5]) as usize;
+    let mut data = if cdb[1] & 0x1 != 0 {
+        match cdb[2] {
+            // Supported VPD pages
+            0x00 => vec![0, 0x00, 0, 2, 0x00, 0x80],
+            // Unit serial number
+            0x80 => {
+                let mut serial = build_disk_image_id(path);
+                while serial.last() == Some(&0) {
+                    serial.pop();
+                }
+                let mut page = vec![0, 0x80, 0, serial.len() as u8];
+                page.extend_from_slice(&serial);
+                page
+            }
+            _ => {
+                return CmdResult::check_condition(
+                    SENSE_KEY_ILLEGAL_REQUEST,
+                    ASC_INVALID_FIELD_IN_CDB,
+                )
+            }
+        }
+    } else {
+        let mut data = vec![0u8; 36];
+        // Direct access block device, SPC-3, response data format 2.
+        data[2] = 0x05;
+        data[3] = 0x02;
+        data[4] = 31;
+        // Command queuing
+        data[7] = 0x02;
+        data[8..16].copy_from_slice(b"CLOUDHV ");
+        data[16..32].copy_from_slice(b"VIRTUAL DISK    ");
+        data[32..36].copy_from_slice(b"0.1 ");
+        data
+    };
+    data.truncate(alloc_len);
+    CmdResult::good(data)
+}
+
+impl ScsiLun {
+    fn execute(&mut self, cdb: &[u8], data_out: &[u8], data_in_len: usize) -> CmdResult {
+        match self.backend {
+            LunBackend::Emulated { .. } => self.execute_emulated(cdb, data_out, data_in_len),
+            LunBackend::Passthrough { readonly, .. } if readonly && is_write_command(cdb[0]) => {
+                CmdResult::check_condition(SENSE_KEY_DATA_PROTECT, ASC_WRITE_PROTECTED)
+            }
+            LunBackend::Passthrough { ref file, .. } => {
+                match execute_passthrough(file, cdb, data_out, data_in_len) {
+                    Ok(result) => result,
+                    Err(e) => {
+                        error!("SG_IO passthrough to {:?} failed: {:?}", self.path, e);
+                        CmdResult::check_condition(SENSE_KEY_MEDIUM_ERROR, 0)
+                    }
+                }
+            }
+        }
+    }
+
+    fn execute_emulated(&mut self, cdb: &[u8], data_out: &[u8], data_in_len: usize) -> CmdResult {
+        let (file, nsectors, readonly, reservation) = match self.backend {
+            LunBackend::Emulated {
+                ref mut file,
+                nsectors,
+                readonly,
+                ref mut reservation,
+            } => (file, nsectors, readonly, reservation),
+            LunBackend::Passthrough { .. } => unreachable!(),
+        };
+
+        let (lba, count, is_write) = match cdb[0] {
+            TEST_UNIT_READY | START_STOP_UNIT | ALLOW_MEDIUM_REMOVAL | VERIFY_10 => {
+                return CmdResult::good(Vec::new())
+            }
+            REQUEST_SENSE => {
+                let mut data = fixed_sense(SENSE_KEY_NO_SENSE, 0);
+                data.truncate(usize::from(cdb[4]));
+                return CmdResult::good(data);
+            }
+            INQUIRY => return inquiry(cdb, &self.path),
+            PERSISTENT_RESERVE_IN => return reservation.read(cdb),
+            PERSISTENT_RESERVE_OUT => return reservation.update(cdb, data_out),
+            MODE_SENSE_6 => {
+                let mut data = vec![3, 0, if readonly { 0x80 } else { 0 }, 0];
+                data.truncate(usize::from(cdb[4]));
+                return CmdResult::good(data);
+            }
+            MODE_SENSE_10 => {
+                let mut data = vec![0, 6, 0, if readonly { 0x80 } else { 0 }, 0, 0, 0, 0];
+                data.truncate(be16(&cdb[7..9]) as usize);
+                return CmdResult::good(data);
+            }
+            READ_CAPACITY_10 => {
+                let last_lba = cmp::min(nsectors.saturating_sub(1), 0xffff_ffff) as u32;
+                let mut data = last_lba.to_be_bytes().to_vec();
+                data.extend_from_slice(&(SECTOR_SIZE as u32).to_be_bytes());
+                return CmdResult::good(data);
+            }
+            SERVICE_ACTION_IN_16 if cdb[1] & 0x1f == SAI_READ_CAPACITY_16 => {
+                let mut data = vec![0u8; 32];
+                data[0..8].copy_from_slice(&nsectors.saturating_sub(1).to_be_bytes());
+                data[8..12].copy_from_slice(&(SECTOR_SIZE as u32).to_be_bytes());
+                data.truncate(be32(&cdb[10..14]) as usize);
+                return CmdResult::good(data);
+            }
+            SYNCHRONIZE_CACHE_10 | SYNCHRONIZE_CACHE_16 => {
+                return match file.sync_data() {
+                    Ok(_) => CmdResult::good(Vec::new()),
+                    Err(e) => {
+                        error!("Failed to flush SCSI LUN {:?}: {:?}", self.path, e);
+                        CmdResult::check_condition(SENSE_KEY_MEDIUM_ERROR, ASC_WRITE_ERROR)
+                    }
+                };
+            }
+            READ_6 | WRITE_6 => {
+                let lba = (u64::from(cdb[1] & 0x1f) << 16) | be16(&cdb[2..4]);
+                // A transfer length of 0 means 256 blocks for 6 bytes CDBs.
+                let count = if cdb[4] == 0 { 256 } else { u64::from(cdb[4]) };
+                (lba, count, cdb[0] == WRITE_6)
+            }
+            READ_10 | WRITE_10 => (be32(&cdb[2..6]), be16(&cdb[7..9]), cdb[0] == WRITE_10),
+            READ_16 | WRITE_16 => (be64(&cdb[2..10]), be32(&cdb[10..14]), cdb[0] == WRITE_16),
+            _ => {
+                debug!("Unsupported SCSI command 0x{:x}", cdb[0]);
+                return CmdResult::check_condition(
+                    SENSE_KEY_ILLEGAL_REQUEST,
+                    ASC_INVALID_COMMAND_OPCODE,
+                );
+            }
+        };
+
+        match lba.checked_add(count) {
+            Some(end) if end <= nsectors => {}
+            _ => {
+                return CmdResult::check_condition(SENSE_KEY_ILLEGAL_REQUEST, ASC_LBA_OUT_OF_RANGE)
+            }
+        }
+        if is_write && readonly {
+            return CmdResult::check_condition(SENSE_KEY_DATA_PROTECT, ASC_WRITE_PROTECTED);
+        }
+
+        let len = (count << SECTOR_SHIFT) as usize;
+        // Nothing gets written unless the driver gave the data for the whole
+        // transfer.
+        if is_write && data_out.len() < len {
+            return CmdResult::check_condition(SENSE_KEY_ILLEGAL_REQUEST, ASC_INVALID_FIELD_IN_CDB);
+        }
+        if let Err(e) = file.seek(SeekFrom::Start(lba << SECTOR_SHIFT)) {
+            error!("Failed to seek SCSI LUN {:?}: {:?}", self.path, e);
+            return CmdResult::check_condition(SENSE_KEY_MEDIUM_ERROR, 0);
+        }
+
+        if is_write {
+            match file.write_all(&data_out[..len]) {
+                Ok(_) => CmdResult::good(Vec::new()),
+                Err(e) => {
+                    error!("Failed to write SCSI LUN {:?}: {:?}", self.path, e);
+                    CmdResult::check_condition(SENSE_KEY_MEDIUM_ERROR, ASC_WRITE_ERROR)
+                }
+            }
+        } else {
+            let mut data = vec![0u8; cmp::min(len, data_in_len)];
+            match file.read_exact(&mut data) {
+                Ok(_) => CmdResult::good(data),
+                Err(e) => {
+                    error!("Failed to read SCSI LUN {:?}: {:?}", self.path, e);
+                    CmdResult::check_condition(SENSE_KEY_MEDIUM_ERROR, ASC_UNRECOVERED_READ_ERROR)
+                }
+            }
+        }
+    }
+}
+
+// Whether the command may modify the medium, or the device configuration,
+// of a passed through LUN. The ATA pass-through commands are included, since
+// they can carry any ATA command.
+fn is_write_command(opcode: u8) -> bool {
+    match opcode {
+        FORMAT_UNIT
+        | WRITE_6
+        | MODE_SELECT_6
+        | WRITE_10
+        | WRITE_VERIFY_10
+        | WRITE_BUFFER
+        | WRITE_LONG_10
+        | WRITE_SAME_10
+        | UNMAP
+        | MODE_SELECT_10
+        | PERSISTENT_RESERVE_OUT
+        | ATA_PASS_THROUGH_16
+        | COMPARE_AND_WRITE
+        | WRITE_16
+        | WRITE_VERIFY_16
+        | WRITE_SAME_16
+        | ATA_PASS_THROUGH_12
+        | WRITE_12
+        | WRITE_VERIFY_12 => true,
+        _ => false,
+    }
+}
+
+fn execute_passthrough(
+    file: &File,
+    cdb: &[u8],
+    data_out: &[u8],
+    data_in_len: usize,
+) -> result::Result<CmdResult, Error> {
+    let mut cdb = cdb[..cdb_length(cdb)].to_vec();
+    let mut sense = vec![0u8; VIRTIO_SCSI_SENSE_SIZE];
+    let mut data_out = data_out.to_vec();
+    let mut data_in = vec![0u8; data_in_len];
+
+    let (dxfer_direction, dxfer_len, dxferp) = if !data_out.is_empty() {
+        (
+            SG_DXFER_TO_DEV,
+            data_out.len(),
+            data_out.as_mut_ptr() as *mut c_void,
+        )
+    } else if data_in_len > 0 {
+        (
+            SG_DXFER_FROM_DEV,
+            data_in_len,
+            data_in.as_mut_ptr() as *mut c_void,
+        )
+    } else {
+        (SG_DXFER_NONE, 0, std::ptr::null_mut())
+    };
+
+    let mut hdr = SgIoHdr {
+        interface_id: i32::from(b'S'),
+        dxfer_direction,
+        cmd_len: cdb.len() as u8,
+        mx_sb_len: sense.len() as u8,
+        iovec_count: 0,
+        dxfer_len: dxfer_len as u32,
+        dxferp,
+        cmdp: cdb.as_mut_ptr(),
+        sbp: sense.as_mut_ptr(),
+        timeout: SG_IO_TIMEOUT_MS,
+        flags: 0,
+        pack_id: 0,
+        usr_ptr: std::ptr::null_mut(),
+        status: 0,
+        masked_status: 0,
+        msg_status: 0,
+        sb_len_wr: 0,
+        host_status: 0,
+        driver_status: 0,
+        resid: 0,
+        duration: 0,
+        info: 0,
+    };
+
+    // Safe because the header and all the buffers it points to outlive the
+    // ioctl, and we check the return value.
+    let ret = unsafe { ioctl_with_mut_ref(file, SG_IO, &mut hdr) };
+    if ret < 0 {
+        return Err(Error::SgIo(io::Error::last_os_error()));
+    }
+    if hdr.host_status != 0 {
+        warn!(
+            "SG_IO host status 0x{:x}, driver status 0x{:x}",
+            hdr.host_status, hdr.driver_status
+        );
+    }
+
+    sense.truncate(usize::from(hdr.sb_len_wr));
+    if dxfer_direction == SG_DXFER_FROM_DEV {
+        let resid = cmp::min(cmp::max(hdr.resid, 0) as usize, data_in.len());
+        data_in.truncate(data_in_len - resid);
+    } else {
+        data_in.clear();
+    }
+
+    Ok(CmdResult {
+        status: hdr.status,
+        sense,
+        data_in,
+    })
+}
+
+/// Guest buffers of a request, split between the device readable part the
+/// driver filled in, and the device writable part we have to fill in.
+struct Buffers {
+    readable: Vec<(GuestAddress, usize)>,
+    writable: Vec<(GuestAddress, usize)>,
+}
+
+impl Buffers {
+    fn parse(avail_desc: &DescriptorChain) -> Self {
+        let mut buffers = Buffers {
+            readable: Vec::new(),
+            writable: Vec::new(),
+        };
+
+        let mut desc = Some(avail_desc.clone());
+        while let Some(d) = desc {
+            if d.is_write_only() {
+                buffers.writable.push((d.addr, d.len as usize));
+            } else {
+                buffers.readable.push((d.addr, d.len as usize));
+            }
+            desc = d.next_descriptor();
+        }
+
+        buffers
+    }
+
+    fn readable_len(&self) -> usize {
+        self.readable.iter().map(|(_, len)| len).sum()
+    }
+
+    fn writable_len(&self) -> usize {
+        self.writable.iter().map(|(_, len)| len).sum()
+    }
+
+    // Reads `len` bytes at `offset` of the readable part of the request.
+    fn read(
+        &self,
+        mem: &GuestMemoryMmap,
+        mut offset: usize,
+        len: usize,
+    ) -> result::Result<Vec<u8>, Error> {
+        let mut data = vec![0u8; len];
+        let mut done = 0;
+        for &(addr, seg_len) in self.readable.iter() {
+            if done == len {
+                break;
+            }
+            if offset >= seg_len {
+                offset -= seg_len;
+                continue;
+            }
+            let count = cmp::min(seg_len - offset, len - done);
+            mem.read_slice(
+                &mut data[done..done + count],
+                addr.unchecked_add(offset as u64),
+            )
+            .map_err(Error::GuestMemory)?;
+            done += count;
+            offset = 0;
+        }
+
+        if done < len {
+            return Err(Error::DescriptorChainTooShort);
+        }
+        Ok(data)
+    }
+
+    // Writes `data` at `offset` of the writable part of the request.
+    fn write(
+        &self,
+        mem: &GuestMemoryMmap,
+        mut offset: usize,
+        data: &[u8],
+    ) -> result::Result<(), Error> {
+        let mut done = 0;
+        for &(addr, seg_len) in self.writable.iter() {
+            if done == data.len() {
+                break;
+            }
+            if offset >= seg_len {
+                offset -= seg_len;
+                continue;
+            }
+            let count = cmp::min(seg_len - offset, data.len() - done);
+            mem.write_slice(&data[done..done + count], addr.unchecked_add(offset as u64))
+                .map_err(Error::GuestMemory)?;
+            done += count;
+            offset = 0;
+        }
+
+        if done < data.len() {
+            return Err(Error::DescriptorChainTooShort);
+        }
+        Ok(())
+    }
+}
+
+struct ScsiEpollHandler {
+    queues: Vec<Queue>,
+    mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    luns: Arc<Mutex<Vec<ScsiLun>>>,
+    interrupt_cb: Arc<dyn VirtioInterrupt>,
+    queue_evts: Vec<EventFd>,
+    kill_evt: EventFd,
+    pause_evt: EventFd,
+}
+
+impl ScsiEpollHandler {
+    fn process_cmd(
+        &self,
+        mem: &GuestMemoryMmap,
+        avail_desc: &DescriptorChain,
+    ) -> result::Result<u32, Error> {
+        let buffers = Buffers::parse(avail_desc);
+        let req_size = std::mem::size_of::<VirtioScsiCmdReq>();
+        let resp_size = std::mem::size_of::<VirtioScsiCmdResp>();
+
+        let mut req = VirtioScsiCmdReq::default();
+        req.as_mut_slice()
+            .copy_from_slice(&buffers.read(mem, 0, req_size)?);
+        let data_out = buffers.read(mem, req_size, buffers.readable_len() - req_size)?;
+        let data_in_len = buffers.writable_len().saturating_sub(resp_size);
+
+        let mut resp = VirtioScsiCmdResp::default();
+        let mut data_in = Vec::new();
+        let mut luns = self.luns.lock().unwrap();
+        match decode_lun(&req.lun) {
+            // REPORT LUNS is answered by the controller itself, so that the
+            // guest sees every LUN, independently of the backend of LUN 0.
+            Some(_) if req.cdb[0] == REPORT_LUNS => {
+                data_in = report_luns(luns.len(), be32(&req.cdb[6..10]) as usize);
+            }
+            Some(lun) if lun < luns.len() => {
+                let result = luns[lun].execute(&req.cdb, &data_out, data_in_len);
+                resp.status = result.status;
+                let sense_len = cmp::min(result.sense.len(), VIRTIO_SCSI_SENSE_SIZE);
+                resp.sense[..sense_len].copy_from_slice(&result.sense[..sense_len]);
+                resp.sense_len = sense_len as u32;
+                data_in = result.data_in;
+            }
+            _ => resp.response = VIRTIO_SCSI_S_BAD_TARGET,
+        }
+
+        data_in.truncate(data_in_len);
+        resp.resid = if data_out.is_empty() {
+            (data_in_len - data_in.len()) as u32
+        } else {
+            0
+        };
+        buffers.write(mem, 0, resp.as_slice())?;
+        buffers.write(mem, resp_size, &data_in)?;
+
+        Ok((resp_size + data_in.len()) as u32)
+    }
+
+    fn process_ctrl(
+        &self,
+        mem: &GuestMemoryMmap,
+        avail_desc: &DescriptorChain,
+    ) -> result::Result<u32, Error> {
+        let buffers = Buffers::parse(avail_desc);
+        let req_type = u32::from_le_bytes({
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&buffers.read(mem, 0, 4)?);
+            bytes
+        });
+
+        match req_type {
+            VIRTIO_SCSI_T_TMF => {
+                // Commands are completed synchronously, meaning there is
+                // never anything left to abort or reset.
+                let mut req = VirtioScsiCtrlTmfReq::default();
+                req.as_mut_slice().copy_from_slice(&buffers.read(
+                    mem,
+                    0,
+                    std::mem::size_of::<VirtioScsiCtrlTmfReq>(),
+                )?);
+                debug!("virtio-scsi TMF subtype {}", { req.subtype });
+                buffers.write(mem, 0, &[VIRTIO_SCSI_S_FUNCTION_COMPLETE])?;
+                Ok(1)
+            }
+            VIRTIO_SCSI_T_AN_QUERY | VIRTIO_SCSI_T_AN_SUBSCRIBE => {
+                // No asynchronous notification is supported.
+                let resp = VirtioScsiCtrlAnResp {
+                    event_actual: 0,
+                    response: VIRTIO_SCSI_S_OK,
+                };
+                buffers.write(mem, 0, resp.as_slice())?;
+                Ok(std::mem::size_of::<VirtioScsiCtrlAnResp>() as u32)
+            }
+            t => {
+                warn!("Unsupported virtio-scsi control request type {}", t);
+                buffers.write(mem, 0, &[VIRTIO_SCSI_S_FAILURE])?;
+                Ok(1)
+            }
+        }
+    }
+
+    fn process_queue(&mut self, queue_index: usize) -> bool {
+        let mem = self.mem.memory();
+        let mut used_desc_heads = Vec::new();
+        for avail_desc in self.queues[queue_index].iter(&mem) {
+            let result = if queue_index == CONTROL_QUEUE_INDEX {
+                self.process_ctrl(&mem, &avail_desc)
+            } else {
+                self.process_cmd(&mem, &avail_desc)
+            };
+            let len = match result {
+                Ok(len) => len,
+                Err(e) => {
+                    error!("Failed to process virtio-scsi request: {:?}", e);
+                    0
+                }
+            };
+            used_desc_heads.push((avail_desc.index, len));
+        }
+
+        for &(desc_index, len) in used_desc_heads.iter() {
+            self.queues[queue_index].add_used(&mem, desc_index, len);
+        }
+        !used_desc_heads.is_empty()
+    }
+
+    fn signal_used_queue(&self, queue_index: usize) -> result::Result<(), DeviceError> {
+        self.interrupt_cb
+            .trigger(&VirtioInterruptType::Queue, Some(&self.queues[queue_index]))
+            .map_err(|e| {
+                error!("Failed to signal used queue: {:?}", e);
+                DeviceError::FailedSignalingUsedQueue(e)
+            })
+    }
+
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), DeviceError> {
+        // Create the epoll file descriptor
+        let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
+
+        // Add events
+        for (index, queue_evt) in self.queue_evts.iter().enumerate() {
+            let ev_type = match index {
+                CONTROL_QUEUE_INDEX => CONTROL_QUEUE_EVENT,
+                EVENT_QUEUE_INDEX => EVENT_QUEUE_EVENT,
+                i => REQUEST_QUEUE_EVENT + (i - REQUEST_QUEUES_OFFSET) as DeviceEventT,
+            };
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                queue_evt.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(ev_type)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.kill_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(KILL_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.pause_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PAUSE_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+
+        const EPOLL_EVENTS_LEN: usize = 100;
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+
+        'epoll: loop {
+            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+                Ok(res) => res,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        // It's well defined from the epoll_wait() syscall
+                        // documentation that the epoll loop can be interrupted
+                        // before any of the requested events occurred or the
+                        // timeout expired. In both those cases, epoll_wait()
+                        // returns an error of type EINTR, but this should not
+                        // be considered as a regular error. Instead it is more
+                        // appropriate to retry, by calling into epoll_wait().
+                        continue;
+                    }
+                    return Err(DeviceError::EpollWait(e));
+                }
+            };
+
+            for event in events.iter().take(num_events) {
+                let ev_type = event.data as u16;
+
+                match ev_type {
+                    KILL_EVENT => {
+                        debug!("KILL_EVENT received, stopping epoll loop");
+                        break 'epoll;
+                    }
+                    PAUSE_EVENT => {
+                        debug!("PAUSE_EVENT received, pausing virtio-scsi epoll loop");
+                        wait_for_resume(&paused, &paused_sync);
+                    }
+                    EVENT_QUEUE_EVENT => {
+                        // The event queue buffers are kept until there is
+                        // an event to report, which never happens as hotplug
+                        // and asynchronous notifications aren't supported.
+                        if let Err(e) = self.queue_evts[EVENT_QUEUE_INDEX].read() {
+                            error!("Failed to get event queue event: {:?}", e);
+                            break 'epoll;
+                        }
+                    }
+                    CONTROL_QUEUE_EVENT | REQUEST_QUEUE_EVENT..=std::u16::MAX => {
+                        let queue_index = if ev_type == CONTROL_QUEUE_EVENT {
+                            CONTROL_QUEUE_INDEX
+                        } else {
+                            (ev_type - REQUEST_QUEUE_EVENT) as usize + REQUEST_QUEUES_OFFSET
+                        };
+                        if queue_index >= self.queue_evts.len() {
+                            error!("Unknown event for virtio-scsi");
+                            continue;
+                        }
+                        if let Err(e) = self.queue_evts[queue_index].read() {
+                            error!("Failed to get queue event: {:?}", e);
+                            break 'epoll;
+                        } else if self.process_queue(queue_index) {
+                            if let Err(e) = self.signal_used_queue(queue_index) {
+                                error!("Failed to signal used queue: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Virtio SCSI host bus adapter, exposing a set of LUNs to the guest. Each
+/// LUN is either an emulated disk or a host SCSI device passed through with
+/// SG_IO.
+pub struct Scsi {
+    kill_evt: Option<EventFd>,
+    pause_evt: Option<EventFd>,
+    luns: Arc<Mutex<Vec<ScsiLun>>>,
+    avail_features: u64,
+    acked_features: u64,
+    config: VirtioScsiConfig,
+    queue_evts: Option<Vec<EventFd>>,
+    interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
+    epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
+    paused: Arc<AtomicBool>,
+    paused_sync: Option<Arc<Barrier>>,
+    queue_size: Vec<u16>,
+}
+
+impl Scsi {
+    /// Create a new virtio-scsi controller with `num_queues` request queues.
+    pub fn new(
+        luns: Vec<ScsiLun>,
+        iommu: bool,
+        num_queues: usize,
+        queue_size: u16,
+    ) -> io::Result<Scsi> {
+        if luns.is_empty() || luns.len() > VIRTIO_SCSI_MAX_LUN as usize + 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid number of SCSI LUNs: {}", luns.len()),
+            ));
+        }
+
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+
+        if iommu {
+            avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+        }
+
+        let config = VirtioScsiConfig {
+            num_queues: num_queues as u32,
+            seg_max: VIRTIO_SCSI_SEG_MAX,
+            max_sectors: VIRTIO_SCSI_MAX_SECTORS,
+            cmd_per_lun: VIRTIO_SCSI_CMD_PER_LUN,
+            event_info_size: 0,
+            sense_size: VIRTIO_SCSI_SENSE_SIZE as u32,
+            cdb_size: VIRTIO_SCSI_CDB_SIZE as u32,
+            max_channel: 0,
+            max_target: 0,
+            max_lun: VIRTIO_SCSI_MAX_LUN,
+        };
+
+        Ok(Scsi {
+            kill_evt: None,
+            pause_evt: None,
+            luns: Arc::new(Mutex::new(luns)),
+            avail_features,
+            acked_features: 0u64,
+            config,
+            queue_evts: None,
+            interrupt_cb: None,
+            epoll_threads: None,
+            paused: Arc::new(AtomicBool::new(false)),
+            paused_sync: None,
+            queue_size: vec![queue_size; num_queues + REQUEST_QUEUES_OFFSET],
+        })
+    }
+}
+
+impl Drop for Scsi {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl VirtioDevice for Scsi {
+    fn device_type(&self) -> u32 {
+        VirtioDeviceType::TYPE_SCSI as u32
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        self.queue_size.as_slice()
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_slice = self.config.as_slice();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // Only sense_size and cdb_size are writable, and we don't support
+        // changing them.
+        warn!(
+            "Ignoring virtio-scsi config write at offset 0x{:x}, len {}",
+            offset,
+            data.len()
+        );
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != self.queue_size.len() || queue_evts.len() != self.queue_size.len() {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                self.queue_size.len(),
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        let (self_kill_evt, kill_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating kill EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.kill_evt = Some(self_kill_evt);
+
+        let (self_pause_evt, pause_evt) = EventFd::new(EFD_NONBLOCK)
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .map_err(|e| {
+                error!("failed creating pause EventFd pair: {}", e);
+                ActivateError::BadActivate
+            })?;
+        self.pause_evt = Some(self_pause_evt);
+
+        // Save the interrupt EventFD as we need to return it on reset
+        // but clone it to pass into the thread.
+        self.interrupt_cb = Some(interrupt_cb.clone());
+
+        let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
+        for queue_evt in queue_evts.iter() {
+            // Save the queue EventFD as we need to return it on reset
+            // but clone it to pass into the thread.
+            tmp_queue_evts.push(queue_evt.try_clone().map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?);
+        }
+        self.queue_evts = Some(tmp_queue_evts);
+
+        let mut handler = ScsiEpollHandler {
+            queues,
+            mem,
+            luns: self.luns.clone(),
+            interrupt_cb,
+            queue_evts,
+            kill_evt,
+            pause_evt,
+        };
+
+        let paused = self.paused.clone();
+        let paused_sync = Arc::new(Barrier::new(2));
+        let paused_sync_clone = paused_sync.clone();
+        let mut epoll_threads = Vec::new();
+        thread::Builder::new()
+            .name("virtio_scsi".to_string())
+            .spawn(move || handler.run(paused, paused_sync_clone))
+            .map(|thread| epoll_threads.push(thread))
+            .map_err(|e| {
+                error!("failed to clone the virtio-scsi epoll thread: {}", e);
+                ActivateError::BadActivate
+            })?;
+
+        self.epoll_threads = Some(epoll_threads);
+        self.paused_sync = Some(paused_sync);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+        // We first must resume the virtio thread if it was paused.
+        if self.pause_evt.take().is_some() {
+            self.resume().ok()?;
+        }
+
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        // Return the interrupt and queue EventFDs
+        Some((
+            self.interrupt_cb.take().unwrap(),
+            self.queue_evts.take().unwrap(),
+        ))
+    }
+
+    fn shutdown(&mut self) {
+        // A paused thread wouldn't see the kill event.
+        if self.paused.load(Ordering::SeqCst) {
+            let _ = self.resume();
+        }
+        if let Some(kill_evt) = self.kill_evt.take() {
+            let _ = kill_evt.write(1);
+        }
+        if let Some(epoll_threads) = self.epoll_threads.take() {
+            join_epoll_threads("virtio-scsi", epoll_threads);
+        }
+
+        for lun in self.luns.lock().unwrap().iter() {
+            if let Err(e) = lun.flush() {
+                error!("Failed to flush SCSI LUN {:?}: {:?}", lun.path, e);
+            }
+        }
+    }
+}
+
+virtio_pausable!(Scsi);
+impl Snapshotable for Scsi {
+    fn id(&self) -> String {
+        "virtio-scsi".to_string()
+    }
+}
+impl Migratable for Scsi {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    const TEST_SECTORS: u64 = 16;
+
+    fn emulated_lun(readonly: bool) -> (NamedTempFile, ScsiLun) {
+        let image = NamedTempFile::new().unwrap();
+        image.as_file().set_len(TEST_SECTORS * SECTOR_SIZE).unwrap();
+        let lun = ScsiLun::new(image.path().to_path_buf(), readonly).unwrap();
+        (image, lun)
+    }
+
+    fn assert_sense(result: &CmdResult, key: u8, asc: u8) {
+        assert_eq!(result.status, SCSI_STATUS_CHECK_CONDITION);
+        assert_eq!(result.sense[2], key);
+        assert_eq!(result.sense[12], asc);
+    }
+
+    fn rw_10(opcode: u8, lba: u32, count: u16) -> [u8; 10] {
+        let mut cdb = [0u8; 10];
+        cdb[0] = opcode;
+        cdb[2..6].copy_from_slice(&lba.to_be_bytes());
+        cdb[7..9].copy_from_slice(&count.to_be_bytes());
+        cdb
+    }
+
+    #[test]
+    fn test_emulated_inquiry_and_capacity() {
+        let (_image, mut lun) = emulated_lun(false);
+        assert!(!lun.is_passthrough());
+
+        let result = lun.execute(&[INQUIRY, 0, 0, 0, 36, 0], &[], 36);
+        assert_eq!(result.status, SCSI_STATUS_GOOD);
+        assert_eq!(result.data_in.len(), 36);
+        assert_eq!(result.data_in[0], 0);
+        assert_eq!(&result.data_in[8..16], b"CLOUDHV ");
+
+        // The allocation length bounds the returned data.
+        let result = lun.execute(&[INQUIRY, 0, 0, 0, 8, 0], &[], 8);
+        assert_eq!(result.data_in.len(), 8);
+
+        let result = lun.execute(&[INQUIRY, 0x1, 0x00, 0, 0xff, 0], &[], 0xff);
+        assert_eq!(result.data_in, vec![0, 0x00, 0, 2, 0x00, 0x80]);
+        let result = lun.execute(&[INQUIRY, 0x1, 0x83, 0, 0xff, 0], &[], 0xff);
+        assert_sense(&result, SENSE_KEY_ILLEGAL_REQUEST, ASC_INVALID_FIELD_IN_CDB);
+
+        let result = lun.execute(&[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[], 8);
+        assert_eq!(result.status, SCSI_STATUS_GOOD);
+        assert_eq!(result.data_in, vec![0, 0, 0, 15, 0, 0, 2, 0]);
+
+        let mut cdb = [0u8; 16];
+        cdb[0] = SERVICE_ACTION_IN_16;
+        cdb[1] = SAI_READ_CAPACITY_16;
+        cdb[13] = 32;
+        let result = lun.execute(&cdb, &[], 32);
+        assert_eq!(result.status, SCSI_STATUS_GOOD);
+        assert_eq!(be64(&result.data_in[0..8]), TEST_SECTORS - 1);
+        assert_eq!(be32(&result.data_in[8..12]), SECTOR_SIZE);
+
+        let result = lun.execute(&[0xff, 0, 0, 0, 0, 0], &[], 0);
+        assert_sense(
+            &result,
+            SENSE_KEY_ILLEGAL_REQUEST,
+            ASC_INVALID_COMMAND_OPCODE,
+        );
+    }
+
+    #[test]
+    fn test_emulated_read_write() {
+        let (image, mut lun) = emulated_lun(false);
+
+        let data = vec![0xa5u8; 2 * SECTOR_SIZE as usize];
+        let result = lun.execute(&rw_10(WRITE_10, 3, 2), &data, 0);
+        assert_eq!(result.status, SCSI_STATUS_GOOD);
+        assert!(result.data_in.is_empty());
+
+        let mut content = Vec::new();
+        image.reopen().unwrap().read_to_end(&mut content).unwrap();
+        assert!(content[..3 * SECTOR_SIZE as usize].iter().all(|b| *b == 0));
+        assert_eq!(
+            &content[3 * SECTOR_SIZE as usize..5 * SECTOR_SIZE as usize],
+            &data[..]
+        );
+
+        let result = lun.execute(&rw_10(READ_10, 3, 2), &[], data.len());
+        assert_eq!(result.status, SCSI_STATUS_GOOD);
+        assert_eq!(result.data_in, data);
+
+        // READ(6) addresses the same sectors.
+        let result = lun.execute(&[READ_6, 0, 0, 4, 1, 0], &[], SECTOR_SIZE as usize);
+        assert_eq!(result.data_in, vec![0xa5u8; SECTOR_SIZE as usize]);
+
+        // A transfer length of 0 is 256 sectors with READ(6), beyond the end.
+        let result = lun.execute(&[READ_6, 0, 0, 0, 0, 0], &[], 0);
+        assert_sense(&result, SENSE_KEY_ILLEGAL_REQUEST, ASC_LBA_OUT_OF_RANGE);
+
+        let result = lun.execute(&rw_10(READ_10, TEST_SECTORS as u32 - 1, 2), &[], 0);
+        assert_sense(&result, SENSE_KEY_ILLEGAL_REQUEST, ASC_LBA_OUT_OF_RANGE);
+        let mut cdb = [0u8; 16];
+        cdb[0] = WRITE_16;
+        cdb[2..10].copy_from_slice(&u64::max_value().to_be_bytes());
+        cdb[13] = 1;
+        let result = lun.execute(&cdb, &data, 0);
+        assert_sense(&result, SENSE_KEY_ILLEGAL_REQUEST, ASC_LBA_OUT_OF_RANGE);
+
+        let result = lun.execute(&[SYNCHRONIZE_CACHE_10, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[], 0);
+        assert_eq!(result.status, SCSI_STATUS_GOOD);
+    }
+
+    #[test]
+    fn test_emulated_short_write() {
+        let (image, mut lun) = emulated_lun(false);
+
+        // The data-out buffer holds one sector and a half, out of two.
+        let data = vec![0xa5u8; 3 * SECTOR_SIZE as usize / 2];
+        let result = lun.execute(&rw_10(WRITE_10, 3, 2), &data, 0);
+        assert_sense(&result, SENSE_KEY_ILLEGAL_REQUEST, ASC_INVALID_FIELD_IN_CDB);
+        let result = lun.execute(&[WRITE_6, 0, 0, 3, 2, 0], &data, 0);
+        assert_sense(&result, SENSE_KEY_ILLEGAL_REQUEST, ASC_INVALID_FIELD_IN_CDB);
+        let result = lun.execute(&rw_10(WRITE_10, 3, 1), &[], 0);
+        assert_sense(&result, SENSE_KEY_ILLEGAL_REQUEST, ASC_INVALID_FIELD_IN_CDB);
+
+        // Nothing got written.
+        let mut content = Vec::new();
+        image.reopen().unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content.len() as u64, TEST_SECTORS * SECTOR_SIZE);
+        assert!(content.iter().all(|b| *b == 0));
+
+        // A longer buffer is fine, only the transfer length gets written.
+        let data = vec![0xa5u8; 2 * SECTOR_SIZE as usize];
+        let result = lun.execute(&rw_10(WRITE_10, 3, 1), &data, 0);
+        assert_eq!(result.status, SCSI_STATUS_GOOD);
+        let mut content = Vec::new();
+        image.reopen().unwrap().read_to_end(&mut content).unwrap();
+        assert!(content[..3 * SECTOR_SIZE as usize].iter().all(|b| *b == 0));
+        assert!(content[3 * SECTOR_SIZE as usize..4 * SECTOR_SIZE as usize]
+            .iter()
+            .all(|b| *b == 0xa5));
+        assert!(content[4 * SECTOR_SIZE as usize..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_emulated_readonly() {
+        let (_image, mut lun) = emulated_lun(true);
+
+        // The write protect bit is reported by MODE SENSE.
+        let result = lun.execute(&[MODE_SENSE_6, 0, 0x3f, 0, 4, 0], &[], 4);
+        assert_eq!(result.data_in, vec![3, 0, 0x80, 0]);
+        let result = lun.execute(&[MODE_SENSE_10, 0, 0x3f, 0, 0, 0, 0, 0, 8, 0], &[], 8);
+        assert_eq!(result.data_in[3], 0x80);
+
+        let data = vec![0u8; SECTOR_SIZE as usize];
+        let result = lun.execute(&rw_10(WRITE_10, 0, 1), &data, 0);
+        assert_sense(&result, SENSE_KEY_DATA_PROTECT, ASC_WRITE_PROTECTED);
+        let result = lun.execute(&[WRITE_6, 0, 0, 0, 1, 0], &data, 0);
+        assert_sense(&result, SENSE_KEY_DATA_PROTECT, ASC_WRITE_PROTECTED);
+
+        let result = lun.execute(&rw_10(READ_10, 0, 1), &[], data.len());
+        assert_eq!(result.status, SCSI_STATUS_GOOD);
+        assert_eq!(result.data_in, data);
+    }
+
+    #[test]
+    fn test_shutdown_flushes_luns() {
+        let (image, mut lun) = emulated_lun(false);
+        let data = vec![0xa5u8; SECTOR_SIZE as usize];
+        let result = lun.execute(&rw_10(WRITE_10, 0, 1), &data, 0);
+        assert_eq!(result.status, SCSI_STATUS_GOOD);
+        assert!(lun.flush().is_ok());
+
+        // Nothing to write back for the read-only and passed through LUNs.
+        let (_ro_image, ro_lun) = emulated_lun(true);
+        assert!(ro_lun.flush().is_ok());
+        let sg_lun = ScsiLun::new(PathBuf::from("/dev/null"), true).unwrap();
+        assert!(sg_lun.flush().is_ok());
+
+        // The controller flushes all of them on shutdown, even when it never
+        // got activated.
+        let mut scsi = Scsi::new(vec![lun, ro_lun, sg_lun], false, 1, 128).unwrap();
+        scsi.shutdown();
+
+        let mut content = Vec::new();
+        image.reopen().unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(&content[..SECTOR_SIZE as usize], &data[..]);
+    }
+
+    #[test]
+    fn test_passthrough_readonly() {
+        // Any character device is taken as a SCSI generic device.
+        let mut lun = ScsiLun::new(PathBuf::from("/dev/null"), true).unwrap();
+        assert!(lun.is_passthrough());
+        match lun.backend {
+            LunBackend::Passthrough { ref file, .. } => {
+                let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+                assert_eq!(flags & libc::O_ACCMODE, libc::O_RDONLY);
+            }
+            _ => panic!("expected a passthrough LUN"),
+        }
+
+        // The write commands never reach the device.
+        let data = vec![0u8; SECTOR_SIZE as usize];
+        for cdb in [
+            rw_10(WRITE_10, 0, 1),
+            rw_10(WRITE_SAME_10, 0, 1),
+            rw_10(UNMAP, 0, 0),
+            rw_10(MODE_SELECT_10, 0, 0),
+        ]
+        .iter()
+        {
+            let result = lun.execute(cdb, &data, 0);
+            assert_sense(&result, SENSE_KEY_DATA_PROTECT, ASC_WRITE_PROTECTED);
+        }
+        assert!(!is_write_command(READ_10));
+        assert!(!is_write_command(INQUIRY));
+        assert!(is_write_command(WRITE_6));
+        assert!(is_write_command(WRITE_16));
+        assert!(is_write_command(ATA_PASS_THROUGH_16));
+    }
+
+    fn pr_out(reservation: &mut PersistentReservation, action: u8, key: u64, sa_key: u64) -> u8 {
+        let cdb = [PERSISTENT_RESERVE_OUT, action, 0x1, 0, 0, 0, 0, 0, 24, 0];
+        let mut params = vec![0u8; PR_OUT_PARAMETER_LIST_SIZE];
+        params[0..8].copy_from_slice(&key.to_be_bytes());
+        params[8..16].copy_from_slice(&sa_key.to_be_bytes());
+        reservation.update(&cdb, &params).status
+    }
+
+    fn pr_in(reservation: &PersistentReservation, action: u8) -> Vec<u8> {
+        let cdb = [PERSISTENT_RESERVE_IN, action, 0, 0, 0, 0, 0, 0x1, 0, 0];
+        reservation.read(&cdb).data_in
+    }
+
+    #[test]
+    fn test_persistent_reservation() {
+        let mut reservation = PersistentReservation::default();
+
+        // Reserving requires a registered key.
+        assert_eq!(
+            pr_out(&mut reservation, PR_OUT_RESERVE, 0, 0),
+            SCSI_STATUS_RESERVATION_CONFLICT
+        );
+        assert_eq!(
+            pr_out(&mut reservation, PR_OUT_REGISTER, 0, 0x1234),
+            SCSI_STATUS_GOOD
+        );
+        assert_eq!(
+            pr_in(&reservation, PR_IN_READ_KEYS),
+            vec![0, 0, 0, 1, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0x12, 0x34]
+        );
+
+        assert_eq!(
+            pr_out(&mut reservation, PR_OUT_RESERVE, 0x5678, 0),
+            SCSI_STATUS_RESERVATION_CONFLICT
+        );
+        assert_eq!(
+            pr_out(&mut reservation, PR_OUT_RESERVE, 0x1234, 0),
+            SCSI_STATUS_GOOD
+        );
+        let data = pr_in(&reservation, PR_IN_READ_RESERVATION);
+        assert_eq!(data[4..8], [0, 0, 0, 16]);
+        assert_eq!(data[21], 0x1);
+
+        assert_eq!(
+            pr_out(&mut reservation, PR_OUT_CLEAR, 0x1234, 0),
+            SCSI_STATUS_GOOD
+        );
+        assert_eq!(
+            pr_in(&reservation, PR_IN_READ_RESERVATION),
+            vec![0, 0, 0, 2, 0, 0, 0, 0]
+        );
+    }
+}
@@ -2,7 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use crate::transport::{VirtioTransport, NOTIFY_REG_OFFSET};
+use crate::transport::{VirtioActivationContext, VirtioTransport, NOTIFY_REG_OFFSET};
 use crate::{
     Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, DEVICE_ACKNOWLEDGE, DEVICE_DRIVER,
     DEVICE_DRIVER_OK, DEVICE_FAILED, DEVICE_FEATURES_OK, DEVICE_INIT,
@@ -86,6 +86,7 @@ pub struct MmioDevice {
     queues: Vec<Queue>,
     queue_evts: Vec<EventFd>,
     mem: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
+    activation_context: Option<Arc<dyn VirtioActivationContext>>,
 }
 
 impl MmioDevice {
@@ -118,9 +119,15 @@ impl MmioDevice {
             queues,
             queue_evts,
             mem: Some(mem),
+            activation_context: None,
         })
     }
 
+    /// Sets the context the activations triggered by the driver run in.
+    pub fn set_activation_context(&mut self, context: Arc<dyn VirtioActivationContext>) {
+        self.activation_context = Some(context);
+    }
+
     /// Gets the list of queue events that must be triggered whenever the VM writes to
     /// `virtio::NOTIFY_REG_OFFSET` past the MMIO base. Each event must be triggered when the
     /// value being written equals the index of the event in this list.
@@ -135,7 +142,14 @@ impl MmioDevice {
 
     fn are_queues_valid(&self) -> bool {
         if let Some(mem) = self.mem.as_ref() {
-            self.queues.iter().all(|q| q.is_valid(&mem.memory()))
+            // The driver leaves the queues it doesn't use disabled, e.g. the
+            // ones of the features it didn't negotiate.
+            self.queues.iter().any(|q| q.ready)
+                && self
+                    .queues
+                    .iter()
+                    .filter(|q| q.ready)
+                    .all(|q| q.is_valid(&mem.memory()))
         } else {
             false
         }
@@ -296,16 +310,19 @@ impl BusDevice for MmioDevice {
             if let Some(interrupt_cb) = self.interrupt_cb.take() {
                 if self.mem.is_some() {
                     let mem = self.mem.as_ref().unwrap().clone();
-                    self.device
-                        .lock()
-                        .unwrap()
-                        .activate(
-                            mem,
-                            interrupt_cb,
-                            self.queues.clone(),
-                            self.queue_evts.split_off(0),
-                        )
-                        .expect("Failed to activate device");
+                    if let Some(context) = &self.activation_context {
+                        context.enter();
+                    }
+                    let result = self.device.lock().unwrap().activate(
+                        mem,
+                        interrupt_cb,
+                        self.queues.clone(),
+                        self.queue_evts.split_off(0),
+                    );
+                    if let Some(context) = &self.activation_context {
+                        context.exit();
+                    }
+                    result.expect("Failed to activate device");
                     self.device_activated = true;
                 }
             }
@@ -323,5 +340,9 @@ impl Pausable for MmioDevice {
     }
 }
 
-impl Snapshotable for MmioDevice {}
+impl Snapshotable for MmioDevice {
+    fn id(&self) -> String {
+        "virtio-mmio".to_string()
+    }
+}
 impl Migratable for MmioDevice {}
@@ -8,6 +8,8 @@ mod pci_common_config;
 #[cfg(feature = "pci_support")]
 mod pci_device;
 #[cfg(feature = "pci_support")]
+mod pci_legacy_config;
+#[cfg(feature = "pci_support")]
 pub use pci_common_config::VirtioPciCommonConfig;
 #[cfg(feature = "pci_support")]
 pub use pci_device::VirtioPciDevice;
@@ -22,3 +24,14 @@ pub const NOTIFY_REG_OFFSET: u32 = 0x50;
 pub trait VirtioTransport {
     fn ioeventfds(&self, base_addr: u64) -> Vec<(&EventFd, u64)>;
 }
+
+/// Context the device activations triggered by the driver run in. These
+/// happen on the vCPU thread the driver wrote from, and the threads the
+/// device spawns inherit the properties of that thread, which the VMM may
+/// want to change for the time of the activation.
+pub trait VirtioActivationContext: Send + Sync {
+    /// Called on the activating thread before the device gets activated.
+    fn enter(&self);
+    /// Called on the same thread once the activation is done.
+    fn exit(&self);
+}
@@ -14,18 +14,20 @@ extern crate vm_memory;
 extern crate vmm_sys_util;
 
 use super::VirtioPciCommonConfig;
-use crate::transport::VirtioTransport;
+use crate::transport::{VirtioActivationContext, VirtioTransport};
 use crate::{
-    Queue, VirtioDevice, VirtioDeviceType, VirtioInterrupt, VirtioInterruptType,
+    ActivateResult, Queue, VirtioDevice, VirtioDeviceType, VirtioInterrupt, VirtioInterruptType,
     VirtioIommuRemapping, DEVICE_ACKNOWLEDGE, DEVICE_DRIVER, DEVICE_DRIVER_OK, DEVICE_FAILED,
     DEVICE_FEATURES_OK, DEVICE_INIT, VIRTIO_MSI_NO_VECTOR,
 };
+use anyhow::anyhow;
 use devices::BusDevice;
 use libc::EFD_NONBLOCK;
 use pci::{
-    BarReprogrammingParams, MsixCap, MsixConfig, PciBarConfiguration, PciBarRegionType,
-    PciCapability, PciCapabilityID, PciClassCode, PciConfiguration, PciDevice, PciDeviceError,
-    PciHeaderType, PciMassStorageSubclass, PciNetworkControllerSubclass, PciSubclass,
+    BarReprogrammingParams, MsixCap, MsixConfig, MsixConfigState, PciBarConfiguration,
+    PciBarRegionType, PciCapability, PciCapabilityID, PciClassCode, PciConfiguration,
+    PciConfigurationState, PciDevice, PciDeviceError, PciHeaderType, PciMassStorageSubclass,
+    PciNetworkControllerSubclass, PciSubclass,
 };
 use std::any::Any;
 use std::cmp;
@@ -37,7 +39,7 @@ use vm_allocator::SystemAllocator;
 use vm_device::interrupt::{
     InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqGroupConfig,
 };
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
 use vm_memory::{
     Address, ByteValued, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap,
     GuestUsize, Le32,
@@ -255,8 +257,43 @@ const CAPABILITY_BAR_SIZE: u64 = 0x80000;
 
 const NOTIFY_OFF_MULTIPLIER: u32 = 4; // A dword per notification address.
 
+// Transitional devices expose the legacy interface through an I/O BAR0,
+// moving the capability BAR out of its way. The legacy BAR is sized for the
+// registers and the device configuration space.
+const LEGACY_BAR_INDEX: usize = 0;
+const LEGACY_BAR_SIZE: u64 = 0x40;
+const TRANSITIONAL_CAPABILITY_BAR_INDEX: usize = 4;
+
 const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
 const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040; // Add to device type to get device ID.
+const VIRTIO_PCI_TRANSITIONAL_DEVICE_ID_BASE: u16 = 0x1000; // Add to device type minus one.
+
+#[derive(Deserialize, Serialize)]
+struct QueueState {
+    max_size: u16,
+    size: u16,
+    ready: bool,
+    vector: u16,
+    desc_table: u64,
+    avail_ring: u64,
+    used_ring: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+struct VirtioPciDeviceState {
+    device_activated: bool,
+    legacy_driver: bool,
+    interrupt_status: usize,
+    driver_status: u8,
+    config_generation: u8,
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    queue_select: u16,
+    msix_config: u16,
+    queues: Vec<QueueState>,
+    configuration: PciConfigurationState,
+    msix: Option<MsixConfigState>,
+}
 
 pub struct VirtioPciDevice {
     // PCI configuration registers.
@@ -293,6 +330,15 @@ pub struct VirtioPciDevice {
     // Whether to use 64-bit bar location or 32-bit
     use_64bit_bar: bool,
 
+    // Whether the legacy interface is exposed, and if the driver is using it
+    transitional: bool,
+    legacy_driver: bool,
+
+    // Legacy drivers notify all queues through the same register, which is
+    // not backed by ioeventfds. These are clones of the queue events, as the
+    // device owns the latter once activated.
+    legacy_queue_evts: Vec<EventFd>,
+
     // Add a dedicated structure to hold information about the very specific
     // virtio-pci capability VIRTIO_PCI_CAP_PCI_CFG. This is needed to support
     // the legacy/backward compatible mechanism of letting the guest access the
@@ -300,6 +346,14 @@ pub struct VirtioPciDevice {
     // needed when the guest tries to early access the virtio configuration of
     // a device.
     cap_pci_cfg_info: VirtioPciCfgCapInfo,
+
+    // What's left to do once the state is restored, the device being
+    // activated after its BARs are where the guest had put them.
+    restored_bar_moves: Vec<BarReprogrammingParams>,
+    restored_activation: bool,
+
+    // Context the activations triggered by the driver run in
+    activation_context: Option<Arc<dyn VirtioActivationContext>>,
 }
 
 impl VirtioPciDevice {
@@ -317,6 +371,12 @@ impl VirtioPciDevice {
         for _ in locked_device.queue_max_sizes().iter() {
             queue_evts.push(EventFd::new(EFD_NONBLOCK)?)
         }
+        let mut legacy_queue_evts = Vec::new();
+        if locked_device.transitional() {
+            for queue_evt in queue_evts.iter() {
+                legacy_queue_evts.push(queue_evt.try_clone()?);
+            }
+        }
         let queues = locked_device
             .queue_max_sizes()
             .iter()
@@ -327,7 +387,18 @@ impl VirtioPciDevice {
             })
             .collect();
 
-        let pci_device_id = VIRTIO_PCI_DEVICE_ID_BASE + locked_device.device_type() as u16;
+        // Transitional devices get a device ID legacy drivers know about,
+        // the device type being provided through the subsystem ID instead.
+        let transitional = locked_device.transitional();
+        let (pci_device_id, pci_subsystem_id) = if transitional {
+            (
+                VIRTIO_PCI_TRANSITIONAL_DEVICE_ID_BASE + locked_device.device_type() as u16 - 1,
+                locked_device.device_type() as u16,
+            )
+        } else {
+            let pci_device_id = VIRTIO_PCI_DEVICE_ID_BASE + locked_device.device_type() as u16;
+            (pci_device_id, pci_device_id)
+        };
 
         let interrupt_source_group = interrupt_manager.create_group(MsiIrqGroupConfig {
             base: 0,
@@ -375,7 +446,7 @@ impl VirtioPciDevice {
             None,
             PciHeaderType::Device,
             VIRTIO_PCI_VENDOR_ID,
-            pci_device_id,
+            pci_subsystem_id,
             msix_config_clone,
         );
 
@@ -400,8 +471,14 @@ impl VirtioPciDevice {
             memory: Some(memory),
             settings_bar: 0,
             use_64bit_bar,
+            transitional,
+            legacy_driver: false,
+            legacy_queue_evts,
             interrupt_source_group,
             cap_pci_cfg_info: VirtioPciCfgCapInfo::default(),
+            restored_bar_moves: Vec::new(),
+            restored_activation: false,
+            activation_context: None,
         };
 
         if let Some(msix_config) = &virtio_pci_device.msix_config {
@@ -423,8 +500,12 @@ impl VirtioPciDevice {
     }
 
     fn is_driver_ready(&self) -> bool {
-        let ready_bits =
-            (DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_DRIVER_OK | DEVICE_FEATURES_OK) as u8;
+        // Legacy drivers don't go through the FEATURES_OK step.
+        let ready_bits = if self.legacy_driver {
+            (DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_DRIVER_OK) as u8
+        } else {
+            (DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_DRIVER_OK | DEVICE_FEATURES_OK) as u8
+        };
         self.common_config.driver_status == ready_bits
             && self.common_config.driver_status & DEVICE_FAILED as u8 == 0
     }
@@ -436,7 +517,14 @@ impl VirtioPciDevice {
 
     fn are_queues_valid(&self) -> bool {
         if let Some(mem) = self.memory.as_ref() {
-            self.queues.iter().all(|q| q.is_valid(&mem.memory()))
+            // The driver leaves the queues it doesn't use disabled, e.g. the
+            // ones of the features it didn't negotiate.
+            self.queues.iter().any(|q| q.ready)
+                && self
+                    .queues
+                    .iter()
+                    .filter(|q| q.ready)
+                    .all(|q| q.is_valid(&mem.memory()))
         } else {
             false
         }
@@ -446,6 +534,78 @@ impl VirtioPciDevice {
         self.configuration.get_bar_addr(self.settings_bar as usize)
     }
 
+    /// Address of the legacy I/O BAR, for transitional devices.
+    pub fn legacy_bar_addr(&self) -> Option<u64> {
+        if self.transitional {
+            Some(self.configuration.get_bar_addr(LEGACY_BAR_INDEX))
+        } else {
+            None
+        }
+    }
+
+    /// Sets the context the activations triggered by the driver run in.
+    pub fn set_activation_context(&mut self, context: Arc<dyn VirtioActivationContext>) {
+        self.activation_context = Some(context);
+    }
+
+    fn activate_device(&mut self) -> ActivateResult {
+        if let Some(virtio_interrupt) = self.virtio_interrupt.take() {
+            if let Some(mem) = self.memory.as_ref() {
+                let mut device = self.device.lock().unwrap();
+                device.activate(
+                    mem.clone(),
+                    virtio_interrupt,
+                    self.queues.clone(),
+                    self.queue_evts.split_off(0),
+                )?;
+                self.device_activated = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// The BARs to move to where the guest had put them, once the state is
+    /// restored, before `activate_restored()` is called.
+    pub fn take_restored_bar_moves(&mut self) -> Vec<BarReprogrammingParams> {
+        self.restored_bar_moves.split_off(0)
+    }
+
+    /// Activates the device again if the driver had, once its state is
+    /// restored, the queues being notified for the device to process the
+    /// requests the guest may have made while it was being paused.
+    pub fn activate_restored(&mut self) -> result::Result<(), MigratableError> {
+        if !self.restored_activation {
+            return Ok(());
+        }
+        self.restored_activation = false;
+
+        let mut queue_evts = Vec::new();
+        for queue_evt in self.queue_evts.iter() {
+            queue_evts.push(
+                queue_evt
+                    .try_clone()
+                    .map_err(|e| MigratableError::Restore(e.into()))?,
+            );
+        }
+        self.activate_device().map_err(|e| {
+            MigratableError::Restore(anyhow!("Failed activating the device: {:?}", e))
+        })?;
+        for queue_evt in queue_evts {
+            queue_evt
+                .write(1)
+                .map_err(|e| MigratableError::Restore(e.into()))?;
+        }
+        Ok(())
+    }
+
+    fn msix_enabled(&self) -> bool {
+        if let Some(msix_config) = &self.msix_config {
+            msix_config.lock().unwrap().enabled()
+        } else {
+            false
+        }
+    }
+
     fn add_pci_capabilities(
         &mut self,
         settings_bar: u8,
@@ -704,6 +864,26 @@ impl PciDevice for VirtioPciDevice {
         let device_clone = self.device.clone();
         let device = device_clone.lock().unwrap();
 
+        // Allocate the legacy I/O BAR first, as it has to be BAR0.
+        let mut capability_bar_index = 0;
+        if self.transitional {
+            let legacy_bar_addr = allocator
+                .allocate_io_addresses(None, LEGACY_BAR_SIZE, Some(LEGACY_BAR_SIZE))
+                .ok_or(PciDeviceError::IoAllocationFailed(LEGACY_BAR_SIZE))?;
+            ranges.push((legacy_bar_addr, LEGACY_BAR_SIZE, PciBarRegionType::IORegion));
+
+            let config = PciBarConfiguration::default()
+                .set_register_index(LEGACY_BAR_INDEX)
+                .set_address(legacy_bar_addr.raw_value())
+                .set_size(LEGACY_BAR_SIZE)
+                .set_region_type(PciBarRegionType::IORegion);
+            self.configuration.add_pci_bar(&config).map_err(|e| {
+                PciDeviceError::IoRegistrationFailed(legacy_bar_addr.raw_value(), e)
+            })?;
+
+            capability_bar_index = TRANSITIONAL_CAPABILITY_BAR_INDEX;
+        }
+
         // Allocate the virtio-pci capability BAR.
         // See http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-740004
         let (virtio_pci_bar_addr, region_type) = if self.use_64bit_bar {
@@ -723,7 +903,7 @@ impl PciDevice for VirtioPciDevice {
         };
 
         let config = PciBarConfiguration::default()
-            .set_register_index(0)
+            .set_register_index(capability_bar_index)
             .set_address(virtio_pci_bar_addr.raw_value())
             .set_size(CAPABILITY_BAR_SIZE)
             .set_region_type(region_type);
@@ -763,7 +943,20 @@ impl PciDevice for VirtioPciDevice {
         Ok(ranges)
     }
 
-    fn read_bar(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+    fn read_bar(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        if self.legacy_bar_addr() == Some(base) {
+            let msix_enabled = self.msix_enabled();
+            self.common_config.read_legacy(
+                offset,
+                data,
+                &self.queues,
+                self.device.clone(),
+                &self.interrupt_status,
+                msix_enabled,
+            );
+            return;
+        }
+
         match offset {
             o if o < COMMON_CONFIG_BAR_OFFSET + COMMON_CONFIG_SIZE => self.common_config.read(
                 o - COMMON_CONFIG_BAR_OFFSET,
@@ -808,14 +1001,29 @@ impl PciDevice for VirtioPciDevice {
         }
     }
 
-    fn write_bar(&mut self, _base: u64, offset: u64, data: &[u8]) {
+    fn write_bar(&mut self, base: u64, offset: u64, data: &[u8]) {
         match offset {
-            o if o < COMMON_CONFIG_BAR_OFFSET + COMMON_CONFIG_SIZE => self.common_config.write(
-                o - COMMON_CONFIG_BAR_OFFSET,
-                data,
-                &mut self.queues,
-                self.device.clone(),
-            ),
+            o if self.legacy_bar_addr() == Some(base) => {
+                let msix_enabled = self.msix_enabled();
+                self.legacy_driver = true;
+                self.common_config.write_legacy(
+                    o,
+                    data,
+                    &mut self.queues,
+                    &self.legacy_queue_evts,
+                    self.device.clone(),
+                    msix_enabled,
+                )
+            }
+            o if o < COMMON_CONFIG_BAR_OFFSET + COMMON_CONFIG_SIZE => {
+                self.legacy_driver = false;
+                self.common_config.write(
+                    o - COMMON_CONFIG_BAR_OFFSET,
+                    data,
+                    &mut self.queues,
+                    self.device.clone(),
+                )
+            }
             o if ISR_CONFIG_BAR_OFFSET <= o && o < ISR_CONFIG_BAR_OFFSET + ISR_CONFIG_SIZE => {
                 if let Some(v) = data.get(0) {
                     self.interrupt_status
@@ -853,21 +1061,15 @@ impl PciDevice for VirtioPciDevice {
         };
 
         if !self.device_activated && self.is_driver_ready() && self.are_queues_valid() {
-            if let Some(virtio_interrupt) = self.virtio_interrupt.take() {
-                if self.memory.is_some() {
-                    let mem = self.memory.as_ref().unwrap().clone();
-                    let mut device = self.device.lock().unwrap();
-                    device
-                        .activate(
-                            mem,
-                            virtio_interrupt,
-                            self.queues.clone(),
-                            self.queue_evts.split_off(0),
-                        )
-                        .expect("Failed to activate device");
-                    self.device_activated = true;
-                }
+            let context = self.activation_context.clone();
+            if let Some(context) = &context {
+                context.enter();
+            }
+            let result = self.activate_device();
+            if let Some(context) = &context {
+                context.exit();
             }
+            result.expect("Failed to activate device");
         }
 
         // Device has been reset by the driver
@@ -916,5 +1118,105 @@ impl Pausable for VirtioPciDevice {
     }
 }
 
-impl Snapshotable for VirtioPciDevice {}
+impl Snapshotable for VirtioPciDevice {
+    fn id(&self) -> String {
+        "virtio-pci".to_string()
+    }
+
+    fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        let state = VirtioPciDeviceState {
+            device_activated: self.device_activated,
+            legacy_driver: self.legacy_driver,
+            interrupt_status: self.interrupt_status.load(Ordering::Acquire),
+            driver_status: self.common_config.driver_status,
+            config_generation: self.common_config.config_generation,
+            device_feature_select: self.common_config.device_feature_select,
+            driver_feature_select: self.common_config.driver_feature_select,
+            queue_select: self.common_config.queue_select,
+            msix_config: self.common_config.msix_config.load(Ordering::Acquire),
+            queues: self
+                .queues
+                .iter()
+                .map(|q| QueueState {
+                    max_size: q.get_max_size(),
+                    size: q.size,
+                    ready: q.ready,
+                    vector: q.vector,
+                    desc_table: q.desc_table.raw_value(),
+                    avail_ring: q.avail_ring.raw_value(),
+                    used_ring: q.used_ring.raw_value(),
+                })
+                .collect(),
+            configuration: self.configuration.state(),
+            msix: self
+                .msix_config
+                .as_ref()
+                .map(|msix_config| msix_config.lock().unwrap().state()),
+        };
+        snapshot.add_state("virtio-pci", &state)?;
+        Ok(snapshot)
+    }
+
+    // The virtio device is restored first, the transport activating it
+    // again from activate_restored().
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        let state: VirtioPciDeviceState = snapshot.state("virtio-pci")?;
+        if self.device_activated
+            || state.queues.len() != self.queues.len()
+            || state
+                .queues
+                .iter()
+                .zip(self.queues.iter())
+                .any(|(s, q)| s.max_size != q.get_max_size())
+        {
+            return Err(MigratableError::Restore(anyhow!(
+                "Invalid virtio-pci queues state"
+            )));
+        }
+
+        self.legacy_driver = state.legacy_driver;
+        self.interrupt_status
+            .store(state.interrupt_status, Ordering::Release);
+        self.common_config.driver_status = state.driver_status;
+        self.common_config.config_generation = state.config_generation;
+        self.common_config.device_feature_select = state.device_feature_select;
+        self.common_config.driver_feature_select = state.driver_feature_select;
+        self.common_config.queue_select = state.queue_select;
+        self.common_config
+            .msix_config
+            .store(state.msix_config, Ordering::Release);
+
+        let mem = self.memory.as_ref().unwrap().memory();
+        for (queue, queue_state) in self.queues.iter_mut().zip(state.queues.iter()) {
+            // The addresses are restored as translated when the queue was
+            // enabled, if there's an IOMMU.
+            queue.size = queue_state.size;
+            queue.ready = queue_state.ready;
+            queue.vector = queue_state.vector;
+            queue.desc_table = GuestAddress(queue_state.desc_table);
+            queue.avail_ring = GuestAddress(queue_state.avail_ring);
+            queue.used_ring = GuestAddress(queue_state.used_ring);
+            if state.device_activated && queue.ready {
+                queue.restore_position(&mem);
+            }
+        }
+
+        self.restored_bar_moves = self.configuration.set_state(&state.configuration)?;
+        match (&self.msix_config, &state.msix) {
+            (Some(msix_config), Some(msix_state)) => {
+                msix_config.lock().unwrap().set_state(msix_state)?
+            }
+            (None, None) => (),
+            _ => {
+                return Err(MigratableError::Restore(anyhow!(
+                    "Invalid virtio-pci MSI-X state"
+                )))
+            }
+        }
+        self.restored_activation = state.device_activated;
+
+        Ok(())
+    }
+}
 impl Migratable for VirtioPciDevice {}
@@ -0,0 +1,336 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+extern crate byteorder;
+
+use super::VirtioPciCommonConfig;
+use crate::{Queue, VirtioDevice};
+use byteorder::{ByteOrder, LittleEndian};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use vm_memory::{Address, GuestAddress};
+use vmm_sys_util::eventfd::EventFd;
+
+// Device specific configuration offset, depending on MSI-X being enabled.
+const LEGACY_CONFIG_OFFSET: u64 = 0x14;
+const LEGACY_CONFIG_OFFSET_MSIX: u64 = 0x18;
+
+// Legacy drivers give the descriptor table address as a page frame number,
+// and expect the used ring to be aligned on a page boundary.
+const LEGACY_QUEUE_PFN_SHIFT: u64 = 12;
+const LEGACY_VRING_ALIGN: u64 = 4096;
+
+/// Implements the legacy (virtio 0.9.5) register layout exposed by
+/// transitional devices through an I/O BAR. The state is shared with the
+/// common configuration, since a driver only uses one of the interfaces.
+///
+/// * Registers:
+/// le32 host_features;             // 0x00 // read-only for driver
+/// le32 guest_features;            // 0x04 // write-only for driver
+/// le32 queue_pfn;                 // 0x08 // read-write
+/// le16 queue_size;                // 0x0C // read-only for driver
+/// le16 queue_select;              // 0x0E // read-write
+/// le16 queue_notify;              // 0x10 // write-only for driver
+/// u8 device_status;               // 0x12 // read-write (driver_status)
+/// u8 isr_status;                  // 0x13 // read-only, cleared on read
+/// ** Only when MSI-X is enabled.
+/// le16 config_msix_vector;        // 0x14 // read-write
+/// le16 queue_msix_vector;         // 0x16 // read-write
+/// ** Device specific configuration, at 0x18 with MSI-X enabled, 0x14 otherwise.
+impl VirtioPciCommonConfig {
+    pub fn read_legacy(
+        &self,
+        offset: u64,
+        data: &mut [u8],
+        queues: &[Queue],
+        device: Arc<Mutex<dyn VirtioDevice>>,
+        interrupt_status: &AtomicUsize,
+        msix_enabled: bool,
+    ) {
+        let config_offset = legacy_config_offset(msix_enabled);
+        if offset >= config_offset {
+            let device = device.lock().unwrap();
+            device.read_config(offset - config_offset, data);
+            return;
+        }
+
+        debug!("read_legacy_config: offset 0x{:x}", offset);
+        let queue = queues.get(self.queue_select as usize);
+        let value = match (offset, data.len()) {
+            (0x00, 4) => device.lock().unwrap().features() as u32,
+            (0x08, 4) => queue
+                .filter(|q| q.ready)
+                .map(|q| (q.desc_table.raw_value() >> LEGACY_QUEUE_PFN_SHIFT) as u32)
+                .unwrap_or(0),
+            (0x0c, 2) => queue.map(|q| u32::from(q.actual_size())).unwrap_or(0),
+            (0x0e, 2) => u32::from(self.queue_select),
+            (0x12, 1) => u32::from(self.driver_status),
+            // Reading this register resets it to 0.
+            (0x13, 1) => interrupt_status.swap(0, Ordering::SeqCst) as u32,
+            (0x14, 2) if msix_enabled => u32::from(self.msix_config.load(Ordering::SeqCst)),
+            (0x16, 2) if msix_enabled => queue.map(|q| u32::from(q.vector)).unwrap_or(0),
+            _ => {
+                warn!(
+                    "invalid legacy virtio register read: 0x{:x}, len {}",
+                    offset,
+                    data.len()
+                );
+                0
+            }
+        };
+
+        match data.len() {
+            1 => data[0] = value as u8,
+            2 => LittleEndian::write_u16(data, value as u16),
+            4 => LittleEndian::write_u32(data, value),
+            _ => (),
+        }
+    }
+
+    pub fn write_legacy(
+        &mut self,
+        offset: u64,
+        data: &[u8],
+        queues: &mut Vec<Queue>,
+        queue_evts: &[EventFd],
+        device: Arc<Mutex<dyn VirtioDevice>>,
+        msix_enabled: bool,
+    ) {
+        let config_offset = legacy_config_offset(msix_enabled);
+        if offset >= config_offset {
+            let mut device = device.lock().unwrap();
+            device.write_config(offset - config_offset, data);
+            return;
+        }
+
+        debug!("write_legacy_config: offset 0x{:x}", offset);
+        let value = match data.len() {
+            1 => u32::from(data[0]),
+            2 => u32::from(LittleEndian::read_u16(data)),
+            4 => LittleEndian::read_u32(data),
+            _ => {
+                error!(
+                    "invalid data length for legacy virtio write: len {}",
+                    data.len()
+                );
+                return;
+            }
+        };
+
+        let queue = queues.get_mut(self.queue_select as usize);
+        match (offset, data.len()) {
+            // Legacy drivers can only acknowledge the first 32 feature bits.
+            (0x04, 4) => device.lock().unwrap().ack_features(u64::from(value)),
+            (0x08, 4) => {
+                if let Some(q) = queue {
+                    set_queue_pfn(q, value);
+                }
+            }
+            (0x0e, 2) => self.queue_select = value as u16,
+            (0x10, 2) => {
+                if let Some(queue_evt) = queue_evts.get(value as usize) {
+                    if let Err(e) = queue_evt.write(1) {
+                        error!("Failed to notify queue {}: {}", value, e);
+                    }
+                }
+            }
+            (0x12, 1) => self.driver_status = value as u8,
+            (0x14, 2) if msix_enabled => self.msix_config.store(value as u16, Ordering::SeqCst),
+            (0x16, 2) if msix_enabled => {
+                if let Some(q) = queue {
+                    q.vector = value as u16;
+                }
+            }
+            _ => {
+                warn!(
+                    "invalid legacy virtio register write: 0x{:x}, len {}",
+                    offset,
+                    data.len()
+                );
+            }
+        }
+    }
+}
+
+fn legacy_config_offset(msix_enabled: bool) -> u64 {
+    if msix_enabled {
+        LEGACY_CONFIG_OFFSET_MSIX
+    } else {
+        LEGACY_CONFIG_OFFSET
+    }
+}
+
+// The legacy interface only provides the page the queue starts at, the
+// rings being laid out contiguously with the queue size being fixed.
+// Writing 0 disables the queue.
+fn set_queue_pfn(queue: &mut Queue, pfn: u32) {
+    if pfn == 0 {
+        queue.ready = false;
+        return;
+    }
+
+    let size = u64::from(queue.actual_size());
+    let desc_table = u64::from(pfn) << LEGACY_QUEUE_PFN_SHIFT;
+    let avail_ring = desc_table + 16 * size;
+    // flags, idx, ring[size] and used_event, all 16 bits.
+    let avail_ring_end = avail_ring + 6 + 2 * size;
+    let used_ring = (avail_ring_end + LEGACY_VRING_ALIGN - 1) & !(LEGACY_VRING_ALIGN - 1);
+
+    queue.desc_table = GuestAddress(desc_table);
+    queue.avail_ring = GuestAddress(avail_ring);
+    queue.used_ring = GuestAddress(used_ring);
+    queue.enable(true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ActivateResult, VirtioInterrupt};
+    use std::sync::atomic::AtomicU16;
+    use vm_memory::{GuestMemoryAtomic, GuestMemoryMmap};
+    use vmm_sys_util::eventfd::EventFd;
+
+    struct DummyDevice(u32);
+    const QUEUE_SIZE: u16 = 256;
+    const QUEUE_SIZES: &'static [u16] = &[QUEUE_SIZE];
+    const DUMMY_FEATURES: u64 = 0x5555_aaaa;
+    impl VirtioDevice for DummyDevice {
+        fn device_type(&self) -> u32 {
+            return self.0;
+        }
+        fn queue_max_sizes(&self) -> &[u16] {
+            QUEUE_SIZES
+        }
+        fn activate(
+            &mut self,
+            _mem: GuestMemoryAtomic<GuestMemoryMmap>,
+            _interrupt_evt: Arc<dyn VirtioInterrupt>,
+            _queues: Vec<Queue>,
+            _queue_evts: Vec<EventFd>,
+        ) -> ActivateResult {
+            Ok(())
+        }
+
+        fn features(&self) -> u64 {
+            DUMMY_FEATURES
+        }
+
+        fn ack_features(&mut self, _value: u64) {}
+
+        fn read_config(&self, _offset: u64, data: &mut [u8]) {
+            for b in data.iter_mut() {
+                *b = 0x42;
+            }
+        }
+
+        fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+    }
+
+    #[test]
+    fn legacy_queue_layout() {
+        let mut regs = VirtioPciCommonConfig {
+            driver_status: 0,
+            config_generation: 0,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            queue_select: 0,
+            msix_config: Arc::new(AtomicU16::new(0)),
+        };
+        let interrupt_status = AtomicUsize::new(0);
+
+        let dev = Arc::new(Mutex::new(DummyDevice(1)));
+        let mut queues = vec![Queue::new(QUEUE_SIZE)];
+
+        // Host features are passed through from the device.
+        let mut read_back = vec![0, 0, 0, 0];
+        regs.read_legacy(
+            0x00,
+            &mut read_back,
+            &queues,
+            dev.clone(),
+            &interrupt_status,
+            true,
+        );
+        assert_eq!(LittleEndian::read_u32(&read_back), DUMMY_FEATURES as u32);
+
+        // The queue size is read-only.
+        regs.write_legacy(0x0c, &[0x10, 0], &mut queues, &[], dev.clone(), true);
+        let mut read_back = vec![0, 0];
+        regs.read_legacy(
+            0x0c,
+            &mut read_back,
+            &queues,
+            dev.clone(),
+            &interrupt_status,
+            true,
+        );
+        assert_eq!(LittleEndian::read_u16(&read_back), QUEUE_SIZE);
+
+        // The rings are derived from the page frame number.
+        regs.write_legacy(0x08, &[0x10, 0, 0, 0], &mut queues, &[], dev.clone(), true);
+        assert!(queues[0].ready);
+        assert_eq!(queues[0].desc_table, GuestAddress(0x10000));
+        assert_eq!(queues[0].avail_ring, GuestAddress(0x11000));
+        assert_eq!(queues[0].used_ring, GuestAddress(0x12000));
+        let mut read_back = vec![0, 0, 0, 0];
+        regs.read_legacy(
+            0x08,
+            &mut read_back,
+            &queues,
+            dev.clone(),
+            &interrupt_status,
+            true,
+        );
+        assert_eq!(LittleEndian::read_u32(&read_back), 0x10);
+
+        // Writing 0 disables the queue.
+        regs.write_legacy(0x08, &[0, 0, 0, 0], &mut queues, &[], dev.clone(), true);
+        assert!(!queues[0].ready);
+    }
+
+    #[test]
+    fn legacy_config_offset_follows_msix() {
+        let mut regs = VirtioPciCommonConfig {
+            driver_status: 0,
+            config_generation: 0,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            queue_select: 0,
+            msix_config: Arc::new(AtomicU16::new(0)),
+        };
+        let interrupt_status = AtomicUsize::new(0);
+
+        let dev = Arc::new(Mutex::new(DummyDevice(1)));
+        let mut queues = vec![Queue::new(QUEUE_SIZE)];
+
+        // With MSI-X enabled, 0x14 is the config vector.
+        regs.write_legacy(0x14, &[0x01, 0], &mut queues, &[], dev.clone(), true);
+        let mut read_back = vec![0, 0];
+        regs.read_legacy(
+            0x14,
+            &mut read_back,
+            &queues,
+            dev.clone(),
+            &interrupt_status,
+            true,
+        );
+        assert_eq!(LittleEndian::read_u16(&read_back), 1);
+
+        // Otherwise it's the start of the device configuration.
+        let mut read_back = vec![0, 0];
+        regs.read_legacy(
+            0x14,
+            &mut read_back,
+            &queues,
+            dev.clone(),
+            &interrupt_status,
+            false,
+        );
+        assert_eq!(read_back, vec![0x42, 0x42]);
+
+        // Device status is shared with the common configuration.
+        regs.write_legacy(0x12, &[0x07], &mut queues, &[], dev.clone(), true);
+        assert_eq!(regs.driver_status, 0x07);
+    }
+}
@@ -0,0 +1,577 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Virtio device whose data path is implemented by a vDPA device, i.e. a
+//! virtio compatible hardware device or a kernel driver, driven through its
+//! `/dev/vhost-vdpa-<N>` character device.
+//!
+//! The device type, features, queue sizes and configuration space are the
+//! ones of the vDPA device, the VMM only setting the virtqueues up for it and
+//! mapping the guest memory into its IOTLB. The guest physical addresses are
+//! used as I/O virtual addresses, so that the addresses the guest driver puts
+//! in the virtqueues are the ones the device accesses.
+
+use super::{
+    ActivateError, ActivateResult, Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType,
+    VIRTIO_F_IOMMU_PLATFORM,
+};
+use libc::c_ulong;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::Arc;
+use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_memory::{
+    Address, GuestAddressSpace, GuestMemory, GuestMemoryAtomic, GuestMemoryMmap, GuestMemoryRegion,
+};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::{
+    ioctl, ioctl_with_mut_ptr, ioctl_with_mut_ref, ioctl_with_ptr, ioctl_with_ref,
+};
+
+// vhost and vhost-vdpa ioctls, from include/uapi/linux/vhost.h.
+const VHOST_GET_FEATURES: c_ulong = 0x8008_af00;
+const VHOST_SET_FEATURES: c_ulong = 0x4008_af00;
+const VHOST_SET_OWNER: c_ulong = 0xaf01;
+const VHOST_SET_VRING_NUM: c_ulong = 0x4008_af10;
+const VHOST_SET_VRING_ADDR: c_ulong = 0x4028_af11;
+const VHOST_SET_VRING_BASE: c_ulong = 0x4008_af12;
+const VHOST_SET_VRING_KICK: c_ulong = 0x4008_af20;
+const VHOST_SET_VRING_CALL: c_ulong = 0x4008_af21;
+const VHOST_SET_BACKEND_FEATURES: c_ulong = 0x4008_af25;
+const VHOST_GET_BACKEND_FEATURES: c_ulong = 0x8008_af26;
+const VHOST_VDPA_GET_DEVICE_ID: c_ulong = 0x8004_af70;
+const VHOST_VDPA_SET_STATUS: c_ulong = 0x4001_af72;
+const VHOST_VDPA_GET_CONFIG: c_ulong = 0x8008_af73;
+const VHOST_VDPA_SET_CONFIG: c_ulong = 0x4008_af74;
+const VHOST_VDPA_SET_VRING_ENABLE: c_ulong = 0x4008_af75;
+const VHOST_VDPA_GET_VRING_NUM: c_ulong = 0x8002_af76;
+const VHOST_VDPA_SET_CONFIG_CALL: c_ulong = 0x4004_af77;
+
+// The guest memory is mapped through IOTLB messages written to the device.
+const VHOST_BACKEND_F_IOTLB_MSG_V2: u64 = 0x1;
+const VHOST_IOTLB_MSG_V2: u32 = 0x2;
+const VHOST_IOTLB_UPDATE: u8 = 2;
+const VHOST_IOTLB_INVALIDATE: u8 = 3;
+const VHOST_ACCESS_RW: u8 = 3;
+
+// Device status bits, from the virtio specification.
+const VIRTIO_CONFIG_S_ACKNOWLEDGE: u8 = 1;
+const VIRTIO_CONFIG_S_DRIVER: u8 = 2;
+const VIRTIO_CONFIG_S_DRIVER_OK: u8 = 4;
+const VIRTIO_CONFIG_S_FEATURES_OK: u8 = 8;
+
+#[repr(C)]
+#[derive(Default)]
+struct VhostVringState {
+    index: u32,
+    num: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct VhostVringFile {
+    index: u32,
+    fd: i32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct VhostVringAddr {
+    index: u32,
+    flags: u32,
+    desc_user_addr: u64,
+    used_user_addr: u64,
+    avail_user_addr: u64,
+    log_guest_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct VhostIotlbMsg {
+    iova: u64,
+    size: u64,
+    uaddr: u64,
+    perm: u8,
+    type_: u8,
+}
+
+// struct vhost_msg_v2, the IOTLB message being in a 64 bytes union.
+#[repr(C)]
+#[derive(Default)]
+struct VhostMsgV2 {
+    type_: u32,
+    asid: u32,
+    iotlb: VhostIotlbMsg,
+    padding: [u8; 32],
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot open the vhost-vdpa device.
+    Open(PathBuf, io::Error),
+
+    /// A vhost-vdpa ioctl failed.
+    Ioctl(&'static str, io::Error),
+
+    /// The device can't have the guest memory mapped through IOTLB
+    /// messages.
+    IotlbNotSupported,
+
+    /// Cannot map the guest memory for the device.
+    DmaMap(io::Error),
+
+    /// The guest can't be notified of the used buffers without interrupt
+    /// eventfds, e.g. with legacy interrupts.
+    MissingInterruptNotifier,
+}
+pub type Result<T> = result::Result<T, Error>;
+
+// The /dev/vhost-vdpa-<N> character device.
+struct VhostVdpa {
+    file: File,
+}
+
+impl VhostVdpa {
+    fn ioctl<T>(&self, name: &'static str, request: c_ulong, arg: &T) -> Result<()> {
+        // Safe because the kernel only reads the size encoded in the
+        // request, which is the size of `arg`, and we check the return value.
+        let ret = unsafe { ioctl_with_ref(&self.file, request, arg) };
+        if ret < 0 {
+            return Err(Error::Ioctl(name, io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    fn ioctl_mut<T>(&self, name: &'static str, request: c_ulong, arg: &mut T) -> Result<()> {
+        // Safe because the kernel only writes the size encoded in the
+        // request, which is the size of `arg`, and we check the return value.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, request, arg) };
+        if ret < 0 {
+            return Err(Error::Ioctl(name, io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    fn set_owner(&self) -> Result<()> {
+        // Safe because the request takes no argument, and we check the
+        // return value.
+        let ret = unsafe { ioctl(&self.file, VHOST_SET_OWNER) };
+        if ret < 0 {
+            return Err(Error::Ioctl("VHOST_SET_OWNER", io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    fn set_status(&self, status: u8) -> Result<()> {
+        self.ioctl("VHOST_VDPA_SET_STATUS", VHOST_VDPA_SET_STATUS, &status)
+    }
+
+    fn set_vring_state(
+        &self,
+        name: &'static str,
+        request: c_ulong,
+        index: usize,
+        num: u32,
+    ) -> Result<()> {
+        let state = VhostVringState {
+            index: index as u32,
+            num,
+        };
+        self.ioctl(name, request, &state)
+    }
+
+    fn set_vring_file(
+        &self,
+        name: &'static str,
+        request: c_ulong,
+        index: usize,
+        eventfd: &EventFd,
+    ) -> Result<()> {
+        let file = VhostVringFile {
+            index: index as u32,
+            fd: eventfd.as_raw_fd(),
+        };
+        self.ioctl(name, request, &file)
+    }
+
+    // The configuration space is read and written through a struct
+    // vhost_vdpa_config, i.e. the offset and length followed by the data.
+    fn config_buffer(offset: u64, len: usize) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(8 + len);
+        buffer.extend_from_slice(&(offset as u32).to_ne_bytes());
+        buffer.extend_from_slice(&(len as u32).to_ne_bytes());
+        buffer.resize(8 + len, 0);
+        buffer
+    }
+
+    fn get_config(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        let mut buffer = Self::config_buffer(offset, data.len());
+        // Safe because the buffer is large enough for the length it
+        // carries, and we check the return value.
+        let ret =
+            unsafe { ioctl_with_mut_ptr(&self.file, VHOST_VDPA_GET_CONFIG, buffer.as_mut_ptr()) };
+        if ret < 0 {
+            return Err(Error::Ioctl(
+                "VHOST_VDPA_GET_CONFIG",
+                io::Error::last_os_error(),
+            ));
+        }
+        data.copy_from_slice(&buffer[8..]);
+        Ok(())
+    }
+
+    fn set_config(&self, offset: u64, data: &[u8]) -> Result<()> {
+        let mut buffer = Self::config_buffer(offset, data.len());
+        buffer[8..].copy_from_slice(data);
+        // Safe because the buffer is as large as the length it carries, and
+        // we check the return value.
+        let ret = unsafe { ioctl_with_ptr(&self.file, VHOST_VDPA_SET_CONFIG, buffer.as_ptr()) };
+        if ret < 0 {
+            return Err(Error::Ioctl(
+                "VHOST_VDPA_SET_CONFIG",
+                io::Error::last_os_error(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn send_iotlb_msg(&self, iotlb: VhostIotlbMsg) -> Result<()> {
+        let msg = VhostMsgV2 {
+            type_: VHOST_IOTLB_MSG_V2,
+            iotlb,
+            ..Default::default()
+        };
+        // Safe because VhostMsgV2 is plain data without implicit padding
+        // beyond what the kernel ignores.
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &msg as *const VhostMsgV2 as *const u8,
+                std::mem::size_of::<VhostMsgV2>(),
+            )
+        };
+        (&self.file).write_all(bytes).map_err(Error::DmaMap)
+    }
+
+    fn dma_map(&self, iova: u64, size: u64, uaddr: u64) -> Result<()> {
+        self.send_iotlb_msg(VhostIotlbMsg {
+            iova,
+            size,
+            uaddr,
+            perm: VHOST_ACCESS_RW,
+            type_: VHOST_IOTLB_UPDATE,
+        })
+    }
+
+    fn dma_unmap(&self, iova: u64, size: u64) -> Result<()> {
+        self.send_iotlb_msg(VhostIotlbMsg {
+            iova,
+            size,
+            type_: VHOST_IOTLB_INVALIDATE,
+            ..Default::default()
+        })
+    }
+}
+
+/// A virtio device backed by a vDPA device.
+pub struct Vdpa {
+    vhost: VhostVdpa,
+    path: PathBuf,
+    device_type: u32,
+    device_features: u64,
+    avail_features: u64,
+    acked_features: u64,
+    queue_sizes: Vec<u16>,
+    // The guest memory ranges mapped for the device, as address and length.
+    mapped: Vec<(u64, u64)>,
+    queue_evts: Option<Vec<EventFd>>,
+    interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
+}
+
+impl Vdpa {
+    /// Opens the vhost-vdpa device at `path`, exposing `num_queues` of its
+    /// virtqueues to the guest.
+    pub fn new(path: &Path, num_queues: usize) -> Result<Vdpa> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC)
+            .open(path)
+            .map_err(|e| Error::Open(path.to_path_buf(), e))?;
+        let vhost = VhostVdpa { file };
+        vhost.set_owner()?;
+
+        let mut backend_features = 0u64;
+        vhost.ioctl_mut(
+            "VHOST_GET_BACKEND_FEATURES",
+            VHOST_GET_BACKEND_FEATURES,
+            &mut backend_features,
+        )?;
+        if backend_features & VHOST_BACKEND_F_IOTLB_MSG_V2 == 0 {
+            return Err(Error::IotlbNotSupported);
+        }
+        vhost.ioctl(
+            "VHOST_SET_BACKEND_FEATURES",
+            VHOST_SET_BACKEND_FEATURES,
+            &VHOST_BACKEND_F_IOTLB_MSG_V2,
+        )?;
+
+        let mut device_type = 0u32;
+        vhost.ioctl_mut(
+            "VHOST_VDPA_GET_DEVICE_ID",
+            VHOST_VDPA_GET_DEVICE_ID,
+            &mut device_type,
+        )?;
+        let mut device_features = 0u64;
+        vhost.ioctl_mut(
+            "VHOST_GET_FEATURES",
+            VHOST_GET_FEATURES,
+            &mut device_features,
+        )?;
+        let mut queue_size = 0u16;
+        vhost.ioctl_mut(
+            "VHOST_VDPA_GET_VRING_NUM",
+            VHOST_VDPA_GET_VRING_NUM,
+            &mut queue_size,
+        )?;
+
+        // The device accesses the guest memory through its IOTLB, which the
+        // guest doesn't need to know about as long as it's not behind the
+        // virtual IOMMU.
+        let avail_features = device_features & !(1u64 << VIRTIO_F_IOMMU_PLATFORM);
+
+        Ok(Vdpa {
+            vhost,
+            path: path.to_path_buf(),
+            device_type,
+            device_features,
+            avail_features,
+            acked_features: 0,
+            queue_sizes: vec![queue_size; num_queues],
+            mapped: Vec::new(),
+            queue_evts: None,
+            interrupt_cb: None,
+        })
+    }
+
+    fn map_memory(&mut self, mem: &GuestMemoryMmap) -> Result<()> {
+        for region in mem.iter() {
+            let iova = region.start_addr().raw_value();
+            let size = region.len() as u64;
+            self.vhost.dma_map(iova, size, region.as_ptr() as u64)?;
+            self.mapped.push((iova, size));
+        }
+        Ok(())
+    }
+
+    fn unmap_memory(&mut self) {
+        for (iova, size) in self.mapped.drain(..) {
+            if let Err(e) = self.vhost.dma_unmap(iova, size) {
+                error!("Failed to unmap vDPA memory at 0x{:x}: {:?}", iova, e);
+            }
+        }
+    }
+
+    fn setup(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        interrupt_cb: &Arc<dyn VirtioInterrupt>,
+        queues: &[Queue],
+        queue_evts: &[EventFd],
+    ) -> Result<()> {
+        self.map_memory(mem)?;
+
+        let mut status = VIRTIO_CONFIG_S_ACKNOWLEDGE | VIRTIO_CONFIG_S_DRIVER;
+        self.vhost.set_status(status)?;
+        let features =
+            self.acked_features | (self.device_features & (1u64 << VIRTIO_F_IOMMU_PLATFORM));
+        self.vhost
+            .ioctl("VHOST_SET_FEATURES", VHOST_SET_FEATURES, &features)?;
+        status |= VIRTIO_CONFIG_S_FEATURES_OK;
+        self.vhost.set_status(status)?;
+
+        for (index, queue) in queues.iter().enumerate() {
+            self.vhost.set_vring_state(
+                "VHOST_SET_VRING_NUM",
+                VHOST_SET_VRING_NUM,
+                index,
+                u32::from(queue.actual_size()),
+            )?;
+            let addr = VhostVringAddr {
+                index: index as u32,
+                desc_user_addr: queue.desc_table.raw_value(),
+                used_user_addr: queue.used_ring.raw_value(),
+                avail_user_addr: queue.avail_ring.raw_value(),
+                ..Default::default()
+            };
+            self.vhost
+                .ioctl("VHOST_SET_VRING_ADDR", VHOST_SET_VRING_ADDR, &addr)?;
+            self.vhost.set_vring_state(
+                "VHOST_SET_VRING_BASE",
+                VHOST_SET_VRING_BASE,
+                index,
+                u32::from(queue.next_avail.0),
+            )?;
+
+            let call = interrupt_cb
+                .notifier(&VirtioInterruptType::Queue, Some(queue))
+                .ok_or(Error::MissingInterruptNotifier)?;
+            self.vhost
+                .set_vring_file("VHOST_SET_VRING_CALL", VHOST_SET_VRING_CALL, index, call)?;
+            self.vhost.set_vring_file(
+                "VHOST_SET_VRING_KICK",
+                VHOST_SET_VRING_KICK,
+                index,
+                &queue_evts[index],
+            )?;
+        }
+
+        if let Some(config_call) = interrupt_cb.notifier(&VirtioInterruptType::Config, None) {
+            self.vhost.ioctl(
+                "VHOST_VDPA_SET_CONFIG_CALL",
+                VHOST_VDPA_SET_CONFIG_CALL,
+                &config_call.as_raw_fd(),
+            )?;
+        }
+
+        for index in 0..queues.len() {
+            self.vhost.set_vring_state(
+                "VHOST_VDPA_SET_VRING_ENABLE",
+                VHOST_VDPA_SET_VRING_ENABLE,
+                index,
+                1,
+            )?;
+        }
+
+        self.vhost.set_status(status | VIRTIO_CONFIG_S_DRIVER_OK)
+    }
+}
+
+impl VirtioDevice for Vdpa {
+    fn device_type(&self) -> u32 {
+        self.device_type
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        let mut v = value;
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature: {:x}", v);
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        if let Err(e) = self.vhost.get_config(offset, data) {
+            error!("Failed to read the {:?} config space: {:?}", self.path, e);
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        if let Err(e) = self.vhost.set_config(offset, data) {
+            error!("Failed to write the {:?} config space: {:?}", self.path, e);
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != self.queue_sizes.len() || queue_evts.len() != self.queue_sizes.len() {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                self.queue_sizes.len(),
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        if let Err(e) = self.setup(&mem.memory(), &interrupt_cb, &queues, &queue_evts) {
+            error!("Failed to set the vDPA device {:?} up: {:?}", self.path, e);
+            let _ = self.vhost.set_status(0);
+            self.unmap_memory();
+            return Err(ActivateError::VdpaSetup(e));
+        }
+
+        // Kept to be returned on reset, the device using the queues
+        // directly.
+        self.interrupt_cb = Some(interrupt_cb);
+        self.queue_evts = Some(queue_evts);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
+        // Resetting the status stops the device.
+        if let Err(e) = self.vhost.set_status(0) {
+            error!("Failed to reset the vDPA device {:?}: {:?}", self.path, e);
+            return None;
+        }
+        self.unmap_memory();
+        self.acked_features = 0;
+
+        // Return the interrupt and queue EventFDs
+        Some((self.interrupt_cb.take()?, self.queue_evts.take()?))
+    }
+
+    fn shutdown(&mut self) {
+        let _ = self.vhost.set_status(0);
+        self.unmap_memory();
+    }
+}
+
+// The data path is in the device, which keeps processing the virtqueues
+// while the VM is paused.
+impl Pausable for Vdpa {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+}
+
+impl Snapshotable for Vdpa {
+    fn id(&self) -> String {
+        "vdpa".to_string()
+    }
+}
+impl Migratable for Vdpa {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vhost_structs_layout() {
+        assert_eq!(std::mem::size_of::<VhostVringAddr>(), 40);
+        assert_eq!(std::mem::size_of::<VhostIotlbMsg>(), 32);
+        assert_eq!(std::mem::size_of::<VhostMsgV2>(), 72);
+    }
+
+    #[test]
+    fn test_config_buffer() {
+        let buffer = VhostVdpa::config_buffer(0x10, 6);
+        assert_eq!(buffer.len(), 14);
+        assert_eq!(&buffer[..8], &[0x10, 0, 0, 0, 6, 0, 0, 0]);
+    }
+}
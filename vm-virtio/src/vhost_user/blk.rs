@@ -16,7 +16,7 @@ use std::mem;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Barrier};
 use std::thread;
 use std::vec::Vec;
 use vhost_rs::vhost_user::message::VhostUserConfigFlags;
@@ -44,6 +44,7 @@ pub struct Blk {
     interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
     epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
     paused: Arc<AtomicBool>,
+    paused_sync: Option<Arc<Barrier>>,
 }
 
 impl Blk {
@@ -121,7 +122,7 @@ impl Blk {
                 VhostUserConfigFlags::WRITABLE,
                 config_space.as_slice(),
             )
-            .unwrap();
+            .map_err(Error::VhostUserGetConfig)?;
         let mut config = VirtioBlockConfig::default();
         if let Some(backend_config) = VirtioBlockConfig::from_slice(config_space.as_slice()) {
             config = *backend_config;
@@ -150,6 +151,7 @@ impl Blk {
             interrupt_cb: None,
             epoll_threads: None,
             paused: Arc::new(AtomicBool::new(false)),
+            paused_sync: None,
         })
     }
 }
@@ -266,6 +268,8 @@ impl VirtioDevice for Blk {
         )
         .map_err(ActivateError::VhostUserBlkSetup)?;
 
+        // One epoll thread per queue, plus the device pausing them.
+        let paused_sync = Arc::new(Barrier::new(vu_interrupt_list.len() + 1));
         let mut epoll_threads = Vec::new();
         for _ in 0..vu_interrupt_list.len() {
             let mut interrupt_list_sub: Vec<(Option<EventFd>, Queue)> = Vec::with_capacity(1);
@@ -280,9 +284,10 @@ impl VirtioDevice for Blk {
             });
 
             let paused = self.paused.clone();
+            let paused_sync = paused_sync.clone();
             thread::Builder::new()
                 .name("vhost_user_blk".to_string())
-                .spawn(move || handler.run(paused))
+                .spawn(move || handler.run(paused, paused_sync))
                 .map(|thread| epoll_threads.push(thread))
                 .map_err(|e| {
                     error!("failed to clone virtio epoll thread: {}", e);
@@ -290,6 +295,7 @@ impl VirtioDevice for Blk {
                 })?;
         }
         self.epoll_threads = Some(epoll_threads);
+        self.paused_sync = Some(paused_sync);
 
         Ok(())
     }
@@ -323,5 +329,9 @@ impl VirtioDevice for Blk {
 }
 
 virtio_pausable!(Blk);
-impl Snapshotable for Blk {}
+impl Snapshotable for Blk {
+    fn id(&self) -> String {
+        "vhost-user-block".to_string()
+    }
+}
 impl Migratable for Blk {}
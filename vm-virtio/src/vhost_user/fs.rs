@@ -13,10 +13,11 @@ use libc::{self, EFD_NONBLOCK};
 use std::cmp;
 use std::io;
 use std::io::Write;
+use std::num::Wrapping;
 use std::os::unix::io::RawFd;
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
 use vhost_rs::vhost_user::message::{
     VhostUserFSSlaveMsg, VhostUserProtocolFeatures, VhostUserVirtioFeatures,
@@ -27,7 +28,9 @@ use vhost_rs::vhost_user::{
 };
 use vhost_rs::VhostBackend;
 use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
-use vm_memory::{ByteValued, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
+use vm_memory::{
+    Address, ByteValued, Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap,
+};
 use vmm_sys_util::eventfd::EventFd;
 
 const NUM_QUEUE_OFFSET: usize = 1;
@@ -37,26 +40,27 @@ struct SlaveReqHandler {
     mmap_cache_addr: u64,
 }
 
-impl VhostUserMasterReqHandler for SlaveReqHandler {
-    fn handle_config_change(&mut self) -> HandlerResult<()> {
-        debug!("handle_config_change");
-        Ok(())
+impl SlaveReqHandler {
+    // Returns the host address of a range of the DAX window, making sure the
+    // backend can't reach beyond it, as the mappings are done with MAP_FIXED.
+    fn cache_addr(&self, offset: u64, len: u64) -> io::Result<u64> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.cache_size => Ok(self.mmap_cache_addr + offset),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Range beyond the cache window",
+            )),
+        }
     }
 
-    fn fs_slave_map(&mut self, fs: &VhostUserFSSlaveMsg, fd: RawFd) -> HandlerResult<()> {
-        debug!("fs_slave_map");
-
+    fn map(&self, fs: &VhostUserFSSlaveMsg, fd: RawFd) -> io::Result<()> {
         for i in 0..VHOST_USER_FS_SLAVE_ENTRIES {
             // Ignore if the length is 0.
             if fs.len[i] == 0 {
                 continue;
             }
 
-            if fs.cache_offset[i] > self.cache_size {
-                return Err(io::Error::new(io::ErrorKind::Other, "Wrong offset"));
-            }
-
-            let addr = self.mmap_cache_addr + fs.cache_offset[i];
+            let addr = self.cache_addr(fs.cache_offset[i], fs.len[i])?;
             let ret = unsafe {
                 libc::mmap(
                     addr as *mut libc::c_void,
@@ -70,11 +74,28 @@ impl VhostUserMasterReqHandler for SlaveReqHandler {
             if ret == libc::MAP_FAILED {
                 return Err(io::Error::last_os_error());
             }
+        }
 
-            let ret = unsafe { libc::close(fd) };
-            if ret == -1 {
-                return Err(io::Error::last_os_error());
-            }
+        Ok(())
+    }
+}
+
+impl VhostUserMasterReqHandler for SlaveReqHandler {
+    fn handle_config_change(&mut self) -> HandlerResult<()> {
+        debug!("handle_config_change");
+        Ok(())
+    }
+
+    fn fs_slave_map(&mut self, fs: &VhostUserFSSlaveMsg, fd: RawFd) -> HandlerResult<()> {
+        debug!("fs_slave_map");
+
+        // All the entries map the same file, which is closed once they're
+        // all mapped, whether they could be or not.
+        let result = self.map(fs, fd);
+        let ret = unsafe { libc::close(fd) };
+        result?;
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
         }
 
         Ok(())
@@ -94,14 +115,10 @@ impl VhostUserMasterReqHandler for SlaveReqHandler {
             // Need to handle a special case where the slave ask for the unmapping
             // of the entire mapping.
             if len == 0xffff_ffff_ffff_ffff {
-                len = self.cache_size;
+                len = self.cache_size.saturating_sub(fs.cache_offset[i]);
             }
 
-            if fs.cache_offset[i] > self.cache_size {
-                return Err(io::Error::new(io::ErrorKind::Other, "Wrong offset"));
-            }
-
-            let addr = self.mmap_cache_addr + fs.cache_offset[i];
+            let addr = self.cache_addr(fs.cache_offset[i], len)?;
             let ret = unsafe {
                 libc::mmap(
                     addr as *mut libc::c_void,
@@ -129,11 +146,7 @@ impl VhostUserMasterReqHandler for SlaveReqHandler {
                 continue;
             }
 
-            if fs.cache_offset[i] > self.cache_size {
-                return Err(io::Error::new(io::ErrorKind::Other, "Wrong offset"));
-            }
-
-            let addr = self.mmap_cache_addr + fs.cache_offset[i];
+            let addr = self.cache_addr(fs.cache_offset[i], fs.len[i])?;
             let ret = unsafe {
                 libc::msync(addr as *mut libc::c_void, fs.len[i] as usize, libc::MS_SYNC)
             };
@@ -164,6 +177,58 @@ impl Default for VirtioFsConfig {
 
 unsafe impl ByteValued for VirtioFsConfig {}
 
+// Connects to the backend and negotiates the features, returning the
+// features offered to the guest, and whether the backend can send requests.
+fn connect_backend(path: &str, num_queues: usize, cache: bool) -> Result<(Master, u64, bool)> {
+    let mut slave_req_support = false;
+
+    // Connect to the vhost-user socket.
+    let mut master =
+        Master::connect(path, num_queues as u64).map_err(Error::VhostUserCreateMaster)?;
+
+    // Filling device and vring features VMM supports.
+    let mut avail_features =
+        1 << VIRTIO_F_VERSION_1 | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+
+    // Set vhost-user owner.
+    master.set_owner().map_err(Error::VhostUserSetOwner)?;
+
+    // Get features from backend, do negotiation to get a feature collection which
+    // both VMM and backend support.
+    let backend_features = master.get_features().map_err(Error::VhostUserGetFeatures)?;
+    avail_features &= backend_features;
+    // Set features back is required by the vhost crate mechanism, since the
+    // later vhost call will check if features is filled in master before execution.
+    master
+        .set_features(avail_features)
+        .map_err(Error::VhostUserSetFeatures)?;
+
+    // Identify if protocol features are supported by the slave.
+    if avail_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0 {
+        let mut protocol_features = master
+            .get_protocol_features()
+            .map_err(Error::VhostUserGetProtocolFeatures)?;
+
+        if cache {
+            protocol_features &= VhostUserProtocolFeatures::MQ
+                | VhostUserProtocolFeatures::REPLY_ACK
+                | VhostUserProtocolFeatures::SLAVE_REQ
+                | VhostUserProtocolFeatures::SLAVE_SEND_FD;
+        } else {
+            protocol_features &=
+                VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::REPLY_ACK;
+        }
+
+        master
+            .set_protocol_features(protocol_features)
+            .map_err(Error::VhostUserSetProtocolFeatures)?;
+
+        slave_req_support = true;
+    }
+
+    Ok((master, avail_features, slave_req_support))
+}
+
 pub struct Fs {
     vu: Master,
     queue_sizes: Vec<u16>,
@@ -178,6 +243,10 @@ pub struct Fs {
     interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
     epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
     paused: Arc<AtomicBool>,
+    paused_sync: Option<Arc<Barrier>>,
+    // Kept from the activation, to set a new backend up.
+    mem: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
+    queues: Option<Vec<Queue>>,
 }
 
 impl Fs {
@@ -189,57 +258,17 @@ impl Fs {
         queue_size: u16,
         cache: Option<(VirtioSharedMemoryList, u64)>,
     ) -> Result<Fs> {
-        let mut slave_req_support = false;
-
         // Calculate the actual number of queues needed.
         let num_queues = NUM_QUEUE_OFFSET + req_num_queues;
 
-        // Connect to the vhost-user socket.
-        let mut master =
-            Master::connect(path, num_queues as u64).map_err(Error::VhostUserCreateMaster)?;
-
-        // Filling device and vring features VMM supports.
-        let mut avail_features =
-            1 << VIRTIO_F_VERSION_1 | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+        let (master, avail_features, slave_req_support) =
+            connect_backend(path, num_queues, cache.is_some())?;
 
-        // Set vhost-user owner.
-        master.set_owner().map_err(Error::VhostUserSetOwner)?;
-
-        // Get features from backend, do negotiation to get a feature collection which
-        // both VMM and backend support.
-        let backend_features = master.get_features().map_err(Error::VhostUserGetFeatures)?;
-        avail_features &= backend_features;
-        // Set features back is required by the vhost crate mechanism, since the
-        // later vhost call will check if features is filled in master before execution.
-        master
-            .set_features(avail_features)
-            .map_err(Error::VhostUserSetFeatures)?;
-
-        // Identify if protocol features are supported by the slave.
-        let mut acked_features = 0;
-        if avail_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0 {
-            acked_features |= VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
-
-            let mut protocol_features = master
-                .get_protocol_features()
-                .map_err(Error::VhostUserGetProtocolFeatures)?;
-
-            if cache.is_some() {
-                protocol_features &= VhostUserProtocolFeatures::MQ
-                    | VhostUserProtocolFeatures::REPLY_ACK
-                    | VhostUserProtocolFeatures::SLAVE_REQ
-                    | VhostUserProtocolFeatures::SLAVE_SEND_FD;
-            } else {
-                protocol_features &=
-                    VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::REPLY_ACK;
-            }
-
-            master
-                .set_protocol_features(protocol_features)
-                .map_err(Error::VhostUserSetProtocolFeatures)?;
-
-            slave_req_support = true;
-        }
+        let acked_features = if slave_req_support {
+            VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits()
+        } else {
+            0
+        };
 
         // Create virtio-fs device configuration.
         let mut config = VirtioFsConfig::default();
@@ -261,8 +290,140 @@ impl Fs {
             interrupt_cb: None,
             epoll_threads: None,
             paused: Arc::new(AtomicBool::new(false)),
+            paused_sync: None,
+            mem: None,
+            queues: None,
         })
     }
+
+    /// Connects to a new backend listening on `path`, once the previous one
+    /// is gone. If the device is activated, the new backend resumes the
+    /// queues where the guest driver expects them to be, processing again
+    /// the requests the previous backend didn't complete.
+    pub fn reconnect(&mut self, path: &str) -> Result<()> {
+        let (vu, avail_features, slave_req_support) =
+            connect_backend(path, self.queue_sizes.len(), self.cache.is_some())?;
+
+        // The guest driver can't negotiate the features again.
+        if avail_features & self.acked_features != self.acked_features {
+            return Err(Error::InvalidFeatures);
+        }
+
+        self.vu = vu;
+        self.slave_req_support = slave_req_support;
+
+        let (mem, mut queues, interrupt_cb) = match (&self.mem, &self.queues, &self.interrupt_cb) {
+            (Some(mem), Some(queues), Some(interrupt_cb)) => {
+                (mem.clone(), queues.clone(), interrupt_cb.clone())
+            }
+            _ => return Ok(()),
+        };
+
+        // Stop the thread handling the previous backend. A paused thread
+        // only stops once resumed, so it's kept along with the new one.
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+        let mut epoll_threads = self.epoll_threads.take().unwrap_or_default();
+        if !self.paused.load(Ordering::SeqCst) {
+            for thread in epoll_threads.drain(..) {
+                // Ignore the result, the previous backend is gone anyway.
+                let _ = thread.join();
+            }
+        }
+
+        // The available descriptors the guest driver hasn't got back yet
+        // are handed to the new backend.
+        for queue in queues.iter_mut() {
+            let used_idx: u16 = mem
+                .memory()
+                .read_obj(queue.used_ring.unchecked_add(2))
+                .map_err(|_| Error::UsedAddress)?;
+            queue.next_avail = Wrapping(used_idx);
+            queue.next_used = Wrapping(used_idx);
+        }
+
+        let mut queue_evts = Vec::new();
+        for queue_evt in self.queue_evts.as_ref().unwrap().iter() {
+            queue_evts.push(queue_evt.try_clone().map_err(Error::CloneQueueEventFd)?);
+        }
+
+        self.setup_backend(&mem, queues, queue_evts, interrupt_cb, epoll_threads)
+    }
+
+    // Sets the backend up with the queues, and starts the thread handling
+    // its interrupts and requests.
+    fn setup_backend(
+        &mut self,
+        mem: &GuestMemoryAtomic<GuestMemoryMmap>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+        interrupt_cb: Arc<dyn VirtioInterrupt>,
+        mut epoll_threads: Vec<thread::JoinHandle<result::Result<(), DeviceError>>>,
+    ) -> Result<()> {
+        let kill_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::CreateKillEventFd)?;
+        self.kill_evt = Some(kill_evt.try_clone().map_err(Error::CloneKillEventFd)?);
+
+        let pause_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::PauseEventFd)?;
+        // The thread handling the backend of a paused device starts paused.
+        if self.paused.load(Ordering::SeqCst) {
+            pause_evt.write(1).map_err(Error::PauseEventFd)?;
+        }
+        self.pause_evt = Some(pause_evt.try_clone().map_err(Error::PauseEventFd)?);
+
+        let vu_call_evt_queue_list = setup_vhost_user(
+            &mut self.vu,
+            &mem.memory(),
+            queues,
+            queue_evts,
+            &interrupt_cb,
+            self.acked_features,
+        )?;
+
+        // Initialize slave communication.
+        let slave_req_handler = if self.slave_req_support {
+            if let Some(cache) = self.cache.clone() {
+                let vu_master_req_handler = Arc::new(Mutex::new(SlaveReqHandler {
+                    cache_size: cache.0.len,
+                    mmap_cache_addr: cache.1,
+                }));
+
+                let req_handler = MasterReqHandler::new(vu_master_req_handler)
+                    .map_err(Error::MasterReqHandlerCreation)?;
+                self.vu
+                    .set_slave_request_fd(req_handler.get_tx_raw_fd())
+                    .map_err(Error::VhostUserSetSlaveRequestFd)?;
+                Some(req_handler)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        let mut handler = VhostUserEpollHandler::new(VhostUserEpollConfig {
+            vu_interrupt_list: vu_call_evt_queue_list,
+            interrupt_cb,
+            kill_evt,
+            pause_evt,
+            slave_req_handler,
+        });
+
+        let paused = self.paused.clone();
+        let paused_sync = Arc::new(Barrier::new(2));
+        let paused_sync_clone = paused_sync.clone();
+        thread::Builder::new()
+            .name("virtio_fs".to_string())
+            .spawn(move || handler.run(paused, paused_sync_clone))
+            .map(|thread| epoll_threads.push(thread))
+            .map_err(Error::EpollThreadSpawn)?;
+
+        self.epoll_threads = Some(epoll_threads);
+        self.paused_sync = Some(paused_sync);
+
+        Ok(())
+    }
 }
 
 impl Drop for Fs {
@@ -342,22 +503,6 @@ impl VirtioDevice for Fs {
             return Err(ActivateError::BadActivate);
         }
 
-        let (self_kill_evt, kill_evt) = EventFd::new(EFD_NONBLOCK)
-            .and_then(|e| Ok((e.try_clone()?, e)))
-            .map_err(|e| {
-                error!("failed creating kill EventFd pair: {}", e);
-                ActivateError::BadActivate
-            })?;
-        self.kill_evt = Some(self_kill_evt);
-
-        let (self_pause_evt, pause_evt) = EventFd::new(EFD_NONBLOCK)
-            .and_then(|e| Ok((e.try_clone()?, e)))
-            .map_err(|e| {
-                error!("failed creating pause EventFd pair: {}", e);
-                ActivateError::BadActivate
-            })?;
-        self.pause_evt = Some(self_pause_evt);
-
         // Save the interrupt EventFD as we need to return it on reset
         // but clone it to pass into the thread.
         self.interrupt_cb = Some(interrupt_cb.clone());
@@ -373,62 +518,12 @@ impl VirtioDevice for Fs {
         }
         self.queue_evts = Some(tmp_queue_evts);
 
-        let vu_call_evt_queue_list = setup_vhost_user(
-            &mut self.vu,
-            &mem.memory(),
-            queues,
-            queue_evts,
-            &interrupt_cb,
-            self.acked_features,
-        )
-        .map_err(ActivateError::VhostUserSetup)?;
-
-        // Initialize slave communication.
-        let slave_req_handler = if self.slave_req_support {
-            if let Some(cache) = self.cache.clone() {
-                let vu_master_req_handler = Arc::new(Mutex::new(SlaveReqHandler {
-                    cache_size: cache.0.len,
-                    mmap_cache_addr: cache.1,
-                }));
-
-                let req_handler = MasterReqHandler::new(vu_master_req_handler).map_err(|e| {
-                    ActivateError::VhostUserSetup(Error::MasterReqHandlerCreation(e))
-                })?;
-                self.vu
-                    .set_slave_request_fd(req_handler.get_tx_raw_fd())
-                    .map_err(|e| {
-                        ActivateError::VhostUserSetup(Error::VhostUserSetSlaveRequestFd(e))
-                    })?;
-                Some(req_handler)
-            } else {
-                None
-            }
-        } else {
-            None
-        };
+        // Keep what's needed to set a new backend up, if this one goes away.
+        self.mem = Some(mem.clone());
+        self.queues = Some(queues.clone());
 
-        let mut handler = VhostUserEpollHandler::new(VhostUserEpollConfig {
-            vu_interrupt_list: vu_call_evt_queue_list,
-            interrupt_cb,
-            kill_evt,
-            pause_evt,
-            slave_req_handler,
-        });
-
-        let paused = self.paused.clone();
-        let mut epoll_threads = Vec::new();
-        thread::Builder::new()
-            .name("virtio_fs".to_string())
-            .spawn(move || handler.run(paused))
-            .map(|thread| epoll_threads.push(thread))
-            .map_err(|e| {
-                error!("failed to clone queue EventFd: {}", e);
-                ActivateError::BadActivate
-            })?;
-
-        self.epoll_threads = Some(epoll_threads);
-
-        Ok(())
+        self.setup_backend(&mem, queues, queue_evts, interrupt_cb, Vec::new())
+            .map_err(ActivateError::VhostUserSetup)
     }
 
     fn reset(&mut self) -> Option<(Arc<dyn VirtioInterrupt>, Vec<EventFd>)> {
@@ -464,5 +559,9 @@ impl VirtioDevice for Fs {
 }
 
 virtio_pausable!(Fs);
-impl Snapshotable for Fs {}
+impl Snapshotable for Fs {
+    fn id(&self) -> String {
+        "vhost-user-fs".to_string()
+    }
+}
 impl Migratable for Fs {}
@@ -12,12 +12,11 @@ use super::{Error, Result};
 use epoll;
 use vmm_sys_util::eventfd::EventFd;
 
-use crate::VirtioInterrupt;
+use crate::{wait_for_resume, VirtioInterrupt};
 use std::io;
 use std::os::unix::io::AsRawFd;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::thread;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Barrier};
 use vhost_rs::vhost_user::{MasterReqHandler, VhostUserMasterReqHandler};
 
 /// Collection of common parameters required by vhost-user devices while
@@ -58,7 +57,7 @@ impl<S: VhostUserMasterReqHandler> VhostUserEpollHandler<S> {
             .map_err(Error::FailedSignalingUsedQueue)
     }
 
-    pub fn run(&mut self, paused: Arc<AtomicBool>) -> Result<()> {
+    pub fn run(&mut self, paused: Arc<AtomicBool>, paused_sync: Arc<Barrier>) -> Result<()> {
         // Create the epoll file descriptor
         let epoll_fd = epoll::create(true).map_err(Error::EpollCreateFd)?;
 
@@ -154,12 +153,7 @@ impl<S: VhostUserMasterReqHandler> VhostUserEpollHandler<S> {
                     }
                     x if pause_evt_index == x => {
                         debug!("PAUSE_EVENT received, pausing vhost-user epoll loop");
-                        // We loop here to handle spurious park() returns.
-                        // Until we have not resumed, the paused boolean will
-                        // be true.
-                        while paused.load(Ordering::SeqCst) {
-                            thread::park();
-                        }
+                        wait_for_resume(&paused, &paused_sync);
                     }
                     x if (slave_evt_index.is_some() && slave_evt_index.unwrap() == x) => {
                         if let Some(slave_req_handler) =
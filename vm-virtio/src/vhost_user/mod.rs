@@ -33,6 +33,12 @@ pub enum Error {
     CreateKillEventFd(io::Error),
     /// Cloning kill eventfd failed.
     CloneKillEventFd(io::Error),
+    /// Creating, cloning or writing pause eventfd failed.
+    PauseEventFd(io::Error),
+    /// Cloning queue eventfd failed.
+    CloneQueueEventFd(io::Error),
+    /// Spawning the epoll thread failed.
+    EpollThreadSpawn(io::Error),
     /// Invalid descriptor table address.
     DescriptorTableAddress,
     /// Create Epoll eventfd failed
@@ -59,6 +65,8 @@ pub enum Error {
     VhostUserGetQueueMaxNum(VhostError),
     /// Get protocol features failed.
     VhostUserGetProtocolFeatures(VhostError),
+    /// Get config space failed.
+    VhostUserGetConfig(VhostError),
     /// Vhost-user Backend not support vhost-user protocol.
     VhostUserProtocolNotSupport,
     /// Set owner failed.
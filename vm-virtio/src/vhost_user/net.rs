@@ -19,7 +19,7 @@ use std::io::Write;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Barrier};
 use std::thread;
 use std::vec::Vec;
 use vhost_rs::vhost_user::message::{VhostUserProtocolFeatures, VhostUserVirtioFeatures};
@@ -50,10 +50,10 @@ pub struct Net {
     epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
     ctrl_queue_epoll_thread: Option<thread::JoinHandle<result::Result<(), CtrlError>>>,
     paused: Arc<AtomicBool>,
+    paused_sync: Option<Arc<Barrier>>,
 }
 
 impl Net {
-    /// Create a new vhost-user-net device
     /// Create a new vhost-user-net device
     pub fn new(mac_addr: MacAddr, vu_cfg: VhostUserConfig) -> Result<Net> {
         let mut vhost_user_net = Master::connect(&vu_cfg.sock, vu_cfg.num_queues as u64)
@@ -103,11 +103,17 @@ impl Net {
             return Err(Error::VhostUserProtocolNotSupport);
         }
 
+        // Only the multiqueue and reply ack protocol features are handled, but
+        // the protocol features have to be acked in any case for the backend
+        // to move on.
+        let protocol_features = protocol_features
+            & (VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::REPLY_ACK);
+        vhost_user_net
+            .set_protocol_features(protocol_features)
+            .map_err(Error::VhostUserSetProtocolFeatures)?;
+
         let max_queue_number =
             if protocol_features.bits() & VhostUserProtocolFeatures::MQ.bits() != 0 {
-                vhost_user_net
-                    .set_protocol_features(protocol_features & VhostUserProtocolFeatures::MQ)
-                    .map_err(Error::VhostUserSetProtocolFeatures)?;
                 match vhost_user_net.get_queue_num() {
                     Ok(qn) => qn,
                     Err(_) => DEFAULT_QUEUE_NUMBER as u64,
@@ -154,6 +160,7 @@ impl Net {
             epoll_threads: None,
             ctrl_queue_epoll_thread: None,
             paused: Arc::new(AtomicBool::new(false)),
+            paused_sync: None,
         })
     }
 }
@@ -268,11 +275,18 @@ impl VirtioDevice for Net {
 
         let queue_num = queue_evts.len();
 
-        if (self.acked_features & 1 << virtio_net::VIRTIO_NET_F_CTRL_VQ) != 0 && queue_num % 2 != 0
-        {
-            let cvq_queue = queues.remove(queue_num - 1);
-            let cvq_queue_evt = queue_evts.remove(queue_num - 1);
+        // The control queue, the last one, is handled by the VMM whether the
+        // guest uses it or not, the backend only knowing about the queue
+        // pairs.
+        let cvq_queue = queues.remove(queue_num - 1);
+        let cvq_queue_evt = queue_evts.remove(queue_num - 1);
+        let ctrl_queue = (self.acked_features & 1 << virtio_net::VIRTIO_NET_F_CTRL_VQ) != 0;
 
+        // One epoll thread per queue pair and the control queue one, plus
+        // the device pausing them.
+        let paused_sync = Arc::new(Barrier::new((queue_num - 1) / 2 + ctrl_queue as usize + 1));
+
+        if ctrl_queue {
             let mut ctrl_handler = NetCtrlEpollHandler {
                 mem: mem.clone(),
                 kill_evt: kill_evt.try_clone().unwrap(),
@@ -282,9 +296,10 @@ impl VirtioDevice for Net {
             };
 
             let paused = self.paused.clone();
+            let paused_sync = paused_sync.clone();
             thread::Builder::new()
-                .name("virtio_net".to_string())
-                .spawn(move || ctrl_handler.run_ctrl(paused))
+                .name("vhost_user_net_ctrl".to_string())
+                .spawn(move || ctrl_handler.run_ctrl(paused, paused_sync))
                 .map(|thread| self.ctrl_queue_epoll_thread = Some(thread))
                 .map_err(|e| {
                     error!("failed to clone queue EventFd: {}", e);
@@ -303,7 +318,7 @@ impl VirtioDevice for Net {
         .map_err(ActivateError::VhostUserNetSetup)?;
 
         let mut epoll_threads = Vec::new();
-        for _ in 0..vu_interrupt_list.len() / 2 {
+        for i in 0..vu_interrupt_list.len() / 2 {
             let mut interrupt_list_sub: Vec<(Option<EventFd>, Queue)> = Vec::with_capacity(2);
             interrupt_list_sub.push(vu_interrupt_list.remove(0));
             interrupt_list_sub.push(vu_interrupt_list.remove(0));
@@ -317,9 +332,10 @@ impl VirtioDevice for Net {
             });
 
             let paused = self.paused.clone();
+            let paused_sync = paused_sync.clone();
             thread::Builder::new()
-                .name("vhost_user_net".to_string())
-                .spawn(move || handler.run(paused))
+                .name(format!("vhost_user_net_q{}", i))
+                .spawn(move || handler.run(paused, paused_sync))
                 .map(|thread| epoll_threads.push(thread))
                 .map_err(|e| {
                     error!("failed to clone queue EventFd: {}", e);
@@ -328,6 +344,7 @@ impl VirtioDevice for Net {
         }
 
         self.epoll_threads = Some(epoll_threads);
+        self.paused_sync = Some(paused_sync);
 
         Ok(())
     }
@@ -338,7 +355,8 @@ impl VirtioDevice for Net {
             self.resume().ok()?;
         }
 
-        if let Err(e) = reset_vhost_user(&mut self.vhost_user_net, self.queue_sizes.len()) {
+        // The backend doesn't know about the control queue.
+        if let Err(e) = reset_vhost_user(&mut self.vhost_user_net, self.queue_sizes.len() - 1) {
             error!("Failed to reset vhost-user daemon: {:?}", e);
             return None;
         }
@@ -361,5 +379,9 @@ impl VirtioDevice for Net {
 }
 
 virtio_ctrl_q_pausable!(Net);
-impl Snapshotable for Net {}
+impl Snapshotable for Net {
+    fn id(&self) -> String {
+        "vhost-user-net".to_string()
+    }
+}
 impl Migratable for Net {}
@@ -10,7 +10,7 @@
 
 use super::{VsockBackend, VsockPacket};
 use crate::Error as DeviceError;
-use crate::VirtioInterrupt;
+use crate::{wait_for_resume, VirtioInterrupt};
 use crate::{
     ActivateError, ActivateResult, DeviceEventT, Queue, VirtioDevice, VirtioDeviceType,
     VirtioInterruptType, VIRTIO_F_IN_ORDER, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
@@ -42,7 +42,7 @@ use std::io;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Barrier, RwLock};
 use std::thread;
 use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
 use vm_memory::{GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
@@ -194,7 +194,11 @@ where
         }
     }
 
-    fn run(&mut self, paused: Arc<AtomicBool>) -> result::Result<(), DeviceError> {
+    fn run(
+        &mut self,
+        paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
+    ) -> result::Result<(), DeviceError> {
         // Create the epoll file descriptor
         let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
 
@@ -277,7 +281,7 @@ where
 
                 let ev_type = event.data as DeviceEventT;
 
-                if self.handle_event(ev_type, evset, paused.clone())? {
+                if self.handle_event(ev_type, evset, paused.clone(), paused_sync.clone())? {
                     break 'epoll;
                 }
             }
@@ -291,6 +295,7 @@ where
         device_event: DeviceEventT,
         evset: epoll::Events,
         paused: Arc<AtomicBool>,
+        paused_sync: Arc<Barrier>,
     ) -> Result<bool, DeviceError> {
         match device_event {
             RX_QUEUE_EVENT => {
@@ -352,12 +357,7 @@ where
             }
             PAUSE_EVENT => {
                 debug!("PAUSE_EVENT received, pausing virtio-vsock epoll loop");
-                // We loop here to handle spurious park() returns.
-                // Until we have not resumed, the paused boolean will
-                // be true.
-                while paused.load(Ordering::SeqCst) {
-                    thread::park();
-                }
+                wait_for_resume(&paused, &paused_sync);
             }
             other => {
                 error!("Unknown event for virtio-vsock");
@@ -384,6 +384,7 @@ pub struct Vsock<B: VsockBackend> {
     interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
     epoll_threads: Option<Vec<thread::JoinHandle<result::Result<(), DeviceError>>>>,
     paused: Arc<AtomicBool>,
+    paused_sync: Option<Arc<Barrier>>,
 }
 
 impl<B> Vsock<B>
@@ -410,6 +411,7 @@ where
             interrupt_cb: None,
             epoll_threads: None,
             paused: Arc::new(AtomicBool::new(false)),
+            paused_sync: None,
         })
     }
 }
@@ -536,10 +538,12 @@ where
         };
 
         let paused = self.paused.clone();
+        let paused_sync = Arc::new(Barrier::new(2));
+        let paused_sync_clone = paused_sync.clone();
         let mut epoll_threads = Vec::new();
         thread::Builder::new()
             .name("virtio_vsock".to_string())
-            .spawn(move || handler.run(paused))
+            .spawn(move || handler.run(paused, paused_sync_clone))
             .map(|thread| epoll_threads.push(thread))
             .map_err(|e| {
                 error!("failed to clone the vsock epoll thread: {}", e);
@@ -547,6 +551,7 @@ where
             })?;
 
         self.epoll_threads = Some(epoll_threads);
+        self.paused_sync = Some(paused_sync);
 
         Ok(())
     }
@@ -572,7 +577,14 @@ where
 
 virtio_pausable!(Vsock, T: 'static + VsockBackend + Sync);
 
-impl<B> Snapshotable for Vsock<B> where B: VsockBackend + Sync + 'static {}
+impl<B> Snapshotable for Vsock<B>
+where
+    B: VsockBackend + Sync + 'static,
+{
+    fn id(&self) -> String {
+        "virtio-vsock".to_string()
+    }
+}
 impl<B> Migratable for Vsock<B> where B: VsockBackend + Sync + 'static {}
 
 #[cfg(test)]
@@ -758,6 +770,7 @@ mod tests {
                 TX_QUEUE_EVENT,
                 epoll::Events::EPOLLIN,
                 Arc::new(AtomicBool::new(false)),
+                Arc::new(Barrier::new(1)),
             ) {
                 Err(DeviceError::FailedReadingQueue { .. }) => (),
                 other => panic!("{:?}", other),
@@ -825,6 +838,7 @@ mod tests {
                 RX_QUEUE_EVENT,
                 epoll::Events::EPOLLIN,
                 Arc::new(AtomicBool::new(false)),
+                Arc::new(Barrier::new(1)),
             ) {
                 Err(DeviceError::FailedReadingQueue { .. }) => (),
                 other => panic!("{:?}", other),
@@ -843,6 +857,7 @@ mod tests {
                 EVT_QUEUE_EVENT,
                 epoll::Events::EPOLLIN,
                 Arc::new(AtomicBool::new(false)),
+                Arc::new(Barrier::new(1)),
             ) {
                 Err(DeviceError::FailedReadingQueue { .. }) => (),
                 other => panic!("{:?}", other),
@@ -865,6 +880,7 @@ mod tests {
                     BACKEND_EVENT,
                     epoll::Events::EPOLLIN,
                     Arc::new(AtomicBool::new(false)),
+                    Arc::new(Barrier::new(1)),
                 )
                 .unwrap();
 
@@ -892,6 +908,7 @@ mod tests {
                     BACKEND_EVENT,
                     epoll::Events::EPOLLIN,
                     Arc::new(AtomicBool::new(false)),
+                    Arc::new(Barrier::new(1)),
                 )
                 .unwrap();
 
@@ -916,6 +933,7 @@ mod tests {
             0xff,
             epoll::Events::EPOLLIN,
             Arc::new(AtomicBool::new(false)),
+            Arc::new(Barrier::new(1)),
         ) {
             Err(DeviceError::UnknownEvent { .. }) => (),
             other => panic!("{:?}", other),
@@ -167,7 +167,7 @@ mod tests {
     use libc::EFD_NONBLOCK;
     use std::os::unix::io::AsRawFd;
     use std::sync::atomic::AtomicBool;
-    use std::sync::{Arc, RwLock};
+    use std::sync::{Arc, Barrier, RwLock};
     use vm_memory::{GuestAddress, GuestMemoryAtomic, GuestMemoryMmap};
     use vmm_sys_util::eventfd::EventFd;
 
@@ -336,6 +336,7 @@ mod tests {
                     TX_QUEUE_EVENT,
                     epoll::Events::EPOLLIN,
                     Arc::new(AtomicBool::new(false)),
+                    Arc::new(Barrier::new(1)),
                 )
                 .unwrap();
         }
@@ -346,6 +347,7 @@ mod tests {
                     RX_QUEUE_EVENT,
                     epoll::Events::EPOLLIN,
                     Arc::new(AtomicBool::new(false)),
+                    Arc::new(Barrier::new(1)),
                 )
                 .unwrap();
         }
@@ -4,18 +4,36 @@
 //
 
 use crate::api::http_endpoint::{
-    VmActionHandler, VmCreate, VmInfo, VmResize, VmmPing, VmmShutdown,
+    Batch, Interfaces, ObjectActionHandler, Operation, VmActionHandler, VmClock, VmCounters,
+    VmCreate, VmDiskChangedBlocksHandler, VmDiskCheckpointHandler, VmFirecrackerConfig, VmInfo,
+    VmResize, VmResizeDisk, VmRestore, VmSnapshot, VmSnapshotExport, VmSnapshotImport, VmSpec,
+    VmUpdateNet, VmmCapabilities, VmmPing, VmmShutdown, Volumes,
 };
-use crate::api::{ApiRequest, VmAction};
+use crate::api::{ApiRequest, ObjectAction, VmAction};
 use crate::{Error, Result};
-use micro_http::{HttpServer, MediaType, Request, Response, StatusCode, Version};
-use std::collections::HashMap;
+use micro_http::{
+    Body, HttpConnection, HttpServer, MediaType, Method, Request, Response, StatusCode, Version,
+};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::PathBuf;
 use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use vmm_sys_util::eventfd::EventFd;
 
-const HTTP_ROOT: &str = "/api/v1";
+pub(crate) const HTTP_ROOT: &str = "/api/v1";
+
+// Routes handling all the paths starting with them, as they embed an id.
+const HTTP_PREFIX_ROUTES: [&str; 1] = ["/operations/"];
+
+// Header clients set on mutating requests they may retry, e.g. after a
+// timeout, so that they're not applied twice.
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+// Number of idempotency keys the server keeps track of.
+const IDEMPOTENCY_CACHE_SIZE: usize = 256;
 
 /// An HTTP endpoint handler interface
 pub trait EndpointHandler: Sync + Send {
@@ -32,6 +50,56 @@ pub trait EndpointHandler: Sync + Send {
     ) -> Response;
 }
 
+/// The credentials of the process at the other end of an API connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeerCredentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl PeerCredentials {
+    /// Gets the credentials of the peer of `stream`, as they were when it
+    /// connected.
+    pub fn from_stream(stream: &UnixStream) -> io::Result<Self> {
+        let mut ucred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // Safe because the kernel only writes up to `len` bytes to `ucred`,
+        // and we check the return value.
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut ucred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(PeerCredentials {
+            pid: ucred.pid,
+            uid: ucred.uid,
+            gid: ucred.gid,
+        })
+    }
+}
+
+/// A policy deciding which HTTP API requests are served, for the VMM
+/// embedders to restrict the API beyond the socket permissions, e.g. only
+/// letting a given user hotplug devices.
+pub trait ApiAuthorizer: Send + Sync {
+    /// Whether the process `peer` may send the `method` request on `path`,
+    /// e.g. `/api/v1/vm.add-disk`. The denied requests get a 400 response.
+    fn authorize(&self, peer: &PeerCredentials, method: Method, path: &str) -> bool;
+}
+
 /// An HTTP routes structure.
 pub struct HttpRoutes {
     /// routes is a hash table mapping endpoint URIs to their endpoint handlers.
@@ -55,25 +123,162 @@ lazy_static! {
         r.routes.insert(endpoint!("/vm.boot"), Box::new(VmActionHandler::new(VmAction::Boot)));
         r.routes.insert(endpoint!("/vm.delete"), Box::new(VmActionHandler::new(VmAction::Delete)));
         r.routes.insert(endpoint!("/vm.info"), Box::new(VmInfo {}));
+        r.routes.insert(endpoint!("/vm.firecracker-config"), Box::new(VmFirecrackerConfig {}));
         r.routes.insert(endpoint!("/vm.pause"), Box::new(VmActionHandler::new(VmAction::Pause)));
         r.routes.insert(endpoint!("/vm.resume"), Box::new(VmActionHandler::new(VmAction::Resume)));
         r.routes.insert(endpoint!("/vm.shutdown"), Box::new(VmActionHandler::new(VmAction::Shutdown)));
         r.routes.insert(endpoint!("/vm.reboot"), Box::new(VmActionHandler::new(VmAction::Reboot)));
         r.routes.insert(endpoint!("/vmm.shutdown"), Box::new(VmmShutdown {}));
         r.routes.insert(endpoint!("/vmm.ping"), Box::new(VmmPing {}));
+        r.routes.insert(endpoint!("/vmm.capabilities"), Box::new(VmmCapabilities {}));
         r.routes.insert(endpoint!("/vm.resize"), Box::new(VmResize {}));
+        r.routes.insert(endpoint!("/vm.resize-disk"), Box::new(VmResizeDisk {}));
+        r.routes.insert(endpoint!("/vm.update-net"), Box::new(VmUpdateNet {}));
+        r.routes.insert(endpoint!("/vm.snapshot"), Box::new(VmSnapshot {}));
+        r.routes.insert(endpoint!("/vm.spec"), Box::new(VmSpec {}));
+        r.routes.insert(endpoint!("/vm.snapshot-cancel"), Box::new(VmActionHandler::new(VmAction::SnapshotCancel)));
+        r.routes.insert(endpoint!("/vm.restore"), Box::new(VmRestore {}));
+        r.routes.insert(endpoint!("/vm.snapshot-export"), Box::new(VmSnapshotExport {}));
+        r.routes.insert(endpoint!("/vm.snapshot-import"), Box::new(VmSnapshotImport {}));
+        r.routes.insert(endpoint!("/vm.clock"), Box::new(VmClock {}));
+        r.routes.insert(endpoint!("/vm.counters"), Box::new(VmCounters {}));
+        r.routes.insert(endpoint!("/vm.disk-checkpoint"), Box::new(VmDiskCheckpointHandler {}));
+        r.routes.insert(endpoint!("/vm.disk-changed-blocks"), Box::new(VmDiskChangedBlocksHandler {}));
+        r.routes.insert(endpoint!("/vm.attach-volume"), Box::new(ObjectActionHandler::new(ObjectAction::AttachVolume)));
+        r.routes.insert(endpoint!("/vm.detach-volume"), Box::new(ObjectActionHandler::new(ObjectAction::DetachVolume)));
+        r.routes.insert(endpoint!("/vm.attach-interface"), Box::new(ObjectActionHandler::new(ObjectAction::AttachInterface)));
+        r.routes.insert(endpoint!("/vm.detach-interface"), Box::new(ObjectActionHandler::new(ObjectAction::DetachInterface)));
+        r.routes.insert(endpoint!("/volumes"), Box::new(Volumes {}));
+        r.routes.insert(endpoint!("/volumes.delete"), Box::new(ObjectActionHandler::new(ObjectAction::DeleteVolume)));
+        r.routes.insert(endpoint!("/interfaces"), Box::new(Interfaces {}));
+        r.routes.insert(endpoint!("/interfaces.delete"), Box::new(ObjectActionHandler::new(ObjectAction::DeleteInterface)));
+        r.routes.insert(endpoint!("/operations/"), Box::new(Operation {}));
+        r.routes.insert(endpoint!("/batch"), Box::new(Batch {}));
 
         r
     };
 }
 
+// Outcome of a request sent with an idempotency key.
+struct IdempotentResponse {
+    path: String,
+    request_body: Option<Vec<u8>>,
+    status: StatusCode,
+    body: Option<Body>,
+}
+
+/// Remembers the responses to the last mutating requests carrying an
+/// idempotency key, so that a retried request gets the original response
+/// back instead of being processed again. Only the successful responses are
+/// remembered, a request which failed being processed again when retried,
+/// the failure being possibly transient.
+struct IdempotencyCache {
+    responses: HashMap<String, IdempotentResponse>,
+    keys: VecDeque<String>,
+}
+
+impl IdempotencyCache {
+    fn new() -> Self {
+        IdempotencyCache {
+            responses: HashMap::new(),
+            keys: VecDeque::with_capacity(IDEMPOTENCY_CACHE_SIZE),
+        }
+    }
+
+    fn insert(&mut self, key: String, response: IdempotentResponse) {
+        if self.keys.len() == IDEMPOTENCY_CACHE_SIZE {
+            if let Some(oldest) = self.keys.pop_front() {
+                self.responses.remove(&oldest);
+            }
+        }
+        self.keys.push_back(key.clone());
+        self.responses.insert(key, response);
+    }
+}
+
+/// Finds the handler for an absolute request path, if any.
+pub(crate) fn route(path: &str) -> Option<&'static dyn EndpointHandler> {
+    HTTP_ROUTES
+        .routes
+        .get(path)
+        .or_else(|| {
+            HTTP_PREFIX_ROUTES
+                .iter()
+                .map(|prefix| endpoint!(prefix))
+                .find(|prefix| path.starts_with(prefix.as_str()))
+                .and_then(|prefix| HTTP_ROUTES.routes.get(&prefix))
+        })
+        .map(|route| route.as_ref() as &'static dyn EndpointHandler)
+}
+
+fn idempotency_key(request: &Request) -> Option<String> {
+    request
+        .headers
+        .custom_entries()
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(IDEMPOTENCY_KEY_HEADER))
+        .map(|(_, value)| value.trim().to_string())
+        .filter(|key| !key.is_empty())
+}
+
+fn rejection(message: &str) -> Response {
+    let mut response = Response::new(Version::Http11, StatusCode::BadRequest);
+    response.set_body(Body::new(message.to_string()));
+    response.set_server("Cloud Hypervisor API");
+    response.set_content_type(MediaType::ApplicationJson);
+    response
+}
+
 fn handle_http_request(
     request: &Request,
+    read_only: bool,
     api_notifier: &EventFd,
     api_sender: &Sender<ApiRequest>,
+    idempotency_cache: &mut IdempotencyCache,
 ) -> Response {
+    // The requests reading the VMM and VM state are the GET ones, the only
+    // ones a read-only socket serves.
+    if read_only {
+        match request.method() {
+            Method::Get => {}
+            _ => return rejection("Only GET requests are allowed on the read-only API socket"),
+        }
+    }
+
     let path = request.uri().get_abs_path().to_string();
-    let mut response = match HTTP_ROUTES.routes.get(&path) {
+    let request_body = request.body.as_ref().map(|body| body.raw().to_vec());
+
+    // Only mutating requests need to be deduplicated.
+    let key = match request.method() {
+        Method::Put => idempotency_key(request),
+        _ => None,
+    };
+
+    if let Some(key) = &key {
+        if let Some(cached) = idempotency_cache.responses.get(key) {
+            // Reusing a key for a different request is a client error.
+            let mut response = if cached.path == path && cached.request_body == request_body {
+                let mut response = Response::new(Version::Http11, cached.status);
+                if let Some(body) = &cached.body {
+                    response.set_body(body.clone());
+                }
+                response
+            } else {
+                let mut response = Response::new(Version::Http11, StatusCode::BadRequest);
+                response.set_body(Body::new(format!(
+                    "Idempotency key {} already used for another request",
+                    key
+                )));
+                response
+            };
+
+            response.set_server("Cloud Hypervisor API");
+            response.set_content_type(MediaType::ApplicationJson);
+            return response;
+        }
+    }
+
+    let mut response = match route(&path) {
         Some(route) => match api_notifier.try_clone() {
             Ok(notifier) => route.handle_request(&request, notifier, api_sender.clone()),
             Err(_) => Response::new(Version::Http11, StatusCode::InternalServerError),
@@ -81,23 +286,158 @@ fn handle_http_request(
         None => Response::new(Version::Http11, StatusCode::NotFound),
     };
 
+    if let Some(key) = key {
+        match response.status() {
+            StatusCode::OK | StatusCode::NoContent => idempotency_cache.insert(
+                key,
+                IdempotentResponse {
+                    path,
+                    request_body,
+                    status: response.status(),
+                    body: response.body(),
+                },
+            ),
+            _ => {}
+        }
+    }
+
     response.set_server("Cloud Hypervisor API");
     response.set_content_type(MediaType::ApplicationJson);
     response
 }
 
+// Serves the requests coming through one connection, as long as it's open.
+fn serve_connection(
+    stream: UnixStream,
+    peer: PeerCredentials,
+    read_only: bool,
+    authorizer: &dyn ApiAuthorizer,
+    api_notifier: &EventFd,
+    api_sender: &Sender<ApiRequest>,
+    idempotency_cache: &Mutex<IdempotencyCache>,
+) {
+    let mut connection = HttpConnection::new(stream);
+    loop {
+        // A request which can't be parsed still gets a response.
+        let read = connection.try_read();
+        while let Some(request) = connection.pop_parsed_request() {
+            let path = request.uri().get_abs_path().to_string();
+            let response = if authorizer.authorize(&peer, request.method(), &path) {
+                handle_http_request(
+                    &request,
+                    read_only,
+                    api_notifier,
+                    api_sender,
+                    &mut idempotency_cache.lock().unwrap(),
+                )
+            } else {
+                warn!("API request on {} denied to {:?}", path, peer);
+                rejection("The request is not authorized")
+            };
+            connection.enqueue_response(response);
+        }
+
+        while connection.pending_write() {
+            if let Err(e) = connection.try_write() {
+                error!("HTTP server error on response: {:?}", e);
+                return;
+            }
+        }
+
+        if let Err(e) = read {
+            debug!("HTTP connection closed: {:?}", e);
+            return;
+        }
+    }
+}
+
+// The HTTP server doesn't tell which connection a request comes from, so the
+// connections are accepted here to know the peer credentials the authorizer
+// needs, each of them being served by its own thread.
+fn serve_authorized(
+    socket_path: PathBuf,
+    read_only: bool,
+    authorizer: Arc<dyn ApiAuthorizer>,
+    api_notifier: EventFd,
+    api_sender: Sender<ApiRequest>,
+) -> Result<()> {
+    let listener = UnixListener::bind(&socket_path).map_err(Error::Bind)?;
+    let idempotency_cache = Arc::new(Mutex::new(IdempotencyCache::new()));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("HTTP server error on accepting a connection: {}", e);
+                continue;
+            }
+        };
+        // Nothing is served to an unknown peer.
+        let peer = match PeerCredentials::from_stream(&stream) {
+            Ok(peer) => peer,
+            Err(e) => {
+                error!("Cannot get the credentials of the HTTP API peer: {}", e);
+                continue;
+            }
+        };
+
+        let authorizer = authorizer.clone();
+        let api_notifier = api_notifier.try_clone().map_err(Error::EventFdClone)?;
+        let api_sender = api_sender.clone();
+        let idempotency_cache = idempotency_cache.clone();
+        thread::Builder::new()
+            .name(format!("http-conn-{}", peer.pid))
+            .spawn(move || {
+                serve_connection(
+                    stream,
+                    peer,
+                    read_only,
+                    authorizer.as_ref(),
+                    &api_notifier,
+                    &api_sender,
+                    &idempotency_cache,
+                )
+            })
+            .map_err(Error::HttpThreadSpawn)?;
+    }
+
+    Ok(())
+}
+
+/// Serves the HTTP API on the UNIX socket `path`, only allowing the requests
+/// reading the VMM and VM state if `read_only` is set, e.g. for monitoring
+/// agents. The requests are only served once `authorizer`, if any, allowed
+/// them.
 pub fn start_http_thread(
     path: &str,
+    read_only: bool,
+    authorizer: Option<Arc<dyn ApiAuthorizer>>,
     api_notifier: EventFd,
     api_sender: Sender<ApiRequest>,
 ) -> Result<thread::JoinHandle<Result<()>>> {
     std::fs::remove_file(path).unwrap_or_default();
     let socket_path = PathBuf::from(path);
+    let thread_name = if read_only {
+        "http-server-ro"
+    } else {
+        "http-server"
+    };
 
     thread::Builder::new()
-        .name("http-server".to_string())
+        .name(thread_name.to_string())
         .spawn(move || {
+            if let Some(authorizer) = authorizer {
+                return serve_authorized(
+                    socket_path,
+                    read_only,
+                    authorizer,
+                    api_notifier,
+                    api_sender,
+                );
+            }
+
             let mut server = HttpServer::new(socket_path).unwrap();
+            let mut idempotency_cache = IdempotencyCache::new();
             server.start_server().unwrap();
             loop {
                 match server.requests() {
@@ -105,7 +445,13 @@ pub fn start_http_thread(
                         for server_request in request_vec {
                             server
                                 .respond(server_request.process(|request| {
-                                    handle_http_request(request, &api_notifier, &api_sender)
+                                    handle_http_request(
+                                        request,
+                                        read_only,
+                                        &api_notifier,
+                                        &api_sender,
+                                        &mut idempotency_cache,
+                                    )
                                 }))
                                 .or_else(|e| {
                                     error!("HTTP server error on response: {}", e);
@@ -124,3 +470,69 @@ pub fn start_http_thread(
         })
         .map_err(Error::HttpThreadSpawn)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{ApiError, ApiResponsePayload};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_idempotency_retry_after_failure() {
+        let api_notifier = EventFd::new(0).unwrap();
+        let (api_sender, api_receiver) = channel();
+        let boots = Arc::new(AtomicUsize::new(0));
+
+        // The first boot fails, the next ones succeed.
+        let handler_boots = boots.clone();
+        let handler = thread::spawn(move || {
+            for request in api_receiver.iter() {
+                if let ApiRequest::VmBoot(response_sender) = request {
+                    let response = if handler_boots.fetch_add(1, Ordering::SeqCst) == 0 {
+                        Err(ApiError::ResponsePayloadType)
+                    } else {
+                        Ok(ApiResponsePayload::Empty)
+                    };
+                    response_sender.send(response).unwrap();
+                }
+            }
+        });
+
+        let request =
+            Request::try_from(b"PUT /api/v1/vm.boot HTTP/1.1\r\nIdempotency-Key: boot-1\r\n\r\n")
+                .unwrap();
+        let mut idempotency_cache = IdempotencyCache::new();
+        let mut send = || {
+            handle_http_request(
+                &request,
+                false,
+                &api_notifier,
+                &api_sender,
+                &mut idempotency_cache,
+            )
+            .status()
+        };
+
+        // The failure isn't remembered, the retry reaching the handler.
+        match send() {
+            StatusCode::InternalServerError => {}
+            _ => panic!("The first boot should fail"),
+        }
+        match send() {
+            StatusCode::NoContent => {}
+            _ => panic!("The retried boot should succeed"),
+        }
+        assert_eq!(boots.load(Ordering::SeqCst), 2);
+
+        // The success is, the next retry getting it back.
+        match send() {
+            StatusCode::NoContent => {}
+            _ => panic!("The boot response should be remembered"),
+        }
+        assert_eq!(boots.load(Ordering::SeqCst), 2);
+
+        drop(api_sender);
+        handler.join().unwrap();
+    }
+}
@@ -3,13 +3,21 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use crate::api::http::EndpointHandler;
+use crate::api::http::{route, EndpointHandler, HTTP_ROOT};
 use crate::api::{
-    vm_boot, vm_create, vm_delete, vm_info, vm_pause, vm_reboot, vm_resize, vm_resume, vm_shutdown,
-    vmm_ping, vmm_shutdown, ApiError, ApiRequest, ApiResult, VmAction, VmConfig, VmResizeData,
+    interface_create, interfaces, object_action, operation_cancel, operation_info, vm_boot,
+    vm_clock, vm_counters, vm_create, vm_delete, vm_disk_changed_blocks, vm_disk_checkpoint,
+    vm_info, vm_pause, vm_reboot, vm_resize, vm_resize_disk, vm_restore, vm_resume, vm_set_clock,
+    vm_shutdown, vm_snapshot, vm_snapshot_cancel, vm_snapshot_export, vm_snapshot_import, vm_spec,
+    vm_update_net, vmm_capabilities, vmm_ping, vmm_shutdown, volume_create, volumes, ApiError,
+    ApiRequest, ApiResult, InterfaceConfig, ObjectAction, ObjectId, VmAction, VmClockData,
+    VmConfig, VmDiskChangedBlocks, VmDiskCheckpoint, VmNetUpdate, VmResizeData, VmResizeDiskData,
+    VmRestoreConfig, VmSnapshotConfig, VmSnapshotExportConfig, VmSnapshotImportConfig,
+    VolumeConfig,
 };
+use crate::firecracker::FirecrackerConfig;
 use micro_http::{Body, Method, Request, Response, StatusCode, Version};
-use serde_json::Error as SerdeError;
+use serde_json::{Error as SerdeError, Value};
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use vmm_sys_util::eventfd::EventFd;
@@ -49,6 +57,72 @@ pub enum HttpError {
 
     /// Could not handle VMM ping
     VmmPing(ApiError),
+
+    /// Could not get the VMM capabilities
+    VmmCapabilities(ApiError),
+
+    /// Could not snapshot the VM
+    VmSnapshot(ApiError),
+
+    /// Could not restore the VM
+    VmRestore(ApiError),
+
+    /// Could not export the VM snapshot
+    VmSnapshotExport(ApiError),
+
+    /// Could not import the VM snapshot
+    VmSnapshotImport(ApiError),
+
+    /// Could not bring the VM to its spec
+    VmSpec(ApiError),
+
+    /// Could not propagate the disk resize
+    VmResizeDisk(ApiError),
+
+    /// Could not update a network interface
+    VmUpdateNet(ApiError),
+
+    /// Could not get the VM clock
+    VmClock(ApiError),
+
+    /// Could not set the VM clock
+    VmSetClock(ApiError),
+
+    /// Could not get the VM counters
+    VmCounters(ApiError),
+
+    /// Could not take the disk checkpoint
+    VmDiskCheckpoint(ApiError),
+
+    /// Could not get the blocks changed on the disk
+    VmDiskChangedBlocks(ApiError),
+
+    /// Could not create a volume
+    VolumeCreate(ApiError),
+
+    /// Could not get the volumes information
+    Volumes(ApiError),
+
+    /// Could not create a network interface
+    InterfaceCreate(ApiError),
+
+    /// Could not get the network interfaces information
+    Interfaces(ApiError),
+
+    /// Could not act on a volume or a network interface
+    ObjectAction(ApiError),
+
+    /// Could not get the operation information
+    OperationInfo(ApiError),
+
+    /// Could not cancel the operation
+    OperationCancel(ApiError),
+
+    /// Invalid request in a batch
+    BatchRequest(String),
+
+    /// Could not export the VM configuration to Firecracker
+    FirecrackerConfig(crate::firecracker::Error),
 }
 
 fn error_response(error: HttpError, status: StatusCode) -> Response {
@@ -114,6 +188,7 @@ impl VmActionHandler {
             VmAction::Reboot => vm_reboot,
             VmAction::Pause => vm_pause,
             VmAction::Resume => vm_resume,
+            VmAction::SnapshotCancel => vm_snapshot_cancel,
         });
 
         VmActionHandler { action_fn }
@@ -172,6 +247,39 @@ impl EndpointHandler for VmInfo {
     }
 }
 
+// /api/v1/vm.firecracker-config handler
+pub struct VmFirecrackerConfig {}
+
+impl EndpointHandler for VmFirecrackerConfig {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Get => match vm_info(api_notifier, api_sender).map_err(HttpError::VmInfo) {
+                Ok(info) => {
+                    match FirecrackerConfig::from_vm_config(&info.config.lock().unwrap())
+                        .map_err(HttpError::FirecrackerConfig)
+                    {
+                        Ok(fc_config) => {
+                            let mut response = Response::new(Version::Http11, StatusCode::OK);
+                            let fc_config_serialized = serde_json::to_string(&fc_config).unwrap();
+
+                            response.set_body(Body::new(fc_config_serialized));
+                            response
+                        }
+                        Err(e) => error_response(e, StatusCode::BadRequest),
+                    }
+                }
+                Err(e) => error_response(e, StatusCode::InternalServerError),
+            },
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
 // /api/v1/vmm.info handler
 pub struct VmmPing {}
 
@@ -198,6 +306,34 @@ impl EndpointHandler for VmmPing {
     }
 }
 
+// /api/v1/vmm.capabilities handler
+pub struct VmmCapabilities {}
+
+impl EndpointHandler for VmmCapabilities {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Get => match vmm_capabilities(api_notifier, api_sender)
+                .map_err(HttpError::VmmCapabilities)
+            {
+                Ok(capabilities) => {
+                    let mut response = Response::new(Version::Http11, StatusCode::OK);
+                    let capabilities_serialized = serde_json::to_string(&capabilities).unwrap();
+
+                    response.set_body(Body::new(capabilities_serialized));
+                    response
+                }
+                Err(e) => error_response(e, StatusCode::InternalServerError),
+            },
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
 // /api/v1/vmm.shutdown handler
 pub struct VmmShutdown {}
 
@@ -258,3 +394,776 @@ impl EndpointHandler for VmResize {
         }
     }
 }
+
+// /api/v1/vm.resize-disk handler
+pub struct VmResizeDisk {}
+
+impl EndpointHandler for VmResizeDisk {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => match &req.body {
+                Some(body) => {
+                    let resize_data: VmResizeDiskData = match serde_json::from_slice(body.raw())
+                        .map_err(HttpError::SerdeJsonDeserialize)
+                    {
+                        Ok(data) => data,
+                        Err(e) => return error_response(e, StatusCode::BadRequest),
+                    };
+
+                    match vm_resize_disk(api_notifier, api_sender, Arc::new(resize_data))
+                        .map_err(HttpError::VmResizeDisk)
+                    {
+                        Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                        Err(e) => error_response(e, StatusCode::InternalServerError),
+                    }
+                }
+                None => Response::new(Version::Http11, StatusCode::BadRequest),
+            },
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.update-net handler
+pub struct VmUpdateNet {}
+
+impl EndpointHandler for VmUpdateNet {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => match &req.body {
+                Some(body) => {
+                    let update: VmNetUpdate = match serde_json::from_slice(body.raw())
+                        .map_err(HttpError::SerdeJsonDeserialize)
+                    {
+                        Ok(update) => update,
+                        Err(e) => return error_response(e, StatusCode::BadRequest),
+                    };
+
+                    match vm_update_net(api_notifier, api_sender, Arc::new(update))
+                        .map_err(HttpError::VmUpdateNet)
+                    {
+                        Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                        Err(e) => error_response(e, StatusCode::InternalServerError),
+                    }
+                }
+                None => Response::new(Version::Http11, StatusCode::BadRequest),
+            },
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.spec handler
+pub struct VmSpec {}
+
+impl EndpointHandler for VmSpec {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a VmConfig
+                        let vm_config: VmConfig = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(config) => config,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        // Call vm_spec()
+                        match vm_spec(api_notifier, api_sender, Arc::new(vm_config))
+                            .map_err(HttpError::VmSpec)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.snapshot handler
+pub struct VmSnapshot {}
+
+impl EndpointHandler for VmSnapshot {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a VmSnapshotConfig
+                        let vm_snapshot_config: VmSnapshotConfig =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(config) => config,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_snapshot()
+                        match vm_snapshot(api_notifier, api_sender, Arc::new(vm_snapshot_config))
+                            .map_err(HttpError::VmSnapshot)
+                        {
+                            Ok(info) => {
+                                let mut response = Response::new(Version::Http11, StatusCode::OK);
+                                let info_serialized = serde_json::to_string(&info).unwrap();
+
+                                response.set_body(Body::new(info_serialized));
+                                response
+                            }
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.restore handler
+pub struct VmRestore {}
+
+impl EndpointHandler for VmRestore {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => match &req.body {
+                Some(body) => {
+                    let restore_config: VmRestoreConfig = match serde_json::from_slice(body.raw())
+                        .map_err(HttpError::SerdeJsonDeserialize)
+                    {
+                        Ok(config) => config,
+                        Err(e) => return error_response(e, StatusCode::BadRequest),
+                    };
+
+                    match vm_restore(api_notifier, api_sender, Arc::new(restore_config))
+                        .map_err(HttpError::VmRestore)
+                    {
+                        Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                        Err(e) => error_response(e, StatusCode::InternalServerError),
+                    }
+                }
+                None => Response::new(Version::Http11, StatusCode::BadRequest),
+            },
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.snapshot-export handler
+pub struct VmSnapshotExport {}
+
+impl EndpointHandler for VmSnapshotExport {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a VmSnapshotExportConfig
+                        let export_config: VmSnapshotExportConfig =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(config) => config,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_snapshot_export()
+                        match vm_snapshot_export(api_notifier, api_sender, Arc::new(export_config))
+                            .map_err(HttpError::VmSnapshotExport)
+                        {
+                            Ok(info) => {
+                                let mut response = Response::new(Version::Http11, StatusCode::OK);
+                                let info_serialized = serde_json::to_string(&info).unwrap();
+
+                                response.set_body(Body::new(info_serialized));
+                                response
+                            }
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.snapshot-import handler
+pub struct VmSnapshotImport {}
+
+impl EndpointHandler for VmSnapshotImport {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a VmSnapshotImportConfig
+                        let import_config: VmSnapshotImportConfig =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(config) => config,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_snapshot_import()
+                        match vm_snapshot_import(api_notifier, api_sender, Arc::new(import_config))
+                            .map_err(HttpError::VmSnapshotImport)
+                        {
+                            Ok(info) => {
+                                let mut response = Response::new(Version::Http11, StatusCode::OK);
+                                let info_serialized = serde_json::to_string(&info).unwrap();
+
+                                response.set_body(Body::new(info_serialized));
+                                response
+                            }
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.clock handler
+pub struct VmClock {}
+
+impl EndpointHandler for VmClock {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a VmClockData
+                        let vm_clock_data: VmClockData = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(data) => data,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        // Call vm_set_clock()
+                        match vm_set_clock(api_notifier, api_sender, Arc::new(vm_clock_data))
+                            .map_err(HttpError::VmSetClock)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            Method::Get => match vm_clock(api_notifier, api_sender).map_err(HttpError::VmClock) {
+                Ok(clock) => {
+                    let mut response = Response::new(Version::Http11, StatusCode::OK);
+                    let clock_serialized = serde_json::to_string(&clock).unwrap();
+
+                    response.set_body(Body::new(clock_serialized));
+                    response
+                }
+                Err(e) => error_response(e, StatusCode::InternalServerError),
+            },
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.counters handler
+pub struct VmCounters {}
+
+impl EndpointHandler for VmCounters {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Get => {
+                match vm_counters(api_notifier, api_sender).map_err(HttpError::VmCounters) {
+                    Ok(counters) => {
+                        let mut response = Response::new(Version::Http11, StatusCode::OK);
+                        let counters_serialized = serde_json::to_string(&counters).unwrap();
+
+                        response.set_body(Body::new(counters_serialized));
+                        response
+                    }
+                    Err(e) => error_response(e, StatusCode::InternalServerError),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.disk-checkpoint handler
+pub struct VmDiskCheckpointHandler {}
+
+impl EndpointHandler for VmDiskCheckpointHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => match &req.body {
+                Some(body) => {
+                    let data: VmDiskCheckpoint = match serde_json::from_slice(body.raw())
+                        .map_err(HttpError::SerdeJsonDeserialize)
+                    {
+                        Ok(data) => data,
+                        Err(e) => return error_response(e, StatusCode::BadRequest),
+                    };
+
+                    match vm_disk_checkpoint(api_notifier, api_sender, Arc::new(data))
+                        .map_err(HttpError::VmDiskCheckpoint)
+                    {
+                        Ok(checkpoint) => {
+                            let mut response = Response::new(Version::Http11, StatusCode::OK);
+                            let checkpoint_serialized = serde_json::to_string(&checkpoint).unwrap();
+
+                            response.set_body(Body::new(checkpoint_serialized));
+                            response
+                        }
+                        Err(e) => error_response(e, StatusCode::InternalServerError),
+                    }
+                }
+                None => Response::new(Version::Http11, StatusCode::BadRequest),
+            },
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.disk-changed-blocks handler
+pub struct VmDiskChangedBlocksHandler {}
+
+impl EndpointHandler for VmDiskChangedBlocksHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => match &req.body {
+                Some(body) => {
+                    let data: VmDiskChangedBlocks = match serde_json::from_slice(body.raw())
+                        .map_err(HttpError::SerdeJsonDeserialize)
+                    {
+                        Ok(data) => data,
+                        Err(e) => return error_response(e, StatusCode::BadRequest),
+                    };
+
+                    match vm_disk_changed_blocks(api_notifier, api_sender, Arc::new(data))
+                        .map_err(HttpError::VmDiskChangedBlocks)
+                    {
+                        Ok(changed_blocks) => {
+                            let mut response = Response::new(Version::Http11, StatusCode::OK);
+                            let changed_blocks_serialized =
+                                serde_json::to_string(&changed_blocks).unwrap();
+
+                            response.set_body(Body::new(changed_blocks_serialized));
+                            response
+                        }
+                        Err(e) => error_response(e, StatusCode::InternalServerError),
+                    }
+                }
+                None => Response::new(Version::Http11, StatusCode::BadRequest),
+            },
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/volumes handler
+pub struct Volumes {}
+
+impl EndpointHandler for Volumes {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a VolumeConfig
+                        let volume_config: VolumeConfig = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(config) => config,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        // Call volume_create()
+                        match volume_create(api_notifier, api_sender, Arc::new(volume_config))
+                            .map_err(HttpError::VolumeCreate)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            Method::Get => match volumes(api_notifier, api_sender).map_err(HttpError::Volumes) {
+                Ok(volumes) => {
+                    let mut response = Response::new(Version::Http11, StatusCode::OK);
+                    let volumes_serialized = serde_json::to_string(&volumes).unwrap();
+
+                    response.set_body(Body::new(volumes_serialized));
+                    response
+                }
+                Err(e) => error_response(e, StatusCode::InternalServerError),
+            },
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/interfaces handler
+pub struct Interfaces {}
+
+impl EndpointHandler for Interfaces {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into an InterfaceConfig
+                        let interface_config: InterfaceConfig =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(config) => config,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call interface_create()
+                        match interface_create(api_notifier, api_sender, Arc::new(interface_config))
+                            .map_err(HttpError::InterfaceCreate)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            Method::Get => {
+                match interfaces(api_notifier, api_sender).map_err(HttpError::Interfaces) {
+                    Ok(interfaces) => {
+                        let mut response = Response::new(Version::Http11, StatusCode::OK);
+                        let interfaces_serialized = serde_json::to_string(&interfaces).unwrap();
+
+                        response.set_body(Body::new(interfaces_serialized));
+                        response
+                    }
+                    Err(e) => error_response(e, StatusCode::InternalServerError),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// Common handler for deleting, attaching and detaching volumes and network
+// interfaces
+pub struct ObjectActionHandler {
+    action: ObjectAction,
+}
+
+impl ObjectActionHandler {
+    pub fn new(action: ObjectAction) -> Self {
+        ObjectActionHandler { action }
+    }
+}
+
+impl EndpointHandler for ObjectActionHandler {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into an ObjectId
+                        let object_id: ObjectId = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(id) => id,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        match object_action(
+                            api_notifier,
+                            api_sender,
+                            &self.action,
+                            Arc::new(object_id),
+                        )
+                        .map_err(HttpError::ObjectAction)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/operations/{id} and /api/v1/operations/{id}/cancel handler
+pub struct Operation {}
+
+impl EndpointHandler for Operation {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        let path = req.uri().get_abs_path().to_string();
+        let mut elements = path.trim_end_matches('/').rsplit('/');
+        let (id, cancel) = match elements.next() {
+            Some("cancel") => (elements.next(), true),
+            last => (last, false),
+        };
+        let id: u64 = match id.and_then(|id| id.parse().ok()) {
+            Some(id) => id,
+            None => return Response::new(Version::Http11, StatusCode::NotFound),
+        };
+
+        match req.method() {
+            Method::Get if !cancel => {
+                match operation_info(api_notifier, api_sender, id).map_err(HttpError::OperationInfo)
+                {
+                    Ok(info) => {
+                        let mut response = Response::new(Version::Http11, StatusCode::OK);
+                        let info_serialized = serde_json::to_string(&info).unwrap();
+
+                        response.set_body(Body::new(info_serialized));
+                        response
+                    }
+                    Err(e) => error_response(e, StatusCode::InternalServerError),
+                }
+            }
+            Method::Put if cancel => {
+                match operation_cancel(api_notifier, api_sender, id)
+                    .map_err(HttpError::OperationCancel)
+                {
+                    Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                    Err(e) => error_response(e, StatusCode::InternalServerError),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+/// One of the requests of a batch, e.g. `{"path": "/vm.boot"}`.
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    #[serde(default = "default_batch_method")]
+    pub method: String,
+    /// Endpoint, relative to the API root.
+    pub path: String,
+    pub body: Option<Value>,
+}
+
+fn default_batch_method() -> String {
+    String::from("PUT")
+}
+
+/// Outcome of one of the requests of a batch.
+#[derive(Serialize)]
+pub struct BatchResponse {
+    pub status: u16,
+    pub body: Option<Value>,
+}
+
+// Builds the HTTP request a batched request stands for, so that it goes
+// through the same handler as if it had been sent on its own.
+fn batch_request(item: &BatchRequest) -> Result<Request, HttpError> {
+    let method = item.method.to_uppercase();
+    if method != "GET" && method != "PUT" {
+        return Err(HttpError::BatchRequest(format!(
+            "Unsupported method {}",
+            item.method
+        )));
+    }
+    if !item.path.starts_with('/') || item.path == "/batch" {
+        return Err(HttpError::BatchRequest(format!(
+            "Invalid path {}",
+            item.path
+        )));
+    }
+
+    let mut raw = format!("{} {}{} HTTP/1.1\r\n", method, HTTP_ROOT, item.path);
+    match &item.body {
+        Some(body) => {
+            let body = body.to_string();
+            raw.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+        }
+        None => raw.push_str("\r\n"),
+    }
+
+    Request::try_from(raw.as_bytes()).map_err(|e| HttpError::BatchRequest(format!("{:?}", e)))
+}
+
+fn batch_response(response: &Response) -> BatchResponse {
+    let status = std::str::from_utf8(response.status().raw())
+        .ok()
+        .and_then(|status| status.parse().ok())
+        .unwrap_or(500);
+    // Error messages aren't JSON, they're reported as strings.
+    let body = response.body().map(|body| {
+        serde_json::from_slice(body.raw())
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body.raw()).into_owned()))
+    });
+
+    BatchResponse { status, body }
+}
+
+// /api/v1/batch handler
+pub struct Batch {}
+
+impl EndpointHandler for Batch {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into an ordered list of requests
+                        let batch: Vec<BatchRequest> = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(batch) => batch,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        // Check the whole batch before running any of it.
+                        let mut requests = Vec::with_capacity(batch.len());
+                        for item in batch.iter() {
+                            match batch_request(item) {
+                                Ok(request) => requests.push(request),
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            }
+                        }
+
+                        // Run the requests in order, stopping at the first
+                        // one failing. The batch fails with its status.
+                        let mut status = StatusCode::OK;
+                        let mut results = Vec::with_capacity(requests.len());
+                        for request in requests.iter() {
+                            let path = request.uri().get_abs_path().to_string();
+                            let response = match (route(&path), api_notifier.try_clone()) {
+                                (Some(route), Ok(notifier)) => {
+                                    route.handle_request(request, notifier, api_sender.clone())
+                                }
+                                (None, _) => Response::new(Version::Http11, StatusCode::NotFound),
+                                (_, Err(_)) => {
+                                    Response::new(Version::Http11, StatusCode::InternalServerError)
+                                }
+                            };
+
+                            let result = batch_response(&response);
+                            let failed = result.status >= 300;
+                            results.push(result);
+                            if failed {
+                                status = response.status();
+                                break;
+                            }
+                        }
+
+                        let mut response = Response::new(Version::Http11, status);
+                        let results_serialized = serde_json::to_string(&results).unwrap();
+
+                        response.set_body(Body::new(results_serialized));
+                        response
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
@@ -31,14 +31,20 @@
 extern crate micro_http;
 extern crate vmm_sys_util;
 
-pub use self::http::start_http_thread;
+pub use self::http::{start_http_thread, ApiAuthorizer, PeerCredentials};
+pub use self::qmp::start_qmp_thread;
 
 pub mod http;
 pub mod http_endpoint;
+pub mod qmp;
 
-use crate::config::VmConfig;
-use crate::vm::{Error as VmError, VmState};
+use crate::config::{self, DiskConfig, NetConfig, RateLimiterConfig, VmConfig};
+use crate::guest_os::GuestOs;
+use crate::operation::OperationPhase;
+use crate::snapshot_archive;
+use crate::vm::{Error as VmError, PausedReason, VmState};
 use std::io;
+use std::path::PathBuf;
 use std::sync::mpsc::{channel, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
 use vmm_sys_util::eventfd::EventFd;
@@ -99,6 +105,71 @@ pub enum ApiError {
 
     /// The VM could not be resized
     VmResize(VmError),
+
+    /// The disk resize could not be propagated to the guest.
+    VmResizeDisk(VmError),
+
+    /// The network interface could not be updated.
+    VmUpdateNet(VmError),
+
+    /// The VM spec changes these fields, which can't be changed once the VM
+    /// is booted.
+    VmSpecUnsupportedChanges(Vec<String>),
+
+    /// The VM could not be snapshotted.
+    VmSnapshot(VmError),
+
+    /// The VM could not be restored.
+    VmRestore(VmError),
+
+    /// The VM snapshot could not be exported.
+    VmSnapshotExport(snapshot_archive::Error),
+
+    /// The VM snapshot could not be imported.
+    VmSnapshotImport(snapshot_archive::Error),
+
+    /// The VM clock could not be read or set.
+    VmClock(VmError),
+
+    /// The VM counters are not available.
+    VmCounters(VmError),
+
+    /// The disk checkpoint could not be taken.
+    VmDiskCheckpoint(VmError),
+
+    /// The blocks changed on the disk are not available.
+    VmDiskChangedBlocks(VmError),
+
+    /// The VM is already booted.
+    VmAlreadyBooted,
+
+    /// An object with the same id already exists.
+    ObjectAlreadyExists(String),
+
+    /// No object with this id exists.
+    ObjectNotFound(String),
+
+    /// The object is attached to the VM.
+    ObjectAttached(String),
+
+    /// The object is not attached to the VM.
+    ObjectNotAttached(String),
+
+    /// Attaching the object would leave the VM with more devices than the
+    /// PCI bus can hold.
+    TooManyPciDevices(config::Error),
+
+    /// No operation with this id exists.
+    OperationNotFound(u64),
+
+    /// The operation is not running anymore.
+    OperationNotRunning(u64),
+
+    /// No VM snapshot is in progress.
+    VmSnapshotNotRunning,
+
+    /// Cannot query KVM for the VMM capabilities.
+    VmmCapabilities(kvm_ioctls::Error),
 }
 pub type ApiResult<T> = std::result::Result<T, ApiError>;
 
@@ -106,6 +177,25 @@ pub type ApiResult<T> = std::result::Result<T, ApiError>;
 pub struct VmInfo {
     pub config: Arc<Mutex<VmConfig>>,
     pub state: VmState,
+    /// The guest OS, as guessed from the guest output since the VM booted.
+    #[serde(default)]
+    pub guest_os: Option<GuestOs>,
+    /// The snapshot the guest hibernated into, to be restored rather than
+    /// booting the VM anew.
+    #[serde(default)]
+    pub hibernate_snapshot: Option<PathBuf>,
+    /// The pty the serial port is exposed on, in `pty` mode.
+    #[serde(default)]
+    pub serial_pty: Option<PathBuf>,
+    /// The pty the virtio-console is exposed on, in `pty` mode.
+    #[serde(default)]
+    pub console_pty: Option<PathBuf>,
+    /// Why the VMM paused the VM on its own, until it's resumed.
+    #[serde(default)]
+    pub paused_reason: Option<PausedReason>,
+    /// The watched disks whose backing file is currently missing.
+    #[serde(default)]
+    pub lost_disks: Vec<PathBuf>,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -113,12 +203,213 @@ pub struct VmmPingResponse {
     pub version: String,
 }
 
+/// What this VMM binary supports, for the clients to find out before
+/// creating or changing a VM.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmmCapabilities {
+    pub version: String,
+    /// The device types a VM can be given.
+    pub devices: Vec<String>,
+    /// The changes which can be made to a booted VM.
+    pub hotplug: Vec<String>,
+    /// The snapshot and migration operations.
+    pub snapshot: Vec<String>,
+    pub max_vcpus: u8,
+    /// The maximum guest memory size, in bytes.
+    pub max_memory: u64,
+    /// The maximum number of PCI devices, 0 without PCI support.
+    pub max_pci_devices: u32,
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct VmResizeData {
     pub desired_vcpus: Option<u8>,
     pub desired_ram: Option<u64>,
 }
 
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmResizeDiskData {
+    /// Path of the disk image which has been resized.
+    pub path: PathBuf,
+}
+
+/// The properties of a network interface which can be changed at any time,
+/// the interface being identified by its `id`. A missing rate limiter lifts
+/// the limit.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmNetUpdate {
+    pub id: String,
+    #[serde(default)]
+    pub rx_rate_limiter_config: Option<RateLimiterConfig>,
+    #[serde(default)]
+    pub tx_rate_limiter_config: Option<RateLimiterConfig>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmDiskCheckpoint {
+    /// Path of the disk image, identifying the disk.
+    pub id: PathBuf,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DiskCheckpoint {
+    /// Identifier of the checkpoint, to get the blocks changed since.
+    pub checkpoint: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmDiskChangedBlocks {
+    /// Path of the disk image, identifying the disk.
+    pub id: PathBuf,
+    /// Checkpoint the changes are relative to.
+    pub since: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DiskRange {
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DiskChangedBlocks {
+    /// Granularity of the tracking, the ranges being aligned on it.
+    pub block_size: u64,
+    /// Ranges of the disk written since the checkpoint, in bytes.
+    pub ranges: Vec<DiskRange>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmSnapshotConfig {
+    /// Directory the snapshot is saved into.
+    pub destination: PathBuf,
+    /// The snapshot is meant to be cloned: the VM UUID is left out of the
+    /// saved configuration, so that each VM created from it gets a new one.
+    #[serde(default)]
+    pub clone: bool,
+    /// The pages the guest gave to the balloon aren't saved, reading back
+    /// as zeroes.
+    #[serde(default)]
+    pub exclude_free_pages: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmRestoreConfig {
+    /// Directory the snapshot has been saved into.
+    pub source: PathBuf,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmSnapshotExportConfig {
+    /// Directory the snapshot has been saved into.
+    pub snapshot: PathBuf,
+    /// Archive the snapshot is packed into.
+    pub path: PathBuf,
+    /// The disk images are packed along with the snapshot.
+    #[serde(default)]
+    pub disks: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmSnapshotImportConfig {
+    /// Archive the snapshot is unpacked from.
+    pub path: PathBuf,
+    /// Directory the snapshot is unpacked into.
+    pub destination: PathBuf,
+}
+
+/// The guest clock, e.g. saved with a VM snapshot.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VmClockData {
+    /// Guest clock, in nanoseconds.
+    pub clock: u64,
+}
+
+/// Host resources used by a vCPU.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VcpuCounters {
+    pub id: u8,
+    /// Host CPU time consumed by the vCPU, in nanoseconds.
+    pub cpu_time: u64,
+}
+
+/// What the memory overcommit controller did to the guest.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OvercommitCounters {
+    /// Number of times the guest memory got adjusted.
+    pub interventions: u64,
+    /// Memory currently held back from the guest through virtio-mem, in
+    /// bytes, the balloon being reported on its own.
+    pub unplugged: u64,
+}
+
+/// Host resources used by the VM, for schedulers to make overcommit
+/// decisions.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VmCounters {
+    pub vcpus: Vec<VcpuCounters>,
+    /// Estimated guest memory working set, in bytes, when enabled.
+    pub working_set: Option<u64>,
+    /// Memory given back by the guest through the balloon, in bytes.
+    pub balloon: Option<u64>,
+    /// Memory the guest reported as free, and whose backing got given back
+    /// to the host, since boot, in bytes.
+    #[serde(default)]
+    pub free_pages_reported: Option<u64>,
+    /// Memory the guest plugged through virtio-mem, in bytes.
+    #[serde(default)]
+    pub memory_plugged: Option<u64>,
+    /// Memory overcommit controller activity, when enabled.
+    #[serde(default)]
+    pub overcommit: Option<OvercommitCounters>,
+}
+
+/// Progress of a long running operation, e.g. a snapshot.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OperationInfo {
+    pub id: u64,
+    pub kind: String,
+    pub phase: OperationPhase,
+    pub bytes_total: u64,
+    pub bytes_transferred: u64,
+    pub error: Option<String>,
+}
+
+/// A volume is a disk managed independently of the VM, which can be
+/// attached to and detached from it.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VolumeConfig {
+    pub id: String,
+    pub disk: DiskConfig,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VolumeInfo {
+    pub id: String,
+    pub disk: DiskConfig,
+    pub attached: bool,
+}
+
+/// An interface is a network device managed independently of the VM, which
+/// can be attached to and detached from it.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct InterfaceConfig {
+    pub id: String,
+    pub net: NetConfig,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct InterfaceInfo {
+    pub id: String,
+    pub net: NetConfig,
+    pub attached: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ObjectId {
+    pub id: String,
+}
+
 pub enum ApiResponsePayload {
     /// No data is sent on the channel.
     Empty,
@@ -128,6 +419,30 @@ pub enum ApiResponsePayload {
 
     /// Vmm ping response
     VmmPing(VmmPingResponse),
+
+    /// VMM capabilities
+    VmmCapabilities(VmmCapabilities),
+
+    /// Volumes information
+    Volumes(Vec<VolumeInfo>),
+
+    /// Interfaces information
+    Interfaces(Vec<InterfaceInfo>),
+
+    /// Long running operation information
+    Operation(OperationInfo),
+
+    /// Guest clock
+    VmClock(VmClockData),
+
+    /// VM counters
+    VmCounters(VmCounters),
+
+    /// Disk checkpoint
+    DiskCheckpoint(DiskCheckpoint),
+
+    /// Blocks changed on a disk
+    DiskChangedBlocks(DiskChangedBlocks),
 }
 
 /// This is the response sent by the VMM API server through the mpsc channel.
@@ -158,6 +473,9 @@ pub enum ApiRequest {
     /// Request the VMM API server status
     VmmPing(Sender<ApiResponse>),
 
+    /// Request the VMM capabilities.
+    VmmCapabilities(Sender<ApiResponse>),
+
     /// Pause a VM.
     VmPause(Sender<ApiResponse>),
 
@@ -181,6 +499,88 @@ pub enum ApiRequest {
 
     //// Resuze the VMM
     VmResize(Arc<VmResizeData>, Sender<ApiResponse>),
+
+    /// Have the block device using a disk image follow its new size.
+    VmResizeDisk(Arc<VmResizeDiskData>, Sender<ApiResponse>),
+
+    /// Update the runtime tunable properties of a network interface.
+    VmUpdateNet(Arc<VmNetUpdate>, Sender<ApiResponse>),
+
+    /// Bring the VM to the desired configuration. The VM is created if
+    /// needed, its configuration is replaced if it is not booted yet, and
+    /// it is resized otherwise.
+    VmSpec(Arc<VmConfig>, Sender<ApiResponse>),
+
+    /// Take a snapshot of the VM, its memory being written out in the
+    /// background while it runs. The snapshot operation information is sent
+    /// back.
+    VmSnapshot(Arc<VmSnapshotConfig>, Sender<ApiResponse>),
+
+    /// Cancel the VM snapshot in progress, if any. The guest memory stops
+    /// being written out, and the incomplete snapshot is discarded.
+    VmSnapshotCancel(Sender<ApiResponse>),
+
+    /// Create the VM from a snapshot, paused in the state it was saved in.
+    VmRestore(Arc<VmRestoreConfig>, Sender<ApiResponse>),
+
+    /// Pack a VM snapshot into a single archive, in the background. The
+    /// export operation information is sent back.
+    VmSnapshotExport(Arc<VmSnapshotExportConfig>, Sender<ApiResponse>),
+
+    /// Unpack a VM snapshot archive, in the background. The import operation
+    /// information is sent back.
+    VmSnapshotImport(Arc<VmSnapshotImportConfig>, Sender<ApiResponse>),
+
+    /// Request the guest clock.
+    VmClock(Sender<ApiResponse>),
+
+    /// Request the host resources used by the VM.
+    VmCounters(Sender<ApiResponse>),
+
+    /// Take a checkpoint of the blocks written to a disk.
+    VmDiskCheckpoint(Arc<VmDiskCheckpoint>, Sender<ApiResponse>),
+
+    /// Request the blocks written to a disk since a checkpoint.
+    VmDiskChangedBlocks(Arc<VmDiskChangedBlocks>, Sender<ApiResponse>),
+
+    /// Set the guest clock, e.g. to account for the time it was not running.
+    VmSetClock(Arc<VmClockData>, Sender<ApiResponse>),
+
+    /// Request the information about a long running operation.
+    Operation(u64, Sender<ApiResponse>),
+
+    /// Cancel a long running operation.
+    OperationCancel(u64, Sender<ApiResponse>),
+
+    /// Create a volume, which is not attached to the VM.
+    VolumeCreate(Arc<VolumeConfig>, Sender<ApiResponse>),
+
+    /// Delete a volume. It must not be attached to the VM.
+    VolumeDelete(Arc<ObjectId>, Sender<ApiResponse>),
+
+    /// Request the volumes information.
+    Volumes(Sender<ApiResponse>),
+
+    /// Attach a volume to the VM, as one of its disks.
+    VmAttachVolume(Arc<ObjectId>, Sender<ApiResponse>),
+
+    /// Detach a volume from the VM.
+    VmDetachVolume(Arc<ObjectId>, Sender<ApiResponse>),
+
+    /// Create a network interface, which is not attached to the VM.
+    InterfaceCreate(Arc<InterfaceConfig>, Sender<ApiResponse>),
+
+    /// Delete a network interface. It must not be attached to the VM.
+    InterfaceDelete(Arc<ObjectId>, Sender<ApiResponse>),
+
+    /// Request the network interfaces information.
+    Interfaces(Sender<ApiResponse>),
+
+    /// Attach a network interface to the VM.
+    VmAttachInterface(Arc<ObjectId>, Sender<ApiResponse>),
+
+    /// Detach a network interface from the VM.
+    VmDetachInterface(Arc<ObjectId>, Sender<ApiResponse>),
 }
 
 pub fn vm_create(
@@ -222,6 +622,9 @@ pub enum VmAction {
 
     /// Resume a VM
     Resume,
+
+    /// Cancel a VM snapshot
+    SnapshotCancel,
 }
 
 fn vm_action(api_evt: EventFd, api_sender: Sender<ApiRequest>, action: VmAction) -> ApiResult<()> {
@@ -234,6 +637,7 @@ fn vm_action(api_evt: EventFd, api_sender: Sender<ApiRequest>, action: VmAction)
         VmAction::Reboot => ApiRequest::VmReboot(response_sender),
         VmAction::Pause => ApiRequest::VmPause(response_sender),
         VmAction::Resume => ApiRequest::VmResume(response_sender),
+        VmAction::SnapshotCancel => ApiRequest::VmSnapshotCancel(response_sender),
     };
 
     // Send the VM request.
@@ -269,6 +673,10 @@ pub fn vm_resume(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<
     vm_action(api_evt, api_sender, VmAction::Resume)
 }
 
+pub fn vm_snapshot_cancel(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<()> {
+    vm_action(api_evt, api_sender, VmAction::SnapshotCancel)
+}
+
 pub fn vm_info(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<VmInfo> {
     let (response_sender, response_receiver) = channel();
 
@@ -302,6 +710,25 @@ pub fn vmm_ping(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<V
     }
 }
 
+pub fn vmm_capabilities(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+) -> ApiResult<VmmCapabilities> {
+    let (response_sender, response_receiver) = channel();
+
+    api_sender
+        .send(ApiRequest::VmmCapabilities(response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let capabilities = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match capabilities {
+        ApiResponsePayload::VmmCapabilities(capabilities) => Ok(capabilities),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
 pub fn vmm_shutdown(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<()> {
     let (response_sender, response_receiver) = channel();
 
@@ -333,3 +760,385 @@ pub fn vm_resize(
 
     Ok(())
 }
+
+pub fn vm_resize_disk(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmResizeDiskData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM disk resize request.
+    api_sender
+        .send(ApiRequest::VmResizeDisk(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_update_net(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmNetUpdate>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the network interface update request.
+    api_sender
+        .send(ApiRequest::VmUpdateNet(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_spec(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    config: Arc<VmConfig>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM spec request.
+    api_sender
+        .send(ApiRequest::VmSpec(config, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_snapshot(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSnapshotConfig>,
+) -> ApiResult<OperationInfo> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM snapshot request.
+    api_sender
+        .send(ApiRequest::VmSnapshot(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let operation = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match operation {
+        ApiResponsePayload::Operation(info) => Ok(info),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn vm_restore(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmRestoreConfig>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM restore request.
+    api_sender
+        .send(ApiRequest::VmRestore(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_snapshot_export(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSnapshotExportConfig>,
+) -> ApiResult<OperationInfo> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM snapshot export request.
+    api_sender
+        .send(ApiRequest::VmSnapshotExport(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let operation = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match operation {
+        ApiResponsePayload::Operation(info) => Ok(info),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn vm_snapshot_import(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSnapshotImportConfig>,
+) -> ApiResult<OperationInfo> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM snapshot import request.
+    api_sender
+        .send(ApiRequest::VmSnapshotImport(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let operation = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match operation {
+        ApiResponsePayload::Operation(info) => Ok(info),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn vm_clock(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<VmClockData> {
+    let (response_sender, response_receiver) = channel();
+
+    api_sender
+        .send(ApiRequest::VmClock(response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let clock = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match clock {
+        ApiResponsePayload::VmClock(clock) => Ok(clock),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn vm_counters(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<VmCounters> {
+    let (response_sender, response_receiver) = channel();
+
+    api_sender
+        .send(ApiRequest::VmCounters(response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let counters = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match counters {
+        ApiResponsePayload::VmCounters(counters) => Ok(counters),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn vm_disk_checkpoint(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmDiskCheckpoint>,
+) -> ApiResult<DiskCheckpoint> {
+    let (response_sender, response_receiver) = channel();
+
+    api_sender
+        .send(ApiRequest::VmDiskCheckpoint(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let checkpoint = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match checkpoint {
+        ApiResponsePayload::DiskCheckpoint(checkpoint) => Ok(checkpoint),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn vm_disk_changed_blocks(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmDiskChangedBlocks>,
+) -> ApiResult<DiskChangedBlocks> {
+    let (response_sender, response_receiver) = channel();
+
+    api_sender
+        .send(ApiRequest::VmDiskChangedBlocks(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let changed_blocks = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match changed_blocks {
+        ApiResponsePayload::DiskChangedBlocks(changed_blocks) => Ok(changed_blocks),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn vm_set_clock(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmClockData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM clock request.
+    api_sender
+        .send(ApiRequest::VmSetClock(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn operation_info(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    id: u64,
+) -> ApiResult<OperationInfo> {
+    let (response_sender, response_receiver) = channel();
+
+    api_sender
+        .send(ApiRequest::Operation(id, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let operation = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match operation {
+        ApiResponsePayload::Operation(info) => Ok(info),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn operation_cancel(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    id: u64,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the operation cancellation request.
+    api_sender
+        .send(ApiRequest::OperationCancel(id, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn volume_create(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    config: Arc<VolumeConfig>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the volume creation request.
+    api_sender
+        .send(ApiRequest::VolumeCreate(config, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn volumes(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<Vec<VolumeInfo>> {
+    let (response_sender, response_receiver) = channel();
+
+    api_sender
+        .send(ApiRequest::Volumes(response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let volumes = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match volumes {
+        ApiResponsePayload::Volumes(volumes) => Ok(volumes),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn interface_create(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    config: Arc<InterfaceConfig>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the interface creation request.
+    api_sender
+        .send(ApiRequest::InterfaceCreate(config, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn interfaces(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+) -> ApiResult<Vec<InterfaceInfo>> {
+    let (response_sender, response_receiver) = channel();
+
+    api_sender
+        .send(ApiRequest::Interfaces(response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let interfaces = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match interfaces {
+        ApiResponsePayload::Interfaces(interfaces) => Ok(interfaces),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+/// Represents an action on a volume or a network interface, identified by
+/// its id.
+pub enum ObjectAction {
+    /// Delete a volume
+    DeleteVolume,
+
+    /// Attach a volume to the VM
+    AttachVolume,
+
+    /// Detach a volume from the VM
+    DetachVolume,
+
+    /// Delete a network interface
+    DeleteInterface,
+
+    /// Attach a network interface to the VM
+    AttachInterface,
+
+    /// Detach a network interface from the VM
+    DetachInterface,
+}
+
+pub fn object_action(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    action: &ObjectAction,
+    id: Arc<ObjectId>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    let request = match action {
+        ObjectAction::DeleteVolume => ApiRequest::VolumeDelete(id, response_sender),
+        ObjectAction::AttachVolume => ApiRequest::VmAttachVolume(id, response_sender),
+        ObjectAction::DetachVolume => ApiRequest::VmDetachVolume(id, response_sender),
+        ObjectAction::DeleteInterface => ApiRequest::InterfaceDelete(id, response_sender),
+        ObjectAction::AttachInterface => ApiRequest::VmAttachInterface(id, response_sender),
+        ObjectAction::DetachInterface => ApiRequest::VmDetachInterface(id, response_sender),
+    };
+
+    // Send the object request.
+    api_sender.send(request).map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
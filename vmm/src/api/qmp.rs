@@ -0,0 +1,256 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! A QMP (QEMU Machine Protocol) listener, translating a subset of the QEMU
+//! lifecycle commands onto API requests, so that the tooling managing QEMU
+//! guests can manage the VM too:
+//!
+//! - `query-status`: the VM state.
+//! - `stop` and `cont`: pause and resume the VM, `cont` boots a VM which
+//!   hasn't been booted yet.
+//! - `system_powerdown`: shut the VM down.
+//! - `quit`: shut the VMM down.
+//!
+//! No asynchronous event is sent.
+
+use crate::api::{
+    vm_boot, vm_info, vm_pause, vm_resume, vm_shutdown, vmm_shutdown, ApiError, ApiRequest,
+};
+use crate::vm::VmState;
+use crate::{Error, Result};
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::result;
+use std::sync::mpsc::Sender;
+use std::thread;
+use vmm_sys_util::eventfd::EventFd;
+
+// QEMU release whose protocol the commands follow.
+const QEMU_VERSION_MAJOR: u32 = 5;
+const QEMU_VERSION_MINOR: u32 = 0;
+
+#[derive(Debug)]
+enum QmpError {
+    /// The command doesn't exist, or can't be run yet.
+    CommandNotFound(String),
+    /// The command failed.
+    GenericError(String),
+}
+
+impl QmpError {
+    fn to_response(&self) -> Value {
+        let (class, desc) = match self {
+            QmpError::CommandNotFound(desc) => ("CommandNotFound", desc),
+            QmpError::GenericError(desc) => ("GenericError", desc),
+        };
+        json!({ "error": { "class": class, "desc": desc } })
+    }
+}
+
+impl From<ApiError> for QmpError {
+    fn from(e: ApiError) -> Self {
+        QmpError::GenericError(format!("{:?}", e))
+    }
+}
+
+// Runs a command, other than quit, once the capabilities are negotiated.
+fn execute(
+    command: &str,
+    negotiated: &mut bool,
+    api_notifier: &EventFd,
+    api_sender: &Sender<ApiRequest>,
+) -> result::Result<Value, QmpError> {
+    if !*negotiated {
+        if command != "qmp_capabilities" {
+            return Err(QmpError::CommandNotFound(String::from(
+                "Expecting capabilities negotiation with 'qmp_capabilities'",
+            )));
+        }
+        *negotiated = true;
+        return Ok(json!({}));
+    }
+
+    let notifier = || {
+        api_notifier
+            .try_clone()
+            .map_err(|e| QmpError::GenericError(e.to_string()))
+    };
+    let sender = api_sender.clone();
+
+    match command {
+        "qmp_capabilities" => Err(QmpError::CommandNotFound(String::from(
+            "Capabilities negotiation is already complete, command ignored",
+        ))),
+        "query-status" => {
+            let info = vm_info(notifier()?, sender)?;
+            let (status, running) = match info.state {
+                VmState::Created => ("prelaunch", false),
+                VmState::Running => ("running", true),
+                // Paused on its own because of a disk.
+                VmState::Paused if info.paused_reason.is_some() => ("io-error", false),
+                VmState::Paused => ("paused", false),
+                VmState::Shutdown => ("shutdown", false),
+            };
+            Ok(json!({ "status": status, "running": running, "singlestep": false }))
+        }
+        "stop" => {
+            vm_pause(notifier()?, sender)?;
+            Ok(json!({}))
+        }
+        "cont" => {
+            if vm_info(notifier()?, sender.clone())?.state == VmState::Created {
+                vm_boot(notifier()?, sender)?;
+            } else {
+                vm_resume(notifier()?, sender)?;
+            }
+            Ok(json!({}))
+        }
+        "system_powerdown" => {
+            vm_shutdown(notifier()?, sender)?;
+            Ok(json!({}))
+        }
+        _ => Err(QmpError::CommandNotFound(format!(
+            "The command {} has not been found",
+            command
+        ))),
+    }
+}
+
+fn send(writer: &mut UnixStream, message: &Value) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, message)?;
+    writer.write_all(b"\r\n")
+}
+
+fn handle_connection(
+    stream: UnixStream,
+    vmm_version: &str,
+    api_notifier: &EventFd,
+    api_sender: &Sender<ApiRequest>,
+) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    send(
+        &mut writer,
+        &json!({
+            "QMP": {
+                "version": {
+                    "qemu": {
+                        "major": QEMU_VERSION_MAJOR,
+                        "minor": QEMU_VERSION_MINOR,
+                        "micro": 0,
+                    },
+                    "package": format!("cloud-hypervisor-{}", vmm_version),
+                },
+                "capabilities": [],
+            }
+        }),
+    )?;
+
+    let mut negotiated = false;
+    for message in serde_json::Deserializer::from_reader(stream).into_iter::<Value>() {
+        let message = match message {
+            Ok(message) => message,
+            Err(e) if e.is_io() => return Err(e.into()),
+            Err(e) => {
+                // The stream can't be resynchronized, the client has to
+                // connect again.
+                let error = QmpError::GenericError(format!("JSON parse error, {}", e));
+                return send(&mut writer, &error.to_response());
+            }
+        };
+
+        let mut quit = false;
+        let mut response = match message.get("execute").and_then(Value::as_str) {
+            None => QmpError::GenericError(String::from("QMP input lacks member 'execute'"))
+                .to_response(),
+            Some("quit") if negotiated => {
+                quit = true;
+                json!({ "return": {} })
+            }
+            Some(command) => match execute(command, &mut negotiated, api_notifier, api_sender) {
+                Ok(ret) => json!({ "return": ret }),
+                Err(e) => e.to_response(),
+            },
+        };
+        if let Some(id) = message.get("id") {
+            response["id"] = id.clone();
+        }
+        send(&mut writer, &response)?;
+
+        if quit {
+            if let Err(e) = vmm_shutdown(api_notifier.try_clone()?, api_sender.clone()) {
+                error!("QMP quit command failed: {:?}", e);
+            }
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
+
+/// Starts the QMP server thread, listening on the UNIX domain socket `path`.
+pub fn start_qmp_thread(
+    path: &str,
+    vmm_version: String,
+    api_notifier: EventFd,
+    api_sender: Sender<ApiRequest>,
+) -> Result<thread::JoinHandle<Result<()>>> {
+    std::fs::remove_file(path).unwrap_or_default();
+    let listener = UnixListener::bind(path).map_err(Error::Bind)?;
+
+    thread::Builder::new()
+        .name("qmp-server".to_string())
+        .spawn(move || {
+            // As with the QEMU monitor, one client is served at a time.
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) =
+                            handle_connection(stream, &vmm_version, &api_notifier, &api_sender)
+                        {
+                            error!("QMP server error on connection: {}", e);
+                        }
+                    }
+                    Err(e) => error!("QMP server error on incoming connection: {}", e),
+                }
+            }
+
+            Ok(())
+        })
+        .map_err(Error::QmpThreadSpawn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_capabilities_negotiation() {
+        let api_notifier = EventFd::new(0).unwrap();
+        let (api_sender, _api_receiver) = channel();
+        let mut negotiated = false;
+
+        match execute("query-status", &mut negotiated, &api_notifier, &api_sender) {
+            Err(QmpError::CommandNotFound(_)) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert_eq!(
+            execute(
+                "qmp_capabilities",
+                &mut negotiated,
+                &api_notifier,
+                &api_sender
+            )
+            .unwrap(),
+            json!({})
+        );
+        assert!(negotiated);
+        match execute("device_add", &mut negotiated, &api_notifier, &api_sender) {
+            Err(QmpError::CommandNotFound(_)) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,250 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::config::CgroupConfig;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::result;
+use std::sync::Mutex;
+use vm_virtio::transport::VirtioActivationContext;
+
+// Sub-hierarchies the VMM threads are spread across.
+const VCPUS_CGROUP: &str = "vcpus";
+const VMM_CGROUP: &str = "vmm";
+
+/// Errors associated with the VM cgroups.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot create a cgroup.
+    Create(io::Error),
+
+    /// Cannot write to a cgroup interface file.
+    Write(PathBuf, io::Error),
+
+    /// Cannot list the VMM threads.
+    ListThreads(io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+fn write_interface_file(path: PathBuf, value: &[u8]) -> Result<()> {
+    fs::write(&path, value).map_err(|e| Error::Write(path, e))
+}
+
+fn gettid() -> libc::pid_t {
+    // Safe because this syscall can't fail.
+    unsafe { libc::syscall(libc::SYS_gettid) as libc::pid_t }
+}
+
+/// The cgroup v2 hierarchy the VMM confines itself to. The vCPU threads and
+/// the VMM threads, including the device I/O threads, live in two threaded
+/// cgroups below it, with their own cpu.max and cpuset.cpus limits.
+///
+/// Threads are placed when spawned: they inherit the cgroup of the thread
+/// spawning them, which is the VMM one, and each vCPU thread moves itself
+/// to the vCPUs cgroup before running the guest.
+///
+/// The hierarchy is not removed when the VM goes away, which lets it be
+/// reused across reboots. Removing it is up to whoever launched the VMM.
+pub struct VmCgroups {
+    path: PathBuf,
+    // Ids of the threads running a vCPU.
+    vcpu_threads: Mutex<HashSet<libc::pid_t>>,
+}
+
+impl VmCgroups {
+    pub fn new(config: &CgroupConfig) -> Result<Self> {
+        let cgroups = VmCgroups {
+            path: config.path.clone(),
+            vcpu_threads: Mutex::new(HashSet::new()),
+        };
+
+        for group in &[VCPUS_CGROUP, VMM_CGROUP] {
+            let path = cgroups.path.join(group);
+            fs::create_dir_all(&path).map_err(Error::Create)?;
+            // Threads of a single process can only be spread across
+            // threaded cgroups.
+            write_interface_file(path.join("cgroup.type"), b"threaded")?;
+        }
+
+        // The whole process moves to the root of the threaded subtree,
+        // which is the only place its threads can be moved from.
+        write_interface_file(
+            cgroups.path.join("cgroup.procs"),
+            std::process::id().to_string().as_bytes(),
+        )?;
+
+        let cpu_limited = config.vcpus_cpu_max.is_some() || config.vmm_cpu_max.is_some();
+        if cpu_limited {
+            write_interface_file(cgroups.path.join("cgroup.subtree_control"), b"+cpu")?;
+        }
+        let cpuset_limited = config.vcpus_cpuset.is_some() || config.vmm_cpuset.is_some();
+        if cpuset_limited {
+            write_interface_file(cgroups.path.join("cgroup.subtree_control"), b"+cpuset")?;
+        }
+
+        let limits = [
+            (VCPUS_CGROUP, config.vcpus_cpu_max, &config.vcpus_cpuset),
+            (VMM_CGROUP, config.vmm_cpu_max, &config.vmm_cpuset),
+        ];
+        for (group, cpu_max, cpuset) in limits.iter() {
+            let path = cgroups.path.join(group);
+            if let Some(quota) = cpu_max {
+                let cpu_max = format!("{} {}", quota, config.cpu_period);
+                write_interface_file(path.join("cpu.max"), cpu_max.as_bytes())?;
+            }
+            if let Some(cpus) = cpuset {
+                write_interface_file(path.join("cpuset.cpus"), cpus.as_bytes())?;
+            }
+        }
+
+        // No vCPU runs yet, every thread so far is a VMM thread, and the ones
+        // spawned from them inherit the VMM cgroup.
+        for entry in fs::read_dir("/proc/self/task").map_err(Error::ListThreads)? {
+            let entry = entry.map_err(Error::ListThreads)?;
+            let path = cgroups.path.join(VMM_CGROUP).join("cgroup.threads");
+            if let Err(e) = fs::write(&path, entry.file_name().as_bytes()) {
+                // The thread may have exited since the directory was read.
+                if e.raw_os_error() != Some(libc::ESRCH) {
+                    return Err(Error::Write(path, e));
+                }
+            }
+        }
+
+        Ok(cgroups)
+    }
+
+    fn place_thread(&self, group: &str, tid: libc::pid_t) -> Result<()> {
+        write_interface_file(
+            self.path.join(group).join("cgroup.threads"),
+            tid.to_string().as_bytes(),
+        )
+    }
+
+    /// Move the calling thread, about to run a vCPU, to the vCPUs cgroup.
+    pub fn add_vcpu_thread(&self) -> Result<()> {
+        let tid = gettid();
+        self.place_thread(VCPUS_CGROUP, tid)?;
+        self.vcpu_threads.lock().unwrap().insert(tid);
+        Ok(())
+    }
+
+    /// Forget about the calling vCPU thread, which is exiting.
+    pub fn remove_vcpu_thread(&self) {
+        self.vcpu_threads.lock().unwrap().remove(&gettid());
+    }
+
+    fn move_vcpu_thread(&self, group: &str) {
+        let tid = gettid();
+        if !self.vcpu_threads.lock().unwrap().contains(&tid) {
+            return;
+        }
+        if let Err(e) = self.place_thread(group, tid) {
+            error!("Failed moving vCPU thread {} to its cgroup: {:?}", tid, e);
+        }
+    }
+}
+
+// The device threads are spawned from the vCPU thread activating the device,
+// which stays in the VMM cgroup meanwhile for them not to end up in the vCPUs
+// one.
+impl VirtioActivationContext for VmCgroups {
+    fn enter(&self) {
+        self.move_vcpu_thread(VMM_CGROUP);
+    }
+
+    fn exit(&self) {
+        self.move_vcpu_thread(VCPUS_CGROUP);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::thread;
+
+    fn read(path: &Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    fn new_cgroups(dir: &Path) -> VmCgroups {
+        let config = CgroupConfig::parse(&format!(
+            "path={},vcpus_cpu_max=200000,vcpus_cpuset=2-5:8,vmm_cpuset=0:1",
+            dir.display()
+        ))
+        .unwrap();
+        VmCgroups::new(&config).unwrap()
+    }
+
+    #[test]
+    fn test_cgroups_new() {
+        let dir = tempfile::tempdir().unwrap();
+        new_cgroups(dir.path());
+
+        assert_eq!(
+            read(&dir.path().join("cgroup.procs")),
+            std::process::id().to_string()
+        );
+        for group in &[VCPUS_CGROUP, VMM_CGROUP] {
+            assert_eq!(
+                read(&dir.path().join(group).join("cgroup.type")),
+                "threaded"
+            );
+        }
+        assert_eq!(read(&dir.path().join("vcpus/cpu.max")), "200000 100000");
+        assert_eq!(read(&dir.path().join("vcpus/cpuset.cpus")), "2-5,8");
+        assert_eq!(read(&dir.path().join("vmm/cpuset.cpus")), "0,1");
+        assert!(!dir.path().join("vmm/cpu.max").exists());
+
+        // The existing threads are all VMM threads.
+        assert!(read(&dir.path().join("vmm/cgroup.threads"))
+            .parse::<libc::pid_t>()
+            .is_ok());
+        assert!(!dir.path().join("vcpus/cgroup.threads").exists());
+    }
+
+    #[test]
+    fn test_vcpu_thread_placement() {
+        let dir = tempfile::tempdir().unwrap();
+        let cgroups = std::sync::Arc::new(new_cgroups(dir.path()));
+        let vcpus_threads = dir.path().join("vcpus/cgroup.threads");
+        let vmm_threads = dir.path().join("vmm/cgroup.threads");
+
+        // Activations from a VMM thread leave it where it is.
+        let vmm_placement = read(&vmm_threads);
+        cgroups.enter();
+        cgroups.exit();
+        assert_eq!(read(&vmm_threads), vmm_placement);
+        assert!(!vcpus_threads.exists());
+
+        let vcpu_cgroups = cgroups.clone();
+        let (vcpus_threads_clone, vmm_threads_clone) = (vcpus_threads.clone(), vmm_threads.clone());
+        thread::spawn(move || {
+            let tid = gettid().to_string();
+
+            vcpu_cgroups.add_vcpu_thread().unwrap();
+            assert_eq!(read(&vcpus_threads_clone), tid);
+
+            // The vCPU thread activates a device from the VMM cgroup.
+            vcpu_cgroups.enter();
+            assert_eq!(read(&vmm_threads_clone), tid);
+            fs::remove_file(&vcpus_threads_clone).unwrap();
+            vcpu_cgroups.exit();
+            assert_eq!(read(&vcpus_threads_clone), tid);
+
+            // An exited vCPU thread isn't a vCPU thread anymore.
+            vcpu_cgroups.remove_vcpu_thread();
+            fs::remove_file(&vcpus_threads_clone).unwrap();
+            vcpu_cgroups.enter();
+            vcpu_cgroups.exit();
+            assert!(!vcpus_threads_clone.exists());
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -13,6 +13,7 @@ use std::net::AddrParseError;
 use std::net::Ipv4Addr;
 use std::path::PathBuf;
 use std::result;
+use uuid::Uuid;
 
 pub const DEFAULT_VCPUS: u8 = 1;
 pub const DEFAULT_MEMORY_MB: u64 = 512;
@@ -21,6 +22,19 @@ pub const DEFAULT_NUM_QUEUES_VUNET: usize = 2;
 pub const DEFAULT_QUEUE_SIZE_VUNET: u16 = 256;
 pub const DEFAULT_NUM_QUEUES_VUBLK: usize = 1;
 pub const DEFAULT_QUEUE_SIZE_VUBLK: u16 = 128;
+pub const DEFAULT_NUM_QUEUES_SCSI: usize = 1;
+pub const DEFAULT_QUEUE_SIZE_SCSI: u16 = 128;
+pub const DEFAULT_QUEUE_SIZE_CONSOLE: u16 = 256;
+pub const MAX_QUEUE_SIZE: u16 = 32768;
+pub const DEFAULT_CGROUP_CPU_PERIOD_US: u64 = 100_000;
+pub const DEFAULT_TARGET_FREE_HOST_PERCENT: u8 = 10;
+pub const DEFAULT_RATE_LIMITER_REFILL_TIME_MS: u64 = 1000;
+// The smallest MTU IPv4 allows, and the largest one of a tap, the Ethernet
+// header included in the 64KiB frames.
+pub const MIN_NET_MTU: u16 = 68;
+pub const MAX_NET_MTU: u16 = 65521;
+// The single PCI bus has 32 devices, device 0 being the host bridge.
+pub const MAX_PCI_DEVICES: usize = 31;
 
 /// Errors associated with VM configuration parameters.
 #[derive(Debug)]
@@ -31,8 +45,34 @@ pub enum Error {
     ParseCpusUnknownParam,
     /// Max is less than boot
     ParseCpusMaxLowerThanBoot,
+    /// The VM must boot with at least one vCPU
+    ParseCpusZeroBoot,
+    /// Failed parsing CPUID feature, expecting +<feature> or -<feature>
+    ParseCpusFeaturesParam(String),
     /// Failed parsing memory file parameter.
     ParseMemoryFileParam,
+    /// Failed parsing memory zone size parameter.
+    ParseMemoryZoneSizeParam,
+    /// Failed parsing memory zone file parameter.
+    ParseMemoryZoneFileParam,
+    /// Failed parsing memory zone host NUMA node parameter.
+    ParseMemoryZoneHostNumaNodeParam(std::num::ParseIntError),
+    /// Memory zone host NUMA node is out of range.
+    InvalidMemoryZoneHostNumaNode(u32),
+    /// Memory zone backed by a file can't ask for hugepages, which come
+    /// from the file being on a hugetlbfs mount.
+    InvalidMemoryZoneHugepagesWithFile,
+    /// Memory size doesn't match the size of the memory zones.
+    InvalidMemoryZonesSize(u64, u64),
+    /// Failed parsing memory hotplug method parameter.
+    ParseMemoryHotplugMethodParam,
+    /// The virtio-mem region must be made of whole blocks.
+    InvalidVirtioMemHotplugSize(u64),
+    /// The hotplugged memory must fit in the virtio-mem region, by whole
+    /// blocks.
+    InvalidMemoryHotpluggedSize(u64),
+    /// Failed parsing SGX EPC section size parameter.
+    ParseSgxEpcSizeParam,
     /// Failed parsing kernel parameters.
     ParseKernelParams,
     /// Failed parsing kernel command line parameters.
@@ -47,6 +87,50 @@ pub enum Error {
     ParseDiskVhostParam(std::str::ParseBoolError),
     /// Failed parsing disk wce parameter.
     ParseDiskWceParam(std::str::ParseBoolError),
+    /// NVMe disks can't be vhost-user ones, nor be attached to the IOMMU.
+    InvalidNvmeDisk,
+    /// Failed parsing rate limiter parameters.
+    ParseRateLimiterParam(std::num::ParseIntError),
+    /// A rate limiter token bucket needs a size.
+    MissingRateLimiterSize,
+    /// Only the virtio-blk disks emulated by the VMM can be rate limited.
+    InvalidRateLimitedDisk,
+    /// Only the virtio-blk disks backed by a local image can have an overlay.
+    InvalidOverlayDisk,
+    /// CD-ROM images are exposed through virtio-blk devices emulated by the
+    /// VMM, and can't have an overlay.
+    InvalidCdromDisk,
+    /// The firmware boots from a virtio-blk disk.
+    InvalidBootDisk,
+    /// Only one disk can be the boot one.
+    MultipleBootDisks,
+    /// A disk serial number is at most 20 bytes long, and only applies to
+    /// the virtio-blk disks emulated by the VMM.
+    InvalidDiskSerial,
+    /// Failed parsing the disk I/O error policy.
+    ParseDiskErrorPolicyParam,
+    /// Only the virtio-blk disks emulated by the VMM can stop on I/O errors.
+    InvalidDiskErrorPolicy,
+    /// Only the virtio-blk disks emulated by the VMM can track the changed
+    /// blocks.
+    InvalidTrackChangesDisk,
+    /// Failed parsing a PCI address.
+    ParsePciAddress(String),
+    /// There is a single PCI segment and bus, with no multi-function
+    /// device, and device 0 is the host bridge.
+    InvalidPciAddress(PciAddress),
+    /// Two devices have the same PCI address.
+    DuplicatePciAddress(PciAddress),
+    /// Two disks, or two network interfaces, have the same id.
+    DuplicateDeviceId(String),
+    /// The VM has more PCI devices than the PCI bus can hold.
+    TooManyPciDevices(usize),
+    /// Failed parsing SCSI controller LUNs parameter.
+    ParseScsiLunsParam,
+    /// Failed parsing SCSI controller number of queues.
+    ParseScsiNumQueuesParam(std::num::ParseIntError),
+    /// Failed parsing SCSI controller queue size.
+    ParseScsiQueueSizeParam(std::num::ParseIntError),
     /// Failed parsing random number generator parameters.
     ParseRngParams,
     /// Failed parsing network ip parameter.
@@ -55,18 +139,60 @@ pub enum Error {
     ParseNetMaskParam(AddrParseError),
     /// Failed parsing network mac parameter.
     ParseNetMacParam(io::Error),
+    /// Failed parsing network host_mac parameter.
+    ParseNetHostMacParam(io::Error),
+    /// Failed parsing network fd parameter.
+    ParseNetFdParam(std::num::ParseIntError),
     /// Failed parsing network queue number parameter.
     ParseNetNumQueuesParam(std::num::ParseIntError),
+    /// The network queue number isn't a non zero even number.
+    InvalidNetNumQueues(usize),
     /// Failed parsing network queue size parameter.
     ParseNetQueueSizeParam(std::num::ParseIntError),
     /// Failed to parse vhost parameters
     ParseNetVhostParam(std::str::ParseBoolError),
+    /// Transitional network devices can't be vhost-user ones, nor be
+    /// attached to the IOMMU.
+    InvalidTransitionalNet,
+    /// Standby network devices can't be vhost-user ones.
+    InvalidStandbyNet,
     /// Need a vhost socket
     ParseNetVhostSocketRequired,
+    /// AF_XDP network devices need the path of the pinned XSKMAP.
+    ParseNetXdpMapRequired,
+    /// AF_XDP network devices can't use a tap nor be vhost-user ones.
+    InvalidXdpNet,
+    /// Network devices using tap fds can't name a tap, use AF_XDP, nor be
+    /// vhost-user ones.
+    InvalidFdNet,
+    /// The number of tap fds, one per queue pair, doesn't match the number
+    /// of queues.
+    InvalidNetFdsNumQueues(usize, usize),
+    /// Only network devices on top of a tap have a host MAC address.
+    InvalidHostMacNet,
+    /// The vhost-user network devices, whose frames aren't processed by the
+    /// VMM, can't be rate limited.
+    InvalidRateLimitedNet,
+    /// Failed parsing network MTU parameter.
+    ParseNetMtuParam(std::num::ParseIntError),
+    /// The MTU is out of the range a tap supports.
+    InvalidNetMtu(u16),
+    /// Only network devices on top of a tap have a configurable MTU.
+    InvalidMtuNet,
+    /// Only network devices on top of a tap have configurable offloads.
+    InvalidOffloadNet,
     /// Failed parsing fs tag parameter.
     ParseFsTagParam,
     /// Failed parsing fs socket path parameter.
     ParseFsSockParam,
+    /// A fs device is served either by a vhost-user backend or by the VMM.
+    InvalidFsBackend,
+    /// Spawning virtiofsd requires both its socket and the shared directory.
+    InvalidFsSpawn,
+    /// Failed parsing fs spawn parameter.
+    ParseFsSpawn,
+    /// Failed parsing fs sandbox parameter.
+    ParseFsSandbox,
     /// Failed parsing fs number of queues parameter.
     ParseFsNumQueuesParam(std::num::ParseIntError),
     /// Failed parsing fs queue size parameter.
@@ -81,8 +207,28 @@ pub enum Error {
     ParseSizeParam(std::num::ParseIntError),
     /// Failed parsing console parameter.
     ParseConsoleParam,
+    /// Failed parsing console queue size parameter.
+    ParseConsoleQueueSizeParam(std::num::ParseIntError),
+    /// Failed parsing console port name parameter.
+    ParseConsolePortNameParam,
+    /// A console port needs either a file or a socket.
+    ParseConsolePortBackendParam,
+    /// Virtio queue sizes must be a power of two, no larger than 32768.
+    InvalidQueueSize(u16),
     /// Both console and serial are tty.
     ParseTTYParam,
+    /// Failed parsing SR-IOV VF PF parameter.
+    ParseSriovVfPfParam,
+    /// Failed parsing SR-IOV VF index parameter.
+    ParseSriovVfIndexParam(std::num::ParseIntError),
+    /// Failed parsing SR-IOV VF mac parameter.
+    ParseSriovVfMacParam(io::Error),
+    /// Failed parsing SR-IOV VF VLAN parameter.
+    ParseSriovVfVlanParam(std::num::ParseIntError),
+    /// A failover SR-IOV VF needs a MAC address to be paired with.
+    ParseSriovVfFailoverWithoutMac,
+    /// No standby network device has the MAC address of this failover VF.
+    FailoverWithoutStandbyNet(String),
     /// Failed parsing vhost-user-net mac parameter.
     ParseVuNetMacParam(io::Error),
     /// Failed parsing vhost-user sock parameter.
@@ -99,6 +245,46 @@ pub enum Error {
     ParseVsockCidParam(std::num::ParseIntError),
     /// Failed parsing vsock socket path parameter.
     ParseVsockSockParam,
+    /// Failed parsing vDPA device path parameter.
+    ParseVdpaPathParam,
+    /// Failed parsing vDPA number of queues parameter.
+    ParseVdpaNumQueuesParam(std::num::ParseIntError),
+    /// A vDPA device needs at least one queue.
+    InvalidVdpaNumQueues,
+    /// Failed parsing cgroup path parameter.
+    ParseCgroupPathParam,
+    /// Failed parsing cgroup CPU bandwidth parameter.
+    ParseCgroupCpuMaxParam(std::num::ParseIntError),
+    /// Failed parsing cgroup CPU bandwidth period parameter.
+    ParseCgroupCpuPeriodParam(std::num::ParseIntError),
+    /// Failed parsing device reset method parameter.
+    ParseDeviceResetParam,
+    /// Failed parsing SEV policy parameter.
+    ParseSevPolicyParam(std::num::ParseIntError),
+    /// Failed parsing the VM UUID.
+    ParseUuid(uuid::Error),
+    /// Failed parsing balloon size parameter.
+    ParseBalloonSizeParam,
+    /// Failed parsing memory overcommit parameter.
+    ParseMemoryOvercommitParam,
+    /// Failed parsing the target host free memory parameter.
+    ParseMemoryTargetFreeHostParam(std::num::ParseIntError),
+    /// The target host free memory must be a percentage.
+    InvalidMemoryTargetFreeHost(u8),
+    /// The memory overcommit controller relies on the balloon or the
+    /// virtio-mem device.
+    OvercommitWithoutDevice,
+    /// The balloon relies on the guest memory being shared with the host,
+    /// which SEV prevents.
+    SevWithBalloon,
+    /// Failed parsing the action taken on guest panic.
+    ParseOnPanicParam,
+    /// Failed parsing the action taken on unknown PIO and MMIO accesses.
+    ParseUnknownAccessParam,
+    /// Failed parsing the number of unknown accesses logged.
+    ParseUnknownAccessLogLimitParam(std::num::ParseIntError),
+    /// Failed parsing the console profile.
+    ParseConsoleProfileParam,
     /// Missing kernel configuration
     ValidateMissingKernelConfig,
     /// Failed parsing generic on|off parameter.
@@ -112,40 +298,75 @@ pub struct VmParams<'a> {
     pub kernel: Option<&'a str>,
     pub cmdline: Option<&'a str>,
     pub disks: Option<Vec<&'a str>>,
+    pub scsi: Option<Vec<&'a str>>,
     pub net: Option<Vec<&'a str>>,
     pub rng: &'a str,
     pub fs: Option<Vec<&'a str>>,
     pub pmem: Option<Vec<&'a str>>,
-    pub serial: &'a str,
-    pub console: &'a str,
+    pub memory_zones: Option<Vec<&'a str>>,
+    pub sgx_epc: Option<Vec<&'a str>>,
+    pub serial: Option<&'a str>,
+    pub console: Option<&'a str>,
+    pub console_profile: Option<&'a str>,
     pub devices: Option<Vec<&'a str>>,
+    pub sriov_vfs: Option<Vec<&'a str>>,
     pub vhost_user_net: Option<Vec<&'a str>>,
     pub vhost_user_blk: Option<Vec<&'a str>>,
     pub vsock: Option<Vec<&'a str>>,
+    pub vdpa: Option<Vec<&'a str>>,
+    pub console_ports: Option<Vec<&'a str>>,
+    pub cgroup: Option<&'a str>,
+    pub platform: Option<&'a str>,
+    pub balloon: Option<&'a str>,
+    pub on_panic: Option<&'a str>,
+    pub unknown_access: Option<&'a str>,
+    pub uuid: Option<&'a str>,
+    pub state_dir: Option<&'a str>,
 }
 
 impl<'a> VmParams<'a> {
     pub fn from_arg_matches(args: &'a ArgMatches) -> Self {
         // These .unwrap()s cannot fail as there is a default value defined
         let cpus = args.value_of("cpus").unwrap();
-        let memory = args.value_of("memory").unwrap();
+        // The memory zones make up the guest RAM unless the memory size is
+        // given explicitly.
+        let memory = if args.is_present("memory-zone") && args.occurrences_of("memory") == 0 {
+            "size=0"
+        } else {
+            args.value_of("memory").unwrap()
+        };
         let rng = args.value_of("rng").unwrap();
-        let serial = args.value_of("serial").unwrap();
+        // The console profile tells the serial and console defaults.
+        let serial = args.value_of("serial");
 
         let kernel = args.value_of("kernel");
         let cmdline = args.value_of("cmdline");
 
         let disks: Option<Vec<&str>> = args.values_of("disk").map(|x| x.collect());
+        let scsi: Option<Vec<&str>> = args.values_of("scsi").map(|x| x.collect());
         let net: Option<Vec<&str>> = args.values_of("net").map(|x| x.collect());
-        let console = args.value_of("console").unwrap();
+        let console = args.value_of("console");
+        let console_profile = args.value_of("console-profile");
         let fs: Option<Vec<&str>> = args.values_of("fs").map(|x| x.collect());
         let pmem: Option<Vec<&str>> = args.values_of("pmem").map(|x| x.collect());
+        let memory_zones: Option<Vec<&str>> = args.values_of("memory-zone").map(|x| x.collect());
+        let sgx_epc: Option<Vec<&str>> = args.values_of("sgx-epc").map(|x| x.collect());
         let devices: Option<Vec<&str>> = args.values_of("device").map(|x| x.collect());
+        let sriov_vfs: Option<Vec<&str>> = args.values_of("sriov-vf").map(|x| x.collect());
         let vhost_user_net: Option<Vec<&str>> =
             args.values_of("vhost-user-net").map(|x| x.collect());
         let vhost_user_blk: Option<Vec<&str>> =
             args.values_of("vhost-user-blk").map(|x| x.collect());
         let vsock: Option<Vec<&str>> = args.values_of("vsock").map(|x| x.collect());
+        let vdpa: Option<Vec<&str>> = args.values_of("vdpa").map(|x| x.collect());
+        let console_ports: Option<Vec<&str>> = args.values_of("console-port").map(|x| x.collect());
+        let cgroup = args.value_of("cgroup");
+        let platform = args.value_of("platform");
+        let balloon = args.value_of("balloon");
+        let on_panic = args.value_of("on-panic");
+        let unknown_access = args.value_of("unknown-access");
+        let uuid = args.value_of("uuid");
+        let state_dir = args.value_of("state-dir");
 
         VmParams {
             cpus,
@@ -153,16 +374,30 @@ impl<'a> VmParams<'a> {
             kernel,
             cmdline,
             disks,
+            scsi,
             net,
             rng,
             fs,
             pmem,
+            memory_zones,
+            sgx_epc,
             serial,
             console,
+            console_profile,
             devices,
+            sriov_vfs,
             vhost_user_net,
             vhost_user_blk,
             vsock,
+            vdpa,
+            console_ports,
+            cgroup,
+            platform,
+            balloon,
+            on_panic,
+            unknown_access,
+            uuid,
+            state_dir,
         }
     }
 }
@@ -199,10 +434,72 @@ fn parse_on_off(param: &str) -> Result<bool> {
     }
 }
 
+fn parse_device_id(param: &str) -> Option<String> {
+    if param.is_empty() {
+        None
+    } else {
+        Some(param.to_owned())
+    }
+}
+
+fn parse_pci_address(param: &str) -> Result<Option<PciAddress>> {
+    if param.is_empty() {
+        Ok(None)
+    } else {
+        PciAddress::parse(param).map(Some)
+    }
+}
+
+// The virtio specification requires the queue sizes to be a power of two,
+// and bounds them to 32768.
+fn validate_queue_size(queue_size: u16) -> Result<()> {
+    if !queue_size.is_power_of_two() || queue_size > MAX_QUEUE_SIZE {
+        return Err(Error::InvalidQueueSize(queue_size));
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CpuFeatureConfig {
+    pub name: String,
+    pub enabled: bool,
+}
+
+impl CpuFeatureConfig {
+    pub fn parse(feature: &str) -> Result<Self> {
+        if feature.starts_with('+') {
+            Ok(CpuFeatureConfig {
+                name: feature[1..].to_string(),
+                enabled: true,
+            })
+        } else if feature.starts_with('-') {
+            Ok(CpuFeatureConfig {
+                name: feature[1..].to_string(),
+                enabled: false,
+            })
+        } else {
+            Err(Error::ParseCpusFeaturesParam(feature.to_string()))
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct CpusConfig {
     pub boot_vcpus: u8,
     pub max_vcpus: u8,
+    #[serde(default)]
+    pub features: Option<Vec<CpuFeatureConfig>>,
+    #[serde(default)]
+    pub tsc_khz: Option<u32>,
+    #[serde(default)]
+    pub nested: Option<bool>,
+    #[serde(default)]
+    pub hyperv: bool,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub freq_hints: bool,
 }
 
 impl CpusConfig {
@@ -212,6 +509,12 @@ impl CpusConfig {
             Ok(CpusConfig {
                 boot_vcpus: legacy_vcpu_count,
                 max_vcpus: legacy_vcpu_count,
+                features: None,
+                tsc_khz: None,
+                nested: None,
+                hyperv: false,
+                model: None,
+                freq_hints: false,
             })
         } else {
             // Split the parameters based on the comma delimiter
@@ -219,12 +522,30 @@ impl CpusConfig {
 
             let mut boot_str: &str = "";
             let mut max_str: &str = "";
+            let mut features_str: &str = "";
+            let mut tsc_khz_str: &str = "";
+            let mut nested_str: &str = "";
+            let mut hyperv_str: &str = "";
+            let mut model_str: &str = "";
+            let mut freq_hints_str: &str = "";
 
             for param in params_list.iter() {
                 if param.starts_with("boot=") {
                     boot_str = &param["boot=".len()..];
                 } else if param.starts_with("max=") {
                     max_str = &param["max=".len()..];
+                } else if param.starts_with("features=") {
+                    features_str = &param["features=".len()..];
+                } else if param.starts_with("tsc_khz=") {
+                    tsc_khz_str = &param["tsc_khz=".len()..];
+                } else if param.starts_with("nested=") {
+                    nested_str = &param["nested=".len()..];
+                } else if param.starts_with("hyperv=") {
+                    hyperv_str = &param["hyperv=".len()..];
+                } else if param.starts_with("model=") {
+                    model_str = &param["model=".len()..];
+                } else if param.starts_with("freq_hints=") {
+                    freq_hints_str = &param["freq_hints=".len()..];
                 } else {
                     return Err(Error::ParseCpusUnknownParam);
                 }
@@ -237,16 +558,73 @@ impl CpusConfig {
                 boot_vcpus
             };
 
-            if max_vcpus < boot_vcpus {
-                return Err(Error::ParseCpusMaxLowerThanBoot);
-            }
+            // Features are separated with ':' since ',' delimits the
+            // parameters, e.g. features=-avx512f:+invtsc
+            let features = if features_str != "" {
+                let mut features = Vec::new();
+                for feature in features_str.split(':') {
+                    features.push(CpuFeatureConfig::parse(feature)?);
+                }
+                Some(features)
+            } else {
+                None
+            };
 
-            Ok(CpusConfig {
+            let tsc_khz = if tsc_khz_str != "" {
+                Some(tsc_khz_str.parse().map_err(Error::ParseCpusParams)?)
+            } else {
+                None
+            };
+
+            // Unless explicitly enabled or disabled, whatever the host
+            // exposes is inherited.
+            let nested = if nested_str != "" {
+                Some(parse_on_off(nested_str)?)
+            } else {
+                None
+            };
+
+            let hyperv = parse_on_off(hyperv_str)?;
+
+            let freq_hints = parse_on_off(freq_hints_str)?;
+
+            // Without a named model, the host CPU is passed through.
+            let model = if model_str != "" {
+                Some(model_str.to_string())
+            } else {
+                None
+            };
+
+            let cpus_config = CpusConfig {
                 boot_vcpus,
                 max_vcpus,
-            })
+                features,
+                tsc_khz,
+                nested,
+                hyperv,
+                model,
+                freq_hints,
+            };
+            cpus_config.validate()?;
+
+            Ok(cpus_config)
         }
     }
+
+    /// The maximum number of vCPUs is described to the guest through ACPI
+    /// when booting, and it bounds the number of vCPUs that can be hot
+    /// plugged later on. It can't be lower than the number of boot vCPUs.
+    pub fn validate(&self) -> Result<()> {
+        if self.boot_vcpus == 0 {
+            return Err(Error::ParseCpusZeroBoot);
+        }
+
+        if self.max_vcpus < self.boot_vcpus {
+            return Err(Error::ParseCpusMaxLowerThanBoot);
+        }
+
+        Ok(())
+    }
 }
 
 impl Default for CpusConfig {
@@ -254,6 +632,12 @@ impl Default for CpusConfig {
         CpusConfig {
             boot_vcpus: DEFAULT_VCPUS,
             max_vcpus: DEFAULT_VCPUS,
+            features: None,
+            tsc_khz: None,
+            nested: None,
+            hyperv: false,
+            model: None,
+            freq_hints: false,
         }
     }
 }
@@ -267,6 +651,80 @@ pub struct MemoryConfig {
     pub mergeable: bool,
     #[serde(default)]
     pub hotplug_size: Option<u64>,
+    #[serde(default)]
+    pub zones: Option<Vec<MemoryZoneConfig>>,
+    #[serde(default)]
+    pub dirty_rate_limit: Option<u64>,
+    #[serde(default)]
+    pub mlock: bool,
+    #[serde(default)]
+    pub prefault: bool,
+    #[serde(default)]
+    pub working_set: bool,
+    #[serde(default)]
+    pub overcommit: MemoryOvercommit,
+    #[serde(default = "default_memoryconfig_target_free_host")]
+    pub target_free_host: u8,
+    #[serde(default)]
+    pub hotplug_method: HotplugMethod,
+    #[serde(default)]
+    pub hotplugged_size: Option<u64>,
+}
+
+fn default_memoryconfig_target_free_host() -> u8 {
+    DEFAULT_TARGET_FREE_HOST_PERCENT
+}
+
+/// How the guest memory is adjusted according to the host memory pressure.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum MemoryOvercommit {
+    /// The guest memory is never adjusted.
+    Off,
+    /// The balloon is inflated and deflated to keep enough free memory on
+    /// the host.
+    Auto,
+}
+
+impl Default for MemoryOvercommit {
+    fn default() -> Self {
+        MemoryOvercommit::Off
+    }
+}
+
+impl MemoryOvercommit {
+    fn parse(overcommit: &str) -> Result<Self> {
+        match overcommit {
+            "" | "off" => Ok(MemoryOvercommit::Off),
+            "auto" => Ok(MemoryOvercommit::Auto),
+            _ => Err(Error::ParseMemoryOvercommitParam),
+        }
+    }
+}
+
+/// How memory gets hotplugged into the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum HotplugMethod {
+    /// Through ACPI memory devices, each hotplug adding a new one.
+    Acpi,
+    /// Through a virtio-mem device, the guest plugging blocks of its region
+    /// as it is resized. Memory can be unplugged as well.
+    VirtioMem,
+}
+
+impl Default for HotplugMethod {
+    fn default() -> Self {
+        HotplugMethod::Acpi
+    }
+}
+
+impl HotplugMethod {
+    fn parse(hotplug_method: &str) -> Result<Self> {
+        match hotplug_method {
+            "" | "acpi" => Ok(HotplugMethod::Acpi),
+            "virtio-mem" => Ok(HotplugMethod::VirtioMem),
+            _ => Err(Error::ParseMemoryHotplugMethodParam),
+        }
+    }
 }
 
 impl MemoryConfig {
@@ -279,6 +737,14 @@ impl MemoryConfig {
         let mut mergeable_str: &str = "";
         let mut backed = false;
         let mut hotplug_str: &str = "";
+        let mut dirty_rate_limit_str: &str = "";
+        let mut mlock_str: &str = "";
+        let mut prefault_str: &str = "";
+        let mut working_set_str: &str = "";
+        let mut overcommit_str: &str = "";
+        let mut target_free_host_str: &str = "";
+        let mut hotplug_method_str: &str = "";
+        let mut hotplugged_size_str: &str = "";
 
         for param in params_list.iter() {
             if param.starts_with("size=") {
@@ -290,6 +756,22 @@ impl MemoryConfig {
                 mergeable_str = &param[10..];
             } else if param.starts_with("hotplug_size=") {
                 hotplug_str = &param[13..]
+            } else if param.starts_with("dirty_rate_limit=") {
+                dirty_rate_limit_str = &param[17..]
+            } else if param.starts_with("mlock=") {
+                mlock_str = &param[6..];
+            } else if param.starts_with("prefault=") {
+                prefault_str = &param[9..];
+            } else if param.starts_with("working_set=") {
+                working_set_str = &param[12..];
+            } else if param.starts_with("overcommit=") {
+                overcommit_str = &param["overcommit=".len()..];
+            } else if param.starts_with("target_free_host=") {
+                target_free_host_str = &param["target_free_host=".len()..];
+            } else if param.starts_with("hotplug_method=") {
+                hotplug_method_str = &param["hotplug_method=".len()..];
+            } else if param.starts_with("hotplugged_size=") {
+                hotplugged_size_str = &param["hotplugged_size=".len()..];
             }
         }
 
@@ -303,6 +785,17 @@ impl MemoryConfig {
             None
         };
 
+        let mut target_free_host = default_memoryconfig_target_free_host();
+        if !target_free_host_str.is_empty() {
+            target_free_host = target_free_host_str
+                .trim_end_matches('%')
+                .parse()
+                .map_err(Error::ParseMemoryTargetFreeHostParam)?;
+            if target_free_host >= 100 {
+                return Err(Error::InvalidMemoryTargetFreeHost(target_free_host));
+            }
+        }
+
         Ok(MemoryConfig {
             size: parse_size(size_str)?,
             file,
@@ -312,8 +805,71 @@ impl MemoryConfig {
             } else {
                 Some(parse_size(hotplug_str)?)
             },
+            zones: None,
+            dirty_rate_limit: if dirty_rate_limit_str == "" {
+                None
+            } else {
+                Some(parse_size(dirty_rate_limit_str)?)
+            },
+            mlock: parse_on_off(mlock_str)?,
+            prefault: parse_on_off(prefault_str)?,
+            working_set: parse_on_off(working_set_str)?,
+            overcommit: MemoryOvercommit::parse(overcommit_str)?,
+            target_free_host,
+            hotplug_method: HotplugMethod::parse(hotplug_method_str)?,
+            hotplugged_size: if hotplugged_size_str.is_empty() {
+                None
+            } else {
+                Some(parse_size(hotplugged_size_str)?)
+            },
         })
     }
+
+    /// Amount of RAM described by the memory zones, if any.
+    pub fn zones_size(&self) -> Option<u64> {
+        self.zones
+            .as_ref()
+            .map(|zones| zones.iter().map(|z| z.size).sum())
+    }
+
+    /// Total amount of guest RAM. Along with memory zones, the memory size
+    /// goes beyond their sum once some RAM got hotplugged, the extra RAM
+    /// following the zones after a reboot.
+    pub fn total_size(&self) -> u64 {
+        std::cmp::max(self.size, self.zones_size().unwrap_or(0))
+    }
+
+    /// The memory size, when not zero, can't be lower than the memory zones
+    /// it's made of. The memory hotplugged through virtio-mem must fit in
+    /// its region.
+    pub fn validate(&self) -> Result<()> {
+        for zone in self.zones.iter().flatten() {
+            zone.validate()?;
+        }
+
+        let hotplug_size = self.hotplug_size.unwrap_or(0);
+        if self.hotplug_method == HotplugMethod::VirtioMem
+            && (hotplug_size == 0 || hotplug_size % vm_virtio::VIRTIO_MEM_BLOCK_SIZE != 0)
+        {
+            return Err(Error::InvalidVirtioMemHotplugSize(hotplug_size));
+        }
+        if let Some(hotplugged_size) = self.hotplugged_size {
+            if self.hotplug_method != HotplugMethod::VirtioMem
+                || hotplugged_size > hotplug_size
+                || hotplugged_size % vm_virtio::VIRTIO_MEM_BLOCK_SIZE != 0
+            {
+                return Err(Error::InvalidMemoryHotpluggedSize(hotplugged_size));
+            }
+        }
+
+        if let Some(zones_size) = self.zones_size() {
+            if self.size != 0 && self.size < zones_size {
+                return Err(Error::InvalidMemoryZonesSize(self.size, zones_size));
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Default for MemoryConfig {
@@ -323,7 +879,135 @@ impl Default for MemoryConfig {
             file: None,
             mergeable: false,
             hotplug_size: None,
+            zones: None,
+            dirty_rate_limit: None,
+            mlock: false,
+            prefault: false,
+            working_set: false,
+            overcommit: MemoryOvercommit::Off,
+            target_free_host: DEFAULT_TARGET_FREE_HOST_PERCENT,
+            hotplug_method: HotplugMethod::Acpi,
+            hotplugged_size: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SgxEpcConfig {
+    pub size: u64,
+    #[serde(default)]
+    pub prefault: bool,
+}
+
+impl SgxEpcConfig {
+    pub fn parse(sgx_epc: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = sgx_epc.split(',').collect();
+
+        let mut size_str: &str = "";
+        let mut prefault_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("size=") {
+                size_str = &param[5..];
+            } else if param.starts_with("prefault=") {
+                prefault_str = &param[9..];
+            }
+        }
+
+        if size_str.is_empty() {
+            return Err(Error::ParseSgxEpcSizeParam);
         }
+
+        Ok(SgxEpcConfig {
+            size: parse_size(size_str)?,
+            prefault: parse_on_off(prefault_str)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MemoryZoneConfig {
+    pub size: u64,
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    #[serde(default)]
+    pub hugepages: bool,
+    #[serde(default)]
+    pub host_numa_node: Option<u32>,
+}
+
+/// Highest host NUMA node a memory zone can be bound to, as the node mask
+/// handed to mbind(2) is a single 64 bits word.
+pub const MAX_HOST_NUMA_NODE: u32 = 63;
+
+impl MemoryZoneConfig {
+    pub fn parse(memory_zone: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = memory_zone.split(',').collect();
+
+        let mut size_str: &str = "";
+        let mut file_str: &str = "";
+        let mut backed = false;
+        let mut hugepages_str: &str = "";
+        let mut host_numa_node_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("size=") {
+                size_str = &param[5..];
+            } else if param.starts_with("file=") {
+                backed = true;
+                file_str = &param[5..];
+            } else if param.starts_with("hugepages=") {
+                hugepages_str = &param[10..];
+            } else if param.starts_with("host_numa_node=") {
+                host_numa_node_str = &param[15..];
+            }
+        }
+
+        if size_str.is_empty() {
+            return Err(Error::ParseMemoryZoneSizeParam);
+        }
+
+        let file = if backed {
+            if file_str.is_empty() {
+                return Err(Error::ParseMemoryZoneFileParam);
+            }
+
+            Some(PathBuf::from(file_str))
+        } else {
+            None
+        };
+
+        let host_numa_node = if host_numa_node_str.is_empty() {
+            None
+        } else {
+            let node = host_numa_node_str
+                .parse::<u32>()
+                .map_err(Error::ParseMemoryZoneHostNumaNodeParam)?;
+            if node > MAX_HOST_NUMA_NODE {
+                return Err(Error::InvalidMemoryZoneHostNumaNode(node));
+            }
+            Some(node)
+        };
+
+        let memory_zone = MemoryZoneConfig {
+            size: parse_size(size_str)?,
+            file,
+            hugepages: parse_on_off(hugepages_str)?,
+            host_numa_node,
+        };
+        memory_zone.validate()?;
+
+        Ok(memory_zone)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.hugepages && self.file.is_some() {
+            return Err(Error::InvalidMemoryZoneHugepagesWithFile);
+        }
+
+        Ok(())
     }
 }
 
@@ -347,6 +1031,83 @@ impl CmdlineConfig {
     }
 }
 
+/// PCI address of a device, as in 0000:00:05.0.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PciAddress {
+    #[serde(default)]
+    pub bus: u8,
+    pub device: u8,
+    #[serde(default)]
+    pub function: u8,
+}
+
+impl PciAddress {
+    /// Parses `[[segment:]bus:]device[.function]`, in hexadecimal as shown
+    /// by lspci.
+    pub fn parse(address: &str) -> Result<Self> {
+        let error = || Error::ParsePciAddress(address.to_owned());
+        let parse = |field: &str| u8::from_str_radix(field, 16).map_err(|_| error());
+
+        let (slot, function) = match address.find('.') {
+            Some(dot) => (&address[..dot], parse(&address[dot + 1..])?),
+            None => (address, 0),
+        };
+        let fields: Vec<&str> = slot.split(':').collect();
+        let (segment, bus, device) = match fields.as_slice() {
+            [device] => ("0", "0", *device),
+            [bus, device] => ("0", *bus, *device),
+            [segment, bus, device] => (*segment, *bus, *device),
+            _ => return Err(error()),
+        };
+        if u16::from_str_radix(segment, 16).map_err(|_| error())? != 0 {
+            return Err(error());
+        }
+
+        let address = PciAddress {
+            bus: parse(bus)?,
+            device: parse(device)?,
+            function,
+        };
+        address.validate()?;
+        Ok(address)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.bus != 0 || self.device == 0 || self.device > 31 || self.function != 0 {
+            return Err(Error::InvalidPciAddress(*self));
+        }
+        Ok(())
+    }
+}
+
+/// What a disk does when its backing store fails a request, e.g. because
+/// the storage is full or unreachable.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum DiskErrorPolicy {
+    /// The I/O error is reported to the guest, whose filesystem usually
+    /// goes read-only.
+    Report,
+    /// The VM is paused, so that the storage can be fixed. The failed
+    /// request is retried once the VM is resumed.
+    Stop,
+}
+
+impl Default for DiskErrorPolicy {
+    fn default() -> Self {
+        DiskErrorPolicy::Report
+    }
+}
+
+impl DiskErrorPolicy {
+    fn parse(on_error: &str) -> Result<Self> {
+        match on_error {
+            "" | "report" => Ok(DiskErrorPolicy::Report),
+            "stop" => Ok(DiskErrorPolicy::Stop),
+            _ => Err(Error::ParseDiskErrorPolicyParam),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct DiskConfig {
     pub path: PathBuf,
@@ -365,6 +1126,27 @@ pub struct DiskConfig {
     pub vhost_socket: Option<String>,
     #[serde(default = "default_diskconfig_wce")]
     pub wce: bool,
+    #[serde(default)]
+    pub nvme: bool,
+    pub rate_limiter_config: Option<RateLimiterConfig>,
+    #[serde(default)]
+    pub watch: bool,
+    #[serde(default)]
+    pub overlay: Option<PathBuf>,
+    #[serde(default)]
+    pub cdrom: bool,
+    #[serde(default)]
+    pub boot: bool,
+    #[serde(default)]
+    pub serial: Option<String>,
+    #[serde(default)]
+    pub pci_address: Option<PciAddress>,
+    #[serde(default)]
+    pub on_error: DiskErrorPolicy,
+    #[serde(default)]
+    pub track_changes: bool,
+    #[serde(default)]
+    pub id: Option<String>,
 }
 
 fn default_diskconfig_num_queues() -> usize {
@@ -393,6 +1175,22 @@ impl DiskConfig {
         let mut vhost_socket_str: &str = "";
         let mut vhost_user_str: &str = "";
         let mut wce_str: &str = "";
+        let mut nvme_str: &str = "";
+        let mut bw_size_str: &str = "";
+        let mut bw_one_time_burst_str: &str = "";
+        let mut bw_refill_time_str: &str = "";
+        let mut ops_size_str: &str = "";
+        let mut ops_one_time_burst_str: &str = "";
+        let mut ops_refill_time_str: &str = "";
+        let mut watch_str: &str = "";
+        let mut overlay_str: &str = "";
+        let mut cdrom_str: &str = "";
+        let mut boot_str: &str = "";
+        let mut serial_str: &str = "";
+        let mut pci_address_str: &str = "";
+        let mut on_error_str: &str = "";
+        let mut track_changes_str: &str = "";
+        let mut id_str: &str = "";
 
         for param in params_list.iter() {
             if param.starts_with("path=") {
@@ -413,6 +1211,38 @@ impl DiskConfig {
                 vhost_socket_str = &param[7..];
             } else if param.starts_with("wce=") {
                 wce_str = &param[4..];
+            } else if param.starts_with("nvme=") {
+                nvme_str = &param[5..];
+            } else if param.starts_with("bw_size=") {
+                bw_size_str = &param[8..];
+            } else if param.starts_with("bw_one_time_burst=") {
+                bw_one_time_burst_str = &param[18..];
+            } else if param.starts_with("bw_refill_time=") {
+                bw_refill_time_str = &param[15..];
+            } else if param.starts_with("ops_size=") {
+                ops_size_str = &param[9..];
+            } else if param.starts_with("ops_one_time_burst=") {
+                ops_one_time_burst_str = &param[19..];
+            } else if param.starts_with("ops_refill_time=") {
+                ops_refill_time_str = &param[16..];
+            } else if param.starts_with("watch=") {
+                watch_str = &param[6..];
+            } else if param.starts_with("overlay=") {
+                overlay_str = &param[8..];
+            } else if param.starts_with("cdrom=") {
+                cdrom_str = &param[6..];
+            } else if param.starts_with("boot=") {
+                boot_str = &param[5..];
+            } else if param.starts_with("serial=") {
+                serial_str = &param[7..];
+            } else if param.starts_with("pci_address=") {
+                pci_address_str = &param[12..];
+            } else if param.starts_with("on_error=") {
+                on_error_str = &param[9..];
+            } else if param.starts_with("track_changes=") {
+                track_changes_str = &param[14..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
             }
         }
 
@@ -431,6 +1261,7 @@ impl DiskConfig {
             queue_size = queue_size_str
                 .parse()
                 .map_err(Error::ParseDiskQueueSizeParam)?;
+            validate_queue_size(queue_size)?;
         }
         if !vhost_user_str.is_empty() {
             vhost_user = vhost_user_str.parse().map_err(Error::ParseDiskVhostParam)?;
@@ -445,16 +1276,259 @@ impl DiskConfig {
             wce = wce_str.parse().map_err(Error::ParseDiskWceParam)?;
         }
 
+        let iommu = parse_on_off(iommu_str)?;
+        let nvme = parse_on_off(nvme_str)?;
+        // The NVMe controller is emulated by the VMM, and does not sit
+        // behind the virtual IOMMU.
+        if nvme && (vhost_user || iommu) {
+            return Err(Error::InvalidNvmeDisk);
+        }
+
+        let rate_limiter_config = RateLimiterConfig::parse(
+            bw_size_str,
+            bw_one_time_burst_str,
+            bw_refill_time_str,
+            ops_size_str,
+            ops_one_time_burst_str,
+            ops_refill_time_str,
+        )?;
+        if rate_limiter_config.is_some() && (nvme || vhost_user) {
+            return Err(Error::InvalidRateLimitedDisk);
+        }
+
+        let overlay = if overlay_str.is_empty() {
+            None
+        } else {
+            if nvme || vhost_user || vm_virtio::is_nbd_uri(path_str) {
+                return Err(Error::InvalidOverlayDisk);
+            }
+            Some(PathBuf::from(overlay_str))
+        };
+
+        // A CD-ROM image is always read-only.
+        let cdrom = parse_on_off(cdrom_str)?;
+        if cdrom && (nvme || vhost_user || overlay.is_some()) {
+            return Err(Error::InvalidCdromDisk);
+        }
+
+        let boot = parse_on_off(boot_str)?;
+        if boot && nvme {
+            return Err(Error::InvalidBootDisk);
+        }
+
+        let serial = if serial_str.is_empty() {
+            None
+        } else {
+            if nvme || vhost_user || serial_str.len() > vm_virtio::DISK_SERIAL_MAX_LEN {
+                return Err(Error::InvalidDiskSerial);
+            }
+            Some(serial_str.to_owned())
+        };
+
+        let on_error = DiskErrorPolicy::parse(on_error_str)?;
+        if on_error == DiskErrorPolicy::Stop && (nvme || vhost_user) {
+            return Err(Error::InvalidDiskErrorPolicy);
+        }
+
+        let track_changes = parse_on_off(track_changes_str)?;
+        if track_changes && (nvme || vhost_user) {
+            return Err(Error::InvalidTrackChangesDisk);
+        }
+
         Ok(DiskConfig {
             path: PathBuf::from(path_str),
-            readonly: parse_on_off(readonly_str)?,
+            readonly: cdrom || parse_on_off(readonly_str)?,
             direct: parse_on_off(direct_str)?,
-            iommu: parse_on_off(iommu_str)?,
+            iommu,
             num_queues,
             queue_size,
             vhost_socket,
             vhost_user,
             wce,
+            nvme,
+            rate_limiter_config,
+            watch: parse_on_off(watch_str)?,
+            overlay,
+            cdrom,
+            boot,
+            serial,
+            pci_address: parse_pci_address(pci_address_str)?,
+            on_error,
+            track_changes,
+            id: parse_device_id(id_str),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct TokenBucketConfig {
+    pub size: u64,
+    #[serde(default)]
+    pub one_time_burst: u64,
+    #[serde(default = "default_tokenbucketconfig_refill_time")]
+    pub refill_time: u64,
+}
+
+fn default_tokenbucketconfig_refill_time() -> u64 {
+    DEFAULT_RATE_LIMITER_REFILL_TIME_MS
+}
+
+impl TokenBucketConfig {
+    fn parse(
+        size_str: &str,
+        one_time_burst_str: &str,
+        refill_time_str: &str,
+        bytes: bool,
+    ) -> Result<Option<Self>> {
+        if size_str.is_empty() {
+            if !one_time_burst_str.is_empty() || !refill_time_str.is_empty() {
+                return Err(Error::MissingRateLimiterSize);
+            }
+            return Ok(None);
+        }
+
+        // Bandwidth buckets are sized in bytes, accepting the K, M and G
+        // suffixes.
+        let parse_tokens = |s: &str| -> Result<u64> {
+            if bytes {
+                parse_size(s)
+            } else {
+                s.parse().map_err(Error::ParseRateLimiterParam)
+            }
+        };
+
+        let size = parse_tokens(size_str)?;
+        let one_time_burst = if one_time_burst_str.is_empty() {
+            0
+        } else {
+            parse_tokens(one_time_burst_str)?
+        };
+        let refill_time = if refill_time_str.is_empty() {
+            default_tokenbucketconfig_refill_time()
+        } else {
+            refill_time_str
+                .parse()
+                .map_err(Error::ParseRateLimiterParam)?
+        };
+
+        Ok(Some(TokenBucketConfig {
+            size,
+            one_time_burst,
+            refill_time,
+        }))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RateLimiterConfig {
+    pub bandwidth: Option<TokenBucketConfig>,
+    pub ops: Option<TokenBucketConfig>,
+}
+
+impl RateLimiterConfig {
+    // No token bucket at all means no rate limiting.
+    fn parse(
+        bw_size_str: &str,
+        bw_one_time_burst_str: &str,
+        bw_refill_time_str: &str,
+        ops_size_str: &str,
+        ops_one_time_burst_str: &str,
+        ops_refill_time_str: &str,
+    ) -> Result<Option<Self>> {
+        let bandwidth =
+            TokenBucketConfig::parse(bw_size_str, bw_one_time_burst_str, bw_refill_time_str, true)?;
+        let ops = TokenBucketConfig::parse(
+            ops_size_str,
+            ops_one_time_burst_str,
+            ops_refill_time_str,
+            false,
+        )?;
+
+        if bandwidth.is_none() && ops.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(RateLimiterConfig { bandwidth, ops }))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ScsiConfig {
+    pub luns: Vec<PathBuf>,
+    #[serde(default)]
+    pub readonly: bool,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default = "default_scsiconfig_num_queues")]
+    pub num_queues: usize,
+    #[serde(default = "default_scsiconfig_queue_size")]
+    pub queue_size: u16,
+}
+
+fn default_scsiconfig_num_queues() -> usize {
+    DEFAULT_NUM_QUEUES_SCSI
+}
+
+fn default_scsiconfig_queue_size() -> u16 {
+    DEFAULT_QUEUE_SIZE_SCSI
+}
+
+impl ScsiConfig {
+    pub fn parse(scsi: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = scsi.split(',').collect();
+
+        let mut luns_str: &str = "";
+        let mut readonly_str: &str = "";
+        let mut iommu_str: &str = "";
+        let mut num_queues_str: &str = "";
+        let mut queue_size_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("luns=") {
+                luns_str = &param[5..];
+            } else if param.starts_with("readonly=") {
+                readonly_str = &param[9..];
+            } else if param.starts_with("iommu=") {
+                iommu_str = &param[6..];
+            } else if param.starts_with("num_queues=") {
+                num_queues_str = &param[11..];
+            } else if param.starts_with("queue_size=") {
+                queue_size_str = &param[11..];
+            }
+        }
+
+        // LUNs are numbered following the order they are listed in, and
+        // they are separated with ':' since ',' delimits the parameters.
+        let luns: Vec<PathBuf> = luns_str
+            .split(':')
+            .filter(|l| !l.is_empty())
+            .map(PathBuf::from)
+            .collect();
+        if luns.is_empty() {
+            return Err(Error::ParseScsiLunsParam);
+        }
+
+        let mut num_queues: usize = default_scsiconfig_num_queues();
+        let mut queue_size: u16 = default_scsiconfig_queue_size();
+
+        if !num_queues_str.is_empty() {
+            num_queues = num_queues_str
+                .parse()
+                .map_err(Error::ParseScsiNumQueuesParam)?;
+        }
+        if !queue_size_str.is_empty() {
+            queue_size = queue_size_str
+                .parse()
+                .map_err(Error::ParseScsiQueueSizeParam)?;
+        }
+
+        Ok(ScsiConfig {
+            luns,
+            readonly: parse_on_off(readonly_str)?,
+            iommu: parse_on_off(iommu_str)?,
+            num_queues,
+            queue_size,
         })
     }
 }
@@ -478,6 +1552,34 @@ pub struct NetConfig {
     #[serde(default)]
     pub vhost_user: bool,
     pub vhost_socket: Option<String>,
+    #[serde(default)]
+    pub transitional: bool,
+    #[serde(default)]
+    pub standby: bool,
+    #[serde(default)]
+    pub pci_address: Option<PciAddress>,
+    #[serde(default)]
+    pub xdp: Option<String>,
+    #[serde(default)]
+    pub xdp_map: Option<PathBuf>,
+    #[serde(default)]
+    pub fds: Option<Vec<i32>>,
+    #[serde(default)]
+    pub host_mac: Option<MacAddr>,
+    #[serde(default)]
+    pub mtu: Option<u16>,
+    #[serde(default = "default_netconfig_offload")]
+    pub offload_csum: bool,
+    #[serde(default = "default_netconfig_offload")]
+    pub offload_tso: bool,
+    #[serde(default = "default_netconfig_offload")]
+    pub offload_ufo: bool,
+    #[serde(default)]
+    pub rx_rate_limiter_config: Option<RateLimiterConfig>,
+    #[serde(default)]
+    pub tx_rate_limiter_config: Option<RateLimiterConfig>,
+    #[serde(default)]
+    pub id: Option<String>,
 }
 
 fn default_netconfig_tap() -> Option<String> {
@@ -504,6 +1606,10 @@ fn default_netconfig_queue_size() -> u16 {
     DEFAULT_QUEUE_SIZE_VUNET
 }
 
+fn default_netconfig_offload() -> bool {
+    true
+}
+
 impl NetConfig {
     pub fn parse(net: &str) -> Result<Self> {
         // Split the parameters based on the comma delimiter
@@ -518,6 +1624,30 @@ impl NetConfig {
         let mut queue_size_str: &str = "";
         let mut vhost_socket_str: &str = "";
         let mut vhost_user_str: &str = "";
+        let mut transitional_str: &str = "";
+        let mut standby_str: &str = "";
+        let mut pci_address_str: &str = "";
+        let mut xdp_str: &str = "";
+        let mut xdp_map_str: &str = "";
+        let mut fds_str: &str = "";
+        let mut host_mac_str: &str = "";
+        let mut mtu_str: &str = "";
+        let mut offload_csum_str: &str = "";
+        let mut offload_tso_str: &str = "";
+        let mut offload_ufo_str: &str = "";
+        let mut rx_bw_size_str: &str = "";
+        let mut rx_bw_one_time_burst_str: &str = "";
+        let mut rx_bw_refill_time_str: &str = "";
+        let mut rx_ops_size_str: &str = "";
+        let mut rx_ops_one_time_burst_str: &str = "";
+        let mut rx_ops_refill_time_str: &str = "";
+        let mut tx_bw_size_str: &str = "";
+        let mut tx_bw_one_time_burst_str: &str = "";
+        let mut tx_bw_refill_time_str: &str = "";
+        let mut tx_ops_size_str: &str = "";
+        let mut tx_ops_one_time_burst_str: &str = "";
+        let mut tx_ops_refill_time_str: &str = "";
+        let mut id_str: &str = "";
 
         for param in params_list.iter() {
             if param.starts_with("tap=") {
@@ -538,6 +1668,56 @@ impl NetConfig {
                 vhost_user_str = &param[11..];
             } else if param.starts_with("socket=") {
                 vhost_socket_str = &param[7..];
+            } else if param.starts_with("transitional=") {
+                transitional_str = &param[13..];
+            } else if param.starts_with("standby=") {
+                standby_str = &param[8..];
+            } else if param.starts_with("pci_address=") {
+                pci_address_str = &param[12..];
+            } else if param.starts_with("xdp=") {
+                xdp_str = &param[4..];
+            } else if param.starts_with("xdp_map=") {
+                xdp_map_str = &param[8..];
+            } else if param.starts_with("fd=") {
+                fds_str = &param[3..];
+            } else if param.starts_with("fds=") {
+                fds_str = &param[4..];
+            } else if param.starts_with("host_mac=") {
+                host_mac_str = &param[9..];
+            } else if param.starts_with("mtu=") {
+                mtu_str = &param[4..];
+            } else if param.starts_with("offload_csum=") {
+                offload_csum_str = &param[13..];
+            } else if param.starts_with("offload_tso=") {
+                offload_tso_str = &param[12..];
+            } else if param.starts_with("offload_ufo=") {
+                offload_ufo_str = &param[12..];
+            } else if param.starts_with("rx_bw_size=") {
+                rx_bw_size_str = &param[11..];
+            } else if param.starts_with("rx_bw_one_time_burst=") {
+                rx_bw_one_time_burst_str = &param[21..];
+            } else if param.starts_with("rx_bw_refill_time=") {
+                rx_bw_refill_time_str = &param[18..];
+            } else if param.starts_with("rx_ops_size=") {
+                rx_ops_size_str = &param[12..];
+            } else if param.starts_with("rx_ops_one_time_burst=") {
+                rx_ops_one_time_burst_str = &param[22..];
+            } else if param.starts_with("rx_ops_refill_time=") {
+                rx_ops_refill_time_str = &param[19..];
+            } else if param.starts_with("tx_bw_size=") {
+                tx_bw_size_str = &param[11..];
+            } else if param.starts_with("tx_bw_one_time_burst=") {
+                tx_bw_one_time_burst_str = &param[21..];
+            } else if param.starts_with("tx_bw_refill_time=") {
+                tx_bw_refill_time_str = &param[18..];
+            } else if param.starts_with("tx_ops_size=") {
+                tx_ops_size_str = &param[12..];
+            } else if param.starts_with("tx_ops_one_time_burst=") {
+                tx_ops_one_time_burst_str = &param[22..];
+            } else if param.starts_with("tx_ops_refill_time=") {
+                tx_ops_refill_time_str = &param[19..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
             }
         }
 
@@ -567,11 +1747,16 @@ impl NetConfig {
             num_queues = num_queues_str
                 .parse()
                 .map_err(Error::ParseNetNumQueuesParam)?;
+            // One Rx and one Tx queue per pair.
+            if num_queues == 0 || num_queues % 2 != 0 {
+                return Err(Error::InvalidNetNumQueues(num_queues));
+            }
         }
         if !queue_size_str.is_empty() {
             queue_size = queue_size_str
                 .parse()
                 .map_err(Error::ParseNetQueueSizeParam)?;
+            validate_queue_size(queue_size)?;
         }
         if !vhost_user_str.is_empty() {
             vhost_user = vhost_user_str.parse().map_err(Error::ParseNetVhostParam)?;
@@ -580,6 +1765,112 @@ impl NetConfig {
             vhost_socket = Some(vhost_socket_str.to_owned());
         }
 
+        let transitional = parse_on_off(transitional_str)?;
+        // Legacy drivers know nothing about VIRTIO_F_IOMMU_PLATFORM, and the
+        // vhost-user backend handles the virtio-net header itself.
+        if transitional && (vhost_user || iommu) {
+            return Err(Error::InvalidTransitionalNet);
+        }
+        let standby = parse_on_off(standby_str)?;
+        if standby && vhost_user {
+            return Err(Error::InvalidStandbyNet);
+        }
+
+        let mut xdp = None;
+        let mut xdp_map = None;
+        if !xdp_str.is_empty() {
+            if tap.is_some() || vhost_user {
+                return Err(Error::InvalidXdpNet);
+            }
+            if xdp_map_str.is_empty() {
+                return Err(Error::ParseNetXdpMapRequired);
+            }
+            xdp = Some(xdp_str.to_owned());
+            xdp_map = Some(PathBuf::from(xdp_map_str));
+        }
+
+        let mut fds = None;
+        if !fds_str.is_empty() {
+            if tap.is_some() || xdp.is_some() || vhost_user {
+                return Err(Error::InvalidFdNet);
+            }
+            let fds_list = fds_str
+                .split(':')
+                .map(|fd| fd.parse().map_err(Error::ParseNetFdParam))
+                .collect::<Result<Vec<i32>>>()?;
+            // One fd per queue pair, the number of queues following the
+            // number of fds unless it's given.
+            if num_queues_str.is_empty() {
+                num_queues = fds_list.len() * 2;
+            } else if num_queues != fds_list.len() * 2 {
+                return Err(Error::InvalidNetFdsNumQueues(fds_list.len(), num_queues));
+            }
+            fds = Some(fds_list);
+        }
+
+        let mut host_mac = None;
+        if !host_mac_str.is_empty() {
+            if xdp.is_some() || vhost_user {
+                return Err(Error::InvalidHostMacNet);
+            }
+            host_mac = Some(MacAddr::parse_str(host_mac_str).map_err(Error::ParseNetHostMacParam)?);
+        }
+
+        let mut mtu = None;
+        if !mtu_str.is_empty() {
+            if xdp.is_some() || vhost_user {
+                return Err(Error::InvalidMtuNet);
+            }
+            let value: u16 = mtu_str.parse().map_err(Error::ParseNetMtuParam)?;
+            if !(MIN_NET_MTU..=MAX_NET_MTU).contains(&value) {
+                return Err(Error::InvalidNetMtu(value));
+            }
+            mtu = Some(value);
+        }
+
+        // The segmentation offloads are only enabled along with the checksum
+        // one.
+        let mut offload_csum = default_netconfig_offload();
+        if !offload_csum_str.is_empty() {
+            offload_csum = parse_on_off(offload_csum_str)?;
+        }
+        let mut offload_tso = default_netconfig_offload();
+        if !offload_tso_str.is_empty() {
+            offload_tso = parse_on_off(offload_tso_str)?;
+        }
+        let mut offload_ufo = default_netconfig_offload();
+        if !offload_ufo_str.is_empty() {
+            offload_ufo = parse_on_off(offload_ufo_str)?;
+        }
+        if !(offload_csum_str.is_empty()
+            && offload_tso_str.is_empty()
+            && offload_ufo_str.is_empty())
+            && (xdp.is_some() || vhost_user)
+        {
+            return Err(Error::InvalidOffloadNet);
+        }
+
+        // The ops token buckets count the frames.
+        let rx_rate_limiter_config = RateLimiterConfig::parse(
+            rx_bw_size_str,
+            rx_bw_one_time_burst_str,
+            rx_bw_refill_time_str,
+            rx_ops_size_str,
+            rx_ops_one_time_burst_str,
+            rx_ops_refill_time_str,
+        )?;
+        let tx_rate_limiter_config = RateLimiterConfig::parse(
+            tx_bw_size_str,
+            tx_bw_one_time_burst_str,
+            tx_bw_refill_time_str,
+            tx_ops_size_str,
+            tx_ops_one_time_burst_str,
+            tx_ops_refill_time_str,
+        )?;
+        if (rx_rate_limiter_config.is_some() || tx_rate_limiter_config.is_some()) && vhost_user {
+            return Err(Error::InvalidRateLimitedNet);
+        }
+
         Ok(NetConfig {
             tap,
             ip,
@@ -590,6 +1881,20 @@ impl NetConfig {
             queue_size,
             vhost_user,
             vhost_socket,
+            transitional,
+            standby,
+            pci_address: parse_pci_address(pci_address_str)?,
+            xdp,
+            xdp_map,
+            fds,
+            host_mac,
+            mtu,
+            offload_csum,
+            offload_tso,
+            offload_ufo,
+            rx_rate_limiter_config,
+            tx_rate_limiter_config,
+            id: parse_device_id(id_str),
         })
     }
 }
@@ -633,10 +1938,53 @@ impl Default for RngConfig {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct BalloonConfig {
+    pub size: u64,
+    #[serde(default)]
+    pub deflate_on_oom: bool,
+    #[serde(default)]
+    pub free_page_reporting: bool,
+}
+
+impl BalloonConfig {
+    pub fn parse(balloon: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = balloon.split(',').collect();
+
+        let mut size_str: &str = "";
+        let mut deflate_on_oom_str: &str = "";
+        let mut free_page_reporting_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("size=") {
+                size_str = &param["size=".len()..];
+            } else if param.starts_with("deflate_on_oom=") {
+                deflate_on_oom_str = &param["deflate_on_oom=".len()..];
+            } else if param.starts_with("free_page_reporting=") {
+                free_page_reporting_str = &param["free_page_reporting=".len()..];
+            }
+        }
+
+        if size_str.is_empty() {
+            return Err(Error::ParseBalloonSizeParam);
+        }
+
+        Ok(BalloonConfig {
+            size: parse_size(size_str)?,
+            deflate_on_oom: parse_on_off(deflate_on_oom_str)?,
+            free_page_reporting: parse_on_off(free_page_reporting_str)?,
+        })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct FsConfig {
     pub tag: String,
-    pub sock: PathBuf,
+    #[serde(default)]
+    pub sock: Option<PathBuf>,
+    #[serde(default)]
+    pub path: Option<PathBuf>,
     #[serde(default = "default_fsconfig_num_queues")]
     pub num_queues: usize,
     #[serde(default = "default_fsconfig_queue_size")]
@@ -645,6 +1993,21 @@ pub struct FsConfig {
     pub dax: bool,
     #[serde(default = "default_fsconfig_cache_size")]
     pub cache_size: u64,
+    #[serde(default)]
+    pub spawn: bool,
+    #[serde(default)]
+    pub virtiofsd: Option<PathBuf>,
+    #[serde(default)]
+    pub sandbox: Option<FsSandbox>,
+    #[serde(default)]
+    pub pci_address: Option<PciAddress>,
+}
+
+/// How the spawned virtiofsd isolates itself from the host.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum FsSandbox {
+    Namespace,
+    Chroot,
 }
 
 fn default_fsconfig_num_queues() -> usize {
@@ -670,16 +2033,23 @@ impl FsConfig {
 
         let mut tag: &str = "";
         let mut sock: &str = "";
+        let mut path: &str = "";
         let mut num_queues_str: &str = "";
         let mut queue_size_str: &str = "";
         let mut dax_str: &str = "";
         let mut cache_size_str: &str = "";
+        let mut spawn_str: &str = "";
+        let mut virtiofsd: &str = "";
+        let mut sandbox_str: &str = "";
+        let mut pci_address_str: &str = "";
 
         for param in params_list.iter() {
             if param.starts_with("tag=") {
                 tag = &param[4..];
             } else if param.starts_with("sock=") {
                 sock = &param[5..];
+            } else if param.starts_with("path=") {
+                path = &param[5..];
             } else if param.starts_with("num_queues=") {
                 num_queues_str = &param[11..];
             } else if param.starts_with("queue_size=") {
@@ -688,6 +2058,14 @@ impl FsConfig {
                 dax_str = &param[4..];
             } else if param.starts_with("cache_size=") {
                 cache_size_str = &param[11..];
+            } else if param.starts_with("spawn=") {
+                spawn_str = &param[6..];
+            } else if param.starts_with("virtiofsd=") {
+                virtiofsd = &param[10..];
+            } else if param.starts_with("sandbox=") {
+                sandbox_str = &param[8..];
+            } else if param.starts_with("pci_address=") {
+                pci_address_str = &param[12..];
             }
         }
 
@@ -700,9 +2078,31 @@ impl FsConfig {
         if tag.is_empty() {
             return Err(Error::ParseFsTagParam);
         }
-        if sock.is_empty() {
+        if sock.is_empty() && path.is_empty() {
             return Err(Error::ParseFsSockParam);
         }
+        let spawn = match spawn_str {
+            "" | "off" => false,
+            "on" => true,
+            _ => return Err(Error::ParseFsSpawn),
+        };
+        let sandbox = match sandbox_str {
+            "" => None,
+            "namespace" => Some(FsSandbox::Namespace),
+            "chroot" => Some(FsSandbox::Chroot),
+            _ => return Err(Error::ParseFsSandbox),
+        };
+        // The spawned virtiofsd listens on the socket and shares the
+        // directory, otherwise the directory is shared by the VMM itself.
+        if spawn {
+            if sock.is_empty() || path.is_empty() {
+                return Err(Error::InvalidFsSpawn);
+            }
+        } else if !virtiofsd.is_empty() || sandbox.is_some() {
+            return Err(Error::InvalidFsSpawn);
+        } else if !sock.is_empty() && !path.is_empty() {
+            return Err(Error::InvalidFsBackend);
+        }
         if !num_queues_str.is_empty() {
             num_queues = num_queues_str
                 .parse()
@@ -734,11 +2134,16 @@ impl FsConfig {
 
         Ok(FsConfig {
             tag: tag.to_string(),
-            sock: PathBuf::from(sock),
+            sock: Some(PathBuf::from(sock)).filter(|_| !sock.is_empty()),
+            path: Some(PathBuf::from(path)).filter(|_| !path.is_empty()),
             num_queues,
             queue_size,
             dax,
             cache_size,
+            spawn,
+            virtiofsd: Some(PathBuf::from(virtiofsd)).filter(|_| !virtiofsd.is_empty()),
+            sandbox,
+            pci_address: parse_pci_address(pci_address_str)?,
         })
     }
 }
@@ -746,11 +2151,14 @@ impl FsConfig {
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct PmemConfig {
     pub file: PathBuf,
-    pub size: u64,
+    #[serde(default)]
+    pub size: Option<u64>,
     #[serde(default)]
     pub iommu: bool,
     #[serde(default)]
     pub mergeable: bool,
+    #[serde(default)]
+    pub discard_writes: bool,
 }
 
 impl PmemConfig {
@@ -762,6 +2170,7 @@ impl PmemConfig {
         let mut size_str: &str = "";
         let mut iommu_str: &str = "";
         let mut mergeable_str: &str = "";
+        let mut discard_writes_str: &str = "";
 
         for param in params_list.iter() {
             if param.starts_with("file=") {
@@ -772,6 +2181,8 @@ impl PmemConfig {
                 iommu_str = &param[6..];
             } else if param.starts_with("mergeable=") {
                 mergeable_str = &param[10..];
+            } else if param.starts_with("discard_writes=") {
+                discard_writes_str = &param["discard_writes=".len()..];
             }
         }
 
@@ -779,11 +2190,19 @@ impl PmemConfig {
             return Err(Error::ParsePmemFileParam);
         }
 
+        // Without an explicit size, the whole file is exposed to the guest.
+        let size = if size_str.is_empty() {
+            None
+        } else {
+            Some(parse_size(size_str)?)
+        };
+
         Ok(PmemConfig {
             file: PathBuf::from(file_str),
-            size: parse_size(size_str)?,
+            size,
             iommu: parse_on_off(iommu_str)?,
             mergeable: parse_on_off(mergeable_str)?,
+            discard_writes: parse_on_off(discard_writes_str)?,
         })
     }
 }
@@ -791,9 +2210,11 @@ impl PmemConfig {
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum ConsoleOutputMode {
     Off,
+    Pty,
     Tty,
     File,
     Null,
+    Socket,
 }
 
 impl ConsoleOutputMode {
@@ -811,13 +2232,23 @@ pub struct ConsoleConfig {
     pub file: Option<PathBuf>,
     pub mode: ConsoleOutputMode,
     #[serde(default)]
+    pub socket: Option<PathBuf>,
+    #[serde(default)]
     pub iommu: bool,
+    #[serde(default = "default_consoleconfig_queue_size")]
+    pub queue_size: u16,
+    #[serde(default)]
+    pub mux: bool,
 }
 
 fn default_consoleconfig_file() -> Option<PathBuf> {
     None
 }
 
+fn default_consoleconfig_queue_size() -> u16 {
+    DEFAULT_QUEUE_SIZE_CONSOLE
+}
+
 impl ConsoleConfig {
     pub fn parse(console: &str) -> Result<Self> {
         // Split the parameters based on the comma delimiter
@@ -826,15 +2257,25 @@ impl ConsoleConfig {
         let mut valid = false;
         let mut file: Option<PathBuf> = default_consoleconfig_file();
         let mut mode: ConsoleOutputMode = ConsoleOutputMode::Off;
+        let mut socket: Option<PathBuf> = None;
         let mut iommu_str: &str = "";
+        let mut queue_size_str: &str = "";
+        let mut mux_str: &str = "";
 
         for param in params_list.iter() {
             if param.starts_with("iommu=") {
                 iommu_str = &param[6..];
+            } else if param.starts_with("queue_size=") {
+                queue_size_str = &param[11..];
+            } else if param.starts_with("mux=") {
+                mux_str = &param[4..];
             } else {
                 if *param == "off" {
                     mode = ConsoleOutputMode::Off;
                     file = None;
+                } else if *param == "pty" {
+                    mode = ConsoleOutputMode::Pty;
+                    file = None;
                 } else if *param == "tty" {
                     mode = ConsoleOutputMode::Tty;
                     file = None;
@@ -844,6 +2285,10 @@ impl ConsoleConfig {
                 } else if param.starts_with("null") {
                     mode = ConsoleOutputMode::Null;
                     file = None;
+                } else if param.starts_with("socket=") {
+                    mode = ConsoleOutputMode::Socket;
+                    file = None;
+                    socket = Some(PathBuf::from(&param[7..]));
                 } else {
                     return Err(Error::ParseConsoleParam);
                 }
@@ -855,10 +2300,26 @@ impl ConsoleConfig {
             return Err(Error::ParseConsoleParam);
         }
 
+        let mut queue_size = default_consoleconfig_queue_size();
+        if !queue_size_str.is_empty() {
+            queue_size = queue_size_str
+                .parse()
+                .map_err(Error::ParseConsoleQueueSizeParam)?;
+            validate_queue_size(queue_size)?;
+        }
+
+        // Only the last mode given counts.
+        if mode != ConsoleOutputMode::Socket {
+            socket = None;
+        }
+
         Ok(Self {
             mode,
             file,
+            socket,
             iommu: parse_on_off(iommu_str)?,
+            queue_size,
+            mux: parse_on_off(mux_str)?,
         })
     }
 
@@ -866,16 +2327,126 @@ impl ConsoleConfig {
         ConsoleConfig {
             file: None,
             mode: ConsoleOutputMode::Null,
+            socket: None,
+            iommu: false,
+            queue_size: DEFAULT_QUEUE_SIZE_CONSOLE,
+            mux: false,
+        }
+    }
+
+    pub fn default_console() -> Self {
+        ConsoleConfig {
+            file: None,
+            mode: ConsoleOutputMode::Tty,
+            socket: None,
             iommu: false,
+            queue_size: DEFAULT_QUEUE_SIZE_CONSOLE,
+            mux: false,
         }
     }
+}
+
+/// Which of the serial port and the virtio-console the guest console goes
+/// to, unless set with `--serial` and `--console`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConsoleProfile {
+    /// The virtio-console is on the terminal, and the serial port output is
+    /// discarded.
+    Virtio,
+    /// The serial port is on the terminal, and there is no virtio-console,
+    /// for the guests lacking a virtio-console driver.
+    Legacy,
+}
+
+impl Default for ConsoleProfile {
+    fn default() -> Self {
+        ConsoleProfile::Virtio
+    }
+}
+
+impl ConsoleProfile {
+    fn parse(profile: &str) -> Result<Self> {
+        match profile {
+            "" | "virtio" => Ok(ConsoleProfile::Virtio),
+            "legacy" => Ok(ConsoleProfile::Legacy),
+            _ => Err(Error::ParseConsoleProfileParam),
+        }
+    }
+
+    pub fn default_serial(self) -> ConsoleConfig {
+        match self {
+            ConsoleProfile::Virtio => ConsoleConfig::default_serial(),
+            ConsoleProfile::Legacy => ConsoleConfig {
+                mode: ConsoleOutputMode::Tty,
+                ..ConsoleConfig::default_serial()
+            },
+        }
+    }
+
+    pub fn default_console(self) -> ConsoleConfig {
+        match self {
+            ConsoleProfile::Virtio => ConsoleConfig::default_console(),
+            ConsoleProfile::Legacy => ConsoleConfig {
+                mode: ConsoleOutputMode::Off,
+                ..ConsoleConfig::default_console()
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ConsolePortConfig {
+    pub name: String,
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    #[serde(default)]
+    pub socket: Option<PathBuf>,
+}
+
+impl ConsolePortConfig {
+    pub fn parse(console_port: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = console_port.split(',').collect();
+
+        let mut name_str: &str = "";
+        let mut file_str: &str = "";
+        let mut socket_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("name=") {
+                name_str = &param[5..];
+            } else if param.starts_with("file=") {
+                file_str = &param[5..];
+            } else if param.starts_with("socket=") {
+                socket_str = &param[7..];
+            }
+        }
+
+        if name_str.is_empty() {
+            return Err(Error::ParseConsolePortNameParam);
+        }
+        if file_str.is_empty() == socket_str.is_empty() {
+            return Err(Error::ParseConsolePortBackendParam);
+        }
+
+        Ok(ConsolePortConfig {
+            name: name_str.to_string(),
+            file: Some(PathBuf::from(file_str)).filter(|_| !file_str.is_empty()),
+            socket: Some(PathBuf::from(socket_str)).filter(|_| !socket_str.is_empty()),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum DeviceResetMethod {
+    Flr,
+    Bus,
+    None,
+}
 
-    pub fn default_console() -> Self {
-        ConsoleConfig {
-            file: None,
-            mode: ConsoleOutputMode::Tty,
-            iommu: false,
-        }
+impl Default for DeviceResetMethod {
+    fn default() -> Self {
+        DeviceResetMethod::Flr
     }
 }
 
@@ -884,6 +2455,20 @@ pub struct DeviceConfig {
     pub path: PathBuf,
     #[serde(default)]
     pub iommu: bool,
+    #[serde(default)]
+    pub reset: DeviceResetMethod,
+    #[serde(default = "default_deviceconfig_reset_on")]
+    pub reset_on_attach: bool,
+    #[serde(default = "default_deviceconfig_reset_on")]
+    pub reset_on_release: bool,
+    #[serde(default)]
+    pub p2p_dma: bool,
+    #[serde(default)]
+    pub pci_address: Option<PciAddress>,
+}
+
+fn default_deviceconfig_reset_on() -> bool {
+    true
 }
 
 impl DeviceConfig {
@@ -893,18 +2478,126 @@ impl DeviceConfig {
 
         let mut path_str: &str = "";
         let mut iommu_str: &str = "";
+        let mut reset_str: &str = "";
+        let mut reset_on_attach_str: &str = "";
+        let mut reset_on_release_str: &str = "";
+        let mut p2p_dma_str: &str = "";
+        let mut pci_address_str: &str = "";
 
         for param in params_list.iter() {
             if param.starts_with("path=") {
                 path_str = &param[5..];
             } else if param.starts_with("iommu=") {
                 iommu_str = &param[6..];
+            } else if param.starts_with("reset=") {
+                reset_str = &param["reset=".len()..];
+            } else if param.starts_with("reset_on_attach=") {
+                reset_on_attach_str = &param["reset_on_attach=".len()..];
+            } else if param.starts_with("reset_on_release=") {
+                reset_on_release_str = &param["reset_on_release=".len()..];
+            } else if param.starts_with("p2p_dma=") {
+                p2p_dma_str = &param["p2p_dma=".len()..];
+            } else if param.starts_with("pci_address=") {
+                pci_address_str = &param["pci_address=".len()..];
             }
         }
 
+        let reset = match reset_str {
+            "" | "flr" => DeviceResetMethod::Flr,
+            "bus" => DeviceResetMethod::Bus,
+            "none" => DeviceResetMethod::None,
+            _ => return Err(Error::ParseDeviceResetParam),
+        };
+
+        let mut reset_on_attach = default_deviceconfig_reset_on();
+        if !reset_on_attach_str.is_empty() {
+            reset_on_attach = parse_on_off(reset_on_attach_str)?;
+        }
+        let mut reset_on_release = default_deviceconfig_reset_on();
+        if !reset_on_release_str.is_empty() {
+            reset_on_release = parse_on_off(reset_on_release_str)?;
+        }
+
         Ok(DeviceConfig {
             path: PathBuf::from(path_str),
             iommu: parse_on_off(iommu_str)?,
+            reset,
+            reset_on_attach,
+            reset_on_release,
+            p2p_dma: parse_on_off(p2p_dma_str)?,
+            pci_address: parse_pci_address(pci_address_str)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SriovVfConfig {
+    pub pf: String,
+    pub index: u32,
+    #[serde(default)]
+    pub mac: Option<MacAddr>,
+    #[serde(default)]
+    pub vlan: Option<u16>,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default)]
+    pub failover: bool,
+}
+
+impl SriovVfConfig {
+    pub fn parse(sriov_vf: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = sriov_vf.split(',').collect();
+
+        let mut pf_str: &str = "";
+        let mut index_str: &str = "";
+        let mut mac_str: &str = "";
+        let mut vlan_str: &str = "";
+        let mut iommu_str: &str = "";
+        let mut failover_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("pf=") {
+                pf_str = &param[3..];
+            } else if param.starts_with("index=") {
+                index_str = &param[6..];
+            } else if param.starts_with("mac=") {
+                mac_str = &param[4..];
+            } else if param.starts_with("vlan=") {
+                vlan_str = &param[5..];
+            } else if param.starts_with("iommu=") {
+                iommu_str = &param[6..];
+            } else if param.starts_with("failover=") {
+                failover_str = &param[9..];
+            }
+        }
+
+        if pf_str.is_empty() {
+            return Err(Error::ParseSriovVfPfParam);
+        }
+
+        let mut mac = None;
+        if !mac_str.is_empty() {
+            mac = Some(MacAddr::parse_str(mac_str).map_err(Error::ParseSriovVfMacParam)?);
+        }
+        let mut vlan = None;
+        if !vlan_str.is_empty() {
+            vlan = Some(vlan_str.parse().map_err(Error::ParseSriovVfVlanParam)?);
+        }
+        // The guest pairs the VF with its standby virtio-net device based on
+        // their MAC address.
+        let failover = parse_on_off(failover_str)?;
+        if failover && mac.is_none() {
+            return Err(Error::ParseSriovVfFailoverWithoutMac);
+        }
+
+        Ok(SriovVfConfig {
+            pf: pf_str.to_string(),
+            index: index_str.parse().map_err(Error::ParseSriovVfIndexParam)?,
+            mac,
+            vlan,
+            iommu: parse_on_off(iommu_str)?,
+            failover,
         })
     }
 }
@@ -988,6 +2681,7 @@ impl VhostUserNetConfig {
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct VsockConfig {
     pub cid: u64,
+    #[serde(alias = "socket")]
     pub sock: PathBuf,
     #[serde(default)]
     pub iommu: bool,
@@ -1007,6 +2701,8 @@ impl VsockConfig {
                 cid_str = &param[4..];
             } else if param.starts_with("sock=") {
                 sock_str = &param[5..];
+            } else if param.starts_with("socket=") {
+                sock_str = &param[7..];
             } else if param.starts_with("iommu=") {
                 iommu_str = &param[6..];
             }
@@ -1024,6 +2720,195 @@ impl VsockConfig {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct VdpaConfig {
+    pub path: PathBuf,
+    #[serde(default = "default_vdpaconfig_num_queues")]
+    pub num_queues: usize,
+}
+
+// The queues of a network device, without the control queue.
+fn default_vdpaconfig_num_queues() -> usize {
+    2
+}
+
+impl VdpaConfig {
+    pub fn parse(vdpa: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = vdpa.split(',').collect();
+
+        let mut path_str: &str = "";
+        let mut num_queues_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("path=") {
+                path_str = &param[5..];
+            } else if param.starts_with("num_queues=") {
+                num_queues_str = &param[11..];
+            }
+        }
+
+        if path_str.is_empty() {
+            return Err(Error::ParseVdpaPathParam);
+        }
+
+        let mut num_queues = default_vdpaconfig_num_queues();
+        if !num_queues_str.is_empty() {
+            num_queues = num_queues_str
+                .parse()
+                .map_err(Error::ParseVdpaNumQueuesParam)?;
+        }
+        if num_queues == 0 {
+            return Err(Error::InvalidVdpaNumQueues);
+        }
+
+        Ok(VdpaConfig {
+            path: PathBuf::from(path_str),
+            num_queues,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CgroupConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub vcpus_cpu_max: Option<u64>,
+    #[serde(default)]
+    pub vcpus_cpuset: Option<String>,
+    #[serde(default)]
+    pub vmm_cpu_max: Option<u64>,
+    #[serde(default)]
+    pub vmm_cpuset: Option<String>,
+    #[serde(default = "default_cgroupconfig_cpu_period")]
+    pub cpu_period: u64,
+}
+
+fn default_cgroupconfig_cpu_period() -> u64 {
+    DEFAULT_CGROUP_CPU_PERIOD_US
+}
+
+impl CgroupConfig {
+    pub fn parse(cgroup: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = cgroup.split(',').collect();
+
+        let mut path_str: &str = "";
+        let mut vcpus_cpu_max_str: &str = "";
+        let mut vcpus_cpuset_str: &str = "";
+        let mut vmm_cpu_max_str: &str = "";
+        let mut vmm_cpuset_str: &str = "";
+        let mut cpu_period_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("path=") {
+                path_str = &param["path=".len()..];
+            } else if param.starts_with("vcpus_cpu_max=") {
+                vcpus_cpu_max_str = &param["vcpus_cpu_max=".len()..];
+            } else if param.starts_with("vcpus_cpuset=") {
+                vcpus_cpuset_str = &param["vcpus_cpuset=".len()..];
+            } else if param.starts_with("vmm_cpu_max=") {
+                vmm_cpu_max_str = &param["vmm_cpu_max=".len()..];
+            } else if param.starts_with("vmm_cpuset=") {
+                vmm_cpuset_str = &param["vmm_cpuset=".len()..];
+            } else if param.starts_with("cpu_period=") {
+                cpu_period_str = &param["cpu_period=".len()..];
+            }
+        }
+
+        if path_str.is_empty() {
+            return Err(Error::ParseCgroupPathParam);
+        }
+
+        let parse_cpu_max = |cpu_max: &str| -> Result<Option<u64>> {
+            if cpu_max.is_empty() {
+                Ok(None)
+            } else {
+                Ok(Some(
+                    cpu_max.parse().map_err(Error::ParseCgroupCpuMaxParam)?,
+                ))
+            }
+        };
+
+        // CPU lists are separated with ':' since ',' delimits the
+        // parameters, e.g. vcpus_cpuset=2-5:8
+        let parse_cpuset = |cpuset: &str| -> Option<String> {
+            if cpuset.is_empty() {
+                None
+            } else {
+                Some(cpuset.replace(':', ","))
+            }
+        };
+
+        let mut cpu_period = default_cgroupconfig_cpu_period();
+        if !cpu_period_str.is_empty() {
+            cpu_period = cpu_period_str
+                .parse()
+                .map_err(Error::ParseCgroupCpuPeriodParam)?;
+        }
+
+        Ok(CgroupConfig {
+            path: PathBuf::from(path_str),
+            vcpus_cpu_max: parse_cpu_max(vcpus_cpu_max_str)?,
+            vcpus_cpuset: parse_cpuset(vcpus_cpuset_str),
+            vmm_cpu_max: parse_cpu_max(vmm_cpu_max_str)?,
+            vmm_cpuset: parse_cpuset(vmm_cpuset_str),
+            cpu_period,
+        })
+    }
+}
+
+pub const DEFAULT_SEV_POLICY: u32 = 0x1;
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PlatformConfig {
+    #[serde(default)]
+    pub sev: bool,
+    #[serde(default = "default_platformconfig_sev_policy")]
+    pub sev_policy: u32,
+}
+
+fn default_platformconfig_sev_policy() -> u32 {
+    DEFAULT_SEV_POLICY
+}
+
+impl PlatformConfig {
+    pub fn parse(platform: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = platform.split(',').collect();
+
+        let mut sev_str: &str = "";
+        let mut sev_policy_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("sev=") {
+                sev_str = &param["sev=".len()..];
+            } else if param.starts_with("sev_policy=") {
+                sev_policy_str = &param["sev_policy=".len()..];
+            }
+        }
+
+        let mut sev_policy = default_platformconfig_sev_policy();
+        if !sev_policy_str.is_empty() {
+            sev_policy = if sev_policy_str.starts_with("0x") {
+                u32::from_str_radix(&sev_policy_str[2..], 16)
+            } else {
+                sev_policy_str.parse()
+            }
+            .map_err(Error::ParseSevPolicyParam)?;
+        }
+
+        Ok(PlatformConfig {
+            sev: parse_on_off(sev_str)?,
+            sev_policy,
+        })
+    }
+
+    pub fn sev_enabled(&self) -> bool {
+        self.sev
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct VhostUserBlkConfig {
     pub sock: String,
@@ -1097,6 +2982,99 @@ impl VhostUserBlkConfig {
     }
 }
 
+/// What the VMM does when the guest reports a panic through the pvpanic
+/// device.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum PanicAction {
+    /// The panic is logged, and the guest carries on, usually rebooting
+    /// depending on its own configuration.
+    Log,
+    /// The VM is paused, so that its state can be inspected.
+    Pause,
+    /// The VM is shut down.
+    Shutdown,
+    /// The VM is rebooted.
+    Reboot,
+}
+
+impl Default for PanicAction {
+    fn default() -> Self {
+        PanicAction::Log
+    }
+}
+
+impl PanicAction {
+    fn parse(on_panic: &str) -> Result<Self> {
+        match on_panic {
+            "" | "log" => Ok(PanicAction::Log),
+            "pause" => Ok(PanicAction::Pause),
+            "shutdown" => Ok(PanicAction::Shutdown),
+            "reboot" => Ok(PanicAction::Reboot),
+            _ => Err(Error::ParseOnPanicParam),
+        }
+    }
+}
+
+pub const DEFAULT_UNKNOWN_ACCESS_LOG_LIMIT: u64 = 10;
+
+/// What the VMM does when the guest accesses a PIO or MMIO address no device
+/// handles. The reads return all ones, as on real hardware.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum UnknownAccessAction {
+    /// The access is ignored.
+    Ignore,
+    /// The access is ignored, and logged up to `log_limit` times.
+    Log,
+    /// The vCPU faults, the access being logged along with the guest
+    /// instruction pointer, and the VM is rebooted as on a triple fault.
+    Fault,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct UnknownAccessConfig {
+    pub action: UnknownAccessAction,
+    #[serde(default = "default_unknownaccessconfig_log_limit")]
+    pub log_limit: u64,
+}
+
+fn default_unknownaccessconfig_log_limit() -> u64 {
+    DEFAULT_UNKNOWN_ACCESS_LOG_LIMIT
+}
+
+impl Default for UnknownAccessConfig {
+    fn default() -> Self {
+        UnknownAccessConfig {
+            action: UnknownAccessAction::Log,
+            log_limit: DEFAULT_UNKNOWN_ACCESS_LOG_LIMIT,
+        }
+    }
+}
+
+impl UnknownAccessConfig {
+    pub fn parse(unknown_access: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = unknown_access.split(',').collect();
+
+        let mut config = UnknownAccessConfig::default();
+        for param in params_list.iter() {
+            if param.starts_with("log_limit=") {
+                config.log_limit = param["log_limit=".len()..]
+                    .parse()
+                    .map_err(Error::ParseUnknownAccessLogLimitParam)?;
+            } else {
+                config.action = match *param {
+                    "" | "log" => UnknownAccessAction::Log,
+                    "ignore" => UnknownAccessAction::Ignore,
+                    "fault" => UnknownAccessAction::Fault,
+                    _ => return Err(Error::ParseUnknownAccessParam),
+                };
+            }
+        }
+
+        Ok(config)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct VmConfig {
     #[serde(default)]
@@ -1107,6 +3085,7 @@ pub struct VmConfig {
     #[serde(default)]
     pub cmdline: CmdlineConfig,
     pub disks: Option<Vec<DiskConfig>>,
+    pub scsi: Option<Vec<ScsiConfig>>,
     pub net: Option<Vec<NetConfig>>,
     #[serde(default)]
     pub rng: RngConfig,
@@ -1116,12 +3095,25 @@ pub struct VmConfig {
     pub serial: ConsoleConfig,
     #[serde(default = "ConsoleConfig::default_console")]
     pub console: ConsoleConfig,
+    pub console_ports: Option<Vec<ConsolePortConfig>>,
     pub devices: Option<Vec<DeviceConfig>>,
+    pub sriov_vfs: Option<Vec<SriovVfConfig>>,
     pub vhost_user_net: Option<Vec<VhostUserNetConfig>>,
     pub vhost_user_blk: Option<Vec<VhostUserBlkConfig>>,
     pub vsock: Option<Vec<VsockConfig>>,
+    pub vdpa: Option<Vec<VdpaConfig>>,
     #[serde(default)]
     pub iommu: bool,
+    pub cgroup: Option<CgroupConfig>,
+    pub sgx_epc: Option<Vec<SgxEpcConfig>>,
+    pub platform: Option<PlatformConfig>,
+    pub balloon: Option<BalloonConfig>,
+    #[serde(default)]
+    pub on_panic: PanicAction,
+    #[serde(default)]
+    pub unknown_access: UnknownAccessConfig,
+    pub uuid: Option<Uuid>,
+    pub state_dir: Option<PathBuf>,
 }
 
 impl VmConfig {
@@ -1129,6 +3121,110 @@ impl VmConfig {
         self.kernel.is_some()
     }
 
+    /// Checks the virtio queue sizes of the block, network and console
+    /// devices, which the API doesn't go through the parsing for.
+    pub fn validate_queue_sizes(&self) -> Result<()> {
+        for disk in self.disks.iter().flatten() {
+            validate_queue_size(disk.queue_size)?;
+        }
+        for net in self.net.iter().flatten() {
+            validate_queue_size(net.queue_size)?;
+        }
+        validate_queue_size(self.console.queue_size)
+    }
+
+    /// The PCI addresses given to the disks, network interfaces, fs and VFIO
+    /// devices.
+    pub fn pci_addresses(&self) -> Vec<PciAddress> {
+        self.disks
+            .iter()
+            .flatten()
+            .map(|disk| disk.pci_address)
+            .chain(self.net.iter().flatten().map(|net| net.pci_address))
+            .chain(self.fs.iter().flatten().map(|fs| fs.pci_address))
+            .chain(
+                self.devices
+                    .iter()
+                    .flatten()
+                    .map(|device| device.pci_address),
+            )
+            .flatten()
+            .collect()
+    }
+
+    /// Checks the PCI addresses given to the disks, network interfaces, fs
+    /// and VFIO devices are valid, and that no two devices share one.
+    pub fn validate_pci_addresses(&self) -> Result<()> {
+        let mut seen = Vec::new();
+        for address in self.pci_addresses() {
+            address.validate()?;
+            if seen.contains(&address) {
+                return Err(Error::DuplicatePciAddress(address));
+            }
+            seen.push(address);
+        }
+        Ok(())
+    }
+
+    /// Checks no two disks, and no two network interfaces, share an id.
+    pub fn validate_device_ids(&self) -> Result<()> {
+        fn validate<'a>(ids: impl Iterator<Item = &'a String>) -> Result<()> {
+            let mut seen = Vec::new();
+            for id in ids {
+                if seen.contains(&id) {
+                    return Err(Error::DuplicateDeviceId(id.clone()));
+                }
+                seen.push(id);
+            }
+            Ok(())
+        }
+
+        validate(self.disks.iter().flatten().filter_map(|d| d.id.as_ref()))?;
+        validate(self.net.iter().flatten().filter_map(|n| n.id.as_ref()))
+    }
+
+    /// The number of PCI devices the VM gets: the virtio devices, counting
+    /// the virtio-iommu, and the assigned devices.
+    pub fn pci_device_count(&self) -> usize {
+        fn len<T>(list: &Option<Vec<T>>) -> usize {
+            list.as_ref().map_or(0, Vec::len)
+        }
+
+        let mut count = len(&self.disks)
+            + len(&self.scsi)
+            + len(&self.net)
+            + len(&self.fs)
+            + len(&self.pmem)
+            + len(&self.devices)
+            + len(&self.sriov_vfs)
+            + len(&self.vhost_user_net)
+            + len(&self.vhost_user_blk)
+            + len(&self.vsock)
+            + len(&self.vdpa);
+        // The virtio-rng device is always there.
+        count += 1;
+        if self.console.mode != ConsoleOutputMode::Off || self.console_ports.is_some() {
+            count += 1;
+        }
+        if self.balloon.is_some() {
+            count += 1;
+        }
+        if self.iommu {
+            count += 1;
+        }
+        count
+    }
+
+    /// Checks the devices fit on the PCI bus, when they sit on it rather
+    /// than on the MMIO transport.
+    pub fn validate_pci_device_count(&self) -> Result<()> {
+        let count = self.pci_device_count();
+        if cfg!(feature = "pci_support") && count > MAX_PCI_DEVICES {
+            return Err(Error::TooManyPciDevices(count));
+        }
+        Ok(())
+    }
+
     pub fn parse(vm_params: VmParams) -> Result<Self> {
         let mut iommu = false;
 
@@ -1142,9 +3238,25 @@ impl VmConfig {
                 }
                 disk_config_list.push(disk_config);
             }
+            if disk_config_list.iter().filter(|disk| disk.boot).count() > 1 {
+                return Err(Error::MultipleBootDisks);
+            }
             disks = Some(disk_config_list);
         }
 
+        let mut scsi: Option<Vec<ScsiConfig>> = None;
+        if let Some(scsi_list) = &vm_params.scsi {
+            let mut scsi_config_list = Vec::new();
+            for item in scsi_list.iter() {
+                let scsi_config = ScsiConfig::parse(item)?;
+                if scsi_config.iommu {
+                    iommu = true;
+                }
+                scsi_config_list.push(scsi_config);
+            }
+            scsi = Some(scsi_config_list);
+        }
+
         let mut net: Option<Vec<NetConfig>> = None;
         if let Some(net_list) = &vm_params.net {
             let mut net_config_list = Vec::new();
@@ -1185,14 +3297,26 @@ impl VmConfig {
             pmem = Some(pmem_config_list);
         }
 
-        let console = ConsoleConfig::parse(vm_params.console)?;
+        let console_profile = ConsoleProfile::parse(vm_params.console_profile.unwrap_or(""))?;
+        let mut console = match vm_params.console {
+            Some(console) => ConsoleConfig::parse(console)?,
+            None => console_profile.default_console(),
+        };
+        let mut serial = match vm_params.serial {
+            Some(serial) => ConsoleConfig::parse(serial)?,
+            None => console_profile.default_serial(),
+        };
+        if console.mode == ConsoleOutputMode::Tty && serial.mode == ConsoleOutputMode::Tty {
+            // The terminal goes to the device explicitly given it.
+            match (vm_params.serial, vm_params.console) {
+                (Some(_), None) => console.mode = ConsoleOutputMode::Off,
+                (None, Some(_)) => serial.mode = ConsoleOutputMode::Null,
+                _ => return Err(Error::ParseTTYParam),
+            }
+        }
         if console.iommu {
             iommu = true;
         }
-        let serial = ConsoleConfig::parse(vm_params.serial)?;
-        if console.mode == ConsoleOutputMode::Tty && serial.mode == ConsoleOutputMode::Tty {
-            return Err(Error::ParseTTYParam);
-        }
 
         let mut devices: Option<Vec<DeviceConfig>> = None;
         if let Some(device_list) = &vm_params.devices {
@@ -1229,6 +3353,24 @@ impl VmConfig {
             vsock = Some(vsock_config_list);
         }
 
+        let mut vdpa: Option<Vec<VdpaConfig>> = None;
+        if let Some(vdpa_list) = &vm_params.vdpa {
+            let mut vdpa_config_list = Vec::new();
+            for item in vdpa_list.iter() {
+                vdpa_config_list.push(VdpaConfig::parse(item)?);
+            }
+            vdpa = Some(vdpa_config_list);
+        }
+
+        let mut console_ports: Option<Vec<ConsolePortConfig>> = None;
+        if let Some(console_port_list) = &vm_params.console_ports {
+            let mut console_port_config_list = Vec::new();
+            for item in console_port_list.iter() {
+                console_port_config_list.push(ConsolePortConfig::parse(item)?);
+            }
+            console_ports = Some(console_port_config_list);
+        }
+
         let mut vhost_user_blk: Option<Vec<VhostUserBlkConfig>> = None;
         if let Some(vhost_user_blk_list) = &vm_params.vhost_user_blk {
             let mut vhost_user_blk_config_list = Vec::new();
@@ -1238,6 +3380,21 @@ impl VmConfig {
             vhost_user_blk = Some(vhost_user_blk_config_list);
         }
 
+        let mut memory = MemoryConfig::parse(vm_params.memory)?;
+        if let Some(memory_zone_list) = &vm_params.memory_zones {
+            let mut memory_zone_config_list = Vec::new();
+            for item in memory_zone_list.iter() {
+                memory_zone_config_list.push(MemoryZoneConfig::parse(item)?);
+            }
+            memory.zones = Some(memory_zone_config_list);
+            // The guest RAM is entirely described by the memory zones.
+            let zones_size = memory.zones_size().unwrap_or(0);
+            if memory.size != 0 && memory.size != zones_size {
+                return Err(Error::InvalidMemoryZonesSize(memory.size, zones_size));
+            }
+            memory.size = zones_size;
+        }
+
         let mut kernel: Option<KernelConfig> = None;
         if let Some(k) = vm_params.kernel {
             kernel = Some(KernelConfig {
@@ -1245,23 +3402,106 @@ impl VmConfig {
             });
         }
 
-        Ok(VmConfig {
+        let mut sriov_vfs: Option<Vec<SriovVfConfig>> = None;
+        if let Some(sriov_vf_list) = &vm_params.sriov_vfs {
+            let mut sriov_vf_config_list = Vec::new();
+            for item in sriov_vf_list.iter() {
+                let sriov_vf_config = SriovVfConfig::parse(item)?;
+                if sriov_vf_config.iommu {
+                    iommu = true;
+                }
+                sriov_vf_config_list.push(sriov_vf_config);
+            }
+            sriov_vfs = Some(sriov_vf_config_list);
+        }
+        for sriov_vf in sriov_vfs.iter().flatten().filter(|vf| vf.failover) {
+            let has_standby = net
+                .iter()
+                .flatten()
+                .any(|net| net.standby && Some(net.mac) == sriov_vf.mac);
+            if !has_standby {
+                return Err(Error::FailoverWithoutStandbyNet(
+                    sriov_vf.mac.unwrap().to_string(),
+                ));
+            }
+        }
+
+        let mut sgx_epc: Option<Vec<SgxEpcConfig>> = None;
+        if let Some(sgx_epc_list) = &vm_params.sgx_epc {
+            let mut sgx_epc_config_list = Vec::new();
+            for item in sgx_epc_list.iter() {
+                sgx_epc_config_list.push(SgxEpcConfig::parse(item)?);
+            }
+            sgx_epc = Some(sgx_epc_config_list);
+        }
+
+        let mut cgroup: Option<CgroupConfig> = None;
+        if let Some(c) = vm_params.cgroup {
+            cgroup = Some(CgroupConfig::parse(c)?);
+        }
+
+        let mut platform: Option<PlatformConfig> = None;
+        if let Some(p) = vm_params.platform {
+            platform = Some(PlatformConfig::parse(p)?);
+        }
+
+        let mut balloon: Option<BalloonConfig> = None;
+        if let Some(b) = vm_params.balloon {
+            balloon = Some(BalloonConfig::parse(b)?);
+        }
+        if memory.overcommit == MemoryOvercommit::Auto
+            && balloon.is_none()
+            && memory.hotplug_method != HotplugMethod::VirtioMem
+        {
+            return Err(Error::OvercommitWithoutDevice);
+        }
+        if balloon.is_some() && platform.as_ref().map_or(false, |p| p.sev_enabled()) {
+            return Err(Error::SevWithBalloon);
+        }
+
+        let on_panic = PanicAction::parse(vm_params.on_panic.unwrap_or(""))?;
+        let unknown_access = UnknownAccessConfig::parse(vm_params.unknown_access.unwrap_or(""))?;
+
+        let uuid = vm_params
+            .uuid
+            .map(Uuid::parse_str)
+            .transpose()
+            .map_err(Error::ParseUuid)?;
+
+        let config = VmConfig {
             cpus: CpusConfig::parse(vm_params.cpus)?,
-            memory: MemoryConfig::parse(vm_params.memory)?,
+            memory,
             kernel,
             cmdline: CmdlineConfig::parse(vm_params.cmdline)?,
             disks,
+            scsi,
             net,
             rng,
             fs,
             pmem,
             serial,
             console,
+            console_ports,
             devices,
+            sriov_vfs,
             vhost_user_net,
             vhost_user_blk,
             vsock,
+            vdpa,
             iommu,
-        })
+            cgroup,
+            sgx_epc,
+            platform,
+            balloon,
+            on_panic,
+            unknown_access,
+            uuid,
+            state_dir: vm_params.state_dir.map(PathBuf::from),
+        };
+        config.validate_pci_addresses()?;
+        config.validate_device_ids()?;
+        config.validate_pci_device_count()?;
+
+        Ok(config)
     }
 }
@@ -0,0 +1,318 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! UNIX sockets the serial port and the virtio-console can be served on, for
+//! clients such as `ch-remote console` to attach to the guest console and to
+//! detach from it at any time.
+//!
+//! One client is attached at a time, a new one taking the console over from
+//! the previous one. While nobody is attached, the guest output is buffered,
+//! up to `BUFFER_SIZE` bytes, the oldest output being discarded beyond, and
+//! handed to the next client when it attaches. The guest never blocks on its
+//! output, a client not keeping up with it getting buffered the same way.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use vmm_sys_util::eventfd::EventFd;
+
+// The guest output kept for the clients to come.
+const BUFFER_SIZE: usize = 64 << 10;
+
+#[derive(Default)]
+struct Shared {
+    client: Option<UnixStream>,
+    // The output the client hasn't taken in yet, all of it while nobody is
+    // attached.
+    buffer: VecDeque<u8>,
+}
+
+impl Shared {
+    fn push(&mut self, buf: &[u8]) {
+        let buf = &buf[buf.len().saturating_sub(BUFFER_SIZE)..];
+        let excess = (self.buffer.len() + buf.len()).saturating_sub(BUFFER_SIZE);
+        self.buffer.drain(..excess);
+        self.buffer.extend(buf);
+    }
+
+    // Sends the buffered output to the client, as much as it takes in
+    // without blocking, returning whether some is left.
+    fn flush(&mut self) -> bool {
+        let client = match self.client.as_mut() {
+            Some(client) => client,
+            None => return false,
+        };
+        while !self.buffer.is_empty() {
+            match client.write(self.buffer.as_slices().0) {
+                Ok(count) => {
+                    self.buffer.drain(..count);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                // The client going away is noticed when polling it.
+                Err(_) => break,
+            }
+        }
+        !self.buffer.is_empty()
+    }
+}
+
+pub struct ConsoleSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    shared: Arc<Mutex<Shared>>,
+    // Tells the reader thread output is pending, for it to wait for the
+    // client to take it in.
+    pending_evt: EventFd,
+}
+
+impl ConsoleSocket {
+    /// Binds the socket the clients connect to at `path`.
+    pub fn new(path: &Path) -> io::Result<ConsoleSocket> {
+        // A socket left behind by a previous run would prevent the binding.
+        fs::remove_file(path).unwrap_or_default();
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(ConsoleSocket {
+            listener,
+            path: path.to_path_buf(),
+            shared: Arc::new(Mutex::new(Shared::default())),
+            pending_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Where the guest output is written to.
+    pub fn writer(&self) -> io::Result<ConsoleSocketWriter> {
+        Ok(ConsoleSocketWriter {
+            shared: self.shared.clone(),
+            pending_evt: self.pending_evt.try_clone()?,
+        })
+    }
+
+    // Attaches a new client, detaching the previous one if any.
+    fn attach(&self, client: UnixStream) -> io::Result<UnixStream> {
+        client.set_nonblocking(true)?;
+        let reader = client.try_clone()?;
+        info!("Client attached to {:?}", self.path);
+        let mut shared = self.shared.lock().unwrap();
+        shared.client = Some(client);
+        shared.flush();
+        Ok(reader)
+    }
+
+    fn detach(&self) {
+        info!("Client detached from {:?}", self.path);
+        self.shared.lock().unwrap().client = None;
+    }
+
+    /// Accepts the clients and passes their input to `input`, from a thread
+    /// of its own, until the returned reader is dropped.
+    pub fn spawn_reader<F>(self, name: &str, mut input: F) -> io::Result<ConsoleSocketReader>
+    where
+        F: FnMut(&[u8]) + Send + 'static,
+    {
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+        let thread_kill_evt = kill_evt.try_clone()?;
+
+        let thread = thread::Builder::new()
+            .name(format!("{}_socket", name))
+            .spawn(move || {
+                let socket = self;
+                let mut client: Option<UnixStream> = None;
+                let mut buf = [0u8; 64];
+                loop {
+                    let pending = socket.shared.lock().unwrap().flush();
+                    let mut fds = vec![
+                        libc::pollfd {
+                            fd: thread_kill_evt.as_raw_fd(),
+                            events: libc::POLLIN,
+                            revents: 0,
+                        },
+                        libc::pollfd {
+                            fd: socket.pending_evt.as_raw_fd(),
+                            events: libc::POLLIN,
+                            revents: 0,
+                        },
+                        libc::pollfd {
+                            fd: socket.listener.as_raw_fd(),
+                            events: libc::POLLIN,
+                            revents: 0,
+                        },
+                    ];
+                    if let Some(client) = &client {
+                        fds.push(libc::pollfd {
+                            fd: client.as_raw_fd(),
+                            events: if pending {
+                                libc::POLLIN | libc::POLLOUT
+                            } else {
+                                libc::POLLIN
+                            },
+                            revents: 0,
+                        });
+                    }
+
+                    // Safe because the fds are valid for as long as the
+                    // thread runs, and we check the return value.
+                    let ret =
+                        unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+                    if ret < 0 {
+                        let e = io::Error::last_os_error();
+                        if e.kind() == io::ErrorKind::Interrupted {
+                            continue;
+                        }
+                        error!("Failed polling {:?}: {}", socket.path, e);
+                        break;
+                    }
+                    if fds[0].revents != 0 {
+                        break;
+                    }
+                    if fds[1].revents != 0 {
+                        // The output is flushed at the next iteration.
+                        let _ = socket.pending_evt.read();
+                    }
+                    if fds[2].revents != 0 {
+                        match socket.listener.accept() {
+                            Ok((stream, _)) => match socket.attach(stream) {
+                                Ok(reader) => {
+                                    client = Some(reader);
+                                    continue;
+                                }
+                                Err(e) => error!("Failed attaching to {:?}: {}", socket.path, e),
+                            },
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                            Err(e) => error!("Failed accepting on {:?}: {}", socket.path, e),
+                        }
+                    }
+                    let revents = fds.get(3).map(|fd| fd.revents).unwrap_or(0);
+                    if revents & libc::POLLIN != 0 {
+                        match client.as_mut().unwrap().read(&mut buf) {
+                            Ok(0) => {
+                                client = None;
+                                socket.detach();
+                            }
+                            Ok(count) => input(&buf[..count]),
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                            Err(_) => {
+                                client = None;
+                                socket.detach();
+                            }
+                        }
+                    } else if revents & (libc::POLLHUP | libc::POLLERR) != 0 {
+                        client = None;
+                        socket.detach();
+                    }
+                }
+                fs::remove_file(&socket.path).unwrap_or_default();
+            })?;
+
+        Ok(ConsoleSocketReader {
+            kill_evt,
+            thread: Some(thread),
+        })
+    }
+}
+
+pub struct ConsoleSocketWriter {
+    shared: Arc<Mutex<Shared>>,
+    pending_evt: EventFd,
+}
+
+impl Write for ConsoleSocketWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut shared = self.shared.lock().unwrap();
+        shared.push(buf);
+        if shared.flush() {
+            self.pending_evt.write(1)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct ConsoleSocketReader {
+    kill_evt: EventFd,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for ConsoleSocketReader {
+    fn drop(&mut self) {
+        let _ = self.kill_evt.write(1);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    #[test]
+    fn test_buffer() {
+        let mut shared = Shared::default();
+        shared.push(&[0u8; BUFFER_SIZE - 1]);
+        shared.push(b"ab");
+        assert_eq!(shared.buffer.len(), BUFFER_SIZE);
+        assert_eq!(shared.buffer.back(), Some(&b'b'));
+        assert_eq!(shared.buffer[BUFFER_SIZE - 2], b'a');
+
+        shared.push(&[1u8; BUFFER_SIZE + 1]);
+        assert!(shared.buffer.iter().all(|b| *b == 1));
+        assert_eq!(shared.buffer.len(), BUFFER_SIZE);
+    }
+
+    #[test]
+    fn test_console_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("console.sock");
+        let socket = ConsoleSocket::new(&path).unwrap();
+        let mut writer = socket.writer().unwrap();
+        let (sender, receiver) = channel();
+        let reader = socket
+            .spawn_reader("test", move |input| sender.send(input.to_vec()).unwrap())
+            .unwrap();
+
+        // Buffered while nobody is attached.
+        writer.write_all(b"boot\n").unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut output = [0u8; 5];
+        client.read_exact(&mut output).unwrap();
+        assert_eq!(&output, b"boot\n");
+
+        client.write_all(b"a").unwrap();
+        assert_eq!(receiver.recv().unwrap(), b"a".to_vec());
+
+        // The output while detached goes to the next client.
+        drop(client);
+        writer.write_all(b"login:").unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut output = [0u8; 6];
+        client.read_exact(&mut output).unwrap();
+        assert_eq!(&output, b"login:");
+
+        drop(reader);
+        assert!(!path.exists());
+    }
+}
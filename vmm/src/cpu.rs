@@ -8,24 +8,36 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
+use crate::cgroup::VmCgroups;
+use crate::config::CpuFeatureConfig;
+use crate::config::{UnknownAccessAction, UnknownAccessConfig};
 use crate::device_manager::DeviceManager;
+use crate::memory_manager::SgxEpcSection;
+use crate::sev::SevGuest;
 #[cfg(feature = "acpi")]
 use acpi_tables::{aml, aml::Aml, sdt::SDT};
+use anyhow::anyhow;
 #[cfg(feature = "acpi")]
 use arch::layout;
 use devices::{ioapic, BusDevice};
-use kvm_bindings::CpuId;
+use kvm_bindings::{
+    kvm_cpuid_entry2, kvm_mp_state, kvm_msr_entry, CpuId, Msrs, KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+    KVM_MP_STATE_UNINITIALIZED,
+};
 use kvm_ioctls::*;
 use libc::{c_void, siginfo_t};
 use std::cmp;
+use std::mem;
 use std::os::unix::thread::JoinHandleExt;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
 use std::sync::{Arc, Barrier, Mutex, Weak};
 use std::thread;
+use std::time::Duration;
 use std::{fmt, io, result};
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
-use vm_memory::{Address, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
+use vm_memory::{Address, GuestAddress};
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::ioctl_with_val;
 use vmm_sys_util::signal::{register_signal_handler, SIGRTMIN};
 
 // Debug I/O port
@@ -118,11 +130,116 @@ pub enum Error {
 
     /// Asking for more vCPUs that we can have
     DesiredVCPUCountExceedsMax,
+
+    /// Unknown CPUID feature name
+    UnknownCpuidFeature(String),
+
+    /// Unknown CPU model name
+    UnknownCpuModel(String),
+
+    /// A feature of the CPU model is not supported by the host.
+    CpuModelFeatureNotSupported(String),
+
+    /// The call to KVM_SET_TSC_KHZ failed.
+    SetTscKhz(io::Error),
+
+    /// SGX is not supported by the host.
+    SgxNotSupported,
+
+    /// Failed to describe the SGX EPC sections through CPUID.
+    SgxEpcCpuid(vmm_sys_util::fam::Error),
+
+    /// Failed to complete the SEV guest launch.
+    SevLaunch(crate::sev::Error),
+
+    /// Nested virtualization is not supported by the host.
+    NestedNotSupported,
+
+    /// Cannot reset the multiprocessing state of a vCPU.
+    VcpuSetMpState(kvm_ioctls::Error),
+
+    /// Failed to describe the Hyper-V enlightenments through CPUID.
+    HypervCpuid(vmm_sys_util::fam::Error),
+
+    /// The host CPU frequencies can't be found.
+    CpuFrequencyUnavailable,
+
+    /// Failed to describe the CPU frequencies through CPUID.
+    FrequencyCpuid(vmm_sys_util::fam::Error),
+
+    /// Failed to allocate the CPPC registers MMIO range.
+    AllocateCppcRegisters,
+
+    /// Cannot get the list of the MSRs to save.
+    MsrIndexList(kvm_ioctls::Error),
+
+    /// Cannot get the state of a vCPU.
+    VcpuGetState(kvm_ioctls::Error),
+
+    /// Cannot set the state of a vCPU.
+    VcpuSetState(kvm_ioctls::Error),
+
+    /// Cannot set the MSR of a vCPU.
+    VcpuSetMsr(u32),
+
+    /// The saved state of a vCPU doesn't match the KVM structures.
+    InvalidVcpuState,
 }
 pub type Result<T> = result::Result<T, Error>;
 
+// SGX support bit, and leaf describing the SGX capabilities.
+const SGX_EBX_BIT: u8 = 2;
+const SGX_CPUID_LEAF: u32 = 0x12;
+// First sub-leaf of the SGX leaf enumerating the EPC sections.
+const SGX_EPC_FIRST_SUBLEAF: u32 = 2;
+
+// VMX and SVM support bits, for Intel and AMD hardware virtualization.
+const VMX_ECX_BIT: u8 = 5;
+const SVM_ECX_BIT: u8 = 2;
+
+// Hyper-V CPUID leaves, as defined by the Hypervisor Top Level Functional
+// Specification. The KVM leaves usually found at the same base are moved
+// past them, where KVM still looks for its signature.
+const HYPERV_CPUID_VENDOR_AND_MAX_FUNCTIONS: u32 = 0x4000_0000;
+const HYPERV_CPUID_INTERFACE: u32 = 0x4000_0001;
+const HYPERV_CPUID_VERSION: u32 = 0x4000_0002;
+const HYPERV_CPUID_FEATURES: u32 = 0x4000_0003;
+const HYPERV_CPUID_ENLIGHTMENT_INFO: u32 = 0x4000_0004;
+const HYPERV_CPUID_IMPLEMENT_LIMITS: u32 = 0x4000_0005;
+const KVM_CPUID_HYPERV_OFFSET: u32 = 0x100;
+
+// "Microsoft Hv" and "Hv#1" signatures.
+const HYPERV_VENDOR_EBX: u32 = 0x7263_694d;
+const HYPERV_VENDOR_ECX: u32 = 0x666f_736f;
+const HYPERV_VENDOR_EDX: u32 = 0x7648_2074;
+const HYPERV_INTERFACE_EAX: u32 = 0x3123_7648;
+
+// Reported as Windows Server 2016, version 10.0 build 14393.
+const HYPERV_VERSION_BUILD: u32 = 0x3839;
+const HYPERV_VERSION_MAJOR_MINOR: u32 = 0x000a_0000;
+
+// Partition privileges, for the synthetic MSRs implemented by KVM.
+const HV_MSR_TIME_REF_COUNT_AVAILABLE: u32 = 1 << 1;
+const HV_MSR_HYPERCALL_AVAILABLE: u32 = 1 << 5;
+const HV_MSR_VP_INDEX_AVAILABLE: u32 = 1 << 6;
+const HV_MSR_REFERENCE_TSC_AVAILABLE: u32 = 1 << 9;
+
+// Recommendations, spinlocks are never to be notified to the hypervisor.
+const HV_X64_RELAXED_TIMING_RECOMMENDED: u32 = 1 << 5;
+const HV_SPINLOCK_NEVER_NOTIFY: u32 = 0xffff_ffff;
+
+// Processor frequency information leaf, reporting the base, maximum and bus
+// frequencies in MHz.
+const FREQUENCY_CPUID_LEAF: u32 = 0x16;
+
+// Host cpufreq attributes, in kHz, used when the frequency leaf is missing.
+const CPUFREQ_SYSFS_PATH: &str = "/sys/devices/system/cpu/cpu0/cpufreq";
+
+// _IO(KVMIO, 0xa2), not wrapped by kvm-ioctls.
+const KVM_SET_TSC_KHZ: libc::c_ulong = 0xaea2;
+
 #[allow(dead_code)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 enum CpuidReg {
     EAX,
     EBX,
@@ -130,6 +247,343 @@ enum CpuidReg {
     EDX,
 }
 
+// CPUID feature bits which can be forced on or off through the CPUs
+// configuration. Names follow the ones from /proc/cpuinfo, except for
+// invtsc which has no dedicated flag there, and for the KVM paravirtual
+// features which are named after their KVM_FEATURE_* definition.
+const CPUID_FEATURES: &[(&str, u32, u32, CpuidReg, u8)] = &[
+    ("sse3", 0x1, 0, CpuidReg::ECX, 0),
+    ("pclmulqdq", 0x1, 0, CpuidReg::ECX, 1),
+    ("vmx", 0x1, 0, CpuidReg::ECX, 5),
+    ("ssse3", 0x1, 0, CpuidReg::ECX, 9),
+    ("fma", 0x1, 0, CpuidReg::ECX, 12),
+    ("cx16", 0x1, 0, CpuidReg::ECX, 13),
+    ("pcid", 0x1, 0, CpuidReg::ECX, 17),
+    ("sse4_1", 0x1, 0, CpuidReg::ECX, 19),
+    ("sse4_2", 0x1, 0, CpuidReg::ECX, 20),
+    ("x2apic", 0x1, 0, CpuidReg::ECX, 21),
+    ("movbe", 0x1, 0, CpuidReg::ECX, 22),
+    ("popcnt", 0x1, 0, CpuidReg::ECX, 23),
+    ("aes", 0x1, 0, CpuidReg::ECX, 25),
+    ("xsave", 0x1, 0, CpuidReg::ECX, 26),
+    ("avx", 0x1, 0, CpuidReg::ECX, 28),
+    ("f16c", 0x1, 0, CpuidReg::ECX, 29),
+    ("rdrand", 0x1, 0, CpuidReg::ECX, 30),
+    ("mtrr", 0x1, 0, CpuidReg::EDX, 12),
+    ("pse36", 0x1, 0, CpuidReg::EDX, 17),
+    ("ss", 0x1, 0, CpuidReg::EDX, 27),
+    ("ht", 0x1, 0, CpuidReg::EDX, 28),
+    ("fsgsbase", 0x7, 0, CpuidReg::EBX, 0),
+    ("bmi1", 0x7, 0, CpuidReg::EBX, 3),
+    ("hle", 0x7, 0, CpuidReg::EBX, 4),
+    ("avx2", 0x7, 0, CpuidReg::EBX, 5),
+    ("smep", 0x7, 0, CpuidReg::EBX, 7),
+    ("bmi2", 0x7, 0, CpuidReg::EBX, 8),
+    ("erms", 0x7, 0, CpuidReg::EBX, 9),
+    ("invpcid", 0x7, 0, CpuidReg::EBX, 10),
+    ("rtm", 0x7, 0, CpuidReg::EBX, 11),
+    ("mpx", 0x7, 0, CpuidReg::EBX, 14),
+    ("avx512f", 0x7, 0, CpuidReg::EBX, 16),
+    ("avx512dq", 0x7, 0, CpuidReg::EBX, 17),
+    ("rdseed", 0x7, 0, CpuidReg::EBX, 18),
+    ("adx", 0x7, 0, CpuidReg::EBX, 19),
+    ("smap", 0x7, 0, CpuidReg::EBX, 20),
+    ("avx512ifma", 0x7, 0, CpuidReg::EBX, 21),
+    ("clflushopt", 0x7, 0, CpuidReg::EBX, 23),
+    ("clwb", 0x7, 0, CpuidReg::EBX, 24),
+    ("avx512pf", 0x7, 0, CpuidReg::EBX, 26),
+    ("avx512er", 0x7, 0, CpuidReg::EBX, 27),
+    ("avx512cd", 0x7, 0, CpuidReg::EBX, 28),
+    ("sha_ni", 0x7, 0, CpuidReg::EBX, 29),
+    ("avx512bw", 0x7, 0, CpuidReg::EBX, 30),
+    ("avx512vl", 0x7, 0, CpuidReg::EBX, 31),
+    ("avx512vbmi", 0x7, 0, CpuidReg::ECX, 1),
+    ("umip", 0x7, 0, CpuidReg::ECX, 2),
+    ("pku", 0x7, 0, CpuidReg::ECX, 3),
+    ("avx512_vbmi2", 0x7, 0, CpuidReg::ECX, 6),
+    ("gfni", 0x7, 0, CpuidReg::ECX, 8),
+    ("vaes", 0x7, 0, CpuidReg::ECX, 9),
+    ("vpclmulqdq", 0x7, 0, CpuidReg::ECX, 10),
+    ("avx512_vnni", 0x7, 0, CpuidReg::ECX, 11),
+    ("avx512_bitalg", 0x7, 0, CpuidReg::ECX, 12),
+    ("avx512_vpopcntdq", 0x7, 0, CpuidReg::ECX, 14),
+    ("rdpid", 0x7, 0, CpuidReg::ECX, 22),
+    ("avx512_4vnniw", 0x7, 0, CpuidReg::EDX, 2),
+    ("avx512_4fmaps", 0x7, 0, CpuidReg::EDX, 3),
+    ("md_clear", 0x7, 0, CpuidReg::EDX, 10),
+    ("arch_capabilities", 0x7, 0, CpuidReg::EDX, 29),
+    ("avx_vnni", 0x7, 1, CpuidReg::EAX, 4),
+    ("avx512_bf16", 0x7, 1, CpuidReg::EAX, 5),
+    ("xsaveopt", 0xd, 1, CpuidReg::EAX, 0),
+    ("xsavec", 0xd, 1, CpuidReg::EAX, 1),
+    ("xgetbv1", 0xd, 1, CpuidReg::EAX, 2),
+    ("xsaves", 0xd, 1, CpuidReg::EAX, 3),
+    ("lahf_lm", 0x8000_0001, 0, CpuidReg::ECX, 0),
+    ("svm", 0x8000_0001, 0, CpuidReg::ECX, 2),
+    ("abm", 0x8000_0001, 0, CpuidReg::ECX, 5),
+    ("sse4a", 0x8000_0001, 0, CpuidReg::ECX, 6),
+    ("3dnowprefetch", 0x8000_0001, 0, CpuidReg::ECX, 8),
+    ("pdpe1gb", 0x8000_0001, 0, CpuidReg::EDX, 26),
+    ("rdtscp", 0x8000_0001, 0, CpuidReg::EDX, 27),
+    ("invtsc", 0x8000_0007, 0, CpuidReg::EDX, 8),
+    ("kvm_clocksource", 0x4000_0001, 0, CpuidReg::EAX, 0),
+    ("kvm_nopiodelay", 0x4000_0001, 0, CpuidReg::EAX, 1),
+    ("kvm_clocksource2", 0x4000_0001, 0, CpuidReg::EAX, 3),
+    ("kvm_async_pf", 0x4000_0001, 0, CpuidReg::EAX, 4),
+    ("kvm_steal_time", 0x4000_0001, 0, CpuidReg::EAX, 5),
+    ("kvm_pv_eoi", 0x4000_0001, 0, CpuidReg::EAX, 6),
+    ("kvm_pv_unhalt", 0x4000_0001, 0, CpuidReg::EAX, 7),
+    ("kvm_pv_tlb_flush", 0x4000_0001, 0, CpuidReg::EAX, 9),
+    ("kvm_async_pf_vmexit", 0x4000_0001, 0, CpuidReg::EAX, 10),
+    ("kvm_pv_send_ipi", 0x4000_0001, 0, CpuidReg::EAX, 11),
+    ("kvm_poll_control", 0x4000_0001, 0, CpuidReg::EAX, 12),
+    ("kvm_pv_sched_yield", 0x4000_0001, 0, CpuidReg::EAX, 13),
+];
+
+// Named CPU models, as baselines of the CPUID features above. Each model is
+// described as its predecessor plus the features it introduced. The
+// virtualization related features (vmx, svm, invtsc and the KVM paravirtual
+// ones) are not part of the models, being driven by other settings. The
+// models whitelist the feature bits, any other one being cleared.
+const NEHALEM_FEATURES: &[&str] = &[
+    "sse3", "ssse3", "cx16", "sse4_1", "sse4_2", "x2apic", "popcnt", "mtrr", "pse36", "lahf_lm",
+    "rdtscp",
+];
+const WESTMERE_FEATURES: &[&str] = &["pclmulqdq", "aes"];
+const SANDYBRIDGE_FEATURES: &[&str] = &["xsave", "avx", "xsaveopt"];
+const HASWELL_FEATURES: &[&str] = &[
+    "fma", "pcid", "movbe", "f16c", "rdrand", "fsgsbase", "bmi1", "avx2", "smep", "bmi2", "erms",
+    "invpcid", "abm",
+];
+const BROADWELL_FEATURES: &[&str] = &["rdseed", "adx", "smap", "3dnowprefetch"];
+const SKYLAKE_CLIENT_FEATURES: &[&str] = &["clflushopt", "xsavec", "xgetbv1"];
+const SKYLAKE_SERVER_FEATURES: &[&str] = &[
+    "pku", "clwb", "avx512f", "avx512dq", "avx512cd", "avx512bw", "avx512vl", "pdpe1gb",
+];
+const CASCADELAKE_SERVER_FEATURES: &[&str] = &["avx512_vnni"];
+const EPYC_FEATURES: &[&str] = &[
+    "sse3",
+    "pclmulqdq",
+    "ssse3",
+    "fma",
+    "cx16",
+    "sse4_1",
+    "sse4_2",
+    "x2apic",
+    "movbe",
+    "popcnt",
+    "aes",
+    "xsave",
+    "avx",
+    "f16c",
+    "rdrand",
+    "mtrr",
+    "pse36",
+    "fsgsbase",
+    "bmi1",
+    "avx2",
+    "smep",
+    "bmi2",
+    "rdseed",
+    "adx",
+    "smap",
+    "clflushopt",
+    "sha_ni",
+    "lahf_lm",
+    "abm",
+    "sse4a",
+    "3dnowprefetch",
+    "pdpe1gb",
+    "rdtscp",
+    "xsaveopt",
+    "xsavec",
+    "xgetbv1",
+];
+
+struct CpuModel {
+    name: &'static str,
+    vendor: &'static [u8; 12],
+    family: u32,
+    model: u32,
+    stepping: u32,
+    features: &'static [&'static [&'static str]],
+}
+
+const INTEL_VENDOR: &[u8; 12] = b"GenuineIntel";
+const AMD_VENDOR: &[u8; 12] = b"AuthenticAMD";
+
+const CPU_MODELS: &[CpuModel] = &[
+    CpuModel {
+        name: "nehalem",
+        vendor: INTEL_VENDOR,
+        family: 6,
+        model: 26,
+        stepping: 3,
+        features: &[NEHALEM_FEATURES],
+    },
+    CpuModel {
+        name: "westmere",
+        vendor: INTEL_VENDOR,
+        family: 6,
+        model: 44,
+        stepping: 1,
+        features: &[NEHALEM_FEATURES, WESTMERE_FEATURES],
+    },
+    CpuModel {
+        name: "sandybridge",
+        vendor: INTEL_VENDOR,
+        family: 6,
+        model: 42,
+        stepping: 1,
+        features: &[NEHALEM_FEATURES, WESTMERE_FEATURES, SANDYBRIDGE_FEATURES],
+    },
+    CpuModel {
+        name: "haswell",
+        vendor: INTEL_VENDOR,
+        family: 6,
+        model: 60,
+        stepping: 4,
+        features: &[
+            NEHALEM_FEATURES,
+            WESTMERE_FEATURES,
+            SANDYBRIDGE_FEATURES,
+            HASWELL_FEATURES,
+        ],
+    },
+    CpuModel {
+        name: "broadwell",
+        vendor: INTEL_VENDOR,
+        family: 6,
+        model: 61,
+        stepping: 2,
+        features: &[
+            NEHALEM_FEATURES,
+            WESTMERE_FEATURES,
+            SANDYBRIDGE_FEATURES,
+            HASWELL_FEATURES,
+            BROADWELL_FEATURES,
+        ],
+    },
+    CpuModel {
+        name: "skylake-client",
+        vendor: INTEL_VENDOR,
+        family: 6,
+        model: 94,
+        stepping: 3,
+        features: &[
+            NEHALEM_FEATURES,
+            WESTMERE_FEATURES,
+            SANDYBRIDGE_FEATURES,
+            HASWELL_FEATURES,
+            BROADWELL_FEATURES,
+            SKYLAKE_CLIENT_FEATURES,
+        ],
+    },
+    CpuModel {
+        name: "skylake-server",
+        vendor: INTEL_VENDOR,
+        family: 6,
+        model: 85,
+        stepping: 4,
+        features: &[
+            NEHALEM_FEATURES,
+            WESTMERE_FEATURES,
+            SANDYBRIDGE_FEATURES,
+            HASWELL_FEATURES,
+            BROADWELL_FEATURES,
+            SKYLAKE_CLIENT_FEATURES,
+            SKYLAKE_SERVER_FEATURES,
+        ],
+    },
+    CpuModel {
+        name: "cascadelake-server",
+        vendor: INTEL_VENDOR,
+        family: 6,
+        model: 85,
+        stepping: 6,
+        features: &[
+            NEHALEM_FEATURES,
+            WESTMERE_FEATURES,
+            SANDYBRIDGE_FEATURES,
+            HASWELL_FEATURES,
+            BROADWELL_FEATURES,
+            SKYLAKE_CLIENT_FEATURES,
+            SKYLAKE_SERVER_FEATURES,
+            CASCADELAKE_SERVER_FEATURES,
+        ],
+    },
+    CpuModel {
+        name: "epyc",
+        vendor: AMD_VENDOR,
+        family: 23,
+        model: 1,
+        stepping: 2,
+        features: &[EPYC_FEATURES],
+    },
+];
+
+const CPU_MODEL_EXEMPT_FEATURES: &[&str] = &["vmx", "svm", "invtsc"];
+
+// The CPUID registers made of feature bits, which the CPU models filter.
+const CPU_MODEL_FILTERED_REGS: &[(u32, u32, CpuidReg)] = &[
+    (0x1, 0, CpuidReg::ECX),
+    (0x1, 0, CpuidReg::EDX),
+    (0x7, 0, CpuidReg::EBX),
+    (0x7, 0, CpuidReg::ECX),
+    (0x7, 0, CpuidReg::EDX),
+    (0x7, 1, CpuidReg::EAX),
+    (0xd, 1, CpuidReg::EAX),
+    (0x8000_0001, 0, CpuidReg::ECX),
+    (0x8000_0001, 0, CpuidReg::EDX),
+];
+
+// The bits of the filtered registers every model keeps: the architectural
+// features of any x86-64 CPU, and the ones driven by KVM or the VMM.
+const CPU_MODEL_BASELINE: &[(u32, u32, CpuidReg, u32)] = &[
+    // TSC deadline timer, OSXSAVE and hypervisor.
+    (0x1, 0, CpuidReg::ECX, 0x8900_0000),
+    // FPU, VME, DE, PSE, TSC, MSR, PAE, MCE, CX8, APIC, SEP, PGE, MCA, CMOV,
+    // PAT, CLFLUSH, MMX, FXSR, SSE and SSE2.
+    (0x1, 0, CpuidReg::EDX, 0x0789_ebff),
+    // The leaf 1 features AMD mirrors, SYSCALL, NX and long mode.
+    (0x8000_0001, 0, CpuidReg::EDX, 0x2193_fbff),
+];
+
+// XSAVE state components, and the features they come with. x87 and SSE are
+// always there.
+const XSAVE_CPUID_LEAF: u32 = 0xd;
+const XSAVE_LEGACY_COMPONENTS: u64 = 0x3;
+const XSAVE_COMPONENTS: &[(u32, &str)] = &[
+    (2, "avx"),
+    (3, "mpx"),
+    (4, "mpx"),
+    (5, "avx512f"),
+    (6, "avx512f"),
+    (7, "avx512f"),
+    (9, "pku"),
+];
+// The legacy region and the XSAVE header.
+const XSAVE_LEGACY_AREA_SIZE: u32 = 576;
+
+fn cpuid_reg(entry: &mut kvm_cpuid_entry2, reg: CpuidReg) -> &mut u32 {
+    match reg {
+        CpuidReg::EAX => &mut entry.eax,
+        CpuidReg::EBX => &mut entry.ebx,
+        CpuidReg::ECX => &mut entry.ecx,
+        CpuidReg::EDX => &mut entry.edx,
+    }
+}
+
+// The leaf 1 EAX processor signature.
+fn cpuid_signature(family: u32, model: u32, stepping: u32) -> u32 {
+    let (family, extended_family) = if family > 0xf {
+        (0xf, family - 0xf)
+    } else {
+        (family, 0)
+    };
+    extended_family << 20 | (model >> 4) << 16 | family << 8 | (model & 0xf) << 4 | stepping
+}
+
 pub struct CpuidPatch {
     pub function: u32,
     pub index: u32,
@@ -195,6 +649,413 @@ impl CpuidPatch {
             }
         }
     }
+
+    /// Force the CPUID bits of the named features on or off. This is applied
+    /// on top of the supported CPUID, so that a set of hosts can expose the
+    /// same CPU model to their guests.
+    pub fn patch_cpuid_features(cpuid: &mut CpuId, features: &[CpuFeatureConfig]) -> Result<()> {
+        for feature in features.iter() {
+            let (_, function, index, reg, bit) = CPUID_FEATURES
+                .iter()
+                .find(|f| f.0 == feature.name)
+                .ok_or_else(|| Error::UnknownCpuidFeature(feature.name.clone()))?;
+
+            let mut found = false;
+            for entry in cpuid.as_mut_slice().iter_mut() {
+                if entry.function != *function || entry.index != *index {
+                    continue;
+                }
+                let value = match reg {
+                    CpuidReg::EAX => &mut entry.eax,
+                    CpuidReg::EBX => &mut entry.ebx,
+                    CpuidReg::ECX => &mut entry.ecx,
+                    CpuidReg::EDX => &mut entry.edx,
+                };
+                if feature.enabled {
+                    *value |= 1 << *bit;
+                } else {
+                    *value &= !(1 << *bit);
+                }
+                found = true;
+            }
+
+            if !found {
+                warn!(
+                    "CPUID leaf 0x{:x} not available, ignoring feature {}",
+                    function, feature.name
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl CpuidPatch {
+    /// Restrict the CPUID features to the ones of a named CPU model, so that
+    /// guests can migrate between any hosts supporting this model. All the
+    /// features of the model must be supported by the host. The vendor,
+    /// family and model are the ones of the CPU model as well.
+    pub fn patch_cpuid_model(cpuid: &mut CpuId, model: &str) -> Result<()> {
+        let model = CPU_MODELS
+            .iter()
+            .find(|m| m.name == model)
+            .ok_or_else(|| Error::UnknownCpuModel(model.to_string()))?;
+        let has_feature = |name: &str| model.features.iter().any(|set| set.contains(&name));
+
+        // The bits kept in each filtered register.
+        let mut allowed: Vec<(u32, u32, CpuidReg, u32)> = CPU_MODEL_FILTERED_REGS
+            .iter()
+            .map(|(function, index, reg)| (*function, *index, *reg, 0))
+            .collect();
+        let mut allow = |function: u32, index: u32, reg: CpuidReg, mask: u32| {
+            if let Some(allowed) = allowed
+                .iter_mut()
+                .find(|a| a.0 == function && a.1 == index && a.2 == reg)
+            {
+                allowed.3 |= mask;
+            }
+        };
+        for (function, index, reg, mask) in CPU_MODEL_BASELINE.iter() {
+            allow(*function, *index, *reg, *mask);
+        }
+        for (name, function, index, reg, bit) in CPUID_FEATURES.iter() {
+            if CPU_MODEL_EXEMPT_FEATURES.contains(name) {
+                allow(*function, *index, *reg, 1 << *bit);
+                continue;
+            }
+            if !has_feature(name) {
+                continue;
+            }
+
+            let supported = cpuid.as_mut_slice().iter_mut().any(|entry| {
+                entry.function == *function
+                    && entry.index == *index
+                    && *cpuid_reg(entry, *reg) & (1 << *bit) != 0
+            });
+            if !supported {
+                return Err(Error::CpuModelFeatureNotSupported(name.to_string()));
+            }
+            allow(*function, *index, *reg, 1 << *bit);
+        }
+
+        for entry in cpuid.as_mut_slice().iter_mut() {
+            for (function, index, reg, mask) in allowed.iter() {
+                if entry.function == *function && entry.index == *index {
+                    *cpuid_reg(entry, *reg) &= *mask;
+                }
+            }
+        }
+
+        Self::patch_cpuid_model_xsave(cpuid, &has_feature);
+
+        let signature = cpuid_signature(model.family, model.model, model.stepping);
+        let vendor = |offset: usize| {
+            let mut value = [0u8; 4];
+            value.copy_from_slice(&model.vendor[offset..offset + 4]);
+            u32::from_le_bytes(value)
+        };
+        for entry in cpuid.as_mut_slice().iter_mut() {
+            match entry.function {
+                0x0 => {
+                    entry.ebx = vendor(0);
+                    entry.edx = vendor(4);
+                    entry.ecx = vendor(8);
+                }
+                0x1 => entry.eax = signature,
+                // AMD repeats the signature in the extended leaf.
+                0x8000_0001 if model.vendor == AMD_VENDOR => entry.eax = signature,
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    // The XSAVE state components are the ones of the model features, and the
+    // XSAVE area sizes follow. The size for the components currently enabled
+    // is maintained by KVM.
+    fn patch_cpuid_model_xsave(cpuid: &mut CpuId, has_feature: &dyn Fn(&str) -> bool) {
+        let components = XSAVE_COMPONENTS
+            .iter()
+            .filter(|(_, feature)| has_feature(feature))
+            .fold(XSAVE_LEGACY_COMPONENTS, |components, (component, _)| {
+                components | 1 << component
+            });
+
+        let mut area_size = XSAVE_LEGACY_AREA_SIZE;
+        for entry in cpuid.as_mut_slice().iter_mut() {
+            if entry.function != XSAVE_CPUID_LEAF || entry.index < 2 {
+                continue;
+            }
+            if entry.index < 64 && components & (1 << entry.index) != 0 {
+                area_size = cmp::max(area_size, entry.ebx + entry.eax);
+            } else {
+                *entry = kvm_cpuid_entry2 {
+                    function: entry.function,
+                    index: entry.index,
+                    flags: entry.flags,
+                    ..Default::default()
+                };
+            }
+        }
+
+        for entry in cpuid.as_mut_slice().iter_mut() {
+            match (entry.function, entry.index) {
+                (XSAVE_CPUID_LEAF, 0) => {
+                    entry.eax &= components as u32;
+                    entry.edx &= (components >> 32) as u32;
+                    entry.ecx = area_size;
+                }
+                // No supervisor state without XSAVES.
+                (XSAVE_CPUID_LEAF, 1) if entry.eax & (1 << 3) == 0 => {
+                    entry.ecx = 0;
+                    entry.edx = 0;
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+impl CpuidPatch {
+    /// Expose, or hide, VMX or SVM to the guest. KVM only reports them as
+    /// supported when the host allows nested virtualization, which is then
+    /// required to enable it.
+    pub fn patch_cpuid_nested(cpuid: &mut CpuId, enabled: bool) -> Result<()> {
+        let mut supported = false;
+        for entry in cpuid.as_mut_slice().iter_mut() {
+            let bit = match (entry.function, entry.index) {
+                (0x1, 0) => VMX_ECX_BIT,
+                (0x8000_0001, 0) => SVM_ECX_BIT,
+                _ => continue,
+            };
+            if entry.ecx & (1 << bit) != 0 {
+                supported = true;
+            }
+            if !enabled {
+                entry.ecx &= !(1 << bit);
+            }
+        }
+
+        if enabled && !supported {
+            return Err(Error::NestedNotSupported);
+        }
+
+        Ok(())
+    }
+}
+
+impl CpuidPatch {
+    /// Expose the Hyper-V enlightenments Windows guests rely on: the
+    /// reference TSC page and time reference counter, the VP index, and the
+    /// relaxed timing recommendation. The synthetic MSRs are handled by KVM,
+    /// only the CPUID leaves advertising them are needed.
+    pub fn patch_cpuid_hyperv(cpuid: &mut CpuId, max_vcpus: u8) -> Result<()> {
+        for entry in cpuid.as_mut_slice().iter_mut() {
+            if entry.function & 0xffff_ff00 == HYPERV_CPUID_VENDOR_AND_MAX_FUNCTIONS {
+                if entry.function == HYPERV_CPUID_VENDOR_AND_MAX_FUNCTIONS {
+                    entry.eax += KVM_CPUID_HYPERV_OFFSET;
+                }
+                entry.function += KVM_CPUID_HYPERV_OFFSET;
+            }
+        }
+
+        let leaves = [
+            (
+                HYPERV_CPUID_VENDOR_AND_MAX_FUNCTIONS,
+                [
+                    HYPERV_CPUID_IMPLEMENT_LIMITS,
+                    HYPERV_VENDOR_EBX,
+                    HYPERV_VENDOR_ECX,
+                    HYPERV_VENDOR_EDX,
+                ],
+            ),
+            (HYPERV_CPUID_INTERFACE, [HYPERV_INTERFACE_EAX, 0, 0, 0]),
+            (
+                HYPERV_CPUID_VERSION,
+                [HYPERV_VERSION_BUILD, HYPERV_VERSION_MAJOR_MINOR, 0, 0],
+            ),
+            (
+                HYPERV_CPUID_FEATURES,
+                [
+                    HV_MSR_TIME_REF_COUNT_AVAILABLE
+                        | HV_MSR_HYPERCALL_AVAILABLE
+                        | HV_MSR_VP_INDEX_AVAILABLE
+                        | HV_MSR_REFERENCE_TSC_AVAILABLE,
+                    0,
+                    0,
+                    0,
+                ],
+            ),
+            (
+                HYPERV_CPUID_ENLIGHTMENT_INFO,
+                [
+                    HV_X64_RELAXED_TIMING_RECOMMENDED,
+                    HV_SPINLOCK_NEVER_NOTIFY,
+                    0,
+                    0,
+                ],
+            ),
+            (
+                HYPERV_CPUID_IMPLEMENT_LIMITS,
+                [u32::from(max_vcpus), u32::from(max_vcpus), 0, 0],
+            ),
+        ];
+
+        for (function, [eax, ebx, ecx, edx]) in leaves.iter() {
+            cpuid
+                .push(kvm_cpuid_entry2 {
+                    function: *function,
+                    eax: *eax,
+                    ebx: *ebx,
+                    ecx: *ecx,
+                    edx: *edx,
+                    ..Default::default()
+                })
+                .map_err(Error::HypervCpuid)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl CpuidPatch {
+    /// Enumerate the SGX EPC sections through the SGX leaf sub-leaves, one
+    /// section per sub-leaf, the list ending with an invalid section.
+    pub fn patch_cpuid_sgx_epc(cpuid: &mut CpuId, sections: &[SgxEpcSection]) -> Result<()> {
+        let sgx_supported = cpuid.as_slice().iter().any(|entry| {
+            entry.function == 0x7 && entry.index == 0 && entry.ebx & (1 << SGX_EBX_BIT) != 0
+        });
+        if !sgx_supported {
+            return Err(Error::SgxNotSupported);
+        }
+
+        let mut index = SGX_EPC_FIRST_SUBLEAF;
+        for section in sections.iter() {
+            let start = section.start.raw_value();
+            let size = section.size;
+            cpuid
+                .push(kvm_cpuid_entry2 {
+                    function: SGX_CPUID_LEAF,
+                    index,
+                    flags: KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+                    // Bits 3:0 set to 1 for a valid section, followed by
+                    // the bits 31:12 of the address.
+                    eax: (start as u32 & 0xffff_f000) | 0x1,
+                    ebx: (start >> 32) as u32,
+                    // Bits 3:0 set to 1 for a confidentiality, integrity
+                    // and replay protected section, followed by the bits
+                    // 31:12 of the size.
+                    ecx: (size as u32 & 0xffff_f000) | 0x1,
+                    edx: (size >> 32) as u32,
+                    padding: [0; 3],
+                })
+                .map_err(Error::SgxEpcCpuid)?;
+            index += 1;
+        }
+
+        cpuid
+            .push(kvm_cpuid_entry2 {
+                function: SGX_CPUID_LEAF,
+                index,
+                flags: KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+                ..Default::default()
+            })
+            .map_err(Error::SgxEpcCpuid)
+    }
+}
+
+/// Frequencies of the host CPUs, in MHz, advertised to the guest so that its
+/// scheduler and tools don't have to derive them from the TSC.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CpuFrequency {
+    pub min_mhz: u32,
+    pub base_mhz: u32,
+    pub max_mhz: u32,
+    pub bus_mhz: u32,
+}
+
+impl CpuFrequency {
+    /// Read the host CPU frequencies from the frequency CPUID leaf, falling
+    /// back to the cpufreq driver on hosts which don't provide the leaf,
+    /// like AMD ones.
+    pub fn from_host() -> Result<Self> {
+        let mut frequency = CpuFrequency::default();
+
+        // Safe because CPUID doesn't have any side effect.
+        let max_leaf = unsafe { std::arch::x86_64::__cpuid(0) }.eax;
+        if max_leaf >= FREQUENCY_CPUID_LEAF {
+            let leaf = unsafe { std::arch::x86_64::__cpuid(FREQUENCY_CPUID_LEAF) };
+            frequency.base_mhz = leaf.eax & 0xffff;
+            frequency.max_mhz = leaf.ebx & 0xffff;
+            frequency.bus_mhz = leaf.ecx & 0xffff;
+        }
+
+        let cpufreq_mhz = |attribute: &str| -> Option<u32> {
+            let khz = std::fs::read_to_string(format!("{}/{}", CPUFREQ_SYSFS_PATH, attribute))
+                .ok()?
+                .trim()
+                .parse::<u32>()
+                .ok()?;
+            Some(khz / 1000)
+        };
+
+        if frequency.base_mhz == 0 {
+            // Only provided by intel_pstate, the maximum frequency being
+            // the turbo one.
+            frequency.base_mhz = cpufreq_mhz("base_frequency").unwrap_or(0);
+        }
+        if frequency.max_mhz == 0 {
+            frequency.max_mhz = cpufreq_mhz("cpuinfo_max_freq").unwrap_or(frequency.base_mhz);
+        }
+        if frequency.base_mhz == 0 {
+            frequency.base_mhz = frequency.max_mhz;
+        }
+        if frequency.base_mhz == 0 {
+            return Err(Error::CpuFrequencyUnavailable);
+        }
+        frequency.min_mhz = cmp::min(
+            cpufreq_mhz("cpuinfo_min_freq").unwrap_or(frequency.base_mhz),
+            frequency.base_mhz,
+        );
+
+        Ok(frequency)
+    }
+}
+
+impl CpuidPatch {
+    /// Report the CPU frequencies through the frequency leaf, raising the
+    /// maximum basic leaf if needed to include it.
+    pub fn patch_cpuid_frequency(cpuid: &mut CpuId, frequency: &CpuFrequency) -> Result<()> {
+        let mut found = false;
+        for entry in cpuid.as_mut_slice().iter_mut() {
+            if entry.function == 0 && entry.eax < FREQUENCY_CPUID_LEAF {
+                entry.eax = FREQUENCY_CPUID_LEAF;
+            }
+            if entry.function == FREQUENCY_CPUID_LEAF {
+                entry.eax = frequency.base_mhz;
+                entry.ebx = frequency.max_mhz;
+                entry.ecx = frequency.bus_mhz;
+                entry.edx = 0;
+                found = true;
+            }
+        }
+
+        if found {
+            return Ok(());
+        }
+
+        cpuid
+            .push(kvm_cpuid_entry2 {
+                function: FREQUENCY_CPUID_LEAF,
+                eax: frequency.base_mhz,
+                ebx: frequency.max_mhz,
+                ecx: frequency.bus_mhz,
+                ..Default::default()
+            })
+            .map_err(Error::FrequencyCpuid)
+    }
 }
 
 #[cfg(feature = "acpi")]
@@ -229,6 +1090,57 @@ struct InterruptSourceOverride {
     pub flags: u16,
 }
 
+/// The guest accesses to the PIO and MMIO addresses no device handles, which
+/// the vCPUs of a VM share the log limit of.
+pub struct UnknownAccesses {
+    config: UnknownAccessConfig,
+    logged: AtomicU64,
+}
+
+impl UnknownAccesses {
+    pub fn new(config: UnknownAccessConfig) -> Self {
+        UnknownAccesses {
+            config,
+            logged: AtomicU64::new(0),
+        }
+    }
+}
+
+// Interval at which the vCPUs being paused are kicked out of the guest,
+// until all of them are.
+const VCPU_PAUSE_POLL_US: u64 = 100;
+
+/// State of a vCPU, as saved in a snapshot. The KVM structures are plain
+/// data, saved as is.
+#[derive(Deserialize, Serialize)]
+pub struct VcpuSnapshot {
+    regs: Vec<u8>,
+    sregs: Vec<u8>,
+    fpu: Vec<u8>,
+    lapic: Vec<u8>,
+    xsave: Vec<u8>,
+    xcrs: Vec<u8>,
+    vcpu_events: Vec<u8>,
+    mp_state: u32,
+    msrs: Vec<(u32, u64)>,
+}
+
+fn kvm_struct_bytes<T: Copy>(value: &T) -> Vec<u8> {
+    // Safe because the KVM structures are plain data, any byte of which can
+    // be read.
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+        .to_vec()
+}
+
+fn kvm_struct_from_bytes<T: Copy>(bytes: &[u8]) -> Result<T> {
+    if bytes.len() != mem::size_of::<T>() {
+        return Err(Error::InvalidVcpuState);
+    }
+    // Safe because the length matches, and any bytes make a valid KVM
+    // structure.
+    Ok(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
 /// A wrapper around creating and using a kvm-based VCPU.
 pub struct Vcpu {
     fd: VcpuFd,
@@ -236,6 +1148,7 @@ pub struct Vcpu {
     io_bus: Arc<devices::Bus>,
     mmio_bus: Arc<devices::Bus>,
     ioapic: Option<Arc<Mutex<ioapic::Ioapic>>>,
+    unknown_accesses: Arc<UnknownAccesses>,
     vm_ts: std::time::Instant,
 }
 
@@ -252,6 +1165,7 @@ impl Vcpu {
         io_bus: Arc<devices::Bus>,
         mmio_bus: Arc<devices::Bus>,
         ioapic: Option<Arc<Mutex<ioapic::Ioapic>>>,
+        unknown_accesses: Arc<UnknownAccesses>,
         creation_ts: std::time::Instant,
     ) -> Result<Self> {
         let kvm_vcpu = fd.create_vcpu(id).map_err(Error::VcpuFd)?;
@@ -262,6 +1176,7 @@ impl Vcpu {
             io_bus,
             mmio_bus,
             ioapic,
+            unknown_accesses,
             vm_ts: creation_ts,
         })
     }
@@ -276,9 +1191,21 @@ impl Vcpu {
     pub fn configure(
         &mut self,
         kernel_start_addr: Option<GuestAddress>,
-        vm_memory: &GuestMemoryAtomic<GuestMemoryMmap>,
         cpuid: CpuId,
+        tsc_khz: Option<u32>,
     ) -> Result<()> {
+        // Pin the TSC frequency, so that the guest keeps seeing the same one
+        // whatever the host it runs on. KVM scales the TSC if needed.
+        if let Some(tsc_khz) = tsc_khz {
+            // Safe because the ioctl takes its argument by value, and we
+            // check the return value.
+            let ret =
+                unsafe { ioctl_with_val(&self.fd, KVM_SET_TSC_KHZ, libc::c_ulong::from(tsc_khz)) };
+            if ret < 0 {
+                return Err(Error::SetTscKhz(io::Error::last_os_error()));
+            }
+        }
+
         let mut cpuid = cpuid;
         CpuidPatch::set_cpuid_reg(&mut cpuid, 0xb, None, CpuidReg::EDX, u32::from(self.id));
         self.fd
@@ -296,13 +1223,111 @@ impl Vcpu {
             )
             .map_err(Error::REGSConfiguration)?;
             arch::x86_64::regs::setup_fpu(&self.fd).map_err(Error::FPUConfiguration)?;
-            arch::x86_64::regs::setup_sregs(&vm_memory.memory(), &self.fd)
-                .map_err(Error::SREGSConfiguration)?;
+            arch::x86_64::regs::setup_sregs(&self.fd).map_err(Error::SREGSConfiguration)?;
         }
         arch::x86_64::interrupts::set_lint(&self.fd).map_err(Error::LocalIntConfiguration)?;
         Ok(())
     }
 
+    /// Bring a vCPU which was previously ejected back to the state of a
+    /// freshly created one, waiting for the guest to start it up.
+    pub fn reset_mp_state(&self) -> Result<()> {
+        self.fd
+            .set_mp_state(kvm_mp_state {
+                mp_state: KVM_MP_STATE_UNINITIALIZED,
+            })
+            .map_err(Error::VcpuSetMpState)
+    }
+
+    /// Save the state of the vCPU, which must not be running, including the
+    /// MSRs in `msr_indexes` KVM can read.
+    pub fn state(&self, msr_indexes: &[u32]) -> Result<VcpuSnapshot> {
+        let mut msr_entries: Vec<kvm_msr_entry> = msr_indexes
+            .iter()
+            .map(|index| kvm_msr_entry {
+                index: *index,
+                ..Default::default()
+            })
+            .collect();
+        let msrs = loop {
+            let mut msrs = Msrs::from_entries(&msr_entries);
+            let count = self.fd.get_msrs(&mut msrs).map_err(Error::VcpuGetState)?;
+            if count == msr_entries.len() {
+                break msrs;
+            }
+            // KVM stops at the first MSR it can't read, which is left out.
+            msr_entries.remove(count);
+        };
+
+        Ok(VcpuSnapshot {
+            regs: kvm_struct_bytes(&self.fd.get_regs().map_err(Error::VcpuGetState)?),
+            sregs: kvm_struct_bytes(&self.fd.get_sregs().map_err(Error::VcpuGetState)?),
+            fpu: kvm_struct_bytes(&self.fd.get_fpu().map_err(Error::VcpuGetState)?),
+            lapic: kvm_struct_bytes(&self.fd.get_lapic().map_err(Error::VcpuGetState)?),
+            xsave: kvm_struct_bytes(&self.fd.get_xsave().map_err(Error::VcpuGetState)?),
+            xcrs: kvm_struct_bytes(&self.fd.get_xcrs().map_err(Error::VcpuGetState)?),
+            vcpu_events: kvm_struct_bytes(&self.fd.get_vcpu_events().map_err(Error::VcpuGetState)?),
+            mp_state: self
+                .fd
+                .get_mp_state()
+                .map_err(Error::VcpuGetState)?
+                .mp_state,
+            msrs: msrs
+                .as_slice()
+                .iter()
+                .map(|entry| (entry.index, entry.data))
+                .collect(),
+        })
+    }
+
+    /// Bring the vCPU, configured but not running yet, to a saved state.
+    pub fn set_state(&self, state: &VcpuSnapshot) -> Result<()> {
+        self.fd
+            .set_mp_state(kvm_mp_state {
+                mp_state: state.mp_state,
+            })
+            .map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_regs(&kvm_struct_from_bytes(&state.regs)?)
+            .map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_sregs(&kvm_struct_from_bytes(&state.sregs)?)
+            .map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_fpu(&kvm_struct_from_bytes(&state.fpu)?)
+            .map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_xsave(&kvm_struct_from_bytes(&state.xsave)?)
+            .map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_xcrs(&kvm_struct_from_bytes(&state.xcrs)?)
+            .map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_lapic(&kvm_struct_from_bytes(&state.lapic)?)
+            .map_err(Error::VcpuSetState)?;
+
+        let msr_entries: Vec<kvm_msr_entry> = state
+            .msrs
+            .iter()
+            .map(|(index, data)| kvm_msr_entry {
+                index: *index,
+                data: *data,
+                ..Default::default()
+            })
+            .collect();
+        let count = self
+            .fd
+            .set_msrs(&Msrs::from_entries(&msr_entries))
+            .map_err(Error::VcpuSetState)?;
+        if count != msr_entries.len() {
+            return Err(Error::VcpuSetMsr(msr_entries[count].index));
+        }
+
+        self.fd
+            .set_vcpu_events(&kvm_struct_from_bytes(&state.vcpu_events)?)
+            .map_err(Error::VcpuSetState)
+    }
+
     /// Runs the VCPU until it exits, returning the reason.
     ///
     /// Note that the state of the VCPU and associated VM must be setup first for this to do
@@ -311,23 +1336,40 @@ impl Vcpu {
         match self.fd.run() {
             Ok(run) => match run {
                 VcpuExit::IoIn(addr, data) => {
-                    self.io_bus.read(u64::from(addr), data);
-                    Ok(true)
+                    if self.io_bus.read(u64::from(addr), data) {
+                        return Ok(true);
+                    }
+                    for b in data.iter_mut() {
+                        *b = 0xff;
+                    }
+                    Ok(self.unknown_access("PIO read", u64::from(addr), data.len()))
                 }
                 VcpuExit::IoOut(addr, data) => {
                     if addr == DEBUG_IOPORT && data.len() == 1 {
                         self.log_debug_ioport(data[0]);
+                        // No device behind it, the port being handled here.
+                        self.io_bus.write(u64::from(addr), data);
+                        return Ok(true);
                     }
-                    self.io_bus.write(u64::from(addr), data);
-                    Ok(true)
+                    if self.io_bus.write(u64::from(addr), data) {
+                        return Ok(true);
+                    }
+                    Ok(self.unknown_access("PIO write", u64::from(addr), data.len()))
                 }
                 VcpuExit::MmioRead(addr, data) => {
-                    self.mmio_bus.read(addr as u64, data);
-                    Ok(true)
+                    if self.mmio_bus.read(addr as u64, data) {
+                        return Ok(true);
+                    }
+                    for b in data.iter_mut() {
+                        *b = 0xff;
+                    }
+                    Ok(self.unknown_access("MMIO read", addr as u64, data.len()))
                 }
                 VcpuExit::MmioWrite(addr, data) => {
-                    self.mmio_bus.write(addr as u64, data);
-                    Ok(true)
+                    if self.mmio_bus.write(addr as u64, data) {
+                        return Ok(true);
+                    }
+                    Ok(self.unknown_access("MMIO write", addr as u64, data.len()))
                 }
                 VcpuExit::IoapicEoi(vector) => {
                     if let Some(ioapic) = &self.ioapic {
@@ -339,6 +1381,25 @@ impl Vcpu {
                     // Triple fault to trigger a reboot
                     Ok(false)
                 }
+                VcpuExit::InternalError => {
+                    // KVM failed emulating an instruction, e.g. an MMIO access
+                    // it can't decode. The guest can't go past it, hence a
+                    // reboot as for a triple fault, rather than leaving the
+                    // vCPU stopped.
+                    match self.fd.get_regs() {
+                        Ok(regs) => error!(
+                            "VCPU {:?} failed emulating the instruction at RIP {:#x}, \
+                             rebooting",
+                            self.id, regs.rip
+                        ),
+                        Err(e) => error!(
+                            "VCPU {:?} failed emulating an instruction, rebooting \
+                             (no RIP: {:?})",
+                            self.id, e
+                        ),
+                    }
+                    Ok(false)
+                }
                 r => {
                     error!("Unexpected exit reason on vcpu run: {:?}", r);
                     Err(Error::VcpuUnhandledKvmExit)
@@ -355,6 +1416,46 @@ impl Vcpu {
         }
     }
 
+    // Handles an access to an address no device handles, according to the
+    // policy of the VM, returning whether the vCPU carries on.
+    fn unknown_access(&self, access: &str, addr: u64, len: usize) -> bool {
+        let config = &self.unknown_accesses.config;
+        match config.action {
+            UnknownAccessAction::Ignore => true,
+            UnknownAccessAction::Log => {
+                let logged = self.unknown_accesses.logged.fetch_add(1, Ordering::Relaxed);
+                if logged < config.log_limit {
+                    warn!(
+                        "VCPU {:?} unknown {} of {} bytes at {:#x}{}",
+                        self.id,
+                        access,
+                        len,
+                        addr,
+                        if logged + 1 == config.log_limit {
+                            ", not logging the next ones"
+                        } else {
+                            ""
+                        }
+                    );
+                }
+                true
+            }
+            UnknownAccessAction::Fault => {
+                match self.fd.get_regs() {
+                    Ok(regs) => error!(
+                        "VCPU {:?} unknown {} of {} bytes at {:#x}, from RIP {:#x}, rebooting",
+                        self.id, access, len, addr, regs.rip
+                    ),
+                    Err(e) => error!(
+                        "VCPU {:?} unknown {} of {} bytes at {:#x}, rebooting (no RIP: {:?})",
+                        self.id, access, len, addr, e
+                    ),
+                }
+                false
+            }
+        }
+    }
+
     // Log debug io port codes.
     fn log_debug_ioport(&self, code: u8) {
         let ts = self.vm_ts.elapsed();
@@ -375,14 +1476,21 @@ pub struct CpuManager {
     io_bus: Weak<devices::Bus>,
     mmio_bus: Arc<devices::Bus>,
     ioapic: Option<Arc<Mutex<ioapic::Ioapic>>>,
-    vm_memory: GuestMemoryAtomic<GuestMemoryMmap>,
+    unknown_accesses: Arc<UnknownAccesses>,
     cpuid: CpuId,
+    tsc_khz: Option<u32>,
     fd: Arc<VmFd>,
     vcpus_kill_signalled: Arc<AtomicBool>,
     vcpus_pause_signalled: Arc<AtomicBool>,
     reset_evt: EventFd,
     vcpu_states: Vec<VcpuState>,
     selected_cpu: u8,
+    // The cgroups the vCPU threads go to when spawned
+    cgroups: Option<Arc<VmCgroups>>,
+    #[cfg(feature = "acpi")]
+    frequency: Option<CpuFrequency>,
+    #[cfg(feature = "acpi")]
+    cppc_registers_address: Option<GuestAddress>,
 }
 
 const CPU_ENABLE_FLAG: usize = 0;
@@ -454,12 +1562,79 @@ impl BusDevice for CpuManager {
     }
 }
 
+// CPPC desired performance registers, one 32 bits register per vCPU. The
+// performance levels the guest requests are only recorded, the host staying
+// in charge of the physical CPUs frequency.
+#[cfg(feature = "acpi")]
+struct CppcRegisters {
+    desired_perf: Vec<u32>,
+}
+
+#[cfg(feature = "acpi")]
+const CPPC_REGISTER_SIZE: u64 = 4;
+
+#[cfg(feature = "acpi")]
+impl BusDevice for CppcRegisters {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        let index = (offset / CPPC_REGISTER_SIZE) as usize;
+        if data.len() != CPPC_REGISTER_SIZE as usize || index >= self.desired_perf.len() {
+            warn!("Unexpected access to the CPPC registers: {:#x}", offset);
+            return;
+        }
+
+        data.copy_from_slice(&self.desired_perf[index].to_le_bytes());
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) {
+        let index = (offset / CPPC_REGISTER_SIZE) as usize;
+        if data.len() != CPPC_REGISTER_SIZE as usize || index >= self.desired_perf.len() {
+            warn!("Unexpected access to the CPPC registers: {:#x}", offset);
+            return;
+        }
+
+        let mut value = [0u8; 4];
+        value.copy_from_slice(data);
+        self.desired_perf[index] = u32::from_le_bytes(value);
+    }
+}
+
+// The CPU time, in microseconds, the thread `tid` of the VMM consumed.
+fn thread_cpu_time(tid: libc::pid_t) -> Option<u64> {
+    // The CPU time clock of a thread, as built by pthread_getcpuclockid().
+    const CPUCLOCK_PERTHREAD_SCHED: libc::clockid_t = 6;
+    let clock = (!tid << 3) | CPUCLOCK_PERTHREAD_SCHED;
+
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Safe because the kernel only writes to `ts`, and we check the return
+    // value.
+    if unsafe { libc::clock_gettime(clock, &mut ts) } < 0 {
+        return None;
+    }
+
+    Some(ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1_000)
+}
+
 #[derive(Default)]
 struct VcpuState {
     inserting: bool,
     removing: bool,
     handle: Option<thread::JoinHandle<()>>,
+    // KVM can't destroy a vCPU, an ejected one is kept here until it gets
+    // added back. The vCPU thread only holds the lock while in the guest.
+    vcpu: Option<Arc<Mutex<Vcpu>>>,
     kill: Arc<AtomicBool>,
+    // Set by the vCPU thread once it's out of the guest for a pause, and
+    // cleared by the resume.
+    paused: Arc<AtomicBool>,
+    exited: Arc<AtomicBool>,
+    // Time, in microseconds, the vCPU thread must sleep for before
+    // entering the guest again.
+    throttle: Arc<AtomicU64>,
+    // Thread id of the vCPU thread, set once it started.
+    tid: Arc<AtomicI32>,
 }
 
 impl VcpuState {
@@ -477,7 +1652,7 @@ impl VcpuState {
 
     fn join_thread(&mut self) -> Result<()> {
         if let Some(handle) = self.handle.take() {
-            handle.join().map_err(Error::ThreadCleanup)?
+            handle.join().map_err(Error::ThreadCleanup)?;
         }
 
         Ok(())
@@ -488,17 +1663,43 @@ impl VcpuState {
             handle.thread().unpark()
         }
     }
+
+    // Host CPU time, in nanoseconds, consumed by the vCPU thread.
+    fn cpu_time(&self) -> Option<u64> {
+        let handle = self.handle.as_ref()?;
+        let mut clock_id: libc::clockid_t = 0;
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+
+        // Safe because the thread can't be released while we hold its
+        // handle, and we check the return values.
+        unsafe {
+            if libc::pthread_getcpuclockid(handle.as_pthread_t(), &mut clock_id) != 0
+                || libc::clock_gettime(clock_id, &mut ts) != 0
+            {
+                return None;
+            }
+        }
+
+        Some(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64)
+    }
 }
 
 impl CpuManager {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         boot_vcpus: u8,
         max_vcpus: u8,
         device_manager: &DeviceManager,
-        guest_memory: GuestMemoryAtomic<GuestMemoryMmap>,
         fd: Arc<VmFd>,
         cpuid: CpuId,
+        tsc_khz: Option<u32>,
+        frequency: Option<CpuFrequency>,
+        unknown_access: UnknownAccessConfig,
         reset_evt: EventFd,
+        cgroups: Option<Arc<VmCgroups>>,
     ) -> Result<Arc<Mutex<CpuManager>>> {
         let mut vcpu_states = Vec::with_capacity(usize::from(max_vcpus));
         vcpu_states.resize_with(usize::from(max_vcpus), VcpuState::default);
@@ -509,14 +1710,20 @@ impl CpuManager {
             io_bus: Arc::downgrade(&device_manager.io_bus().clone()),
             mmio_bus: device_manager.mmio_bus().clone(),
             ioapic: device_manager.ioapic().clone(),
-            vm_memory: guest_memory,
+            unknown_accesses: Arc::new(UnknownAccesses::new(unknown_access)),
             cpuid,
+            tsc_khz,
             fd,
             vcpus_kill_signalled: Arc::new(AtomicBool::new(false)),
             vcpus_pause_signalled: Arc::new(AtomicBool::new(false)),
             vcpu_states,
             reset_evt,
             selected_cpu: 0,
+            cgroups,
+            #[cfg(feature = "acpi")]
+            frequency,
+            #[cfg(feature = "acpi")]
+            cppc_registers_address: None,
         }));
 
         device_manager
@@ -535,6 +1742,35 @@ impl CpuManager {
             .insert(cpu_manager.clone(), 0x0cd8, 0xc)
             .map_err(Error::BusError)?;
 
+        // The frequencies are only described through CPUID without ACPI.
+        #[cfg(not(feature = "acpi"))]
+        let _ = frequency;
+
+        #[cfg(feature = "acpi")]
+        {
+            if frequency.is_some() {
+                let size = u64::from(max_vcpus) * CPPC_REGISTER_SIZE;
+                let address = device_manager
+                    .allocator()
+                    .lock()
+                    .unwrap()
+                    .allocate_mmio_hole_addresses(None, size, Some(CPPC_REGISTER_SIZE))
+                    .ok_or(Error::AllocateCppcRegisters)?;
+                let cppc_registers = CppcRegisters {
+                    desired_perf: vec![0; usize::from(max_vcpus)],
+                };
+                device_manager
+                    .mmio_bus()
+                    .insert(
+                        Arc::new(Mutex::new(cppc_registers)),
+                        address.raw_value(),
+                        size,
+                    )
+                    .map_err(Error::BusError)?;
+                cpu_manager.lock().unwrap().cppc_registers_address = Some(address);
+            }
+        }
+
         Ok(cpu_manager)
     }
 
@@ -542,6 +1778,8 @@ impl CpuManager {
         &mut self,
         desired_vcpus: u8,
         entry_addr: Option<GuestAddress>,
+        sev: Option<&SevGuest>,
+        hotplug: bool,
     ) -> Result<()> {
         if desired_vcpus > self.max_vcpus {
             return Err(Error::DesiredVCPUCountExceedsMax);
@@ -551,6 +1789,15 @@ impl CpuManager {
         let vcpu_thread_barrier = Arc::new(Barrier::new(
             (desired_vcpus - self.present_vcpus() + 1) as usize,
         ));
+        // A SEV guest launch completes once all vCPUs have been configured,
+        // and before any of them runs.
+        let vcpu_launch_barrier = if sev.is_some() {
+            Some(Arc::new(Barrier::new(
+                (desired_vcpus - self.present_vcpus() + 1) as usize,
+            )))
+        } else {
+            None
+        };
 
         for cpu_id in self.present_vcpus()..desired_vcpus {
             let ioapic = if let Some(ioapic) = &self.ioapic {
@@ -559,43 +1806,116 @@ impl CpuManager {
                 None
             };
 
-            let mut vcpu = Vcpu::new(
-                cpu_id,
-                &self.fd,
-                self.io_bus.clone().upgrade().unwrap(),
-                self.mmio_bus.clone(),
-                ioapic,
-                creation_ts,
-            )?;
+            let vcpu = match self.vcpu_states[usize::from(cpu_id)].vcpu.clone() {
+                Some(vcpu) => {
+                    vcpu.lock().unwrap().reset_mp_state()?;
+                    vcpu
+                }
+                None => Arc::new(Mutex::new(Vcpu::new(
+                    cpu_id,
+                    &self.fd,
+                    self.io_bus.clone().upgrade().unwrap(),
+                    self.mmio_bus.clone(),
+                    ioapic,
+                    self.unknown_accesses.clone(),
+                    creation_ts,
+                )?)),
+            };
+            self.vcpu_states[usize::from(cpu_id)].vcpu = Some(vcpu.clone());
 
             let vcpu_thread_barrier = vcpu_thread_barrier.clone();
+            let vcpu_launch_barrier = vcpu_launch_barrier.clone();
 
             let reset_evt = self.reset_evt.try_clone().unwrap();
             let vcpu_kill_signalled = self.vcpus_kill_signalled.clone();
             let vcpu_pause_signalled = self.vcpus_pause_signalled.clone();
 
             let vcpu_kill = self.vcpu_states[usize::from(cpu_id)].kill.clone();
-            let vm_memory = self.vm_memory.clone();
+            let vcpu_throttle = self.vcpu_states[usize::from(cpu_id)].throttle.clone();
+            let vcpu_tid = self.vcpu_states[usize::from(cpu_id)].tid.clone();
+            let vcpu_cgroups = self.cgroups.clone();
+            let vcpu_paused = self.vcpu_states[usize::from(cpu_id)].paused.clone();
+            let vcpu_exited = self.vcpu_states[usize::from(cpu_id)].exited.clone();
+            vcpu_paused.store(false, Ordering::SeqCst);
+            vcpu_exited.store(false, Ordering::SeqCst);
             let cpuid = self.cpuid.clone();
+            let tsc_khz = self.tsc_khz;
 
             let handle = Some(
                 thread::Builder::new()
-                    .name(format!("vcpu{}", vcpu.id))
+                    .name(format!("vcpu{}", cpu_id))
                     .spawn(move || {
                         extern "C" fn handle_signal(_: i32, _: *mut siginfo_t, _: *mut c_void) {}
                         // This uses an async signal safe handler to kill the vcpu handles.
                         register_signal_handler(SIGRTMIN(), handle_signal)
                             .expect("Failed to register vcpu signal handler");
+                        // Safe because gettid() has no side effect.
+                        vcpu_tid.store(
+                            unsafe { libc::syscall(libc::SYS_gettid) } as i32,
+                            Ordering::SeqCst,
+                        );
 
-                        vcpu.configure(entry_addr, &vm_memory, cpuid)
-                            .expect("Failed to configure vCPU");
+                        let configured = vcpu.lock().unwrap().configure(entry_addr, cpuid, tsc_khz);
 
                         // Block until all CPUs are ready.
                         vcpu_thread_barrier.wait();
 
+                        if let Some(vcpu_launch_barrier) = vcpu_launch_barrier {
+                            vcpu_launch_barrier.wait();
+                            // The launch failed.
+                            if vcpu_kill_signalled.load(Ordering::SeqCst) {
+                                vcpu_exited.store(true, Ordering::SeqCst);
+                                return;
+                            }
+                        }
+
+                        if let Err(e) = configured {
+                            error!("Failed to configure vCPU {}: {:?}", cpu_id, e);
+                            vcpu_exited.store(true, Ordering::SeqCst);
+                            return;
+                        }
+
+                        // Move to the vCPUs cgroup before running the guest.
+                        if let Some(cgroups) = &vcpu_cgroups {
+                            if let Err(e) = cgroups.add_vcpu_thread() {
+                                error!("Failed placing vCPU {} in its cgroup: {:?}", cpu_id, e);
+                            }
+                        }
+
                         loop {
+                            // If we are being told to pause, we park the thread
+                            // until the pause boolean is toggled.
+                            // The resume operation is responsible for toggling
+                            // the boolean and unpark the thread.
+                            // We enter a loop because park() could spuriously
+                            // return. We will then park() again unless the
+                            // pause boolean has been toggled. A vCPU restored
+                            // from a snapshot is paused before running at all.
+                            if vcpu_pause_signalled.load(Ordering::SeqCst) {
+                                vcpu_paused.store(true, Ordering::SeqCst);
+                                while vcpu_pause_signalled.load(Ordering::SeqCst)
+                                    && !vcpu_kill_signalled.load(Ordering::SeqCst)
+                                {
+                                    thread::park();
+                                }
+                            }
+
+                            // We've been told to terminate
+                            if vcpu_kill_signalled.load(Ordering::SeqCst)
+                                || vcpu_kill.load(Ordering::SeqCst)
+                            {
+                                break;
+                            }
+
+                            // Stay out of the guest for the requested amount
+                            // of time if we're being throttled.
+                            let throttle = vcpu_throttle.swap(0, Ordering::SeqCst);
+                            if throttle != 0 {
+                                thread::sleep(Duration::from_micros(throttle));
+                            }
+
                             // vcpu.run() returns false on a KVM_EXIT_SHUTDOWN (triple-fault) so trigger a reset
-                            match vcpu.run() {
+                            match vcpu.lock().unwrap().run() {
                                 Err(e) => {
                                     error!("VCPU generated error: {:?}", e);
                                     break;
@@ -606,37 +1926,34 @@ impl CpuManager {
                                     break;
                                 }
                             }
+                        }
 
-                            // We've been told to terminate
-                            if vcpu_kill_signalled.load(Ordering::SeqCst)
-                                || vcpu_kill.load(Ordering::SeqCst)
-                            {
-                                break;
-                            }
-
-                            // If we are being told to pause, we park the thread
-                            // until the pause boolean is toggled.
-                            // The resume operation is responsible for toggling
-                            // the boolean and unpark the thread.
-                            // We enter a loop because park() could spuriously
-                            // return. We will then park() again unless the
-                            // pause boolean has been toggled.
-                            while vcpu_pause_signalled.load(Ordering::SeqCst) {
-                                thread::park();
-                            }
+                        if let Some(cgroups) = &vcpu_cgroups {
+                            cgroups.remove_vcpu_thread();
                         }
+                        vcpu_exited.store(true, Ordering::SeqCst);
                     })
                     .map_err(Error::VcpuSpawn)?,
             );
 
-            // On hot plug calls into this function entry_addr is None. It is for
-            // those hotplug CPU additions that we need to set the inserting flag.
+            // The hotplug CPU additions are to be notified to the guest
+            // through the inserting flag.
             self.vcpu_states[usize::from(cpu_id)].handle = handle;
-            self.vcpu_states[usize::from(cpu_id)].inserting = entry_addr.is_none();
+            self.vcpu_states[usize::from(cpu_id)].inserting = hotplug;
         }
 
         // Unblock all CPU threads.
         vcpu_thread_barrier.wait();
+
+        if let (Some(sev), Some(vcpu_launch_barrier)) = (sev, vcpu_launch_barrier) {
+            let launch = sev.launch_finish();
+            if launch.is_err() {
+                self.vcpus_kill_signalled.store(true, Ordering::SeqCst);
+            }
+            vcpu_launch_barrier.wait();
+            launch.map_err(Error::SevLaunch)?;
+        }
+
         Ok(())
     }
 
@@ -648,37 +1965,111 @@ impl CpuManager {
         Ok(())
     }
 
+    // Called once the guest has offlined the vCPU, and ejected it. The vCPU
+    // thread is stopped, and the vCPU parked for it to be added back later.
     fn remove_vcpu(&mut self, cpu_id: u8) -> Result<()> {
         let mut state = &mut self.vcpu_states[usize::from(cpu_id)];
         state.kill.store(true, Ordering::SeqCst);
         state.signal_thread();
         state.join_thread()?;
-        state.handle = None;
+        state.removing = false;
+        // The thread of a vCPU added back must not be stopped right away.
+        state.kill.store(false, Ordering::SeqCst);
         Ok(())
     }
 
     // Starts all the vCPUs that the VM is booting with. Blocks until all vCPUs are running.
-    pub fn start_boot_vcpus(&mut self, entry_addr: GuestAddress) -> Result<()> {
-        self.activate_vcpus(self.boot_vcpus(), Some(entry_addr))
+    // The launch of a SEV guest is completed along the way.
+    pub fn start_boot_vcpus(
+        &mut self,
+        entry_addr: GuestAddress,
+        sev: Option<&SevGuest>,
+    ) -> Result<()> {
+        self.activate_vcpus(self.boot_vcpus(), Some(entry_addr), sev, false)
     }
 
     pub fn resize(&mut self, desired_vcpus: u8) -> Result<bool> {
         match desired_vcpus.cmp(&self.present_vcpus()) {
-            cmp::Ordering::Greater => self.activate_vcpus(desired_vcpus, None).and(Ok(true)),
+            cmp::Ordering::Greater => self
+                .activate_vcpus(desired_vcpus, None, None, true)
+                .and(Ok(true)),
             cmp::Ordering::Less => self.mark_vcpus_for_removal(desired_vcpus).and(Ok(true)),
             _ => Ok(false),
         }
     }
 
+    /// The CPU time, in microseconds, each running vCPU thread consumed,
+    /// indexed by vCPU id. Unlike the time spent in KVM_RUN, it leaves out
+    /// the time a vCPU is halted, sleeping in the kernel until it gets an
+    /// interrupt, as well as the time it is throttled or paused.
+    pub fn vcpus_cpu_time(&self) -> Vec<(u8, u64)> {
+        self.vcpu_states
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.active())
+            .filter_map(|(cpu_id, s)| match s.tid.load(Ordering::SeqCst) {
+                0 => None,
+                tid => thread_cpu_time(tid).map(|time| (cpu_id as u8, time)),
+            })
+            .collect()
+    }
+
+    /// Kick the vCPU `cpu_id` out of the guest, making it sleep for
+    /// `duration` before resuming the guest execution. This is a no-op
+    /// when the vCPUs are paused, or this one isn't running.
+    pub fn throttle_vcpu(&self, cpu_id: u8, duration: Duration) {
+        if duration.as_micros() == 0 || self.vcpus_pause_signalled.load(Ordering::SeqCst) {
+            return;
+        }
+
+        if let Some(state) = self
+            .vcpu_states
+            .get(usize::from(cpu_id))
+            .filter(|s| s.active())
+        {
+            state
+                .throttle
+                .store(duration.as_micros() as u64, Ordering::SeqCst);
+            state.signal_thread();
+        }
+    }
+
+    /// Wait for the vCPUs being paused to be out of the guest, for their
+    /// state to be stable. The CPU manager must not be locked meanwhile,
+    /// since a vCPU may need it to complete an I/O access.
+    pub fn wait_for_pause(cpu_manager: &Arc<Mutex<CpuManager>>) {
+        let vcpus: Vec<(usize, Arc<AtomicBool>, Arc<AtomicBool>)> = cpu_manager
+            .lock()
+            .unwrap()
+            .vcpu_states
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.active())
+            .map(|(index, s)| (index, s.paused.clone(), s.exited.clone()))
+            .collect();
+
+        for (index, paused, exited) in vcpus {
+            // A vCPU kicked right before entering the guest misses the
+            // signal, hence it being sent again until the vCPU is out.
+            while !paused.load(Ordering::SeqCst) && !exited.load(Ordering::SeqCst) {
+                if let Some(state) = cpu_manager.lock().unwrap().vcpu_states.get(index) {
+                    state.signal_thread();
+                }
+                thread::sleep(Duration::from_micros(VCPU_PAUSE_POLL_US));
+            }
+        }
+    }
+
     pub fn shutdown(&mut self) -> Result<()> {
         // Tell the vCPUs to stop themselves next time they go through the loop
         self.vcpus_kill_signalled.store(true, Ordering::SeqCst);
 
         // Signal to the spawned threads (vCPUs and console signal handler). For the vCPU threads
         // this will interrupt the KVM_RUN ioctl() allowing the loop to check the boolean set
-        // above.
+        // above. The paused vCPUs are woken up for the same purpose.
         for state in self.vcpu_states.iter() {
             state.signal_thread();
+            state.unpark_thread();
         }
 
         // Wait for all the threads to finish. This removes the state from the vector.
@@ -697,7 +2088,17 @@ impl CpuManager {
         self.max_vcpus
     }
 
-    fn present_vcpus(&self) -> u8 {
+    /// Host CPU time, in nanoseconds, consumed by each of the present
+    /// vCPUs, along with their id.
+    pub fn vcpus_cpu_time(&self) -> Vec<(u8, u64)> {
+        self.vcpu_states
+            .iter()
+            .enumerate()
+            .filter_map(|(id, state)| state.cpu_time().map(|time| (id as u8, time)))
+            .collect()
+    }
+
+    pub fn present_vcpus(&self) -> u8 {
         self.vcpu_states
             .iter()
             .fold(0, |acc, state| acc + state.active() as u8)
@@ -751,6 +2152,81 @@ impl CpuManager {
 #[cfg(feature = "acpi")]
 struct CPU {
     cpu_id: u8,
+    cppc: Option<Cppc>,
+}
+
+// Collaborative Processor Performance Control description of a vCPU, the
+// performance levels being expressed in MHz.
+#[cfg(feature = "acpi")]
+struct Cppc {
+    frequency: CpuFrequency,
+    desired_perf_address: u64,
+}
+
+#[cfg(feature = "acpi")]
+const CPPC_NUM_ENTRIES: u8 = 23;
+#[cfg(feature = "acpi")]
+const CPPC_REVISION: u8 = 3;
+
+// Platform wide _OSC capabilities, CPPC and CPPC revision 2 and later.
+#[cfg(feature = "acpi")]
+const OSC_SB_CPPC_SUPPORT: u8 = (1 << 5) | (1 << 6);
+
+#[cfg(feature = "acpi")]
+impl Aml for Cppc {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let frequency = &self.frequency;
+        // The optional registers are described as null ones.
+        let null_register = aml::Register::new(aml::OpRegionSpace::SystemMemory, 0, 0, 0, 0);
+        let null_register = aml::ResourceTemplate::new(vec![&null_register]);
+        let desired_perf = aml::Register::new(
+            aml::OpRegionSpace::SystemMemory,
+            32,
+            0,
+            self.desired_perf_address,
+            3,
+        );
+        let desired_perf = aml::ResourceTemplate::new(vec![&desired_perf]);
+
+        aml::Name::new(
+            "_CPC".into(),
+            &aml::Package::new(vec![
+                &CPPC_NUM_ENTRIES,
+                &CPPC_REVISION,
+                // Highest, nominal, lowest nonlinear and lowest performance
+                &frequency.max_mhz,
+                &frequency.base_mhz,
+                &frequency.min_mhz,
+                &frequency.min_mhz,
+                // Guaranteed, desired, minimum and maximum performance
+                &null_register,
+                &desired_perf,
+                &null_register,
+                &null_register,
+                // Performance reduction tolerance and time window
+                &null_register,
+                &null_register,
+                // Counter wraparound time, reference and delivered
+                // performance counters
+                &aml::ZERO,
+                &null_register,
+                &null_register,
+                // Performance limited, CPPC enable, autonomous selection
+                // enable, autonomous activity window and energy
+                // performance preference
+                &null_register,
+                &null_register,
+                &aml::ZERO,
+                &null_register,
+                &null_register,
+                // Reference performance, lowest and nominal frequencies
+                &frequency.base_mhz,
+                &frequency.min_mhz,
+                &frequency.base_mhz,
+            ]),
+        )
+        .to_aml_bytes()
+    }
 }
 
 #[cfg(feature = "acpi")]
@@ -771,7 +2247,7 @@ impl Aml for CPU {
         mat_data.resize(std::mem::size_of_val(&lapic), 0);
         unsafe { *(mat_data.as_mut_ptr() as *mut LocalAPIC) = lapic };
 
-        aml::Device::new(
+        let mut bytes = aml::Device::new(
             format!("C{:03}", self.cpu_id).as_str().into(),
             vec![
                 &aml::Name::new("_HID".into(), &"ACPI0007"),
@@ -812,7 +2288,16 @@ impl Aml for CPU {
                 ),
             ],
         )
-        .to_aml_bytes()
+        .to_aml_bytes();
+
+        if let Some(cppc) = &self.cppc {
+            bytes.extend_from_slice(
+                &aml::Scope::new(format!("C{:03}", self.cpu_id).as_str().into(), vec![cppc])
+                    .to_aml_bytes(),
+            );
+        }
+
+        bytes
     }
 }
 
@@ -1019,7 +2504,15 @@ impl Aml for CpuManager {
 
         let mut cpu_devices = Vec::new();
         for cpu_id in 0..self.max_vcpus {
-            let cpu_device = CPU { cpu_id };
+            let cppc = match (self.frequency, self.cppc_registers_address) {
+                (Some(frequency), Some(address)) => Some(Cppc {
+                    frequency,
+                    desired_perf_address: address.raw_value()
+                        + u64::from(cpu_id) * CPPC_REGISTER_SIZE,
+                }),
+                _ => None,
+            };
+            let cpu_device = CPU { cpu_id, cppc };
 
             cpu_devices.push(cpu_device);
         }
@@ -1031,6 +2524,29 @@ impl Aml for CpuManager {
         bytes.extend_from_slice(
             &aml::Device::new("_SB_.CPUS".into(), cpu_data_inner).to_aml_bytes(),
         );
+
+        // Recent Linux kernels ignore _CPC unless CPPC is acknowledged
+        // through the platform wide _OSC, which doesn't acknowledge any
+        // other capability.
+        if self.cppc_registers_address.is_some() {
+            bytes.extend_from_slice(
+                &aml::Method::new(
+                    "_SB_._OSC".into(),
+                    4,
+                    false,
+                    vec![
+                        &aml::CreateField::<u32>::new(&aml::Arg(3), &4u8, "CDW2".into()),
+                        &aml::And::new(
+                            &aml::Path::new("CDW2"),
+                            &aml::Path::new("CDW2"),
+                            &OSC_SB_CPPC_SUPPORT,
+                        ),
+                        &aml::Return::new(&aml::Arg(3)),
+                    ],
+                )
+                .to_aml_bytes(),
+            );
+        }
         bytes
     }
 }
@@ -1053,6 +2569,9 @@ impl Pausable for CpuManager {
     fn resume(&mut self) -> std::result::Result<(), MigratableError> {
         // Toggle the vCPUs pause boolean
         self.vcpus_pause_signalled.store(false, Ordering::SeqCst);
+        for state in self.vcpu_states.iter() {
+            state.paused.store(false, Ordering::SeqCst);
+        }
 
         // Unpark all the VCPU threads.
         // Once unparked, the next thing they will do is checking for the pause
@@ -1065,5 +2584,279 @@ impl Pausable for CpuManager {
     }
 }
 
-impl Snapshotable for CpuManager {}
+impl Snapshotable for CpuManager {
+    fn id(&self) -> String {
+        "cpu-manager".to_string()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        if !self.vcpus_pause_signalled.load(Ordering::SeqCst)
+            || self
+                .vcpu_states
+                .iter()
+                .any(|s| s.active() && !s.paused.load(Ordering::SeqCst))
+        {
+            return Err(MigratableError::Snapshot(anyhow!(
+                "The vCPUs aren't paused"
+            )));
+        }
+
+        let msr_indexes = Kvm::new()
+            .and_then(|kvm| kvm.get_msr_index_list())
+            .map_err(|e| MigratableError::Snapshot(anyhow!("{:?}", Error::MsrIndexList(e))))?;
+
+        let mut snapshot = Snapshot::new(&self.id());
+        for (cpu_id, state) in self.vcpu_states.iter().enumerate() {
+            if !state.active() {
+                continue;
+            }
+            let vcpu_state = state
+                .vcpu
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .state(msr_indexes.as_slice())
+                .map_err(|e| MigratableError::Snapshot(anyhow!("vCPU {}: {:?}", cpu_id, e)))?;
+            let mut vcpu_snapshot = Snapshot::new(&format!("vcpu-{}", cpu_id));
+            vcpu_snapshot.add_state("vcpu", &vcpu_state)?;
+            snapshot.add_snapshot(vcpu_snapshot);
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Start the vCPUs the snapshot was taken with, paused, from their saved
+    /// state. The VM must have been created with the configuration of the
+    /// snapshot, none of its vCPUs being started yet.
+    fn restore(&mut self, mut snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        let mut vcpu_states = Vec::new();
+        while let Ok(vcpu_snapshot) = snapshot.take_snapshot(&format!("vcpu-{}", vcpu_states.len()))
+        {
+            vcpu_states.push(vcpu_snapshot.state::<VcpuSnapshot>("vcpu")?);
+        }
+        if !snapshot.snapshots.is_empty()
+            || vcpu_states.is_empty()
+            || vcpu_states.len() > usize::from(self.max_vcpus)
+        {
+            return Err(MigratableError::Restore(anyhow!(
+                "Invalid vCPUs in the snapshot"
+            )));
+        }
+
+        self.vcpus_pause_signalled.store(true, Ordering::SeqCst);
+        self.activate_vcpus(vcpu_states.len() as u8, None, None, false)
+            .map_err(|e| MigratableError::Restore(anyhow!("{:?}", e)))?;
+
+        for (cpu_id, vcpu_state) in vcpu_states.iter().enumerate() {
+            let state = &self.vcpu_states[cpu_id];
+            // The vCPU thread parks right after configuring the vCPU.
+            while !state.paused.load(Ordering::SeqCst) && !state.exited.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_micros(VCPU_PAUSE_POLL_US));
+            }
+            if state.exited.load(Ordering::SeqCst) {
+                return Err(MigratableError::Restore(anyhow!(
+                    "vCPU {} failed to start",
+                    cpu_id
+                )));
+            }
+            state
+                .vcpu
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .set_state(vcpu_state)
+                .map_err(|e| MigratableError::Restore(anyhow!("vCPU {}: {:?}", cpu_id, e)))?;
+        }
+
+        Ok(())
+    }
+}
 impl Migratable for CpuManager {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A host supporting every feature, with the AVX and AVX-512 XSAVE
+    // components.
+    fn host_cpuid() -> CpuId {
+        let leaf =
+            |function: u32, index: u32, eax: u32, ebx: u32, ecx: u32, edx: u32| kvm_cpuid_entry2 {
+                function,
+                index,
+                eax,
+                ebx,
+                ecx,
+                edx,
+                ..Default::default()
+            };
+        CpuId::from_entries(&[
+            leaf(0x0, 0, 0xd, 0, 0, 0),
+            leaf(0x1, 0, 0, 0, 0xffff_ffff, 0xffff_ffff),
+            leaf(0x7, 0, 1, 0xffff_ffff, 0xffff_ffff, 0xffff_ffff),
+            leaf(0x7, 1, 0xffff_ffff, 0, 0, 0),
+            leaf(0xd, 0, 0xe7, 2688, 2688, 0),
+            leaf(0xd, 1, 0xf, 0, 0x100, 0),
+            leaf(0xd, 2, 256, 576, 0, 0),
+            leaf(0xd, 5, 64, 1088, 0, 0),
+            leaf(0xd, 6, 512, 1152, 0, 0),
+            leaf(0xd, 7, 1024, 1664, 0, 0),
+            leaf(0x8000_0001, 0, 0, 0, 0xffff_ffff, 0xffff_ffff),
+        ])
+    }
+
+    fn leaf(cpuid: &CpuId, function: u32, index: u32) -> kvm_cpuid_entry2 {
+        *cpuid
+            .as_slice()
+            .iter()
+            .find(|entry| entry.function == function && entry.index == index)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_cpuid_model_whitelist() {
+        let mut cpuid = host_cpuid();
+        CpuidPatch::patch_cpuid_model(&mut cpuid, "haswell").unwrap();
+
+        let leaf_1 = leaf(&cpuid, 0x1, 0);
+        // AVX and XSAVE are part of the model, MONITOR isn't.
+        assert_ne!(leaf_1.ecx & (1 << 28), 0);
+        assert_ne!(leaf_1.ecx & (1 << 26), 0);
+        assert_eq!(leaf_1.ecx & (1 << 3), 0);
+        // The hypervisor bit and the VMX bit are left alone.
+        assert_ne!(leaf_1.ecx & (1 << 31), 0);
+        assert_ne!(leaf_1.ecx & (1 << VMX_ECX_BIT), 0);
+        // So are the architectural features.
+        assert_eq!(leaf_1.edx & 0x0789_ebff, 0x0789_ebff);
+
+        let leaf_7 = leaf(&cpuid, 0x7, 0);
+        assert_ne!(leaf_7.ebx & (1 << 5), 0);
+        assert_eq!(leaf_7.ebx & (1 << 16), 0);
+        assert_eq!(leaf_7.ecx, 0);
+        assert_eq!(leaf_7.edx, 0);
+        assert_eq!(leaf(&cpuid, 0x7, 1).eax, 0);
+
+        // XSAVEOPT only.
+        assert_eq!(leaf(&cpuid, 0xd, 1).eax, 0x1);
+        assert_eq!(leaf(&cpuid, 0xd, 1).ecx, 0);
+
+        let leaf_ext = leaf(&cpuid, 0x8000_0001, 0);
+        assert_eq!(leaf_ext.ecx, 1 | 1 << SVM_ECX_BIT | 1 << 5);
+        assert_eq!(leaf_ext.edx, 0x2193_fbff | 1 << 27);
+    }
+
+    #[test]
+    fn test_cpuid_model_leaf_7() {
+        let mut cpuid = host_cpuid();
+        CpuidPatch::patch_cpuid_model(&mut cpuid, "cascadelake-server").unwrap();
+
+        let leaf_7 = leaf(&cpuid, 0x7, 0);
+        // PKU and AVX-512 VNNI, but no VBMI.
+        assert_eq!(leaf_7.ecx, 1 << 3 | 1 << 11);
+        assert_eq!(leaf_7.edx, 0);
+        assert_eq!(leaf(&cpuid, 0x7, 1).eax, 0);
+        assert_eq!(leaf(&cpuid, 0xd, 1).eax, 0x7);
+    }
+
+    #[test]
+    fn test_cpuid_model_xsave() {
+        let mut cpuid = host_cpuid();
+        CpuidPatch::patch_cpuid_model(&mut cpuid, "haswell").unwrap();
+
+        // x87, SSE and AVX, the AVX-512 components being dropped.
+        let leaf_d = leaf(&cpuid, 0xd, 0);
+        assert_eq!(leaf_d.eax, 0x7);
+        assert_eq!(leaf_d.edx, 0);
+        assert_eq!(leaf_d.ecx, 832);
+        assert_eq!(leaf(&cpuid, 0xd, 2).eax, 256);
+        for index in 5..8 {
+            let component = leaf(&cpuid, 0xd, index);
+            assert_eq!((component.eax, component.ebx), (0, 0));
+        }
+
+        let mut cpuid = host_cpuid();
+        CpuidPatch::patch_cpuid_model(&mut cpuid, "skylake-server").unwrap();
+        let leaf_d = leaf(&cpuid, 0xd, 0);
+        assert_eq!(leaf_d.eax, 0xe7);
+        assert_eq!(leaf_d.ecx, 2688);
+
+        let mut cpuid = host_cpuid();
+        CpuidPatch::patch_cpuid_model(&mut cpuid, "nehalem").unwrap();
+        let leaf_d = leaf(&cpuid, 0xd, 0);
+        assert_eq!(leaf_d.eax, 0x3);
+        assert_eq!(leaf_d.ecx, 576);
+        assert_eq!(leaf(&cpuid, 0xd, 1).eax, 0);
+    }
+
+    #[test]
+    fn test_cpuid_model_signature() {
+        let mut cpuid = host_cpuid();
+        CpuidPatch::patch_cpuid_model(&mut cpuid, "skylake-server").unwrap();
+        let leaf_0 = leaf(&cpuid, 0x0, 0);
+        assert_eq!(&leaf_0.ebx.to_le_bytes(), b"Genu");
+        assert_eq!(&leaf_0.edx.to_le_bytes(), b"ineI");
+        assert_eq!(&leaf_0.ecx.to_le_bytes(), b"ntel");
+        // The maximum leaf is left alone.
+        assert_eq!(leaf_0.eax, 0xd);
+        assert_eq!(leaf(&cpuid, 0x1, 0).eax, 0x5_0654);
+        assert_eq!(leaf(&cpuid, 0x8000_0001, 0).eax, 0);
+
+        let mut cpuid = host_cpuid();
+        CpuidPatch::patch_cpuid_model(&mut cpuid, "epyc").unwrap();
+        let leaf_0 = leaf(&cpuid, 0x0, 0);
+        assert_eq!(&leaf_0.ebx.to_le_bytes(), b"Auth");
+        assert_eq!(&leaf_0.edx.to_le_bytes(), b"enti");
+        assert_eq!(&leaf_0.ecx.to_le_bytes(), b"cAMD");
+        assert_eq!(leaf(&cpuid, 0x1, 0).eax, 0x80_0f12);
+        assert_eq!(leaf(&cpuid, 0x8000_0001, 0).eax, 0x80_0f12);
+    }
+
+    #[test]
+    fn test_cpuid_model_unsupported() {
+        let mut cpuid = host_cpuid();
+        for entry in cpuid.as_mut_slice().iter_mut() {
+            if entry.function == 0x1 {
+                entry.ecx &= !(1 << 28);
+            }
+        }
+        match CpuidPatch::patch_cpuid_model(&mut cpuid, "sandybridge") {
+            Err(Error::CpuModelFeatureNotSupported(name)) => assert_eq!(name, "avx"),
+            _ => panic!("AVX is missing from the host"),
+        }
+        assert!(CpuidPatch::patch_cpuid_model(&mut cpuid, "westmere").is_ok());
+
+        match CpuidPatch::patch_cpuid_model(&mut host_cpuid(), "pentium") {
+            Err(Error::UnknownCpuModel(name)) => assert_eq!(name, "pentium"),
+            _ => panic!("There's no such model"),
+        }
+    }
+
+    #[test]
+    fn test_thread_cpu_time() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+        // A thread sleeping, as a halted vCPU does, doesn't consume any CPU
+        // time.
+        let sleeper = thread::spawn(move || {
+            tx.send(unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t)
+                .unwrap();
+            done_rx.recv().unwrap();
+        });
+        let tid = rx.recv().unwrap();
+        let start = thread_cpu_time(tid).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(thread_cpu_time(tid).unwrap() - start < 50_000);
+
+        // Whereas a busy one does.
+        let own_tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+        let start = thread_cpu_time(own_tid).unwrap();
+        let busy_start = std::time::Instant::now();
+        while busy_start.elapsed() < Duration::from_millis(100) {}
+        assert!(thread_cpu_time(own_tid).unwrap() - start >= 50_000);
+
+        done_tx.send(()).unwrap();
+        sleeper.join().unwrap();
+    }
+}
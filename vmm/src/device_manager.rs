@@ -11,14 +11,28 @@
 
 extern crate vm_device;
 
+use crate::cgroup::VmCgroups;
 use crate::config::ConsoleOutputMode;
-use crate::config::{DiskConfig, NetConfig, VmConfig};
+#[cfg(feature = "pci_support")]
+use crate::config::{DeviceConfig, DeviceResetMethod};
+use crate::config::{
+    DiskConfig, DiskErrorPolicy, HotplugMethod, MemoryOvercommit, NetConfig, PciAddress,
+    RateLimiterConfig, TokenBucketConfig, VmConfig,
+};
+use crate::console_socket::{ConsoleSocket, ConsoleSocketReader};
+use crate::guest_os::{GuestOs, GuestOsProbe, ProbeWriter};
 use crate::interrupt::{
     KvmLegacyUserspaceInterruptManager, KvmMsiInterruptManager, KvmRoutingEntry,
 };
 use crate::memory_manager::{Error as MemoryManagerError, MemoryManager};
+use crate::pty::{Pty, PtyReader};
+#[cfg(feature = "pci_support")]
+use crate::sriov;
+use crate::state_dir;
+use crate::virtiofsd::{self, Virtiofsd};
 #[cfg(feature = "acpi")]
 use acpi_tables::{aml, aml::Aml};
+use anyhow::anyhow;
 #[cfg(feature = "acpi")]
 use arch::layout;
 use arch::layout::{APIC_START, IOAPIC_SIZE, IOAPIC_START};
@@ -30,24 +44,28 @@ use libc::TIOCGWINSZ;
 use pci::{
     DeviceRelocation, PciBarRegionType, PciBus, PciConfigIo, PciConfigMmio, PciDevice, PciRoot,
 };
-use qcow::{self, ImageType, QcowFile};
+use qcow::{self, ImageType, QcowFile, VhdFile, VhdxFile};
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, sink, stdout};
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::OpenOptionsExt;
-use std::path::PathBuf;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
 use std::result;
 #[cfg(feature = "pci_support")]
 use std::sync::Weak;
 use std::sync::{Arc, Mutex};
 use tempfile::NamedTempFile;
 #[cfg(feature = "pci_support")]
-use vfio::{VfioDevice, VfioDmaMapping, VfioPciDevice, VfioPciError};
+use vfio::{
+    VfioDevice, VfioDmaMapping, VfioPciDevice, VfioPciError, VfioResetMethod, VfioResetPolicy,
+};
 use vm_allocator::SystemAllocator;
 use vm_device::interrupt::{
     InterruptIndex, InterruptManager, LegacyIrqGroupConfig, MsiIrqGroupConfig,
 };
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
 use vm_memory::guest_memory::FileOffset;
 #[cfg(feature = "cmos")]
 use vm_memory::GuestAddressSpace;
@@ -64,6 +82,12 @@ use vmm_sys_util::eventfd::EventFd;
 #[cfg(feature = "mmio_support")]
 const MMIO_LEN: u64 = 0x1000;
 
+// Set when the host VFIO driver accepts devices whose MSIs can't be isolated,
+// for lack of interrupt remapping.
+#[cfg(feature = "pci_support")]
+const VFIO_UNSAFE_INTERRUPTS_PARAM: &str =
+    "/sys/module/vfio_iommu_type1/parameters/allow_unsafe_interrupts";
+
 /// Errors associated with device manager
 #[derive(Debug)]
 pub enum DeviceManagerError {
@@ -73,12 +97,39 @@ pub enum DeviceManagerError {
     /// Cannot open disk path
     Disk(io::Error),
 
+    /// Cannot connect to the NBD export
+    NbdConnect(io::Error),
+
+    /// No virtio-blk device uses this disk
+    DiskNotResizable(PathBuf),
+
+    /// Cannot resize the disk
+    DiskResize(io::Error),
+
+    /// No virtio-blk device tracks the blocks written to this disk
+    DiskNotTracked(PathBuf),
+
+    /// No virtio-net device emulated by the VMM has this id
+    NetNotUpdatable(String),
+
+    /// Cannot get the blocks changed since the checkpoint
+    DiskChangedBlocks(vm_virtio::ChangedBlocksError),
+
     /// Cannot create vhost-user-net device
     CreateVhostUserNet(vm_virtio::vhost_user::Error),
 
+    /// Cannot create vDPA device
+    CreateVdpa(vm_virtio::vdpa::Error),
+
     /// Cannot create virtio-blk device
     CreateVirtioBlock(io::Error),
 
+    /// Cannot open SCSI LUN path
+    ScsiLun(io::Error),
+
+    /// Cannot create virtio-scsi device
+    CreateVirtioScsi(io::Error),
+
     /// Cannot create virtio-net device
     CreateVirtioNet(vm_virtio::net::Error),
 
@@ -88,9 +139,41 @@ pub enum DeviceManagerError {
     /// Cannot create virtio-rng device
     CreateVirtioRng(io::Error),
 
+    /// Cannot create virtio-balloon device
+    CreateVirtioBalloon(io::Error),
+
+    /// The balloon relies on the guest memory being shared with the host,
+    /// which SEV prevents
+    SevBalloon,
+
+    /// The virtio device can't offer VIRTIO_F_IOMMU_PLATFORM, which SEV
+    /// guests need to share their DMA buffers with the host
+    SevAccessPlatform(String),
+
+    /// Cannot create virtio-mem device
+    CreateVirtioMem(io::Error),
+
     /// Cannot create virtio-fs device
     CreateVirtioFs(vm_virtio::vhost_user::Error),
 
+    /// Cannot create the virtio-fs device serving a shared directory
+    CreateBuiltinFs(io::Error),
+
+    /// A virtio-fs device needs either a vhost-user socket or a shared directory
+    InvalidFsBackend,
+
+    /// Spawning virtiofsd needs both its socket and the shared directory
+    InvalidFsSpawn,
+
+    /// Only one disk can be the boot one
+    MultipleBootDisks,
+
+    /// The disk serial number is too long
+    InvalidDiskSerial,
+
+    /// Cannot spawn or supervise virtiofsd
+    Virtiofsd(virtiofsd::Error),
+
     /// Cannot create vhost-user-blk device
     CreateVhostUserBlk(vm_virtio::vhost_user::Error),
 
@@ -115,6 +198,15 @@ pub enum DeviceManagerError {
     /// Cannot open qcow disk path
     QcowDeviceCreate(qcow::Error),
 
+    /// Cannot open VHD disk path
+    VhdDeviceCreate(qcow::vhd::Error),
+
+    /// Cannot open VHDX disk path
+    VhdxDeviceCreate(qcow::vhdx::Error),
+
+    /// Cannot open disk overlay path
+    OverlayDeviceCreate(qcow::Error),
+
     /// Cannot open tap interface
     OpenTap(net_util::TapError),
 
@@ -144,6 +236,12 @@ pub enum DeviceManagerError {
     /// Cannot set persistent memory file size
     PmemFileSetLen(io::Error),
 
+    /// Cannot get persistent memory file size
+    PmemFileMetadata(io::Error),
+
+    /// The size of a persistent memory backed by a temporary file is needed
+    PmemSizeMissing,
+
     /// Cannot find a memory range for persistent memory
     PmemRangeAllocation,
 
@@ -156,6 +254,24 @@ pub enum DeviceManagerError {
     /// Error creating console output file
     ConsoleOutputFileOpen(io::Error),
 
+    /// Error creating console port output file
+    ConsolePortFileOpen(io::Error),
+
+    /// Cannot allocate a pty for the serial port or the console
+    CreatePty(io::Error),
+
+    /// Cannot spawn the thread reading the input from a pty
+    PtyReaderSpawn(io::Error),
+
+    /// Cannot bind the socket the serial port or the console is served on
+    CreateConsoleSocket(io::Error),
+
+    /// Cannot spawn the thread serving the clients of a console socket
+    ConsoleSocketReaderSpawn(io::Error),
+
+    /// Error binding console port socket
+    ConsolePortSocketBind(io::Error),
+
     /// Cannot create a VFIO device
     #[cfg(feature = "pci_support")]
     VfioCreate(vfio::VfioError),
@@ -168,6 +284,22 @@ pub enum DeviceManagerError {
     #[cfg(feature = "pci_support")]
     VfioMapRegion(VfioPciError),
 
+    /// Failed to let VFIO devices DMA to each other.
+    #[cfg(feature = "pci_support")]
+    VfioP2pDma(VfioPciError),
+
+    /// Peer-to-peer DMA can't go through the virtual IOMMU.
+    #[cfg(feature = "pci_support")]
+    VfioP2pDmaIommu,
+
+    /// Failed to find out a SR-IOV VF sysfs path.
+    #[cfg(feature = "pci_support")]
+    SriovVf(crate::sriov::Error),
+
+    /// Cannot create an NVMe controller.
+    #[cfg(feature = "pci_support")]
+    CreateNvme(nvme::Error),
+
     /// Failed to create the KVM device.
     CreateKvmDevice(kvm_ioctls::Error),
 
@@ -214,6 +346,78 @@ pub type DeviceManagerResult<T> = result::Result<T, DeviceManagerError>;
 
 type VirtioDeviceArc = Arc<Mutex<dyn vm_virtio::VirtioDevice>>;
 
+// A device the VMM stops on shutdown, outside of the virtio ones.
+trait Teardown: Send {
+    fn teardown(&mut self);
+}
+
+#[cfg(feature = "pci_support")]
+impl<T: 'static + vm_virtio::DiskFile + Send> Teardown for nvme::NvmeController<T> {
+    fn teardown(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(feature = "pci_support")]
+impl Teardown for VfioPciDevice {
+    fn teardown(&mut self) {
+        if let Err(e) = self.release() {
+            error!("Failed releasing VFIO device: {}", e);
+        }
+    }
+}
+
+// Stops the devices, the ones backed by a disk image first: the virtio-blk
+// and virtio-scsi devices, then the NVMe controllers. The other virtio
+// devices follow, and the VFIO devices are released last.
+fn shutdown_devices(
+    virtio_devices: Vec<VirtioDeviceArc>,
+    nvme_devices: Vec<Arc<Mutex<dyn Teardown>>>,
+    vfio_devices: Vec<Arc<Mutex<dyn Teardown>>>,
+) {
+    let device_type = |device: &VirtioDeviceArc| device.lock().unwrap().device_type();
+    let (blocks, others): (Vec<_>, Vec<_>) = virtio_devices
+        .into_iter()
+        .partition(|device| device_type(device) == vm_virtio::VirtioDeviceType::TYPE_BLOCK as u32);
+    let (scsis, others): (Vec<_>, Vec<_>) = others
+        .into_iter()
+        .partition(|device| device_type(device) == vm_virtio::VirtioDeviceType::TYPE_SCSI as u32);
+
+    for device in blocks.iter().chain(scsis.iter()) {
+        device.lock().unwrap().shutdown();
+    }
+    for device in nvme_devices.iter() {
+        device.lock().unwrap().teardown();
+    }
+    for device in others.iter() {
+        device.lock().unwrap().shutdown();
+    }
+    for device in vfio_devices.iter() {
+        device.lock().unwrap().teardown();
+    }
+}
+
+// The number a device gets on the PCI bus, the one from its PCI address if
+// it was given one.
+#[cfg(feature = "pci_support")]
+fn pci_bus_device_id(pci: &PciBus, pci_address: Option<PciAddress>) -> DeviceManagerResult<u32> {
+    match pci_address {
+        Some(address) => Ok(u32::from(address.device)),
+        None => pci
+            .next_device_id()
+            .map_err(DeviceManagerError::AddPciDevice),
+    }
+}
+
+// Whether the host isolates the MSIs of the assigned devices, which is what
+// keeps them isolated once assigned again from the guest.
+#[cfg(feature = "pci_support")]
+fn host_msi_isolation() -> bool {
+    fs::read_to_string(VFIO_UNSAFE_INTERRUPTS_PARAM)
+        .map(|allowed| allowed.trim() != "Y")
+        .unwrap_or(true)
+}
+
 pub fn get_win_size() -> (u16, u16) {
     #[repr(C)]
     struct WS {
@@ -231,12 +435,48 @@ pub fn get_win_size() -> (u16, u16) {
     (ws.cols, ws.rows)
 }
 
+fn rate_limiter(config: &RateLimiterConfig) -> vm_virtio::RateLimiter {
+    let token_bucket = |config: &Option<TokenBucketConfig>| {
+        config.as_ref().and_then(|config| {
+            vm_virtio::TokenBucket::new(config.size, config.one_time_burst, config.refill_time)
+        })
+    };
+
+    vm_virtio::RateLimiter::new(token_bucket(&config.bandwidth), token_bucket(&config.ops))
+}
+
+fn net_offloads(config: &NetConfig) -> vm_virtio::NetOffloads {
+    vm_virtio::NetOffloads {
+        csum: config.offload_csum,
+        tso: config.offload_tso,
+        ufo: config.offload_ufo,
+    }
+}
+
+// The serial number of a disk which isn't given one. It only depends on the
+// disk image path, so that the guest /dev/disk/by-id links are the same from
+// one boot to the next.
+fn default_disk_serial(path: &Path) -> String {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    // 64-bit FNV-1a, as the std hashers aren't guaranteed to be stable.
+    let hash = path
+        .as_os_str()
+        .as_bytes()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    format!("ch-{:016x}", hash)
+}
+
 #[derive(Default)]
 pub struct Console {
     // Serial port on 0x3f8
     serial: Option<Arc<Mutex<devices::legacy::Serial>>>,
     console_input: Option<Arc<vm_virtio::ConsoleInput>>,
     input_enabled: bool,
+    serial_pty: Option<PathBuf>,
+    console_pty: Option<PathBuf>,
 }
 
 impl Console {
@@ -269,6 +509,21 @@ impl Console {
     pub fn input_enabled(&self) -> bool {
         self.input_enabled
     }
+
+    /// The pty the serial port is exposed on, if any.
+    pub fn serial_pty(&self) -> Option<&Path> {
+        self.serial_pty.as_deref()
+    }
+
+    /// The pty the virtio-console is exposed on, if any.
+    pub fn console_pty(&self) -> Option<&Path> {
+        self.console_pty.as_deref()
+    }
+}
+
+// The guest output is appended to the file, which is created if needed.
+fn open_output_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
 }
 
 struct AddressManager {
@@ -418,20 +673,73 @@ pub struct DeviceManager {
     // Migratable devices
     migratable_devices: Vec<Arc<Mutex<dyn Migratable>>>,
 
+    // The virtio-pci transports, also among the migratable devices, to be
+    // finished restoring once all the devices are
+    #[cfg(feature = "pci_support")]
+    virtio_pci_devices: Vec<Arc<Mutex<VirtioPciDevice>>>,
+
+    // The VFIO devices, to be released once the vCPUs are stopped
+    #[cfg(feature = "pci_support")]
+    vfio_devices: Vec<Arc<Mutex<VfioPciDevice>>>,
+
+    // The NVMe controllers, to be stopped along with the virtio disks
+    #[cfg(feature = "pci_support")]
+    nvme_devices: Vec<Arc<Mutex<dyn Teardown>>>,
+
     // Memory Manager
     memory_manager: Arc<Mutex<MemoryManager>>,
 
     // The virtio devices on the system
-    virtio_devices: Vec<(VirtioDeviceArc, bool)>,
+    virtio_devices: Vec<(VirtioDeviceArc, bool, Option<PciAddress>)>,
 
     // The path to the VMM for self spawning
     vmm_path: PathBuf,
 
     // Backends that have been spawned
     vhost_user_backends: Vec<ActivatedBackend>,
+
+    // virtiofsd instances that have been spawned
+    virtiofsd: Vec<Virtiofsd>,
+
+    // Balloon device, if any
+    balloon: Option<Arc<Mutex<vm_virtio::Balloon>>>,
+
+    // virtio-mem device, if any
+    virtio_mem: Option<Arc<Mutex<vm_virtio::Mem>>>,
+
+    // virtio-blk devices that can follow the size of their disk image
+    resizable_disks: Vec<(PathBuf, Arc<Mutex<dyn vm_virtio::DiskResize>>)>,
+
+    // Blocks written to the virtio-blk devices tracking them
+    tracked_disks: Vec<(PathBuf, Arc<vm_virtio::ChangedBlocks>)>,
+
+    // virtio-net devices which can be updated while running, by id
+    updatable_nets: Vec<(String, Arc<Mutex<vm_virtio::Net>>)>,
+
+    // Whether the host supports io_uring, lazily checked
+    #[cfg(feature = "io_uring")]
+    io_uring_supported: Option<bool>,
+
+    // Signals the VMM that a disk stopped on an I/O error
+    disk_evt: EventFd,
+
+    // Guest OS detected from the guest output
+    guest_os_probe: Arc<GuestOsProbe>,
+
+    // Threads passing the input from the ptys to the serial port and the
+    // virtio-console
+    pty_readers: Vec<PtyReader>,
+
+    // Threads serving the clients of the serial port and the virtio-console
+    // sockets
+    console_socket_readers: Vec<ConsoleSocketReader>,
+
+    // The cgroups the device threads spawned on activation go to
+    cgroups: Option<Arc<VmCgroups>>,
 }
 
 impl DeviceManager {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         vm_fd: Arc<VmFd>,
         config: Arc<Mutex<VmConfig>>,
@@ -439,13 +747,17 @@ impl DeviceManager {
         memory_manager: Arc<Mutex<MemoryManager>>,
         _exit_evt: &EventFd,
         reset_evt: &EventFd,
+        panic_evt: &EventFd,
+        hibernate_evt: &EventFd,
+        disk_evt: &EventFd,
         vmm_path: PathBuf,
+        cgroups: Option<Arc<VmCgroups>>,
     ) -> DeviceManagerResult<Self> {
         let io_bus = devices::Bus::new();
         let mmio_bus = devices::Bus::new();
 
-        let mut virtio_devices: Vec<(Arc<Mutex<dyn vm_virtio::VirtioDevice>>, bool)> = Vec::new();
-        let migratable_devices: Vec<Arc<Mutex<dyn Migratable>>> = Vec::new();
+        let mut virtio_devices: Vec<(VirtioDeviceArc, bool, Option<PciAddress>)> = Vec::new();
+        let mut migratable_devices: Vec<Arc<Mutex<dyn Migratable>>> = Vec::new();
         let mut _mmap_regions = Vec::new();
 
         #[allow(unused_mut)]
@@ -480,6 +792,7 @@ impl DeviceManager {
 
         let ioapic =
             DeviceManager::add_ioapic(&address_manager, Arc::clone(&msi_interrupt_manager))?;
+        migratable_devices.push(ioapic.clone() as Arc<Mutex<dyn Migratable>>);
 
         // Now we can create the legacy interrupt manager, which needs the freshly
         // formed IOAPIC device.
@@ -511,14 +824,35 @@ impl DeviceManager {
             ged_notification_device: None,
             config,
             migratable_devices,
+            #[cfg(feature = "pci_support")]
+            virtio_pci_devices: Vec::new(),
+            #[cfg(feature = "pci_support")]
+            vfio_devices: Vec::new(),
+            #[cfg(feature = "pci_support")]
+            nvme_devices: Vec::new(),
             memory_manager,
             virtio_devices: Vec::new(),
             vmm_path,
             vhost_user_backends: Vec::new(),
+            virtiofsd: Vec::new(),
+            balloon: None,
+            virtio_mem: None,
+            resizable_disks: Vec::new(),
+            tracked_disks: Vec::new(),
+            updatable_nets: Vec::new(),
+            #[cfg(feature = "io_uring")]
+            io_uring_supported: None,
+            disk_evt: disk_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
+            guest_os_probe: Arc::new(GuestOsProbe::new()),
+            pty_readers: Vec::new(),
+            console_socket_readers: Vec::new(),
+            cgroups,
         };
 
-        device_manager
-            .add_legacy_devices(reset_evt.try_clone().map_err(DeviceManagerError::EventFd)?)?;
+        device_manager.add_legacy_devices(
+            reset_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
+            panic_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
+        )?;
 
         #[cfg(feature = "acpi")]
         {
@@ -526,6 +860,9 @@ impl DeviceManager {
                 &legacy_interrupt_manager,
                 reset_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
                 _exit_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
+                hibernate_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
             )?;
         }
 
@@ -535,6 +872,8 @@ impl DeviceManager {
         #[cfg(any(feature = "pci_support", feature = "mmio_support"))]
         virtio_devices.append(&mut device_manager.make_virtio_devices()?);
 
+        device_manager.check_sev_devices(&virtio_devices)?;
+
         if cfg!(feature = "pci_support") {
             device_manager.add_pci_devices(virtio_devices.clone(), &msi_interrupt_manager)?;
         } else if cfg!(feature = "mmio_support") {
@@ -549,7 +888,7 @@ impl DeviceManager {
     #[allow(unused_variables)]
     fn add_pci_devices(
         &mut self,
-        virtio_devices: Vec<(Arc<Mutex<dyn vm_virtio::VirtioDevice>>, bool)>,
+        virtio_devices: Vec<(VirtioDeviceArc, bool, Option<PciAddress>)>,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
     ) -> DeviceManagerResult<()> {
         #[cfg(feature = "pci_support")]
@@ -568,17 +907,30 @@ impl DeviceManager {
                 (None, None)
             };
 
+            // The devices given a PCI address get it, whatever the order they
+            // are added in.
+            for address in self.config.lock().unwrap().pci_addresses() {
+                pci_bus
+                    .reserve_device_id(u32::from(address.device))
+                    .map_err(DeviceManagerError::AddPciDevice)?;
+            }
+
             let mut iommu_attached_devices = Vec::new();
 
-            for (device, iommu_attached) in virtio_devices {
+            for (device, iommu_attached, pci_address) in virtio_devices {
                 let mapping: &Option<Arc<IommuMapping>> = if iommu_attached {
                     &iommu_mapping
                 } else {
                     &None
                 };
 
-                let virtio_iommu_attach_dev =
-                    self.add_virtio_pci_device(device, &mut pci_bus, mapping, interrupt_manager)?;
+                let virtio_iommu_attach_dev = self.add_virtio_pci_device(
+                    device,
+                    &mut pci_bus,
+                    mapping,
+                    interrupt_manager,
+                    pci_address,
+                )?;
 
                 if let Some(dev_id) = virtio_iommu_attach_dev {
                     iommu_attached_devices.push(dev_id);
@@ -590,6 +942,8 @@ impl DeviceManager {
 
             iommu_attached_devices.append(&mut vfio_iommu_device_ids);
 
+            self.add_nvme_devices(&mut pci_bus, interrupt_manager)?;
+
             if let Some(mut iommu_device) = iommu_device {
                 iommu_device.attach_pci_devices(0, iommu_attached_devices);
 
@@ -601,6 +955,7 @@ impl DeviceManager {
                     &mut pci_bus,
                     &None,
                     interrupt_manager,
+                    None,
                 )?;
             }
 
@@ -627,12 +982,12 @@ impl DeviceManager {
     #[allow(unused_variables, unused_mut)]
     fn add_mmio_devices(
         &mut self,
-        virtio_devices: Vec<(Arc<Mutex<dyn vm_virtio::VirtioDevice>>, bool)>,
+        virtio_devices: Vec<(VirtioDeviceArc, bool, Option<PciAddress>)>,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
     ) -> DeviceManagerResult<()> {
         #[cfg(feature = "mmio_support")]
         {
-            for (device, _) in virtio_devices {
+            for (device, _, _) in virtio_devices {
                 let mmio_addr = self
                     .address_manager
                     .allocator
@@ -674,9 +1029,12 @@ impl DeviceManager {
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
         reset_evt: EventFd,
         exit_evt: EventFd,
+        hibernate_evt: EventFd,
     ) -> DeviceManagerResult<Option<Arc<Mutex<devices::AcpiGEDDevice>>>> {
         let acpi_device = Arc::new(Mutex::new(devices::AcpiShutdownDevice::new(
-            exit_evt, reset_evt,
+            exit_evt,
+            reset_evt,
+            hibernate_evt,
         )));
 
         self.address_manager
@@ -724,7 +1082,11 @@ impl DeviceManager {
         Ok(Some(ged_device))
     }
 
-    fn add_legacy_devices(&mut self, reset_evt: EventFd) -> DeviceManagerResult<()> {
+    fn add_legacy_devices(
+        &mut self,
+        reset_evt: EventFd,
+        panic_evt: EventFd,
+    ) -> DeviceManagerResult<()> {
         // Add a shutdown device (i8042)
         let i8042 = Arc::new(Mutex::new(devices::legacy::I8042Device::new(reset_evt)));
 
@@ -732,19 +1094,46 @@ impl DeviceManager {
             .io_bus
             .insert(i8042, 0x61, 0x4)
             .map_err(DeviceManagerError::BusError)?;
+
+        // Add a debug console, capturing the firmware debug output into the
+        // VMM log, and looking for the OS the firmware boots in it.
+        let debug_console = Arc::new(Mutex::new(devices::legacy::DebugConsole::with_output(
+            Box::new(ProbeWriter::new(sink(), self.guest_os_probe.clone())),
+        )));
+
+        self.address_manager
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate_io_addresses(Some(GuestAddress(0x402)), 0x1, None)
+            .ok_or(DeviceManagerError::AllocateIOPort)?;
+
+        self.address_manager
+            .io_bus
+            .insert(debug_console, 0x402, 0x1)
+            .map_err(DeviceManagerError::BusError)?;
+
+        // Add a pvpanic device, through which the guest reports its panics.
+        let pvpanic = Arc::new(Mutex::new(devices::legacy::Pvpanic::new(panic_evt)));
+
+        self.address_manager
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate_io_addresses(Some(GuestAddress(0x505)), 0x1, None)
+            .ok_or(DeviceManagerError::AllocateIOPort)?;
+
+        self.address_manager
+            .io_bus
+            .insert(pvpanic, 0x505, 0x1)
+            .map_err(DeviceManagerError::BusError)?;
+
         #[cfg(feature = "cmos")]
         {
             // Add a CMOS emulated device
-            use vm_memory::GuestMemory;
-            let mem_size = self
-                .memory_manager
-                .lock()
-                .unwrap()
-                .guest_memory()
-                .memory()
-                .last_addr()
-                .0
-                + 1;
+            let mem_size =
+                arch::boot_ram_end(&self.memory_manager.lock().unwrap().guest_memory().memory()).0
+                    + 1;
             let mem_below_4g = std::cmp::min(arch::layout::MEM_32BIT_RESERVED_START.0, mem_size);
             let mem_above_4g = mem_size.saturating_sub(arch::layout::RAM_64BIT_START.0);
 
@@ -755,8 +1144,11 @@ impl DeviceManager {
 
             self.address_manager
                 .io_bus
-                .insert(cmos, 0x70, 0x2)
+                .insert(cmos.clone(), 0x70, 0x2)
                 .map_err(DeviceManagerError::BusError)?;
+
+            self.migratable_devices
+                .push(cmos as Arc<Mutex<dyn Migratable>>);
         }
 
         Ok(())
@@ -765,17 +1157,56 @@ impl DeviceManager {
     fn add_console_device(
         &mut self,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
-        virtio_devices: &mut Vec<(Arc<Mutex<dyn vm_virtio::VirtioDevice>>, bool)>,
+        virtio_devices: &mut Vec<(VirtioDeviceArc, bool, Option<PciAddress>)>,
     ) -> DeviceManagerResult<Arc<Console>> {
+        let sev = self.sev_enabled();
         let serial_config = self.config.lock().unwrap().serial.clone();
+        let state_dir = self.config.lock().unwrap().state_dir.clone();
+        let serial_pty = if serial_config.mode == ConsoleOutputMode::Pty {
+            Some(Pty::new().map_err(DeviceManagerError::CreatePty)?)
+        } else {
+            None
+        };
+        let serial_socket = if serial_config.mode == ConsoleOutputMode::Socket {
+            Some(
+                ConsoleSocket::new(&state_dir::resolve(
+                    state_dir.as_deref(),
+                    serial_config.socket.as_ref().unwrap(),
+                ))
+                .map_err(DeviceManagerError::CreateConsoleSocket)?,
+            )
+        } else {
+            None
+        };
         let serial_writer: Option<Box<dyn io::Write + Send>> = match serial_config.mode {
             ConsoleOutputMode::File => Some(Box::new(
-                File::create(serial_config.file.as_ref().unwrap())
-                    .map_err(DeviceManagerError::SerialOutputFileOpen)?,
+                open_output_file(&state_dir::resolve(
+                    state_dir.as_deref(),
+                    serial_config.file.as_ref().unwrap(),
+                ))
+                .map_err(DeviceManagerError::SerialOutputFileOpen)?,
+            )),
+            ConsoleOutputMode::Pty => Some(Box::new(
+                serial_pty
+                    .as_ref()
+                    .unwrap()
+                    .writer()
+                    .map_err(DeviceManagerError::CreatePty)?,
+            )),
+            ConsoleOutputMode::Socket => Some(Box::new(
+                serial_socket
+                    .as_ref()
+                    .unwrap()
+                    .writer()
+                    .map_err(DeviceManagerError::CreateConsoleSocket)?,
             )),
             ConsoleOutputMode::Tty => Some(Box::new(stdout())),
-            ConsoleOutputMode::Off | ConsoleOutputMode::Null => None,
+            ConsoleOutputMode::Null => Some(Box::new(sink())),
+            ConsoleOutputMode::Off => None,
         };
+        let serial_writer = serial_writer.map(|writer| -> Box<dyn io::Write + Send> {
+            Box::new(ProbeWriter::new(writer, self.guest_os_probe.clone()))
+        });
         let serial = if serial_config.mode != ConsoleOutputMode::Off {
             // Serial is tied to IRQ #4
             let serial_irq = 4;
@@ -803,50 +1234,227 @@ impl DeviceManager {
                 .insert(serial.clone(), 0x3f8, 0x8)
                 .map_err(DeviceManagerError::BusError)?;
 
+            self.migratable_devices
+                .push(serial.clone() as Arc<Mutex<dyn Migratable>>);
+
             Some(serial)
         } else {
             None
         };
 
+        let mut serial_pty_path = None;
+        if let (Some(pty), Some(serial)) = (serial_pty, &serial) {
+            info!("Serial port on {:?}", pty.path());
+            serial_pty_path = Some(pty.path().to_path_buf());
+            let serial = serial.clone();
+            self.pty_readers.push(
+                pty.spawn_reader("serial", move |input| {
+                    if let Err(e) = serial.lock().unwrap().queue_input_bytes(input) {
+                        error!("Failed queuing the serial port input: {:?}", e);
+                    }
+                })
+                .map_err(DeviceManagerError::PtyReaderSpawn)?,
+            );
+        }
+        if let (Some(socket), Some(serial)) = (serial_socket, &serial) {
+            info!("Serial port on {:?}", socket.path());
+            let serial = serial.clone();
+            self.console_socket_readers.push(
+                socket
+                    .spawn_reader("serial", move |input| {
+                        if let Err(e) = serial.lock().unwrap().queue_input_bytes(input) {
+                            error!("Failed queuing the serial port input: {:?}", e);
+                        }
+                    })
+                    .map_err(DeviceManagerError::ConsoleSocketReaderSpawn)?,
+            );
+        }
+
         // Create serial and virtio-console
         let console_config = self.config.lock().unwrap().console.clone();
+        let console_pty = if console_config.mode == ConsoleOutputMode::Pty {
+            Some(Pty::new().map_err(DeviceManagerError::CreatePty)?)
+        } else {
+            None
+        };
+        let console_socket = if console_config.mode == ConsoleOutputMode::Socket {
+            Some(
+                ConsoleSocket::new(&state_dir::resolve(
+                    state_dir.as_deref(),
+                    console_config.socket.as_ref().unwrap(),
+                ))
+                .map_err(DeviceManagerError::CreateConsoleSocket)?,
+            )
+        } else {
+            None
+        };
         let console_writer: Option<Box<dyn io::Write + Send + Sync>> = match console_config.mode {
             ConsoleOutputMode::File => Some(Box::new(
-                File::create(console_config.file.as_ref().unwrap())
-                    .map_err(DeviceManagerError::ConsoleOutputFileOpen)?,
+                open_output_file(&state_dir::resolve(
+                    state_dir.as_deref(),
+                    console_config.file.as_ref().unwrap(),
+                ))
+                .map_err(DeviceManagerError::ConsoleOutputFileOpen)?,
+            )),
+            ConsoleOutputMode::Pty => Some(Box::new(
+                console_pty
+                    .as_ref()
+                    .unwrap()
+                    .writer()
+                    .map_err(DeviceManagerError::CreatePty)?,
+            )),
+            ConsoleOutputMode::Socket => Some(Box::new(
+                console_socket
+                    .as_ref()
+                    .unwrap()
+                    .writer()
+                    .map_err(DeviceManagerError::CreateConsoleSocket)?,
             )),
             ConsoleOutputMode::Tty => Some(Box::new(stdout())),
             ConsoleOutputMode::Null => Some(Box::new(sink())),
             ConsoleOutputMode::Off => None,
         };
+        let console_writer = console_writer.map(|writer| -> Box<dyn io::Write + Send + Sync> {
+            Box::new(ProbeWriter::new(writer, self.guest_os_probe.clone()))
+        });
+
+        let console_ports_config = self.config.lock().unwrap().console_ports.clone();
+        let mut console_ports = Vec::new();
+        for port_config in console_ports_config.unwrap_or_default() {
+            let backend = if let Some(file) = &port_config.file {
+                vm_virtio::ConsolePortBackend::File(Box::new(
+                    File::create(state_dir::resolve(state_dir.as_deref(), file))
+                        .map_err(DeviceManagerError::ConsolePortFileOpen)?,
+                ))
+            } else {
+                let socket =
+                    state_dir::resolve(state_dir.as_deref(), port_config.socket.as_ref().unwrap());
+                // A socket left behind by a previous run would prevent the
+                // binding.
+                fs::remove_file(&socket).unwrap_or_default();
+                vm_virtio::ConsolePortBackend::Socket(
+                    UnixListener::bind(&socket)
+                        .map_err(DeviceManagerError::ConsolePortSocketBind)?,
+                )
+            };
+            console_ports.push(vm_virtio::ConsolePort {
+                name: port_config.name,
+                backend,
+            });
+        }
+        // The named ports come with the console port, the guest console
+        // output being discarded when there's no console.
+        let console_writer = match console_writer {
+            None if !console_ports.is_empty() => {
+                Some(Box::new(sink()) as Box<dyn io::Write + Send + Sync>)
+            }
+            console_writer => console_writer,
+        };
+
         let (col, row) = get_win_size();
         let console_input = if let Some(writer) = console_writer {
-            let (virtio_console_device, console_input) =
-                vm_virtio::Console::new(writer, col, row, console_config.iommu)
-                    .map_err(DeviceManagerError::CreateVirtioConsole)?;
+            let (virtio_console_device, console_input) = vm_virtio::Console::new(
+                writer,
+                console_ports,
+                col,
+                row,
+                console_config.iommu || sev,
+                console_config.queue_size,
+            )
+            .map_err(DeviceManagerError::CreateVirtioConsole)?;
+            let virtio_console_device = Arc::new(Mutex::new(virtio_console_device));
             virtio_devices.push((
-                Arc::new(Mutex::new(virtio_console_device))
-                    as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                Arc::clone(&virtio_console_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                 false,
+                None,
             ));
+            self.migratable_devices
+                .push(virtio_console_device as Arc<Mutex<dyn Migratable>>);
             Some(console_input)
         } else {
             None
         };
 
+        let mut console_pty_path = None;
+        if let (Some(pty), Some(console_input)) = (console_pty, &console_input) {
+            info!("Virtio console on {:?}", pty.path());
+            console_pty_path = Some(pty.path().to_path_buf());
+            let console_input = console_input.clone();
+            self.pty_readers.push(
+                pty.spawn_reader("console", move |input| {
+                    console_input.queue_input_bytes(input)
+                })
+                .map_err(DeviceManagerError::PtyReaderSpawn)?,
+            );
+        }
+        if let (Some(socket), Some(console_input)) = (console_socket, &console_input) {
+            info!("Virtio console on {:?}", socket.path());
+            let console_input = console_input.clone();
+            self.console_socket_readers.push(
+                socket
+                    .spawn_reader("console", move |input| {
+                        console_input.queue_input_bytes(input)
+                    })
+                    .map_err(DeviceManagerError::ConsoleSocketReaderSpawn)?,
+            );
+        }
+
         Ok(Arc::new(Console {
             serial,
             console_input,
             input_enabled: serial_config.mode.input_enabled()
                 || console_config.mode.input_enabled(),
+            serial_pty: serial_pty_path,
+            console_pty: console_pty_path,
         }))
     }
 
-    fn make_virtio_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
-        let mut devices: Vec<(Arc<Mutex<dyn vm_virtio::VirtioDevice>>, bool)> = Vec::new();
+    /// Whether the guest memory is encrypted with SEV. The virtio devices then
+    /// offer VIRTIO_F_IOMMU_PLATFORM, even when not behind the virtio-iommu,
+    /// for the guest to bounce its DMA buffers through the memory it shares
+    /// with the host.
+    fn sev_enabled(&self) -> bool {
+        self.config
+            .lock()
+            .unwrap()
+            .platform
+            .as_ref()
+            .map_or(false, |p| p.sev_enabled())
+    }
+
+    // The devices which can't offer VIRTIO_F_IOMMU_PLATFORM, or whose driver
+    // may ignore it, can't work with a SEV guest, which would hand them
+    // encrypted buffers.
+    fn check_sev_devices(
+        &self,
+        devices: &[(VirtioDeviceArc, bool, Option<PciAddress>)],
+    ) -> DeviceManagerResult<()> {
+        if !self.sev_enabled() {
+            return Ok(());
+        }
+
+        for (device, _, _) in devices.iter() {
+            let device = device.lock().unwrap();
+            if device.features() & (1u64 << vm_virtio::VIRTIO_F_IOMMU_PLATFORM) == 0
+                || device.transitional()
+            {
+                return Err(DeviceManagerError::SevAccessPlatform(
+                    vm_virtio::VirtioDeviceType::from(device.device_type()).to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn make_virtio_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<PciAddress>)>> {
+        let mut devices: Vec<(VirtioDeviceArc, bool, Option<PciAddress>)> = Vec::new();
 
         // Create "standard" virtio devices (net/block/rng)
         devices.append(&mut self.make_virtio_block_devices()?);
+        devices.append(&mut self.make_virtio_scsi_devices()?);
         devices.append(&mut self.make_virtio_net_devices()?);
         devices.append(&mut self.make_virtio_rng_devices()?);
 
@@ -865,6 +1473,15 @@ impl DeviceManager {
         // Add virtio-vsock if required
         devices.append(&mut self.make_virtio_vsock_devices()?);
 
+        // Add vDPA devices if required
+        devices.append(&mut self.make_vdpa_devices()?);
+
+        // Add virtio-balloon if required
+        devices.append(&mut self.make_virtio_balloon_devices()?);
+
+        // Add virtio-mem if required
+        devices.append(&mut self.make_virtio_mem_devices()?);
+
         Ok(devices)
     }
 
@@ -896,12 +1513,60 @@ impl DeviceManager {
         Ok(sock)
     }
 
-    fn make_virtio_block_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
+    #[cfg(feature = "io_uring")]
+    fn io_uring_supported(&mut self) -> bool {
+        if let Some(supported) = self.io_uring_supported {
+            return supported;
+        }
+
+        let supported = vm_virtio::block_io_uring_is_supported();
+        self.io_uring_supported = Some(supported);
+        supported
+    }
+
+    fn make_virtio_block_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<PciAddress>)>> {
         let mut devices = Vec::new();
+        let sev = self.sev_enabled();
 
         let block_devices = self.config.lock().unwrap().disks.clone();
-        if let Some(disk_list_cfg) = &block_devices {
-            for disk_cfg in disk_list_cfg.iter() {
+        if let Some(mut disk_list_cfg) = block_devices {
+            if disk_list_cfg.iter().filter(|disk| disk.boot).count() > 1 {
+                return Err(DeviceManagerError::MultipleBootDisks);
+            }
+            // The boot disk is the first one the firmware finds.
+            disk_list_cfg.sort_by_key(|disk| !disk.boot);
+
+            for disk_cfg in disk_list_cfg.iter_mut() {
+                // A CD-ROM image is always read-only.
+                disk_cfg.readonly |= disk_cfg.cdrom;
+
+                let serial = match &disk_cfg.serial {
+                    Some(serial) if serial.len() > vm_virtio::DISK_SERIAL_MAX_LEN => {
+                        return Err(DeviceManagerError::InvalidDiskSerial);
+                    }
+                    Some(serial) => serial.clone(),
+                    None => default_disk_serial(&disk_cfg.path),
+                };
+
+                // The VM gets paused through the same event as when a
+                // watched disk goes away.
+                let error_evt = match disk_cfg.on_error {
+                    DiskErrorPolicy::Report => None,
+                    DiskErrorPolicy::Stop => Some(
+                        self.disk_evt
+                            .try_clone()
+                            .map_err(DeviceManagerError::EventFd)?,
+                    ),
+                };
+
+                // NVMe disks get their own controller, which is added along
+                // with the other PCI devices.
+                if disk_cfg.nvme {
+                    continue;
+                }
+
                 if disk_cfg.vhost_user {
                     let sock = if let Some(sock) = disk_cfg.vhost_socket.clone() {
                         sock
@@ -922,57 +1587,138 @@ impl DeviceManager {
                         Arc::clone(&vhost_user_block_device)
                             as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                         false,
+                        disk_cfg.pci_address,
                     ));
 
                     self.migratable_devices
                         .push(Arc::clone(&vhost_user_block_device) as Arc<Mutex<dyn Migratable>>);
-                } else {
+                } else if vm_virtio::is_nbd_uri(&disk_cfg.path.to_string_lossy()) {
+                    let nbd_disk = vm_virtio::NbdDisk::connect(&disk_cfg.path.to_string_lossy())
+                        .map_err(DeviceManagerError::NbdConnect)?;
+                    let readonly = disk_cfg.readonly || nbd_disk.is_read_only();
+                    let mut dev = vm_virtio::Block::new(
+                        nbd_disk,
+                        disk_cfg.path.clone(),
+                        Some(serial),
+                        readonly,
+                        disk_cfg.iommu || sev,
+                        disk_cfg.num_queues,
+                        disk_cfg.queue_size,
+                        disk_cfg.rate_limiter_config.as_ref().map(rate_limiter),
+                        error_evt,
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioBlock)?;
+
+                    if disk_cfg.track_changes {
+                        self.tracked_disks
+                            .push((disk_cfg.path.clone(), dev.track_changes()));
+                    }
+
+                    let block = Arc::new(Mutex::new(dev));
+
+                    devices.push((
+                        Arc::clone(&block) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                        disk_cfg.iommu,
+                        disk_cfg.pci_address,
+                    ));
+                    self.migratable_devices
+                        .push(Arc::clone(&block) as Arc<Mutex<dyn Migratable>>);
+                    self.resizable_disks.push((
+                        disk_cfg.path.clone(),
+                        Arc::clone(&block) as Arc<Mutex<dyn vm_virtio::DiskResize>>,
+                    ));
+                } else if let Some(overlay_path) = &disk_cfg.overlay {
+                    // The base image is shared between guests and is never
+                    // written to, all the writes end up in the overlay.
                     let mut options = OpenOptions::new();
                     options.read(true);
-                    options.write(!disk_cfg.readonly);
                     if disk_cfg.direct {
                         options.custom_flags(libc::O_DIRECT);
                     }
-                    // Open block device path
-                    let image: File = options
+                    let base: File = options
                         .open(&disk_cfg.path)
                         .map_err(DeviceManagerError::Disk)?;
 
-                    let mut raw_img = vm_virtio::RawFile::new(image, disk_cfg.direct);
+                    let mut options = OpenOptions::new();
+                    options.read(true);
+                    options.write(!disk_cfg.readonly);
+                    options.create(!disk_cfg.readonly);
+                    if disk_cfg.direct {
+                        options.custom_flags(libc::O_DIRECT);
+                    }
+                    let overlay: File = options
+                        .open(overlay_path)
+                        .map_err(DeviceManagerError::Disk)?;
 
-                    let image_type = qcow::detect_image_type(&mut raw_img)
-                        .map_err(DeviceManagerError::DetectImageType)?;
-                    match image_type {
-                        ImageType::Raw => {
-                            let dev = vm_virtio::Block::new(
-                                raw_img,
-                                disk_cfg.path.clone(),
-                                disk_cfg.readonly,
-                                disk_cfg.iommu,
-                                disk_cfg.num_queues,
-                                disk_cfg.queue_size,
-                            )
-                            .map_err(DeviceManagerError::CreateVirtioBlock)?;
+                    let overlay_img = QcowFile::new_overlay(
+                        vm_virtio::RawFile::new(base, disk_cfg.direct),
+                        vm_virtio::RawFile::new(overlay, disk_cfg.direct),
+                    )
+                    .map_err(DeviceManagerError::OverlayDeviceCreate)?;
+                    let mut dev = vm_virtio::Block::new(
+                        overlay_img,
+                        disk_cfg.path.clone(),
+                        Some(serial),
+                        disk_cfg.readonly,
+                        disk_cfg.iommu || sev,
+                        disk_cfg.num_queues,
+                        disk_cfg.queue_size,
+                        disk_cfg.rate_limiter_config.as_ref().map(rate_limiter),
+                        error_evt,
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioBlock)?;
 
-                            let block = Arc::new(Mutex::new(dev));
+                    if disk_cfg.track_changes {
+                        self.tracked_disks
+                            .push((disk_cfg.path.clone(), dev.track_changes()));
+                    }
 
-                            devices.push((
-                                Arc::clone(&block) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
-                                disk_cfg.iommu,
-                            ));
-                            self.migratable_devices
-                                .push(Arc::clone(&block) as Arc<Mutex<dyn Migratable>>);
-                        }
-                        ImageType::Qcow2 => {
-                            let qcow_img = QcowFile::from(raw_img)
-                                .map_err(DeviceManagerError::QcowDeviceCreate)?;
-                            let dev = vm_virtio::Block::new(
-                                qcow_img,
+                    let block = Arc::new(Mutex::new(dev));
+
+                    // The overlay can't outgrow its base image, so it is not
+                    // registered as a resizable disk.
+                    devices.push((
+                        Arc::clone(&block) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                        disk_cfg.iommu,
+                        disk_cfg.pci_address,
+                    ));
+                    self.migratable_devices
+                        .push(Arc::clone(&block) as Arc<Mutex<dyn Migratable>>);
+                } else {
+                    let mut options = OpenOptions::new();
+                    options.read(true);
+                    options.write(!disk_cfg.readonly);
+                    if disk_cfg.direct {
+                        options.custom_flags(libc::O_DIRECT);
+                    }
+                    // Open block device path
+                    let image: File = options
+                        .open(&disk_cfg.path)
+                        .map_err(DeviceManagerError::Disk)?;
+
+                    let mut raw_img = vm_virtio::RawFile::new(image, disk_cfg.direct);
+
+                    let image_type = qcow::detect_image_type(&mut raw_img)
+                        .map_err(DeviceManagerError::DetectImageType)?;
+                    match image_type {
+                        #[cfg(feature = "io_uring")]
+                        // The io_uring backend reports all the errors to
+                        // the guest, and doesn't track the changed blocks.
+                        ImageType::Raw
+                            if !disk_cfg.direct
+                                && disk_cfg.on_error == DiskErrorPolicy::Report
+                                && !disk_cfg.track_changes
+                                && self.io_uring_supported() =>
+                        {
+                            let dev = vm_virtio::BlockIoUring::new(
+                                raw_img,
                                 disk_cfg.path.clone(),
+                                Some(serial),
                                 disk_cfg.readonly,
-                                disk_cfg.iommu,
+                                disk_cfg.iommu || sev,
                                 disk_cfg.num_queues,
                                 disk_cfg.queue_size,
+                                disk_cfg.rate_limiter_config.as_ref().map(rate_limiter),
                             )
                             .map_err(DeviceManagerError::CreateVirtioBlock)?;
 
@@ -981,9 +1727,42 @@ impl DeviceManager {
                             devices.push((
                                 Arc::clone(&block) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                                 disk_cfg.iommu,
+                                disk_cfg.pci_address,
                             ));
                             self.migratable_devices
                                 .push(Arc::clone(&block) as Arc<Mutex<dyn Migratable>>);
+                            self.resizable_disks.push((
+                                disk_cfg.path.clone(),
+                                Arc::clone(&block) as Arc<Mutex<dyn vm_virtio::DiskResize>>,
+                            ));
+                        }
+                        ImageType::Raw => devices.push(
+                            self.add_virtio_block_device(raw_img, disk_cfg, serial, error_evt)?,
+                        ),
+                        ImageType::Qcow2 => {
+                            let qcow_img = QcowFile::from(raw_img)
+                                .map_err(DeviceManagerError::QcowDeviceCreate)?;
+                            devices.push(
+                                self.add_virtio_block_device(
+                                    qcow_img, disk_cfg, serial, error_evt,
+                                )?,
+                            );
+                        }
+                        ImageType::Vhd => {
+                            let vhd_img = VhdFile::from(raw_img)
+                                .map_err(DeviceManagerError::VhdDeviceCreate)?;
+                            devices.push(
+                                self.add_virtio_block_device(vhd_img, disk_cfg, serial, error_evt)?,
+                            );
+                        }
+                        ImageType::Vhdx => {
+                            let vhdx_img = VhdxFile::from(raw_img)
+                                .map_err(DeviceManagerError::VhdxDeviceCreate)?;
+                            devices.push(
+                                self.add_virtio_block_device(
+                                    vhdx_img, disk_cfg, serial, error_evt,
+                                )?,
+                            );
                         }
                     };
                 }
@@ -993,6 +1772,92 @@ impl DeviceManager {
         Ok(devices)
     }
 
+    // Whatever the image format, the disk is exposed through the same
+    // virtio-blk device, which can follow the size of its image.
+    fn add_virtio_block_device<T: 'static + vm_virtio::DiskFile + Send>(
+        &mut self,
+        disk: T,
+        disk_cfg: &DiskConfig,
+        serial: String,
+        error_evt: Option<EventFd>,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, Option<PciAddress>)> {
+        let sev = self.sev_enabled();
+        let mut dev = vm_virtio::Block::new(
+            disk,
+            disk_cfg.path.clone(),
+            Some(serial),
+            disk_cfg.readonly,
+            disk_cfg.iommu || sev,
+            disk_cfg.num_queues,
+            disk_cfg.queue_size,
+            disk_cfg.rate_limiter_config.as_ref().map(rate_limiter),
+            error_evt,
+        )
+        .map_err(DeviceManagerError::CreateVirtioBlock)?;
+
+        if disk_cfg.track_changes {
+            self.tracked_disks
+                .push((disk_cfg.path.clone(), dev.track_changes()));
+        }
+
+        let block = Arc::new(Mutex::new(dev));
+
+        self.migratable_devices
+            .push(Arc::clone(&block) as Arc<Mutex<dyn Migratable>>);
+        self.resizable_disks.push((
+            disk_cfg.path.clone(),
+            Arc::clone(&block) as Arc<Mutex<dyn vm_virtio::DiskResize>>,
+        ));
+
+        Ok((
+            block as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+            disk_cfg.iommu,
+            disk_cfg.pci_address,
+        ))
+    }
+
+    fn make_virtio_scsi_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<PciAddress>)>> {
+        let mut devices = Vec::new();
+        let sev = self.sev_enabled();
+
+        let scsi_controllers = self.config.lock().unwrap().scsi.clone();
+        if let Some(scsi_list_cfg) = &scsi_controllers {
+            for scsi_cfg in scsi_list_cfg.iter() {
+                let mut luns = Vec::new();
+                for lun_path in scsi_cfg.luns.iter() {
+                    let lun = vm_virtio::ScsiLun::new(lun_path.clone(), scsi_cfg.readonly)
+                        .map_err(DeviceManagerError::ScsiLun)?;
+                    if lun.is_passthrough() {
+                        info!("Passing through host SCSI device {:?}", lun_path);
+                    }
+                    luns.push(lun);
+                }
+
+                let scsi = Arc::new(Mutex::new(
+                    vm_virtio::Scsi::new(
+                        luns,
+                        scsi_cfg.iommu || sev,
+                        scsi_cfg.num_queues,
+                        scsi_cfg.queue_size,
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioScsi)?,
+                ));
+
+                devices.push((
+                    Arc::clone(&scsi) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                    scsi_cfg.iommu,
+                    None,
+                ));
+                self.migratable_devices
+                    .push(Arc::clone(&scsi) as Arc<Mutex<dyn Migratable>>);
+            }
+        }
+
+        Ok(devices)
+    }
+
     /// Launch network backend
     fn start_net_backend(&mut self, net_cfg: &NetConfig) -> DeviceManagerResult<String> {
         let _socket_file = NamedTempFile::new().map_err(DeviceManagerError::CreateSocketFile)?;
@@ -1019,8 +1884,11 @@ impl DeviceManager {
     }
 
     /// Add virto-net and vhost-user-net devices
-    fn make_virtio_net_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
+    fn make_virtio_net_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<PciAddress>)>> {
         let mut devices = Vec::new();
+        let sev = self.sev_enabled();
         let net_devices = self.config.lock().unwrap().net.clone();
         if let Some(net_list_cfg) = &net_devices {
             for net_cfg in net_list_cfg.iter() {
@@ -1043,40 +1911,82 @@ impl DeviceManager {
                         Arc::clone(&vhost_user_net_device)
                             as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                         net_cfg.iommu,
+                        net_cfg.pci_address,
                     ));
                     self.migratable_devices
                         .push(Arc::clone(&vhost_user_net_device) as Arc<Mutex<dyn Migratable>>);
                 } else {
                     let virtio_net_device = if let Some(ref tap_if_name) = net_cfg.tap {
-                        Arc::new(Mutex::new(
-                            vm_virtio::Net::new(
-                                Some(tap_if_name),
-                                None,
-                                None,
-                                Some(net_cfg.mac),
-                                net_cfg.iommu,
-                                net_cfg.num_queues,
-                                net_cfg.queue_size,
-                            )
-                            .map_err(DeviceManagerError::CreateVirtioNet)?,
-                        ))
+                        vm_virtio::Net::new(
+                            Some(tap_if_name),
+                            None,
+                            None,
+                            net_cfg.host_mac,
+                            Some(net_cfg.mac),
+                            net_cfg.mtu,
+                            net_offloads(net_cfg),
+                            net_cfg.iommu || sev,
+                            net_cfg.num_queues,
+                            net_cfg.queue_size,
+                            net_cfg.transitional,
+                            net_cfg.standby,
+                        )
+                    } else if let Some(ref fds) = net_cfg.fds {
+                        vm_virtio::Net::new_with_tap_fds(
+                            fds,
+                            net_cfg.host_mac,
+                            Some(net_cfg.mac),
+                            net_cfg.mtu,
+                            net_offloads(net_cfg),
+                            net_cfg.iommu || sev,
+                            net_cfg.num_queues,
+                            net_cfg.queue_size,
+                            net_cfg.transitional,
+                            net_cfg.standby,
+                        )
+                    } else if let Some(ref xdp_if_name) = net_cfg.xdp {
+                        // A config lacking the map, only possible through
+                        // the API, fails opening it.
+                        vm_virtio::Net::new_with_xdp(
+                            xdp_if_name,
+                            net_cfg.xdp_map.as_deref().unwrap_or_else(|| Path::new("")),
+                            Some(net_cfg.mac),
+                            net_cfg.iommu || sev,
+                            net_cfg.num_queues,
+                            net_cfg.queue_size,
+                            net_cfg.transitional,
+                            net_cfg.standby,
+                        )
                     } else {
-                        Arc::new(Mutex::new(
-                            vm_virtio::Net::new(
-                                None,
-                                Some(net_cfg.ip),
-                                Some(net_cfg.mask),
-                                Some(net_cfg.mac),
-                                net_cfg.iommu,
-                                net_cfg.num_queues,
-                                net_cfg.queue_size,
-                            )
-                            .map_err(DeviceManagerError::CreateVirtioNet)?,
-                        ))
-                    };
+                        vm_virtio::Net::new(
+                            None,
+                            Some(net_cfg.ip),
+                            Some(net_cfg.mask),
+                            net_cfg.host_mac,
+                            Some(net_cfg.mac),
+                            net_cfg.mtu,
+                            net_offloads(net_cfg),
+                            net_cfg.iommu || sev,
+                            net_cfg.num_queues,
+                            net_cfg.queue_size,
+                            net_cfg.transitional,
+                            net_cfg.standby,
+                        )
+                    }
+                    .map_err(DeviceManagerError::CreateVirtioNet)?;
+                    virtio_net_device.set_rate_limiters(
+                        net_cfg.rx_rate_limiter_config.as_ref().map(rate_limiter),
+                        net_cfg.tx_rate_limiter_config.as_ref().map(rate_limiter),
+                    );
+                    let virtio_net_device = Arc::new(Mutex::new(virtio_net_device));
+                    if let Some(id) = &net_cfg.id {
+                        self.updatable_nets
+                            .push((id.clone(), Arc::clone(&virtio_net_device)));
+                    }
                     devices.push((
                         Arc::clone(&virtio_net_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                         net_cfg.iommu,
+                        net_cfg.pci_address,
                     ));
                     self.migratable_devices
                         .push(Arc::clone(&virtio_net_device) as Arc<Mutex<dyn Migratable>>);
@@ -1087,19 +1997,23 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_rng_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
+    fn make_virtio_rng_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<PciAddress>)>> {
         let mut devices = Vec::new();
+        let sev = self.sev_enabled();
 
         // Add virtio-rng if required
         let rng_config = self.config.lock().unwrap().rng.clone();
         if let Some(rng_path) = rng_config.src.to_str() {
             let virtio_rng_device = Arc::new(Mutex::new(
-                vm_virtio::Rng::new(rng_path, rng_config.iommu)
+                vm_virtio::Rng::new(rng_path, rng_config.iommu || sev)
                     .map_err(DeviceManagerError::CreateVirtioRng)?,
             ));
             devices.push((
                 Arc::clone(&virtio_rng_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                 false,
+                None,
             ));
 
             self.migratable_devices
@@ -1109,82 +2023,200 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_fs_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
+    fn make_virtio_balloon_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<PciAddress>)>> {
+        let mut devices = Vec::new();
+
+        let config = self.config.lock().unwrap();
+        if let Some(balloon_config) = &config.balloon {
+            // The pages the guest gives back are encrypted, and couldn't be
+            // handed back to it without going through the SEV firmware.
+            if config.platform.as_ref().map_or(false, |p| p.sev_enabled()) {
+                return Err(DeviceManagerError::SevBalloon);
+            }
+
+            // The overcommit controller relies on the free pages reporting
+            // for the host to reclaim the memory the guest doesn't use.
+            let free_page_reporting = balloon_config.free_page_reporting
+                || config.memory.overcommit == MemoryOvercommit::Auto;
+            let virtio_balloon_device = Arc::new(Mutex::new(
+                vm_virtio::Balloon::new(
+                    balloon_config.size,
+                    balloon_config.deflate_on_oom,
+                    free_page_reporting,
+                )
+                .map_err(DeviceManagerError::CreateVirtioBalloon)?,
+            ));
+            devices.push((
+                Arc::clone(&virtio_balloon_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                false,
+                None,
+            ));
+
+            self.migratable_devices
+                .push(Arc::clone(&virtio_balloon_device) as Arc<Mutex<dyn Migratable>>);
+
+            self.balloon = Some(virtio_balloon_device);
+        }
+
+        Ok(devices)
+    }
+
+    fn make_virtio_mem_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<PciAddress>)>> {
+        let mut devices = Vec::new();
+
+        let config = self.config.lock().unwrap();
+        if config.memory.hotplug_method != HotplugMethod::VirtioMem {
+            return Ok(devices);
+        }
+
+        let region = self.memory_manager.lock().unwrap().virtio_mem_region();
+        if let Some((addr, size)) = region {
+            let virtio_mem_device = Arc::new(Mutex::new(
+                vm_virtio::Mem::new(addr, size, config.memory.hotplugged_size.unwrap_or(0))
+                    .map_err(DeviceManagerError::CreateVirtioMem)?,
+            ));
+            devices.push((
+                Arc::clone(&virtio_mem_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                false,
+                None,
+            ));
+
+            self.migratable_devices
+                .push(Arc::clone(&virtio_mem_device) as Arc<Mutex<dyn Migratable>>);
+
+            self.virtio_mem = Some(virtio_mem_device);
+        }
+
+        Ok(devices)
+    }
+
+    fn make_virtio_fs_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<PciAddress>)>> {
         let mut devices = Vec::new();
         // Add virtio-fs if required
         if let Some(fs_list_cfg) = &self.config.lock().unwrap().fs {
             for fs_cfg in fs_list_cfg.iter() {
-                if let Some(fs_sock) = fs_cfg.sock.to_str() {
-                    let cache: Option<(VirtioSharedMemoryList, u64)> = if fs_cfg.dax {
-                        let fs_cache = fs_cfg.cache_size;
-                        // The memory needs to be 2MiB aligned in order to support
-                        // hugepages.
-                        let fs_guest_addr = self
-                            .address_manager
-                            .allocator
-                            .lock()
-                            .unwrap()
-                            .allocate_mmio_addresses(
-                                None,
-                                fs_cache as GuestUsize,
-                                Some(0x0020_0000),
-                            )
-                            .ok_or(DeviceManagerError::FsRangeAllocation)?;
+                if fs_cfg.spawn {
+                    if fs_cfg.sock.is_none() || fs_cfg.path.is_none() {
+                        return Err(DeviceManagerError::InvalidFsSpawn);
+                    }
+                } else if fs_cfg.sock.is_some() == fs_cfg.path.is_some() {
+                    return Err(DeviceManagerError::InvalidFsBackend);
+                }
 
-                        let mmap_region = MmapRegion::build(
-                            None,
-                            fs_cache as usize,
-                            libc::PROT_NONE,
-                            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
-                        )
-                        .map_err(DeviceManagerError::NewMmapRegion)?;
-                        let addr: u64 = mmap_region.as_ptr() as u64;
-
-                        self._mmap_regions.push(mmap_region);
-
-                        self.memory_manager
-                            .lock()
-                            .unwrap()
-                            .create_userspace_mapping(
-                                fs_guest_addr.raw_value(),
-                                fs_cache,
-                                addr,
-                                false,
-                            )
-                            .map_err(DeviceManagerError::MemoryManager)?;
-
-                        let mut region_list = Vec::new();
-                        region_list.push(VirtioSharedMemory {
-                            offset: 0,
-                            len: fs_cache,
-                        });
-
-                        Some((
-                            VirtioSharedMemoryList {
-                                addr: fs_guest_addr,
-                                len: fs_cache as GuestUsize,
-                                region_list,
-                            },
-                            addr,
-                        ))
+                let cache: Option<(VirtioSharedMemoryList, u64)> = if fs_cfg.dax {
+                    let fs_cache = fs_cfg.cache_size;
+                    // The memory needs to be 2MiB aligned in order to support
+                    // hugepages.
+                    let fs_guest_addr = self
+                        .address_manager
+                        .allocator
+                        .lock()
+                        .unwrap()
+                        .allocate_mmio_addresses(None, fs_cache as GuestUsize, Some(0x0020_0000))
+                        .ok_or(DeviceManagerError::FsRangeAllocation)?;
+
+                    let mmap_region = MmapRegion::build(
+                        None,
+                        fs_cache as usize,
+                        libc::PROT_NONE,
+                        libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+                    )
+                    .map_err(DeviceManagerError::NewMmapRegion)?;
+                    let addr: u64 = mmap_region.as_ptr() as u64;
+
+                    self._mmap_regions.push(mmap_region);
+
+                    self.memory_manager
+                        .lock()
+                        .unwrap()
+                        .create_userspace_mapping(fs_guest_addr.raw_value(), fs_cache, addr, false)
+                        .map_err(DeviceManagerError::MemoryManager)?;
+
+                    let mut region_list = Vec::new();
+                    region_list.push(VirtioSharedMemory {
+                        offset: 0,
+                        len: fs_cache,
+                    });
+
+                    Some((
+                        VirtioSharedMemoryList {
+                            addr: fs_guest_addr,
+                            len: fs_cache as GuestUsize,
+                            region_list,
+                        },
+                        addr,
+                    ))
+                } else {
+                    None
+                };
+
+                if let Some(fs_sock) = &fs_cfg.sock {
+                    let mut daemon = if fs_cfg.spawn {
+                        Some(virtiofsd::spawn(fs_cfg).map_err(DeviceManagerError::Virtiofsd)?)
                     } else {
                         None
                     };
 
                     let virtio_fs_device = Arc::new(Mutex::new(
                         vm_virtio::vhost_user::Fs::new(
-                            fs_sock,
+                            &fs_sock.to_string_lossy(),
+                            &fs_cfg.tag,
+                            fs_cfg.num_queues,
+                            fs_cfg.queue_size,
+                            cache,
+                        )
+                        .map_err(|e| {
+                            if let Some(child) = daemon.as_mut() {
+                                // Ignore the result because there is nothing we can do about it.
+                                let _ = child.kill();
+                                let _ = child.wait();
+                            }
+                            DeviceManagerError::CreateVirtioFs(e)
+                        })?,
+                    ));
+
+                    if let Some(child) = daemon {
+                        self.virtiofsd.push(
+                            Virtiofsd::supervise(
+                                child,
+                                fs_cfg.clone(),
+                                Arc::clone(&virtio_fs_device),
+                            )
+                            .map_err(DeviceManagerError::Virtiofsd)?,
+                        );
+                    }
+
+                    devices.push((
+                        Arc::clone(&virtio_fs_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                        false,
+                        fs_cfg.pci_address,
+                    ));
+
+                    self.migratable_devices
+                        .push(Arc::clone(&virtio_fs_device) as Arc<Mutex<dyn Migratable>>);
+                } else if let Some(shared_dir) = &fs_cfg.path {
+                    // The shared directory is served by the VMM itself.
+                    let virtio_fs_device = Arc::new(Mutex::new(
+                        vhost_user_fs::device::Fs::new(
+                            &shared_dir.to_string_lossy(),
                             &fs_cfg.tag,
                             fs_cfg.num_queues,
                             fs_cfg.queue_size,
                             cache,
                         )
-                        .map_err(DeviceManagerError::CreateVirtioFs)?,
+                        .map_err(DeviceManagerError::CreateBuiltinFs)?,
                     ));
 
                     devices.push((
                         Arc::clone(&virtio_fs_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                         false,
+                        fs_cfg.pci_address,
                     ));
 
                     self.migratable_devices
@@ -1196,45 +2228,69 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_pmem_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
+    fn make_virtio_pmem_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<PciAddress>)>> {
         let mut devices = Vec::new();
+        let sev = self.sev_enabled();
         // Add virtio-pmem if required
         if let Some(pmem_list_cfg) = &self.config.lock().unwrap().pmem {
             for pmem_cfg in pmem_list_cfg.iter() {
-                let size = pmem_cfg.size;
-
-                // The memory needs to be 2MiB aligned in order to support
-                // hugepages.
-                let pmem_guest_addr = self
-                    .address_manager
-                    .allocator
-                    .lock()
-                    .unwrap()
-                    .allocate_mmio_addresses(None, size as GuestUsize, Some(0x0020_0000))
-                    .ok_or(DeviceManagerError::PmemRangeAllocation)?;
-
                 let (custom_flags, set_len) = if pmem_cfg.file.is_dir() {
                     (O_TMPFILE, true)
                 } else {
                     (0, false)
                 };
 
+                // When the guest writes are discarded, the file is never
+                // written to, unless it's a temporary one.
                 let file = OpenOptions::new()
                     .read(true)
-                    .write(true)
+                    .write(set_len || !pmem_cfg.discard_writes)
                     .custom_flags(custom_flags)
                     .open(&pmem_cfg.file)
                     .map_err(DeviceManagerError::PmemFileOpen)?;
 
-                if set_len {
+                let size = if set_len {
+                    let size = pmem_cfg.size.ok_or(DeviceManagerError::PmemSizeMissing)?;
                     file.set_len(size)
                         .map_err(DeviceManagerError::PmemFileSetLen)?;
-                }
+                    size
+                } else {
+                    match pmem_cfg.size {
+                        Some(size) => size,
+                        None => file
+                            .metadata()
+                            .map_err(DeviceManagerError::PmemFileMetadata)?
+                            .len(),
+                    }
+                };
+
+                // The memory needs to be 2MiB aligned in order to support
+                // hugepages.
+                let pmem_guest_addr = self
+                    .address_manager
+                    .allocator
+                    .lock()
+                    .unwrap()
+                    .allocate_mmio_addresses(None, size as GuestUsize, Some(0x0020_0000))
+                    .ok_or(DeviceManagerError::PmemRangeAllocation)?;
 
                 let cloned_file = file.try_clone().map_err(DeviceManagerError::CloneFile)?;
-                let mmap_region =
+                let mmap_region = if pmem_cfg.discard_writes {
+                    // A private mapping keeps the guest writes in anonymous
+                    // memory, which makes for an ephemeral root filesystem
+                    // on top of a shared image.
+                    MmapRegion::build(
+                        Some(FileOffset::new(cloned_file, 0)),
+                        size as usize,
+                        libc::PROT_READ | libc::PROT_WRITE,
+                        libc::MAP_NORESERVE | libc::MAP_PRIVATE,
+                    )
+                } else {
                     MmapRegion::from_file(FileOffset::new(cloned_file, 0), size as usize)
-                        .map_err(DeviceManagerError::NewMmapRegion)?;
+                }
+                .map_err(DeviceManagerError::NewMmapRegion)?;
                 let addr: u64 = mmap_region.as_ptr() as u64;
 
                 self._mmap_regions.push(mmap_region);
@@ -1251,13 +2307,19 @@ impl DeviceManager {
                     .map_err(DeviceManagerError::MemoryManager)?;
 
                 let virtio_pmem_device = Arc::new(Mutex::new(
-                    vm_virtio::Pmem::new(file, pmem_guest_addr, size as GuestUsize, pmem_cfg.iommu)
-                        .map_err(DeviceManagerError::CreateVirtioPmem)?,
+                    vm_virtio::Pmem::new(
+                        file,
+                        pmem_guest_addr,
+                        size as GuestUsize,
+                        pmem_cfg.iommu || sev,
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioPmem)?,
                 ));
 
                 devices.push((
                     Arc::clone(&virtio_pmem_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                     false,
+                    None,
                 ));
 
                 self.migratable_devices
@@ -1270,7 +2332,7 @@ impl DeviceManager {
 
     fn make_virtio_vhost_user_net_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<PciAddress>)>> {
         let mut devices = Vec::new();
         // Add vhost-user-net if required
         if let Some(vhost_user_net_list_cfg) = &self.config.lock().unwrap().vhost_user_net {
@@ -1288,6 +2350,7 @@ impl DeviceManager {
                 devices.push((
                     Arc::clone(&vhost_user_net_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                     false,
+                    None,
                 ));
 
                 self.migratable_devices
@@ -1300,7 +2363,7 @@ impl DeviceManager {
 
     fn make_virtio_vhost_user_blk_devices(
         &mut self,
-    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<PciAddress>)>> {
         let mut devices = Vec::new();
         // Add vhost-user-blk if required
         if let Some(vhost_user_blk_list_cfg) = &self.config.lock().unwrap().vhost_user_blk {
@@ -1318,6 +2381,7 @@ impl DeviceManager {
                 devices.push((
                     Arc::clone(&vhost_user_blk_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                     false,
+                    None,
                 ));
 
                 self.migratable_devices
@@ -1328,8 +2392,11 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_vsock_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
+    fn make_virtio_vsock_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<PciAddress>)>> {
         let mut devices = Vec::new();
+        let sev = self.sev_enabled();
         // Add vsock if required
         if let Some(vsock_list_cfg) = &self.config.lock().unwrap().vsock {
             for vsock_cfg in vsock_list_cfg.iter() {
@@ -1342,13 +2409,14 @@ impl DeviceManager {
                         .map_err(DeviceManagerError::CreateVsockBackend)?;
 
                 let vsock_device = Arc::new(Mutex::new(
-                    vm_virtio::Vsock::new(vsock_cfg.cid, backend, vsock_cfg.iommu)
+                    vm_virtio::Vsock::new(vsock_cfg.cid, backend, vsock_cfg.iommu || sev)
                         .map_err(DeviceManagerError::CreateVirtioVsock)?,
                 ));
 
                 devices.push((
                     Arc::clone(&vsock_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                     false,
+                    None,
                 ));
 
                 self.migratable_devices
@@ -1359,6 +2427,31 @@ impl DeviceManager {
         Ok(devices)
     }
 
+    fn make_vdpa_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<PciAddress>)>> {
+        let mut devices = Vec::new();
+        if let Some(vdpa_list_cfg) = &self.config.lock().unwrap().vdpa {
+            for vdpa_cfg in vdpa_list_cfg.iter() {
+                let vdpa_device = Arc::new(Mutex::new(
+                    vm_virtio::Vdpa::new(&vdpa_cfg.path, vdpa_cfg.num_queues)
+                        .map_err(DeviceManagerError::CreateVdpa)?,
+                ));
+
+                devices.push((
+                    Arc::clone(&vdpa_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                    false,
+                    None,
+                ));
+
+                self.migratable_devices
+                    .push(Arc::clone(&vdpa_device) as Arc<Mutex<dyn Migratable>>);
+            }
+        }
+
+        Ok(devices)
+    }
+
     #[cfg(feature = "pci_support")]
     fn create_kvm_device(vm: &Arc<VmFd>) -> DeviceManagerResult<DeviceFd> {
         let mut vfio_dev = kvm_bindings::kvm_create_device {
@@ -1385,7 +2478,33 @@ impl DeviceManager {
             .allocate_kvm_memory_slot();
         let mut iommu_attached_device_ids = Vec::new();
 
-        if let Some(device_list_cfg) = &self.config.lock().unwrap().devices {
+        let mut device_list_cfg = self
+            .config
+            .lock()
+            .unwrap()
+            .devices
+            .clone()
+            .unwrap_or_default();
+        // The SR-IOV VFs have been bound to vfio-pci already, and are then
+        // assigned as any other device.
+        if let Some(sriov_vf_list_cfg) = &self.config.lock().unwrap().sriov_vfs {
+            for sriov_vf_cfg in sriov_vf_list_cfg.iter() {
+                device_list_cfg.push(DeviceConfig {
+                    path: sriov::vf_sysfs_path(&sriov_vf_cfg.pf, sriov_vf_cfg.index)
+                        .map_err(DeviceManagerError::SriovVf)?,
+                    iommu: sriov_vf_cfg.iommu,
+                    reset: DeviceResetMethod::Flr,
+                    reset_on_attach: true,
+                    reset_on_release: true,
+                    p2p_dma: false,
+                    pci_address: None,
+                });
+            }
+        }
+
+        if !device_list_cfg.is_empty() {
+            let mut p2p_devices = Vec::new();
+
             // Create the KVM VFIO device
             let device_fd = DeviceManager::create_kvm_device(&self.address_manager.vm_fd)?;
             let device_fd = Arc::new(device_fd);
@@ -1396,7 +2515,8 @@ impl DeviceManager {
                 // do multifunction. Also, because we only support one PCI
                 // bus, the bus 0, we don't need to add anything to the
                 // global device ID.
-                let device_id = pci.next_device_id() << 3;
+                let pci_device_id = pci_bus_device_id(pci, device_cfg.pci_address)?;
+                let device_id = pci_device_id << 3;
 
                 let memory = self.memory_manager.lock().unwrap().guest_memory();
                 let vfio_device = VfioDevice::new(
@@ -1409,6 +2529,13 @@ impl DeviceManager {
 
                 if device_cfg.iommu {
                     if let Some(iommu) = iommu_device {
+                        if iommu_attached_device_ids.is_empty() && !host_msi_isolation() {
+                            warn!(
+                                "The host allows unsafe interrupts, the MSIs of the devices \
+                                 assigned from the guest are not isolated"
+                            );
+                        }
+
                         let vfio_mapping = Arc::new(VfioDmaMapping::new(
                             vfio_device.get_container(),
                             memory.clone(),
@@ -1419,9 +2546,23 @@ impl DeviceManager {
                     }
                 }
 
-                let mut vfio_pci_device =
-                    VfioPciDevice::new(&self.address_manager.vm_fd, vfio_device, interrupt_manager)
-                        .map_err(DeviceManagerError::VfioPciCreate)?;
+                let reset_policy = VfioResetPolicy {
+                    method: match device_cfg.reset {
+                        DeviceResetMethod::Flr => VfioResetMethod::Flr,
+                        DeviceResetMethod::Bus => VfioResetMethod::Bus,
+                        DeviceResetMethod::None => VfioResetMethod::None,
+                    },
+                    on_attach: device_cfg.reset_on_attach,
+                    on_release: device_cfg.reset_on_release,
+                };
+
+                let mut vfio_pci_device = VfioPciDevice::new(
+                    &self.address_manager.vm_fd,
+                    vfio_device,
+                    interrupt_manager,
+                    reset_policy,
+                )
+                .map_err(DeviceManagerError::VfioPciCreate)?;
 
                 let bars = vfio_pci_device
                     .allocate_bars(&mut self.address_manager.allocator.lock().unwrap())
@@ -1433,7 +2574,17 @@ impl DeviceManager {
 
                 let vfio_pci_device = Arc::new(Mutex::new(vfio_pci_device));
 
-                pci.add_device(vfio_pci_device.clone())
+                if device_cfg.p2p_dma {
+                    // The peer BARs are mapped at their guest address,
+                    // which is not what a device behind the virtual IOMMU
+                    // would see.
+                    if device_cfg.iommu {
+                        return Err(DeviceManagerError::VfioP2pDmaIommu);
+                    }
+                    p2p_devices.push(vfio_pci_device.clone());
+                }
+
+                pci.add_device(pci_device_id, vfio_pci_device.clone())
                     .map_err(DeviceManagerError::AddPciDevice)?;
 
                 pci.register_mapping(
@@ -1443,11 +2594,129 @@ impl DeviceManager {
                     bars,
                 )
                 .map_err(DeviceManagerError::AddPciDevice)?;
+
+                self.vfio_devices.push(vfio_pci_device);
+            }
+
+            // Let each device taking part in peer-to-peer DMA reach the BARs
+            // of the others.
+            for device in p2p_devices.iter() {
+                for peer in p2p_devices.iter() {
+                    if Arc::ptr_eq(device, peer) {
+                        continue;
+                    }
+
+                    let container = peer.lock().unwrap().container();
+                    device
+                        .lock()
+                        .unwrap()
+                        .add_p2p_peer(container)
+                        .map_err(DeviceManagerError::VfioP2pDma)?;
+                }
             }
         }
         Ok(iommu_attached_device_ids)
     }
 
+    #[cfg(feature = "pci_support")]
+    fn add_nvme_devices(
+        &mut self,
+        pci: &mut PciBus,
+        interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+    ) -> DeviceManagerResult<()> {
+        let block_devices = self.config.lock().unwrap().disks.clone();
+        if let Some(disk_list_cfg) = &block_devices {
+            for disk_cfg in disk_list_cfg.iter().filter(|d| d.nvme) {
+                if vm_virtio::is_nbd_uri(&disk_cfg.path.to_string_lossy()) {
+                    let nbd_disk = vm_virtio::NbdDisk::connect(&disk_cfg.path.to_string_lossy())
+                        .map_err(DeviceManagerError::NbdConnect)?;
+                    self.add_nvme_device(nbd_disk, disk_cfg, pci, interrupt_manager)?;
+                    continue;
+                }
+
+                let mut options = OpenOptions::new();
+                options.read(true);
+                options.write(!disk_cfg.readonly);
+                if disk_cfg.direct {
+                    options.custom_flags(libc::O_DIRECT);
+                }
+                let image: File = options
+                    .open(&disk_cfg.path)
+                    .map_err(DeviceManagerError::Disk)?;
+
+                let mut raw_img = vm_virtio::RawFile::new(image, disk_cfg.direct);
+
+                let image_type = qcow::detect_image_type(&mut raw_img)
+                    .map_err(DeviceManagerError::DetectImageType)?;
+                match image_type {
+                    ImageType::Raw => {
+                        self.add_nvme_device(raw_img, disk_cfg, pci, interrupt_manager)?
+                    }
+                    ImageType::Qcow2 => {
+                        let qcow_img = QcowFile::from(raw_img)
+                            .map_err(DeviceManagerError::QcowDeviceCreate)?;
+                        self.add_nvme_device(qcow_img, disk_cfg, pci, interrupt_manager)?
+                    }
+                    ImageType::Vhd => {
+                        let vhd_img =
+                            VhdFile::from(raw_img).map_err(DeviceManagerError::VhdDeviceCreate)?;
+                        self.add_nvme_device(vhd_img, disk_cfg, pci, interrupt_manager)?
+                    }
+                    ImageType::Vhdx => {
+                        let vhdx_img = VhdxFile::from(raw_img)
+                            .map_err(DeviceManagerError::VhdxDeviceCreate)?;
+                        self.add_nvme_device(vhdx_img, disk_cfg, pci, interrupt_manager)?
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "pci_support")]
+    fn add_nvme_device<T: 'static + vm_virtio::DiskFile + Send>(
+        &mut self,
+        disk: T,
+        disk_cfg: &DiskConfig,
+        pci: &mut PciBus,
+        interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+    ) -> DeviceManagerResult<()> {
+        let memory = self.memory_manager.lock().unwrap().guest_memory();
+        let mut nvme_device = nvme::NvmeController::new(
+            memory,
+            disk,
+            &disk_cfg.path,
+            disk_cfg.readonly,
+            disk_cfg.num_queues as u16,
+            disk_cfg.queue_size,
+            interrupt_manager,
+        )
+        .map_err(DeviceManagerError::CreateNvme)?;
+
+        let bars = nvme_device
+            .allocate_bars(&mut self.address_manager.allocator.lock().unwrap())
+            .map_err(DeviceManagerError::AllocateBars)?;
+
+        let nvme_device = Arc::new(Mutex::new(nvme_device));
+
+        let pci_device_id = pci_bus_device_id(pci, disk_cfg.pci_address)?;
+        pci.add_device(pci_device_id, nvme_device.clone())
+            .map_err(DeviceManagerError::AddPciDevice)?;
+
+        pci.register_mapping(
+            nvme_device.clone(),
+            self.address_manager.io_bus.as_ref(),
+            self.address_manager.mmio_bus.as_ref(),
+            bars,
+        )
+        .map_err(DeviceManagerError::AddPciDevice)?;
+
+        self.nvme_devices.push(nvme_device);
+
+        Ok(())
+    }
+
     #[cfg(feature = "pci_support")]
     fn add_virtio_pci_device(
         &mut self,
@@ -1455,6 +2724,7 @@ impl DeviceManager {
         pci: &mut PciBus,
         iommu_mapping: &Option<Arc<IommuMapping>>,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+        pci_address: Option<PciAddress>,
     ) -> DeviceManagerResult<Option<u32>> {
         // Allows support for one MSI-X vector per queue. It also adds 1
         // as we need to take into account the dedicated vector to notify
@@ -1465,7 +2735,8 @@ impl DeviceManager {
         // to the PCI function, and we know we don't do multifunction.
         // Also, because we only support one PCI bus, the bus 0, we don't need
         // to add anything to the global device ID.
-        let dev_id = pci.next_device_id() << 3;
+        let pci_device_id = pci_bus_device_id(pci, pci_address)?;
+        let dev_id = pci_device_id << 3;
 
         // Create the callback from the implementation of the DmaRemapping
         // trait. The point with the callback is to simplify the code as we
@@ -1512,9 +2783,13 @@ impl DeviceManager {
                 .map_err(DeviceManagerError::RegisterIoevent)?;
         }
 
+        if let Some(cgroups) = &self.cgroups {
+            virtio_pci_device.set_activation_context(cgroups.clone());
+        }
+
         let virtio_pci_device = Arc::new(Mutex::new(virtio_pci_device));
 
-        pci.add_device(virtio_pci_device.clone())
+        pci.add_device(pci_device_id, virtio_pci_device.clone())
             .map_err(DeviceManagerError::AddPciDevice)?;
 
         pci.register_mapping(
@@ -1527,6 +2802,7 @@ impl DeviceManager {
 
         self.migratable_devices
             .push(Arc::clone(&virtio_pci_device) as Arc<Mutex<dyn Migratable>>);
+        self.virtio_pci_devices.push(virtio_pci_device);
 
         let ret = if iommu_mapping.is_some() {
             Some(dev_id)
@@ -1556,6 +2832,10 @@ impl DeviceManager {
                 .map_err(DeviceManagerError::RegisterIoevent)?;
         }
 
+        if let Some(cgroups) = &self.cgroups {
+            mmio_device.set_activation_context(cgroups.clone());
+        }
+
         let irq_num = self
             .address_manager
             .allocator
@@ -1611,6 +2891,139 @@ impl DeviceManager {
         &self.console
     }
 
+    pub fn balloon(&self) -> &Option<Arc<Mutex<vm_virtio::Balloon>>> {
+        &self.balloon
+    }
+
+    pub fn virtio_mem(&self) -> &Option<Arc<Mutex<vm_virtio::Mem>>> {
+        &self.virtio_mem
+    }
+
+    /// Whether devices DMA to guest memory pinned on their behalf, which a
+    /// copy-on-write view of the guest memory would get out of sync with.
+    pub fn pins_guest_memory(&self) -> bool {
+        #[cfg(feature = "pci_support")]
+        {
+            if !self.vfio_devices.is_empty() {
+                return true;
+            }
+        }
+
+        self.config
+            .lock()
+            .unwrap()
+            .vdpa
+            .as_ref()
+            .map_or(false, |vdpa| !vdpa.is_empty())
+    }
+
+    /// Tears the devices down once the vCPUs are stopped. The disks, behind
+    /// virtio-blk, virtio-scsi or NVMe, go first, each of them completing the requests in flight before flushing
+    /// its image, and the other virtio devices, closing their taps and
+    /// sockets, follow. The VFIO devices are then released, stopping their
+    /// DMA before the guest memory leaves their IOMMU table. The guest memory
+    /// is only released afterwards, along with the VM.
+    pub fn shutdown(&mut self) {
+        // No more input for the devices.
+        self.pty_readers.clear();
+        self.console_socket_readers.clear();
+
+        let virtio_devices = self
+            .virtio_devices
+            .drain(..)
+            .map(|(device, _, _)| device)
+            .collect();
+        #[allow(unused_mut)]
+        let mut nvme_devices = Vec::new();
+        #[allow(unused_mut)]
+        let mut vfio_devices = Vec::new();
+        #[cfg(feature = "pci_support")]
+        {
+            nvme_devices.append(&mut self.nvme_devices);
+            for device in self.vfio_devices.drain(..) {
+                vfio_devices.push(device as Arc<Mutex<dyn Teardown>>);
+            }
+        }
+
+        shutdown_devices(virtio_devices, nvme_devices, vfio_devices);
+    }
+
+    /// The images of the disks `resize_disk()` can be called for.
+    pub fn resizable_disks(&self) -> Vec<PathBuf> {
+        self.resizable_disks
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    /// Propagates the new size of the disk image at `path` to the
+    /// virtio-blk device using it. Returns the new size, in bytes.
+    pub fn resize_disk(&self, path: &Path) -> DeviceManagerResult<u64> {
+        let (_, disk) = self
+            .resizable_disks
+            .iter()
+            .find(|(disk_path, _)| disk_path == path)
+            .ok_or_else(|| DeviceManagerError::DiskNotResizable(path.to_path_buf()))?;
+
+        disk.lock()
+            .unwrap()
+            .resize()
+            .map_err(DeviceManagerError::DiskResize)
+    }
+
+    /// Replaces the rate limiters of the virtio-net device with the id
+    /// `id`, a missing configuration lifting the limit.
+    pub fn update_net(
+        &self,
+        id: &str,
+        rx_rate_limiter_config: Option<&RateLimiterConfig>,
+        tx_rate_limiter_config: Option<&RateLimiterConfig>,
+    ) -> DeviceManagerResult<()> {
+        let (_, net) = self
+            .updatable_nets
+            .iter()
+            .find(|(net_id, _)| net_id == id)
+            .ok_or_else(|| DeviceManagerError::NetNotUpdatable(id.to_string()))?;
+
+        net.lock().unwrap().set_rate_limiters(
+            rx_rate_limiter_config.map(rate_limiter),
+            tx_rate_limiter_config.map(rate_limiter),
+        );
+
+        Ok(())
+    }
+
+    /// The guest OS detected so far from the guest output, if any.
+    pub fn guest_os(&self) -> Option<GuestOs> {
+        self.guest_os_probe.guest_os()
+    }
+
+    fn changed_blocks(&self, path: &Path) -> DeviceManagerResult<&vm_virtio::ChangedBlocks> {
+        self.tracked_disks
+            .iter()
+            .find(|(disk_path, _)| disk_path == path)
+            .map(|(_, changed_blocks)| changed_blocks.as_ref())
+            .ok_or_else(|| DeviceManagerError::DiskNotTracked(path.to_path_buf()))
+    }
+
+    /// Takes a checkpoint of the blocks written to the disk image at
+    /// `path`, returning its identifier.
+    pub fn disk_checkpoint(&self, path: &Path) -> DeviceManagerResult<u64> {
+        Ok(self.changed_blocks(path)?.checkpoint())
+    }
+
+    /// Returns the ranges of bytes, as offset and length, of the disk image
+    /// at `path` written since the checkpoint `since`.
+    pub fn disk_changed_blocks(
+        &self,
+        path: &Path,
+        since: u64,
+    ) -> DeviceManagerResult<Vec<(u64, u64)>> {
+        self.changed_blocks(path)?
+            .changed_since(since)
+            .map_err(DeviceManagerError::DiskChangedBlocks)
+    }
+
     pub fn cmdline_additions(&self) -> &[String] {
         self.cmdline_additions.as_slice()
     }
@@ -1750,6 +3163,34 @@ impl Aml for DeviceManager {
         )
         .to_aml_bytes();
 
+        let pvpanic_dsdt_data = aml::Device::new(
+            "_SB_.PEVT".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &"QEMU0001"),
+                &aml::Name::new("_UID".into(), &aml::ZERO),
+                &aml::Name::new(
+                    "_CRS".into(),
+                    &aml::ResourceTemplate::new(vec![&aml::IO::new(0x505, 0x505, 1, 0x1)]),
+                ),
+            ],
+        )
+        .to_aml_bytes();
+
+        // The guest hibernating is turned into a VM snapshot, hence S4 is only
+        // advertised when the VM can be restored from it, i.e. when there's
+        // a state directory to save the snapshot into and SEV is disabled.
+        let config = self.config.lock().unwrap();
+        let hibernation = config.state_dir.is_some()
+            && !config
+                .platform
+                .as_ref()
+                .map(|p| p.sev_enabled())
+                .unwrap_or(false);
+        let serial_off = config.serial.mode == ConsoleOutputMode::Off;
+        drop(config);
+        let s4_sleep_data =
+            aml::Name::new("_S4_".into(), &aml::Package::new(vec![&4u8])).to_aml_bytes();
+
         let s5_sleep_data =
             aml::Name::new("_S5_".into(), &aml::Package::new(vec![&5u8])).to_aml_bytes();
 
@@ -1764,9 +3205,13 @@ impl Aml for DeviceManager {
 
         bytes.extend_from_slice(pci_dsdt_data.as_slice());
         bytes.extend_from_slice(mbrd_dsdt_data.as_slice());
-        if self.config.lock().unwrap().serial.mode != ConsoleOutputMode::Off {
+        if !serial_off {
             bytes.extend_from_slice(com1_dsdt_data.as_slice());
         }
+        bytes.extend_from_slice(pvpanic_dsdt_data.as_slice());
+        if hibernation {
+            bytes.extend_from_slice(s4_sleep_data.as_slice());
+        }
         bytes.extend_from_slice(s5_sleep_data.as_slice());
         bytes.extend_from_slice(ged_data.as_slice());
         bytes
@@ -1791,13 +3236,210 @@ impl Pausable for DeviceManager {
     }
 }
 
-impl Snapshotable for DeviceManager {}
+impl DeviceManager {
+    // The snapshot ids of the devices, numbered per kind in the order the
+    // devices are created, which is the same from the same configuration.
+    fn migratable_ids(&self) -> Vec<String> {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        self.migratable_devices
+            .iter()
+            .map(|dev| {
+                let id = dev.lock().unwrap().id();
+                let count = counts.entry(id.clone()).or_insert(0);
+                *count += 1;
+                format!("{}-{}", id, *count - 1)
+            })
+            .collect()
+    }
+
+    // Host devices passed through hold some state the VMM can't save.
+    fn check_snapshot_support(&self) -> result::Result<(), MigratableError> {
+        let config = self.config.lock().unwrap();
+        if config.devices.is_some() || config.sriov_vfs.is_some() {
+            return Err(MigratableError::Snapshot(anyhow!(
+                "VFIO devices don't support snapshots"
+            )));
+        }
+        if config.disks.iter().flatten().any(|disk| disk.nvme) {
+            return Err(MigratableError::Snapshot(anyhow!(
+                "NVMe disks don't support snapshots"
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Snapshotable for DeviceManager {
+    fn id(&self) -> String {
+        "device-manager".to_string()
+    }
+
+    fn snapshot(&mut self) -> result::Result<Snapshot, MigratableError> {
+        self.check_snapshot_support()?;
+
+        let mut snapshot = Snapshot::new(&self.id());
+        for (dev, id) in self.migratable_devices.iter().zip(self.migratable_ids()) {
+            let mut dev_snapshot = dev.lock().unwrap().snapshot()?;
+            dev_snapshot.id = id;
+            snapshot.add_snapshot(dev_snapshot);
+        }
+        Ok(snapshot)
+    }
+
+    // The devices are restored as created, hence running, and have to be
+    // paused before the vCPUs are started.
+    fn restore(&mut self, mut snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        self.check_snapshot_support()
+            .map_err(|e| MigratableError::Restore(e.into()))?;
+
+        for (dev, id) in self.migratable_devices.iter().zip(self.migratable_ids()) {
+            dev.lock().unwrap().restore(snapshot.take_snapshot(&id)?)?;
+        }
+        if let Some(id) = snapshot.snapshots.keys().next() {
+            return Err(MigratableError::Restore(anyhow!(
+                "Device {} missing from the configuration",
+                id
+            )));
+        }
+
+        #[cfg(feature = "pci_support")]
+        for virtio_pci_device in self.virtio_pci_devices.iter() {
+            let mut virtio_pci_device = virtio_pci_device.lock().unwrap();
+            for params in virtio_pci_device.take_restored_bar_moves() {
+                self.address_manager
+                    .move_bar(
+                        params.old_base,
+                        params.new_base,
+                        params.len,
+                        &mut *virtio_pci_device,
+                        params.region_type,
+                    )
+                    .map_err(|e| MigratableError::Restore(e.into()))?;
+            }
+            virtio_pci_device.activate_restored()?;
+        }
+
+        Ok(())
+    }
+}
 impl Migratable for DeviceManager {}
 
 impl Drop for DeviceManager {
     fn drop(&mut self) {
-        for (device, _) in self.virtio_devices.drain(..) {
-            device.lock().unwrap().shutdown();
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::{GuestMemoryAtomic, GuestMemoryMmap};
+    use vm_virtio::{ActivateResult, Queue, VirtioDevice, VirtioDeviceType, VirtioInterrupt};
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    // A virtio device logging its shutdown, and the flush of its disk if it
+    // has one.
+    struct TestVirtioDevice {
+        name: &'static str,
+        device_type: VirtioDeviceType,
+        log: Log,
+    }
+
+    impl VirtioDevice for TestVirtioDevice {
+        fn device_type(&self) -> u32 {
+            self.device_type as u32
         }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            &[]
+        }
+
+        fn read_config(&self, _offset: u64, _data: &mut [u8]) {}
+
+        fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+        fn activate(
+            &mut self,
+            _mem: GuestMemoryAtomic<GuestMemoryMmap>,
+            _interrupt_evt: Arc<dyn VirtioInterrupt>,
+            _queues: Vec<Queue>,
+            _queue_evts: Vec<EventFd>,
+        ) -> ActivateResult {
+            Ok(())
+        }
+
+        fn shutdown(&mut self) {
+            let mut log = self.log.lock().unwrap();
+            log.push(format!("shutdown {}", self.name));
+            match self.device_type {
+                VirtioDeviceType::TYPE_BLOCK | VirtioDeviceType::TYPE_SCSI => {
+                    log.push(format!("flush {}", self.name))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // An NVMe controller or VFIO device logging its teardown.
+    struct TestDevice {
+        name: &'static str,
+        log: Log,
+    }
+
+    impl Teardown for TestDevice {
+        fn teardown(&mut self) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("teardown {}", self.name));
+        }
+    }
+
+    #[test]
+    fn test_shutdown_devices() {
+        let log: Log = Arc::new(Mutex::new(Vec::new()));
+        let virtio = |name, device_type| -> VirtioDeviceArc {
+            Arc::new(Mutex::new(TestVirtioDevice {
+                name,
+                device_type,
+                log: log.clone(),
+            }))
+        };
+        let other = |name| -> Arc<Mutex<dyn Teardown>> {
+            Arc::new(Mutex::new(TestDevice {
+                name,
+                log: log.clone(),
+            }))
+        };
+
+        shutdown_devices(
+            vec![
+                virtio("net0", VirtioDeviceType::TYPE_NET),
+                virtio("scsi0", VirtioDeviceType::TYPE_SCSI),
+                virtio("rng0", VirtioDeviceType::TYPE_RNG),
+                virtio("disk0", VirtioDeviceType::TYPE_BLOCK),
+                virtio("disk1", VirtioDeviceType::TYPE_BLOCK),
+            ],
+            vec![other("nvme0")],
+            vec![other("vfio0"), other("vfio1")],
+        );
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "shutdown disk0",
+                "flush disk0",
+                "shutdown disk1",
+                "flush disk1",
+                "shutdown scsi0",
+                "flush scsi0",
+                "teardown nvme0",
+                "shutdown net0",
+                "shutdown rng0",
+                "teardown vfio0",
+                "teardown vfio1",
+            ]
+        );
     }
 }
@@ -0,0 +1,399 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Conversion between the Firecracker machine configuration, as given to
+//! `firecracker --config-file`, and `VmConfig`, easing the migration of
+//! existing microVMs.
+//!
+//! The Firecracker devices sit on virtio-mmio, while the Cloud Hypervisor
+//! ones are virtio-pci devices, hence `pci=off` is dropped from the kernel
+//! command line. Settings without an equivalent are rejected rather than
+//! silently ignored, except for the logger, the metrics and MMDS which don't
+//! change what the guest sees.
+
+use crate::config::{RateLimiterConfig, VmConfig};
+use net_util::MacAddr;
+use serde_json::json;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::result;
+
+/// Memory size of a Firecracker microVM without machine configuration.
+const DEFAULT_MEM_SIZE_MIB: u64 = 128;
+/// Kernel command line of a Firecracker microVM without boot arguments.
+const DEFAULT_BOOT_ARGS: &str = "reboot=k panic=1 nomodules 8250.nr_uarts=0 \
+                                 i8042.noaux i8042.nomux i8042.nopnp i8042.dumbkbd";
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot open the Firecracker configuration file.
+    Open(io::Error),
+    /// Cannot parse the Firecracker configuration.
+    Parse(serde_json::Error),
+    /// Cannot build the VM configuration.
+    Convert(serde_json::Error),
+    /// This setting has no equivalent.
+    Unsupported(String),
+    /// The VM has no kernel.
+    MissingKernel,
+    /// Firecracker network interfaces need a TAP interface name.
+    MissingTap,
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BootSource {
+    pub kernel_image_path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_args: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initrd_path: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Drive {
+    pub drive_id: String,
+    pub path_on_host: PathBuf,
+    pub is_root_device: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partuuid: Option<String>,
+    pub is_read_only: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limiter: Option<RateLimiterConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MachineConfig {
+    pub vcpu_count: u8,
+    pub mem_size_mib: u64,
+    #[serde(default)]
+    pub ht_enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_template: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub track_dirty_pages: bool,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        MachineConfig {
+            vcpu_count: 1,
+            mem_size_mib: DEFAULT_MEM_SIZE_MIB,
+            ht_enabled: false,
+            cpu_template: None,
+            track_dirty_pages: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NetworkInterface {
+    pub iface_id: String,
+    pub host_dev_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_mac: Option<MacAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rx_rate_limiter: Option<RateLimiterConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tx_rate_limiter: Option<RateLimiterConfig>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub allow_mmds_requests: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Vsock {
+    pub vsock_id: String,
+    pub guest_cid: u64,
+    pub uds_path: PathBuf,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct FirecrackerConfig {
+    #[serde(rename = "boot-source")]
+    pub boot_source: BootSource,
+    #[serde(default)]
+    pub drives: Vec<Drive>,
+    #[serde(rename = "machine-config", default)]
+    pub machine_config: MachineConfig,
+    #[serde(rename = "network-interfaces", default)]
+    pub network_interfaces: Vec<NetworkInterface>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vsock: Option<Vsock>,
+    #[serde(default, skip_serializing)]
+    pub logger: Option<serde_json::Value>,
+    #[serde(default, skip_serializing)]
+    pub metrics: Option<serde_json::Value>,
+    #[serde(rename = "mmds-config", default, skip_serializing)]
+    pub mmds_config: Option<serde_json::Value>,
+}
+
+fn unsupported<T>(setting: &str) -> Result<T> {
+    Err(Error::Unsupported(setting.to_string()))
+}
+
+impl FirecrackerConfig {
+    /// Reads a Firecracker configuration file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let file = File::open(path).map_err(Error::Open)?;
+        serde_json::from_reader(file).map_err(Error::Parse)
+    }
+
+    /// Maps the Firecracker configuration onto a VM configuration.
+    pub fn to_vm_config(&self) -> Result<VmConfig> {
+        if self.boot_source.initrd_path.is_some() {
+            return unsupported("initrd_path");
+        }
+        if self.machine_config.cpu_template.is_some() {
+            return unsupported("cpu_template");
+        }
+        if self.machine_config.ht_enabled {
+            warn!("Firecracker ht_enabled ignored, the vCPUs are exposed as separate cores");
+        }
+        if self.logger.is_some() || self.metrics.is_some() || self.mmds_config.is_some() {
+            warn!("Firecracker logger, metrics and MMDS configurations ignored");
+        }
+
+        let mut cmdline: Vec<String> = self
+            .boot_source
+            .boot_args
+            .as_deref()
+            .unwrap_or(DEFAULT_BOOT_ARGS)
+            .split_whitespace()
+            .filter(|arg| *arg != "pci=off")
+            .map(String::from)
+            .collect();
+
+        // Firecracker exposes the root device as the first disk, and adds
+        // it to the kernel command line.
+        let mut drives: Vec<&Drive> = self.drives.iter().filter(|d| d.is_root_device).collect();
+        if drives.len() > 1 {
+            return unsupported("is_root_device set on several drives");
+        }
+        if let Some(drive) = drives.first() {
+            if !cmdline.iter().any(|arg| arg.starts_with("root=")) {
+                cmdline.push(match &drive.partuuid {
+                    Some(partuuid) => format!("root=PARTUUID={}", partuuid),
+                    None => String::from("root=/dev/vda"),
+                });
+                cmdline.push(String::from(if drive.is_read_only { "ro" } else { "rw" }));
+            }
+        }
+        drives.extend(self.drives.iter().filter(|d| !d.is_root_device));
+
+        let disks: Vec<serde_json::Value> = drives
+            .iter()
+            .map(|drive| {
+                json!({
+                    "path": drive.path_on_host,
+                    "readonly": drive.is_read_only,
+                    "rate_limiter_config": drive.rate_limiter,
+                })
+            })
+            .collect();
+
+        let mut net = Vec::new();
+        for iface in self.network_interfaces.iter() {
+            if iface.rx_rate_limiter.is_some() || iface.tx_rate_limiter.is_some() {
+                return unsupported("network interface rate limiters");
+            }
+            if iface.allow_mmds_requests {
+                warn!("Firecracker allow_mmds_requests ignored, MMDS isn't supported");
+            }
+            let mut config = json!({ "tap": iface.host_dev_name });
+            if let Some(mac) = &iface.guest_mac {
+                config["mac"] = json!(mac);
+            }
+            net.push(config);
+        }
+
+        let vsock = self.vsock.as_ref().map(|vsock| {
+            json!([{
+                "cid": vsock.guest_cid,
+                "sock": vsock.uds_path,
+            }])
+        });
+
+        let vcpus = self.machine_config.vcpu_count;
+        serde_json::from_value(json!({
+            "cpus": {"boot_vcpus": vcpus, "max_vcpus": vcpus},
+            "memory": {"size": self.machine_config.mem_size_mib << 20},
+            "kernel": {"path": self.boot_source.kernel_image_path},
+            "cmdline": {"args": cmdline.join(" ")},
+            "disks": Some(disks).filter(|d| !d.is_empty()),
+            "net": Some(net).filter(|n| !n.is_empty()),
+            "vsock": vsock,
+            // The Firecracker serial port is the guest console.
+            "serial": {"mode": "Tty"},
+            "console": {"mode": "Off"},
+        }))
+        .map_err(Error::Convert)
+    }
+
+    /// Maps a VM configuration onto a Firecracker configuration, provided
+    /// it only uses what Firecracker supports.
+    pub fn from_vm_config(config: &VmConfig) -> Result<Self> {
+        if config.cpus.max_vcpus != config.cpus.boot_vcpus {
+            return unsupported("max_vcpus");
+        }
+        if config.memory.hotplug_size.is_some() || config.memory.zones.is_some() {
+            return unsupported("memory hotplug and zones");
+        }
+        if config.scsi.is_some()
+            || config.fs.is_some()
+            || config.pmem.is_some()
+            || config.devices.is_some()
+            || config.sriov_vfs.is_some()
+            || config.vhost_user_net.is_some()
+            || config.vhost_user_blk.is_some()
+            || config.sgx_epc.is_some()
+            || config.balloon.is_some()
+        {
+            return unsupported("devices other than disks, network interfaces and vsock");
+        }
+
+        let kernel = config.kernel.as_ref().ok_or(Error::MissingKernel)?;
+
+        let mut drives = Vec::new();
+        for (i, disk) in config.disks.iter().flatten().enumerate() {
+            if disk.vhost_user || disk.nvme || disk.overlay.is_some() {
+                return unsupported("vhost-user, NVMe and overlay disks");
+            }
+            drives.push(Drive {
+                drive_id: format!("disk{}", i),
+                path_on_host: disk.path.clone(),
+                is_root_device: false,
+                partuuid: None,
+                is_read_only: disk.readonly,
+                rate_limiter: disk.rate_limiter_config.clone(),
+            });
+        }
+
+        let mut network_interfaces = Vec::new();
+        for (i, net) in config.net.iter().flatten().enumerate() {
+            if net.vhost_user {
+                return unsupported("vhost-user network interfaces");
+            }
+            network_interfaces.push(NetworkInterface {
+                iface_id: format!("net{}", i),
+                host_dev_name: net.tap.clone().ok_or(Error::MissingTap)?,
+                guest_mac: Some(net.mac),
+                rx_rate_limiter: None,
+                tx_rate_limiter: None,
+                allow_mmds_requests: false,
+            });
+        }
+
+        let vsock = match config.vsock.as_deref() {
+            None | Some([]) => None,
+            Some([vsock]) => Some(Vsock {
+                vsock_id: String::from("vsock0"),
+                guest_cid: vsock.cid,
+                uds_path: vsock.sock.clone(),
+            }),
+            Some(_) => return unsupported("several vsock devices"),
+        };
+
+        Ok(FirecrackerConfig {
+            boot_source: BootSource {
+                kernel_image_path: kernel.path.clone(),
+                boot_args: Some(config.cmdline.args.clone()),
+                initrd_path: None,
+            },
+            drives,
+            machine_config: MachineConfig {
+                vcpu_count: config.cpus.boot_vcpus,
+                mem_size_mib: config.memory.size >> 20,
+                ..Default::default()
+            },
+            network_interfaces,
+            vsock,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_vm_config() {
+        let fc_config: FirecrackerConfig = serde_json::from_str(
+            r#"{
+                "boot-source": {
+                    "kernel_image_path": "/path/to/vmlinux",
+                    "boot_args": "console=ttyS0 reboot=k panic=1 pci=off"
+                },
+                "drives": [
+                    {
+                        "drive_id": "data",
+                        "path_on_host": "/path/to/data.ext4",
+                        "is_root_device": false,
+                        "is_read_only": true
+                    },
+                    {
+                        "drive_id": "rootfs",
+                        "path_on_host": "/path/to/rootfs.ext4",
+                        "is_root_device": true,
+                        "is_read_only": false,
+                        "rate_limiter": {"ops": {"size": 1000, "refill_time": 100}}
+                    }
+                ],
+                "machine-config": {"vcpu_count": 2, "mem_size_mib": 1024, "ht_enabled": false},
+                "network-interfaces": [
+                    {"iface_id": "eth0", "guest_mac": "AA:FC:00:00:00:01", "host_dev_name": "tap0"}
+                ],
+                "vsock": {"vsock_id": "1", "guest_cid": 3, "uds_path": "/tmp/v.sock"},
+                "logger": {"log_path": "/tmp/fc.log"}
+            }"#,
+        )
+        .unwrap();
+
+        let config = fc_config.to_vm_config().unwrap();
+        assert_eq!(config.cpus.boot_vcpus, 2);
+        assert_eq!(config.cpus.max_vcpus, 2);
+        assert_eq!(config.memory.size, 1 << 30);
+        assert_eq!(
+            config.cmdline.args,
+            "console=ttyS0 reboot=k panic=1 root=/dev/vda rw"
+        );
+
+        let disks = config.disks.as_ref().unwrap();
+        assert_eq!(disks[0].path, PathBuf::from("/path/to/rootfs.ext4"));
+        assert!(!disks[0].readonly);
+        assert!(disks[0].rate_limiter_config.is_some());
+        assert_eq!(disks[1].path, PathBuf::from("/path/to/data.ext4"));
+        assert!(disks[1].readonly);
+
+        let net = config.net.as_ref().unwrap();
+        assert_eq!(net[0].tap, Some(String::from("tap0")));
+        assert_eq!(net[0].mac, MacAddr::parse_str("AA:FC:00:00:00:01").unwrap());
+
+        let vsock = config.vsock.as_ref().unwrap();
+        assert_eq!(vsock[0].cid, 3);
+
+        let fc_config = FirecrackerConfig::from_vm_config(&config).unwrap();
+        assert_eq!(fc_config.machine_config.vcpu_count, 2);
+        assert_eq!(fc_config.drives.len(), 2);
+        assert_eq!(fc_config.network_interfaces[0].host_dev_name, "tap0");
+    }
+
+    #[test]
+    fn test_to_vm_config_unsupported() {
+        let fc_config: FirecrackerConfig = serde_json::from_str(
+            r#"{
+                "boot-source": {
+                    "kernel_image_path": "/path/to/vmlinux",
+                    "initrd_path": "/path/to/initrd"
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert!(fc_config.to_vm_config().is_err());
+    }
+}
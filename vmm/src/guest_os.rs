@@ -0,0 +1,188 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Guesses the guest OS from what it prints while booting, so that the VMM
+//! users can apply OS specific policies without being told which OS runs.
+//!
+//! This is a heuristic: a Linux guest is recognized from the kernel banner
+//! on the serial port or on the virtio console, and a Windows guest from the
+//! Windows Boot Manager path the firmware logs on its debug port. A guest
+//! printing neither, e.g. booted with a quiet console, remains unknown.
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+// The part of the lines looked at, both patterns being near the start.
+const MAX_LINE_LEN: usize = 256;
+
+const LINUX_BANNER: &str = "Linux version ";
+const WINDOWS_BOOT_MANAGER: &str = "\\efi\\microsoft\\boot\\bootmgfw.efi";
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum GuestOsFamily {
+    Linux,
+    Windows,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct GuestOs {
+    pub family: GuestOsFamily,
+    /// The kernel release, for Linux.
+    pub version: Option<String>,
+}
+
+// Recognizes the guest OS from one line of its output.
+fn probe_line(line: &str) -> Option<GuestOs> {
+    if let Some(start) = line.find(LINUX_BANNER) {
+        let version = line[start + LINUX_BANNER.len()..]
+            .split_whitespace()
+            .next()
+            .map(String::from);
+        return Some(GuestOs {
+            family: GuestOsFamily::Linux,
+            version,
+        });
+    }
+
+    if line.to_lowercase().contains(WINDOWS_BOOT_MANAGER) {
+        return Some(GuestOs {
+            family: GuestOsFamily::Windows,
+            version: None,
+        });
+    }
+
+    None
+}
+
+/// The guest OS detected so far, fed by the guest output.
+#[derive(Default)]
+pub struct GuestOsProbe {
+    detected: AtomicBool,
+    guest_os: Mutex<Option<GuestOs>>,
+}
+
+impl GuestOsProbe {
+    pub fn new() -> Self {
+        GuestOsProbe::default()
+    }
+
+    pub fn guest_os(&self) -> Option<GuestOs> {
+        self.guest_os.lock().unwrap().clone()
+    }
+
+    fn detected(&self) -> bool {
+        self.detected.load(Ordering::Acquire)
+    }
+
+    fn probe(&self, line: &[u8]) {
+        if let Some(guest_os) = probe_line(&String::from_utf8_lossy(line)) {
+            let mut detected = self.guest_os.lock().unwrap();
+            // The first OS to show up is the one booting.
+            if detected.is_none() {
+                info!("Guest OS detected: {:?}", guest_os);
+                *detected = Some(guest_os);
+                self.detected.store(true, Ordering::Release);
+            }
+        }
+    }
+}
+
+/// Writes the guest output to `inner`, looking for the guest OS in it until
+/// it's found.
+pub struct ProbeWriter<W: Write> {
+    inner: W,
+    probe: Arc<GuestOsProbe>,
+    line: Vec<u8>,
+}
+
+impl<W: Write> ProbeWriter<W> {
+    pub fn new(inner: W, probe: Arc<GuestOsProbe>) -> Self {
+        ProbeWriter {
+            inner,
+            probe,
+            line: Vec::with_capacity(MAX_LINE_LEN),
+        }
+    }
+}
+
+impl<W: Write> Write for ProbeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        if self.probe.detected() {
+            return Ok(count);
+        }
+
+        for b in &buf[..count] {
+            match b {
+                b'\n' => {
+                    self.probe.probe(&self.line);
+                    self.line.clear();
+                }
+                b'\r' => (),
+                _ if self.line.len() < MAX_LINE_LEN => self.line.push(*b),
+                _ => (),
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_line() {
+        assert_eq!(
+            probe_line(
+                "[    0.000000] Linux version 5.6.0-rc4+ (gcc version 9.2.1) #1 SMP Mon Mar 9"
+            ),
+            Some(GuestOs {
+                family: GuestOsFamily::Linux,
+                version: Some("5.6.0-rc4+".to_string()),
+            })
+        );
+        assert_eq!(
+            probe_line(
+                "BdsDxe: loading Boot0001 \"Windows Boot Manager\" from \
+                 HD(1,GPT,0A1B,0x800,0x32000)/\\EFI\\Microsoft\\Boot\\bootmgfw.efi"
+            ),
+            Some(GuestOs {
+                family: GuestOsFamily::Windows,
+                version: None,
+            })
+        );
+        assert_eq!(probe_line("Welcome to Linux!"), None);
+    }
+
+    #[test]
+    fn test_probe_writer() {
+        let probe = Arc::new(GuestOsProbe::new());
+        let mut writer = ProbeWriter::new(Vec::new(), probe.clone());
+
+        // The banner can be split across writes.
+        writer
+            .write_all(b"Booting\r\n[    0.000000] Linux ver")
+            .unwrap();
+        assert_eq!(probe.guest_os(), None);
+        writer.write_all(b"sion 5.4.0 (gcc)\r\n").unwrap();
+        assert_eq!(
+            probe.guest_os().map(|os| os.version),
+            Some(Some("5.4.0".to_string()))
+        );
+
+        // The output goes through unchanged.
+        assert_eq!(
+            writer.inner,
+            b"Booting\r\n[    0.000000] Linux version 5.4.0 (gcc)\r\n".to_vec()
+        );
+    }
+}
@@ -13,27 +13,54 @@ extern crate serde;
 extern crate serde_derive;
 extern crate serde_json;
 extern crate tempfile;
+#[macro_use]
 extern crate vmm_sys_util;
 
-use crate::api::{ApiError, ApiRequest, ApiResponse, ApiResponsePayload, VmInfo, VmmPingResponse};
-use crate::config::VmConfig;
+use crate::api::{
+    ApiError, ApiRequest, ApiResponse, ApiResponsePayload, DiskChangedBlocks, DiskCheckpoint,
+    DiskRange, InterfaceConfig, InterfaceInfo, OperationInfo, VmClockData, VmCounters, VmInfo,
+    VmNetUpdate, VmSnapshotExportConfig, VmSnapshotImportConfig, VmmCapabilities, VmmPingResponse,
+    VolumeConfig, VolumeInfo,
+};
+use crate::config::{DiskConfig, NetConfig, PanicAction, VmConfig};
+use crate::operation::{Operation, OperationPhase};
+use crate::timer::TimerWheel;
+use crate::tty_mux::TtyMux;
 use crate::vm::{Error as VmError, Vm, VmState};
 use libc::EFD_NONBLOCK;
-use std::io;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{Receiver, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
-use std::{result, thread};
+use std::time::Duration;
+use std::{cmp, result, thread};
+use uuid::Uuid;
 use vm_device::Pausable;
 use vmm_sys_util::eventfd::EventFd;
 
 pub mod api;
+pub mod cgroup;
 pub mod config;
+pub mod console_socket;
 pub mod cpu;
 pub mod device_manager;
+pub mod firecracker;
+pub mod guest_os;
 pub mod interrupt;
 pub mod memory_manager;
+pub mod operation;
+pub mod pty;
+pub mod sev;
+pub mod snapshot_archive;
+#[cfg(feature = "pci_support")]
+pub mod sriov;
+pub mod state_dir;
+pub mod timer;
+pub mod tty_mux;
+pub mod virtiofsd;
 pub mod vm;
 
 #[cfg(feature = "acpi")]
@@ -61,21 +88,39 @@ pub enum Error {
     /// Cannot read from EventFd.
     EventFdRead(io::Error),
 
+    /// Cannot write to EventFd.
+    EventFdWrite(io::Error),
+
     /// Cannot create epoll context.
     Epoll(io::Error),
 
+    /// Cannot create the timers.
+    TimersCreate(timer::Error),
+
+    /// Cannot run the expired timers.
+    Timers(timer::Error),
+
     /// Cannot create HTTP thread
     HttpThreadSpawn(io::Error),
 
+    /// Cannot create QMP thread
+    QmpThreadSpawn(io::Error),
+
     /// Cannot handle the VM STDIN stream
     Stdin(VmError),
 
+    /// Cannot pause the VM
+    VmPause(VmError),
+
     /// Cannot reboot the VM
     VmReboot(VmError),
 
     /// Cannot shut a VM down
     VmShutdown(VmError),
 
+    /// Cannot complete the VM snapshot
+    VmSnapshot(VmError),
+
     /// Cannot create VMM thread
     VmmThreadSpawn(io::Error),
 
@@ -91,8 +136,13 @@ pub type Result<T> = result::Result<T, Error>;
 pub enum EpollDispatch {
     Exit,
     Reset,
+    Panic,
+    Hibernate,
+    DiskError,
+    Snapshot,
     Stdin,
     Api,
+    Timer,
 }
 
 pub struct EpollContext {
@@ -107,9 +157,14 @@ impl EpollContext {
         // Initial capacity needs to be large enough to hold:
         // * 1 exit event
         // * 1 reset event
+        // * 1 panic event
+        // * 1 hibernation event
+        // * 1 disk lost event
+        // * 1 snapshot written event
         // * 1 stdin event
         // * 1 API event
-        let mut dispatch_table = Vec::with_capacity(5);
+        // * 1 timer event
+        let mut dispatch_table = Vec::with_capacity(10);
         dispatch_table.push(None);
 
         Ok(EpollContext {
@@ -155,14 +210,23 @@ impl AsRawFd for EpollContext {
     }
 }
 
+/// Starts the VMM and its API servers, the HTTP API requests being only
+/// served once `api_authorizer`, if any, allowed them.
+#[allow(clippy::too_many_arguments)]
 pub fn start_vmm_thread(
     vmm_version: String,
     http_path: &str,
+    http_ro_path: Option<&str>,
+    qmp_path: Option<&str>,
+    api_authorizer: Option<Arc<dyn api::ApiAuthorizer>>,
     api_event: EventFd,
     api_sender: Sender<ApiRequest>,
     api_receiver: Receiver<ApiRequest>,
 ) -> Result<thread::JoinHandle<Result<()>>> {
     let http_api_event = api_event.try_clone().map_err(Error::EventFdClone)?;
+    let http_ro_api_event = api_event.try_clone().map_err(Error::EventFdClone)?;
+    let qmp_api_event = api_event.try_clone().map_err(Error::EventFdClone)?;
+    let qmp_vmm_version = vmm_version.clone();
 
     // Find the path that the "/proc/<pid>/exe" symlink points to. Must be done before spawning
     // a thread as Rust does not put the child threads in the same thread group which prevents the
@@ -179,21 +243,143 @@ pub fn start_vmm_thread(
         })
         .map_err(Error::VmmThreadSpawn)?;
 
-    // The VMM thread is started, we can start serving HTTP requests
-    api::start_http_thread(http_path, http_api_event, api_sender)?;
+    // The VMM thread is started, we can start serving QMP and HTTP requests
+    if let Some(qmp_path) = qmp_path {
+        api::start_qmp_thread(qmp_path, qmp_vmm_version, qmp_api_event, api_sender.clone())?;
+    }
+    if let Some(http_ro_path) = http_ro_path {
+        api::start_http_thread(
+            http_ro_path,
+            true,
+            api_authorizer.clone(),
+            http_ro_api_event,
+            api_sender.clone(),
+        )?;
+    }
+    api::start_http_thread(http_path, false, api_authorizer, http_api_event, api_sender)?;
 
     Ok(thread)
 }
 
+// A disk created through the API, independently of the VM
+struct Volume {
+    disk: DiskConfig,
+    attached: bool,
+}
+
+// A network interface created through the API, independently of the VM
+struct Interface {
+    net: NetConfig,
+    attached: bool,
+}
+
+// Writes the TTY multiplexer output, the terminal being stdout.
+fn write_terminal(output: &[u8]) {
+    if output.is_empty() {
+        return;
+    }
+    let mut stdout = io::stdout();
+    if let Err(e) = stdout.write_all(output).and_then(|_| stdout.flush()) {
+        warn!("Failed writing to the terminal: {}", e);
+    }
+}
+
+// Names of the VM config top level fields which differ.
+fn vm_config_changes(current: &VmConfig, desired: &VmConfig) -> Vec<String> {
+    match (serde_json::to_value(current), serde_json::to_value(desired)) {
+        (Ok(serde_json::Value::Object(current)), Ok(serde_json::Value::Object(desired))) => current
+            .keys()
+            .chain(desired.keys().filter(|k| !current.contains_key(*k)))
+            .filter(|k| current.get(*k) != desired.get(*k))
+            .cloned()
+            .collect(),
+        _ => vec![String::from("*")],
+    }
+}
+
+// The operations bringing a booted VM from its configuration to the desired
+// one.
+#[derive(Default)]
+struct SpecChanges {
+    desired_vcpus: Option<u8>,
+    desired_ram: Option<u64>,
+    net_updates: Vec<VmNetUpdate>,
+}
+
+// Computes the operations bringing a booted VM from its `current`
+// configuration to the `desired` one, or returns the fields which would
+// require the VM to be rebooted: anything but the number of vCPUs, the
+// memory size and the rate limiters of the interfaces the VMM emulates.
+fn spec_changes(
+    current: &VmConfig,
+    desired: &VmConfig,
+) -> result::Result<SpecChanges, Vec<String>> {
+    let mut applied = desired.clone();
+    applied.cpus.boot_vcpus = current.cpus.boot_vcpus;
+    applied.memory.size = current.memory.size;
+
+    let mut net_updates = Vec::new();
+    if let (Some(current_net), Some(applied_net)) = (&current.net, &mut applied.net) {
+        if current_net.len() == applied_net.len() {
+            for (current, applied) in current_net.iter().zip(applied_net.iter_mut()) {
+                let id = match &current.id {
+                    Some(id) if current.id == applied.id && !current.vhost_user => id,
+                    _ => continue,
+                };
+                if current.rx_rate_limiter_config != applied.rx_rate_limiter_config
+                    || current.tx_rate_limiter_config != applied.tx_rate_limiter_config
+                {
+                    net_updates.push(VmNetUpdate {
+                        id: id.clone(),
+                        rx_rate_limiter_config: applied.rx_rate_limiter_config.take(),
+                        tx_rate_limiter_config: applied.tx_rate_limiter_config.take(),
+                    });
+                    applied.rx_rate_limiter_config = current.rx_rate_limiter_config.clone();
+                    applied.tx_rate_limiter_config = current.tx_rate_limiter_config.clone();
+                }
+            }
+        }
+    }
+
+    let changes = vm_config_changes(current, &applied);
+    if !changes.is_empty() {
+        return Err(changes);
+    }
+
+    Ok(SpecChanges {
+        desired_vcpus: Some(desired.cpus.boot_vcpus).filter(|v| *v != current.cpus.boot_vcpus),
+        desired_ram: Some(desired.memory.size).filter(|s| *s != current.memory.size),
+        net_updates,
+    })
+}
+
+// Resolution of the timers the VM subsystems schedule.
+const TIMER_TICK_MS: u64 = 10;
+
+// How long the finished operations are kept around for their outcome to be
+// followed.
+const OPERATION_TTL: Duration = Duration::from_secs(600);
+
 pub struct Vmm {
     epoll: EpollContext,
+    timers: Arc<TimerWheel>,
     exit_evt: EventFd,
     reset_evt: EventFd,
+    panic_evt: EventFd,
+    hibernate_evt: EventFd,
+    disk_evt: EventFd,
+    snapshot_evt: EventFd,
     api_evt: EventFd,
     version: String,
     vm: Option<Vm>,
     vm_config: Option<Arc<Mutex<VmConfig>>>,
     vmm_path: PathBuf,
+    volumes: BTreeMap<String, Volume>,
+    interfaces: BTreeMap<String, Interface>,
+    operations: BTreeMap<u64, Arc<Operation>>,
+    next_operation_id: u64,
+    saved_config: Option<VmConfig>,
+    tty_mux: TtyMux,
 }
 
 impl Vmm {
@@ -201,6 +387,10 @@ impl Vmm {
         let mut epoll = EpollContext::new().map_err(Error::Epoll)?;
         let exit_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
         let reset_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let panic_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let hibernate_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let disk_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let snapshot_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
 
         if unsafe { libc::isatty(libc::STDIN_FILENO as i32) } != 0 {
             epoll.add_stdin().map_err(Error::Epoll)?;
@@ -214,33 +404,137 @@ impl Vmm {
             .add_event(&reset_evt, EpollDispatch::Reset)
             .map_err(Error::Epoll)?;
 
+        epoll
+            .add_event(&panic_evt, EpollDispatch::Panic)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&hibernate_evt, EpollDispatch::Hibernate)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&disk_evt, EpollDispatch::DiskError)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&snapshot_evt, EpollDispatch::Snapshot)
+            .map_err(Error::Epoll)?;
+
         epoll
             .add_event(&api_evt, EpollDispatch::Api)
             .map_err(Error::Epoll)?;
 
+        let timers = Arc::new(
+            TimerWheel::new(Duration::from_millis(TIMER_TICK_MS)).map_err(Error::TimersCreate)?,
+        );
+        epoll
+            .add_event(&*timers, EpollDispatch::Timer)
+            .map_err(Error::Epoll)?;
+
         Ok(Vmm {
             epoll,
+            timers,
             exit_evt,
             reset_evt,
+            panic_evt,
+            hibernate_evt,
+            disk_evt,
+            snapshot_evt,
             api_evt,
             version: vmm_version,
             vm: None,
             vm_config: None,
             vmm_path,
+            volumes: BTreeMap::new(),
+            interfaces: BTreeMap::new(),
+            operations: BTreeMap::new(),
+            next_operation_id: 0,
+            saved_config: None,
+            tty_mux: TtyMux::new(),
         })
     }
 
+    fn handle_stdin(&mut self) -> Result<()> {
+        let output = match &self.vm {
+            Some(vm) if vm.tty_mux() => {
+                let mut input = [0u8; 64];
+                let count = vm.read_stdin(&mut input).map_err(Error::Stdin)?;
+                let output = self.tty_mux.process(&input[..count]);
+                vm.queue_console_input(&output.guest)
+                    .map_err(Error::Stdin)?;
+                output
+            }
+            Some(vm) => return vm.handle_stdin().map_err(Error::Stdin),
+            None => return Ok(()),
+        };
+
+        write_terminal(&output.terminal);
+        for command in output.commands {
+            match command {
+                tty_mux::Command::Info => {
+                    match self.vm_info() {
+                        Ok(info) => {
+                            let config = info.config.lock().unwrap();
+                            write_terminal(
+                                format!(
+                                    "State: {:?}\r\nvCPUs: {} (max {})\r\nMemory: {} MiB\r\n",
+                                    info.state,
+                                    config.cpus.boot_vcpus,
+                                    config.cpus.max_vcpus,
+                                    config.memory.total_size() >> 20
+                                )
+                                .as_bytes(),
+                            );
+                        }
+                        Err(e) => {
+                            write_terminal(format!("Error: {:?}\r\n", e).as_bytes());
+                        }
+                    }
+                    if self.tty_mux.prompting() {
+                        write_terminal(tty_mux::PROMPT.as_bytes());
+                    }
+                }
+                // Both go through the same path as the guest requests.
+                tty_mux::Command::Reboot => self.reset_evt.write(1).map_err(Error::EventFdWrite)?,
+                tty_mux::Command::Quit => self.exit_evt.write(1).map_err(Error::EventFdWrite)?,
+            }
+        }
+
+        Ok(())
+    }
+
     fn vm_boot(&mut self) -> result::Result<(), VmError> {
         // Create a new VM is we don't have one yet.
         if self.vm.is_none() {
             let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
             let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+            let panic_evt = self.panic_evt.try_clone().map_err(VmError::EventFdClone)?;
+            let hibernate_evt = self
+                .hibernate_evt
+                .try_clone()
+                .map_err(VmError::EventFdClone)?;
+            let disk_evt = self.disk_evt.try_clone().map_err(VmError::EventFdClone)?;
 
             if let Some(ref vm_config) = self.vm_config {
+                // The hibernation snapshot is only discarded once the VM
+                // has been restored from it, never on its behalf, but the
+                // guest disks move on from the state it was taken with.
+                let state_dir = vm_config.lock().unwrap().state_dir.clone();
+                if let Some(snapshot) = state_dir.as_deref().and_then(state_dir::hibernated) {
+                    warn!(
+                        "Booting anew, the hibernation snapshot {:?} no longer matches the disks",
+                        snapshot
+                    );
+                }
+
                 let vm = Vm::new(
                     Arc::clone(vm_config),
                     exit_evt,
                     reset_evt,
+                    panic_evt,
+                    hibernate_evt,
+                    disk_evt,
+                    self.timers.clone(),
                     self.vmm_path.clone(),
                 )?;
                 self.vm = Some(vm);
@@ -279,6 +573,82 @@ impl Vmm {
         }
     }
 
+    fn vm_panic(&mut self) -> Result<()> {
+        let on_panic = match &self.vm {
+            Some(vm) => vm.get_config().lock().unwrap().on_panic,
+            None => return Ok(()),
+        };
+
+        error!("Guest kernel panicked, action: {:?}", on_panic);
+
+        match on_panic {
+            PanicAction::Log => Ok(()),
+            PanicAction::Pause => self.vm_pause().map_err(Error::VmPause),
+            PanicAction::Shutdown => self.vm_shutdown().map_err(Error::VmShutdown),
+            PanicAction::Reboot => self.vm_reboot().map_err(Error::VmReboot),
+        }
+    }
+
+    // The guest entered the S4 sleep state. Its state is saved as the
+    // hibernation snapshot of the VM state directory, offered for restore
+    // until the VM boots anew, then the VM is powered off as with S5.
+    fn vm_hibernate(&mut self) -> Result<()> {
+        let state_dir = match &self.vm {
+            Some(vm) => vm.get_config().lock().unwrap().state_dir.clone(),
+            None => return Ok(()),
+        };
+
+        match state_dir {
+            Some(state_dir) => {
+                let destination = state_dir::hibernate_snapshot(&state_dir);
+                match self.vm_hibernate_snapshot(&destination) {
+                    Ok(()) => info!("Guest hibernated into {:?}", destination),
+                    Err(e) => error!("Failed saving the hibernation snapshot: {:?}", e),
+                }
+            }
+            None => warn!("Guest hibernated without a VM state directory, powering it off"),
+        }
+
+        self.vmm_shutdown().map_err(Error::VmmShutdown)
+    }
+
+    // The VM is paused for good, the guest not running anymore past its
+    // hibernation, and the snapshot waited for.
+    fn vm_hibernate_snapshot(&mut self, destination: &Path) -> result::Result<(), VmError> {
+        if self.vm.as_ref().unwrap().get_state()? == VmState::Running {
+            self.vm_pause()?;
+        }
+        let operation = self.vm_snapshot(destination, false, false)?;
+        let vm = self.vm.as_mut().unwrap();
+        vm.snapshot_written()?;
+        match self
+            .operations
+            .get(&operation.id)
+            .map(|op| op.info(operation.id))
+        {
+            Some(info) if info.phase == OperationPhase::Completed => Ok(()),
+            Some(info) => Err(VmError::SnapshotIncomplete(info.error.unwrap_or_default())),
+            None => Err(VmError::SnapshotIncomplete(String::new())),
+        }
+    }
+
+    // The memory of the snapshot in progress has been written out.
+    fn vm_snapshot_written(&mut self) -> result::Result<(), VmError> {
+        match self.vm {
+            Some(ref mut vm) => vm.snapshot_written(),
+            None => Ok(()),
+        }
+    }
+
+    // A watched disk went away, or a disk with the stop policy got an I/O
+    // error.
+    fn vm_disk_error(&mut self) -> Result<()> {
+        match &mut self.vm {
+            Some(vm) => vm.pause_on_disk_error().map_err(Error::VmPause),
+            None => Ok(()),
+        }
+    }
+
     fn vm_reboot(&mut self) -> result::Result<(), VmError> {
         // Without ACPI, a reset is equivalent to a shutdown
         #[cfg(not(feature = "acpi"))]
@@ -295,6 +665,12 @@ impl Vmm {
 
             let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
             let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+            let panic_evt = self.panic_evt.try_clone().map_err(VmError::EventFdClone)?;
+            let hibernate_evt = self
+                .hibernate_evt
+                .try_clone()
+                .map_err(VmError::EventFdClone)?;
+            let disk_evt = self.disk_evt.try_clone().map_err(VmError::EventFdClone)?;
 
             // The Linux kernel fires off an i8042 reset after doing the ACPI reset so there may be
             // an event sitting in the shared reset_evt. Without doing this we get very early reboots
@@ -302,7 +678,16 @@ impl Vmm {
             if self.reset_evt.read().is_ok() {
                 warn!("Spurious second reset event received. Ignoring.");
             }
-            self.vm = Some(Vm::new(config, exit_evt, reset_evt, self.vmm_path.clone())?);
+            self.vm = Some(Vm::new(
+                config,
+                exit_evt,
+                reset_evt,
+                panic_evt,
+                hibernate_evt,
+                disk_evt,
+                self.timers.clone(),
+                self.vmm_path.clone(),
+            )?);
         }
 
         // Then we start the new VM.
@@ -318,14 +703,32 @@ impl Vmm {
     fn vm_info(&self) -> result::Result<VmInfo, VmError> {
         match &self.vm_config {
             Some(config) => {
-                let state = match &self.vm {
-                    Some(vm) => vm.get_state()?,
-                    None => VmState::Created,
+                let (state, guest_os, serial_pty, console_pty) = match &self.vm {
+                    Some(vm) => {
+                        let (serial_pty, console_pty) = vm.ptys();
+                        (vm.get_state()?, vm.guest_os(), serial_pty, console_pty)
+                    }
+                    None => (VmState::Created, None, None, None),
+                };
+                let (paused_reason, lost_disks) = match &self.vm {
+                    Some(vm) => (vm.paused_reason(), vm.lost_disks()),
+                    None => (None, Vec::new()),
+                };
+                // Until the VM boots, which discards it.
+                let hibernate_snapshot = match (&self.vm, &config.lock().unwrap().state_dir) {
+                    (None, Some(state_dir)) => state_dir::hibernated(state_dir),
+                    _ => None,
                 };
 
                 Ok(VmInfo {
                     config: Arc::clone(config),
                     state,
+                    guest_os,
+                    hibernate_snapshot,
+                    serial_pty,
+                    console_pty,
+                    paused_reason,
+                    lost_disks,
                 })
             }
             None => Err(VmError::VmNotCreated),
@@ -338,6 +741,69 @@ impl Vmm {
         })
     }
 
+    fn vmm_capabilities(&self) -> result::Result<VmmCapabilities, ApiError> {
+        let kvm = kvm_ioctls::Kvm::new().map_err(ApiError::VmmCapabilities)?;
+
+        let mut devices = vec![
+            "disks",
+            "scsi",
+            "net",
+            "rng",
+            "fs",
+            "pmem",
+            "serial",
+            "console",
+            "vhost_user_net",
+            "vhost_user_blk",
+            "vsock",
+            "iommu",
+            "balloon",
+        ];
+        if cfg!(feature = "pci_support") {
+            devices.extend(&["nvme", "devices", "sriov_vfs"]);
+        }
+
+        // The guest is told about the vCPU and memory hotplug through ACPI.
+        // The volumes and interfaces are attached before the VM is booted.
+        let mut hotplug = Vec::new();
+        if cfg!(feature = "acpi") {
+            hotplug.extend(&["vcpus", "memory"]);
+        }
+        hotplug.push("disk_resize");
+
+        // The guest memory and the 32-bit devices share the physical
+        // address space.
+        let max_memory = (1u64 << memory_manager::get_host_cpu_phys_bits())
+            - arch::layout::MEM_32BIT_RESERVED_SIZE;
+
+        let max_pci_devices = if cfg!(feature = "pci_support") {
+            config::MAX_PCI_DEVICES as u32
+        } else {
+            0
+        };
+
+        Ok(VmmCapabilities {
+            version: self.version.clone(),
+            devices: devices.into_iter().map(String::from).collect(),
+            hotplug: hotplug.into_iter().map(String::from).collect(),
+            // There is no live migration yet.
+            snapshot: [
+                "snapshot",
+                "snapshot_clone",
+                "snapshot_cancel",
+                "snapshot_export",
+                "snapshot_import",
+                "restore",
+            ]
+            .iter()
+            .map(|s| String::from(*s))
+            .collect(),
+            max_vcpus: cmp::min(kvm.get_max_vcpus(), u8::MAX as usize) as u8,
+            max_memory,
+            max_pci_devices,
+        })
+    }
+
     fn vm_delete(&mut self) -> result::Result<(), VmError> {
         if self.vm_config.is_none() {
             return Ok(());
@@ -348,6 +814,15 @@ impl Vmm {
 
         self.vm_config = None;
 
+        // The volumes and interfaces go away with the VM they're attached
+        // to, but they can be attached to the next one.
+        for volume in self.volumes.values_mut() {
+            volume.attached = false;
+        }
+        for interface in self.interfaces.values_mut() {
+            interface.attached = false;
+        }
+
         Ok(())
     }
 
@@ -355,6 +830,27 @@ impl Vmm {
         self.vm_delete()
     }
 
+    fn vm_resize_disk(&mut self, path: &Path) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            let size = vm.resize_disk(path)?;
+            info!("Disk {:?} resized to {} bytes", path, size);
+            Ok(())
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_update_net(&mut self, update: &VmNetUpdate) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            return vm.update_net(update);
+        }
+
+        // Before the VM is booted, only its configuration is updated.
+        let vm_config = self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+        let mut vm_config = vm_config.lock().unwrap();
+        vm::update_net_config(&mut vm_config, update)
+    }
+
     fn vm_resize(
         &mut self,
         desired_vcpus: Option<u8>,
@@ -372,6 +868,561 @@ impl Vmm {
         }
     }
 
+    // Brings the VM to the desired configuration. The configuration of a VM
+    // which isn't booted yet is simply replaced, while a booted VM can only
+    // be resized, get the rate limiters of its interfaces updated, and its
+    // disks follow the size of their image.
+    fn vm_spec(&mut self, desired: &VmConfig) -> result::Result<(), ApiError> {
+        // The VM keeps its UUID unless the desired one sets another.
+        let mut desired = desired.clone();
+        if desired.uuid.is_none() {
+            desired.uuid = match &self.vm_config {
+                Some(vm_config) => vm_config.lock().unwrap().uuid,
+                None => Some(Uuid::new_v4()),
+            };
+        }
+
+        let vm_config = match (&self.vm_config, &self.vm) {
+            (None, _) => {
+                self.vm_config = Some(Arc::new(Mutex::new(desired)));
+                self.sync_attached_objects();
+                return Ok(());
+            }
+            (Some(vm_config), None) => {
+                *vm_config.lock().unwrap() = desired;
+                self.sync_attached_objects();
+                return Ok(());
+            }
+            (Some(vm_config), Some(_)) => vm_config.lock().unwrap().clone(),
+        };
+
+        let changes =
+            spec_changes(&vm_config, &desired).map_err(ApiError::VmSpecUnsupportedChanges)?;
+
+        for update in changes.net_updates.iter() {
+            info!(
+                "Updating interface {} to reach the VM spec: rx {:?}, tx {:?}",
+                update.id, update.rx_rate_limiter_config, update.tx_rate_limiter_config
+            );
+            self.vm_update_net(update).map_err(ApiError::VmUpdateNet)?;
+        }
+
+        // The images grown since the VM was booted, or since their last
+        // resize, are part of the desired state.
+        let disks = self
+            .vm
+            .as_ref()
+            .map(|vm| vm.resizable_disks())
+            .unwrap_or_default();
+        for path in disks.iter() {
+            self.vm_resize_disk(path).map_err(ApiError::VmResizeDisk)?;
+        }
+
+        if changes.desired_vcpus.is_none() && changes.desired_ram.is_none() {
+            return Ok(());
+        }
+
+        info!(
+            "Resizing VM to reach its spec: vCPUs {:?}, memory {:?}",
+            changes.desired_vcpus, changes.desired_ram
+        );
+        self.vm_resize(changes.desired_vcpus, changes.desired_ram)
+            .map_err(ApiError::VmResize)
+    }
+
+    // Keeps the configuration saved in the VM state directory, if any, in
+    // line with the effective one.
+    fn save_state_config(&mut self) {
+        let config = match &self.vm_config {
+            Some(config) => config.lock().unwrap().clone(),
+            None => return,
+        };
+        if self.saved_config.as_ref() == Some(&config) {
+            return;
+        }
+        if let Some(state_dir) = &config.state_dir {
+            match state_dir::save_config(state_dir, &config) {
+                Ok(()) => self.saved_config = Some(config),
+                Err(e) => error!("Failed saving the VM configuration: {:?}", e),
+            }
+        }
+    }
+
+    // Keeps the volumes and interfaces state in line with the VM config,
+    // once it has been replaced.
+    fn sync_attached_objects(&mut self) {
+        let vm_config = self.vm_config.as_ref().map(|c| c.lock().unwrap().clone());
+        let disks = vm_config
+            .as_ref()
+            .and_then(|c| c.disks.clone())
+            .unwrap_or_default();
+        let net = vm_config
+            .as_ref()
+            .and_then(|c| c.net.clone())
+            .unwrap_or_default();
+
+        for (id, volume) in self.volumes.iter_mut() {
+            volume.attached = disks.iter().any(|disk| disk.id.as_ref() == Some(id));
+        }
+        for (id, interface) in self.interfaces.iter_mut() {
+            interface.attached = net.iter().any(|net| net.id.as_ref() == Some(id));
+        }
+    }
+
+    fn vm_snapshot(
+        &mut self,
+        destination: &Path,
+        clone: bool,
+        exclude_free_pages: bool,
+    ) -> result::Result<OperationInfo, VmError> {
+        if let Some(ref mut vm) = self.vm {
+            // Relative destinations end up in the VM state directory.
+            let snapshots_dir = vm
+                .get_config()
+                .lock()
+                .unwrap()
+                .state_dir
+                .as_ref()
+                .map(|dir| dir.join(state_dir::SNAPSHOTS_DIR));
+            let destination = state_dir::resolve(snapshots_dir.as_deref(), destination);
+            let done_evt = self
+                .snapshot_evt
+                .try_clone()
+                .map_err(VmError::EventFdClone)?;
+            let operation = vm.snapshot(&destination, clone, exclude_free_pages, done_evt)?;
+            Ok(self.add_operation(operation))
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    // The VM is created from the configuration saved along with the
+    // snapshot, replacing the one of the VM not booted yet, if any.
+    fn vm_restore(&mut self, source: &Path) -> result::Result<(), ApiError> {
+        if self.vm.is_some() {
+            return Err(ApiError::VmAlreadyBooted);
+        }
+
+        let source = state_dir::resolve(self.snapshots_dir().as_deref(), source);
+        let config_file = fs::File::open(source.join(state_dir::CONFIG_FILE))
+            .map_err(|e| ApiError::VmRestore(VmError::RestoreFile(e)))?;
+        let config: VmConfig = serde_json::from_reader(config_file)
+            .map_err(|e| ApiError::VmRestore(VmError::RestoreState(e)))?;
+        let config = Arc::new(Mutex::new(config));
+
+        let mut vm = self.vm_new(config.clone()).map_err(ApiError::VmRestore)?;
+        if let Err(e) = vm.restore(&source) {
+            // The vCPUs may have been started already.
+            if let Err(e) = vm.shutdown() {
+                warn!("Failed to shut the VM being restored down: {:?}", e);
+            }
+            return Err(ApiError::VmRestore(e));
+        }
+        info!("VM restored from {:?}", source);
+
+        // The guest resumes from its hibernation only once, its disks moving
+        // on from there.
+        let state_dir = config.lock().unwrap().state_dir.clone();
+        if let Some(state_dir) = state_dir {
+            if state_dir::hibernated(&state_dir).as_deref() == Some(source.as_path()) {
+                if let Err(e) = fs::remove_dir_all(&source) {
+                    warn!("Failed to remove {:?}: {}", source, e);
+                }
+            }
+        }
+
+        self.vm_config = Some(config);
+        self.vm = Some(vm);
+        Ok(())
+    }
+
+    fn vm_new(&self, config: Arc<Mutex<VmConfig>>) -> result::Result<Vm, VmError> {
+        let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let panic_evt = self.panic_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let hibernate_evt = self
+            .hibernate_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
+        let disk_evt = self.disk_evt.try_clone().map_err(VmError::EventFdClone)?;
+
+        Vm::new(
+            config,
+            exit_evt,
+            reset_evt,
+            panic_evt,
+            hibernate_evt,
+            disk_evt,
+            self.timers.clone(),
+            self.vmm_path.clone(),
+        )
+    }
+
+    fn vm_clock(&self) -> result::Result<VmClockData, VmError> {
+        if let Some(ref vm) = self.vm {
+            Ok(VmClockData { clock: vm.clock()? })
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_counters(&self) -> result::Result<VmCounters, VmError> {
+        if let Some(ref vm) = self.vm {
+            Ok(vm.counters())
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_disk_checkpoint(&self, path: &Path) -> result::Result<DiskCheckpoint, VmError> {
+        if let Some(ref vm) = self.vm {
+            Ok(DiskCheckpoint {
+                checkpoint: vm.disk_checkpoint(path)?,
+            })
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_disk_changed_blocks(
+        &self,
+        path: &Path,
+        since: u64,
+    ) -> result::Result<DiskChangedBlocks, VmError> {
+        if let Some(ref vm) = self.vm {
+            let ranges = vm
+                .disk_changed_blocks(path, since)?
+                .into_iter()
+                .map(|(offset, length)| DiskRange { offset, length })
+                .collect();
+            Ok(DiskChangedBlocks {
+                block_size: vm_virtio::CHANGED_BLOCK_SIZE,
+                ranges,
+            })
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_set_clock(&self, clock: u64) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.set_clock(clock)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    // Cancelling the snapshot means stopping the memory writer and
+    // discarding what was written so far, the VM being resumed if it was
+    // running.
+    fn vm_snapshot_cancel(&mut self) -> result::Result<(), ApiError> {
+        if self.vm.is_none() {
+            return Err(ApiError::VmNotBooted);
+        }
+
+        let mut cancelled = false;
+        for operation in self
+            .operations
+            .values()
+            .filter(|operation| operation.kind() == "snapshot")
+        {
+            cancelled |= operation.cancel();
+        }
+
+        if cancelled {
+            Ok(())
+        } else {
+            Err(ApiError::VmSnapshotNotRunning)
+        }
+    }
+
+    // Relative snapshot and archive paths end up in the snapshots directory
+    // of the VM state directory, if any.
+    fn snapshots_dir(&self) -> Option<PathBuf> {
+        self.vm_config.as_ref().and_then(|config| {
+            config
+                .lock()
+                .unwrap()
+                .state_dir
+                .as_ref()
+                .map(|dir| dir.join(state_dir::SNAPSHOTS_DIR))
+        })
+    }
+
+    fn vm_snapshot_export(
+        &mut self,
+        config: &VmSnapshotExportConfig,
+    ) -> result::Result<OperationInfo, ApiError> {
+        let snapshots_dir = self.snapshots_dir();
+        let operation = snapshot_archive::export(
+            &state_dir::resolve(snapshots_dir.as_deref(), &config.snapshot),
+            &state_dir::resolve(snapshots_dir.as_deref(), &config.path),
+            config.disks,
+        )
+        .map_err(ApiError::VmSnapshotExport)?;
+
+        Ok(self.add_operation(operation))
+    }
+
+    fn vm_snapshot_import(
+        &mut self,
+        config: &VmSnapshotImportConfig,
+    ) -> result::Result<OperationInfo, ApiError> {
+        let snapshots_dir = self.snapshots_dir();
+        let operation = snapshot_archive::import(
+            &state_dir::resolve(snapshots_dir.as_deref(), &config.path),
+            &state_dir::resolve(snapshots_dir.as_deref(), &config.destination),
+        )
+        .map_err(ApiError::VmSnapshotImport)?;
+
+        Ok(self.add_operation(operation))
+    }
+
+    // Keep track of a long running operation, so that it can be followed
+    // and cancelled through its id.
+    fn add_operation(&mut self, operation: Arc<Operation>) -> OperationInfo {
+        let id = self.next_operation_id;
+        self.next_operation_id += 1;
+
+        self.evict_operations();
+        let info = operation.info(id);
+        self.operations.insert(id, operation);
+
+        info
+    }
+
+    // Forget about the operations that finished too long ago for anybody
+    // to still be following them.
+    fn evict_operations(&mut self) {
+        self.operations
+            .retain(|_, operation| !operation.expired(OPERATION_TTL));
+    }
+
+    // Once its outcome has been reported, a finished operation is forgotten.
+    fn operation_info(&mut self, id: u64) -> result::Result<OperationInfo, ApiError> {
+        self.evict_operations();
+        let info = self
+            .operations
+            .get(&id)
+            .map(|operation| operation.info(id))
+            .ok_or(ApiError::OperationNotFound(id))?;
+
+        if info.phase != OperationPhase::Running {
+            self.operations.remove(&id);
+        }
+
+        Ok(info)
+    }
+
+    fn operation_cancel(&self, id: u64) -> result::Result<(), ApiError> {
+        let operation = self
+            .operations
+            .get(&id)
+            .ok_or(ApiError::OperationNotFound(id))?;
+
+        if !operation.cancel() {
+            return Err(ApiError::OperationNotRunning(id));
+        }
+
+        Ok(())
+    }
+
+    // Volumes and interfaces can only be attached to or detached from a VM
+    // that is created but not booted, since disks and network devices can't
+    // be hot plugged.
+    fn vm_config_for_attach(&self) -> result::Result<Arc<Mutex<VmConfig>>, ApiError> {
+        if self.vm.is_some() {
+            return Err(ApiError::VmAlreadyBooted);
+        }
+
+        self.vm_config.clone().ok_or(ApiError::VmNotCreated)
+    }
+
+    fn volume_create(&mut self, config: &VolumeConfig) -> result::Result<(), ApiError> {
+        if self.volumes.contains_key(&config.id) {
+            return Err(ApiError::ObjectAlreadyExists(config.id.clone()));
+        }
+
+        self.volumes.insert(
+            config.id.clone(),
+            Volume {
+                disk: config.disk.clone(),
+                attached: false,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn volume_delete(&mut self, id: &str) -> result::Result<(), ApiError> {
+        match self.volumes.get(id) {
+            None => Err(ApiError::ObjectNotFound(id.to_string())),
+            Some(volume) if volume.attached => Err(ApiError::ObjectAttached(id.to_string())),
+            Some(_) => {
+                self.volumes.remove(id);
+                Ok(())
+            }
+        }
+    }
+
+    fn volumes(&self) -> Vec<VolumeInfo> {
+        self.volumes
+            .iter()
+            .map(|(id, volume)| VolumeInfo {
+                id: id.clone(),
+                disk: volume.disk.clone(),
+                attached: volume.attached,
+            })
+            .collect()
+    }
+
+    fn vm_attach_volume(&mut self, id: &str) -> result::Result<(), ApiError> {
+        let vm_config = self.vm_config_for_attach()?;
+        let volume = self
+            .volumes
+            .get_mut(id)
+            .ok_or_else(|| ApiError::ObjectNotFound(id.to_string()))?;
+        if volume.attached {
+            return Err(ApiError::ObjectAttached(id.to_string()));
+        }
+
+        // The disk is given the volume id, through which it's detached.
+        let mut disk = volume.disk.clone();
+        disk.id = Some(id.to_string());
+
+        let mut vm_config = vm_config.lock().unwrap();
+        let mut new_vm_config = vm_config.clone();
+        if disk.iommu {
+            new_vm_config.iommu = true;
+        }
+        new_vm_config.disks.get_or_insert_with(Vec::new).push(disk);
+        new_vm_config
+            .validate_device_ids()
+            .map_err(|_| ApiError::ObjectAlreadyExists(id.to_string()))?;
+        new_vm_config
+            .validate_pci_device_count()
+            .map_err(ApiError::TooManyPciDevices)?;
+        *vm_config = new_vm_config;
+        volume.attached = true;
+
+        Ok(())
+    }
+
+    fn vm_detach_volume(&mut self, id: &str) -> result::Result<(), ApiError> {
+        let vm_config = self.vm_config_for_attach()?;
+        let volume = self
+            .volumes
+            .get_mut(id)
+            .ok_or_else(|| ApiError::ObjectNotFound(id.to_string()))?;
+        if !volume.attached {
+            return Err(ApiError::ObjectNotAttached(id.to_string()));
+        }
+
+        let mut vm_config = vm_config.lock().unwrap();
+        if let Some(disks) = vm_config.disks.as_mut() {
+            disks.retain(|disk| disk.id.as_deref() != Some(id));
+            if disks.is_empty() {
+                vm_config.disks = None;
+            }
+        }
+        volume.attached = false;
+
+        Ok(())
+    }
+
+    fn interface_create(&mut self, config: &InterfaceConfig) -> result::Result<(), ApiError> {
+        if self.interfaces.contains_key(&config.id) {
+            return Err(ApiError::ObjectAlreadyExists(config.id.clone()));
+        }
+
+        self.interfaces.insert(
+            config.id.clone(),
+            Interface {
+                net: config.net.clone(),
+                attached: false,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn interface_delete(&mut self, id: &str) -> result::Result<(), ApiError> {
+        match self.interfaces.get(id) {
+            None => Err(ApiError::ObjectNotFound(id.to_string())),
+            Some(interface) if interface.attached => Err(ApiError::ObjectAttached(id.to_string())),
+            Some(_) => {
+                self.interfaces.remove(id);
+                Ok(())
+            }
+        }
+    }
+
+    fn interfaces(&self) -> Vec<InterfaceInfo> {
+        self.interfaces
+            .iter()
+            .map(|(id, interface)| InterfaceInfo {
+                id: id.clone(),
+                net: interface.net.clone(),
+                attached: interface.attached,
+            })
+            .collect()
+    }
+
+    fn vm_attach_interface(&mut self, id: &str) -> result::Result<(), ApiError> {
+        let vm_config = self.vm_config_for_attach()?;
+        let interface = self
+            .interfaces
+            .get_mut(id)
+            .ok_or_else(|| ApiError::ObjectNotFound(id.to_string()))?;
+        if interface.attached {
+            return Err(ApiError::ObjectAttached(id.to_string()));
+        }
+
+        // The network device is given the interface id, through which it's
+        // detached.
+        let mut net = interface.net.clone();
+        net.id = Some(id.to_string());
+
+        let mut vm_config = vm_config.lock().unwrap();
+        let mut new_vm_config = vm_config.clone();
+        if net.iommu {
+            new_vm_config.iommu = true;
+        }
+        new_vm_config.net.get_or_insert_with(Vec::new).push(net);
+        new_vm_config
+            .validate_device_ids()
+            .map_err(|_| ApiError::ObjectAlreadyExists(id.to_string()))?;
+        new_vm_config
+            .validate_pci_device_count()
+            .map_err(ApiError::TooManyPciDevices)?;
+        *vm_config = new_vm_config;
+        interface.attached = true;
+
+        Ok(())
+    }
+
+    fn vm_detach_interface(&mut self, id: &str) -> result::Result<(), ApiError> {
+        let vm_config = self.vm_config_for_attach()?;
+        let interface = self
+            .interfaces
+            .get_mut(id)
+            .ok_or_else(|| ApiError::ObjectNotFound(id.to_string()))?;
+        if !interface.attached {
+            return Err(ApiError::ObjectNotAttached(id.to_string()));
+        }
+
+        let mut vm_config = vm_config.lock().unwrap();
+        if let Some(net) = vm_config.net.as_mut() {
+            net.retain(|net| net.id.as_deref() != Some(id));
+            if net.is_empty() {
+                vm_config.net = None;
+            }
+        }
+        interface.attached = false;
+
+        Ok(())
+    }
+
     fn control_loop(&mut self, api_receiver: Arc<Receiver<ApiRequest>>) -> Result<()> {
         const EPOLL_EVENTS_LEN: usize = 100;
 
@@ -413,10 +1464,33 @@ impl Vmm {
                             self.reset_evt.read().map_err(Error::EventFdRead)?;
                             self.vm_reboot().map_err(Error::VmReboot)?;
                         }
+                        EpollDispatch::Panic => {
+                            // Consume the event.
+                            self.panic_evt.read().map_err(Error::EventFdRead)?;
+                            self.vm_panic()?;
+                        }
+                        EpollDispatch::Hibernate => {
+                            // Consume the event.
+                            self.hibernate_evt.read().map_err(Error::EventFdRead)?;
+                            self.vm_hibernate()?;
+
+                            break 'outer;
+                        }
+                        EpollDispatch::DiskError => {
+                            // Consume the event.
+                            self.disk_evt.read().map_err(Error::EventFdRead)?;
+                            self.vm_disk_error()?;
+                        }
+                        EpollDispatch::Snapshot => {
+                            // Consume the event.
+                            self.snapshot_evt.read().map_err(Error::EventFdRead)?;
+                            self.vm_snapshot_written().map_err(Error::VmSnapshot)?;
+                        }
                         EpollDispatch::Stdin => {
-                            if let Some(ref vm) = self.vm {
-                                vm.handle_stdin().map_err(Error::Stdin)?;
-                            }
+                            self.handle_stdin()?;
+                        }
+                        EpollDispatch::Timer => {
+                            self.timers.process().map_err(Error::Timers)?;
                         }
                         EpollDispatch::Api => {
                             // Consume the event.
@@ -430,6 +1504,13 @@ impl Vmm {
                                     // We only store the passed VM config.
                                     // The VM will be created when being asked to boot it.
                                     let response = if self.vm_config.is_none() {
+                                        // Every VM gets a UUID, which remains the same
+                                        // for as long as the VM is around.
+                                        config
+                                            .lock()
+                                            .unwrap()
+                                            .uuid
+                                            .get_or_insert_with(Uuid::new_v4);
                                         self.vm_config = Some(config);
                                         Ok(ApiResponsePayload::Empty)
                                     } else {
@@ -491,6 +1572,13 @@ impl Vmm {
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmmCapabilities(sender) => {
+                                    let response = self
+                                        .vmm_capabilities()
+                                        .map(ApiResponsePayload::VmmCapabilities);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmPause(sender) => {
                                     let response = self
                                         .vm_pause()
@@ -527,7 +1615,166 @@ impl Vmm {
                                         .map(|_| ApiResponsePayload::Empty);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmResizeDisk(resize_data, sender) => {
+                                    let response = self
+                                        .vm_resize_disk(&resize_data.path)
+                                        .map_err(ApiError::VmResizeDisk)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmUpdateNet(update, sender) => {
+                                    let response = self
+                                        .vm_update_net(&update)
+                                        .map_err(ApiError::VmUpdateNet)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSpec(config, sender) => {
+                                    let response =
+                                        self.vm_spec(&config).map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSnapshot(snapshot_data, sender) => {
+                                    let response = self
+                                        .vm_snapshot(
+                                            &snapshot_data.destination,
+                                            snapshot_data.clone,
+                                            snapshot_data.exclude_free_pages,
+                                        )
+                                        .map_err(ApiError::VmSnapshot)
+                                        .map(ApiResponsePayload::Operation);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSnapshotCancel(sender) => {
+                                    let response = self
+                                        .vm_snapshot_cancel()
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmRestore(restore_data, sender) => {
+                                    let response = self
+                                        .vm_restore(&restore_data.source)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSnapshotExport(export_data, sender) => {
+                                    let response = self
+                                        .vm_snapshot_export(&export_data)
+                                        .map(ApiResponsePayload::Operation);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSnapshotImport(import_data, sender) => {
+                                    let response = self
+                                        .vm_snapshot_import(&import_data)
+                                        .map(ApiResponsePayload::Operation);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmClock(sender) => {
+                                    let response = self
+                                        .vm_clock()
+                                        .map_err(ApiError::VmClock)
+                                        .map(ApiResponsePayload::VmClock);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmCounters(sender) => {
+                                    let response = self
+                                        .vm_counters()
+                                        .map_err(ApiError::VmCounters)
+                                        .map(ApiResponsePayload::VmCounters);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmDiskCheckpoint(data, sender) => {
+                                    let response = self
+                                        .vm_disk_checkpoint(&data.id)
+                                        .map_err(ApiError::VmDiskCheckpoint)
+                                        .map(ApiResponsePayload::DiskCheckpoint);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmDiskChangedBlocks(data, sender) => {
+                                    let response = self
+                                        .vm_disk_changed_blocks(&data.id, data.since)
+                                        .map_err(ApiError::VmDiskChangedBlocks)
+                                        .map(ApiResponsePayload::DiskChangedBlocks);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSetClock(clock_data, sender) => {
+                                    let response = self
+                                        .vm_set_clock(clock_data.clock)
+                                        .map_err(ApiError::VmClock)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::Operation(id, sender) => {
+                                    let response =
+                                        self.operation_info(id).map(ApiResponsePayload::Operation);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::OperationCancel(id, sender) => {
+                                    let response = self
+                                        .operation_cancel(id)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VolumeCreate(config, sender) => {
+                                    let response = self
+                                        .volume_create(&config)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VolumeDelete(object, sender) => {
+                                    let response = self
+                                        .volume_delete(&object.id)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::Volumes(sender) => {
+                                    let response = Ok(ApiResponsePayload::Volumes(self.volumes()));
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmAttachVolume(object, sender) => {
+                                    let response = self
+                                        .vm_attach_volume(&object.id)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmDetachVolume(object, sender) => {
+                                    let response = self
+                                        .vm_detach_volume(&object.id)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::InterfaceCreate(config, sender) => {
+                                    let response = self
+                                        .interface_create(&config)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::InterfaceDelete(object, sender) => {
+                                    let response = self
+                                        .interface_delete(&object.id)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::Interfaces(sender) => {
+                                    let response =
+                                        Ok(ApiResponsePayload::Interfaces(self.interfaces()));
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmAttachInterface(object, sender) => {
+                                    let response = self
+                                        .vm_attach_interface(&object.id)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmDetachInterface(object, sender) => {
+                                    let response = self
+                                        .vm_detach_interface(&object.id)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                             }
+
+                            self.save_state_config();
                         }
                     }
                 }
@@ -537,3 +1784,143 @@ impl Vmm {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::VmParams;
+
+    fn vm_config(cpus: &str, memory: &str, net: Option<Vec<&str>>) -> VmConfig {
+        VmConfig::parse(VmParams {
+            cpus,
+            memory,
+            kernel: None,
+            cmdline: None,
+            disks: None,
+            scsi: None,
+            net,
+            rng: "src=/dev/urandom",
+            fs: None,
+            pmem: None,
+            memory_zones: None,
+            sgx_epc: None,
+            serial: None,
+            console: None,
+            console_profile: None,
+            devices: None,
+            sriov_vfs: None,
+            vhost_user_net: None,
+            vhost_user_blk: None,
+            vsock: None,
+            vdpa: None,
+            console_ports: None,
+            cgroup: None,
+            platform: None,
+            balloon: None,
+            on_panic: None,
+            unknown_access: None,
+            uuid: None,
+            state_dir: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_spec_resize() {
+        let current = vm_config("boot=2,max=8", "size=1G", None);
+
+        let changes = spec_changes(&current, &current).unwrap();
+        assert_eq!(changes.desired_vcpus, None);
+        assert_eq!(changes.desired_ram, None);
+        assert!(changes.net_updates.is_empty());
+
+        let desired = vm_config("boot=4,max=8", "size=2G", None);
+        let changes = spec_changes(&current, &desired).unwrap();
+        assert_eq!(changes.desired_vcpus, Some(4));
+        assert_eq!(changes.desired_ram, Some(2 << 30));
+        assert!(changes.net_updates.is_empty());
+    }
+
+    #[test]
+    fn test_spec_net_update() {
+        let current = vm_config(
+            "boot=1",
+            "size=512M",
+            Some(vec!["tap=tap0,id=net0", "tap=tap1,id=net1"]),
+        );
+        let desired = vm_config(
+            "boot=1",
+            "size=512M",
+            Some(vec![
+                "tap=tap0,id=net0",
+                "tap=tap1,id=net1,tx_bw_size=1000000,tx_bw_refill_time=100",
+            ]),
+        );
+
+        let changes = spec_changes(&current, &desired).unwrap();
+        assert_eq!(changes.desired_vcpus, None);
+        assert_eq!(changes.desired_ram, None);
+        assert_eq!(changes.net_updates.len(), 1);
+        let update = &changes.net_updates[0];
+        assert_eq!(update.id, "net1");
+        assert_eq!(update.rx_rate_limiter_config, None);
+        assert_eq!(
+            update.tx_rate_limiter_config,
+            desired.net.as_ref().unwrap()[1].tx_rate_limiter_config
+        );
+        assert!(update.tx_rate_limiter_config.is_some());
+
+        // Lifting the limit again.
+        let changes = spec_changes(&desired, &current).unwrap();
+        assert_eq!(changes.net_updates.len(), 1);
+        assert_eq!(changes.net_updates[0].id, "net1");
+        assert_eq!(changes.net_updates[0].tx_rate_limiter_config, None);
+    }
+
+    #[test]
+    fn test_spec_unsupported_changes() {
+        let current = vm_config("boot=1", "size=512M", Some(vec!["tap=tap0,id=net0"]));
+
+        // Any other change requires a reboot.
+        let desired = vm_config("boot=1,max=2", "size=512M", Some(vec!["tap=tap0,id=net0"]));
+        assert_eq!(
+            spec_changes(&current, &desired).err(),
+            Some(vec![String::from("cpus")])
+        );
+
+        // As does adding an interface, or changing the rate limiters of an
+        // interface along with anything else about it.
+        let desired = vm_config(
+            "boot=1",
+            "size=512M",
+            Some(vec!["tap=tap0,id=net0", "tap=tap1,id=net1"]),
+        );
+        assert_eq!(
+            spec_changes(&current, &desired).err(),
+            Some(vec![String::from("net")])
+        );
+        let desired = vm_config(
+            "boot=1",
+            "size=512M",
+            Some(vec![
+                "tap=tap1,id=net0,rx_bw_size=1000000,rx_bw_refill_time=100",
+            ]),
+        );
+        assert_eq!(
+            spec_changes(&current, &desired).err(),
+            Some(vec![String::from("net")])
+        );
+
+        // Neither can the interfaces without an id be told apart.
+        let current = vm_config("boot=1", "size=512M", Some(vec!["tap=tap0"]));
+        let desired = vm_config(
+            "boot=1",
+            "size=512M",
+            Some(vec!["tap=tap0,rx_bw_size=1000000,rx_bw_refill_time=100"]),
+        );
+        assert_eq!(
+            spec_changes(&current, &desired).err(),
+            Some(vec![String::from("net")])
+        );
+    }
+}
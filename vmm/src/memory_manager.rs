@@ -3,17 +3,21 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use crate::config::{HotplugMethod, MemoryZoneConfig, SgxEpcConfig};
 #[cfg(feature = "acpi")]
 use acpi_tables::{aml, aml::Aml};
 use arch::RegionType;
 use devices::BusDevice;
-use kvm_bindings::kvm_userspace_memory_region;
+use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES};
 use kvm_ioctls::*;
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::os::unix::io::FromRawFd;
-use std::path::PathBuf;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use vm_allocator::SystemAllocator;
 use vm_memory::guest_memory::FileOffset;
@@ -25,6 +29,15 @@ use vm_memory::{
 
 const HOTPLUG_COUNT: usize = 8;
 
+// Memory policy flags for mbind(2)
+const MPOL_BIND: libc::c_ulong = 2;
+const MPOL_MF_STRICT: libc::c_uint = 1;
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+
+// Device providing the SGX enclave page cache to the guests.
+const SGX_VIRT_EPC_PATH: &str = "/dev/sgx_vepc";
+const SGX_EPC_PAGE_SIZE: u64 = 4096;
+
 #[derive(Default)]
 struct HotPlugState {
     base: u64,
@@ -47,6 +60,23 @@ pub struct MemoryManager {
     allocator: Arc<Mutex<SystemAllocator>>,
     current_ram: u64,
     next_hotplug_slot: usize,
+    ram_regions: Vec<kvm_userspace_memory_region>,
+    dirty_log: bool,
+    // Union of the dirty pages bitmaps read since the last working set
+    // sample, per memory slot, when the working set is being estimated.
+    working_set: Option<BTreeMap<u32, Vec<u64>>>,
+    mlock: bool,
+    prefault: bool,
+    sgx_epc_sections: Vec<SgxEpcSection>,
+    virtio_mem_region: Option<(GuestAddress, u64)>,
+}
+
+/// A range of guest physical addresses backed by SGX enclave page cache.
+#[derive(Clone, Copy, Debug)]
+pub struct SgxEpcSection {
+    pub start: GuestAddress,
+    pub size: GuestUsize,
+    host_addr: u64,
 }
 
 #[derive(Debug)]
@@ -80,6 +110,345 @@ pub enum Error {
 
     /// Failed to set the user memory region.
     SetUserMemoryRegion(kvm_ioctls::Error),
+
+    /// Failed to bind a memory zone to a host NUMA node.
+    ApplyNumaPolicy(io::Error),
+
+    /// The memory zones exceed the boot RAM size.
+    InvalidMemoryZones,
+
+    /// Failed to create the memory snapshot file.
+    SnapshotFileCreate(io::Error),
+
+    /// Failed to write the guest memory to the snapshot file.
+    SnapshotWrite(io::Error),
+
+    /// Failed to save the memory ranges description.
+    SnapshotRanges(serde_json::Error),
+
+    /// Failed to fork the memory snapshot writer process.
+    SnapshotFork(io::Error),
+
+    /// The memory snapshot writer process failed.
+    SnapshotWriterFailed(i32),
+
+    /// Failed to read the guest memory from the snapshot.
+    RestoreRead(io::Error),
+
+    /// The memory snapshot doesn't match the guest memory layout.
+    RestoreLayout(u64),
+
+    /// Failed to retrieve the dirty pages log.
+    DirtyLog(kvm_ioctls::Error),
+
+    /// The memory slot doesn't hold guest RAM.
+    UnknownRamSlot(u32),
+
+    /// Failed to lock the guest RAM into host memory.
+    Mlock(io::Error),
+
+    /// The SGX EPC section size is not a multiple of the EPC page size.
+    InvalidSgxEpcSize(u64),
+
+    /// Failed to open the SGX virtual EPC device.
+    SgxVirtEpcOpen(io::Error),
+
+    /// Failed to map the SGX virtual EPC device.
+    SgxVirtEpcMap(io::Error),
+}
+
+/// Description of a guest RAM range saved in the memory snapshot file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MemoryRangeSnapshot {
+    pub gpa: u64,
+    pub length: u64,
+    pub file_offset: u64,
+}
+
+/// Size of the pages tracked by the KVM dirty pages log.
+pub const DIRTY_LOG_PAGE_SIZE: u64 = 4096;
+
+/// Pages written by the guest in a guest RAM memory slot, one bit per page.
+pub struct DirtyBitmap {
+    pub slot: u32,
+    pub gpa: u64,
+    pub length: u64,
+    pub bitmap: Vec<u64>,
+}
+
+impl DirtyBitmap {
+    /// Number of dirty pages.
+    pub fn dirty_pages(&self) -> u64 {
+        self.bitmap.iter().map(|b| u64::from(b.count_ones())).sum()
+    }
+
+    /// Guest physical addresses of the dirty pages.
+    pub fn dirty_addresses(&self) -> impl Iterator<Item = u64> + '_ {
+        let gpa = self.gpa;
+        self.bitmap
+            .iter()
+            .enumerate()
+            .flat_map(move |(index, bits)| {
+                let bits = *bits;
+                (0..64)
+                    .filter(move |bit| bits & (1 << bit) != 0)
+                    .map(move |bit| gpa + (index as u64 * 64 + bit) * DIRTY_LOG_PAGE_SIZE)
+            })
+    }
+}
+
+// Maximum amount of guest memory written at once when taking a snapshot, so
+// that the progress can be followed.
+const SNAPSHOT_WRITE_CHUNK: u64 = 64 << 20;
+
+// Interval at which the progress of the memory snapshot writer process is
+// reported, and the cancellation checked.
+const SNAPSHOT_PROGRESS_PERIOD_MS: u64 = 100;
+
+// Byte counter living in a shared anonymous mapping, so that the updates
+// made by the snapshot writer process are visible from the VMM.
+struct SharedCounter {
+    counter: *mut AtomicU64,
+}
+
+// Safe because the counter is only accessed atomically.
+unsafe impl Send for SharedCounter {}
+unsafe impl Sync for SharedCounter {}
+
+impl SharedCounter {
+    fn new() -> Result<Self, io::Error> {
+        // Safe because we check the return value.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                std::mem::size_of::<AtomicU64>(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The mapping is zero filled, which is a valid AtomicU64 set to 0.
+        Ok(SharedCounter {
+            counter: addr as *mut AtomicU64,
+        })
+    }
+
+    fn add(&self, value: u64) {
+        // Safe because the mapping is valid for the lifetime of self.
+        unsafe { (*self.counter).fetch_add(value, Ordering::SeqCst) };
+    }
+
+    fn get(&self) -> u64 {
+        // Safe because the mapping is valid for the lifetime of self.
+        unsafe { (*self.counter).load(Ordering::SeqCst) }
+    }
+}
+
+impl Drop for SharedCounter {
+    fn drop(&mut self) {
+        // Safe because we're unmapping the region we mapped.
+        unsafe {
+            libc::munmap(
+                self.counter as *mut libc::c_void,
+                std::mem::size_of::<AtomicU64>(),
+            )
+        };
+    }
+}
+
+/// Handle on the child process writing a copy-on-write view of the guest
+/// memory to the snapshot file in the background, while the guest runs.
+/// There is no such process when the whole guest memory had to be written
+/// before resuming the VM.
+pub struct MemorySnapshotWriter {
+    pid: Option<libc::pid_t>,
+    bytes_total: u64,
+    bytes_written: SharedCounter,
+}
+
+impl MemorySnapshotWriter {
+    fn exit_status(status: libc::c_int) -> Result<(), Error> {
+        if libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0 {
+            Ok(())
+        } else {
+            Err(Error::SnapshotWriterFailed(status))
+        }
+    }
+
+    // Check whether the child is done writing, without blocking.
+    fn try_wait(&mut self) -> Result<bool, Error> {
+        let pid = match self.pid {
+            Some(pid) => pid,
+            None => return Ok(true),
+        };
+
+        let mut status: libc::c_int = 0;
+        // Safe because we only wait on the child we forked.
+        let ret = unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(Error::SnapshotWrite(err));
+        }
+        if ret == 0 {
+            return Ok(false);
+        }
+
+        self.pid = None;
+        MemorySnapshotWriter::exit_status(status).map(|_| true)
+    }
+
+    // Stop the child, leaving the snapshot incomplete.
+    fn kill(&mut self) {
+        if let Some(pid) = self.pid.take() {
+            // Safe because we only kill and reap the child we forked.
+            unsafe {
+                libc::kill(pid, libc::SIGKILL);
+                libc::waitpid(pid, std::ptr::null_mut(), 0);
+            }
+        }
+    }
+
+    // Fork the child writing the host memory `ranges`, as host address,
+    // length and file offset, to `file`, as they are at the time of the fork.
+    fn fork(
+        file: &File,
+        ranges: &[(u64, u64, u64)],
+        bytes_total: u64,
+        bytes_written: SharedCounter,
+    ) -> Result<Self, Error> {
+        // Safe because the child only performs async-signal-safe calls on
+        // memory which has been set up before the fork, then exits.
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(Error::SnapshotFork(io::Error::last_os_error()));
+        }
+        if pid == 0 {
+            let fd = file.as_raw_fd();
+            let mut success = true;
+            for &(host_addr, length, file_offset) in ranges.iter() {
+                if !write_host_range(fd, host_addr, length, file_offset, &bytes_written) {
+                    success = false;
+                    break;
+                }
+            }
+            // Safe because fd is a valid file descriptor, and _exit() never
+            // returns.
+            unsafe {
+                if success && libc::fsync(fd) == 0 {
+                    libc::_exit(0);
+                }
+                libc::_exit(1);
+            }
+        }
+
+        Ok(MemorySnapshotWriter {
+            pid: Some(pid),
+            bytes_total,
+            bytes_written,
+        })
+    }
+
+    /// Size of the guest memory being saved.
+    pub fn bytes_total(&self) -> u64 {
+        self.bytes_total
+    }
+
+    /// Wait for the whole guest memory to be written out, calling
+    /// `progress` with the amount written so far along the way. Returns
+    /// false, the snapshot being left incomplete, if `progress` returns
+    /// false.
+    pub fn write<F>(mut self, mut progress: F) -> Result<bool, Error>
+    where
+        F: FnMut(u64) -> bool,
+    {
+        loop {
+            let done = self.try_wait()?;
+            let keep_going = progress(self.bytes_written.get());
+            if done {
+                return Ok(true);
+            }
+            if !keep_going {
+                self.kill();
+                return Ok(false);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(
+                SNAPSHOT_PROGRESS_PERIOD_MS,
+            ));
+        }
+    }
+}
+
+impl Drop for MemorySnapshotWriter {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+// Writes a whole host memory range at the given offset of the file. This is
+// called from the forked child, hence it must only rely on async-signal-safe
+// functions and must not allocate.
+fn write_host_range(
+    fd: libc::c_int,
+    host_addr: u64,
+    length: u64,
+    file_offset: u64,
+    written: &SharedCounter,
+) -> bool {
+    let mut done: u64 = 0;
+    while done < length {
+        let count = std::cmp::min(length - done, SNAPSHOT_WRITE_CHUNK);
+        // Safe because the range is part of the guest memory mappings.
+        let ret = unsafe {
+            libc::pwrite(
+                fd,
+                (host_addr + done) as *const libc::c_void,
+                count as libc::size_t,
+                (file_offset + done) as libc::off_t,
+            )
+        };
+        if ret < 0 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return false;
+        }
+        done += ret as u64;
+        written.add(ret as u64);
+    }
+    true
+}
+
+// The parts of the guest memory range at `gpa`, as offset in the range and
+// length, which aren't in the sorted `excluded` ranges.
+fn included_ranges(gpa: u64, length: u64, excluded: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut ranges = Vec::new();
+    let end = gpa + length;
+    let mut start = gpa;
+    for &(excluded_start, excluded_length) in excluded {
+        let excluded_end = excluded_start + excluded_length;
+        if excluded_end <= start {
+            continue;
+        }
+        if excluded_start >= end {
+            break;
+        }
+        if excluded_start > start {
+            ranges.push((start - gpa, excluded_start - start));
+        }
+        start = excluded_end;
+    }
+    if start < end {
+        ranges.push((start - gpa, end - start));
+    }
+    ranges
 }
 
 pub fn get_host_cpu_phys_bits() -> u8 {
@@ -191,13 +560,18 @@ impl BusDevice for MemoryManager {
 }
 
 impl MemoryManager {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         allocator: Arc<Mutex<SystemAllocator>>,
         fd: Arc<VmFd>,
         boot_ram: u64,
         hotplug_size: Option<u64>,
+        hotplug_method: HotplugMethod,
         backing_file: &Option<PathBuf>,
         mergeable: bool,
+        mlock: bool,
+        prefault: bool,
+        zones: &Option<Vec<MemoryZoneConfig>>,
     ) -> Result<Arc<Mutex<MemoryManager>>, Error> {
         // Init guest memory
         let arch_mem_regions = arch::arch_memory_regions(boot_ram);
@@ -208,16 +582,24 @@ impl MemoryManager {
             .map(|r| (r.0, r.1))
             .collect();
 
-        let mut mem_regions = Vec::new();
-        for region in ram_regions.iter() {
-            mem_regions.push(MemoryManager::create_ram_region(
-                backing_file,
-                region.0,
-                region.1,
-            )?);
-        }
+        let mem_regions = if let Some(zones) = zones {
+            MemoryManager::create_zone_regions(&ram_regions, zones, backing_file)?
+        } else {
+            let mut mem_regions = Vec::new();
+            for region in ram_regions.iter() {
+                mem_regions.push(MemoryManager::create_ram_region(
+                    backing_file,
+                    0,
+                    region.0,
+                    region.1,
+                    false,
+                    None,
+                )?);
+            }
+            mem_regions
+        };
 
-        let guest_memory =
+        let mut guest_memory =
             GuestMemoryMmap::from_arc_regions(mem_regions).map_err(Error::GuestMemory)?;
 
         let end_of_device_area = GuestAddress((1 << get_host_cpu_phys_bits()) - 1);
@@ -228,8 +610,31 @@ impl MemoryManager {
             mem_end.unchecked_add(1)
         };
 
-        if let Some(size) = hotplug_size {
-            start_of_device_area = start_of_device_area.unchecked_add(size);
+        let mut virtio_mem_region = None;
+        match hotplug_size {
+            Some(size) if hotplug_method == HotplugMethod::VirtioMem => {
+                // The region is kept apart from the boot RAM, for the guest
+                // not to take it as such, and aligned on the guest memory
+                // sections.
+                let start = GuestAddress(
+                    (start_of_device_area.raw_value() + (256 << 20)) & !((128 << 20) - 1),
+                );
+                let region = MemoryManager::create_ram_region(
+                    backing_file,
+                    0,
+                    start,
+                    size as usize,
+                    false,
+                    None,
+                )?;
+                guest_memory = guest_memory
+                    .insert_region(region)
+                    .map_err(Error::GuestMemory)?;
+                virtio_mem_region = Some((start, size));
+                start_of_device_area = start.unchecked_add(size);
+            }
+            Some(size) => start_of_device_area = start_of_device_area.unchecked_add(size),
+            None => {}
         }
 
         let guest_memory = GuestMemoryAtomic::new(guest_memory);
@@ -250,16 +655,25 @@ impl MemoryManager {
             allocator: allocator.clone(),
             current_ram: boot_ram,
             next_hotplug_slot: 0,
+            ram_regions: Vec::new(),
+            dirty_log: false,
+            working_set: None,
+            mlock,
+            prefault,
+            sgx_epc_sections: Vec::new(),
+            virtio_mem_region,
         }));
 
         guest_memory.memory().with_regions(|_, region| {
-            let _ = memory_manager.lock().unwrap().create_userspace_mapping(
+            // The virtio-mem region is only populated as the guest plugs
+            // and uses its blocks.
+            let populate = virtio_mem_region.map(|r| r.0) != Some(region.start_addr());
+            memory_manager.lock().unwrap().create_ram_mapping(
                 region.start_addr().raw_value(),
                 region.len() as u64,
                 region.as_ptr() as u64,
-                mergeable,
-            )?;
-            Ok(())
+                populate,
+            )
         })?;
 
         // Allocate RAM and Reserved address ranges.
@@ -270,48 +684,200 @@ impl MemoryManager {
                 .allocate_mmio_addresses(Some(region.0), region.1 as GuestUsize, None)
                 .ok_or(Error::MemoryRangeAllocation)?;
         }
+        if let Some((start, size)) = virtio_mem_region {
+            allocator
+                .lock()
+                .unwrap()
+                .allocate_mmio_addresses(Some(start), size, None)
+                .ok_or(Error::MemoryRangeAllocation)?;
+        }
 
         Ok(memory_manager)
     }
 
+    // Fill the RAM regions from the architecture layout with the memory
+    // zones, in order. A zone crossing the boundary between two RAM regions
+    // ends up being split into two guest regions, the second one mapping
+    // the backing file from where the first one stopped. The RAM beyond the
+    // zones, which got hotplugged before a reboot, is backed as the memory
+    // is without zones.
+    fn create_zone_regions(
+        ram_regions: &[(GuestAddress, usize)],
+        zones: &[MemoryZoneConfig],
+        backing_file: &Option<PathBuf>,
+    ) -> Result<Vec<Arc<GuestRegionMmap>>, Error> {
+        let mut mem_regions = Vec::new();
+        for (zone, file_offset, start_addr, size) in MemoryManager::split_zones(ram_regions, zones)?
+        {
+            mem_regions.push(match zone {
+                Some(zone) => MemoryManager::create_ram_region(
+                    &zone.file,
+                    file_offset,
+                    start_addr,
+                    size,
+                    zone.hugepages,
+                    zone.host_numa_node,
+                )?,
+                None => MemoryManager::create_ram_region(
+                    backing_file,
+                    file_offset,
+                    start_addr,
+                    size,
+                    false,
+                    None,
+                )?,
+            });
+        }
+
+        Ok(mem_regions)
+    }
+
+    // Lay the memory zones out over the RAM regions, returning for each
+    // guest region its zone, the offset in the zone, its address and size.
+    // The zone is None for the RAM left past the zones.
+    #[allow(clippy::type_complexity)]
+    fn split_zones<'a>(
+        ram_regions: &[(GuestAddress, usize)],
+        zones: &'a [MemoryZoneConfig],
+    ) -> Result<Vec<(Option<&'a MemoryZoneConfig>, usize, GuestAddress, usize)>, Error> {
+        let mut split = Vec::new();
+        let mut zones_iter = zones.iter().filter(|zone| zone.size != 0);
+        let mut zone = zones_iter.next();
+        let mut zone_offset: usize = 0;
+
+        for (region_start, region_size) in ram_regions.iter() {
+            let mut region_offset: usize = 0;
+            while region_offset < *region_size {
+                let size = match zone {
+                    Some(zone_cfg) => std::cmp::min(
+                        zone_cfg.size as usize - zone_offset,
+                        region_size - region_offset,
+                    ),
+                    None => region_size - region_offset,
+                };
+
+                split.push((
+                    zone,
+                    zone_offset,
+                    region_start.unchecked_add(region_offset as u64),
+                    size,
+                ));
+
+                region_offset += size;
+                zone_offset += size;
+                if let Some(zone_cfg) = zone {
+                    if zone_offset == zone_cfg.size as usize {
+                        zone = zones_iter.next();
+                        zone_offset = 0;
+                    }
+                }
+            }
+        }
+
+        if zone.is_some() {
+            return Err(Error::InvalidMemoryZones);
+        }
+
+        Ok(split)
+    }
+
     fn create_ram_region(
         backing_file: &Option<PathBuf>,
+        file_offset: usize,
         start_addr: GuestAddress,
         size: usize,
+        hugepages: bool,
+        host_numa_node: Option<u32>,
     ) -> Result<Arc<GuestRegionMmap>, Error> {
-        Ok(Arc::new(match backing_file {
+        let region = match backing_file {
             Some(ref file) => {
-                let f = if file.is_dir() {
+                let (f, file_offset) = if file.is_dir() {
                     let fs_str = format!("{}{}", file.display(), "/tmpfile_XXXXXX");
                     let fs = std::ffi::CString::new(fs_str).unwrap();
                     let mut path = fs.as_bytes_with_nul().to_owned();
                     let path_ptr = path.as_mut_ptr() as *mut _;
                     let fd = unsafe { libc::mkstemp(path_ptr) };
                     unsafe { libc::unlink(path_ptr) };
-                    unsafe { File::from_raw_fd(fd) }
+                    (unsafe { File::from_raw_fd(fd) }, 0)
                 } else {
-                    OpenOptions::new()
-                        .read(true)
-                        .write(true)
-                        .open(file)
-                        .map_err(Error::SharedFileCreate)?
+                    (
+                        OpenOptions::new()
+                            .read(true)
+                            .write(true)
+                            .open(file)
+                            .map_err(Error::SharedFileCreate)?,
+                        file_offset,
+                    )
                 };
 
-                f.set_len(size as u64).map_err(Error::SharedFileSetLen)?;
+                f.set_len((file_offset + size) as u64)
+                    .map_err(Error::SharedFileSetLen)?;
 
-                GuestRegionMmap::new(
-                    MmapRegion::from_file(FileOffset::new(f, 0), size)
-                        .map_err(Error::GuestMemoryRegion)?,
-                    start_addr,
-                )
-                .map_err(Error::GuestMemory)?
+                let mmap_region = if hugepages {
+                    MmapRegion::build(
+                        Some(FileOffset::new(f, file_offset as u64)),
+                        size,
+                        libc::PROT_READ | libc::PROT_WRITE,
+                        libc::MAP_SHARED | libc::MAP_HUGETLB,
+                    )
+                } else {
+                    MmapRegion::from_file(FileOffset::new(f, file_offset as u64), size)
+                }
+                .map_err(Error::GuestMemoryRegion)?;
+
+                GuestRegionMmap::new(mmap_region, start_addr).map_err(Error::GuestMemory)?
+            }
+            None => {
+                let mmap_region = if hugepages {
+                    MmapRegion::build(
+                        None,
+                        size,
+                        libc::PROT_READ | libc::PROT_WRITE,
+                        libc::MAP_ANONYMOUS
+                            | libc::MAP_NORESERVE
+                            | libc::MAP_PRIVATE
+                            | libc::MAP_HUGETLB,
+                    )
+                } else {
+                    MmapRegion::new(size)
+                }
+                .map_err(Error::GuestMemoryRegion)?;
+
+                GuestRegionMmap::new(mmap_region, start_addr).map_err(Error::GuestMemory)?
             }
-            None => GuestRegionMmap::new(
-                MmapRegion::new(size).map_err(Error::GuestMemoryRegion)?,
-                start_addr,
+        };
+
+        if let Some(node) = host_numa_node {
+            MemoryManager::bind_to_host_numa_node(&region, node)?;
+        }
+
+        Ok(Arc::new(region))
+    }
+
+    fn bind_to_host_numa_node(region: &GuestRegionMmap, node: u32) -> Result<(), Error> {
+        let nodemask: u64 = 1 << node;
+        // The kernel expects maxnode to be one more than the number of bits
+        // in the mask.
+        let maxnode: libc::c_ulong = 64 + 1;
+
+        // Safe because the address and size are valid since the mmap
+        // succeeded, and the node mask outlives the syscall.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                region.as_ptr() as *mut libc::c_void,
+                region.len() as libc::c_ulong,
+                MPOL_BIND,
+                &nodemask as *const u64,
+                maxnode,
+                MPOL_MF_STRICT | MPOL_MF_MOVE,
             )
-            .map_err(Error::GuestMemory)?,
-        }))
+        };
+        if ret != 0 {
+            return Err(Error::ApplyNumaPolicy(io::Error::last_os_error()));
+        }
+
+        Ok(())
     }
 
     fn hotplug_ram_region(&mut self, size: usize) -> Result<(), Error> {
@@ -342,14 +908,15 @@ impl MemoryManager {
         }
 
         // Allocate memory for the region
-        let region = MemoryManager::create_ram_region(&self.backing_file, start_addr, size)?;
+        let region =
+            MemoryManager::create_ram_region(&self.backing_file, 0, start_addr, size, false, None)?;
 
         // Map it into the guest
-        self.create_userspace_mapping(
+        self.create_ram_mapping(
             region.start_addr().0,
             region.len() as u64,
             region.as_ptr() as u64,
-            self.mergeable,
+            true,
         )?;
 
         // Tell the allocator
@@ -391,6 +958,81 @@ impl MemoryManager {
         self.end_of_device_area
     }
 
+    /// Guest memory region, as address and size, the memory gets hotplugged
+    /// into through virtio-mem, if any.
+    pub fn virtio_mem_region(&self) -> Option<(GuestAddress, u64)> {
+        self.virtio_mem_region
+    }
+
+    /// Back the guest with the requested SGX EPC sections. They are placed
+    /// one after the other, where the device area starts, and the device
+    /// area is moved up accordingly. Hence this must be called before any
+    /// device gets created.
+    pub fn setup_sgx(
+        &mut self,
+        sgx_epc_config: &[SgxEpcConfig],
+    ) -> Result<Vec<SgxEpcSection>, Error> {
+        let mut start = GuestAddress(
+            (self.start_of_device_area.raw_value() + SGX_EPC_PAGE_SIZE - 1)
+                & !(SGX_EPC_PAGE_SIZE - 1),
+        );
+
+        for config in sgx_epc_config.iter() {
+            if config.size == 0 || config.size % SGX_EPC_PAGE_SIZE != 0 {
+                return Err(Error::InvalidSgxEpcSize(config.size));
+            }
+
+            // Each opening of the device provides a distinct EPC section.
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(SGX_VIRT_EPC_PATH)
+                .map_err(Error::SgxVirtEpcOpen)?;
+
+            let mut flags = libc::MAP_SHARED;
+            if config.prefault {
+                flags |= libc::MAP_POPULATE;
+            }
+
+            // Safe because we check the return value, and the mapping is
+            // unmapped when the memory manager goes away.
+            let host_addr = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    config.size as usize,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    flags,
+                    file.as_raw_fd(),
+                    0,
+                )
+            };
+            if host_addr == libc::MAP_FAILED {
+                return Err(Error::SgxVirtEpcMap(io::Error::last_os_error()));
+            }
+
+            let section = SgxEpcSection {
+                start,
+                size: config.size,
+                host_addr: host_addr as u64,
+            };
+            self.sgx_epc_sections.push(section);
+
+            self.allocator
+                .lock()
+                .unwrap()
+                .allocate_mmio_addresses(Some(start), config.size, None)
+                .ok_or(Error::MemoryRangeAllocation)?;
+
+            self.create_userspace_mapping(start.raw_value(), config.size, host_addr as u64, false)?;
+
+            start = start.unchecked_add(config.size);
+        }
+
+        self.start_of_device_area = start;
+
+        Ok(self.sgx_epc_sections.clone())
+    }
+
     pub fn allocate_kvm_memory_slot(&mut self) -> u32 {
         let slot_id = self.next_kvm_memory_slot;
         self.next_kvm_memory_slot += 1;
@@ -450,6 +1092,190 @@ impl MemoryManager {
         Ok(slot)
     }
 
+    // Guest RAM mappings are remembered so that dirty pages logging can be
+    // toggled on all of them, including the ones hotplugged later. Unless
+    // `populate` is false, the memory is locked or prefaulted as asked for.
+    fn create_ram_mapping(
+        &mut self,
+        guest_phys_addr: u64,
+        memory_size: u64,
+        userspace_addr: u64,
+        populate: bool,
+    ) -> Result<(), Error> {
+        let slot = self.create_userspace_mapping(
+            guest_phys_addr,
+            memory_size,
+            userspace_addr,
+            self.mergeable,
+        )?;
+
+        self.ram_regions.push(kvm_userspace_memory_region {
+            slot,
+            guest_phys_addr,
+            memory_size,
+            userspace_addr,
+            flags: 0,
+        });
+
+        if self.dirty_log {
+            self.set_slot_dirty_log(slot, true)?;
+        }
+
+        if !populate {
+            return Ok(());
+        }
+
+        if self.mlock {
+            // Safe because the address and size are valid since the
+            // mmap succeeded. Locking the pages faults them in as well.
+            let ret = unsafe {
+                libc::mlock(
+                    userspace_addr as *const libc::c_void,
+                    memory_size as libc::size_t,
+                )
+            };
+            if ret != 0 {
+                return Err(Error::Mlock(io::Error::last_os_error()));
+            }
+        } else if self.prefault {
+            MemoryManager::prefault_range(userspace_addr, memory_size);
+        }
+
+        Ok(())
+    }
+
+    // Touch every page of the range so that the guest doesn't take the
+    // first access page faults at runtime. Pages are written with their
+    // own content, which leaves file backed memory untouched.
+    fn prefault_range(userspace_addr: u64, memory_size: u64) {
+        // Safe because sysconf() has no side effect.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let mut offset = 0;
+        while offset < memory_size {
+            let addr = (userspace_addr + offset) as *mut u8;
+            // Safe because the address is within a valid mapping that the
+            // guest can't access yet, or concurrently with this write.
+            unsafe { std::ptr::write_volatile(addr, std::ptr::read_volatile(addr)) };
+            offset += page_size;
+        }
+    }
+
+    /// KVM memory slots holding the guest RAM.
+    pub fn ram_slots(&self) -> Vec<u32> {
+        self.ram_regions.iter().map(|r| r.slot).collect()
+    }
+
+    /// Enable or disable the dirty pages logging of one of the guest RAM
+    /// memory slots.
+    pub fn set_slot_dirty_log(&mut self, slot: u32, enable: bool) -> Result<(), Error> {
+        let mem_region = self
+            .ram_regions
+            .iter_mut()
+            .find(|r| r.slot == slot)
+            .ok_or(Error::UnknownRamSlot(slot))?;
+
+        let mut new_region = *mem_region;
+        new_region.flags = if enable { KVM_MEM_LOG_DIRTY_PAGES } else { 0 };
+
+        // Safe because we're only updating the flags of an existing region.
+        unsafe { self.fd.set_user_memory_region(new_region) }
+            .map_err(Error::SetUserMemoryRegion)?;
+        *mem_region = new_region;
+
+        Ok(())
+    }
+
+    /// Start logging the guest RAM pages written by the guest, on all the
+    /// guest RAM memory slots.
+    pub fn start_dirty_log(&mut self) -> Result<(), Error> {
+        for slot in self.ram_slots() {
+            self.set_slot_dirty_log(slot, true)?;
+        }
+        self.dirty_log = true;
+
+        Ok(())
+    }
+
+    /// Stop logging the guest RAM pages written by the guest, on all the
+    /// guest RAM memory slots.
+    pub fn stop_dirty_log(&mut self) -> Result<(), Error> {
+        for slot in self.ram_slots() {
+            self.set_slot_dirty_log(slot, false)?;
+        }
+        self.dirty_log = false;
+
+        Ok(())
+    }
+
+    /// Retrieve the dirty pages bitmaps of the guest RAM memory slots being
+    /// logged. KVM resets the bitmaps when they're read, so each of them
+    /// describes the pages written since the previous call.
+    pub fn dirty_bitmaps(&mut self) -> Result<Vec<DirtyBitmap>, Error> {
+        let mut bitmaps = Vec::new();
+        for mem_region in self
+            .ram_regions
+            .iter()
+            .filter(|r| r.flags & KVM_MEM_LOG_DIRTY_PAGES != 0)
+        {
+            let bitmap = self
+                .fd
+                .get_dirty_log(mem_region.slot, mem_region.memory_size as usize)
+                .map_err(Error::DirtyLog)?;
+
+            // The bitmaps are reset when read, whoever reads them, so they're
+            // accumulated here for the working set estimation.
+            if let Some(working_set) = self.working_set.as_mut() {
+                let union = working_set
+                    .entry(mem_region.slot)
+                    .or_insert_with(|| vec![0; bitmap.len()]);
+                for (union_bits, bits) in union.iter_mut().zip(bitmap.iter()) {
+                    *union_bits |= *bits;
+                }
+            }
+
+            bitmaps.push(DirtyBitmap {
+                slot: mem_region.slot,
+                gpa: mem_region.guest_phys_addr,
+                length: mem_region.memory_size,
+                bitmap,
+            });
+        }
+
+        Ok(bitmaps)
+    }
+
+    /// Number of guest RAM pages written since the previous call, or since
+    /// dirty pages logging was started.
+    pub fn dirty_pages(&mut self) -> Result<u64, Error> {
+        Ok(self.dirty_bitmaps()?.iter().map(|b| b.dirty_pages()).sum())
+    }
+
+    /// Start estimating the guest working set, from the pages it writes to.
+    pub fn start_working_set(&mut self) -> Result<(), Error> {
+        self.start_dirty_log()?;
+        self.working_set = Some(BTreeMap::new());
+
+        Ok(())
+    }
+
+    /// Amount of guest RAM, in bytes, written since the previous call, or
+    /// since the working set estimation was started.
+    pub fn sample_working_set(&mut self) -> Result<u64, Error> {
+        self.dirty_bitmaps()?;
+
+        let working_set = match self.working_set.as_mut() {
+            Some(working_set) => std::mem::take(working_set),
+            None => return Ok(0),
+        };
+        let pages: u64 = working_set
+            .values()
+            .flatten()
+            .map(|bits| u64::from(bits.count_ones()))
+            .sum();
+
+        Ok(pages * DIRTY_LOG_PAGE_SIZE)
+    }
+
     pub fn resize(&mut self, desired_ram: u64) -> Result<bool, Error> {
         if desired_ram > self.current_ram {
             self.hotplug_ram_region((desired_ram - self.current_ram) as usize)?;
@@ -459,6 +1285,127 @@ impl MemoryManager {
             Ok(false)
         }
     }
+
+    /// Save the guest RAM into `snapshot_dir`, the VM being paused.
+    ///
+    /// With `cow`, the anonymous private regions are written by a forked
+    /// child process which gets a copy-on-write view of the guest memory,
+    /// meaning the VM can be resumed as soon as this function returns, while
+    /// the memory is written out in the background by the returned writer.
+    /// The shared regions (file backed) would see the guest writes through
+    /// fork(), and are saved before returning instead, as is the whole guest
+    /// memory without `cow`, e.g. when devices DMA to pinned guest pages
+    /// which copy-on-write would get the guest out of sync with.
+    ///
+    /// The child only performs async-signal-safe calls on memory set up
+    /// before the fork, which keeps forking the multithreaded VMM safe.
+    ///
+    /// The guest memory ranges in `excluded`, as address and length, sorted
+    /// and not overlapping, are left as holes in the memory file, e.g. the
+    /// pages the guest doesn't use.
+    pub fn snapshot(
+        &self,
+        snapshot_dir: &Path,
+        excluded: &[(u64, u64)],
+        cow: bool,
+    ) -> Result<MemorySnapshotWriter, Error> {
+        let memory_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(snapshot_dir.join("memory"))
+            .map_err(Error::SnapshotFileCreate)?;
+
+        let bytes_written = SharedCounter::new().map_err(Error::SnapshotWrite)?;
+        let mut ranges = Vec::new();
+        let mut cow_ranges = Vec::new();
+        let mut file_offset = 0;
+        let mut bytes_total = 0;
+        let guest_memory = self.guest_memory.memory();
+        for region in guest_memory.iter() {
+            let range = MemoryRangeSnapshot {
+                gpa: region.start_addr().raw_value(),
+                length: region.len() as u64,
+                file_offset,
+            };
+            let host_addr = region.as_ptr() as u64;
+            for (offset, length) in included_ranges(range.gpa, range.length, excluded) {
+                bytes_total += length;
+                if cow && region.file_offset().is_none() {
+                    cow_ranges.push((host_addr + offset, length, range.file_offset + offset));
+                } else if !write_host_range(
+                    memory_file.as_raw_fd(),
+                    host_addr + offset,
+                    length,
+                    range.file_offset + offset,
+                    &bytes_written,
+                ) {
+                    return Err(Error::SnapshotWrite(io::Error::last_os_error()));
+                }
+            }
+            file_offset += range.length;
+            ranges.push(range);
+        }
+        // The excluded ranges read back as zeroes.
+        memory_file
+            .set_len(file_offset)
+            .map_err(Error::SnapshotWrite)?;
+
+        let ranges_file = File::create(snapshot_dir.join("memory-ranges.json"))
+            .map_err(Error::SnapshotFileCreate)?;
+        serde_json::to_writer(ranges_file, &ranges).map_err(Error::SnapshotRanges)?;
+
+        if cow_ranges.is_empty() {
+            memory_file.sync_all().map_err(Error::SnapshotWrite)?;
+            return Ok(MemorySnapshotWriter {
+                pid: None,
+                bytes_total,
+                bytes_written,
+            });
+        }
+
+        MemorySnapshotWriter::fork(&memory_file, &cow_ranges, bytes_total, bytes_written)
+    }
+
+    /// Fill the guest RAM from the memory snapshot in `snapshot_dir`, the
+    /// guest memory layout being the one the snapshot was taken with.
+    pub fn restore(&self, snapshot_dir: &Path) -> Result<(), Error> {
+        let ranges_file =
+            File::open(snapshot_dir.join("memory-ranges.json")).map_err(Error::RestoreRead)?;
+        let ranges: Vec<MemoryRangeSnapshot> =
+            serde_json::from_reader(ranges_file).map_err(Error::SnapshotRanges)?;
+        let memory_file = File::open(snapshot_dir.join("memory")).map_err(Error::RestoreRead)?;
+
+        let guest_memory = self.guest_memory.memory();
+        if ranges.len() != guest_memory.num_regions() {
+            return Err(Error::RestoreLayout(0));
+        }
+        for range in ranges.iter() {
+            let region = guest_memory
+                .iter()
+                .find(|region| {
+                    region.start_addr().raw_value() == range.gpa
+                        && region.len() as u64 == range.length
+                })
+                .ok_or(Error::RestoreLayout(range.gpa))?;
+            let host_addr = region.as_ptr() as u64;
+            let mut done = 0;
+            while done < range.length {
+                let count = std::cmp::min(range.length - done, SNAPSHOT_WRITE_CHUNK);
+                // Safe because the range is part of the guest memory mappings,
+                // the guest not running yet.
+                let buf = unsafe {
+                    std::slice::from_raw_parts_mut((host_addr + done) as *mut u8, count as usize)
+                };
+                memory_file
+                    .read_exact_at(buf, range.file_offset + done)
+                    .map_err(Error::RestoreRead)?;
+                done += count;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(feature = "acpi")]
@@ -716,11 +1663,53 @@ impl Aml for MemoryMethods {
     }
 }
 
+impl Drop for MemoryManager {
+    fn drop(&mut self) {
+        for section in self.sgx_epc_sections.iter() {
+            // Safe because the section has been mapped with this address
+            // and size, and the VM is gone.
+            unsafe {
+                libc::munmap(
+                    section.host_addr as *mut libc::c_void,
+                    section.size as usize,
+                );
+            }
+        }
+    }
+}
+
 #[cfg(feature = "acpi")]
 impl Aml for MemoryManager {
     fn to_aml_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
 
+        // SGX EPC, for the guest to find out where the EPC sections are,
+        // along with CPUID.
+        if let (Some(first), Some(last)) =
+            (self.sgx_epc_sections.first(), self.sgx_epc_sections.last())
+        {
+            let min = first.start.raw_value();
+            let max = last.start.raw_value() + last.size - 1;
+            bytes.extend_from_slice(
+                &aml::Device::new(
+                    "_SB_.EPC_".into(),
+                    vec![
+                        &aml::Name::new("_HID".into(), &aml::EISAName::new("INT0E0C")),
+                        &aml::Name::new(
+                            "_CRS".into(),
+                            &aml::ResourceTemplate::new(vec![&aml::AddressSpace::new_memory(
+                                aml::AddressSpaceCachable::NotCacheable,
+                                true,
+                                min,
+                                max,
+                            )]),
+                        ),
+                    ],
+                )
+                .to_aml_bytes(),
+            );
+        }
+
         // Memory Hotplug Controller
         bytes.extend_from_slice(
             &aml::Device::new(
@@ -794,3 +1783,106 @@ impl Aml for MemoryManager {
         bytes
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(size: u64) -> MemoryZoneConfig {
+        MemoryZoneConfig {
+            size,
+            file: None,
+            hugepages: false,
+            host_numa_node: None,
+        }
+    }
+
+    #[test]
+    fn test_split_zones() {
+        let ram_regions = [(GuestAddress(0), 3 << 30), (GuestAddress(4 << 30), 2 << 30)];
+        let zones = [zone(1 << 30), zone(3 << 30), zone(1 << 30)];
+
+        let split: Vec<(u64, usize, GuestAddress, usize)> =
+            MemoryManager::split_zones(&ram_regions, &zones)
+                .unwrap()
+                .into_iter()
+                .map(|(zone, offset, start, size)| (zone.unwrap().size, offset, start, size))
+                .collect();
+        assert_eq!(
+            split,
+            vec![
+                (1 << 30, 0, GuestAddress(0), 1 << 30),
+                // The second zone crosses the 32-bit hole.
+                (3 << 30, 0, GuestAddress(1 << 30), 2 << 30),
+                (3 << 30, 2 << 30, GuestAddress(4 << 30), 1 << 30),
+                (1 << 30, 0, GuestAddress(5 << 30), 1 << 30),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_zones_hotplugged_ram() {
+        let ram_regions = [(GuestAddress(0), 3 << 30)];
+        let zones = [zone(1 << 30), zone(0), zone(1 << 30)];
+
+        let split = MemoryManager::split_zones(&ram_regions, &zones).unwrap();
+        assert_eq!(split.len(), 3);
+        assert_eq!(split[1].0, Some(&zones[2]));
+        // The hotplugged RAM follows the zones.
+        assert_eq!(split[2], (None, 0, GuestAddress(2 << 30), 1 << 30));
+    }
+
+    #[test]
+    fn test_split_zones_too_large() {
+        let ram_regions = [(GuestAddress(0), 1 << 30)];
+        let zones = [zone(1 << 30), zone(1 << 20)];
+
+        assert!(MemoryManager::split_zones(&ram_regions, &zones).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_writer_cow() {
+        let len = 0x3000;
+        let mut memory = vec![0xa5u8; len as usize];
+        let file = tempfile::tempfile().unwrap();
+        let ranges = [(memory.as_ptr() as u64, len, 0x1000)];
+
+        let writer =
+            MemorySnapshotWriter::fork(&file, &ranges, len, SharedCounter::new().unwrap()).unwrap();
+        // What the guest writes once resumed isn't part of the snapshot.
+        for b in memory.iter_mut() {
+            *b = 0x5a;
+        }
+
+        let mut progress = Vec::new();
+        assert!(writer
+            .write(|written| {
+                progress.push(written);
+                true
+            })
+            .unwrap());
+        assert_eq!(progress.last(), Some(&len));
+
+        assert_eq!(file.metadata().unwrap().len(), 0x1000 + len);
+        let mut content = vec![0xffu8; (0x1000 + len) as usize];
+        file.read_exact_at(&mut content, 0).unwrap();
+        assert!(content[..0x1000].iter().all(|b| *b == 0));
+        assert!(content[0x1000..].iter().all(|b| *b == 0xa5));
+    }
+
+    #[test]
+    fn test_snapshot_writer_cancel() {
+        let len = 0x10_0000;
+        let memory = vec![0xa5u8; len as usize];
+        let file = tempfile::tempfile().unwrap();
+        let ranges = [(memory.as_ptr() as u64, len, 0)];
+
+        let writer =
+            MemorySnapshotWriter::fork(&file, &ranges, len, SharedCounter::new().unwrap()).unwrap();
+        // Either the child got killed, or it was already done.
+        let written = writer.write(|_| false).unwrap();
+        if !written {
+            assert!(file.metadata().unwrap().len() <= len);
+        }
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::api::OperationInfo;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Phase of a long running operation.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum OperationPhase {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+struct OperationState {
+    phase: OperationPhase,
+    bytes_total: u64,
+    bytes_transferred: u64,
+    error: Option<String>,
+    // When the operation reached one of the final phases.
+    finished_at: Option<Instant>,
+}
+
+/// A long running operation, e.g. a snapshot, carried out in the background.
+/// The thread performing it reports its progress, and checks whether it has
+/// been asked to stop.
+pub struct Operation {
+    kind: String,
+    state: Mutex<OperationState>,
+    cancel_requested: AtomicBool,
+}
+
+impl Operation {
+    pub fn new(kind: &str, bytes_total: u64) -> Self {
+        Operation {
+            kind: kind.to_string(),
+            state: Mutex::new(OperationState {
+                phase: OperationPhase::Running,
+                bytes_total,
+                bytes_transferred: 0,
+                error: None,
+                finished_at: None,
+            }),
+            cancel_requested: AtomicBool::new(false),
+        }
+    }
+
+    pub fn info(&self, id: u64) -> OperationInfo {
+        let state = self.state.lock().unwrap();
+        OperationInfo {
+            id,
+            kind: self.kind.clone(),
+            phase: state.phase,
+            bytes_total: state.bytes_total,
+            bytes_transferred: state.bytes_transferred,
+            error: state.error.clone(),
+        }
+    }
+
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn phase(&self) -> OperationPhase {
+        self.state.lock().unwrap().phase
+    }
+
+    pub fn set_progress(&self, bytes_transferred: u64) {
+        self.state.lock().unwrap().bytes_transferred = bytes_transferred;
+    }
+
+    // Only a running operation can move to one of the final phases.
+    fn finish(&self, phase: OperationPhase, error: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        if state.phase == OperationPhase::Running {
+            state.phase = phase;
+            state.error = error;
+            state.finished_at = Some(Instant::now());
+            if phase == OperationPhase::Completed {
+                state.bytes_transferred = state.bytes_total;
+            }
+        }
+    }
+
+    pub fn complete(&self) {
+        self.finish(OperationPhase::Completed, None);
+    }
+
+    pub fn fail(&self, error: String) {
+        self.finish(OperationPhase::Failed, Some(error));
+    }
+
+    pub fn set_cancelled(&self) {
+        self.finish(OperationPhase::Cancelled, None);
+    }
+
+    /// Ask for the operation to be stopped. Returns false if the operation
+    /// is not running anymore.
+    pub fn cancel(&self) -> bool {
+        if self.phase() != OperationPhase::Running {
+            return false;
+        }
+        self.cancel_requested.store(true, Ordering::SeqCst);
+        true
+    }
+
+    pub fn cancel_requested(&self) -> bool {
+        self.cancel_requested.load(Ordering::SeqCst)
+    }
+
+    /// Whether the operation finished more than `ttl` ago.
+    pub fn expired(&self, ttl: Duration) -> bool {
+        match self.state.lock().unwrap().finished_at {
+            Some(finished_at) => finished_at.elapsed() >= ttl,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operation_completion() {
+        let operation = Operation::new("snapshot", 4096);
+        operation.set_progress(1024);
+        assert_eq!(operation.info(1).bytes_transferred, 1024);
+
+        operation.complete();
+        let info = operation.info(1);
+        assert_eq!(info.phase, OperationPhase::Completed);
+        assert_eq!(info.bytes_transferred, 4096);
+
+        // A finished operation can't be cancelled, nor fail.
+        assert!(!operation.cancel());
+        operation.fail("error".to_string());
+        assert_eq!(operation.phase(), OperationPhase::Completed);
+    }
+
+    #[test]
+    fn test_operation_cancellation() {
+        let operation = Operation::new("snapshot", 4096);
+        assert!(!operation.cancel_requested());
+        assert!(operation.cancel());
+        assert!(operation.cancel_requested());
+        assert_eq!(operation.phase(), OperationPhase::Running);
+
+        operation.set_cancelled();
+        assert_eq!(operation.phase(), OperationPhase::Cancelled);
+    }
+
+    #[test]
+    fn test_operation_expiry() {
+        let operation = Operation::new("snapshot", 4096);
+        assert!(!operation.expired(Duration::from_secs(0)));
+
+        operation.complete();
+        assert!(operation.expired(Duration::from_secs(0)));
+        assert!(!operation.expired(Duration::from_secs(3600)));
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Pseudo terminals the serial port and the virtio-console can be exposed
+//! on, so that a daemonized VMM, without any terminal of its own, still gives
+//! access to the guest console, e.g. with `screen /dev/pts/<N>`.
+//!
+//! The VMM keeps the subsidiary side of the pty open, so that the guest
+//! output doesn't depend on a client being connected: it is buffered by the
+//! pty up to its capacity, and discarded beyond.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::thread;
+use vmm_sys_util::eventfd::EventFd;
+
+// Sets the close-on-exec and non-blocking flags, which openpty() doesn't.
+fn set_flags(file: &File, nonblocking: bool) -> io::Result<()> {
+    // Safe because the fd is valid, and we check the return values.
+    unsafe {
+        if libc::fcntl(file.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if nonblocking {
+            let flags = libc::fcntl(file.as_raw_fd(), libc::F_GETFL);
+            if flags < 0
+                || libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+// The guest handles the line editing and the echo, hence the raw mode.
+fn set_raw_mode(file: &File) -> io::Result<()> {
+    // Safe because termios is plain data, the fd is valid, and we check the
+    // return values.
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(file.as_raw_fd(), &mut termios) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(file.as_raw_fd(), libc::TCSANOW, &termios) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+pub struct Pty {
+    main: File,
+    sub: File,
+    path: PathBuf,
+}
+
+impl Pty {
+    /// Allocates a new pty.
+    pub fn new() -> io::Result<Pty> {
+        let mut main = -1;
+        let mut sub = -1;
+        // Safe because the pointers are valid, the others being optional,
+        // and we check the return value.
+        let ret = unsafe {
+            libc::openpty(
+                &mut main,
+                &mut sub,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because openpty() returned these fds, which nothing else owns.
+        let (main, sub) = unsafe { (File::from_raw_fd(main), File::from_raw_fd(sub)) };
+
+        set_flags(&main, true)?;
+        set_flags(&sub, false)?;
+        set_raw_mode(&sub)?;
+        let path = fs::read_link(format!("/proc/self/fd/{}", sub.as_raw_fd()))?;
+
+        Ok(Pty { main, sub, path })
+    }
+
+    /// The path of the subsidiary side, for the clients to open.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Where the guest output is written to.
+    pub fn writer(&self) -> io::Result<File> {
+        self.main.try_clone()
+    }
+
+    /// Passes the input from the clients to `input`, from a thread of its
+    /// own, until the returned reader is dropped.
+    pub fn spawn_reader<F>(self, name: &str, mut input: F) -> io::Result<PtyReader>
+    where
+        F: FnMut(&[u8]) + Send + 'static,
+    {
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+        let thread_kill_evt = kill_evt.try_clone()?;
+
+        let thread = thread::Builder::new()
+            .name(format!("{}_pty", name))
+            .spawn(move || {
+                let mut pty = self;
+                let mut fds = [
+                    libc::pollfd {
+                        fd: pty.main.as_raw_fd(),
+                        events: libc::POLLIN,
+                        revents: 0,
+                    },
+                    libc::pollfd {
+                        fd: thread_kill_evt.as_raw_fd(),
+                        events: libc::POLLIN,
+                        revents: 0,
+                    },
+                ];
+                let mut buf = [0u8; 64];
+                loop {
+                    // Safe because the fds are valid for as long as the
+                    // thread runs, and we check the return value.
+                    let ret =
+                        unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+                    if ret < 0 {
+                        let e = io::Error::last_os_error();
+                        if e.kind() == io::ErrorKind::Interrupted {
+                            continue;
+                        }
+                        error!("Failed polling {:?}: {}", pty.path, e);
+                        break;
+                    }
+                    if fds[1].revents != 0 {
+                        break;
+                    }
+                    if fds[0].revents & libc::POLLIN != 0 {
+                        match pty.main.read(&mut buf) {
+                            Ok(count) => input(&buf[..count]),
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                            Err(e) => {
+                                error!("Failed reading {:?}: {}", pty.path, e);
+                                break;
+                            }
+                        }
+                    }
+                }
+                // The subsidiary side only goes away with the thread.
+                drop(pty.sub);
+            })?;
+
+        Ok(PtyReader {
+            kill_evt,
+            thread: Some(thread),
+        })
+    }
+}
+
+pub struct PtyReader {
+    kill_evt: EventFd,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for PtyReader {
+    fn drop(&mut self) {
+        let _ = self.kill_evt.write(1);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_pty() {
+        let pty = Pty::new().unwrap();
+        let path = pty.path().to_path_buf();
+        assert!(path.starts_with("/dev/pts"));
+
+        let mut writer = pty.writer().unwrap();
+        let (sender, receiver) = channel();
+        let reader = pty
+            .spawn_reader("test", move |input| sender.send(input.to_vec()).unwrap())
+            .unwrap();
+
+        let mut client = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        client.write_all(b"a").unwrap();
+        assert_eq!(receiver.recv().unwrap(), b"a".to_vec());
+
+        // Raw mode, without any translation of the guest output.
+        writer.write_all(b"b\n").unwrap();
+        let mut output = [0u8; 2];
+        client.read_exact(&mut output).unwrap();
+        assert_eq!(&output, b"b\n");
+
+        drop(reader);
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::config::PlatformConfig;
+use kvm_ioctls::VmFd;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::Arc;
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+
+const SEV_DEVICE_PATH: &str = "/dev/sev";
+
+// KVM memory encryption ioctls and SEV commands, from
+// include/uapi/linux/kvm.h
+const KVMIO: u32 = 0xae;
+ioctl_iowr_nr!(KVM_MEMORY_ENCRYPT_OP, KVMIO, 0xba, std::os::raw::c_ulong);
+ioctl_ior_nr!(KVM_MEMORY_ENCRYPT_REG_REGION, KVMIO, 0xbb, KvmEncRegion);
+
+const KVM_SEV_INIT: u32 = 0;
+const KVM_SEV_LAUNCH_START: u32 = 2;
+const KVM_SEV_LAUNCH_UPDATE_DATA: u32 = 3;
+const KVM_SEV_LAUNCH_MEASURE: u32 = 6;
+const KVM_SEV_LAUNCH_FINISH: u32 = 7;
+
+// The launch measurement is a 32 bytes HMAC followed by a 16 bytes nonce.
+const SEV_LAUNCH_MEASUREMENT_LEN: usize = 48;
+
+#[repr(C)]
+#[derive(Default)]
+struct KvmSevCmd {
+    id: u32,
+    data: u64,
+    error: u32,
+    sev_fd: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct KvmSevLaunchStart {
+    handle: u32,
+    policy: u32,
+    dh_uaddr: u64,
+    dh_len: u32,
+    session_uaddr: u64,
+    session_len: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct KvmSevLaunchUpdateData {
+    uaddr: u64,
+    len: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct KvmSevLaunchMeasure {
+    uaddr: u64,
+    len: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct KvmEncRegion {
+    addr: u64,
+    size: u64,
+}
+
+/// Errors associated with the SEV guest launch.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot open the SEV device.
+    OpenSevDevice(io::Error),
+
+    /// A SEV command failed, along with the error code from the firmware.
+    Command(&'static str, io::Error, u32),
+
+    /// Cannot register a guest memory region as encrypted.
+    RegisterRegion(io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+/// Drives the launch of a SEV guest through the AMD secure processor.
+///
+/// The guest context is created along with the VM, before any vCPU. The
+/// memory the guest boots from is then encrypted in place and measured with
+/// `launch_update_data()`, until `launch_finish()` seals the guest, logging
+/// the launch measurement a guest owner can check.
+pub struct SevGuest {
+    vm_fd: Arc<VmFd>,
+    sev: File,
+    c_bit_mask: u64,
+}
+
+impl SevGuest {
+    pub fn new(vm_fd: Arc<VmFd>, config: &PlatformConfig) -> Result<Self> {
+        let sev = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(SEV_DEVICE_PATH)
+            .map_err(Error::OpenSevDevice)?;
+
+        // The C-bit is the physical address bit marking a page as
+        // encrypted in the guest page tables.
+        // Safe because the CPUID instruction has no side effect.
+        let c_bit = unsafe { std::arch::x86_64::__cpuid(0x8000_001f) }.ebx & 0x3f;
+
+        let guest = SevGuest {
+            vm_fd,
+            sev,
+            c_bit_mask: 1 << c_bit,
+        };
+
+        guest.command("INIT", KVM_SEV_INIT, 0)?;
+
+        // No guest owner Diffie-Hellman certificate nor session, meaning
+        // the guest does not get any launch secret.
+        let mut launch_start = KvmSevLaunchStart {
+            policy: config.sev_policy,
+            ..Default::default()
+        };
+        guest.command(
+            "LAUNCH_START",
+            KVM_SEV_LAUNCH_START,
+            &mut launch_start as *mut _ as u64,
+        )?;
+
+        Ok(guest)
+    }
+
+    /// The mask to set in the guest page table entries mapping encrypted
+    /// memory.
+    pub fn c_bit_mask(&self) -> u64 {
+        self.c_bit_mask
+    }
+
+    fn command(&self, name: &'static str, id: u32, data: u64) -> Result<()> {
+        let mut cmd = KvmSevCmd {
+            id,
+            data,
+            error: 0,
+            sev_fd: self.sev.as_raw_fd() as u32,
+        };
+
+        // Safe because we know the VM fd is valid, and the structure
+        // `data` points to matches the command.
+        let ret =
+            unsafe { ioctl_with_mut_ref(self.vm_fd.as_ref(), KVM_MEMORY_ENCRYPT_OP(), &mut cmd) };
+        if ret < 0 {
+            return Err(Error::Command(name, io::Error::last_os_error(), cmd.error));
+        }
+
+        Ok(())
+    }
+
+    /// Pin the guest memory region at `host_addr`, as encrypted pages must
+    /// not be moved by the host.
+    pub fn register_region(&self, host_addr: u64, size: u64) -> Result<()> {
+        let mut region = KvmEncRegion {
+            addr: host_addr,
+            size,
+        };
+
+        // Safe because we know the VM fd is valid, and the region is owned
+        // by the VM.
+        let ret = unsafe {
+            ioctl_with_mut_ref(
+                self.vm_fd.as_ref(),
+                KVM_MEMORY_ENCRYPT_REG_REGION(),
+                &mut region,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::RegisterRegion(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    /// Encrypt in place, and add to the launch measurement, the guest
+    /// memory at `host_addr`.
+    pub fn launch_update_data(&self, host_addr: u64, size: u32) -> Result<()> {
+        let mut update_data = KvmSevLaunchUpdateData {
+            uaddr: host_addr,
+            len: size,
+        };
+
+        self.command(
+            "LAUNCH_UPDATE_DATA",
+            KVM_SEV_LAUNCH_UPDATE_DATA,
+            &mut update_data as *mut _ as u64,
+        )
+    }
+
+    /// Complete the guest launch, once every vCPU has been set up.
+    pub fn launch_finish(&self) -> Result<()> {
+        let mut measurement = vec![0u8; SEV_LAUNCH_MEASUREMENT_LEN];
+        let mut launch_measure = KvmSevLaunchMeasure {
+            uaddr: measurement.as_mut_ptr() as u64,
+            len: SEV_LAUNCH_MEASUREMENT_LEN as u32,
+        };
+        self.command(
+            "LAUNCH_MEASURE",
+            KVM_SEV_LAUNCH_MEASURE,
+            &mut launch_measure as *mut _ as u64,
+        )?;
+
+        self.command("LAUNCH_FINISH", KVM_SEV_LAUNCH_FINISH, 0)?;
+
+        let measurement: Vec<String> = measurement.iter().map(|b| format!("{:02x}", b)).collect();
+        info!("SEV launch measurement: {}", measurement.join(""));
+
+        Ok(())
+    }
+}
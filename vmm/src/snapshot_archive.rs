@@ -0,0 +1,305 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Packs a VM snapshot into a single zstd compressed tar archive, and
+//! unpacks it back, so that a snapshot can be moved between hosts or stored
+//! as one object.
+//!
+//! The archive holds the snapshot files at its root. The disk images, when
+//! exported along, are stored as `disks/<index>` and `disks/<index>-overlay`,
+//! `index` being the position of the disk in the VM configuration. When
+//! importing the archive, the configuration is updated to point to the
+//! unpacked disk images.
+
+use crate::config::VmConfig;
+use crate::operation::Operation;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::Arc;
+use std::thread;
+
+// Files a snapshot is made of.
+const SNAPSHOT_FILES: [&str; 5] = [
+    "config.json",
+    "clock.json",
+    "state.json",
+    "memory-ranges.json",
+    "memory",
+];
+const CONFIG_FILE: &str = "config.json";
+const DISKS_DIR: &str = "disks";
+
+// Trading some compression ratio for speed, the guest memory being the bulk
+// of the archive.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Errors associated with the snapshot archives.
+#[derive(Debug)]
+pub enum Error {
+    /// The snapshot lacks one of its files, e.g. it's still being written.
+    MissingFile(PathBuf),
+
+    /// Cannot read a file.
+    ReadFile(PathBuf, io::Error),
+
+    /// Cannot parse the snapshot configuration.
+    ParseConfig(serde_json::Error),
+
+    /// Cannot write the snapshot configuration.
+    WriteConfig(io::Error),
+
+    /// Cannot write the archive.
+    WriteArchive(io::Error),
+
+    /// Cannot read the archive.
+    ReadArchive(io::Error),
+
+    /// Cannot create the directory the archive is unpacked into.
+    CreateDirectory(io::Error),
+
+    /// Cannot spawn the thread packing or unpacking the archive.
+    ThreadSpawn(io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+// Reads from `inner`, reporting the progress of the operation, and failing
+// once the operation has been cancelled.
+struct ProgressReader<'a, R: Read> {
+    inner: R,
+    operation: &'a Operation,
+    bytes: u64,
+}
+
+impl<'a, R: Read> Read for ProgressReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.operation.cancel_requested() {
+            return Err(io::Error::new(io::ErrorKind::Other, "cancelled"));
+        }
+        let count = self.inner.read(buf)?;
+        self.bytes += count as u64;
+        self.operation.set_progress(self.bytes);
+        Ok(count)
+    }
+}
+
+fn read_config(path: &Path) -> Result<VmConfig> {
+    let file = File::open(path).map_err(|e| Error::ReadFile(path.to_path_buf(), e))?;
+    serde_json::from_reader(file).map_err(Error::ParseConfig)
+}
+
+fn disk_name(index: usize, overlay: bool) -> PathBuf {
+    let name = if overlay {
+        format!("{}-overlay", index)
+    } else {
+        index.to_string()
+    };
+    Path::new(DISKS_DIR).join(name)
+}
+
+// The files to archive, along with their name in the archive.
+fn archive_files(snapshot: &Path, disks: bool) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut files = Vec::new();
+    for name in SNAPSHOT_FILES.iter() {
+        let path = snapshot.join(name);
+        if !path.is_file() {
+            return Err(Error::MissingFile(path));
+        }
+        files.push((path, PathBuf::from(name)));
+    }
+
+    if disks {
+        let config = read_config(&snapshot.join(CONFIG_FILE))?;
+        for (index, disk) in config.disks.iter().flatten().enumerate() {
+            if disk.vhost_user {
+                continue;
+            }
+            let images = std::iter::once((&disk.path, false))
+                .chain(disk.overlay.iter().map(|overlay| (overlay, true)));
+            for (path, overlay) in images {
+                // Block devices and the like can't be archived.
+                if path.is_file() {
+                    files.push((path.clone(), disk_name(index, overlay)));
+                } else {
+                    warn!("Not exporting the disk {:?}, it's not a file", path);
+                }
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+fn write_archive(
+    files: &[(PathBuf, PathBuf)],
+    archive: &Path,
+    operation: &Operation,
+) -> Result<()> {
+    let file = File::create(archive).map_err(Error::WriteArchive)?;
+    let encoder = zstd::Encoder::new(file, ZSTD_LEVEL).map_err(Error::WriteArchive)?;
+    let mut builder = tar::Builder::new(encoder);
+
+    let mut bytes = 0;
+    for (path, name) in files {
+        let file = File::open(path).map_err(|e| Error::ReadFile(path.clone(), e))?;
+        let metadata = file
+            .metadata()
+            .map_err(|e| Error::ReadFile(path.clone(), e))?;
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&metadata);
+
+        let reader = ProgressReader {
+            inner: file,
+            operation,
+            bytes,
+        };
+        builder
+            .append_data(&mut header, name, reader)
+            .map_err(Error::WriteArchive)?;
+        bytes += metadata.len();
+    }
+
+    builder
+        .into_inner()
+        .and_then(|encoder| encoder.finish())
+        .and_then(|file| file.sync_all())
+        .map_err(Error::WriteArchive)
+}
+
+fn read_archive(archive: &Path, destination: &Path, operation: &Operation) -> Result<()> {
+    let file = File::open(archive).map_err(Error::ReadArchive)?;
+    let reader = ProgressReader {
+        inner: file,
+        operation,
+        bytes: 0,
+    };
+    let decoder = zstd::Decoder::new(reader).map_err(Error::ReadArchive)?;
+    let mut entries = tar::Archive::new(decoder);
+    for entry in entries.entries().map_err(Error::ReadArchive)? {
+        // Entries trying to escape the destination, e.g. through "..", are
+        // skipped.
+        entry
+            .and_then(|mut entry| entry.unpack_in(destination))
+            .map_err(Error::ReadArchive)?;
+    }
+
+    // The configuration points to the disk images on the host the snapshot
+    // was exported from.
+    let disks_dir = destination.join(DISKS_DIR);
+    if disks_dir.is_dir() {
+        let config_path = destination.join(CONFIG_FILE);
+        let mut config = read_config(&config_path)?;
+        for (index, disk) in config.disks.iter_mut().flatten().enumerate() {
+            let path = destination.join(disk_name(index, false));
+            if path.is_file() {
+                disk.path = path;
+            }
+            let overlay = destination.join(disk_name(index, true));
+            if overlay.is_file() {
+                disk.overlay = Some(overlay);
+            }
+        }
+        let data = serde_json::to_vec(&config).map_err(Error::ParseConfig)?;
+        fs::write(&config_path, data).map_err(Error::WriteConfig)?;
+    }
+
+    Ok(())
+}
+
+// Sets the final phase of the operation, cleaning up after a failure.
+fn finish(operation: &Operation, result: Result<()>, cleanup: impl FnOnce()) {
+    match result {
+        Ok(()) => operation.complete(),
+        Err(_) if operation.cancel_requested() => {
+            cleanup();
+            operation.set_cancelled();
+        }
+        Err(e) => {
+            error!("{} failed: {:?}", operation.kind(), e);
+            cleanup();
+            operation.fail(format!("{:?}", e));
+        }
+    }
+}
+
+/// Packs the snapshot saved in the directory `snapshot` into the archive
+/// `archive`, along with the disk images if `disks` is set. The archive is
+/// written in the background, the returned operation reporting its progress.
+pub fn export(snapshot: &Path, archive: &Path, disks: bool) -> Result<Arc<Operation>> {
+    let files = archive_files(snapshot, disks)?;
+    let mut bytes_total = 0;
+    for (path, _) in files.iter() {
+        bytes_total += fs::metadata(path)
+            .map_err(|e| Error::ReadFile(path.clone(), e))?
+            .len();
+    }
+
+    let operation = Arc::new(Operation::new("snapshot_export", bytes_total));
+    let thread_operation = operation.clone();
+    let archive = archive.to_path_buf();
+    thread::Builder::new()
+        .name("snapshot_export".to_string())
+        .spawn(move || {
+            let result = write_archive(&files, &archive, &thread_operation);
+            // An incomplete archive is useless.
+            finish(&thread_operation, result, || {
+                let _ = fs::remove_file(&archive);
+            });
+        })
+        .map_err(Error::ThreadSpawn)?;
+
+    Ok(operation)
+}
+
+/// Unpacks the snapshot archive `archive` into the directory `destination`,
+/// in the background, the returned operation reporting its progress.
+pub fn import(archive: &Path, destination: &Path) -> Result<Arc<Operation>> {
+    let bytes_total = fs::metadata(archive).map_err(Error::ReadArchive)?.len();
+
+    // Only a directory created here is removed on failure.
+    let created = !destination.exists();
+    fs::create_dir_all(destination).map_err(Error::CreateDirectory)?;
+
+    let operation = Arc::new(Operation::new("snapshot_import", bytes_total));
+    let thread_operation = operation.clone();
+    let archive = archive.to_path_buf();
+    let destination = destination.to_path_buf();
+    thread::Builder::new()
+        .name("snapshot_import".to_string())
+        .spawn(move || {
+            let result = read_archive(&archive, &destination, &thread_operation);
+            finish(&thread_operation, result, || {
+                if created {
+                    let _ = fs::remove_dir_all(&destination);
+                }
+            });
+        })
+        .map_err(Error::ThreadSpawn)?;
+
+    Ok(operation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_name() {
+        assert_eq!(disk_name(0, false), PathBuf::from("disks/0"));
+        assert_eq!(disk_name(2, true), PathBuf::from("disks/2-overlay"));
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let snapshot = tempfile::tempdir().unwrap();
+        match archive_files(snapshot.path(), false) {
+            Err(Error::MissingFile(path)) => {
+                assert_eq!(path, snapshot.path().join("config.json"))
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,312 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::config::SriovVfConfig;
+use net_util::MacAddr;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::result;
+
+const SYSFS_NET_PATH: &str = "/sys/class/net";
+const SYSFS_PCI_DEVICES_PATH: &str = "/sys/bus/pci/devices";
+const SYSFS_PCI_DRIVERS_PROBE_PATH: &str = "/sys/bus/pci/drivers_probe";
+const VFIO_PCI_DRIVER: &str = "vfio-pci";
+
+// Netlink attributes configuring the VFs of a PF, from
+// include/uapi/linux/if_link.h
+const IFLA_VFINFO_LIST: u16 = 22;
+const IFLA_VF_INFO: u16 = 1;
+const IFLA_VF_MAC: u16 = 1;
+const IFLA_VF_VLAN: u16 = 2;
+const NLMSG_HDRLEN: usize = 16;
+// Room for the error code and the original request header in the ack.
+const NLMSG_ACK_LEN: usize = NLMSG_HDRLEN + 4 + NLMSG_HDRLEN;
+// struct ifla_vf_mac holds a 32 bytes address.
+const IFLA_VF_MAC_ADDR_LEN: usize = 32;
+
+/// Errors associated with the SR-IOV VFs management.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot access a PF or VF sysfs attribute.
+    Sysfs(PathBuf, io::Error),
+
+    /// The PF doesn't have that many VFs enabled.
+    VfIndexOutOfRange(u32),
+
+    /// The PF network interface doesn't exist.
+    UnknownPf(String),
+
+    /// Cannot open the netlink socket.
+    NetlinkSocket(io::Error),
+
+    /// The netlink request configuring the VF failed.
+    NetlinkRequest(io::Error),
+
+    /// The VF didn't get bound to the vfio-pci driver.
+    VfioBind(String),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+fn read_sysfs(path: &Path) -> Result<String> {
+    fs::read_to_string(path)
+        .map(|s| s.trim().to_string())
+        .map_err(|e| Error::Sysfs(path.to_path_buf(), e))
+}
+
+fn write_sysfs(path: &Path, value: &str) -> Result<()> {
+    fs::write(path, value).map_err(|e| Error::Sysfs(path.to_path_buf(), e))
+}
+
+// Name of the driver a PCI device is bound to, if any.
+fn pci_driver(pci_address: &str) -> Option<String> {
+    fs::read_link(
+        Path::new(SYSFS_PCI_DEVICES_PATH)
+            .join(pci_address)
+            .join("driver"),
+    )
+    .ok()
+    .and_then(|p| p.file_name().map(|f| f.to_string_lossy().into_owned()))
+}
+
+fn bound_to_vfio(pci_address: &str) -> bool {
+    pci_driver(pci_address).map_or(false, |driver| driver == VFIO_PCI_DRIVER)
+}
+
+/// PCI address of the VF `index` of the PF `pf`, e.g. 0000:03:10.0
+fn vf_pci_address(pf: &str, index: u32) -> Result<String> {
+    let path = Path::new(SYSFS_NET_PATH)
+        .join(pf)
+        .join("device")
+        .join(format!("virtfn{}", index));
+    let target = fs::read_link(&path).map_err(|e| Error::Sysfs(path.clone(), e))?;
+
+    Ok(target
+        .file_name()
+        .map(|f| f.to_string_lossy().into_owned())
+        .unwrap_or_default())
+}
+
+/// Sysfs path of the VF `index` of the PF `pf`, as expected by VFIO.
+pub fn vf_sysfs_path(pf: &str, index: u32) -> Result<PathBuf> {
+    Ok(Path::new(SYSFS_PCI_DEVICES_PATH).join(vf_pci_address(pf, index)?))
+}
+
+fn push_netlink_attr(buf: &mut Vec<u8>, attr_type: u16, payload: &[u8]) {
+    let len = 4 + payload.len();
+    buf.extend_from_slice(&(len as u16).to_ne_bytes());
+    buf.extend_from_slice(&attr_type.to_ne_bytes());
+    buf.extend_from_slice(payload);
+    // Attributes are 4 bytes aligned.
+    buf.resize((buf.len() + 3) & !3, 0);
+}
+
+// Send a RTM_SETLINK request on the PF, describing the VF settings, and
+// wait for the kernel to acknowledge it.
+fn set_vf_link(pf_index: u32, vf_info: &[u8]) -> Result<()> {
+    let mut vf_info_attr = Vec::new();
+    push_netlink_attr(&mut vf_info_attr, IFLA_VF_INFO, vf_info);
+    let mut attrs = Vec::new();
+    push_netlink_attr(&mut attrs, IFLA_VFINFO_LIST, &vf_info_attr);
+
+    // struct nlmsghdr, followed by struct ifinfomsg
+    let len = NLMSG_HDRLEN + 16 + attrs.len();
+    let mut msg = Vec::with_capacity(len);
+    msg.extend_from_slice(&(len as u32).to_ne_bytes());
+    msg.extend_from_slice(&libc::RTM_SETLINK.to_ne_bytes());
+    msg.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16).to_ne_bytes());
+    msg.extend_from_slice(&1u32.to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg.push(libc::AF_UNSPEC as u8);
+    msg.push(0);
+    msg.extend_from_slice(&0u16.to_ne_bytes());
+    msg.extend_from_slice(&(pf_index as i32).to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg.extend_from_slice(&attrs);
+
+    // Safe because we check the return value.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::NetlinkSocket(io::Error::last_os_error()));
+    }
+    // Safe because the file descriptor is valid and owned by nothing else.
+    // An unbound netlink socket talks to the kernel.
+    let mut socket = unsafe { File::from_raw_fd(fd) };
+
+    socket.write_all(&msg).map_err(Error::NetlinkRequest)?;
+
+    let mut ack = [0u8; NLMSG_ACK_LEN];
+    let count = socket.read(&mut ack).map_err(Error::NetlinkRequest)?;
+    let msg_type = u16::from_ne_bytes([ack[4], ack[5]]);
+    if count < NLMSG_HDRLEN + 4 || msg_type != libc::NLMSG_ERROR as u16 {
+        return Err(Error::NetlinkRequest(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected netlink reply",
+        )));
+    }
+
+    // A zero error code acknowledges the request.
+    let error = i32::from_ne_bytes([ack[16], ack[17], ack[18], ack[19]]);
+    if error != 0 {
+        return Err(Error::NetlinkRequest(io::Error::from_raw_os_error(-error)));
+    }
+
+    Ok(())
+}
+
+fn set_vf_mac(pf_index: u32, vf: u32, mac: &MacAddr) -> Result<()> {
+    // struct ifla_vf_mac
+    let mut vf_mac = Vec::with_capacity(4 + IFLA_VF_MAC_ADDR_LEN);
+    vf_mac.extend_from_slice(&vf.to_ne_bytes());
+    vf_mac.extend_from_slice(mac.get_bytes());
+    vf_mac.resize(4 + IFLA_VF_MAC_ADDR_LEN, 0);
+
+    let mut vf_info = Vec::new();
+    push_netlink_attr(&mut vf_info, IFLA_VF_MAC, &vf_mac);
+    set_vf_link(pf_index, &vf_info)
+}
+
+fn set_vf_vlan(pf_index: u32, vf: u32, vlan: u16) -> Result<()> {
+    // struct ifla_vf_vlan, with no QoS
+    let mut vf_vlan = Vec::with_capacity(12);
+    vf_vlan.extend_from_slice(&vf.to_ne_bytes());
+    vf_vlan.extend_from_slice(&u32::from(vlan).to_ne_bytes());
+    vf_vlan.extend_from_slice(&0u32.to_ne_bytes());
+
+    let mut vf_info = Vec::new();
+    push_netlink_attr(&mut vf_info, IFLA_VF_VLAN, &vf_vlan);
+    set_vf_link(pf_index, &vf_info)
+}
+
+/// A SR-IOV VF, set up and bound to vfio-pci on behalf of the VM. It is
+/// given back to its original driver when dropped, and the VFs are removed
+/// from the PF if they have been created for the VM.
+pub struct VirtualFunction {
+    pf: String,
+    pci_address: String,
+    original_driver: Option<String>,
+    bound_to_vfio: bool,
+    created_vfs: bool,
+}
+
+impl VirtualFunction {
+    pub fn new(config: &SriovVfConfig) -> Result<Self> {
+        let numvfs_path = Path::new(SYSFS_NET_PATH)
+            .join(&config.pf)
+            .join("device")
+            .join("sriov_numvfs");
+
+        // The VFs are only created if there are none yet, as changing their
+        // number would remove the ones possibly used by someone else.
+        let numvfs: u32 = read_sysfs(&numvfs_path)?.parse().unwrap_or(0);
+        let created_vfs = if numvfs == 0 {
+            write_sysfs(&numvfs_path, &(config.index + 1).to_string())?;
+            true
+        } else if config.index >= numvfs {
+            return Err(Error::VfIndexOutOfRange(config.index));
+        } else {
+            false
+        };
+
+        let mut vf = VirtualFunction {
+            pf: config.pf.clone(),
+            pci_address: String::new(),
+            original_driver: None,
+            bound_to_vfio: false,
+            created_vfs,
+        };
+
+        // The MAC address and VLAN are set through the PF, while the VF
+        // driver has no say about it.
+        if config.mac.is_some() || config.vlan.is_some() {
+            let pf_name =
+                CString::new(config.pf.clone()).map_err(|_| Error::UnknownPf(config.pf.clone()))?;
+            // Safe because pf_name is a valid nul terminated string.
+            let pf_index = unsafe { libc::if_nametoindex(pf_name.as_ptr()) };
+            if pf_index == 0 {
+                return Err(Error::UnknownPf(config.pf.clone()));
+            }
+
+            if let Some(mac) = &config.mac {
+                set_vf_mac(pf_index, config.index, mac)?;
+            }
+            if let Some(vlan) = config.vlan {
+                set_vf_vlan(pf_index, config.index, vlan)?;
+            }
+        }
+
+        vf.pci_address = vf_pci_address(&config.pf, config.index)?;
+        let device_path = Path::new(SYSFS_PCI_DEVICES_PATH).join(&vf.pci_address);
+
+        match pci_driver(&vf.pci_address) {
+            // Already bound, most likely by the operator, who keeps
+            // managing it.
+            Some(ref driver) if driver == VFIO_PCI_DRIVER => return Ok(vf),
+            Some(driver) => {
+                write_sysfs(&device_path.join("driver").join("unbind"), &vf.pci_address)?;
+                vf.original_driver = Some(driver);
+            }
+            None => {}
+        }
+
+        vf.bound_to_vfio = true;
+        write_sysfs(&device_path.join("driver_override"), VFIO_PCI_DRIVER)?;
+        write_sysfs(Path::new(SYSFS_PCI_DRIVERS_PROBE_PATH), &vf.pci_address)?;
+
+        if !bound_to_vfio(&vf.pci_address) {
+            return Err(Error::VfioBind(vf.pci_address.clone()));
+        }
+
+        Ok(vf)
+    }
+
+    fn release(&mut self) -> Result<()> {
+        if self.bound_to_vfio {
+            let device_path = Path::new(SYSFS_PCI_DEVICES_PATH).join(&self.pci_address);
+
+            if bound_to_vfio(&self.pci_address) {
+                write_sysfs(
+                    &device_path.join("driver").join("unbind"),
+                    &self.pci_address,
+                )?;
+            }
+            write_sysfs(&device_path.join("driver_override"), "\n")?;
+
+            if self.original_driver.is_some() {
+                write_sysfs(Path::new(SYSFS_PCI_DRIVERS_PROBE_PATH), &self.pci_address)?;
+            }
+        }
+
+        if self.created_vfs {
+            let numvfs_path = Path::new(SYSFS_NET_PATH)
+                .join(&self.pf)
+                .join("device")
+                .join("sriov_numvfs");
+            write_sysfs(&numvfs_path, "0")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for VirtualFunction {
+    fn drop(&mut self) {
+        if let Err(e) = self.release() {
+            error!(
+                "Failed releasing VF {} of {}: {:?}",
+                self.pci_address, self.pf, e
+            );
+        }
+    }
+}
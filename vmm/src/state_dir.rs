@@ -0,0 +1,131 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! The per-VM state directory, given with `--state-dir`, where everything
+//! needed to bring the VM back lives, so that backing it up is a matter of
+//! copying the directory. Its layout is:
+//!
+//! - `config.json`: the effective VM configuration, rewritten every time it
+//!   changes, e.g. after a resize or a volume attachment.
+//! - `snapshots/`: the VM snapshots whose destination is a relative path,
+//!   including `snapshots/hibernate`, saved when the guest hibernates.
+//! - The serial and virtio-console output files given as relative paths,
+//!   e.g. `--serial file=serial.log`.
+//! - `nvram/` and `tpm/`: reserved for the UEFI variable store and the TPM
+//!   state, once those devices are supported.
+
+use crate::config::VmConfig;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+/// Effective VM configuration.
+pub const CONFIG_FILE: &str = "config.json";
+/// VM snapshots.
+pub const SNAPSHOTS_DIR: &str = "snapshots";
+/// Snapshot the guest hibernated into, in the snapshots directory.
+pub const HIBERNATE_SNAPSHOT: &str = "hibernate";
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot create the state directory.
+    Create(io::Error),
+    /// Cannot serialize the VM configuration.
+    SerializeConfig(serde_json::Error),
+    /// Cannot write the VM configuration.
+    WriteConfig(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Resolves a path relative to the state directory, if any. Absolute paths
+/// are left untouched.
+pub fn resolve(state_dir: Option<&Path>, path: &Path) -> PathBuf {
+    match state_dir {
+        Some(state_dir) => state_dir.join(path),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Where the snapshot the guest hibernated into is saved.
+pub fn hibernate_snapshot(state_dir: &Path) -> PathBuf {
+    state_dir.join(SNAPSHOTS_DIR).join(HIBERNATE_SNAPSHOT)
+}
+
+/// Returns the snapshot the guest hibernated into, if there's a complete
+/// one, i.e. whose vCPUs and devices state got written, which only happens
+/// once the guest memory is.
+pub fn hibernated(state_dir: &Path) -> Option<PathBuf> {
+    let snapshot = hibernate_snapshot(state_dir);
+    if snapshot.join(CONFIG_FILE).is_file()
+        && snapshot.join("memory").is_file()
+        && snapshot.join("state.json").is_file()
+    {
+        Some(snapshot)
+    } else {
+        None
+    }
+}
+
+/// Writes the VM configuration into its state directory, creating the
+/// directory if needed. The previous configuration is replaced atomically,
+/// so that a crash never leaves a truncated one behind.
+pub fn save_config(state_dir: &Path, config: &VmConfig) -> Result<()> {
+    // The directory ends up holding the guest memory, through the snapshots,
+    // so it is only accessible to its owner.
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(state_dir)
+        .map_err(Error::Create)?;
+
+    let data = serde_json::to_vec_pretty(config).map_err(Error::SerializeConfig)?;
+    let tmp_path = state_dir.join(format!("{}.tmp", CONFIG_FILE));
+    fs::write(&tmp_path, data).map_err(Error::WriteConfig)?;
+    fs::rename(&tmp_path, state_dir.join(CONFIG_FILE)).map_err(Error::WriteConfig)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let state_dir = Path::new("/var/lib/vm0");
+
+        assert_eq!(
+            resolve(Some(state_dir), Path::new("serial.log")),
+            PathBuf::from("/var/lib/vm0/serial.log")
+        );
+        assert_eq!(
+            resolve(Some(state_dir), Path::new("/tmp/serial.log")),
+            PathBuf::from("/tmp/serial.log")
+        );
+        assert_eq!(
+            resolve(None, Path::new("serial.log")),
+            PathBuf::from("serial.log")
+        );
+    }
+
+    #[test]
+    fn test_hibernated() {
+        let state_dir = tempfile::tempdir().unwrap();
+        assert_eq!(hibernated(state_dir.path()), None);
+
+        let snapshot = hibernate_snapshot(state_dir.path());
+        fs::create_dir_all(&snapshot).unwrap();
+        fs::write(snapshot.join(CONFIG_FILE), "{}").unwrap();
+        // Without its memory, the snapshot is incomplete.
+        assert_eq!(hibernated(state_dir.path()), None);
+
+        fs::write(snapshot.join("memory"), "").unwrap();
+        // Nor until the vCPUs and devices state is saved, after the memory.
+        assert_eq!(hibernated(state_dir.path()), None);
+
+        fs::write(snapshot.join("state.json"), "{}").unwrap();
+        assert_eq!(hibernated(state_dir.path()), Some(snapshot));
+    }
+}
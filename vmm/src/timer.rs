@@ -0,0 +1,508 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Timers for the VMM subsystems and the devices, so that a periodic task
+//! runs from the VMM control loop rather than from a thread of its own
+//! sleeping in between.
+//!
+//! The timers are kept in a hashed timer wheel, their deadlines being
+//! rounded up to the tick of the wheel. A timerfd, part of the control loop
+//! epoll set, fires at every tick for as long as some timer is pending, and
+//! is disarmed otherwise so that an idle VMM doesn't wake up.
+//!
+//! The callbacks run on the control loop thread, hence they must be short
+//! and must not block. The ones which may block, e.g. probing a file or
+//! scanning the guest memory, are scheduled with `schedule_blocking()`
+//! instead, to run on a worker thread, reporting to the control loop through
+//! an eventfd when needed.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+use vmm_sys_util::errno;
+use vmm_sys_util::timerfd::TimerFd;
+
+// Timers due within that many ticks don't need to go around the wheel.
+const WHEEL_SLOTS: usize = 256;
+
+/// Errors associated with the timers.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot create the timerfd.
+    TimerFdCreate(errno::Error),
+
+    /// Cannot arm or disarm the timerfd.
+    TimerFdArm(errno::Error),
+
+    /// Cannot read the timerfd expirations.
+    TimerFdRead(errno::Error),
+
+    /// Cannot spawn the worker thread.
+    WorkerSpawn(io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+pub type TimerCallback = Box<dyn FnMut() + Send>;
+
+/// Identifies a scheduled timer, to cancel it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+// A callback running on the worker thread, which is skipped when the
+// previous run hasn't completed yet.
+struct BlockingCallback {
+    callback: Mutex<TimerCallback>,
+    // Set until the run completes.
+    queued: AtomicBool,
+    cancelled: AtomicBool,
+}
+
+enum Callback {
+    Inline(TimerCallback),
+    Blocking(Arc<BlockingCallback>),
+}
+
+struct Entry {
+    id: TimerId,
+    // Times the wheel has to go around before the timer expires.
+    rounds: u64,
+    period: Option<u64>,
+    callback: Callback,
+}
+
+struct Wheel {
+    timer: TimerFd,
+    slots: Vec<Vec<Entry>>,
+    current: usize,
+    pending: usize,
+    // The timers whose callback is running, which are only rescheduled if
+    // they haven't been cancelled in the meantime.
+    running: HashSet<TimerId>,
+    // The blocking timers, for as long as their callback is around, so that
+    // cancelling one waits for its run in progress, even past its expiry.
+    blocking: HashMap<TimerId, Weak<BlockingCallback>>,
+    next_id: u64,
+}
+
+impl Wheel {
+    fn insert(&mut self, ticks: u64, mut entry: Entry, tick: Duration) -> Result<()> {
+        let ticks = ticks.max(1);
+        entry.rounds = (ticks - 1) / WHEEL_SLOTS as u64;
+        let slot = (self.current + (ticks % WHEEL_SLOTS as u64) as usize) % WHEEL_SLOTS;
+        self.slots[slot].push(entry);
+
+        self.pending += 1;
+        if self.pending == 1 {
+            self.timer
+                .reset(tick, Some(tick))
+                .map_err(Error::TimerFdArm)?;
+        }
+        Ok(())
+    }
+
+    fn removed(&mut self) -> Result<()> {
+        self.pending -= 1;
+        if self.pending == 0 {
+            self.timer.clear().map_err(Error::TimerFdArm)?;
+        }
+        Ok(())
+    }
+
+    // Moves the wheel one tick forward, returning the timers expiring.
+    fn advance(&mut self) -> Vec<Entry> {
+        self.current = (self.current + 1) % WHEEL_SLOTS;
+        let entries = std::mem::take(&mut self.slots[self.current]);
+        let mut expired = Vec::new();
+        for mut entry in entries {
+            if entry.rounds == 0 {
+                expired.push(entry);
+            } else {
+                entry.rounds -= 1;
+                self.slots[self.current].push(entry);
+            }
+        }
+        expired
+    }
+}
+
+pub struct TimerWheel {
+    tick: Duration,
+    // Kept apart from the wheel, not to take its lock to poll it.
+    timer_fd: RawFd,
+    wheel: Mutex<Wheel>,
+    // Spawned along with the first blocking timer.
+    worker: Mutex<Option<(Sender<Arc<BlockingCallback>>, thread::JoinHandle<()>)>>,
+}
+
+impl TimerWheel {
+    /// Creates a timer wheel with a resolution of `tick`.
+    pub fn new(tick: Duration) -> Result<Self> {
+        let timer = TimerFd::new().map_err(Error::TimerFdCreate)?;
+        Ok(TimerWheel {
+            tick,
+            timer_fd: timer.as_raw_fd(),
+            wheel: Mutex::new(Wheel {
+                timer,
+                slots: (0..WHEEL_SLOTS).map(|_| Vec::new()).collect(),
+                current: 0,
+                pending: 0,
+                running: HashSet::new(),
+                blocking: HashMap::new(),
+                next_id: 0,
+            }),
+            worker: Mutex::new(None),
+        })
+    }
+
+    pub fn tick(&self) -> Duration {
+        self.tick
+    }
+
+    fn ticks(&self, duration: Duration) -> u64 {
+        let tick = self.tick.as_nanos().max(1);
+        ((duration.as_nanos() + tick - 1) / tick) as u64
+    }
+
+    /// Runs `callback` once `delay` has elapsed, then every `period` if
+    /// any, until the timer is cancelled.
+    pub fn schedule(
+        &self,
+        delay: Duration,
+        period: Option<Duration>,
+        callback: TimerCallback,
+    ) -> Result<TimerId> {
+        self.insert(delay, period, Callback::Inline(callback))
+    }
+
+    /// Same as `schedule()`, for a callback which may block, running on the
+    /// worker thread. A periodic run is skipped while the previous one is
+    /// still going on.
+    pub fn schedule_blocking(
+        &self,
+        delay: Duration,
+        period: Option<Duration>,
+        callback: TimerCallback,
+    ) -> Result<TimerId> {
+        {
+            let mut worker = self.worker.lock().unwrap();
+            if worker.is_none() {
+                let (sender, receiver) = channel::<Arc<BlockingCallback>>();
+                let thread = thread::Builder::new()
+                    .name("timer_worker".to_string())
+                    .spawn(move || {
+                        for blocking in receiver.iter() {
+                            let mut callback = blocking.callback.lock().unwrap();
+                            if !blocking.cancelled.load(Ordering::SeqCst) {
+                                (callback)();
+                            }
+                            blocking.queued.store(false, Ordering::SeqCst);
+                        }
+                    })
+                    .map_err(Error::WorkerSpawn)?;
+                *worker = Some((sender, thread));
+            }
+        }
+
+        let blocking = BlockingCallback {
+            callback: Mutex::new(callback),
+            queued: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+        };
+        self.insert(delay, period, Callback::Blocking(Arc::new(blocking)))
+    }
+
+    fn insert(
+        &self,
+        delay: Duration,
+        period: Option<Duration>,
+        callback: Callback,
+    ) -> Result<TimerId> {
+        let mut wheel = self.wheel.lock().unwrap();
+        let id = TimerId(wheel.next_id);
+        wheel.next_id += 1;
+
+        if let Callback::Blocking(blocking) = &callback {
+            wheel
+                .blocking
+                .retain(|_, blocking| blocking.strong_count() > 0);
+            wheel.blocking.insert(id, Arc::downgrade(blocking));
+        }
+
+        let entry = Entry {
+            id,
+            rounds: 0,
+            period: period.map(|period| self.ticks(period)),
+            callback,
+        };
+        wheel.insert(self.ticks(delay), entry, self.tick)?;
+
+        Ok(id)
+    }
+
+    /// Cancels the timer `id`, returning whether it was still scheduled.
+    /// The callback of a blocking timer is not running anymore once this
+    /// returns, unless cancelled from the callback itself.
+    pub fn cancel(&self, id: TimerId) -> Result<bool> {
+        let mut wheel = self.wheel.lock().unwrap();
+        let blocking = wheel
+            .blocking
+            .remove(&id)
+            .and_then(|blocking| blocking.upgrade());
+
+        let mut found = wheel.running.remove(&id);
+        if !found {
+            for slot in wheel.slots.iter_mut() {
+                if let Some(index) = slot.iter().position(|entry| entry.id == id) {
+                    slot.remove(index);
+                    found = true;
+                    break;
+                }
+            }
+            if found {
+                wheel.removed()?;
+            }
+        }
+        drop(wheel);
+
+        // Without holding the lock, as the callback may be scheduling or
+        // cancelling timers.
+        if let Some(blocking) = blocking {
+            blocking.cancelled.store(true, Ordering::SeqCst);
+            if !self.on_worker() {
+                drop(blocking.callback.lock().unwrap());
+            }
+        }
+
+        Ok(found)
+    }
+
+    // Whether the caller is a blocking callback, which would wait for itself.
+    fn on_worker(&self) -> bool {
+        self.worker
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(false, |(_, thread)| {
+                thread.thread().id() == thread::current().id()
+            })
+    }
+
+    /// Runs the callbacks of the expired timers, once the timerfd fired.
+    pub fn process(&self) -> Result<()> {
+        let mut expired = Vec::new();
+        {
+            let mut wheel = self.wheel.lock().unwrap();
+            // The last timer may have been cancelled since the timerfd fired,
+            // resetting its expirations.
+            if wheel.pending == 0 {
+                return Ok(());
+            }
+            let ticks = wheel.timer.wait().map_err(Error::TimerFdRead)?;
+            for _ in 0..ticks {
+                expired.append(&mut wheel.advance());
+            }
+            for entry in expired.iter() {
+                wheel.running.insert(entry.id);
+            }
+        }
+
+        // Without holding the lock, so that the callbacks can schedule or
+        // cancel timers.
+        for entry in expired.iter_mut() {
+            match &mut entry.callback {
+                Callback::Inline(callback) => callback(),
+                Callback::Blocking(blocking) => {
+                    if !blocking.queued.swap(true, Ordering::SeqCst) {
+                        let worker = self.worker.lock().unwrap();
+                        let sender = &worker.as_ref().unwrap().0;
+                        // The worker only goes away with the wheel.
+                        sender.send(blocking.clone()).unwrap();
+                    }
+                }
+            }
+        }
+
+        let mut wheel = self.wheel.lock().unwrap();
+        for entry in expired {
+            let running = wheel.running.remove(&entry.id);
+            wheel.pending -= 1;
+            match entry.period {
+                Some(period) if running => wheel.insert(period, entry, self.tick)?,
+                _ => {
+                    // Cancelled from another callback, while maybe queued.
+                    if let Callback::Blocking(blocking) = &entry.callback {
+                        blocking.cancelled.store(!running, Ordering::SeqCst);
+                    }
+                }
+            }
+        }
+        if wheel.pending == 0 {
+            wheel.timer.clear().map_err(Error::TimerFdArm)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for TimerWheel {
+    fn drop(&mut self) {
+        if let Some((sender, thread)) = self.worker.lock().unwrap().take() {
+            drop(sender);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl AsRawFd for TimerWheel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer_fd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc::sync_channel;
+
+    fn counter(count: &Arc<AtomicUsize>) -> TimerCallback {
+        let count = count.clone();
+        Box::new(move || {
+            count.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    #[test]
+    fn test_timer_wheel() {
+        let timers = TimerWheel::new(Duration::from_millis(1)).unwrap();
+        let once = Arc::new(AtomicUsize::new(0));
+        let periodic = Arc::new(AtomicUsize::new(0));
+
+        timers
+            .schedule(Duration::from_millis(2), None, counter(&once))
+            .unwrap();
+        let id = timers
+            .schedule(
+                Duration::from_millis(1),
+                Some(Duration::from_millis(1)),
+                counter(&periodic),
+            )
+            .unwrap();
+
+        while periodic.load(Ordering::SeqCst) < 3 {
+            timers.process().unwrap();
+        }
+        assert_eq!(once.load(Ordering::SeqCst), 1);
+
+        assert!(timers.cancel(id).unwrap());
+        assert!(!timers.cancel(id).unwrap());
+        assert_eq!(timers.wheel.lock().unwrap().pending, 0);
+    }
+
+    #[test]
+    fn test_blocking_timer() {
+        let timers = TimerWheel::new(Duration::from_millis(1)).unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+        let (release, blocked) = sync_channel::<()>(0);
+        let callback_count = count.clone();
+        let id = timers
+            .schedule_blocking(
+                Duration::from_millis(1),
+                Some(Duration::from_millis(1)),
+                Box::new(move || {
+                    callback_count.fetch_add(1, Ordering::SeqCst);
+                    assert_eq!(thread::current().name(), Some("timer_worker"));
+                    blocked.recv().unwrap();
+                }),
+            )
+            .unwrap();
+
+        // The control loop goes on while the callback blocks, the runs
+        // expiring meanwhile being skipped.
+        while count.load(Ordering::SeqCst) == 0 {
+            timers.process().unwrap();
+        }
+        for _ in 0..10 {
+            timers.process().unwrap();
+        }
+        release.send(()).unwrap();
+        while count.load(Ordering::SeqCst) < 2 {
+            timers.process().unwrap();
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        release.send(()).unwrap();
+        assert!(timers.cancel(id).unwrap());
+        let count_cancelled = count.load(Ordering::SeqCst);
+        drop(release);
+        drop(timers);
+        assert_eq!(count.load(Ordering::SeqCst), count_cancelled);
+    }
+
+    #[test]
+    fn test_cancel_running_blocking_timer() {
+        let timers = Arc::new(TimerWheel::new(Duration::from_millis(1)).unwrap());
+        let done = Arc::new(AtomicBool::new(false));
+        let (started_tx, started) = channel::<()>();
+        let (release, blocked) = sync_channel::<()>(0);
+        let callback_done = done.clone();
+        let id = timers
+            .schedule_blocking(
+                Duration::from_millis(1),
+                None,
+                Box::new(move || {
+                    started_tx.send(()).unwrap();
+                    blocked.recv().unwrap();
+                    callback_done.store(true, Ordering::SeqCst);
+                }),
+            )
+            .unwrap();
+
+        while started.try_recv().is_err() {
+            timers.process().unwrap();
+        }
+
+        // Cancelling from another thread waits for the run in progress.
+        let (cancelled_tx, cancelled) = channel();
+        let cancel_timers = timers.clone();
+        let cancel_done = done.clone();
+        let canceller = thread::spawn(move || {
+            cancel_timers.cancel(id).unwrap();
+            cancelled_tx
+                .send(cancel_done.load(Ordering::SeqCst))
+                .unwrap();
+        });
+        assert!(cancelled.recv_timeout(Duration::from_millis(100)).is_err());
+
+        release.send(()).unwrap();
+        assert!(cancelled.recv().unwrap());
+        canceller.join().unwrap();
+    }
+
+    #[test]
+    fn test_wheel_rounds() {
+        let timers = TimerWheel::new(Duration::from_millis(1)).unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+        timers
+            .schedule(
+                Duration::from_millis(WHEEL_SLOTS as u64 + 2),
+                None,
+                counter(&count),
+            )
+            .unwrap();
+
+        let mut wheel = timers.wheel.lock().unwrap();
+        for _ in 0..=WHEEL_SLOTS {
+            assert!(wheel.advance().is_empty());
+        }
+        assert_eq!(wheel.advance().len(), 1);
+    }
+}
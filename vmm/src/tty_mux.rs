@@ -0,0 +1,203 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Shares the terminal between the guest console and a VMM command prompt,
+//! the way the QEMU monitor does. The input goes to the guest, except for
+//! the escape sequences starting with `Ctrl-a`:
+//!
+//! - `Ctrl-a c`: switch between the guest console and the prompt.
+//! - `Ctrl-a x`: quit.
+//! - `Ctrl-a h`: print the escape sequences.
+//! - `Ctrl-a Ctrl-a`: send `Ctrl-a` to the guest.
+//!
+//! The prompt runs the `info`, `reboot` and `quit` commands.
+
+// Ctrl-a
+const ESCAPE: u8 = 0x01;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+pub const PROMPT: &str = "(cloud-hypervisor) ";
+
+const ESCAPE_HELP: &str = "\r\nC-a c    switch between console and prompt\r\n\
+                           C-a x    quit\r\n\
+                           C-a h    print this help\r\n\
+                           C-a C-a  send C-a\r\n";
+
+const PROMPT_HELP: &str = "info     show the VM state and resources\r\n\
+                           reboot   reboot the VM, back to the console\r\n\
+                           quit     shut the VM and the VMM down\r\n\
+                           help     print this help\r\n";
+
+/// A command for the VMM, from the prompt or from an escape sequence.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Info,
+    Reboot,
+    Quit,
+}
+
+/// What becomes of some terminal input.
+#[derive(Debug, Default, PartialEq)]
+pub struct Output {
+    /// The input for the guest.
+    pub guest: Vec<u8>,
+    /// What to write to the terminal, such as the echo of the prompt input.
+    pub terminal: Vec<u8>,
+    /// The commands to run, in order.
+    pub commands: Vec<Command>,
+}
+
+#[derive(Default)]
+pub struct TtyMux {
+    escape: bool,
+    prompt: bool,
+    line: Vec<u8>,
+}
+
+impl TtyMux {
+    pub fn new() -> Self {
+        TtyMux::default()
+    }
+
+    /// Whether the input goes to the prompt rather than to the guest.
+    pub fn prompting(&self) -> bool {
+        self.prompt
+    }
+
+    pub fn process(&mut self, input: &[u8]) -> Output {
+        let mut output = Output::default();
+
+        for &byte in input {
+            if self.escape {
+                self.escape = false;
+                match byte {
+                    b'c' => self.toggle(&mut output),
+                    b'x' => output.commands.push(Command::Quit),
+                    b'h' => {
+                        output.terminal.extend_from_slice(ESCAPE_HELP.as_bytes());
+                        if self.prompt {
+                            output.terminal.extend_from_slice(PROMPT.as_bytes());
+                            output.terminal.extend_from_slice(&self.line);
+                        }
+                    }
+                    ESCAPE if !self.prompt => output.guest.push(ESCAPE),
+                    // Unknown sequences are dropped.
+                    _ => {}
+                }
+            } else if byte == ESCAPE {
+                self.escape = true;
+            } else if self.prompt {
+                self.edit(byte, &mut output);
+            } else {
+                output.guest.push(byte);
+            }
+        }
+
+        output
+    }
+
+    fn toggle(&mut self, output: &mut Output) {
+        self.prompt = !self.prompt;
+        self.line.clear();
+        output.terminal.extend_from_slice(b"\r\n");
+        if self.prompt {
+            output.terminal.extend_from_slice(PROMPT.as_bytes());
+        }
+    }
+
+    // Line editing, the terminal being in raw mode.
+    fn edit(&mut self, byte: u8, output: &mut Output) {
+        match byte {
+            b'\r' | b'\n' => {
+                output.terminal.extend_from_slice(b"\r\n");
+                let line = String::from_utf8_lossy(&self.line).trim().to_string();
+                self.line.clear();
+                match line.as_str() {
+                    "" => output.terminal.extend_from_slice(PROMPT.as_bytes()),
+                    "info" => output.commands.push(Command::Info),
+                    "reboot" => {
+                        // The guest console is what's interesting next.
+                        self.prompt = false;
+                        output.commands.push(Command::Reboot);
+                    }
+                    "quit" => output.commands.push(Command::Quit),
+                    "help" => {
+                        output.terminal.extend_from_slice(PROMPT_HELP.as_bytes());
+                        output.terminal.extend_from_slice(PROMPT.as_bytes());
+                    }
+                    _ => {
+                        output.terminal.extend_from_slice(
+                            format!("Unknown command '{}', try 'help'\r\n{}", line, PROMPT)
+                                .as_bytes(),
+                        );
+                    }
+                }
+            }
+            BACKSPACE | DELETE => {
+                if self.line.pop().is_some() {
+                    output.terminal.extend_from_slice(b"\x08 \x08");
+                }
+            }
+            // Other control characters are dropped.
+            byte if byte < 0x20 => {}
+            byte => {
+                self.line.push(byte);
+                output.terminal.push(byte);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guest_input() {
+        let mut mux = TtyMux::new();
+
+        let output = mux.process(b"ls\r\x01\x01a\x01zb");
+        assert_eq!(output.guest, b"ls\r\x01ab".to_vec());
+        assert!(output.terminal.is_empty());
+        assert!(output.commands.is_empty());
+        assert!(!mux.prompting());
+
+        // The escape sequence can be split across reads.
+        assert!(mux.process(b"\x01").guest.is_empty());
+        assert_eq!(mux.process(b"x").commands, vec![Command::Quit]);
+    }
+
+    #[test]
+    fn test_prompt() {
+        let mut mux = TtyMux::new();
+
+        let output = mux.process(b"\x01c");
+        assert!(mux.prompting());
+        assert_eq!(output.terminal, format!("\r\n{}", PROMPT).into_bytes());
+
+        let output = mux.process(b"inff\x7fo\r");
+        assert!(output.guest.is_empty());
+        assert_eq!(output.terminal, b"inff\x08 \x08o\r\n".to_vec());
+        assert_eq!(output.commands, vec![Command::Info]);
+        assert!(mux.prompting());
+
+        let output = mux.process(b"foo\r");
+        assert!(output.commands.is_empty());
+        assert!(String::from_utf8(output.terminal)
+            .unwrap()
+            .ends_with(&format!("Unknown command 'foo', try 'help'\r\n{}", PROMPT)));
+
+        let output = mux.process(b"reboot\rls");
+        assert_eq!(output.commands, vec![Command::Reboot]);
+        assert_eq!(output.guest, b"ls".to_vec());
+        assert!(!mux.prompting());
+
+        mux.process(b"\x01c");
+        let output = mux.process(b"\x01cls");
+        assert!(!mux.prompting());
+        assert_eq!(output.guest, b"ls".to_vec());
+    }
+}
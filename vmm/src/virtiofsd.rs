@@ -0,0 +1,227 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Spawns virtiofsd on behalf of the fs devices configured with `spawn=on`,
+//! and supervises it: when it exits while the VM is running, a new instance
+//! is spawned and the device reconnects to it.
+
+use crate::config::{FsConfig, FsSandbox};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::result;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_VIRTIOFSD: &str = "virtiofsd";
+
+// How long virtiofsd gets to create its socket.
+const SOCKET_WAIT_INTERVAL: Duration = Duration::from_millis(10);
+const SOCKET_WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The supervisor gives up on a virtiofsd exiting more than MAX_RESTARTS
+// times within RESTART_WINDOW, as it's not going to get any better.
+const MAX_RESTARTS: usize = 5;
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+
+/// Errors associated with the spawned virtiofsd.
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot spawn virtiofsd.
+    Spawn(io::Error),
+
+    /// Cannot check whether virtiofsd is still running.
+    Wait(io::Error),
+
+    /// virtiofsd exited before creating its socket.
+    Exited(ExitStatus),
+
+    /// virtiofsd didn't create its socket in time.
+    SocketTimeout(PathBuf),
+
+    /// Cannot spawn the supervisor thread.
+    ThreadSpawn(io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+fn command(config: &FsConfig, sock: &Path, shared_dir: &Path) -> Command {
+    let mut command = Command::new(
+        config
+            .virtiofsd
+            .as_deref()
+            .unwrap_or_else(|| Path::new(DEFAULT_VIRTIOFSD)),
+    );
+    command
+        .arg(format!("--socket-path={}", sock.display()))
+        .arg("-o")
+        .arg(format!("source={}", shared_dir.display()))
+        .arg("-o")
+        .arg("cache=none");
+    if let Some(sandbox) = config.sandbox {
+        let sandbox = match sandbox {
+            FsSandbox::Namespace => "namespace",
+            FsSandbox::Chroot => "chroot",
+        };
+        command.arg("-o").arg(format!("sandbox={}", sandbox));
+    }
+    command.stdin(Stdio::null());
+
+    command
+}
+
+/// Spawns virtiofsd for the fs device `config`, returning once it listens
+/// on the device socket. The config must hold both the socket and the
+/// shared directory, as checked when parsing it.
+pub fn spawn(config: &FsConfig) -> Result<Child> {
+    let sock = config.sock.as_ref().unwrap();
+    let shared_dir = config.path.as_ref().unwrap();
+
+    // Remove the socket of a previous instance, if any, so that its
+    // creation tells the new instance is listening.
+    fs::remove_file(sock).unwrap_or_default();
+
+    let mut child = command(config, sock, shared_dir)
+        .spawn()
+        .map_err(Error::Spawn)?;
+
+    let start = Instant::now();
+    while !sock.exists() {
+        if let Some(status) = child.try_wait().map_err(Error::Wait)? {
+            return Err(Error::Exited(status));
+        }
+        if start.elapsed() > SOCKET_WAIT_TIMEOUT {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(Error::SocketTimeout(sock.clone()));
+        }
+        thread::sleep(SOCKET_WAIT_INTERVAL);
+    }
+
+    Ok(child)
+}
+
+/// A spawned virtiofsd, and the thread restarting it. Dropping it stops
+/// virtiofsd for good.
+pub struct Virtiofsd {
+    pid: Arc<AtomicI32>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Virtiofsd {
+    /// Supervises `child`, spawned for the fs device `config`, reconnecting
+    /// `device` to the new instance whenever virtiofsd gets restarted.
+    pub fn supervise(
+        mut child: Child,
+        config: FsConfig,
+        device: Arc<Mutex<vm_virtio::vhost_user::Fs>>,
+    ) -> Result<Self> {
+        let pid = Arc::new(AtomicI32::new(child.id() as i32));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let thread_pid = pid.clone();
+        let thread_stopped = stopped.clone();
+        thread::Builder::new()
+            .name(format!("virtiofsd_{}", config.tag))
+            .spawn(move || {
+                let sock = config.sock.as_ref().unwrap().to_string_lossy().into_owned();
+                let mut restarts: Vec<Instant> = Vec::new();
+
+                loop {
+                    let status = child.wait();
+                    // The pid is no longer virtiofsd's.
+                    thread_pid.store(0, Ordering::SeqCst);
+                    if thread_stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    restarts.retain(|restart| restart.elapsed() < RESTART_WINDOW);
+                    if restarts.len() >= MAX_RESTARTS {
+                        error!(
+                            "virtiofsd for fs {} exited too often, not restarting it",
+                            config.tag
+                        );
+                        break;
+                    }
+                    restarts.push(Instant::now());
+
+                    warn!(
+                        "virtiofsd for fs {} exited ({:?}), restarting it",
+                        config.tag, status
+                    );
+                    child = match spawn(&config) {
+                        Ok(child) => child,
+                        Err(e) => {
+                            error!("Failed restarting virtiofsd for fs {}: {:?}", config.tag, e);
+                            break;
+                        }
+                    };
+
+                    // Publishing the pid before checking whether the device
+                    // got stopped in the meantime, ensures the new instance
+                    // is stopped in any case.
+                    thread_pid.store(child.id() as i32, Ordering::SeqCst);
+                    if thread_stopped.load(Ordering::SeqCst) {
+                        // Ignore the result because there is nothing we can do about it.
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        break;
+                    }
+
+                    if let Err(e) = device.lock().unwrap().reconnect(&sock) {
+                        error!(
+                            "Failed reconnecting fs {} to virtiofsd: {:?}",
+                            config.tag, e
+                        );
+                    }
+                }
+            })
+            .map_err(Error::ThreadSpawn)?;
+
+        Ok(Virtiofsd { pid, stopped })
+    }
+}
+
+impl Drop for Virtiofsd {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // The supervisor thread reaps virtiofsd and exits.
+        let pid = self.pid.load(Ordering::SeqCst);
+        if pid > 0 {
+            // Ignore the result because there is nothing we can do about it.
+            unsafe { libc::kill(pid, libc::SIGTERM) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command() {
+        let mut config =
+            FsConfig::parse("tag=myfs,sock=/tmp/virtiofs,path=/tmp/shared,spawn=on").unwrap();
+        let args = |config: &FsConfig| {
+            let command = command(config, Path::new("/tmp/virtiofs"), Path::new("/tmp/shared"));
+            format!("{:?}", command)
+        };
+
+        assert_eq!(
+            args(&config),
+            r#""virtiofsd" "--socket-path=/tmp/virtiofs" "-o" "source=/tmp/shared" "-o" "cache=none""#
+        );
+
+        config.virtiofsd = Some(PathBuf::from("/usr/libexec/virtiofsd"));
+        config.sandbox = Some(FsSandbox::Chroot);
+        assert_eq!(
+            args(&config),
+            r#""/usr/libexec/virtiofsd" "--socket-path=/tmp/virtiofs" "-o" "source=/tmp/shared" "-o" "cache=none" "-o" "sandbox=chroot""#
+        );
+    }
+}
@@ -24,27 +24,44 @@ extern crate vm_allocator;
 extern crate vm_memory;
 extern crate vm_virtio;
 
-use crate::config::VmConfig;
+use crate::api::{OvercommitCounters, VcpuCounters, VmClockData, VmCounters, VmNetUpdate};
+use crate::cgroup::{Error as CgroupError, VmCgroups};
+use crate::config::{ConsoleConfig, ConsoleOutputMode, MemoryOvercommit, VmConfig};
 use crate::cpu;
 use crate::device_manager::{get_win_size, Console, DeviceManager, DeviceManagerError};
-use crate::memory_manager::{get_host_cpu_phys_bits, Error as MemoryManagerError, MemoryManager};
+use crate::guest_os::GuestOs;
+use crate::memory_manager::{
+    get_host_cpu_phys_bits, Error as MemoryManagerError, MemoryManager, DIRTY_LOG_PAGE_SIZE,
+};
+use crate::operation::Operation;
+use crate::sev::{Error as SevError, SevGuest};
+#[cfg(feature = "pci_support")]
+use crate::sriov::{Error as SriovError, VirtualFunction};
+use crate::timer::{Error as TimerError, TimerCallback, TimerId, TimerWheel};
 use anyhow::anyhow;
 use arch::layout;
 use devices::{ioapic, HotPlugNotificationFlags};
-use kvm_bindings::{kvm_enable_cap, kvm_userspace_memory_region, KVM_CAP_SPLIT_IRQCHIP};
+use kvm_bindings::{
+    kvm_clock_data, kvm_enable_cap, kvm_userspace_memory_region, KVM_CAP_SPLIT_IRQCHIP,
+};
 use kvm_ioctls::*;
 use linux_loader::cmdline::Cmdline;
 use linux_loader::loader::KernelLoader;
 use signal_hook::{iterator::Signals, SIGINT, SIGTERM, SIGWINCH};
+use std::collections::HashMap;
 use std::ffi::CString;
-use std::fs::File;
-use std::io;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
-use std::{result, str, thread};
+use std::time::{Duration, Instant};
+use std::{cmp, result, str, thread};
+use uuid::Uuid;
 use vm_allocator::{GsiApic, SystemAllocator};
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
 use vm_memory::{
     Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryMmap,
     GuestMemoryRegion, GuestUsize,
@@ -57,6 +74,33 @@ const X86_64_IRQ_BASE: u32 = 5;
 // CPUID feature bits
 const TSC_DEADLINE_TIMER_ECX_BIT: u8 = 24; // tsc deadline timer ecx bit.
 const HYPERVISOR_ECX_BIT: u8 = 31; // Hypervisor ecx bit.
+const INVARIANT_TSC_EDX_BIT: u8 = 8; // Invariant TSC edx bit.
+
+// Period over which the guest dirty page rate is measured, and the vCPUs
+// throttled, when the memory dirty rate is limited.
+const DIRTY_LIMIT_PERIOD_MS: u64 = 100;
+// Minimum share of each period, in percent, a vCPU is allowed to run for.
+const DIRTY_LIMIT_MIN_RUN_PERCENT: u64 = 1;
+
+// Period over which the pages written by the guest make up its estimated
+// working set.
+const WORKING_SET_PERIOD_MS: u64 = 10_000;
+
+// Interval at which the memory overcommit controller checks the host free
+// memory and adjusts the guest memory.
+const OVERCOMMIT_PERIOD_MS: u64 = 1_000;
+// Memory the overcommit controller always leaves to the guest.
+const OVERCOMMIT_GUEST_RESERVE: u64 = 256 << 20;
+
+// Names of the serial port and virtio-console in the guest kernel.
+const SERIAL_CONSOLE: &str = "ttyS0";
+const VIRTIO_CONSOLE: &str = "hvc0";
+
+// Interval at which the watched disks backing files are checked.
+const DISK_WATCH_PERIOD_MS: u64 = 1_000;
+
+// Snapshot file holding the vCPUs and devices state.
+const SNAPSHOT_STATE_FILE: &str = "state.json";
 
 // 64 bit direct boot entry offset for bzImage
 const KERNEL_64BIT_ENTRY_OFFSET: u64 = 0x200;
@@ -164,9 +208,234 @@ pub enum Error {
 
     /// Memory manager error
     MemoryManager(MemoryManagerError),
+
+    /// Cannot create the snapshot directory
+    SnapshotDirectory(io::Error),
+
+    /// Cannot save the VM configuration in the snapshot
+    SnapshotConfig(serde_json::Error),
+
+    /// The VM can only be snapshotted while running or paused
+    InvalidStateForSnapshot(VmState),
+
+    /// Cannot spawn the snapshot writer thread
+    SnapshotThreadSpawn(io::Error),
+
+    /// A snapshot of the VM is already being written
+    SnapshotInProgress,
+
+    /// Cannot save the vCPUs and devices state
+    Snapshot(MigratableError),
+
+    /// Cannot write the vCPUs and devices state in the snapshot
+    SnapshotState(serde_json::Error),
+
+    /// The VM can only be restored before being booted
+    InvalidStateForRestore(VmState),
+
+    /// Cannot open a snapshot file
+    RestoreFile(io::Error),
+
+    /// Cannot read a snapshot file
+    RestoreState(serde_json::Error),
+
+    /// Cannot restore the vCPUs and devices state
+    Restore(MigratableError),
+
+    /// The snapshot didn't complete
+    SnapshotIncomplete(String),
+
+    /// Memory overcommit requires a balloon or virtio-mem device
+    OvercommitWithoutDevice,
+
+    /// Cannot resize the memory plugged through virtio-mem
+    VirtioMemResize(io::Error),
+
+    /// Cannot watch a disk backing file
+    DiskWatch(io::Error),
+
+    /// Invalid vCPUs configuration
+    InvalidCpusConfig(crate::config::Error),
+
+    /// Invalid memory configuration
+    InvalidMemoryConfig(crate::config::Error),
+
+    /// Invalid virtio queue size
+    InvalidQueueSize(crate::config::Error),
+
+    /// Invalid or conflicting PCI addresses
+    InvalidPciAddress(crate::config::Error),
+
+    /// More devices than the PCI bus can hold
+    TooManyPciDevices(crate::config::Error),
+
+    /// Two disks or network interfaces share an id
+    InvalidDeviceId(crate::config::Error),
+
+    /// Cannot confine the VMM to its cgroups
+    Cgroup(CgroupError),
+
+    /// Cannot schedule a timer
+    ScheduleTimer(TimerError),
+
+    /// Cannot cancel a timer
+    CancelTimer(TimerError),
+
+    /// Cannot set a SR-IOV VF up
+    #[cfg(feature = "pci_support")]
+    SriovVf(SriovError),
+
+    /// Cannot launch the SEV guest
+    Sev(SevError),
+
+    /// The memory of a SEV guest can't be hotplugged
+    SevMemoryHotplug,
+
+    /// The memory of a SEV guest is encrypted, it can't be snapshotted
+    SevSnapshot,
+
+    /// The SEV guest boot data doesn't fit in the first RAM region
+    SevBootData,
+
+    /// The balloon relies on the guest memory being shared with the host
+    SevBalloon,
+
+    /// The virtio-iommu device can't offer VIRTIO_F_IOMMU_PLATFORM, the guest
+    /// wouldn't share its queues with the host
+    SevIommu,
+
+    /// Cannot write the boot CPUs tables
+    SetupBootTables(arch::x86_64::regs::Error),
+
+    /// Cannot get the guest clock
+    GetClock(kvm_ioctls::Error),
+
+    /// Cannot set the guest clock
+    SetClock(kvm_ioctls::Error),
+
+    /// Cannot save the guest clock in the snapshot
+    SnapshotClock(serde_json::Error),
+
+    /// No network interface has this id
+    NetNotFound(String),
+
+    /// The vhost-user network interfaces can't be rate limited
+    NetNotUpdatable(String),
 }
 pub type Result<T> = result::Result<T, Error>;
 
+/// Applies the runtime tunable properties of a network interface to its
+/// configuration, for them to survive a reboot.
+pub fn update_net_config(config: &mut VmConfig, update: &VmNetUpdate) -> Result<()> {
+    let net = config
+        .net
+        .iter_mut()
+        .flatten()
+        .find(|net| net.id.as_ref() == Some(&update.id))
+        .ok_or_else(|| Error::NetNotFound(update.id.clone()))?;
+    if net.vhost_user {
+        return Err(Error::NetNotUpdatable(update.id.clone()));
+    }
+
+    net.rx_rate_limiter_config = update.rx_rate_limiter_config.clone();
+    net.tx_rate_limiter_config = update.tx_rate_limiter_config.clone();
+
+    Ok(())
+}
+
+// The console= arguments sending the guest console to the serial port and
+// the virtio-console having an output, the virtio-console coming last so that
+// it is /dev/console. A command line choosing the console itself is left
+// alone, with a warning when the guest console goes nowhere, as the guest
+// would look hung.
+fn console_cmdline_args(
+    cmdline: &str,
+    serial: &ConsoleConfig,
+    console: &ConsoleConfig,
+) -> Vec<String> {
+    let has_output = |config: &ConsoleConfig| match config.mode {
+        ConsoleOutputMode::Pty
+        | ConsoleOutputMode::Tty
+        | ConsoleOutputMode::File
+        | ConsoleOutputMode::Socket => true,
+        ConsoleOutputMode::Off | ConsoleOutputMode::Null => false,
+    };
+    let mut outputs = Vec::new();
+    if has_output(serial) {
+        outputs.push(SERIAL_CONSOLE);
+    }
+    if has_output(console) {
+        outputs.push(VIRTIO_CONSOLE);
+    }
+
+    // The device name comes before the options, as in console=ttyS0,115200n8.
+    let consoles: Vec<&str> = cmdline
+        .split_whitespace()
+        .filter(|arg| arg.starts_with("console="))
+        .filter_map(|arg| arg[8..].split(',').next())
+        .collect();
+    if consoles.is_empty() {
+        return outputs
+            .iter()
+            .map(|output| format!("console={}", output))
+            .collect();
+    }
+
+    if !outputs.is_empty() && !consoles.iter().any(|c| outputs.contains(c)) {
+        warn!(
+            "The guest console goes to {}, while only {} have an output",
+            consoles.join(", "),
+            outputs.join(", ")
+        );
+    }
+    Vec::new()
+}
+
+// The end of the highest loadable segment of an ELF kernel, its BSS
+// included, from the program headers.
+fn elf_kernel_end<F: Read + Seek>(kernel: &mut F) -> Result<u64> {
+    const PT_LOAD: u32 = 1;
+
+    fn le_u64(bytes: &[u8]) -> u64 {
+        let mut value = [0u8; 8];
+        value.copy_from_slice(&bytes[..8]);
+        u64::from_le_bytes(value)
+    }
+    fn le_u32(bytes: &[u8]) -> u32 {
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+    fn le_u16(bytes: &[u8]) -> u16 {
+        u16::from_le_bytes([bytes[0], bytes[1]])
+    }
+
+    let mut ehdr = [0u8; 64];
+    kernel.seek(SeekFrom::Start(0)).map_err(Error::KernelFile)?;
+    kernel.read_exact(&mut ehdr).map_err(Error::KernelFile)?;
+    let phoff = le_u64(&ehdr[0x20..]);
+    let phentsize = le_u16(&ehdr[0x36..]);
+    let phnum = le_u16(&ehdr[0x38..]);
+    if phentsize < 56 {
+        return Err(Error::SevBootData);
+    }
+
+    let mut end = 0u64;
+    let mut phdr = vec![0u8; usize::from(phentsize)];
+    for i in 0..u64::from(phnum) {
+        kernel
+            .seek(SeekFrom::Start(phoff + i * u64::from(phentsize)))
+            .map_err(Error::KernelFile)?;
+        kernel.read_exact(&mut phdr).map_err(Error::KernelFile)?;
+        if le_u32(&phdr) != PT_LOAD {
+            continue;
+        }
+        let paddr = le_u64(&phdr[0x18..]);
+        let memsz = le_u64(&phdr[0x28..]);
+        end = cmp::max(end, paddr.checked_add(memsz).ok_or(Error::MemOverflow)?);
+    }
+
+    Ok(end)
+}
+
 #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
 pub enum VmState {
     Created,
@@ -175,6 +444,15 @@ pub enum VmState {
     Paused,
 }
 
+/// Why the VMM paused the VM on its own, rather than being asked to.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum PausedReason {
+    /// The backing file of a watched disk went away.
+    DiskLost,
+    /// A disk with the stop error policy failed a request.
+    DiskError,
+}
+
 impl VmState {
     fn valid_transition(self, new_state: VmState) -> Result<()> {
         match self {
@@ -209,6 +487,24 @@ impl VmState {
     }
 }
 
+// A snapshot whose guest memory is being written out in the background.
+struct SnapshotInProgress {
+    operation: Arc<Operation>,
+    destination: PathBuf,
+    // The vCPUs and devices state, written once the guest memory is.
+    state: Snapshot,
+    thread: thread::JoinHandle<result::Result<bool, MemoryManagerError>>,
+}
+
+// What the memory overcommit controller did to the guest memory.
+#[derive(Default)]
+struct OvercommitState {
+    // Number of times the guest memory got adjusted.
+    interventions: AtomicU64,
+    // Memory held back from the memory plugged through virtio-mem.
+    unplugged: AtomicU64,
+}
+
 pub struct Vm {
     kernel: File,
     threads: Vec<thread::JoinHandle<()>>,
@@ -219,15 +515,83 @@ pub struct Vm {
     state: RwLock<VmState>,
     cpu_manager: Arc<Mutex<cpu::CpuManager>>,
     memory_manager: Arc<Mutex<MemoryManager>>,
+    working_set: Option<Arc<AtomicU64>>,
+    overcommit: Option<Arc<OvercommitState>>,
+    exit_evt: EventFd,
+    disk_evt: EventFd,
+    // The watched disks whose backing file is currently missing.
+    lost_disks: Arc<Mutex<Vec<PathBuf>>>,
+    paused_reason: Option<PausedReason>,
+    timers: Arc<TimerWheel>,
+    timer_ids: Vec<TimerId>,
+    sev: Option<SevGuest>,
+    snapshot: Option<SnapshotInProgress>,
+    fd: Arc<VmFd>,
+    // Last, so that the VFs are released once the devices using them are
+    // gone.
+    #[cfg(feature = "pci_support")]
+    _sriov_vfs: Vec<VirtualFunction>,
 }
 
 impl Vm {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: Arc<Mutex<VmConfig>>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        panic_evt: EventFd,
+        hibernate_evt: EventFd,
+        disk_evt: EventFd,
+        timers: Arc<TimerWheel>,
         vmm_path: PathBuf,
     ) -> Result<Self> {
+        // The configuration may come from the API rather than from the
+        // command line, in which case it hasn't been validated yet.
+        config
+            .lock()
+            .unwrap()
+            .cpus
+            .validate()
+            .map_err(Error::InvalidCpusConfig)?;
+        config
+            .lock()
+            .unwrap()
+            .memory
+            .validate()
+            .map_err(Error::InvalidMemoryConfig)?;
+        // The memory zones make up the guest RAM when no size is given.
+        let memory_size = config.lock().unwrap().memory.total_size();
+        config.lock().unwrap().memory.size = memory_size;
+        config
+            .lock()
+            .unwrap()
+            .validate_queue_sizes()
+            .map_err(Error::InvalidQueueSize)?;
+        config
+            .lock()
+            .unwrap()
+            .validate_pci_addresses()
+            .map_err(Error::InvalidPciAddress)?;
+        config
+            .lock()
+            .unwrap()
+            .validate_device_ids()
+            .map_err(Error::InvalidDeviceId)?;
+        config
+            .lock()
+            .unwrap()
+            .validate_pci_device_count()
+            .map_err(Error::TooManyPciDevices)?;
+
+        // Confine the VMM before any device thread gets spawned, so that
+        // they all inherit the VMM cgroup. The vCPU threads are moved to
+        // theirs by the CPU manager when spawned.
+        let cgroups = if let Some(cgroup) = &config.lock().unwrap().cgroup {
+            Some(Arc::new(VmCgroups::new(cgroup).map_err(Error::Cgroup)?))
+        } else {
+            None
+        };
+
         let kvm = Kvm::new().map_err(Error::KvmNew)?;
 
         // Check required capabilities:
@@ -265,6 +629,24 @@ impl Vm {
         }
         let fd = Arc::new(fd);
 
+        // The SEV guest context must exist before any vCPU is created.
+        let platform_config = config.lock().unwrap().platform.clone();
+        let sev = match platform_config {
+            Some(platform_config) if platform_config.sev_enabled() => {
+                if config.lock().unwrap().memory.hotplug_size.is_some() {
+                    return Err(Error::SevMemoryHotplug);
+                }
+                if config.lock().unwrap().balloon.is_some() {
+                    return Err(Error::SevBalloon);
+                }
+                if config.lock().unwrap().iommu {
+                    return Err(Error::SevIommu);
+                }
+                Some(SevGuest::new(fd.clone(), &platform_config).map_err(Error::Sev)?)
+            }
+            _ => None,
+        };
+
         // Set TSS
         fd.set_tss_address(arch::x86_64::layout::KVM_TSS_ADDRESS.raw_value() as usize)
             .map_err(Error::VmSetup)?;
@@ -300,6 +682,25 @@ impl Vm {
             edx_bit: None,
         });
 
+        // A pinned TSC frequency is constant across hosts, hence it can be
+        // advertised as invariant for the guest to rely on it.
+        let tsc_khz = config.lock().unwrap().cpus.tsc_khz;
+        if tsc_khz.is_some() {
+            if !kvm.check_extension(Cap::TscControl) {
+                return Err(Error::CapabilityMissing(Cap::TscControl));
+            }
+
+            cpuid_patches.push(cpu::CpuidPatch {
+                function: 0x8000_0007,
+                index: 0,
+                flags_bit: None,
+                eax_bit: None,
+                ebx_bit: None,
+                ecx_bit: None,
+                edx_bit: Some(INVARIANT_TSC_EDX_BIT),
+            });
+        }
+
         // Supported CPUID
         let mut cpuid = kvm
             .get_supported_cpuid(kvm_bindings::KVM_MAX_CPUID_ENTRIES)
@@ -307,6 +708,52 @@ impl Vm {
 
         cpu::CpuidPatch::patch_cpuid(&mut cpuid, cpuid_patches);
 
+        if let Some(nested) = config.lock().unwrap().cpus.nested {
+            cpu::CpuidPatch::patch_cpuid_nested(&mut cpuid, nested).map_err(Error::CpuManager)?;
+        }
+
+        if let Some(model) = &config.lock().unwrap().cpus.model {
+            cpu::CpuidPatch::patch_cpuid_model(&mut cpuid, model).map_err(Error::CpuManager)?;
+        }
+
+        // Apply the user requested CPUID features last, so that they can
+        // override any of the default patches.
+        if let Some(features) = &config.lock().unwrap().cpus.features {
+            cpu::CpuidPatch::patch_cpuid_features(&mut cpuid, features)
+                .map_err(Error::CpuManager)?;
+        }
+
+        // A pinned TSC frequency is the one the guest sees as its base
+        // frequency.
+        let cpus_config = config.lock().unwrap().cpus.clone();
+        let frequency = if cpus_config.freq_hints {
+            let mut frequency = cpu::CpuFrequency::from_host().map_err(Error::CpuManager)?;
+            if let Some(tsc_khz) = tsc_khz {
+                frequency.base_mhz = tsc_khz / 1000;
+                frequency.min_mhz = cmp::min(frequency.min_mhz, frequency.base_mhz);
+                frequency.max_mhz = cmp::max(frequency.max_mhz, frequency.base_mhz);
+            }
+            cpu::CpuidPatch::patch_cpuid_frequency(&mut cpuid, &frequency)
+                .map_err(Error::CpuManager)?;
+            Some(frequency)
+        } else {
+            None
+        };
+
+        // The Hyper-V leaves take the place of the KVM ones, which are
+        // moved away, hence the KVM features being patched beforehand.
+        if cpus_config.hyperv {
+            if !kvm.check_extension(Cap::Hyperv) {
+                return Err(Error::CapabilityMissing(Cap::Hyperv));
+            }
+            if !kvm.check_extension(Cap::HypervTime) {
+                return Err(Error::CapabilityMissing(Cap::HypervTime));
+            }
+
+            cpu::CpuidPatch::patch_cpuid_hyperv(&mut cpuid, cpus_config.max_vcpus)
+                .map_err(Error::CpuManager)?;
+        }
+
         let ioapic = GsiApic::new(
             X86_64_IRQ_BASE,
             ioapic::NUM_IOAPIC_PINS as u32 - X86_64_IRQ_BASE,
@@ -331,15 +778,54 @@ impl Vm {
         let memory_manager = MemoryManager::new(
             allocator.clone(),
             fd.clone(),
-            memory_config.size,
+            memory_config.total_size(),
             memory_config.hotplug_size,
+            memory_config.hotplug_method,
             &memory_config.file,
             memory_config.mergeable,
+            memory_config.mlock,
+            memory_config.prefault,
+            &memory_config.zones,
         )
         .map_err(Error::MemoryManager)?;
 
+        if let Some(sgx_epc_config) = &config.lock().unwrap().sgx_epc {
+            let sections = memory_manager
+                .lock()
+                .unwrap()
+                .setup_sgx(sgx_epc_config)
+                .map_err(Error::MemoryManager)?;
+            cpu::CpuidPatch::patch_cpuid_sgx_epc(&mut cpuid, &sections)
+                .map_err(Error::CpuManager)?;
+        }
+
         let guest_memory = memory_manager.lock().unwrap().guest_memory();
 
+        if let Some(sev) = &sev {
+            guest_memory
+                .memory()
+                .with_regions(|_, region| {
+                    sev.register_region(region.as_ptr() as u64, region.len() as u64)
+                })
+                .map_err(Error::Sev)?;
+        }
+
+        // The VFs of a PF get created all at once by the first one to be set
+        // up, hence the highest index goes first. The VF which created them
+        // is released last, as this removes all of them.
+        #[cfg(feature = "pci_support")]
+        let sriov_vfs = {
+            let mut sriov_vf_configs = config.lock().unwrap().sriov_vfs.clone().unwrap_or_default();
+            sriov_vf_configs.sort_by(|a, b| b.index.cmp(&a.index));
+
+            let mut sriov_vfs = Vec::new();
+            for sriov_vf_config in sriov_vf_configs.iter() {
+                sriov_vfs.push(VirtualFunction::new(sriov_vf_config).map_err(Error::SriovVf)?);
+            }
+            sriov_vfs.reverse();
+            sriov_vfs
+        };
+
         let device_manager = DeviceManager::new(
             fd.clone(),
             config.clone(),
@@ -347,7 +833,11 @@ impl Vm {
             memory_manager.clone(),
             &exit_evt,
             &reset_evt,
+            &panic_evt,
+            &hibernate_evt,
+            &disk_evt,
             vmm_path,
+            cgroups.clone(),
         )
         .map_err(Error::DeviceManager)?;
 
@@ -359,10 +849,13 @@ impl Vm {
             boot_vcpus,
             max_vcpus,
             &device_manager,
-            guest_memory,
-            fd,
+            fd.clone(),
             cpuid,
+            tsc_khz,
+            frequency,
+            config.lock().unwrap().unknown_access,
             reset_evt,
+            cgroups,
         )
         .map_err(Error::CpuManager)?;
 
@@ -376,14 +869,34 @@ impl Vm {
             state: RwLock::new(VmState::Created),
             cpu_manager,
             memory_manager,
+            working_set: None,
+            overcommit: None,
+            exit_evt,
+            disk_evt,
+            lost_disks: Arc::new(Mutex::new(Vec::new())),
+            paused_reason: None,
+            timers,
+            timer_ids: Vec::new(),
+            sev,
+            snapshot: None,
+            fd,
+            #[cfg(feature = "pci_support")]
+            _sriov_vfs: sriov_vfs,
         })
     }
 
     fn load_kernel(&mut self) -> Result<GuestAddress> {
         let mut cmdline = Cmdline::new(arch::CMDLINE_MAX_SIZE);
-        cmdline
-            .insert_str(self.config.lock().unwrap().cmdline.args.clone())
-            .map_err(Error::CmdLineInsertStr)?;
+        let (args, console_args) = {
+            let config = self.config.lock().unwrap();
+            let args = config.cmdline.args.clone();
+            let console_args = console_cmdline_args(&args, &config.serial, &config.console);
+            (args, console_args)
+        };
+        cmdline.insert_str(args).map_err(Error::CmdLineInsertStr)?;
+        for entry in console_args {
+            cmdline.insert_str(entry).map_err(Error::CmdLineInsertStr)?;
+        }
         for entry in self.devices.cmdline_additions() {
             cmdline.insert_str(entry).map_err(Error::CmdLineInsertStr)?;
         }
@@ -432,7 +945,14 @@ impl Vm {
             ));
         }
 
-        match entry_addr.setup_header {
+        // The boot CPUs tables map the guest memory encrypted for a SEV
+        // guest, so that the kernel reads what it has been launched with.
+        let encryption_mask = self.sev.as_ref().map(SevGuest::c_bit_mask).unwrap_or(0);
+        arch::x86_64::regs::setup_boot_tables(&mem, encryption_mask)
+            .map_err(Error::SetupBootTables)?;
+
+        let uuid = self.config.lock().unwrap().uuid;
+        let entry = match entry_addr.setup_header {
             Some(hdr) => {
                 arch::configure_system(
                     &mem,
@@ -441,6 +961,7 @@ impl Vm {
                     boot_vcpus,
                     Some(hdr),
                     rsdp_addr,
+                    uuid.as_ref().map(Uuid::as_bytes),
                 )
                 .map_err(Error::ConfigureSystem)?;
 
@@ -450,7 +971,7 @@ impl Vm {
                     .checked_add(KERNEL_64BIT_ENTRY_OFFSET)
                     .ok_or(Error::MemOverflow)?;
 
-                Ok(GuestAddress(load_addr))
+                GuestAddress(load_addr)
             }
             None => {
                 arch::configure_system(
@@ -460,15 +981,62 @@ impl Vm {
                     boot_vcpus,
                     None,
                     rsdp_addr,
+                    uuid.as_ref().map(Uuid::as_bytes),
                 )
                 .map_err(Error::ConfigureSystem)?;
 
-                Ok(entry_addr.kernel_load)
+                entry_addr.kernel_load
             }
+        };
+
+        if self.sev.is_some() {
+            // The kernel extends past its image, up to the end of its BSS
+            // or, for a bzImage, of the memory it decompresses itself into.
+            let kernel_end = match entry_addr.setup_header {
+                Some(hdr) => entry_addr
+                    .kernel_load
+                    .raw_value()
+                    .checked_add(u64::from(hdr.init_size))
+                    .ok_or(Error::MemOverflow)?,
+                None => elf_kernel_end(&mut self.kernel)?,
+            };
+            self.sev_encrypt_boot_data(kernel_end)?;
+        }
+
+        Ok(entry)
+    }
+
+    // Everything the guest boots from has been written below the end of the
+    // kernel: the boot data structures in the first MiB, i.e. the boot
+    // parameters, the command line, the boot CPUs tables and the ACPI
+    // tables, then the kernel itself. That whole range gets encrypted, and
+    // measured, before the SEV guest is launched.
+    fn sev_encrypt_boot_data(&self, kernel_end: u64) -> Result<()> {
+        let boot_data_size = kernel_end.checked_add(0xfff).ok_or(Error::MemOverflow)? & !0xfff;
+
+        let guest_memory = self.memory_manager.lock().unwrap().guest_memory();
+        let mem = guest_memory.memory();
+        let region = mem
+            .find_region(layout::LOW_RAM_START)
+            .ok_or(Error::SevBootData)?;
+        if boot_data_size > region.len() as u64 || boot_data_size > u64::from(u32::MAX) {
+            return Err(Error::SevBootData);
         }
+
+        self.sev
+            .as_ref()
+            .unwrap()
+            .launch_update_data(region.as_ptr() as u64, boot_data_size as u32)
+            .map_err(Error::Sev)
     }
 
     pub fn shutdown(&mut self) -> Result<()> {
+        // A snapshot being written can't be completed anymore.
+        if let Some(snapshot) = self.snapshot.as_ref() {
+            snapshot.operation.cancel();
+        }
+        self.snapshot_written()?;
+
         let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
         let new_state = VmState::Shutdown;
 
@@ -488,6 +1056,12 @@ impl Vm {
             signals.close();
         }
 
+        // The timers limiting the dirty rate, estimating the working set,
+        // controlling the memory overcommit and watching the disks go along.
+        for id in self.timer_ids.drain(..) {
+            self.timers.cancel(id).map_err(Error::CancelTimer)?;
+        }
+
         self.cpu_manager
             .lock()
             .unwrap()
@@ -498,6 +1072,10 @@ impl Vm {
         for thread in self.threads.drain(..) {
             thread.join().map_err(Error::ThreadCleanup)?
         }
+
+        // With nothing left to submit requests, the devices can be torn
+        // down.
+        self.devices.shutdown();
         *state = new_state;
 
         Ok(())
@@ -520,6 +1098,10 @@ impl Vm {
         }
 
         if let Some(desired_memory) = desired_memory {
+            if let Some(virtio_mem) = self.devices.virtio_mem() {
+                return self.resize_virtio_mem(virtio_mem, desired_memory);
+            }
+
             if self
                 .memory_manager
                 .lock()
@@ -536,27 +1118,426 @@ impl Vm {
         Ok(())
     }
 
-    fn os_signal_handler(signals: Signals, console_input_clone: Arc<Console>, on_tty: bool) {
+    // The memory past the boot RAM is plugged by the guest, the overcommit
+    // controller keeping back what it already took from it.
+    fn resize_virtio_mem(
+        &self,
+        virtio_mem: &Arc<Mutex<vm_virtio::Mem>>,
+        desired_memory: u64,
+    ) -> Result<()> {
+        let mut virtio_mem = virtio_mem.lock().unwrap();
+        let mut config = self.config.lock().unwrap();
+        let hotplugged = desired_memory
+            .checked_sub(config.memory.total_size())
+            .ok_or_else(|| {
+                Error::VirtioMemResize(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "cannot unplug the boot memory",
+                ))
+            })?;
+        let unplugged = self
+            .overcommit
+            .as_ref()
+            .map_or(0, |overcommit| overcommit.unplugged.load(Ordering::SeqCst));
+
+        virtio_mem
+            .resize(hotplugged.saturating_sub(unplugged))
+            .map_err(Error::VirtioMemResize)?;
+        config.memory.hotplugged_size = Some(hotplugged);
+
+        Ok(())
+    }
+
+    pub fn resize_disk(&self, path: &Path) -> Result<u64> {
+        self.devices.resize_disk(path).map_err(Error::DeviceManager)
+    }
+
+    /// The images of the disks which can be resized while the VM is running.
+    pub fn resizable_disks(&self) -> Vec<PathBuf> {
+        self.devices.resizable_disks()
+    }
+
+    /// Updates a network interface while the VM is running.
+    pub fn update_net(&self, update: &VmNetUpdate) -> Result<()> {
+        let mut config = self.config.lock().unwrap();
+        let mut new_config = config.clone();
+        update_net_config(&mut new_config, update)?;
+
+        self.devices
+            .update_net(
+                &update.id,
+                update.rx_rate_limiter_config.as_ref(),
+                update.tx_rate_limiter_config.as_ref(),
+            )
+            .map_err(Error::DeviceManager)?;
+        *config = new_config;
+
+        Ok(())
+    }
+
+    pub fn guest_os(&self) -> Option<GuestOs> {
+        self.devices.guest_os()
+    }
+
+    /// Why the VMM paused the VM on its own, until it's resumed.
+    pub fn paused_reason(&self) -> Option<PausedReason> {
+        self.paused_reason
+    }
+
+    /// The watched disks whose backing file is currently missing.
+    pub fn lost_disks(&self) -> Vec<PathBuf> {
+        self.lost_disks.lock().unwrap().clone()
+    }
+
+    /// Pause the VM after a watched disk went away, or a disk with the stop
+    /// policy failed a request. It may already be paused, e.g. after several
+    /// disks went away, in which case the first reason is kept.
+    pub fn pause_on_disk_error(&mut self) -> Result<()> {
+        if self.get_state()? != VmState::Running {
+            return Ok(());
+        }
+
+        let reason = if self.lost_disks.lock().unwrap().is_empty() {
+            PausedReason::DiskError
+        } else {
+            PausedReason::DiskLost
+        };
+        warn!("Pausing the VM: {:?}", reason);
+        self.pause().map_err(Error::Pause)?;
+        self.paused_reason = Some(reason);
+
+        Ok(())
+    }
+
+    /// The ptys the serial port and the virtio-console are exposed on.
+    pub fn ptys(&self) -> (Option<PathBuf>, Option<PathBuf>) {
+        let console = self.devices.console();
+        (
+            console.serial_pty().map(Path::to_path_buf),
+            console.console_pty().map(Path::to_path_buf),
+        )
+    }
+
+    pub fn disk_checkpoint(&self, path: &Path) -> Result<u64> {
+        self.devices
+            .disk_checkpoint(path)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn disk_changed_blocks(&self, path: &Path, since: u64) -> Result<Vec<(u64, u64)>> {
+        self.devices
+            .disk_changed_blocks(path, since)
+            .map_err(Error::DeviceManager)
+    }
+
+    // SIGTERM and SIGINT shut the VMM down through its control loop, rather
+    // than exiting right away, so that the devices get flushed and the
+    // terminal restored.
+    fn os_signal_handler(signals: Signals, console_input_clone: Arc<Console>, exit_evt: EventFd) {
         for signal in signals.forever() {
             match signal {
                 SIGWINCH => {
                     let (col, row) = get_win_size();
+                    debug!("Terminal resized to {}x{}", col, row);
                     console_input_clone.update_console_size(col, row);
                 }
                 SIGTERM | SIGINT => {
-                    if on_tty {
-                        io::stdin()
-                            .lock()
-                            .set_canon_mode()
-                            .expect("failed to restore terminal mode");
+                    if let Err(e) = exit_evt.write(1) {
+                        error!("Failed signaling the VMM exit: {:?}", e);
+                        std::process::exit(1);
                     }
-                    std::process::exit((signal != SIGTERM) as i32);
                 }
                 _ => (),
             }
         }
     }
 
+    // Keep the rate at which each vCPU dirties the guest memory under `limit`
+    // bytes per second, throttling each vCPU on its own by having it spend
+    // part of each period outside of the guest. KVM doesn't tell which vCPU
+    // wrote to a page, so the pages dirtied over a period are attributed to
+    // the vCPUs in proportion to the time each of them ran the guest for.
+    fn dirty_limit_timer(
+        limit: u64,
+        cpu_manager: Arc<Mutex<cpu::CpuManager>>,
+        memory_manager: Arc<Mutex<MemoryManager>>,
+    ) -> TimerCallback {
+        let period = Duration::from_millis(DIRTY_LIMIT_PERIOD_MS);
+        // The CPU time last sampled, and the share of each period it may run
+        // the guest for, of each vCPU.
+        let mut vcpus: HashMap<u8, (u64, u64)> = HashMap::new();
+        let mut last_sample = Instant::now();
+        let mut failed = false;
+
+        Box::new(move || {
+            if failed {
+                return;
+            }
+
+            let dirty_pages = match memory_manager.lock().unwrap().dirty_pages() {
+                Ok(dirty_pages) => dirty_pages,
+                Err(e) => {
+                    error!("Failed retrieving the dirty pages log: {:?}", e);
+                    failed = true;
+                    return;
+                }
+            };
+            let elapsed_ms = std::cmp::max(last_sample.elapsed().as_millis() as u64, 1);
+            last_sample = Instant::now();
+            let rate = dirty_pages * DIRTY_LOG_PAGE_SIZE * 1000 / elapsed_ms;
+
+            // A vCPU appearing is only accounted for from the next period on,
+            // and one going away is forgotten.
+            let cpu_manager = cpu_manager.lock().unwrap();
+            let run_times = cpu_manager.vcpus_cpu_time();
+            let run_deltas: Vec<u64> = run_times
+                .iter()
+                .map(|(cpu_id, run_time)| {
+                    vcpus.get(cpu_id).map_or(0, |(last_run_time, _)| {
+                        run_time.saturating_sub(*last_run_time)
+                    })
+                })
+                .collect();
+            let vcpu_rates = Vm::dirty_rate_per_vcpu(rate, &run_deltas);
+
+            let mut new_vcpus = HashMap::new();
+            for ((cpu_id, run_time), vcpu_rate) in run_times.iter().zip(vcpu_rates) {
+                let run_percent = vcpus.get(cpu_id).map_or(100, |(_, p)| *p);
+                let new_run_percent = Vm::dirty_limit_run_percent(run_percent, vcpu_rate, limit);
+                if new_run_percent != run_percent {
+                    debug!(
+                        "vCPU {} dirty rate {} bytes/s (limit {}): running {}% of the time",
+                        cpu_id, vcpu_rate, limit, new_run_percent
+                    );
+                }
+
+                cpu_manager.throttle_vcpu(*cpu_id, period * (100 - new_run_percent as u32) / 100);
+                new_vcpus.insert(*cpu_id, (*run_time, new_run_percent));
+            }
+            vcpus = new_vcpus;
+        })
+    }
+
+    // Split the guest memory dirty rate among the vCPUs, in proportion to the
+    // CPU time each of them consumed over the same period, a halted vCPU not
+    // dirtying any memory.
+    fn dirty_rate_per_vcpu(rate: u64, run_deltas: &[u64]) -> Vec<u64> {
+        let total: u64 = run_deltas.iter().sum();
+        run_deltas
+            .iter()
+            .map(|delta| {
+                if total == 0 {
+                    0
+                } else {
+                    (u128::from(rate) * u128::from(*delta) / u128::from(total)) as u64
+                }
+            })
+            .collect()
+    }
+
+    // The share of each period, in percent, a vCPU may run the guest for so
+    // that it dirties memory at `limit` bytes per second at most. Its dirty
+    // rate is roughly proportional to the time spent running the guest, so
+    // scale it by how far off the limit the vCPU is.
+    fn dirty_limit_run_percent(run_percent: u64, rate: u64, limit: u64) -> u64 {
+        (run_percent * limit / cmp::max(rate, 1))
+            .max(DIRTY_LIMIT_MIN_RUN_PERCENT)
+            .min(100)
+    }
+
+    // Sample the pages written by the guest over each period, their total
+    // size being the estimated working set. Pages only read by the guest
+    // aren't accounted for.
+    fn working_set_timer(
+        working_set: Arc<AtomicU64>,
+        memory_manager: Arc<Mutex<MemoryManager>>,
+    ) -> TimerCallback {
+        let mut failed = false;
+
+        Box::new(move || {
+            if failed {
+                return;
+            }
+            match memory_manager.lock().unwrap().sample_working_set() {
+                Ok(bytes) => working_set.store(bytes, Ordering::SeqCst),
+                Err(e) => {
+                    error!("Failed estimating the guest working set: {:?}", e);
+                    failed = true;
+                }
+            }
+        })
+    }
+
+    // Read the total and available memory of the host, in bytes.
+    fn host_meminfo() -> io::Result<(u64, u64)> {
+        let meminfo = fs::read_to_string("/proc/meminfo")?;
+        let field = |name: &str| -> io::Result<u64> {
+            meminfo
+                .lines()
+                .find(|line| line.starts_with(name))
+                .and_then(|line| line.split_whitespace().nth(1))
+                .and_then(|kib| kib.parse::<u64>().ok())
+                .map(|kib| kib << 10)
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("No {} entry", name))
+                })
+        };
+
+        Ok((field("MemTotal:")?, field("MemAvailable:")?))
+    }
+
+    // Keep at least target_free_host percent of the host memory available
+    // by taking memory back from the guest when the host runs short of it,
+    // and giving it back once enough memory is available again. The memory
+    // plugged through virtio-mem is taken back first, by whole blocks, and
+    // the balloon makes up the rest, while the balloon is deflated first
+    // when giving memory back.
+    fn overcommit_timer(
+        target_free_host: u8,
+        config: Arc<Mutex<VmConfig>>,
+        balloon: Option<Arc<Mutex<vm_virtio::Balloon>>>,
+        virtio_mem: Option<Arc<Mutex<vm_virtio::Mem>>>,
+        state: Arc<OvercommitState>,
+    ) -> TimerCallback {
+        let block_size = vm_virtio::VIRTIO_MEM_BLOCK_SIZE;
+        let mut failed = false;
+
+        Box::new(move || {
+            if failed {
+                return;
+            }
+
+            let (total, available) = match Vm::host_meminfo() {
+                Ok(meminfo) => meminfo,
+                Err(e) => {
+                    error!("Failed reading the host memory usage: {:?}", e);
+                    failed = true;
+                    return;
+                }
+            };
+
+            let target = total / 100 * u64::from(target_free_host);
+            // Only give memory back once the host has some margin above the
+            // target, so that the guest memory doesn't oscillate around it.
+            let hysteresis = target / 2;
+            if available >= target && available <= target + hysteresis {
+                return;
+            }
+
+            // The guest memory size can't change while the devices are
+            // locked.
+            let mut virtio_mem = virtio_mem.as_ref().map(|m| m.lock().unwrap());
+            let mut balloon = balloon.as_ref().map(|b| b.lock().unwrap());
+            let (guest_size, hotplugged) = {
+                let memory = &config.lock().unwrap().memory;
+                let hotplugged = if virtio_mem.is_some() {
+                    memory.hotplugged_size.unwrap_or(0)
+                } else {
+                    0
+                };
+                (memory.total_size() + hotplugged, hotplugged)
+            };
+
+            let unplugged = cmp::min(state.unplugged.load(Ordering::SeqCst), hotplugged);
+            let balloon_size = balloon.as_ref().map_or(0, |b| b.size());
+            let (mut new_unplugged, mut new_balloon_size) = (unplugged, balloon_size);
+
+            if available < target {
+                let room = guest_size
+                    .saturating_sub(OVERCOMMIT_GUEST_RESERVE)
+                    .saturating_sub(unplugged + balloon_size);
+                let mut reclaim = cmp::min(target - available, room);
+                if virtio_mem.is_some() {
+                    let unplug = cmp::min(
+                        cmp::min((reclaim + block_size - 1) & !(block_size - 1), room),
+                        hotplugged - unplugged,
+                    ) & !(block_size - 1);
+                    new_unplugged += unplug;
+                    reclaim = reclaim.saturating_sub(unplug);
+                }
+                if balloon.is_some() {
+                    new_balloon_size += reclaim;
+                }
+            } else {
+                let mut release = available - target - hysteresis;
+                let deflate = cmp::min(release, balloon_size);
+                new_balloon_size -= deflate;
+                release -= deflate;
+                new_unplugged -= cmp::min(release & !(block_size - 1), unplugged);
+            }
+
+            if new_unplugged == unplugged && new_balloon_size == balloon_size {
+                return;
+            }
+
+            info!(
+                "Host memory available {} bytes (target {}): virtio-mem unplugged from {} to {} \
+                 bytes, balloon from {} to {} bytes",
+                available, target, unplugged, new_unplugged, balloon_size, new_balloon_size
+            );
+
+            if let Some(virtio_mem) = virtio_mem.as_mut() {
+                if let Err(e) = virtio_mem.resize(hotplugged - new_unplugged) {
+                    error!("Failed resizing the virtio-mem device: {:?}", e);
+                    failed = true;
+                    return;
+                }
+            }
+            if let Some(balloon) = balloon.as_mut() {
+                if let Err(e) = balloon.resize(new_balloon_size) {
+                    error!("Failed resizing the balloon: {:?}", e);
+                    failed = true;
+                    return;
+                }
+            }
+
+            state.unplugged.store(new_unplugged, Ordering::SeqCst);
+            state.interventions.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    // Have the VM paused as soon as a disk backing file is deleted or
+    // replaced, or its filesystem goes away, rather than letting the guest
+    // run into I/O errors. Each disk is identified by its device and inode,
+    // and is listed in `lost` for as long as it's missing.
+    fn disk_watch_timer(
+        disks: Vec<(PathBuf, (u64, u64))>,
+        lost: Arc<Mutex<Vec<PathBuf>>>,
+        disk_evt: EventFd,
+    ) -> TimerCallback {
+        let mut missing = vec![false; disks.len()];
+
+        Box::new(move || {
+            for ((path, id), missing) in disks.iter().zip(missing.iter_mut()) {
+                let error = match fs::metadata(path) {
+                    Ok(metadata) if (metadata.dev(), metadata.ino()) == *id => None,
+                    Ok(_) => Some(String::from("replaced")),
+                    Err(e) => Some(e.to_string()),
+                };
+
+                match error {
+                    Some(error) if !*missing => {
+                        error!(
+                            "Disk {:?} backing file went away ({}), pausing the VM",
+                            path, error
+                        );
+                        *missing = true;
+                        lost.lock().unwrap().push(path.clone());
+                        if let Err(e) = disk_evt.write(1) {
+                            error!("Failed signaling the disk loss: {:?}", e);
+                        }
+                    }
+                    None if *missing => {
+                        info!("Disk {:?} backing file is back", path);
+                        *missing = false;
+                        lost.lock().unwrap().retain(|lost| lost != path);
+                    }
+                    _ => {}
+                }
+            }
+        })
+    }
+
     pub fn boot(&mut self) -> Result<()> {
         let current_state = self.get_state()?;
         if current_state == VmState::Paused {
@@ -571,58 +1552,457 @@ impl Vm {
         self.cpu_manager
             .lock()
             .unwrap()
-            .start_boot_vcpus(entry_addr)
+            .start_boot_vcpus(entry_addr, self.sev.as_ref())
             .map_err(Error::CpuManager)?;
 
-        if self.devices.console().input_enabled() {
-            let console = self.devices.console().clone();
-            let signals = Signals::new(&[SIGWINCH, SIGINT, SIGTERM]);
-            match signals {
-                Ok(signals) => {
-                    self.signals = Some(signals.clone());
-
-                    let on_tty = self.on_tty;
-                    self.threads.push(
-                        thread::Builder::new()
-                            .name("signal_handler".to_string())
-                            .spawn(move || Vm::os_signal_handler(signals, console, on_tty))
-                            .map_err(Error::SignalHandlerSpawn)?,
-                    );
-                }
-                Err(e) => error!("Signal not found {}", e),
+        self.start_services()?;
+
+        let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
+        *state = new_state;
+
+        Ok(())
+    }
+
+    // Starts what runs along with the vCPUs: the background threads and
+    // timers, and the terminal handling. The timers touching files or the
+    // guest memory run on the timer worker, not to hold the control loop up.
+    fn start_services(&mut self) -> Result<()> {
+        let dirty_rate_limit = self.config.lock().unwrap().memory.dirty_rate_limit;
+        if let Some(limit) = dirty_rate_limit {
+            self.memory_manager
+                .lock()
+                .unwrap()
+                .start_dirty_log()
+                .map_err(Error::MemoryManager)?;
+
+            let period = Duration::from_millis(DIRTY_LIMIT_PERIOD_MS);
+            let id = self
+                .timers
+                .schedule_blocking(
+                    period,
+                    Some(period),
+                    Vm::dirty_limit_timer(
+                        limit,
+                        self.cpu_manager.clone(),
+                        self.memory_manager.clone(),
+                    ),
+                )
+                .map_err(Error::ScheduleTimer)?;
+            self.timer_ids.push(id);
+        }
+
+        if self.config.lock().unwrap().memory.working_set {
+            self.memory_manager
+                .lock()
+                .unwrap()
+                .start_working_set()
+                .map_err(Error::MemoryManager)?;
+
+            let working_set = Arc::new(AtomicU64::new(0));
+            self.working_set = Some(working_set.clone());
+            let period = Duration::from_millis(WORKING_SET_PERIOD_MS);
+            let id = self
+                .timers
+                .schedule_blocking(
+                    period,
+                    Some(period),
+                    Vm::working_set_timer(working_set, self.memory_manager.clone()),
+                )
+                .map_err(Error::ScheduleTimer)?;
+            self.timer_ids.push(id);
+        }
+
+        let memory_config = self.config.lock().unwrap().memory.clone();
+        if memory_config.overcommit == MemoryOvercommit::Auto {
+            let balloon = self.devices.balloon().clone();
+            let virtio_mem = self.devices.virtio_mem().clone();
+            if balloon.is_none() && virtio_mem.is_none() {
+                return Err(Error::OvercommitWithoutDevice);
             }
 
-            if self.on_tty {
-                io::stdin()
-                    .lock()
-                    .set_raw_mode()
-                    .map_err(Error::SetTerminalRaw)?;
+            let overcommit = Arc::new(OvercommitState::default());
+            self.overcommit = Some(overcommit.clone());
+            let period = Duration::from_millis(OVERCOMMIT_PERIOD_MS);
+            let id = self
+                .timers
+                .schedule_blocking(
+                    period,
+                    Some(period),
+                    Vm::overcommit_timer(
+                        memory_config.target_free_host,
+                        self.config.clone(),
+                        balloon,
+                        virtio_mem,
+                        overcommit,
+                    ),
+                )
+                .map_err(Error::ScheduleTimer)?;
+            self.timer_ids.push(id);
+        }
+
+        // Only local files can be watched, not vhost-user or NBD disks.
+        let mut watched_disks = Vec::new();
+        for disk in self.config.lock().unwrap().disks.iter().flatten() {
+            if disk.watch
+                && !disk.vhost_user
+                && !vm_virtio::is_nbd_uri(&disk.path.to_string_lossy())
+            {
+                let metadata = fs::metadata(&disk.path).map_err(Error::DiskWatch)?;
+                watched_disks.push((disk.path.clone(), (metadata.dev(), metadata.ino())));
             }
         }
+        if !watched_disks.is_empty() {
+            let disk_evt = self.disk_evt.try_clone().map_err(Error::EventFdClone)?;
+            let period = Duration::from_millis(DISK_WATCH_PERIOD_MS);
+            let id = self
+                .timers
+                .schedule_blocking(
+                    period,
+                    Some(period),
+                    Vm::disk_watch_timer(watched_disks, self.lost_disks.clone(), disk_evt),
+                )
+                .map_err(Error::ScheduleTimer)?;
+            self.timer_ids.push(id);
+        }
 
-        let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
-        *state = new_state;
+        let input_enabled = self.devices.console().input_enabled();
+        let console = self.devices.console().clone();
+        let exit_evt = self.exit_evt.try_clone().map_err(Error::EventFdClone)?;
+        // The terminal size only matters to the virtio-console, when it's on
+        // the terminal.
+        let console_on_tty =
+            self.on_tty && self.config.lock().unwrap().console.mode == ConsoleOutputMode::Tty;
+        let signals = if console_on_tty {
+            Signals::new(&[SIGWINCH, SIGINT, SIGTERM])
+        } else {
+            Signals::new(&[SIGINT, SIGTERM])
+        };
+        match signals {
+            Ok(signals) => {
+                self.signals = Some(signals.clone());
+
+                self.threads.push(
+                    thread::Builder::new()
+                        .name("signal_handler".to_string())
+                        .spawn(move || Vm::os_signal_handler(signals, console, exit_evt))
+                        .map_err(Error::SignalHandlerSpawn)?,
+                );
+            }
+            Err(e) => error!("Signal not found {}", e),
+        }
+
+        if input_enabled && self.on_tty {
+            io::stdin()
+                .lock()
+                .set_raw_mode()
+                .map_err(Error::SetTerminalRaw)?;
+        }
 
         Ok(())
     }
 
     pub fn handle_stdin(&self) -> Result<()> {
         let mut out = [0u8; 64];
-        let count = io::stdin()
-            .lock()
-            .read_raw(&mut out)
-            .map_err(Error::Console)?;
+        let count = self.read_stdin(&mut out)?;
+
+        self.queue_console_input(&out[..count])
+    }
+
+    pub fn read_stdin(&self, out: &mut [u8]) -> Result<usize> {
+        io::stdin().lock().read_raw(out).map_err(Error::Console)
+    }
 
-        if self.devices.console().input_enabled() {
+    pub fn queue_console_input(&self, input: &[u8]) -> Result<()> {
+        if self.devices.console().input_enabled() && !input.is_empty() {
             self.devices
                 .console()
-                .queue_input_bytes(&out[..count])
+                .queue_input_bytes(input)
                 .map_err(Error::Console)?;
         }
 
         Ok(())
     }
 
+    /// Whether the terminal is shared with the VMM command prompt.
+    pub fn tty_mux(&self) -> bool {
+        let config = self.config.lock().unwrap();
+        (config.serial.mux && config.serial.mode == ConsoleOutputMode::Tty)
+            || (config.console.mux && config.console.mode == ConsoleOutputMode::Tty)
+    }
+
+    /// Save the VM configuration, guest clock, vCPUs and devices state, and
+    /// guest RAM into `destination`.
+    ///
+    /// The VM is only paused for the vCPUs and devices state to be saved,
+    /// along with a copy-on-write view of the guest memory, and resumed if
+    /// it was running. The guest memory is then written out in the
+    /// background, `done_evt` being written to once done, for the snapshot
+    /// to be completed by `snapshot_written()`. The returned operation
+    /// reports the progress of the snapshot. The vCPUs and devices state is
+    /// written last, a snapshot without it being incomplete.
+    ///
+    /// When devices DMA to pinned guest memory (VFIO, vDPA), the guest
+    /// memory is written out before resuming the VM instead.
+    ///
+    /// The saved configuration keeps the VM UUID, so that restoring the
+    /// snapshot brings back the same machine, unless `clone` is set.
+    ///
+    /// With `exclude_free_pages`, the pages the guest gave to the balloon
+    /// aren't saved, shrinking the snapshot of a mostly idle guest. The
+    /// memory the guest didn't plug through virtio-mem is never saved.
+    pub fn snapshot(
+        &mut self,
+        destination: &Path,
+        clone: bool,
+        exclude_free_pages: bool,
+        done_evt: EventFd,
+    ) -> Result<Arc<Operation>> {
+        if self.sev.is_some() {
+            return Err(Error::SevSnapshot);
+        }
+        if self.snapshot.is_some() {
+            return Err(Error::SnapshotInProgress);
+        }
+
+        let current_state = self.get_state()?;
+        match current_state {
+            VmState::Running | VmState::Paused => {}
+            _ => return Err(Error::InvalidStateForSnapshot(current_state)),
+        }
+
+        fs::create_dir_all(destination).map_err(Error::SnapshotDirectory)?;
+        // A previous snapshot at the same place must not look complete
+        // while this one is being written.
+        match fs::remove_file(destination.join(SNAPSHOT_STATE_FILE)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(Error::SnapshotDirectory(e))
+            }
+            _ => {}
+        }
+        let config_file =
+            File::create(destination.join("config.json")).map_err(Error::SnapshotDirectory)?;
+        let mut config = self.config.lock().unwrap().clone();
+        if clone {
+            config.uuid = None;
+        }
+        serde_json::to_writer(config_file, &config).map_err(Error::SnapshotConfig)?;
+
+        if current_state == VmState::Running {
+            self.pause().map_err(Error::Pause)?;
+        }
+
+        // The balloon pages and the plugged memory can't change while the
+        // VM is paused.
+        let mut excluded = match self.devices.balloon() {
+            Some(balloon) if exclude_free_pages => balloon.lock().unwrap().free_ranges(),
+            _ => Vec::new(),
+        };
+        if let Some(virtio_mem) = self.devices.virtio_mem() {
+            excluded.append(&mut virtio_mem.lock().unwrap().unplugged_ranges());
+            excluded.sort_unstable();
+        }
+
+        // The guest clock, vCPUs and devices are saved while paused, so that
+        // they match the guest memory content.
+        let cow = !self.devices.pins_guest_memory();
+        let prepared = self
+            .save_clock(destination)
+            .and_then(|_| Snapshotable::snapshot(self).map_err(Error::Snapshot))
+            .and_then(|state| {
+                self.memory_manager
+                    .lock()
+                    .unwrap()
+                    .snapshot(destination, &excluded, cow)
+                    .map(|writer| (state, writer))
+                    .map_err(Error::MemoryManager)
+            });
+
+        // The guest can run again, the memory being saved as it was.
+        if current_state == VmState::Running {
+            self.resume().map_err(Error::Resume)?;
+        }
+        let (state, writer) = prepared?;
+
+        let operation = Arc::new(Operation::new("snapshot", writer.bytes_total()));
+        let writer_operation = operation.clone();
+        let thread = thread::Builder::new()
+            .name("snapshot_writer".to_string())
+            .spawn(move || {
+                let written = writer.write(|written| {
+                    writer_operation.set_progress(written);
+                    !writer_operation.cancel_requested()
+                });
+                if let Err(e) = done_evt.write(1) {
+                    error!("Failed signalling the end of the snapshot: {}", e);
+                }
+                written
+            });
+        let thread = thread.map_err(Error::SnapshotThreadSpawn)?;
+
+        self.snapshot = Some(SnapshotInProgress {
+            operation: operation.clone(),
+            destination: destination.to_path_buf(),
+            state,
+            thread,
+        });
+
+        Ok(operation)
+    }
+
+    /// Complete the snapshot in progress, if any, once its guest memory has
+    /// been written out, waiting for it otherwise.
+    pub fn snapshot_written(&mut self) -> Result<()> {
+        let snapshot = match self.snapshot.take() {
+            Some(snapshot) => snapshot,
+            None => return Ok(()),
+        };
+
+        let written = match snapshot.thread.join().map_err(Error::ThreadCleanup)? {
+            Ok(true) => Vm::save_state(&snapshot.destination, &snapshot.state).map(|_| true),
+            Ok(false) => Ok(false),
+            Err(e) => Err(Error::MemoryManager(e)),
+        };
+        match written {
+            Ok(true) => {
+                snapshot.operation.complete();
+                info!("VM snapshot written to {:?}", snapshot.destination);
+            }
+            Ok(false) => {
+                // An incomplete memory file can't be restored from.
+                let _ = fs::remove_file(snapshot.destination.join("memory"));
+                snapshot.operation.set_cancelled();
+                info!("VM snapshot to {:?} cancelled", snapshot.destination);
+            }
+            Err(e) => {
+                let _ = fs::remove_file(snapshot.destination.join("memory"));
+                error!(
+                    "Failed writing VM snapshot to {:?}: {:?}",
+                    snapshot.destination, e
+                );
+                snapshot.operation.fail(format!("{:?}", e));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn save_state(destination: &Path, state: &Snapshot) -> Result<()> {
+        let state_file = File::create(destination.join(SNAPSHOT_STATE_FILE))
+            .map_err(Error::SnapshotDirectory)?;
+        serde_json::to_writer(&state_file, state).map_err(Error::SnapshotState)?;
+        state_file.sync_all().map_err(Error::SnapshotDirectory)
+    }
+
+    /// Bring the VM, freshly created from the configuration saved in the
+    /// snapshot at `source`, to the state it had when the snapshot was
+    /// taken. The VM is left paused, to be resumed or booted.
+    pub fn restore(&mut self, source: &Path) -> Result<()> {
+        if self.sev.is_some() {
+            return Err(Error::SevSnapshot);
+        }
+
+        let current_state = self.get_state()?;
+        if current_state != VmState::Created {
+            return Err(Error::InvalidStateForRestore(current_state));
+        }
+        // Paused from now on, for the VM to be shut down would the restore
+        // fail midway.
+        *self.state.try_write().map_err(|_| Error::PoisonedState)? = VmState::Paused;
+
+        let state_file =
+            File::open(source.join(SNAPSHOT_STATE_FILE)).map_err(Error::RestoreFile)?;
+        let state: Snapshot = serde_json::from_reader(state_file).map_err(Error::RestoreState)?;
+        let clock_file = File::open(source.join("clock.json")).map_err(Error::RestoreFile)?;
+        let clock: VmClockData =
+            serde_json::from_reader(clock_file).map_err(Error::RestoreState)?;
+
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .restore(source)
+            .map_err(Error::MemoryManager)?;
+
+        // The vCPUs are started paused, and the devices activated as they
+        // were, hence them being paused right after.
+        Snapshotable::restore(self, state).map_err(Error::Restore)?;
+        self.devices.pause().map_err(Error::Pause)?;
+        self.set_clock(clock.clock)?;
+
+        self.start_services()
+    }
+
+    fn save_clock(&self, destination: &Path) -> Result<()> {
+        let clock = VmClockData {
+            clock: self.clock()?,
+        };
+        let clock_file =
+            File::create(destination.join("clock.json")).map_err(Error::SnapshotDirectory)?;
+        serde_json::to_writer(clock_file, &clock).map_err(Error::SnapshotClock)
+    }
+
+    /// Get the guest clock, in nanoseconds, as read by the guest through
+    /// the KVM clock.
+    pub fn clock(&self) -> Result<u64> {
+        let clock = self.fd.get_clock().map_err(Error::GetClock)?;
+        Ok(clock.clock)
+    }
+
+    /// Set the guest clock, in nanoseconds. This lets the guest clock catch
+    /// up with the time the VM didn't run for, e.g. after it got restored
+    /// from a snapshot.
+    pub fn set_clock(&self, clock: u64) -> Result<()> {
+        let clock = kvm_clock_data {
+            clock,
+            ..Default::default()
+        };
+        self.fd.set_clock(&clock).map_err(Error::SetClock)
+    }
+
+    /// Host resources used by the VM: the CPU time of each vCPU, the guest
+    /// working set estimated over the last period, if enabled, and the
+    /// memory taken back from the guest.
+    pub fn counters(&self) -> VmCounters {
+        let vcpus = self
+            .cpu_manager
+            .lock()
+            .unwrap()
+            .vcpus_cpu_time()
+            .into_iter()
+            .map(|(id, cpu_time)| VcpuCounters { id, cpu_time })
+            .collect();
+
+        VmCounters {
+            vcpus,
+            working_set: self
+                .working_set
+                .as_ref()
+                .map(|working_set| working_set.load(Ordering::SeqCst)),
+            balloon: self
+                .devices
+                .balloon()
+                .as_ref()
+                .map(|balloon| balloon.lock().unwrap().actual_size()),
+            free_pages_reported: self
+                .devices
+                .balloon()
+                .as_ref()
+                .filter(|balloon| balloon.lock().unwrap().free_page_reporting())
+                .map(|balloon| balloon.lock().unwrap().reported_size()),
+            memory_plugged: self
+                .devices
+                .virtio_mem()
+                .as_ref()
+                .map(|virtio_mem| virtio_mem.lock().unwrap().plugged_size()),
+            overcommit: self
+                .overcommit
+                .as_ref()
+                .map(|overcommit| OvercommitCounters {
+                    interventions: overcommit.interventions.load(Ordering::SeqCst),
+                    unplugged: overcommit.unplugged.load(Ordering::SeqCst),
+                }),
+        }
+    }
+
     /// Gets a thread-safe reference counted pointer to the VM configuration.
     pub fn get_config(&self) -> Arc<Mutex<VmConfig>> {
         Arc::clone(&self.config)
@@ -650,6 +2030,7 @@ impl Pausable for Vm {
             .map_err(|e| MigratableError::Pause(anyhow!("Invalid transition: {:?}", e)))?;
 
         self.cpu_manager.lock().unwrap().pause()?;
+        cpu::CpuManager::wait_for_pause(&self.cpu_manager);
         self.devices.pause()?;
 
         *state = new_state;
@@ -673,12 +2054,33 @@ impl Pausable for Vm {
 
         // And we're back to the Running state.
         *state = new_state;
+        self.paused_reason = None;
 
         Ok(())
     }
 }
 
-impl Snapshotable for Vm {}
+impl Snapshotable for Vm {
+    fn id(&self) -> String {
+        "vm".to_string()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(&self.id());
+        snapshot.add_snapshot(self.cpu_manager.lock().unwrap().snapshot()?);
+        snapshot.add_snapshot(self.devices.snapshot()?);
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, mut snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        // The devices are restored first, for the vCPUs to find them as they
+        // were.
+        let devices = snapshot.take_snapshot(&self.devices.id())?;
+        self.devices.restore(devices)?;
+        let cpu_manager = snapshot.take_snapshot("cpu-manager")?;
+        self.cpu_manager.lock().unwrap().restore(cpu_manager)
+    }
+}
 impl Migratable for Vm {}
 
 #[cfg(test)]
@@ -737,6 +2139,123 @@ mod tests {
     fn test_vm_paused_transitions() {
         test_vm_state_transitions(VmState::Paused);
     }
+
+    #[test]
+    fn test_console_cmdline_args() {
+        let serial = ConsoleConfig::default_serial();
+        let console = ConsoleConfig::default_console();
+        let serial_tty = ConsoleConfig {
+            mode: ConsoleOutputMode::Tty,
+            ..ConsoleConfig::default_serial()
+        };
+        let console_file = ConsoleConfig {
+            mode: ConsoleOutputMode::File,
+            file: Some(PathBuf::from("/tmp/console")),
+            ..ConsoleConfig::default_console()
+        };
+
+        assert_eq!(
+            console_cmdline_args("root=/dev/vda1", &serial, &console),
+            vec!["console=hvc0"]
+        );
+        assert_eq!(
+            console_cmdline_args("root=/dev/vda1", &serial_tty, &console_file),
+            vec!["console=ttyS0", "console=hvc0"]
+        );
+        assert!(
+            console_cmdline_args("console=ttyS0,115200n8 root=/dev/vda1", &serial, &console)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_elf_kernel_end() {
+        // An ELF header followed by three program headers: a loadable text
+        // segment, a note and a loadable data segment with its BSS.
+        let mut elf = vec![0u8; 64 + 3 * 56];
+        elf[0x20..0x28].copy_from_slice(&64u64.to_le_bytes());
+        elf[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+        elf[0x38..0x3a].copy_from_slice(&3u16.to_le_bytes());
+        let phdrs = [
+            (1u32, 0x100_0000u64, 0x80_0000u64),
+            (4u32, 0x400_0000u64, 0x1000u64),
+            (1u32, 0x180_0000u64, 0x20_0000u64),
+        ];
+        for (i, (p_type, paddr, memsz)) in phdrs.iter().enumerate() {
+            let phdr = &mut elf[64 + i * 56..64 + (i + 1) * 56];
+            phdr[0..4].copy_from_slice(&p_type.to_le_bytes());
+            phdr[0x18..0x20].copy_from_slice(&paddr.to_le_bytes());
+            phdr[0x28..0x30].copy_from_slice(&memsz.to_le_bytes());
+        }
+
+        let mut kernel = io::Cursor::new(elf);
+        assert_eq!(elf_kernel_end(&mut kernel).unwrap(), 0x1a0_0000);
+    }
+
+    #[test]
+    fn test_disk_watch_timer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.img");
+        File::create(&path).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        let lost = Arc::new(Mutex::new(Vec::new()));
+        let disk_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut timer = Vm::disk_watch_timer(
+            vec![(path.clone(), (metadata.dev(), metadata.ino()))],
+            lost.clone(),
+            disk_evt.try_clone().unwrap(),
+        );
+
+        timer();
+        assert!(lost.lock().unwrap().is_empty());
+        assert!(disk_evt.read().is_err());
+
+        // The VM only gets paused once for a missing disk.
+        let moved = dir.path().join("moved.img");
+        fs::rename(&path, &moved).unwrap();
+        timer();
+        timer();
+        assert_eq!(*lost.lock().unwrap(), vec![path.clone()]);
+        assert_eq!(disk_evt.read().unwrap(), 1);
+
+        // Another file at the same place isn't the disk.
+        File::create(&path).unwrap();
+        timer();
+        assert_eq!(*lost.lock().unwrap(), vec![path.clone()]);
+
+        fs::rename(&moved, &path).unwrap();
+        timer();
+        assert!(lost.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dirty_rate_per_vcpu() {
+        // The vCPU running the guest the most gets most of the dirty pages.
+        assert_eq!(
+            Vm::dirty_rate_per_vcpu(1000, &[300, 100, 0]),
+            vec![750, 250, 0]
+        );
+        // No vCPU ran the guest.
+        assert_eq!(Vm::dirty_rate_per_vcpu(1000, &[0, 0]), vec![0, 0]);
+        assert!(Vm::dirty_rate_per_vcpu(1000, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_dirty_limit_run_percent() {
+        let limit = 32 << 20;
+
+        // A vCPU under the limit runs unthrottled.
+        assert_eq!(Vm::dirty_limit_run_percent(100, limit / 2, limit), 100);
+        assert_eq!(Vm::dirty_limit_run_percent(50, 0, limit), 100);
+        // One over it is slowed down in proportion.
+        assert_eq!(Vm::dirty_limit_run_percent(100, limit * 4, limit), 25);
+        assert_eq!(Vm::dirty_limit_run_percent(25, limit / 2, limit), 50);
+        // But it always gets to run a bit.
+        assert_eq!(
+            Vm::dirty_limit_run_percent(100, limit * 1000, limit),
+            DIRTY_LIMIT_MIN_RUN_PERCENT
+        );
+    }
 }
 
 #[allow(unused)]